@@ -0,0 +1,96 @@
+//! Finding ACPI tables.
+//!
+//! The root system description pointer is searched for where the BIOS puts
+//! it: in the first KiB of the extended BIOS data area and in the BIOS area
+//! from 0xe0000 to 0xfffff. The tables are read through the physical memory
+//! mapping, so `memory::init` must have run.
+
+use core::convert::TryInto;
+use crate::memory;
+
+/// Size of the header every system description table starts with.
+const HEADER_SIZE: usize = 36;
+
+/// The physical memory at `addr`. It must be mapped, as all RAM is.
+fn physical(addr: u64, len: usize) -> &'static [u8] {
+    let virt = memory::physical_memory_offset() + addr;
+    unsafe { core::slice::from_raw_parts(virt.as_ptr(), len) }
+}
+
+/// Whether the bytes of `data` add up to zero, as they do in every ACPI structure.
+fn checksum_ok(data: &[u8]) -> bool {
+    data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+/// The physical address of the root system description pointer.
+fn find_rsdp() -> Option<u64> {
+    // the EBDA segment is stored at 0x40e in the BIOS data area
+    let ebda = u64::from(u16::from_le_bytes(physical(0x40e, 2).try_into().unwrap())) << 4;
+    let areas = [(ebda, 1024), (0xe0000, 0x20000)];
+    areas.iter()
+        .filter(|&&(start, _)| start != 0)
+        .flat_map(|&(start, len)| (start..start + len).step_by(16))
+        .find(|&addr| {
+            let rsdp = physical(addr, 20);
+            &rsdp[..8] == b"RSD PTR " && checksum_ok(rsdp)
+        })
+}
+
+/// The table at `addr`, if its checksum is right.
+fn table_at(addr: u64) -> Option<&'static [u8]> {
+    let length = read_u32(physical(addr, HEADER_SIZE), 4) as usize;
+    if length < HEADER_SIZE {
+        return None;
+    }
+    let table = physical(addr, length);
+    if checksum_ok(table) { Some(table) } else { None }
+}
+
+/// Calls `f` with the physical address of every table the root table lists.
+fn for_each_table(mut f: impl FnMut(u64)) -> Result<(), &'static str> {
+    let addr = find_rsdp().ok_or("no ACPI root pointer")?;
+    let rsdp = physical(addr, 36);
+    // revision 2 and later point to the XSDT with 64-bit entries
+    let (root, entry_size) = if rsdp[15] >= 2 && checksum_ok(rsdp) && read_u64(rsdp, 24) != 0 {
+        (read_u64(rsdp, 24), 8)
+    } else {
+        (u64::from(read_u32(rsdp, 16)), 4)
+    };
+    let root = table_at(root).ok_or("invalid ACPI root table")?;
+    for entry in root[HEADER_SIZE..].chunks_exact(entry_size) {
+        match entry_size {
+            8 => f(read_u64(entry, 0)),
+            _ => f(u64::from(read_u32(entry, 0))),
+        }
+    }
+    Ok(())
+}
+
+/// The table with `signature`, like `b"HPET"`, header included.
+pub fn find_table(signature: &[u8; 4]) -> Result<&'static [u8], &'static str> {
+    let mut found = None;
+    for_each_table(|addr| {
+        if found.is_none() && physical(addr, 4) == signature {
+            found = table_at(addr);
+        }
+    })?;
+    found.ok_or("no such ACPI table")
+}
+
+#[test_case]
+fn test_find_table() {
+    assert!(checksum_ok(&[0x10, 0xf0]));
+    assert!(!checksum_ok(&[0x10, 0xf1]));
+    // QEMU always provides the fixed ACPI description table
+    let facp = find_table(b"FACP").unwrap();
+    assert_eq!(&facp[..4], b"FACP");
+    assert_eq!(find_table(b"NONE"), Err("no such ACPI table"));
+}
@@ -1,5 +1,6 @@
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::ptr::null_mut;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 use x86_64::{
     structures::paging::{
@@ -7,16 +8,62 @@ use x86_64::{
     },
     VirtAddr,
 };
+use crate::aslr;
+use crate::sched::tls::Slot;
+#[cfg(feature = "heap-debug")]
+use crate::allocator::debug::DebugAllocator;
+#[cfg(not(feature = "heap-debug"))]
 use crate::allocator::linked_list::LinkedListAllocator;
 
 
+#[cfg(not(feature = "heap-debug"))]
 #[global_allocator]
 static ALLOCATOR: Locked<LinkedListAllocator> =
     Locked::new(LinkedListAllocator::new());
 
-pub const HEAP_START: usize = 0x_4444_4444_0000;
+#[cfg(feature = "heap-debug")]
+#[global_allocator]
+static ALLOCATOR: Locked<DebugAllocator> =
+    Locked::new(DebugAllocator::new());
+
 pub const HEAP_SIZE: usize = 100 * 1024;
 
+/// Start of the heap, chosen by `init_heap`.
+static HEAP_START: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the start address of the heap, randomized unless ASLR is disabled.
+pub fn heap_start() -> usize {
+    HEAP_START.load(Ordering::Relaxed)
+}
+
+/// Bytes currently handed out by the global allocator.
+static USED: AtomicUsize = AtomicUsize::new(0);
+
+/// Bytes currently allocated on the heap, not counting allocator overhead.
+pub fn heap_used() -> usize {
+    USED.load(Ordering::Relaxed)
+}
+
+/// Records that the allocation at `ptr` was made, or freed if `allocated` is
+/// false.
+fn account(ptr: *mut u8, layout: Layout, allocated: bool) {
+    if allocated {
+        USED.fetch_add(layout.size(), Ordering::Relaxed);
+        Slot::HEAP_ALLOCATED.add(layout.size() as u64);
+        trace::record_alloc(ptr, layout);
+    } else {
+        USED.fetch_sub(layout.size(), Ordering::Relaxed);
+        trace::record_free(ptr);
+    }
+}
+
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+/// Whether `init_heap` has run, so heap allocations succeed.
+pub fn is_initialized() -> bool {
+    INITIALIZED.load(Ordering::Acquire)
+}
+
 
 pub struct Dummy;
 
@@ -34,8 +81,13 @@ pub fn init_heap(
     mapper: &mut impl Mapper<Size4KiB>,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
 ) -> Result<(), MapToError<Size4KiB>> {
+    // if no free spot is found, mapping the window start reports the collision
+    let heap_start = aslr::random_free_base(aslr::HEAP_WINDOW, HEAP_SIZE as u64, 4096)
+        .unwrap_or(aslr::HEAP_WINDOW.start) as usize;
+    HEAP_START.store(heap_start, Ordering::Relaxed);
+
     let page_range = {
-        let heap_start = VirtAddr::new(HEAP_START as u64);
+        let heap_start = VirtAddr::new(heap_start as u64);
         let heap_end = heap_start + HEAP_SIZE - 1u64;
         let heap_start_page = Page::containing_address(heap_start);
         let heap_end_page = Page::containing_address(heap_end);
@@ -53,13 +105,22 @@ pub fn init_heap(
     }
 
     unsafe {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+        ALLOCATOR.lock().init(heap_start, HEAP_SIZE);
     }
+    INITIALIZED.store(true, Ordering::Release);
 
 
     Ok(())
 }
 
+/// Panics if the canaries of a live heap allocation were overwritten.
+///
+/// Only the `heap-debug` allocator keeps canaries: without it this does nothing.
+pub fn check_heap() {
+    #[cfg(feature = "heap-debug")]
+    ALLOCATOR.check_heap();
+}
+
 /// A wrapper around spin::Mutex to permit trait implementations.
 pub struct Locked<A> {
     inner: spin::Mutex<A>,
@@ -88,4 +149,35 @@ fn align_up(addr: usize, align: usize) -> usize {
 }
 
 pub mod bump;
-pub mod linked_list;
\ No newline at end of file
+pub mod linked_list;
+pub mod debug;
+pub mod trace;
+
+crate::should_panic_test!(test_allocation_larger_than_heap_panics, {
+    let too_large: alloc::vec::Vec<u8> = alloc::vec::Vec::with_capacity(HEAP_SIZE + 1);
+    core::hint::black_box(too_large);
+});
+
+#[cfg(feature = "heap-debug")]
+crate::should_panic_test!(test_heap_overflow_is_detected, {
+    let mut buffer: alloc::vec::Vec<u8> = alloc::vec::Vec::with_capacity(16);
+    unsafe { buffer.as_mut_ptr().add(16).write(0) };
+    drop(buffer);
+});
+
+#[cfg(feature = "heap-debug")]
+crate::should_panic_test!(test_check_heap_finds_underflow, {
+    let buffer: alloc::boxed::Box<[u8; 16]> = alloc::boxed::Box::new([0; 16]);
+    let ptr = alloc::boxed::Box::into_raw(buffer) as *mut u8;
+    unsafe { ptr.sub(1).write(0) };
+    check_heap();
+});
+
+#[cfg(feature = "heap-debug")]
+#[test_case]
+fn test_freed_memory_is_poisoned() {
+    let buffer = alloc::boxed::Box::new([0u8; 64]);
+    let ptr = &*buffer as *const [u8; 64] as *const u8;
+    drop(buffer);
+    assert_eq!(unsafe { ptr.add(32).read_volatile() }, debug::FREE_POISON);
+}
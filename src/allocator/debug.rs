@@ -0,0 +1,195 @@
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::{mem, ptr};
+use crate::arch::interrupts::without_interrupts;
+use crate::allocator::linked_list::LinkedListAllocator;
+use crate::allocator::{align_up, heap_start, Locked, HEAP_SIZE};
+use crate::debug::Backtrace;
+
+/// Guards both ends of every live allocation.
+const CANARY: u64 = 0x5afe_c0de_5afe_c0de;
+/// Replaces the front canary of a freed allocation, to recognize double frees.
+const FREED: u64 = 0xdead_f4ee_dead_f4ee;
+/// Fills newly allocated memory, so reads of uninitialized memory stand out.
+pub const ALLOC_POISON: u8 = 0xcd;
+/// Fills freed memory, so use-after-free reads stand out.
+pub const FREE_POISON: u8 = 0x6b;
+
+/// Bookkeeping placed right before the memory handed out by the allocator.
+/// The rear canary follows the allocation, unaligned.
+#[repr(C)]
+struct Header {
+    prev: usize,
+    next: usize,
+    size: usize,
+    backtrace: Backtrace,
+    canary: u64,
+}
+
+/// Wraps the linked list allocator with canaries around every allocation and
+/// poisoning of allocated and freed memory. Selected by the `heap-debug` feature.
+///
+/// Corruption is detected when the allocation is freed or by `check_heap`, and
+/// panics with the size of the allocation and where it was allocated.
+pub struct DebugAllocator {
+    heap: LinkedListAllocator,
+    /// Address of the most recently allocated live header, 0 if there is none.
+    live: usize,
+}
+
+impl DebugAllocator {
+    pub const fn new() -> Self {
+        DebugAllocator { heap: LinkedListAllocator::new(), live: 0 }
+    }
+
+    /// Initialize the allocator with the given heap bounds.
+    ///
+    /// Unsafe for the same reasons as `LinkedListAllocator::init`.
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.heap.init(heap_start, heap_size);
+    }
+
+    /// Removes `header` from the live list.
+    unsafe fn unlink(&mut self, header: *mut Header) {
+        let (prev, next) = ((*header).prev, (*header).next);
+        // the links may be corrupted as well: then the rest of the list is lost
+        if !in_heap(prev) || !in_heap(next) {
+            if self.live == header as usize {
+                self.live = 0;
+            }
+            return;
+        }
+        if prev == 0 {
+            self.live = next;
+        } else {
+            (*(prev as *mut Header)).next = next;
+        }
+        if next != 0 {
+            (*(next as *mut Header)).prev = prev;
+        }
+    }
+}
+
+/// The layout actually allocated for `layout` and the offset of the user memory in it.
+fn padded(layout: Layout) -> Option<(Layout, usize)> {
+    let align = layout.align().max(mem::align_of::<Header>());
+    let offset = align_up(mem::size_of::<Header>(), align);
+    let size = offset.checked_add(layout.size())?.checked_add(mem::size_of::<u64>())?;
+    Some((Layout::from_size_align(size, align).ok()?, offset))
+}
+
+fn header_of(ptr: *mut u8) -> *mut Header {
+    ptr.wrapping_sub(mem::size_of::<Header>()) as *mut Header
+}
+
+fn in_heap(addr: usize) -> bool {
+    addr == 0 || (heap_start()..heap_start() + HEAP_SIZE).contains(&addr)
+}
+
+/// What is wrong with the allocation behind `header`, if anything.
+unsafe fn check(header: *const Header) -> Result<(), &'static str> {
+    match (*header).canary {
+        CANARY => {}
+        FREED => return Err("double free"),
+        _ => return Err("heap underflow (front canary overwritten)"),
+    }
+    let end = (header as *const u8).add(mem::size_of::<Header>() + (*header).size);
+    if (end as *const u64).read_unaligned() != CANARY {
+        return Err("heap overflow (rear canary overwritten)");
+    }
+    Ok(())
+}
+
+fn report(error: &str, ptr: usize, size: usize, backtrace: &Backtrace) -> ! {
+    panic!("heap corruption: {} in the {} byte allocation at {:#x}, allocated from\n{}",
+        error, size, ptr, backtrace);
+}
+
+unsafe impl GlobalAlloc for Locked<DebugAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // a thread preempted while holding the lock would block every other one
+        without_interrupts(|| self.allocate(layout))
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        without_interrupts(|| self.deallocate(ptr, layout))
+    }
+}
+
+impl Locked<DebugAllocator> {
+    unsafe fn allocate(&self, layout: Layout) -> *mut u8 {
+        let backtrace = Backtrace::capture();
+        let (outer, offset) = match padded(layout) {
+            Some(padded) => padded,
+            None => return ptr::null_mut(),
+        };
+        let mut allocator = self.lock();
+        let base = allocator.heap.allocate(outer);
+        if base.is_null() {
+            return base;
+        }
+
+        let ptr = base.add(offset);
+        let header = header_of(ptr);
+        header.write(Header { prev: 0, next: allocator.live, size: layout.size(), backtrace, canary: CANARY });
+        if allocator.live != 0 {
+            (*(allocator.live as *mut Header)).prev = header as usize;
+        }
+        allocator.live = header as usize;
+
+        ptr.write_bytes(ALLOC_POISON, layout.size());
+        (ptr.add(layout.size()) as *mut u64).write_unaligned(CANARY);
+        super::account(ptr, layout, true);
+        ptr
+    }
+
+    unsafe fn deallocate(&self, ptr: *mut u8, layout: Layout) {
+        let (outer, offset) = padded(layout).expect("invalid layout");
+        let header = header_of(ptr);
+        let mut allocator = self.lock();
+
+        let mut result = check(header);
+        if result.is_ok() && (*header).size != layout.size() {
+            result = Err("size mismatch on dealloc");
+        }
+        if let Err(error) = result {
+            // leak the allocation: its memory can't be trusted
+            let (size, backtrace) = ((*header).size, (*header).backtrace);
+            if error != "double free" {
+                allocator.unlink(header);
+            }
+            drop(allocator);
+            report(error, ptr as usize, size, &backtrace);
+        }
+
+        allocator.unlink(header);
+        (*header).canary = FREED;
+        ptr.write_bytes(FREE_POISON, layout.size());
+        allocator.heap.deallocate(ptr.sub(offset), outer);
+        super::account(ptr, layout, false);
+    }
+
+    /// Validates the canaries of every live allocation, panicking on the first
+    /// corrupted one.
+    pub fn check_heap(&self) {
+        let mut allocator = self.lock();
+        let mut current = allocator.live;
+        while current != 0 {
+            if !in_heap(current) {
+                allocator.live = 0;
+                drop(allocator);
+                panic!("heap corruption: live allocation list points outside the heap ({:#x})", current);
+            }
+            let header = current as *mut Header;
+            unsafe {
+                if let Err(error) = check(header) {
+                    let (size, backtrace) = ((*header).size, (*header).backtrace);
+                    allocator.unlink(header);
+                    drop(allocator);
+                    let ptr = current + mem::size_of::<Header>();
+                    report(error, ptr, size, &backtrace);
+                }
+                current = (*header).next;
+            }
+        }
+    }
+}
@@ -1,4 +1,5 @@
 use core::mem;
+use crate::arch::interrupts::without_interrupts;
 use crate::allocator::align_up;
 
 struct ListNode {
@@ -126,17 +127,17 @@ use super::Locked;
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::ptr;
 
-unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+impl LinkedListAllocator {
+    /// Allocates a block for `layout`, returning null if no region is large enough.
+    pub unsafe fn allocate(&mut self, layout: Layout) -> *mut u8 {
         // perform layout adjustments
         let (size, align) = LinkedListAllocator::size_align(layout);
-        let mut allocator = self.lock();
 
-        if let Some((region, alloc_start)) = allocator.find_region(size, align) {
+        if let Some((region, alloc_start)) = self.find_region(size, align) {
             let alloc_end = alloc_start.checked_add(size).expect("overflow");
             let excess_size = region.end_addr() - alloc_end;
             if excess_size > 0 {
-                allocator.add_free_region(alloc_end, excess_size);
+                self.add_free_region(alloc_end, excess_size);
             }
             alloc_start as *mut u8
         } else {
@@ -144,10 +145,28 @@ unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
         }
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+    /// Returns the block at `ptr`, allocated with `layout`, to the free list.
+    pub unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
         // perform layout adjustments
         let (size, _) = LinkedListAllocator::size_align(layout);
 
-        self.lock().add_free_region(ptr as usize, size)
+        self.add_free_region(ptr as usize, size)
+    }
+}
+
+unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // a thread preempted while holding the lock would block every other one
+        let ptr = without_interrupts(|| self.lock().allocate(layout));
+        if !ptr.is_null() {
+            super::account(ptr, layout, true);
+        }
+        ptr
     }
-}
\ No newline at end of file
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // before the memory can be handed out again
+        super::account(ptr, layout, false);
+        without_interrupts(|| self.lock().deallocate(ptr, layout));
+    }
+}
@@ -0,0 +1,264 @@
+//! Opt-in tracing of heap allocations, to hunt for leaks.
+//!
+//! While tracing, every allocation is recorded with its size, alignment, when
+//! it was made and the call chain it was made from, and forgotten again when
+//! it is freed. The records live in an arena of frames set aside the first
+//! time tracing starts, one slot per `GRANULE` bytes of heap, so recording
+//! takes no heap memory and finding a record is a division.
+//!
+//! `checkpoint` starts a new generation of allocations. `leaks` sums up the
+//! live allocations made between two checkpoints by call chain: what a
+//! workload run between them allocated and never freed. Run the workload
+//! again before looking, so caches it filled the first time don't show up.
+
+use core::alloc::Layout;
+use core::mem;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use crate::arch::interrupts::without_interrupts;
+use crate::allocator::{heap_start, HEAP_SIZE};
+use crate::debug::Backtrace;
+use crate::memory;
+use crate::symbols::{self, Symbol};
+use crate::time::Instant;
+
+/// Heap bytes per slot. Allocations are at least this large, so no two live
+/// ones start in the same slot.
+const GRANULE: usize = 16;
+const SLOTS: usize = HEAP_SIZE / GRANULE;
+/// Return addresses recorded per allocation, innermost first.
+pub const SITE_FRAMES: usize = 6;
+/// Call chains `leaks` tells apart; the allocations of any others are only counted.
+pub const MAX_SITES: usize = 32;
+
+/// Name prefixes of the functions between an allocating function and the
+/// allocator, skipped when naming the call site.
+const ALLOCATOR_FUNCTIONS: [&str; 7] = ["alloc::", "<alloc::", "core::", "<core::", "__rust", "__rg_",
+                                        "MarOS::allocator::"];
+
+#[derive(Clone, Copy)]
+struct Record {
+    /// The checkpoint the allocation was made after, zero for free slots.
+    generation: u32,
+    size: u32,
+    align: u32,
+    /// TSC ticks.
+    time: u64,
+    frames: [u64; SITE_FRAMES],
+}
+
+struct Arena {
+    records: &'static mut [Record],
+    generation: u32,
+}
+
+impl Arena {
+    fn slot(&mut self, ptr: *mut u8) -> Option<&mut Record> {
+        let offset = (ptr as usize).checked_sub(heap_start())?;
+        self.records.get_mut(offset / GRANULE)
+    }
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// Locked with interrupts disabled, and nothing is allocated while it is held,
+/// so allocations never find it locked by their own thread.
+static ARENA: Mutex<Option<Arena>> = Mutex::new(None);
+
+/// Starts recording allocations, from checkpoint 1. The arena is allocated
+/// the first time and kept.
+pub fn start() -> Result<(), &'static str> {
+    let allocated = without_interrupts(|| ARENA.lock().is_some());
+    if !allocated {
+        let region = memory::allocate_dma((SLOTS * mem::size_of::<Record>()) as u64)?;
+        // zeroed, so every slot is free
+        let records = unsafe { core::slice::from_raw_parts_mut(region.virt.as_mut_ptr::<Record>(), SLOTS) };
+        without_interrupts(move || {
+            let mut arena = ARENA.lock();
+            if arena.is_none() {
+                *arena = Some(Arena { records, generation: 1 });
+            }
+        });
+    }
+    without_interrupts(|| {
+        if let Some(arena) = ARENA.lock().as_mut() {
+            arena.records.iter_mut().for_each(|record| record.generation = 0);
+            arena.generation = 1;
+        }
+    });
+    ENABLED.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Stops recording. The records are forgotten.
+pub fn stop() {
+    ENABLED.store(false, Ordering::SeqCst);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Starts a new generation of allocations and returns its checkpoint, or
+/// `None` if tracing is off.
+pub fn checkpoint() -> Option<u32> {
+    if !is_enabled() {
+        return None;
+    }
+    without_interrupts(|| {
+        let mut arena = ARENA.lock();
+        let arena = arena.as_mut()?;
+        arena.generation += 1;
+        Some(arena.generation)
+    })
+}
+
+/// The checkpoint new allocations belong to, or `None` if tracing is off.
+pub fn current_checkpoint() -> Option<u32> {
+    if !is_enabled() {
+        return None;
+    }
+    without_interrupts(|| ARENA.lock().as_ref().map(|arena| arena.generation))
+}
+
+pub(super) fn record_alloc(ptr: *mut u8, layout: Layout) {
+    if !is_enabled() {
+        return;
+    }
+    let backtrace = Backtrace::capture();
+    let mut frames = [0; SITE_FRAMES];
+    // the first frame is in the allocator
+    for (frame, &addr) in frames.iter_mut().zip(backtrace.frames().iter().skip(1)) {
+        *frame = addr;
+    }
+    let time = Instant::now().ticks();
+    without_interrupts(|| {
+        let mut arena = ARENA.lock();
+        if let Some(arena) = arena.as_mut() {
+            let generation = arena.generation;
+            if let Some(record) = arena.slot(ptr) {
+                *record = Record { generation, size: layout.size() as u32, align: layout.align() as u32, time, frames };
+            }
+        }
+    });
+}
+
+pub(super) fn record_free(ptr: *mut u8) {
+    if !is_enabled() {
+        return;
+    }
+    without_interrupts(|| {
+        if let Some(record) = ARENA.lock().as_mut().and_then(|arena| arena.slot(ptr)) {
+            record.generation = 0;
+        }
+    });
+}
+
+/// Live allocations made from one call chain.
+#[derive(Debug, Clone, Copy)]
+pub struct Site {
+    pub frames: [u64; SITE_FRAMES],
+    pub count: usize,
+    pub bytes: usize,
+    /// Largest alignment asked for.
+    pub align: usize,
+    /// When the oldest of the allocations was made, in TSC ticks.
+    pub oldest: u64,
+}
+
+impl Site {
+    /// The function that allocated, the first of the call chain outside of
+    /// the allocator, if a symbol table is loaded.
+    pub fn caller(&self) -> Option<Symbol> {
+        self.frames.iter()
+            .take_while(|&&addr| addr != 0)
+            .filter_map(|&addr| symbols::resolve(addr))
+            .find(|symbol| !ALLOCATOR_FUNCTIONS.iter().any(|prefix| symbol.name.starts_with(prefix)))
+    }
+}
+
+/// The live allocations of some generations, by call chain.
+pub struct Leaks {
+    sites: [Site; MAX_SITES],
+    len: usize,
+    /// Allocations and bytes of the call chains beyond `MAX_SITES`.
+    pub others: (usize, usize),
+}
+
+impl Leaks {
+    /// The call chains, most bytes first.
+    pub fn sites(&self) -> &[Site] {
+        &self.sites[..self.len]
+    }
+}
+
+/// Sums up the live allocations made between checkpoints `from` and `to`, or
+/// returns `None` if tracing is off.
+pub fn leaks(from: u32, to: u32) -> Option<Leaks> {
+    if !is_enabled() {
+        return None;
+    }
+    const EMPTY: Site = Site { frames: [0; SITE_FRAMES], count: 0, bytes: 0, align: 0, oldest: 0 };
+    let mut leaks = Leaks { sites: [EMPTY; MAX_SITES], len: 0, others: (0, 0) };
+    without_interrupts(|| {
+        let arena = ARENA.lock();
+        for record in arena.as_ref()?.records.iter() {
+            if record.generation < from.max(1) || record.generation >= to {
+                continue;
+            }
+            let (size, time) = (record.size as usize, record.time);
+            let known = leaks.sites[..leaks.len].iter_mut().find(|site| site.frames == record.frames);
+            let site = match known {
+                Some(site) => site,
+                None if leaks.len < MAX_SITES => {
+                    leaks.sites[leaks.len] = Site { frames: record.frames, oldest: time, ..EMPTY };
+                    leaks.len += 1;
+                    &mut leaks.sites[leaks.len - 1]
+                }
+                None => {
+                    leaks.others.0 += 1;
+                    leaks.others.1 += size;
+                    continue;
+                }
+            };
+            site.count += 1;
+            site.bytes += size;
+            site.align = site.align.max(record.align as usize);
+            site.oldest = site.oldest.min(time);
+        }
+        Some(())
+    })?;
+    leaks.sites[..leaks.len].sort_unstable_by(|a, b| b.bytes.cmp(&a.bytes));
+    Some(leaks)
+}
+
+#[test_case]
+fn test_leaks() {
+    use alloc::boxed::Box;
+
+    let record = |ptr: *const u8| without_interrupts(|| {
+        ARENA.lock().as_mut().and_then(|arena| arena.slot(ptr as *mut u8)).map(|record| (record.generation, record.size))
+    });
+    let was_enabled = is_enabled();
+    start().unwrap();
+    let first = checkpoint().unwrap();
+    let kept = Box::new([1u8; 40]);
+    let freed = Box::new([2u8; 24]);
+    let freed_ptr = &*freed as *const [u8; 24] as *const u8;
+    drop(freed);
+    assert_eq!(record(freed_ptr).map(|(generation, _)| generation), Some(0));
+    let second = checkpoint().unwrap();
+    let later = Box::new(3u64);
+
+    let kept_ptr = &*kept as *const [u8; 40] as *const u8;
+    assert_eq!(record(kept_ptr), Some((first, 40)));
+    assert_eq!(record(&*later as *const u64 as *const u8), Some((second, 8)));
+    let found = leaks(first, second).unwrap();
+    assert!(found.sites().iter().any(|site| site.bytes >= 40 && site.frames[0] != 0));
+    drop((kept, later));
+    assert_eq!(record(kept_ptr).map(|(generation, _)| generation), Some(0));
+
+    if !was_enabled {
+        stop();
+        assert_eq!(current_checkpoint(), None);
+    }
+}
@@ -0,0 +1,51 @@
+//! The architecture boundary: what the generic kernel needs from the CPU,
+//! implemented for the one it is built for.
+//!
+//! Generic code (memory, scheduling, time, the drivers of devices that aren't
+//! tied to the CPU) uses the facades below and nothing else of the
+//! architecture. The rest of x86_64 lives in `x86_64`: the GDT and TSS, the
+//! IDT and the PICs, the local APIC, the FPU state and port I/O, used
+//! directly only by code that is x86-specific itself, like the PC device
+//! drivers and the `syscall` entry. A port to another architecture adds a
+//! sibling of `x86_64` providing the same facade items.
+//!
+//! The page table entries and addresses are still those of the `x86_64`
+//! crate throughout the memory code.
+
+#[cfg(target_arch = "x86_64")]
+pub mod x86_64;
+
+#[cfg(target_arch = "x86_64")]
+use self::x86_64 as imp;
+
+pub use self::imp::halt;
+
+/// Masking interrupts, waiting for them, and the device interrupt lines.
+pub mod interrupts {
+    pub use super::imp::interrupts::{are_enabled, disable, enable, enable_and_hlt, in_irq, irq_counts, register_irq,
+        register_shared_irq, set_irq_masked, spurious_count, without_interrupts};
+    pub use super::imp::interrupts::nmi::{count as nmi_count, freeze as freeze_on_nmi};
+}
+
+/// The root of the page tables, and the TLB caching them.
+pub mod paging {
+    pub use super::imp::paging::{active_table, flush, flush_all, switch_table};
+}
+
+/// Thread contexts: the registers switched on the thread's stack, the stack
+/// interrupts switch to, and the lazily switched FPU state.
+pub mod context {
+    pub use super::imp::context::{init_stack, saved_rip, stack_pointer, switch};
+    pub use super::imp::fpu::{release, switch_to, FpuState};
+    pub use super::imp::gdt::set_kernel_stack;
+}
+
+/// The timer that interrupts at a deadline, for a tickless idle.
+pub mod timer {
+    pub use super::imp::apic::{clear_deadline, has_tsc_deadline as has_deadline, set_deadline};
+}
+
+/// Copying from and to user memory without trusting it to be mapped.
+pub mod usercopy {
+    pub use super::imp::usercopy::copy;
+}
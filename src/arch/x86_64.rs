@@ -0,0 +1,18 @@
+//! x86_64: the CPU tables, interrupt controllers, FPU, port I/O and user
+//! memory access of a PC, and the paravirtual interfaces of hypervisors.
+
+pub mod apic;
+pub mod context;
+pub mod fpu;
+pub mod gdt;
+pub mod hypervisor;
+pub mod interrupts;
+pub mod ioport;
+pub mod paging;
+pub mod protection;
+pub mod usercopy;
+
+/// Waits for the next interrupt.
+pub fn halt() {
+    x86_64::instructions::hlt();
+}
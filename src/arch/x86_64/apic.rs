@@ -0,0 +1,94 @@
+//! The local APIC, used for its timer in TSC-deadline mode.
+//!
+//! Device interrupts still come through the 8259 PICs, which reach the CPU
+//! through the local APIC's LINT0 input as the firmware set it up. The timer
+//! interrupts once when the TSC reaches the value written to
+//! `IA32_TSC_DEADLINE`, which lets the idle thread stop the periodic tick and
+//! sleep until the next timer is due, see `time::stop_tick`.
+
+use core::arch::x86_64::__cpuid;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Once;
+use x86_64::registers::model_specific::Msr;
+use x86_64::PhysAddr;
+use crate::mmio;
+use crate::time::Instant;
+use crate::initcall;
+
+/// Vector of the timer interrupt.
+pub const TIMER_VECTOR: u8 = 0xf0;
+/// Vector of spurious interrupts, which need no end of interrupt.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const IA32_TSC_DEADLINE: u32 = 0x6e0;
+const APIC_GLOBAL_ENABLE: u64 = 1 << 11;
+
+crate::register_block! {
+    /// The registers of the local APIC, 32 bits every 16 bytes.
+    struct Registers, size 0x1000 {
+        end_of_interrupt: u32, WriteOnly @ 0x0b0;
+        spurious: u32, ReadWrite @ 0x0f0;
+        lvt_timer: u32, ReadWrite @ 0x320;
+    }
+}
+
+const SOFTWARE_ENABLE: u32 = 1 << 8;
+const TIMER_TSC_DEADLINE: u32 = 0b10 << 17;
+
+static REGISTERS: Once<Registers> = Once::new();
+/// Whether the timer runs in TSC-deadline mode.
+static TSC_DEADLINE: AtomicBool = AtomicBool::new(false);
+
+/// Enables the local APIC and sets its timer up in TSC-deadline mode. Must be
+/// called after `memory::install`.
+pub fn init() -> Result<(), &'static str> {
+    let features = unsafe { __cpuid(1) };
+    if features.edx & (1 << 9) == 0 {
+        return Err("no local APIC");
+    }
+    let apic_base = unsafe { Msr::new(IA32_APIC_BASE).read() };
+    if apic_base & APIC_GLOBAL_ENABLE == 0 {
+        return Err("local APIC disabled by the firmware");
+    }
+    let registers = mmio::map(PhysAddr::new(apic_base & 0x000f_ffff_ffff_f000))?;
+    let registers = REGISTERS.call_once(|| registers);
+    registers.spurious().write(SOFTWARE_ENABLE | u32::from(SPURIOUS_VECTOR));
+
+    if features.ecx & (1 << 24) == 0 {
+        return Err("no TSC-deadline timer");
+    }
+    registers.lvt_timer().write(TIMER_TSC_DEADLINE | u32::from(TIMER_VECTOR));
+    TSC_DEADLINE.store(true, Ordering::Relaxed);
+    Ok(())
+}
+initcall!(Core, "apic", init, after: &["hpet"]);
+
+/// Whether `set_deadline` works.
+pub fn has_tsc_deadline() -> bool {
+    TSC_DEADLINE.load(Ordering::Relaxed)
+}
+
+/// Makes the timer interrupt once at `deadline`, right away if it has passed.
+/// Replaces the previous deadline.
+pub fn set_deadline(deadline: Instant) {
+    // zero disarms the timer
+    let mut msr = Msr::new(IA32_TSC_DEADLINE);
+    unsafe { msr.write(deadline.ticks().max(1)) };
+}
+
+/// Disarms the timer.
+pub fn clear_deadline() {
+    let mut msr = Msr::new(IA32_TSC_DEADLINE);
+    unsafe { msr.write(0) };
+}
+
+/// Ends the handling of an interrupt the local APIC delivered.
+pub fn end_of_interrupt() {
+    if super::hypervisor::pv_end_of_interrupt() {
+        return;
+    }
+    if let Some(registers) = REGISTERS.get() {
+        registers.end_of_interrupt().write(0);
+    }
+}
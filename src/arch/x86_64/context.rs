@@ -0,0 +1,94 @@
+//! Switching between kernel threads: the callee-saved registers and RFLAGS go
+//! on the stack of the thread switched from, and its stack pointer is all
+//! that is left to keep. RFLAGS holds the flag allowing user accesses under
+//! SMAP, which must not leak to another thread.
+
+use core::arch::{asm, global_asm};
+
+global_asm!(r#"
+// switches from the thread whose stack pointer is saved at [rdi] to the
+// stack pointer in rsi, saving and restoring RFLAGS and the callee-saved
+// registers
+.global arch_switch_context
+arch_switch_context:
+    pushfq
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    mov [rdi], rsp
+    mov rsp, rsi
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    popfq
+    ret
+
+// first code of a new thread, with its entry point in r13 and its argument
+// in r12
+.global arch_thread_start
+arch_thread_start:
+    mov rdi, r12
+    and rsp, -16
+    call r13
+    ud2
+"#);
+
+extern "C" {
+    fn arch_switch_context(old_rsp: *mut u64, new_rsp: u64);
+    fn arch_thread_start();
+}
+
+/// Saves the stack pointer of the current thread at `old_rsp` and resumes the
+/// thread whose stack pointer is `new_rsp`, returning once switched back to.
+///
+/// # Safety
+/// `new_rsp` must be one saved by `switch` or made by `init_stack`, of a
+/// stack still mapped.
+pub unsafe fn switch(old_rsp: *mut u64, new_rsp: u64) {
+    arch_switch_context(old_rsp, new_rsp);
+}
+
+/// Where a thread that is not running resumes: the return address of its
+/// `switch`, above the registers saved on its stack.
+///
+/// # Safety
+/// `rsp` must be one saved by `switch` or made by `init_stack`, of a stack
+/// still mapped.
+pub unsafe fn saved_rip(rsp: u64) -> u64 {
+    *(rsp as *const u64).add(7)
+}
+
+/// The stack pointer of the code calling this.
+#[inline(always)]
+pub fn stack_pointer() -> u64 {
+    let rsp: u64;
+    unsafe { asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags)) };
+    rsp
+}
+
+/// Writes the frame `switch` pops to start a thread below `stack_top`, and
+/// returns the stack pointer to switch to. The thread calls `entry(arg)`.
+///
+/// # Safety
+/// The stack below `stack_top` must be mapped and unused.
+pub unsafe fn init_stack(stack_top: u64, entry: extern "C" fn(u64) -> !, arg: u64) -> u64 {
+    let frame = [
+        0, 0, // r15, r14
+        entry as u64, // r13
+        arg, // r12
+        0, 0, // rbx, rbp
+        // RFLAGS: interrupts disabled, as by `switch`'s callers
+        0x2,
+        arch_thread_start as unsafe extern "C" fn() as u64,
+        0,
+    ];
+    let rsp = stack_top - (frame.len() * 8) as u64;
+    core::ptr::copy_nonoverlapping(frame.as_ptr(), rsp as *mut u64, frame.len());
+    rsp
+}
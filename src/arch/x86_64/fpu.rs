@@ -0,0 +1,183 @@
+use core::arch::asm;
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+use x86_64::registers::xcontrol::{XCr0, XCr0Flags};
+use crate::initcall;
+
+/// Size of a saved FPU/SSE/AVX register image.
+///
+/// Only x87, SSE and AVX state is enabled in XCR0, which needs 832 bytes in the
+/// standard XSAVE format, so a fixed buffer is enough.
+const STATE_SIZE: usize = 1024;
+
+/// Whether `xsave`/`xrstor` are used instead of `fxsave`/`fxrstor`.
+static XSAVE_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// The state whose registers are currently loaded in the FPU.
+static OWNER: AtomicPtr<FpuState> = AtomicPtr::new(null_mut());
+/// The state belonging to the code that is currently running.
+static CURRENT: AtomicPtr<FpuState> = AtomicPtr::new(null_mut());
+
+/// A saved copy of the x87/SSE/AVX registers of one task.
+#[repr(C, align(64))]
+pub struct FpuState {
+    area: [u8; STATE_SIZE],
+}
+
+impl FpuState {
+    /// Creates a state in the processor's reset configuration.
+    pub const fn new() -> Self {
+        let mut area = [0u8; STATE_SIZE];
+        // x87 control word: all exceptions masked, 64-bit precision
+        area[0] = 0x7f;
+        area[1] = 0x03;
+        // MXCSR: all SIMD exceptions masked
+        area[24] = 0x80;
+        area[25] = 0x1f;
+        FpuState { area }
+    }
+
+    /// Stores the current FPU registers into `self`.
+    fn save(&mut self) {
+        let ptr = self.area.as_mut_ptr();
+        unsafe {
+            if XSAVE_ENABLED.load(Ordering::Relaxed) {
+                asm!("xsave64 [{}]", in(reg) ptr, in("eax") u32::MAX, in("edx") u32::MAX,
+                     options(nostack));
+            } else {
+                asm!("fxsave64 [{}]", in(reg) ptr, options(nostack));
+            }
+        }
+    }
+
+    /// Loads the FPU registers from `self`.
+    fn restore(&self) {
+        let ptr = self.area.as_ptr();
+        unsafe {
+            if XSAVE_ENABLED.load(Ordering::Relaxed) {
+                asm!("xrstor64 [{}]", in(reg) ptr, in("eax") u32::MAX, in("edx") u32::MAX,
+                     options(nostack, readonly));
+            } else {
+                asm!("fxrstor64 [{}]", in(reg) ptr, options(nostack, readonly));
+            }
+        }
+    }
+}
+
+/// Enables the FPU, SSE and (when supported) AVX.
+///
+/// Clears CR0.EM and sets CR0.MP/NE so FPU instructions execute natively and
+/// raise `#NM` when CR0.TS is set, sets CR4.OSFXSR/OSXMMEXCPT, and enables
+/// XSAVE with x87|SSE|AVX state in XCR0 if the CPU supports it.
+pub fn init() {
+    unsafe {
+        Cr0::update(|flags| {
+            flags.remove(Cr0Flags::EMULATE_COPROCESSOR | Cr0Flags::TASK_SWITCHED);
+            flags.insert(Cr0Flags::MONITOR_COPROCESSOR | Cr0Flags::NUMERIC_ERROR);
+        });
+        Cr4::update(|flags| flags.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE));
+    }
+
+    let leaf1 = unsafe { __cpuid(1) };
+    let has_xsave = leaf1.ecx & (1 << 26) != 0;
+    let has_avx = leaf1.ecx & (1 << 28) != 0;
+    if has_xsave {
+        let mut xcr0 = XCr0Flags::X87 | XCr0Flags::SSE;
+        if has_avx {
+            xcr0 |= XCr0Flags::AVX;
+        }
+        unsafe {
+            Cr4::update(|flags| flags.insert(Cr4Flags::OSXSAVE));
+            XCr0::write(xcr0);
+        }
+        let required = unsafe { __cpuid_count(0xd, 0) }.ebx as usize;
+        assert!(required <= STATE_SIZE, "XSAVE area of {} bytes does not fit", required);
+        XSAVE_ENABLED.store(true, Ordering::Relaxed);
+    }
+
+    unsafe { asm!("fninit", options(nomem, nostack)) };
+}
+initcall!(Early, "fpu", || {
+    init();
+    Ok(())
+});
+
+/// Returns whether AVX state is enabled and preserved across task switches.
+pub fn avx_enabled() -> bool {
+    XSAVE_ENABLED.load(Ordering::Relaxed) && XCr0::read().contains(XCr0Flags::AVX)
+}
+
+/// Makes `state` the FPU state of the code that runs next.
+///
+/// The registers are not switched here: CR0.TS is set instead, so the first FPU
+/// instruction executed afterwards traps to `handle_device_not_available`, which
+/// saves the previous owner's registers and loads `state`. Tasks that never touch
+/// the FPU never pay for a save/restore.
+///
+/// ## Safety
+///
+/// `state` must stay valid (and must not move) until another state is switched in.
+pub unsafe fn switch_to(state: *mut FpuState) {
+    CURRENT.store(state, Ordering::SeqCst);
+    if OWNER.load(Ordering::SeqCst) == state {
+        asm!("clts", options(nomem, nostack));
+    } else {
+        Cr0::update(|flags| flags.insert(Cr0Flags::TASK_SWITCHED));
+    }
+}
+
+/// Forgets `state` if it owns the FPU, e.g. because its task exited.
+pub fn release(state: *mut FpuState) {
+    let _ = OWNER.compare_exchange(state, null_mut(), Ordering::SeqCst, Ordering::SeqCst);
+}
+
+/// Called from the `#NM` (device not available) exception handler.
+///
+/// Lazily switches the FPU registers from the previous owner to the current state.
+pub fn handle_device_not_available() {
+    unsafe { asm!("clts", options(nomem, nostack)) };
+    let current = CURRENT.load(Ordering::SeqCst);
+    let owner = OWNER.load(Ordering::SeqCst);
+    if owner == current {
+        return;
+    }
+    unsafe {
+        if let Some(owner) = owner.as_mut() {
+            owner.save();
+        }
+        if let Some(current) = current.as_ref() {
+            current.restore();
+        }
+    }
+    OWNER.store(current, Ordering::SeqCst);
+}
+
+#[test_case]
+fn test_lazy_switch_preserves_registers() {
+    fn write_xmm0(value: u64) {
+        unsafe { asm!("movq xmm0, {}", in(reg) value, options(nomem, nostack)) };
+    }
+    fn read_xmm0() -> u64 {
+        let value: u64;
+        unsafe { asm!("movq {}, xmm0", out(reg) value, options(nomem, nostack)) };
+        value
+    }
+
+    let mut a = FpuState::new();
+    let mut b = FpuState::new();
+    unsafe {
+        switch_to(&mut a);
+        write_xmm0(0xaaaa);
+        switch_to(&mut b);
+        write_xmm0(0xbbbb);
+        switch_to(&mut a);
+        assert_eq!(read_xmm0(), 0xaaaa);
+        switch_to(&mut b);
+        assert_eq!(read_xmm0(), 0xbbbb);
+        switch_to(null_mut());
+    }
+    release(&mut a);
+    release(&mut b);
+}
@@ -0,0 +1,82 @@
+use x86_64::VirtAddr;
+use lazy_static::lazy_static;
+use x86_64::instructions::segmentation::Segment;
+use x86_64::instructions::tables::load_tss;
+use x86_64::registers::segmentation::{CS, SS};
+use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
+use x86_64::structures::tss::TaskStateSegment;
+use crate::initcall;
+
+// The exceptions that get a stack of their own in the interrupt stack table,
+// as the stack they happen on may be overflowed or corrupt. Page faults
+// don't: their handler may block, and a stack overflow into the guard page
+// becomes a double fault anyway, once the CPU fails to push the frame.
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+/// NMIs can interrupt any code, even a handler in the middle of switching stacks.
+pub const NMI_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+const IST_STACKS: usize = 3;
+
+/// Bytes of each interrupt stack table stack.
+pub const IST_STACK_SIZE: usize = 4096 * 5;
+
+static mut IST_STACK: [[u8; IST_STACK_SIZE]; IST_STACKS] = [[0; IST_STACK_SIZE]; IST_STACKS];
+
+/// Mutable because the kernel stack used for entries from user mode changes
+/// with every thread switch, see `set_kernel_stack`.
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
+
+fn tss() -> &'static TaskStateSegment {
+    unsafe {
+        for index in 0..IST_STACKS {
+            let stack_start = VirtAddr::from_ptr(core::ptr::addr_of!(IST_STACK[index]));
+            TSS.interrupt_stack_table[index] = stack_start + IST_STACK_SIZE;
+        }
+        &*core::ptr::addr_of!(TSS)
+    }
+}
+
+lazy_static! {
+    static ref GDT : (GlobalDescriptorTable, Selectors) = {
+      let mut gdt = GlobalDescriptorTable::new();
+        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+        let data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
+        let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+        let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss()));
+        (gdt, Selectors{code_selector, data_selector, user_code_selector, user_data_selector, tss_selector})
+    };
+}
+
+struct Selectors {
+    code_selector: SegmentSelector,
+    data_selector: SegmentSelector,
+    user_code_selector: SegmentSelector,
+    user_data_selector: SegmentSelector,
+    tss_selector: SegmentSelector,
+}
+
+pub fn init() {
+    GDT.0.load();
+    unsafe {
+        CS::set_reg(GDT.1.code_selector);
+        SS::set_reg(GDT.1.data_selector);
+        load_tss(GDT.1.tss_selector);
+    }
+}
+initcall!(Early, "gdt", || {
+    init();
+    Ok(())
+});
+
+/// The code and stack segment selectors of user mode, with privilege level 3.
+pub fn user_selectors() -> (SegmentSelector, SegmentSelector) {
+    (GDT.1.user_code_selector, GDT.1.user_data_selector)
+}
+
+/// Sets the stack the CPU switches to on interrupts and system calls from user mode.
+pub fn set_kernel_stack(top: VirtAddr) {
+    // only the CPU reads the TSS once it is loaded, and interrupts are disabled
+    // around thread switches
+    unsafe { TSS.privilege_stack_table[0] = top };
+}
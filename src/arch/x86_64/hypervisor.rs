@@ -0,0 +1,214 @@
+//! Running under a hypervisor: which one, from the CPUID leaves it answers,
+//! and under KVM its paravirtual clock and end of interrupt.
+//!
+//! The kvmclock is a structure the host keeps up to date with how it scales
+//! the TSC to nanoseconds, which gives the TSC frequency exactly instead of
+//! measured against the PIT or the HPET, both emulated and late whenever the
+//! host is busy. With PV EOI the host tells us in a memory word when an
+//! interrupt needs no end of interrupt written to the local APIC, which saves
+//! an exit to the host per timer interrupt; `nopveoi` leaves it off.
+
+use core::arch::x86_64::__cpuid;
+use core::sync::atomic::{fence, AtomicU32, AtomicUsize, Ordering};
+use spin::Once;
+use x86_64::registers::model_specific::Msr;
+use crate::{initcall, log_info, memory, time};
+
+const CPUID_HYPERVISOR_PRESENT: u32 = 1 << 31;
+const LEAF_SIGNATURE: u32 = 0x4000_0000;
+const LEAF_KVM_FEATURES: u32 = 0x4000_0001;
+
+const KVM_FEATURE_CLOCKSOURCE2: u32 = 1 << 3;
+const KVM_FEATURE_PV_EOI: u32 = 1 << 6;
+const MSR_KVM_SYSTEM_TIME_NEW: u32 = 0x4b56_4d01;
+const MSR_KVM_PV_EOI_EN: u32 = 0x4b56_4d04;
+/// Enables what the two MSRs above point at.
+const MSR_ENABLE: u64 = 1;
+
+/// Where in the shared page the host finds the clock and the EOI word.
+const CLOCK_OFFSET: usize = 0;
+const EOI_OFFSET: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hypervisor {
+    Kvm,
+    /// QEMU emulating the CPU itself.
+    Tcg,
+    VirtualBox,
+    HyperV,
+    Vmware,
+    Xen,
+    /// Another one, with its signature.
+    Other([u8; 12]),
+}
+
+impl Hypervisor {
+    pub fn name(&self) -> &str {
+        match self {
+            Hypervisor::Kvm => "KVM",
+            Hypervisor::Tcg => "QEMU TCG",
+            Hypervisor::VirtualBox => "VirtualBox",
+            Hypervisor::HyperV => "Hyper-V",
+            Hypervisor::Vmware => "VMware",
+            Hypervisor::Xen => "Xen",
+            Hypervisor::Other(signature) => core::str::from_utf8(signature).unwrap_or("unknown"),
+        }
+    }
+}
+
+static HYPERVISOR: Once<Option<Hypervisor>> = Once::new();
+/// Virtual addresses of the kvmclock structure and the PV EOI word, 0 while off.
+static CLOCK: AtomicUsize = AtomicUsize::new(0);
+static EOI_WORD: AtomicUsize = AtomicUsize::new(0);
+
+/// The hypervisor the kernel runs under, `None` on real hardware.
+pub fn detect() -> Option<Hypervisor> {
+    *HYPERVISOR.call_once(|| {
+        if unsafe { __cpuid(1) }.ecx & CPUID_HYPERVISOR_PRESENT == 0 {
+            return None;
+        }
+        let leaf = unsafe { __cpuid(LEAF_SIGNATURE) };
+        let mut signature = [0; 12];
+        signature[0..4].copy_from_slice(&leaf.ebx.to_le_bytes());
+        signature[4..8].copy_from_slice(&leaf.ecx.to_le_bytes());
+        signature[8..12].copy_from_slice(&leaf.edx.to_le_bytes());
+        Some(match &signature {
+            b"KVMKVMKVM\0\0\0" => Hypervisor::Kvm,
+            b"TCGTCGTCGTCG" => Hypervisor::Tcg,
+            b"VBoxVBoxVBox" => Hypervisor::VirtualBox,
+            b"Microsoft Hv" => Hypervisor::HyperV,
+            b"VMwareVMware" => Hypervisor::Vmware,
+            b"XenVMMXenVMM" => Hypervisor::Xen,
+            _ => Hypervisor::Other(signature),
+        })
+    })
+}
+
+/// Logs the hypervisor and, under KVM, takes the TSC frequency from the
+/// kvmclock and turns PV EOI on. Must be called after `memory::install`, with
+/// no timers pending, and after the HPET, whose TSC frequency it replaces.
+pub fn init() -> Result<(), &'static str> {
+    let hypervisor = match detect() {
+        Some(hypervisor) => hypervisor,
+        None => return Ok(()),
+    };
+    log_info!("hypervisor: {}", hypervisor.name());
+    if hypervisor != Hypervisor::Kvm || unsafe { __cpuid(LEAF_SIGNATURE) }.eax < LEAF_KVM_FEATURES {
+        return Ok(());
+    }
+    let features = unsafe { __cpuid(LEAF_KVM_FEATURES) }.eax;
+    // shared with the host for good
+    let frame = memory::allocate_frames(1)?[0];
+    let phys = frame.start_address().as_u64();
+    let virt = (memory::physical_memory_offset() + phys).as_u64() as usize;
+
+    if features & KVM_FEATURE_CLOCKSOURCE2 != 0 {
+        unsafe { Msr::new(MSR_KVM_SYSTEM_TIME_NEW).write((phys + CLOCK_OFFSET as u64) | MSR_ENABLE) };
+        CLOCK.store(virt + CLOCK_OFFSET, Ordering::Release);
+        match clock().map(|clock| clock.tsc_frequency()) {
+            Some(hz) if hz > 0 => {
+                time::set_tsc_frequency(hz);
+                log_info!("kvmclock: TSC at {} Hz", hz);
+            }
+            _ => log_info!("kvmclock: no TSC frequency"),
+        }
+    }
+    if features & KVM_FEATURE_PV_EOI != 0 && !crate::boot::cmdline().flag("nopveoi") {
+        EOI_WORD.store(virt + EOI_OFFSET, Ordering::Release);
+        unsafe { Msr::new(MSR_KVM_PV_EOI_EN).write((phys + EOI_OFFSET as u64) | MSR_ENABLE) };
+        log_info!("kvm: PV EOI on");
+    }
+    Ok(())
+}
+initcall!(Core, "hypervisor", init, after: &["hpet"]);
+
+/// Stops the host from writing to the shared page, before handing the machine
+/// to another kernel.
+pub fn disable() {
+    if EOI_WORD.swap(0, Ordering::AcqRel) != 0 {
+        unsafe { Msr::new(MSR_KVM_PV_EOI_EN).write(0) };
+    }
+    if CLOCK.swap(0, Ordering::AcqRel) != 0 {
+        unsafe { Msr::new(MSR_KVM_SYSTEM_TIME_NEW).write(0) };
+    }
+}
+
+/// Whether the host said the interrupt being handled needs no end of
+/// interrupt, which it then considers done.
+pub fn pv_end_of_interrupt() -> bool {
+    match EOI_WORD.load(Ordering::Acquire) {
+        0 => false,
+        virt => unsafe { &*(virt as *const AtomicU32) }.fetch_and(!1, Ordering::Relaxed) & 1 != 0,
+    }
+}
+
+/// The scaling of the kvmclock structure.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Clock {
+    tsc_to_system_mul: u32,
+    tsc_shift: i8,
+}
+
+impl Clock {
+    /// The TSC frequency the scaling to nanoseconds implies: a TSC delta is
+    /// shifted by `tsc_shift`, multiplied by `tsc_to_system_mul` and divided
+    /// by 2^32.
+    fn tsc_frequency(&self) -> u64 {
+        if self.tsc_to_system_mul == 0 {
+            return 0;
+        }
+        let hz = (1_000_000_000u128 << 32) / u128::from(self.tsc_to_system_mul);
+        let hz = if self.tsc_shift < 0 {
+            hz << -self.tsc_shift
+        } else {
+            hz >> self.tsc_shift
+        };
+        hz as u64
+    }
+}
+
+/// Reads the scaling from the kvmclock structure, which the host updates with the version odd
+/// while it writes:
+///
+/// | offset | size | field |
+/// |--------|------|-------|
+/// | 0      | 4    | version |
+/// | 8      | 8    | TSC timestamp |
+/// | 16     | 8    | system time, ns |
+/// | 24     | 4    | TSC to system time multiplier |
+/// | 28     | 1    | TSC shift |
+/// | 29     | 1    | flags |
+fn clock() -> Option<Clock> {
+    let base = match CLOCK.load(Ordering::Acquire) {
+        0 => return None,
+        virt => virt as *const u8,
+    };
+    unsafe {
+        let version = &*(base as *const AtomicU32);
+        loop {
+            let before = version.load(Ordering::Acquire);
+            if before & 1 != 0 {
+                continue;
+            }
+            fence(Ordering::Acquire);
+            let clock = Clock {
+                tsc_to_system_mul: (base.add(24) as *const u32).read_volatile(),
+                tsc_shift: (base.add(28) as *const i8).read_volatile(),
+            };
+            fence(Ordering::Acquire);
+            if version.load(Ordering::Acquire) == before {
+                return Some(clock);
+            }
+        }
+    }
+}
+
+#[test_case]
+fn test_kvmclock_scaling() {
+    // what KVM sets up for a 2.5 GHz TSC: mul = 2^32 * 2^-shift * 10^9 / hz
+    let clock = Clock { tsc_to_system_mul: 3_435_973_836, tsc_shift: -1 };
+    assert!((2_499_999_990..=2_500_000_010).contains(&clock.tsc_frequency()));
+    let clock = Clock { tsc_to_system_mul: 2_147_483_648, tsc_shift: 1 };
+    assert_eq!(clock.tsc_frequency(), 1_000_000_000);
+    assert_eq!(Clock::default().tsc_frequency(), 0);
+}
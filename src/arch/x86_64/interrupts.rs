@@ -0,0 +1,467 @@
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::{PrivilegeLevel, VirtAddr};
+use super::{gdt, ioport, protection};
+use crate::{hlt_loop, initcall, println, process};
+use lazy_static::lazy_static;
+
+pub mod double_fault;
+pub mod nmi;
+
+pub use x86_64::instructions::interrupts::{are_enabled, disable, enable, enable_and_hlt, without_interrupts};
+
+/// The data port of the PS/2 controller, where the keyboard's scancodes and
+/// the mouse's packets arrive.
+static KEYBOARD_PORT: spin::Once<ioport::PortRange> = spin::Once::new();
+/// The ports of the two PICs, which `PICS` accesses by itself.
+static PIC_PORTS: spin::Once<[ioport::PortRange; 2]> = spin::Once::new();
+
+/// The data port of the PS/2 controller, shared with the mouse driver.
+pub(crate) fn ps2_data_port() -> Option<&'static ioport::PortRange> {
+    KEYBOARD_PORT.get()
+}
+
+pub fn init_idt() {
+    IDT.load();
+    KEYBOARD_PORT.call_once(|| ioport::claim(0x60, 1, "keyboard").expect("keyboard port in use"));
+    PIC_PORTS.call_once(|| [
+        ioport::claim(0x20, 2, "pic1").expect("PIC ports in use"),
+        ioport::claim(0xa0, 2, "pic2").expect("PIC ports in use"),
+    ]);
+}
+initcall!(Early, "idt", || {
+    init_idt();
+    Ok(())
+}, after: &["gdt"]);
+initcall!(Early, "pic", || {
+    unsafe { PICS.lock().initialize() };
+    Ok(())
+}, after: &["idt"]);
+
+lazy_static! {
+    static ref IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        #[cfg(not(feature = "gdbstub"))]
+        idt.breakpoint.set_handler_fn(breakpoint_handler);
+        #[cfg(not(feature = "gdbstub"))]
+        idt.debug.set_handler_fn(debug_handler);
+        #[cfg(feature = "gdbstub")]
+        crate::gdbstub::set_handlers(&mut idt);
+        idt.divide_error.set_handler_fn(divide_error_handler);
+        idt.page_fault.set_handler_fn(page_fault_handler);
+        idt.device_not_available.set_handler_fn(device_not_available_handler);
+        idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+        idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
+        unsafe {
+            idt.double_fault.set_handler_fn(double_fault_handler)
+            .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+            idt.non_maskable_interrupt.set_handler_fn(nmi::handler)
+                .set_stack_index(gdt::NMI_IST_INDEX);
+            idt.machine_check.set_handler_fn(machine_check_handler)
+                .set_stack_index(gdt::MACHINE_CHECK_IST_INDEX);
+        }
+        idt[InterruptIndex::Timer.as_usize()]
+            .set_handler_fn(timer_interrupt_handler);
+        idt[InterruptIndex::Keyboard.as_usize()]
+            .set_handler_fn(keyboard_interrupt_handler);
+        set_irq_handlers(&mut idt);
+        idt[usize::from(super::apic::TIMER_VECTOR)].set_handler_fn(apic_timer_interrupt_handler);
+        idt[usize::from(super::apic::SPURIOUS_VECTOR)].set_handler_fn(spurious_interrupt_handler);
+        unsafe {
+            idt[usize::from(process::syscall::VECTOR)]
+                .set_handler_addr(VirtAddr::new(process::syscall::process_syscall_entry as unsafe extern "C" fn() as u64))
+                .set_privilege_level(PrivilegeLevel::Ring3);
+        }
+        idt
+    };
+}
+
+#[cfg(not(feature = "gdbstub"))]
+extern "x86-interrupt" fn breakpoint_handler(
+    stack_frame: InterruptStackFrame,
+) {
+    protection::forbid_user_access();
+    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+}
+
+#[cfg(not(feature = "gdbstub"))]
+extern "x86-interrupt" fn debug_handler(mut stack_frame: InterruptStackFrame) {
+    protection::forbid_user_access();
+    use x86_64::registers::rflags::RFlags;
+
+    let backtrace = crate::debug::Backtrace::capture();
+    if crate::debug::handle_debug_exception(stack_frame.instruction_pointer.as_u64(), &backtrace) {
+        // an instruction breakpoint would fire again on return without this
+        unsafe {
+            stack_frame.as_mut().update(|frame| frame.cpu_flags |= RFlags::RESUME_FLAG.bits());
+        }
+    }
+}
+
+extern "x86-interrupt" fn page_fault_handler(
+    mut stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    protection::forbid_user_access();
+    use crate::memory::{self, fault};
+    use x86_64::registers::control::Cr3;
+
+    let addr = Cr2::read();
+    if stack_frame.code_segment & 3 == 3 {
+        crate::sched::tls::restore();
+    }
+    if process::handle_page_fault(addr, error_code) {
+        return;
+    }
+    let kind = fault::classify(addr, error_code);
+    let error = match kind {
+        FaultKind::DemandPaging | FaultKind::CopyOnWrite => match fault::service(kind, addr) {
+            Ok(()) => return,
+            Err(error) => error,
+        },
+        FaultKind::User | FaultKind::Kernel => "invalid memory access",
+    };
+    // a copy from or to user memory fails instead
+    if let Some(fixup) = super::usercopy::fixup(stack_frame.instruction_pointer.as_u64()) {
+        unsafe { stack_frame.as_mut().update(|frame| frame.instruction_pointer = VirtAddr::new(fixup)) };
+        return;
+    }
+
+    // the console thread won't run again
+    crate::console::emergency();
+    println!("EXCEPTION: PAGE FAULT ({:?}: {})", kind, error);
+    println!("Accessed address: {:?}", addr);
+    println!("Error code: {:?}", error_code);
+    match memory::mapping_of(addr) {
+        Some(mapping) => println!("Page mapping: {:?}", mapping),
+        None => println!("Page mapping: not mapped"),
+    }
+    println!("Page table root: {:?}", Cr3::read().0);
+    println!("Stack_frame {:#?}", stack_frame);
+    let dumped = crate::crashdump::write(&crate::crashdump::Crash {
+        reason: crate::crashdump::Reason::PageFault,
+        message: format_args!("page fault at {:#x} ({:?}: {})", addr.as_u64(), kind, error),
+        registers: crate::crashdump::Registers::capture().with_frame(&stack_frame, Some(error_code.bits())),
+        backtrace: &crate::debug::Backtrace::capture(),
+        fault_addr: Some(addr.as_u64()),
+    });
+    if dumped.is_ok() {
+        println!("crash dump written");
+    }
+    hlt_loop();
+}
+
+extern "x86-interrupt" fn device_not_available_handler(_stack_frame: InterruptStackFrame) {
+    protection::forbid_user_access();
+    super::fpu::handle_device_not_available();
+}
+
+/// Ends the current process as if killed by signal number `signal` if
+/// `stack_frame` was pushed on an exception in user mode. Returns if the
+/// kernel itself is at fault.
+fn kill_user_mode(stack_frame: &InterruptStackFrame, exception: &str, signal: i64) {
+    if stack_frame.code_segment & 3 == 3 {
+        crate::sched::tls::restore();
+        if let Some(process) = process::current() {
+            crate::log_info!("process {} ({}): {} at {:#x}", process.pid.0, process.name(), exception,
+                stack_frame.instruction_pointer.as_u64());
+        }
+        process::exit(128 + signal);
+    }
+}
+
+extern "x86-interrupt" fn divide_error_handler(stack_frame: InterruptStackFrame) {
+    protection::forbid_user_access();
+    kill_user_mode(&stack_frame, "divide error", 8);
+    panic!("EXCEPTION: DIVIDE ERROR (vector 0)\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: InterruptStackFrame) {
+    protection::forbid_user_access();
+    kill_user_mode(&stack_frame, "invalid opcode", 4);
+    panic!("EXCEPTION: INVALID OPCODE (vector 6)\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn general_protection_fault_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    protection::forbid_user_access();
+    kill_user_mode(&stack_frame, "general protection fault", 11);
+    panic!("EXCEPTION: GENERAL PROTECTION FAULT (vector 13, error code {:#x})\n{:#?}", error_code, stack_frame);
+}
+
+extern "x86-interrupt" fn double_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) -> ! {
+    protection::forbid_user_access();
+    double_fault::report(&stack_frame, error_code)
+}
+
+extern "x86-interrupt" fn machine_check_handler(stack_frame: InterruptStackFrame) -> ! {
+    protection::forbid_user_access();
+    crate::vga_buffer::draw_status_line(b"MACHINE CHECK, see the serial port");
+    double_fault::report_fatal("MACHINE CHECK", &stack_frame, None)
+}
+
+#[test_case]
+fn test_spurious_check() {
+    // outside their handlers, neither line is in service
+    without_interrupts(|| {
+        assert!(is_spurious(7));
+        assert!(is_spurious(15));
+    });
+}
+
+#[test_case]
+fn test_breakpoints_exception() {
+    x86_64::instructions::interrupts::int3();
+}
+
+use pic8259::ChainedPics;
+use spin;
+use x86_64::registers::control::Cr2;
+use crate::memory::fault::FaultKind;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+pub const PIC_1_OFFSET: u8 = 32;
+pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
+pub static PICS: spin::Mutex<ChainedPics> = spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) }); // range 32 - 47
+
+
+#[derive(Debug, Clone, Copy)]
+#[repr(u8)]
+pub enum InterruptIndex {
+    Timer = PIC_1_OFFSET,
+    Keyboard,
+}
+
+impl InterruptIndex {
+    fn as_u8(self) -> u8 {
+        self as u8
+    }
+    fn as_usize(self) -> usize {
+        usize::from(self.as_u8())
+    }
+}
+
+/// Interrupts taken so far on each PIC line.
+static IRQ_COUNTS: [AtomicU64; 16] = {
+    const ZERO: AtomicU64 = AtomicU64::new(0);
+    [ZERO; 16]
+};
+
+/// The number of interrupts taken so far on each PIC line.
+pub fn irq_counts() -> [u64; 16] {
+    let mut counts = [0; 16];
+    for (count, taken) in counts.iter_mut().zip(IRQ_COUNTS.iter()) {
+        *count = taken.load(Ordering::Relaxed);
+    }
+    counts
+}
+
+/// Interrupts that turned out to have no request behind them: IRQ 7 or 15
+/// raised by a PIC for a request that went away, and the local APIC's
+/// spurious vector.
+static SPURIOUS: AtomicU64 = AtomicU64::new(0);
+
+pub fn spurious_count() -> u64 {
+    SPURIOUS.load(Ordering::Relaxed)
+}
+
+/// OCW3: the next read of the command port returns the in-service register.
+const READ_ISR: u8 = 0x0b;
+/// OCW2: non-specific end of interrupt.
+const END_OF_INTERRUPT: u8 = 0x20;
+
+/// Whether IRQ 7 or 15 is spurious: a PIC raises its lowest priority line
+/// when a request goes away before it is acknowledged, without marking it
+/// in service.
+fn is_spurious(irq: u8) -> bool {
+    match PIC_PORTS.get() {
+        Some(ports) => {
+            let pic = &ports[usize::from(irq / 8)];
+            pic.write(0, READ_ISR);
+            pic.read::<u8>(0) & (1 << (irq % 8)) == 0
+        }
+        None => false,
+    }
+}
+
+/// Handlers of a line sharable by this many devices.
+const SHARED_HANDLERS: usize = 4;
+
+/// The handlers drivers registered for a PIC line.
+#[derive(Clone, Copy)]
+struct IrqLine {
+    handlers: [Option<fn()>; SHARED_HANDLERS],
+    shared: bool,
+}
+
+/// Handlers of the PIC lines that drivers registered with `register_irq`.
+static IRQ_HANDLERS: spin::Mutex<[IrqLine; 16]> =
+    spin::Mutex::new([IrqLine { handlers: [None; SHARED_HANDLERS], shared: false }; 16]);
+
+/// Routes every PIC line except the timer and the keyboard to `irq_handler`.
+fn set_irq_handlers(idt: &mut InterruptDescriptorTable) {
+    macro_rules! set {
+        ($($irq:literal),*) => {
+            $(idt[usize::from(PIC_1_OFFSET + $irq)].set_handler_fn(irq_handler::<$irq>);)*
+        };
+    }
+    set!(2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15);
+}
+
+/// Calls `handler` from the interrupt of PIC line `irq` and unmasks the line.
+/// The handler runs with interrupts disabled and must not block.
+pub fn register_irq(irq: u8, handler: fn()) -> Result<(), &'static str> {
+    add_irq_handler(irq, handler, false)
+}
+
+/// Like `register_irq`, for a line that other devices may interrupt on too,
+/// as PCI devices share the few lines they are routed to. Every handler of
+/// the line is called, and each must find out whether its device interrupted.
+pub fn register_shared_irq(irq: u8, handler: fn()) -> Result<(), &'static str> {
+    add_irq_handler(irq, handler, true)
+}
+
+fn add_irq_handler(irq: u8, handler: fn(), shared: bool) -> Result<(), &'static str> {
+    if irq >= 16 {
+        return Err("no such IRQ line");
+    }
+    if irq == 0 || irq == 1 || irq == 2 {
+        return Err("IRQ line used by the kernel");
+    }
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut lines = IRQ_HANDLERS.lock();
+        let line = &mut lines[usize::from(irq)];
+        let used = line.handlers.iter().any(Option::is_some);
+        if used && !(shared && line.shared) {
+            return Err("IRQ line already registered");
+        }
+        let slot = line.handlers.iter_mut().find(|slot| slot.is_none()).ok_or("too many devices on the IRQ line")?;
+        *slot = Some(handler);
+        line.shared = shared;
+
+        let mut pics = PICS.lock();
+        let [mut master, mut slave] = unsafe { pics.read_masks() };
+        if irq < 8 {
+            master &= !(1 << irq);
+        } else {
+            // the slave PIC is cascaded on line 2 of the master
+            master &= !(1 << 2);
+            slave &= !(1 << (irq - 8));
+        }
+        unsafe { pics.write_masks(master, slave) };
+        Ok(())
+    })
+}
+
+/// Masks or unmasks the PIC line `irq`, e.g. the timer while the CPU idles.
+pub fn set_irq_masked(irq: u8, masked: bool) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut pics = PICS.lock();
+        let [mut master, mut slave] = unsafe { pics.read_masks() };
+        let (mask, bit) = if irq < 8 { (&mut master, irq) } else { (&mut slave, irq - 8) };
+        if masked {
+            *mask |= 1 << bit;
+        } else {
+            *mask &= !(1 << bit);
+        }
+        unsafe { pics.write_masks(master, slave) };
+    });
+}
+
+extern "x86-interrupt" fn irq_handler<const IRQ: u8>(_stack_frame: InterruptStackFrame) {
+    protection::forbid_user_access();
+    if (IRQ == 7 || IRQ == 15) && is_spurious(IRQ) {
+        SPURIOUS.fetch_add(1, Ordering::Relaxed);
+        // an end of interrupt would end the one in service, if any; only the
+        // master, for which the slave's request on line 2 was real, gets one
+        if IRQ == 15 {
+            if let Some(ports) = PIC_PORTS.get() {
+                ports[0].write(0, END_OF_INTERRUPT);
+            }
+        }
+        return;
+    }
+    IRQ_COUNTS[usize::from(IRQ)].fetch_add(1, Ordering::Relaxed);
+    crate::rand::add_interrupt_entropy();
+    let line = IRQ_HANDLERS.try_lock().map(|lines| lines[usize::from(IRQ)]);
+    if let Some(line) = line {
+        IN_IRQ.store(true, Ordering::Relaxed);
+        line.handlers.iter().flatten().for_each(|handler| handler());
+        IN_IRQ.store(false, Ordering::Relaxed);
+    }
+    unsafe {
+        PICS.lock().notify_end_of_interrupt(PIC_1_OFFSET + IRQ)
+    }
+    crate::sched::preempt();
+}
+
+/// Set while a device interrupt handler runs. Interrupts are disabled in the
+/// handlers, so they never nest.
+static IN_IRQ: AtomicBool = AtomicBool::new(false);
+
+/// Whether the code calling this runs in a device interrupt handler.
+pub fn in_irq() -> bool {
+    IN_IRQ.load(Ordering::Relaxed)
+}
+
+extern "x86-interrupt" fn timer_interrupt_handler(stack_frame: InterruptStackFrame) {
+    protection::forbid_user_access();
+    // print!(".");
+    IRQ_COUNTS[0].fetch_add(1, Ordering::Relaxed);
+    timer_tick(&stack_frame);
+    unsafe {
+        PICS.lock().notify_end_of_interrupt(InterruptIndex::Timer.as_u8())
+    }
+    if stack_frame.code_segment & 3 == 3 {
+        crate::sched::tls::restore();
+        process::enforce_cpu_limit();
+    }
+    // may switch to another thread, so it comes after the end of interrupt
+    crate::sched::tick();
+}
+
+/// Comes at the deadline the idle thread armed when it stopped the periodic tick.
+extern "x86-interrupt" fn apic_timer_interrupt_handler(stack_frame: InterruptStackFrame) {
+    protection::forbid_user_access();
+    timer_tick(&stack_frame);
+    super::apic::end_of_interrupt();
+    crate::sched::tick();
+}
+
+/// The local APIC's spurious vector, which takes no end of interrupt.
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    protection::forbid_user_access();
+    SPURIOUS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// The work of a timer interrupt, before its end of interrupt.
+fn timer_tick(stack_frame: &InterruptStackFrame) {
+    crate::rand::add_interrupt_entropy();
+    crate::profiler::record_sample(stack_frame.instruction_pointer.as_u64());
+    crate::time::tick();
+    crate::watchdog::check(stack_frame);
+    crate::drivers::speaker::tick();
+    crate::statusbar::tick();
+    crate::check_test_deadline();
+}
+
+extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    protection::forbid_user_access();
+    IRQ_COUNTS[1].fetch_add(1, Ordering::Relaxed);
+    crate::rand::add_interrupt_entropy();
+    if let Some(port) = KEYBOARD_PORT.get() {
+        let scancode: u8 = port.read(0);
+        IN_IRQ.store(true, Ordering::Relaxed);
+        crate::keyboard::handle_scancode(scancode);
+        IN_IRQ.store(false, Ordering::Relaxed);
+    }
+    unsafe {
+        PICS.lock().notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8())
+    }
+    crate::sched::preempt();
+}
+
+
+
+
@@ -0,0 +1,168 @@
+//! Diagnostics for double faults, and the other exceptions the kernel can't
+//! go on after, like machine checks.
+//!
+//! A double fault means the kernel failed to handle another exception, often
+//! because its stack overflowed or its state is corrupt, so the report avoids
+//! everything that might be broken: no locks, no heap and no `core::fmt`.
+//! Numbers are converted by hand and written to COM1 by polling.
+
+use x86_64::instructions::tables::{sgdt, sidt};
+use x86_64::registers::control::{Cr2, Cr3};
+use x86_64::structures::idt::InterruptStackFrame;
+use crate::drivers::uart::{Com, RawWriter};
+use crate::klog;
+
+/// The machine state reported for a double fault.
+pub struct Report {
+    /// The exception, in capitals.
+    pub exception: &'static str,
+    pub error_code: Option<u64>,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+    pub cr2: u64,
+    pub cr3: u64,
+    pub gdt_base: u64,
+    pub gdt_limit: u16,
+    pub idt_base: u64,
+    pub idt_limit: u16,
+}
+
+impl Report {
+    pub fn capture(exception: &'static str, stack_frame: &InterruptStackFrame, error_code: Option<u64>) -> Report {
+        let gdt = sgdt();
+        let idt = sidt();
+        Report {
+            exception,
+            error_code,
+            rip: stack_frame.instruction_pointer.as_u64(),
+            cs: stack_frame.code_segment,
+            rflags: stack_frame.cpu_flags,
+            rsp: stack_frame.stack_pointer.as_u64(),
+            ss: stack_frame.stack_segment,
+            // the address of the last page fault, which may have started it
+            cr2: Cr2::read_raw(),
+            cr3: Cr3::read().0.start_address().as_u64(),
+            gdt_base: gdt.base.as_u64(),
+            gdt_limit: gdt.limit,
+            idt_base: idt.base.as_u64(),
+            idt_limit: idt.limit,
+        }
+    }
+
+    /// Writes the report to `out`, followed by the last log lines.
+    pub fn write(&self, out: &mut impl FnMut(u8)) {
+        write_str(out, self.exception);
+        if let Some(error_code) = self.error_code {
+            write_str(out, " (error code ");
+            write_hex(out, error_code);
+            out(b')');
+        }
+        out(b'\n');
+        write_field(out, "rip", self.rip);
+        write_field(out, "cs", self.cs);
+        write_field(out, "rflags", self.rflags);
+        write_field(out, "rsp", self.rsp);
+        write_field(out, "ss", self.ss);
+        write_field(out, "cr2", self.cr2);
+        write_field(out, "cr3", self.cr3);
+        write_table(out, "gdt", self.gdt_base, self.gdt_limit);
+        write_table(out, "idt", self.idt_base, self.idt_limit);
+        write_str(out, "recent log:\n");
+        klog::recent_lines(|line| {
+            write_str(out, "  ");
+            line.iter().for_each(|&byte| out(byte));
+            out(b'\n');
+        });
+    }
+}
+
+/// Reports a double fault on the serial port and halts.
+pub fn report(stack_frame: &InterruptStackFrame, error_code: u64) -> ! {
+    crate::vga_buffer::draw_status_line(b"DOUBLE FAULT, see the serial port");
+    report_fatal("DOUBLE FAULT", stack_frame, Some(error_code))
+}
+
+/// Reports `exception` on the serial port and halts.
+pub fn report_fatal(exception: &'static str, stack_frame: &InterruptStackFrame, error_code: Option<u64>) -> ! {
+    let mut serial = RawWriter(Com::Com1);
+    serial.write_byte(b'\n');
+    Report::capture(exception, stack_frame, error_code).write(&mut |byte| serial.write_byte(byte));
+    crate::hlt_loop()
+}
+
+fn write_str(out: &mut impl FnMut(u8), s: &str) {
+    s.bytes().for_each(|byte| out(byte));
+}
+
+/// Writes `value` as `0x` and 16 hex digits.
+fn write_hex(out: &mut impl FnMut(u8), value: u64) {
+    write_str(out, "0x");
+    for shift in (0..16).rev() {
+        out(b"0123456789abcdef"[(value >> (4 * shift) & 0xf) as usize]);
+    }
+}
+
+fn write_field(out: &mut impl FnMut(u8), name: &str, value: u64) {
+    write_str(out, "  ");
+    write_str(out, name);
+    (name.len()..8).for_each(|_| out(b' '));
+    write_hex(out, value);
+    out(b'\n');
+}
+
+fn write_table(out: &mut impl FnMut(u8), name: &str, base: u64, limit: u16) {
+    write_str(out, "  ");
+    write_str(out, name);
+    (name.len()..8).for_each(|_| out(b' '));
+    write_hex(out, base);
+    write_str(out, " limit ");
+    write_hex(out, u64::from(limit));
+    out(b'\n');
+}
+
+#[test_case]
+fn test_report_format() {
+    use alloc::string::String;
+    use alloc::vec::Vec;
+
+    crate::log_info!("test_report_format");
+    let report = Report {
+        exception: "DOUBLE FAULT",
+        error_code: Some(0),
+        rip: 0x20_1234,
+        cs: 8,
+        rflags: 0x202,
+        rsp: 0x4444_0000_1000,
+        ss: 0x10,
+        cr2: 0x4444_0000_0ff8,
+        cr3: 0x1000,
+        gdt_base: 0x22_0000,
+        gdt_limit: 0x37,
+        idt_base: 0x23_0000,
+        idt_limit: 0xfff,
+    };
+    let mut out = Vec::new();
+    report.write(&mut |byte| out.push(byte));
+    let out = String::from_utf8(out).unwrap();
+    let mut lines = out.lines();
+    assert_eq!(lines.next(), Some("DOUBLE FAULT (error code 0x0000000000000000)"));
+    assert_eq!(lines.next(), Some("  rip     0x0000000000201234"));
+    assert_eq!(lines.next(), Some("  cs      0x0000000000000008"));
+    assert_eq!(lines.next(), Some("  rflags  0x0000000000000202"));
+    assert_eq!(lines.next(), Some("  rsp     0x0000444400001000"));
+    assert_eq!(lines.next(), Some("  ss      0x0000000000000010"));
+    assert_eq!(lines.next(), Some("  cr2     0x0000444400000ff8"));
+    assert_eq!(lines.next(), Some("  cr3     0x0000000000001000"));
+    assert_eq!(lines.next(), Some("  gdt     0x0000000000220000 limit 0x0000000000000037"));
+    assert_eq!(lines.next(), Some("  idt     0x0000000000230000 limit 0x0000000000000fff"));
+    assert_eq!(lines.next(), Some("recent log:"));
+    assert_eq!(lines.last(), Some("  [INFO] test_report_format"));
+
+    let report = Report { exception: "MACHINE CHECK", error_code: None, ..report };
+    let mut out = Vec::new();
+    report.write(&mut |byte| out.push(byte));
+    assert!(out.starts_with(b"MACHINE CHECK\n  rip "));
+}
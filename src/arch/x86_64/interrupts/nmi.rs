@@ -0,0 +1,110 @@
+//! Non-maskable interrupts: hardware errors the chipset reports, a watchdog,
+//! or `nmi` typed into the QEMU monitor.
+//!
+//! The handler runs on its own stack and can interrupt anything, even code
+//! holding the locks of the console or the log, so like the double fault
+//! report it takes none: it counts the NMI, remembers where it hit and why,
+//! and dumps the CPU state to COM1 by polling. After `freeze`, an NMI halts
+//! the CPU it arrives on for good, which is how a panicking CPU can stop the
+//! others for a consistent crash dump, once there are others.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use x86_64::instructions::port::Port;
+use x86_64::structures::idt::InterruptStackFrame;
+use crate::drivers::uart::{Com, RawWriter};
+use super::double_fault::Report;
+
+// bits of system control port B that say why the chipset raised an NMI
+const PARITY_ERROR: u8 = 1 << 7;
+const CHANNEL_CHECK: u8 = 1 << 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Reason {
+    /// A memory parity error, or SERR# on the PCI bus.
+    ParityError,
+    /// An I/O channel check from an expansion card.
+    ChannelCheck,
+    /// None of the chipset's, like a watchdog or the QEMU monitor.
+    Unknown,
+}
+
+impl Reason {
+    fn decode(port_b: u8) -> Reason {
+        if port_b & PARITY_ERROR != 0 {
+            Reason::ParityError
+        } else if port_b & CHANNEL_CHECK != 0 {
+            Reason::ChannelCheck
+        } else {
+            Reason::Unknown
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Reason::ParityError => "memory parity error",
+            Reason::ChannelCheck => "I/O channel check",
+            Reason::Unknown => "unknown",
+        }
+    }
+}
+
+static COUNT: AtomicU64 = AtomicU64::new(0);
+static LAST_RIP: AtomicU64 = AtomicU64::new(0);
+static LAST_REASON: AtomicU8 = AtomicU8::new(Reason::Unknown as u8);
+static FREEZE: AtomicBool = AtomicBool::new(false);
+
+/// NMIs taken so far.
+pub fn count() -> u64 {
+    COUNT.load(Ordering::Relaxed)
+}
+
+/// Where the last NMI hit and why, if there was one.
+pub fn last() -> Option<(u64, Reason)> {
+    if count() == 0 {
+        return None;
+    }
+    let reason = match LAST_REASON.load(Ordering::Relaxed) {
+        0 => Reason::ParityError,
+        1 => Reason::ChannelCheck,
+        _ => Reason::Unknown,
+    };
+    Some((LAST_RIP.load(Ordering::Relaxed), reason))
+}
+
+/// Makes every NMI from now on halt the CPU it arrives on. Called when the
+/// kernel panics.
+pub fn freeze() {
+    FREEZE.store(true, Ordering::SeqCst);
+}
+
+pub(super) extern "x86-interrupt" fn handler(stack_frame: InterruptStackFrame) {
+    super::protection::forbid_user_access();
+    if FREEZE.load(Ordering::SeqCst) {
+        crate::hlt_loop();
+    }
+    // only read, so taking it from the speaker and the PIT driver is harmless
+    let reason = Reason::decode(unsafe { Port::<u8>::new(0x61).read() });
+    COUNT.fetch_add(1, Ordering::Relaxed);
+    LAST_RIP.store(stack_frame.instruction_pointer.as_u64(), Ordering::Relaxed);
+    LAST_REASON.store(reason as u8, Ordering::Relaxed);
+
+    let mut serial = RawWriter(Com::Com1);
+    let mut out = |byte| serial.write_byte(byte);
+    out(b'\n');
+    Report::capture("NMI", &stack_frame, None).write(&mut out);
+    b"  reason  ".iter().chain(reason.name().as_bytes()).chain(b"\n").for_each(|&byte| out(byte));
+}
+
+#[test_case]
+fn test_nmi() {
+    assert_eq!(Reason::decode(PARITY_ERROR | CHANNEL_CHECK), Reason::ParityError);
+    assert_eq!(Reason::decode(CHANNEL_CHECK), Reason::ChannelCheck);
+    assert_eq!(Reason::decode(0x20), Reason::Unknown);
+
+    // vector 2 raised in software takes the same path, on the same stack
+    let count = count();
+    unsafe { core::arch::asm!("int 2") };
+    assert_eq!(self::count(), count + 1);
+    assert!(last().is_some());
+}
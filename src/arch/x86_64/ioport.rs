@@ -0,0 +1,140 @@
+//! Ownership of I/O ports.
+//!
+//! A driver claims the ports of its device with `claim` before it uses them
+//! and gets a `PortRange` to access them through. Claims of overlapping ports
+//! fail, so two drivers can't drive the same device behind each other's back,
+//! and `claims` lists who owns what. Dropping a `PortRange` gives its ports
+//! back, so ports that are only needed for a while can be claimed for that
+//! long.
+
+use alloc::vec::Vec;
+use core::ops::Range;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::{Port, PortRead, PortWrite};
+
+/// Number of claims that can be held at the same time.
+const MAX_CLAIMS: usize = 32;
+
+/// Ports owned by a driver.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Claim {
+    pub start: u16,
+    pub len: u16,
+    pub owner: &'static str,
+}
+
+impl Claim {
+    fn ports(&self) -> Range<u32> {
+        u32::from(self.start)..u32::from(self.start) + u32::from(self.len)
+    }
+}
+
+static CLAIMS: Mutex<[Option<Claim>; MAX_CLAIMS]> = {
+    const FREE: Option<Claim> = None;
+    Mutex::new([FREE; MAX_CLAIMS])
+};
+
+/// Claims the `len` ports from `start` for `owner`. Fails if one of them
+/// belongs to someone else already.
+pub fn claim(start: u16, len: u16, owner: &'static str) -> Result<PortRange, &'static str> {
+    let claim = Claim { start, len, owner };
+    if len == 0 || claim.ports().end > 0x1_0000 {
+        return Err("invalid I/O port range");
+    }
+    let result = without_interrupts(|| {
+        let mut claims = CLAIMS.lock();
+        let ports = claim.ports();
+        if let Some(other) = claims.iter().flatten().find(|other| {
+            let other = other.ports();
+            other.start < ports.end && ports.start < other.end
+        }) {
+            return Err(Some(*other));
+        }
+        match claims.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => {
+                *slot = Some(claim);
+                Ok(())
+            }
+            None => Err(None),
+        }
+    });
+    match result {
+        Ok(()) => Ok(PortRange { start, len }),
+        Err(None) => Err("too many I/O port claims"),
+        Err(Some(other)) => {
+            crate::log_warn!("ioport: {} wants {:#x}-{:#x}, which {} owns", owner, start,
+                claim.ports().end - 1, other.owner);
+            Err("I/O ports claimed by another driver")
+        }
+    }
+}
+
+/// The claims held, ordered by port.
+pub fn claims() -> Vec<Claim> {
+    let mut claims: Vec<Claim> = without_interrupts(|| CLAIMS.lock().iter().flatten().copied().collect());
+    claims.sort_by_key(|claim| claim.start);
+    claims
+}
+
+/// Claimed ports, accessed by their offset from the first one.
+#[derive(Debug)]
+pub struct PortRange {
+    start: u16,
+    len: u16,
+}
+
+impl PortRange {
+    /// The first port.
+    pub fn start(&self) -> u16 {
+        self.start
+    }
+
+    /// The port at `offset`, accessed `T` at a time.
+    pub fn port<T: PortRead + PortWrite>(&self, offset: u16) -> Port<T> {
+        Port::new(self.address::<T>(offset))
+    }
+
+    pub fn read<T: PortRead>(&self, offset: u16) -> T {
+        unsafe { T::read_from_port(self.address::<T>(offset)) }
+    }
+
+    pub fn write<T: PortWrite>(&self, offset: u16, value: T) {
+        unsafe { T::write_to_port(self.address::<T>(offset), value) }
+    }
+
+    /// The port at `offset`, checking that an access of `T` stays in the range.
+    fn address<T>(&self, offset: u16) -> u16 {
+        assert!(usize::from(offset) + core::mem::size_of::<T>() <= usize::from(self.len),
+            "I/O port offset {:#x} outside of the claim", offset);
+        self.start + offset
+    }
+}
+
+impl Drop for PortRange {
+    fn drop(&mut self) {
+        let start = self.start;
+        without_interrupts(|| {
+            let mut claims = CLAIMS.lock();
+            if let Some(slot) = claims.iter_mut().find(|slot| matches!(slot, Some(claim) if claim.start == start)) {
+                *slot = None;
+            }
+        });
+    }
+}
+
+#[test_case]
+fn test_claim() {
+    // ports of the second DMA controller, which nothing uses
+    let ports = claim(0xc0, 0x10, "test").unwrap();
+    assert_eq!(claim(0xc8, 2, "other").err(), Some("I/O ports claimed by another driver"));
+    assert_eq!(claim(0xbf, 2, "other").err(), Some("I/O ports claimed by another driver"));
+    assert_eq!(claim(0xffff, 2, "other").err(), Some("invalid I/O port range"));
+    assert!(claims().iter().any(|claim| claim.owner == "test" && claim.len == 0x10));
+    let next = claim(0xd0, 1, "next").unwrap();
+    drop(ports);
+    assert!(!claims().iter().any(|claim| claim.owner == "test"));
+    let again = claim(0xc8, 2, "again").unwrap();
+    assert_eq!(again.start(), 0xc8);
+    drop(next);
+}
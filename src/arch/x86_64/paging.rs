@@ -0,0 +1,28 @@
+//! The page table root in CR3, and the TLB.
+
+use x86_64::registers::control::{Cr3, Cr3Flags};
+use x86_64::structures::paging::PhysFrame;
+use x86_64::VirtAddr;
+
+/// The frame of the level 4 table in use.
+pub fn active_table() -> PhysFrame {
+    Cr3::read().0
+}
+
+/// Makes `table` the level 4 table in use, which flushes the TLB.
+///
+/// # Safety
+/// `table` must map the kernel as the current table does.
+pub unsafe fn switch_table(table: PhysFrame) {
+    Cr3::write(table, Cr3Flags::empty());
+}
+
+/// Forgets the cached translation of `addr`.
+pub fn flush(addr: VirtAddr) {
+    x86_64::instructions::tlb::flush(addr);
+}
+
+/// Forgets the cached translations, but those of global pages.
+pub fn flush_all() {
+    x86_64::instructions::tlb::flush_all();
+}
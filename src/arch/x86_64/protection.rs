@@ -0,0 +1,78 @@
+//! The protections of CR4 the kernel turns on if the CPU has them: SMEP, so
+//! ring 0 can't execute user pages, SMAP, so it can't access them either
+//! except in the copies of `usercopy`, between `stac` and `clac`, and UMIP,
+//! so ring 3 can't read the descriptor tables with `sgdt` and friends.
+//!
+//! Ring 3 can set the access flag itself with `popf`, and neither interrupts
+//! nor exceptions clear it, so the system call gate and every handler that
+//! can interrupt user mode start with `forbid_user_access`.
+//!
+//! The options `nosmep`, `nosmap` and `noumip` leave them off, for debugging
+//! a kernel that trips over one.
+
+use core::arch::asm;
+use core::arch::x86_64::__cpuid_count;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::registers::control::{Cr4, Cr4Flags};
+use crate::{initcall, log_info};
+
+static SMAP: AtomicBool = AtomicBool::new(false);
+
+/// Turns on the protections the CPU supports and the command line allows.
+pub fn init() {
+    let leaf7 = unsafe { __cpuid_count(7, 0) };
+    let protections = [
+        ("SMEP", leaf7.ebx & (1 << 7) != 0, "nosmep", Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION),
+        ("SMAP", leaf7.ebx & (1 << 20) != 0, "nosmap", Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION),
+        ("UMIP", leaf7.ecx & (1 << 2) != 0, "noumip", Cr4Flags::USER_MODE_INSTRUCTION_PREVENTION),
+    ];
+    let cmdline = crate::boot::cmdline();
+    for &(name, supported, option, flag) in protections.iter() {
+        let state = match (supported, cmdline.flag(option)) {
+            (false, _) => "not supported",
+            (true, true) => "off",
+            (true, false) => {
+                unsafe { Cr4::update(|flags| flags.insert(flag)) };
+                "on"
+            }
+        };
+        log_info!("cpu: {} {}", name, state);
+    }
+    SMAP.store(Cr4::read().contains(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION), Ordering::Relaxed);
+}
+initcall!(Early, "protection", || {
+    init();
+    Ok(())
+});
+
+/// Whether ring 0 accesses to user pages fault outside of `allow_user_access`.
+pub fn smap_enabled() -> bool {
+    SMAP.load(Ordering::Relaxed)
+}
+
+/// Lets ring 0 access user pages until `forbid_user_access`, if SMAP is on.
+///
+/// The access flag is part of RFLAGS, which interrupts and thread switches
+/// save and restore, so it stays with the code that set it.
+#[inline(always)]
+pub fn allow_user_access() {
+    if smap_enabled() {
+        unsafe { asm!("stac", options(nomem, nostack)) };
+    }
+}
+
+#[inline(always)]
+pub fn forbid_user_access() {
+    if smap_enabled() {
+        unsafe { asm!("clac", options(nomem, nostack)) };
+    }
+}
+
+#[test_case]
+fn test_protections_match_cr4() {
+    let cr4 = Cr4::read();
+    assert_eq!(cr4.contains(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION), smap_enabled());
+    // with SMAP on, the flag has to be off again outside of the copies
+    let rflags = x86_64::registers::rflags::read();
+    assert!(!rflags.contains(x86_64::registers::rflags::RFlags::ALIGNMENT_CHECK));
+}
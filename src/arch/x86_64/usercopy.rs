@@ -0,0 +1,66 @@
+//! Copies from and to user memory that fail instead of faulting the kernel.
+//!
+//! The copy is a single `rep movsb` at a known address. When it faults on a
+//! page that can't be mapped, the page fault handler asks `fixup` where to
+//! resume, and the copy returns with `rcx` still counting the bytes it did
+//! not get to. With SMAP on, the copy is the only code allowed to access
+//! user pages, see `protection`.
+
+use core::arch::global_asm;
+use super::protection;
+
+global_asm!(r#"
+// copies rdx bytes from rsi to rdi, returning in rax those not copied
+.global arch_copy_user
+arch_copy_user:
+    mov rcx, rdx
+.global arch_copy_user_movsb
+arch_copy_user_movsb:
+    rep movsb
+.global arch_copy_user_done
+arch_copy_user_done:
+    mov rax, rcx
+    ret
+"#);
+
+extern "C" {
+    fn arch_copy_user(dst: *mut u8, src: *const u8, len: usize) -> usize;
+    // only their addresses are taken
+    static arch_copy_user_movsb: u8;
+    static arch_copy_user_done: u8;
+}
+
+/// Copies `len` bytes from `src` to `dst`, stopping at the first page fault
+/// the kernel can't resolve. Returns the number of bytes not copied.
+///
+/// ## Safety
+///
+/// The kernel memory of the two must be valid; the user memory may be
+/// anything below the end of the user half.
+pub unsafe fn copy(dst: *mut u8, src: *const u8, len: usize) -> usize {
+    protection::allow_user_access();
+    let left = arch_copy_user(dst, src, len);
+    protection::forbid_user_access();
+    left
+}
+
+/// Where code that page faulted at `rip` resumes instead of the fault being
+/// fatal, if it is the copy.
+pub fn fixup(rip: u64) -> Option<u64> {
+    let (movsb, done) = unsafe { (&arch_copy_user_movsb as *const u8 as u64, &arch_copy_user_done as *const u8 as u64) };
+    if rip == movsb { Some(done) } else { None }
+}
+
+#[test_case]
+fn test_copy_stops_at_fault() {
+    let stack = crate::sched::stack::allocate().unwrap();
+    let data = *b"copied";
+    let mut buf = [0u8; 6];
+    assert_eq!(unsafe { copy(buf.as_mut_ptr(), data.as_ptr(), data.len()) }, 0);
+    assert_eq!(buf, data);
+    // the unmapped guard page below the stack
+    let guard = (stack.bottom() - 16) as *mut u8;
+    assert_eq!(unsafe { copy(buf.as_mut_ptr(), guard, buf.len()) }, buf.len());
+    assert_eq!(unsafe { copy(guard, data.as_ptr(), data.len()) }, data.len());
+    crate::sched::stack::free(stack);
+}
@@ -0,0 +1,79 @@
+use core::ops::Range;
+use core::sync::atomic::{AtomicBool, Ordering};
+use crate::{memory, rand};
+
+/// Where the kernel heap is placed (64 GiB, 24 bits of entropy for 4 KiB pages).
+pub const HEAP_WINDOW: Range<u64> = 0x4444_4444_0000..0x4454_4444_0000;
+/// Where kernel task stacks are placed.
+pub const KERNEL_STACK_WINDOW: Range<u64> = 0x4800_0000_0000..0x4810_0000_0000;
+/// Where shared memory objects are mapped.
+pub const SHARED_MEMORY_WINDOW: Range<u64> = 0x4900_0000_0000..0x4910_0000_0000;
+/// Where a user program image is loaded. Not at the bottom of the address
+/// space, where the kernel is.
+pub const USER_IMAGE_WINDOW: Range<u64> = 0x0800_0000_0000..0x0808_0000_0000;
+/// Where anonymous user mappings (`mmap`) are placed.
+pub const USER_MMAP_WINDOW: Range<u64> = 0x1000_0000_0000..0x2000_0000_0000;
+/// Where the user stack is placed.
+pub const USER_STACK_WINDOW: Range<u64> = 0x7000_0000_0000..0x7fff_0000_0000;
+
+/// How often `random_free_base` retries after hitting an existing mapping.
+const MAX_ATTEMPTS: usize = 16;
+
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// Turns randomization on or off. When off, every region is placed at the start
+/// of its window, which makes addresses reproducible between boots for debugging.
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Returns a random `align`-aligned base address in `window` for a region of
+/// `size` bytes, or the start of the window if randomization is disabled.
+///
+/// Panics if the region does not fit into the window.
+pub fn random_base(window: Range<u64>, size: u64, align: u64) -> u64 {
+    assert!(window.start % align == 0, "window start must be aligned");
+    let room = window.end.checked_sub(window.start)
+        .and_then(|len| len.checked_sub(size))
+        .expect("region does not fit into its window");
+    if !is_enabled() {
+        return window.start;
+    }
+    let slots = room / align + 1;
+    window.start + rand::u64() % slots * align
+}
+
+/// Like `random_base`, but avoids regions that are already (partly) mapped.
+///
+/// Checks the active page tables, so `memory::init` must have been called.
+pub fn random_free_base(window: Range<u64>, size: u64, align: u64) -> Option<u64> {
+    (0..MAX_ATTEMPTS)
+        .map(|_| random_base(window.clone(), size, align))
+        .find(|&base| {
+            let mut free = true;
+            memory::walk_mappings(base..base + size, |_| free = false);
+            free
+        })
+}
+
+#[test_case]
+fn test_random_base_stays_in_window() {
+    let window = 0x1000_0000..0x2000_0000;
+    for _ in 0..100 {
+        let base = random_base(window.clone(), 0x10_0000, 0x1000);
+        assert_eq!(base % 0x1000, 0);
+        assert!(window.start <= base && base + 0x10_0000 <= window.end);
+    }
+}
+
+#[test_case]
+fn test_disabled_uses_window_start() {
+    let enabled = is_enabled();
+    set_enabled(false);
+    assert_eq!(random_base(HEAP_WINDOW, 0x1000, 0x1000), HEAP_WINDOW.start);
+    set_enabled(enabled);
+}
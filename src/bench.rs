@@ -0,0 +1,110 @@
+use core::hint::black_box;
+use core::time::Duration;
+use crate::time::Instant;
+use crate::{exit_qemu, hlt_loop, report_result, serial_print, serial_println, QemuExitCode, TestStatus, Testable};
+
+/// Minimum measured time for an adaptive benchmark run.
+const TARGET_TIME: Duration = Duration::from_millis(10);
+/// Upper bound on the iterations of an adaptive benchmark run.
+const MAX_ITERATIONS: u64 = 1 << 16;
+
+/// Runs the benchmarked closure and records how long it took.
+pub struct Bencher {
+    iterations: u64,
+    elapsed: Duration,
+}
+
+impl Bencher {
+    fn new() -> Self {
+        Bencher { iterations: 0, elapsed: Duration::from_nanos(0) }
+    }
+
+    /// Runs `f` repeatedly, doubling the iteration count until the run takes at
+    /// least `TARGET_TIME` or reaches `MAX_ITERATIONS`.
+    pub fn iter<R>(&mut self, mut f: impl FnMut() -> R) {
+        // warm up caches and lazily initialized state
+        black_box(f());
+        let mut iterations = 1;
+        loop {
+            let elapsed = measure(iterations, &mut f);
+            if elapsed >= TARGET_TIME || iterations >= MAX_ITERATIONS {
+                self.iterations = iterations;
+                self.elapsed = elapsed;
+                return;
+            }
+            iterations *= 2;
+        }
+    }
+
+    /// Runs `f` exactly `iterations` times, for operations that consume a
+    /// limited resource (e.g. physical frames).
+    pub fn iter_n<R>(&mut self, iterations: u64, mut f: impl FnMut() -> R) {
+        self.iterations = iterations;
+        self.elapsed = measure(iterations, &mut f);
+    }
+
+    pub fn ns_per_iter(&self) -> u64 {
+        if self.iterations == 0 {
+            return 0;
+        }
+        self.elapsed.as_nanos() as u64 / self.iterations
+    }
+}
+
+fn measure<R>(iterations: u64, f: &mut impl FnMut() -> R) -> Duration {
+    let start = Instant::now();
+    for _ in 0..iterations {
+        black_box(f());
+    }
+    start.elapsed()
+}
+
+/// A benchmark run by the test runner. Declare it with `bench_test!`.
+pub struct Benchmark {
+    pub name: &'static str,
+    pub func: fn(&mut Bencher),
+    /// The benchmark fails if it is slower than this, to catch regressions.
+    pub max_ns_per_iter: u64,
+}
+
+impl Testable for Benchmark {
+    fn run(&self) -> () {
+        serial_print!("{}...\t", self.name);
+        let mut bencher = Bencher::new();
+        (self.func)(&mut bencher);
+        let ns = bencher.ns_per_iter();
+        serial_print!("{:>10} ns/iter ({} iterations)\t", ns, bencher.iterations);
+        if ns > self.max_ns_per_iter {
+            serial_println!("[failed]\n");
+            serial_println!("Error: regression: {} ns/iter exceeds the limit of {} ns/iter\n",
+                ns, self.max_ns_per_iter);
+            report_result(self.name, TestStatus::Failed);
+            exit_qemu(QemuExitCode::Failed);
+            hlt_loop();
+        }
+        serial_println!("[ok]");
+        report_result(self.name, TestStatus::Passed);
+    }
+
+    fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+/// Declares a `#[test_case]` benchmark that fails above `$max_ns` ns per iteration.
+///
+/// ```ignore
+/// bench_test!(bench_box, 5_000, |b| b.iter(|| Box::new(1)));
+/// ```
+#[macro_export]
+macro_rules! bench_test {
+    ($name:ident, $max_ns:expr, $func:expr) => {
+        #[test_case]
+        #[allow(non_upper_case_globals)]
+        const $name: $crate::bench::Benchmark = $crate::bench::Benchmark {
+            name: concat!(module_path!(), "::", stringify!($name)),
+            func: $func,
+            max_ns_per_iter: $max_ns,
+        };
+    };
+}
@@ -0,0 +1,153 @@
+//! The kernel command line: whitespace-separated `key=value` options and flags.
+//!
+//! bootloader 0.9 has no way to pass a command line, so it is read from the QEMU
+//! fw_cfg file `opt/maros/cmdline`, e.g.
+//! `-fw_cfg name=opt/maros/cmdline,string="log=debug aslr=off"`.
+//! Without it, the `MAROS_CMDLINE` environment variable at build time is used.
+//!
+//! Options:
+//! - `log=error|warn|info|debug|trace`: level of the kernel log
+//! - `console=vga|serial|both`: where the kernel log is written
+//! - `aslr=off`: place the heap and other regions at fixed addresses
+//! - `hz=<rate>`: timer interrupts per second, 1000 by default
+//! - `nosmep`, `nosmap`, `noumip`: leave that CPU protection off
+//! - `nopveoi`: write every end of interrupt to the local APIC under KVM
+//! - `ide=pio`: move the sectors of IDE disks through the data port rather
+//!   than with the bus master's DMA
+//! - `virtio=legacy`: drive transitional virtio devices through their legacy
+//!   I/O registers rather than the modern ones
+//! - `init=<path>|none`: the program started as PID 1, `/bin/init` by
+//!   default, see `process::init`
+//! - `test=<pattern>`: only run the tests whose name contains `pattern`
+//! - `ip=<address>/<prefix length>`, `gateway=<address>|none`: IPv4
+//!   configuration of the first network interface, `10.0.2.15/24` and
+//!   `10.0.2.2` by default
+//! - `dns=<address>`: the DNS resolver, `10.0.2.3` by default
+//! - `telnet=<port>|off`: port of the remote shell, 23 by default
+//! - `serial_shell=com1|com2|hvc0`: also run a remote shell session on that
+//!   port, `hvc0` being the virtio console
+//! - `syslog=<address>[:<port>]`: ship the kernel log over UDP to a syslog
+//!   server, port 514 by default
+//! - `crashdump=com1|com2|<device>[@<sector>]|off`: where a dump of the kernel
+//!   goes when it crashes, see `crashdump`
+
+use spin::Once;
+use crate::boot::info::BootInformation;
+use crate::{aslr, initcall, klog, log_warn};
+
+pub mod fw_cfg;
+pub mod info;
+pub mod kexec;
+pub mod multiboot2;
+pub mod progress;
+
+const FW_CFG_FILE: &str = "opt/maros/cmdline";
+const MAX_CMDLINE: usize = 256;
+
+static CMDLINE: Once<Cmdline> = Once::new();
+static INFO: Once<BootInformation> = Once::new();
+
+/// Stores the boot information translated from the boot loader's handoff,
+/// after taking the persistent log (`klog::pstore`) out of its usable memory.
+/// Only the first call has an effect.
+pub fn set_info(mut info: BootInformation) -> &'static BootInformation {
+    INFO.call_once(|| {
+        klog::pstore::reserve(&mut info);
+        info
+    })
+}
+
+/// The boot information, once the entry point has called `set_info`.
+pub fn info() -> Option<&'static BootInformation> {
+    INFO.get()
+}
+
+/// A parsed command line. Kept in a fixed buffer so it is usable before the heap is.
+pub struct Cmdline {
+    buf: [u8; MAX_CMDLINE],
+    len: usize,
+}
+
+impl Cmdline {
+    pub fn new(line: &str) -> Self {
+        let mut cmdline = Cmdline { buf: [0; MAX_CMDLINE], len: 0 };
+        // cut at a character boundary if the line is too long
+        let mut len = line.len().min(MAX_CMDLINE);
+        while !line.is_char_boundary(len) {
+            len -= 1;
+        }
+        cmdline.buf[..len].copy_from_slice(&line.as_bytes()[..len]);
+        cmdline.len = len;
+        cmdline
+    }
+
+    pub fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.buf[..self.len]).unwrap_or("")
+    }
+
+    /// The options in order, as `(key, value)`. Flags without `=` have no value.
+    pub fn options(&self) -> impl Iterator<Item = (&str, Option<&str>)> {
+        self.as_str().split_whitespace().map(|option| match option.find('=') {
+            Some(eq) => (&option[..eq], Some(&option[eq + 1..])),
+            None => (option, None),
+        })
+    }
+
+    /// The value of the last `key=value` option.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.options().filter(|(k, _)| *k == key).filter_map(|(_, value)| value).last()
+    }
+
+    /// Whether the flag `key` is given.
+    pub fn flag(&self, key: &str) -> bool {
+        self.options().any(|(k, value)| k == key && value.is_none())
+    }
+}
+
+/// Returns the kernel command line, reading it on first use.
+pub fn cmdline() -> &'static Cmdline {
+    CMDLINE.call_once(|| {
+        let mut buf = [0; MAX_CMDLINE];
+        if let Some(len) = fw_cfg::read_file(FW_CFG_FILE, &mut buf) {
+            if let Ok(line) = core::str::from_utf8(&buf[..len]) {
+                return Cmdline::new(line.trim_end_matches('\0'));
+            }
+        }
+        Cmdline::new(option_env!("MAROS_CMDLINE").unwrap_or(""))
+    })
+}
+
+/// Applies the options that configure kernel subsystems. Called by `init`.
+pub fn init() {
+    let cmdline = cmdline();
+    if let Some(level) = cmdline.get("log") {
+        match klog::Level::parse(level) {
+            Some(level) => klog::set_level(level),
+            None => log_warn!("cmdline: unknown log level {}", level),
+        }
+    }
+    if let Some(console) = cmdline.get("console") {
+        match klog::Console::parse(console) {
+            Some(console) => klog::set_console(console),
+            None => log_warn!("cmdline: unknown console {}", console),
+        }
+    }
+    if let Some(value) = cmdline.get("aslr") {
+        aslr::set_enabled(value != "off");
+    }
+}
+initcall!(Early, "cmdline", || {
+    init();
+    Ok(())
+});
+
+#[test_case]
+fn test_cmdline_options() {
+    let cmdline = Cmdline::new("log=debug quiet aslr=on aslr=off test=");
+    assert_eq!(cmdline.get("log"), Some("debug"));
+    assert_eq!(cmdline.get("aslr"), Some("off"));
+    assert_eq!(cmdline.get("test"), Some(""));
+    assert_eq!(cmdline.get("quiet"), None);
+    assert!(cmdline.flag("quiet"));
+    assert!(!cmdline.flag("log"));
+}
@@ -0,0 +1,84 @@
+//! Minimal reader for QEMU's firmware configuration device (fw_cfg).
+//!
+//! Files are added on the QEMU command line with
+//! `-fw_cfg name=opt/<name>,string=<contents>` (or `file=<path>`).
+
+use spin::Once;
+use crate::arch::x86_64::ioport::{self, PortRange};
+
+/// The selector port, followed by the data port at `DATA`.
+const SELECTOR_PORT: u16 = 0x510;
+const DATA: u16 = 0x1;
+const SIGNATURE: u16 = 0x0000;
+const FILE_DIR: u16 = 0x0019;
+
+static PORTS: Once<PortRange> = Once::new();
+
+fn ports() -> &'static PortRange {
+    PORTS.call_once(|| ioport::claim(SELECTOR_PORT, 2, "fw_cfg").expect("fw_cfg ports in use"))
+}
+
+/// Selects the item `key` and restarts reading it from the beginning.
+fn select(key: u16) {
+    ports().write(0, key);
+}
+
+fn read_bytes(buf: &mut [u8]) {
+    let ports = ports();
+    for byte in buf {
+        *byte = ports.read(DATA);
+    }
+}
+
+fn read_be_u32() -> u32 {
+    let mut bytes = [0; 4];
+    read_bytes(&mut bytes);
+    u32::from_be_bytes(bytes)
+}
+
+/// Whether the fw_cfg device is present, i.e. we are running under QEMU.
+pub fn is_present() -> bool {
+    select(SIGNATURE);
+    let mut signature = [0; 4];
+    read_bytes(&mut signature);
+    &signature == b"QEMU"
+}
+
+/// The size and selector key of the file called `name`.
+fn find(name: &str) -> Option<(usize, u16)> {
+    if !is_present() {
+        return None;
+    }
+    select(FILE_DIR);
+    let count = read_be_u32();
+    for _ in 0..count {
+        let size = read_be_u32() as usize;
+        let mut key = [0; 2];
+        read_bytes(&mut key);
+        let mut reserved = [0; 2];
+        read_bytes(&mut reserved);
+        let mut file_name = [0; 56];
+        read_bytes(&mut file_name);
+
+        let len = file_name.iter().position(|&b| b == 0).unwrap_or(file_name.len());
+        if &file_name[..len] == name.as_bytes() {
+            return Some((size, u16::from_be_bytes(key)));
+        }
+    }
+    None
+}
+
+/// The size of the file called `name`, if there is one.
+pub fn file_size(name: &str) -> Option<usize> {
+    find(name).map(|(size, _)| size)
+}
+
+/// Reads the file called `name` into `buf`. Returns the number of bytes read,
+/// which is less than the file size if `buf` is too small.
+pub fn read_file(name: &str, buf: &mut [u8]) -> Option<usize> {
+    let (size, key) = find(name)?;
+    let len = size.min(buf.len());
+    select(key);
+    read_bytes(&mut buf[..len]);
+    Some(len)
+}
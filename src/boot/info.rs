@@ -0,0 +1,180 @@
+/// Largest number of memory regions a `BootInformation` can hold.
+pub const MAX_MEMORY_REGIONS: usize = 64;
+
+/// What a physical memory region may be used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryKind {
+    /// Free RAM, available to the frame allocator.
+    Usable,
+    /// RAM used by the kernel image, its stack or the boot page tables.
+    Kernel,
+    /// ACPI tables, usable once they have been read.
+    AcpiReclaimable,
+    /// ACPI non-volatile storage.
+    AcpiNvs,
+    BadMemory,
+    Reserved,
+}
+
+/// A range of physical memory reported by the boot loader.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryRegion {
+    /// Physical start address, page aligned for usable memory.
+    pub start: u64,
+    /// Physical end address (exclusive).
+    pub end: u64,
+    pub kind: MemoryKind,
+}
+
+impl MemoryRegion {
+    pub fn size(&self) -> u64 {
+        self.end - self.start
+    }
+}
+
+/// A linear framebuffer or text buffer set up by the boot loader.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Framebuffer {
+    /// Physical address of the first pixel or character.
+    pub addr: u64,
+    /// Bytes per row.
+    pub pitch: u32,
+    pub width: u32,
+    pub height: u32,
+    pub bits_per_pixel: u8,
+    /// Whether this is a VGA text buffer rather than a pixel framebuffer.
+    pub text: bool,
+}
+
+/// What the kernel needs to know from whichever boot loader started it.
+#[derive(Debug, Clone)]
+pub struct BootInformation {
+    /// Virtual address at which all physical memory is mapped.
+    pub physical_memory_offset: u64,
+    pub framebuffer: Option<Framebuffer>,
+    regions: [MemoryRegion; MAX_MEMORY_REGIONS],
+    region_count: usize,
+}
+
+impl BootInformation {
+    pub const fn new(physical_memory_offset: u64) -> Self {
+        const EMPTY: MemoryRegion = MemoryRegion { start: 0, end: 0, kind: MemoryKind::Reserved };
+        BootInformation {
+            physical_memory_offset,
+            framebuffer: None,
+            regions: [EMPTY; MAX_MEMORY_REGIONS],
+            region_count: 0,
+        }
+    }
+
+    /// Adds a memory region, shrinking usable regions to whole pages.
+    /// Regions beyond `MAX_MEMORY_REGIONS` are dropped.
+    pub fn add_memory_region(&mut self, mut region: MemoryRegion) {
+        if region.kind == MemoryKind::Usable {
+            region.start = (region.start + 0xfff) & !0xfff;
+            region.end &= !0xfff;
+        }
+        if region.start >= region.end || self.region_count == MAX_MEMORY_REGIONS {
+            return;
+        }
+        self.regions[self.region_count] = region;
+        self.region_count += 1;
+    }
+
+    /// Takes `size` bytes, `margin` bytes below the end of the highest usable
+    /// region under 4 GiB, out of the usable memory, so memory at the same
+    /// place on every boot is left to the caller. Returns their address.
+    pub fn reserve_high(&mut self, size: u64, margin: u64) -> Option<u64> {
+        let index = (0..self.region_count)
+            .filter(|&i| {
+                let region = &self.regions[i];
+                region.kind == MemoryKind::Usable && region.end <= 1 << 32 && region.size() >= size + margin
+            })
+            .max_by_key(|&i| self.regions[i].end)?;
+        if self.region_count + 2 > MAX_MEMORY_REGIONS {
+            return None;
+        }
+        let region = self.regions[index];
+        let start = (region.end - margin - size) & !0xfff;
+        let end = (start + size + 0xfff) & !0xfff;
+        self.regions[index].end = start;
+        self.add_memory_region(MemoryRegion { start: end, end: region.end, kind: MemoryKind::Usable });
+        self.add_memory_region(MemoryRegion { start, end, kind: MemoryKind::Reserved });
+        Some(start)
+    }
+
+    pub fn memory_regions(&self) -> &[MemoryRegion] {
+        &self.regions[..self.region_count]
+    }
+
+    /// Total size of the usable memory in bytes.
+    pub fn usable_memory(&self) -> u64 {
+        self.memory_regions()
+            .iter()
+            .filter(|region| region.kind == MemoryKind::Usable)
+            .map(MemoryRegion::size)
+            .sum()
+    }
+}
+
+impl From<&bootloader::BootInfo> for BootInformation {
+    fn from(boot_info: &bootloader::BootInfo) -> Self {
+        use bootloader::bootinfo::MemoryRegionType;
+
+        let mut info = BootInformation::new(boot_info.physical_memory_offset);
+        for region in boot_info.memory_map.iter() {
+            let kind = match region.region_type {
+                MemoryRegionType::Usable => MemoryKind::Usable,
+                MemoryRegionType::AcpiReclaimable => MemoryKind::AcpiReclaimable,
+                MemoryRegionType::AcpiNvs => MemoryKind::AcpiNvs,
+                MemoryRegionType::BadMemory => MemoryKind::BadMemory,
+                MemoryRegionType::Kernel
+                | MemoryRegionType::KernelStack
+                | MemoryRegionType::PageTable
+                | MemoryRegionType::Bootloader
+                | MemoryRegionType::BootInfo
+                | MemoryRegionType::Package => MemoryKind::Kernel,
+                _ => MemoryKind::Reserved,
+            };
+            info.add_memory_region(MemoryRegion {
+                start: region.range.start_addr(),
+                end: region.range.end_addr(),
+                kind,
+            });
+        }
+        // bootloader 0.9 always leaves the CPU in VGA text mode
+        info.framebuffer = Some(Framebuffer {
+            addr: 0xb8000,
+            pitch: 160,
+            width: 80,
+            height: 25,
+            bits_per_pixel: 16,
+            text: true,
+        });
+        info
+    }
+}
+
+#[test_case]
+fn test_usable_regions_are_page_aligned() {
+    let mut info = BootInformation::new(0);
+    info.add_memory_region(MemoryRegion { start: 0x1234, end: 0x5678, kind: MemoryKind::Usable });
+    info.add_memory_region(MemoryRegion { start: 0x100, end: 0x200, kind: MemoryKind::Usable });
+    info.add_memory_region(MemoryRegion { start: 0x100, end: 0x200, kind: MemoryKind::Reserved });
+    assert_eq!(info.memory_regions().len(), 2);
+    assert_eq!((info.memory_regions()[0].start, info.memory_regions()[0].end), (0x2000, 0x5000));
+    assert_eq!(info.usable_memory(), 0x3000);
+}
+
+#[test_case]
+fn test_reserve_high() {
+    let mut info = BootInformation::new(0);
+    info.add_memory_region(MemoryRegion { start: 0x10_0000, end: 0x80_0000, kind: MemoryKind::Usable });
+    info.add_memory_region(MemoryRegion { start: 0x100_0000, end: 0x200_0000, kind: MemoryKind::Usable });
+    info.add_memory_region(MemoryRegion { start: 0x1_0000_0000, end: 0x2_0000_0000, kind: MemoryKind::Usable });
+    assert_eq!(info.reserve_high(0x1_0000, 0x10_0000), Some(0x1ef_0000));
+    assert_eq!(info.usable_memory(), 0x70_0000 + 0xff_0000 + 0x1_0000_0000);
+    let reserved = info.memory_regions().iter().find(|region| region.kind == MemoryKind::Reserved).unwrap();
+    assert_eq!((reserved.start, reserved.end), (0x1ef_0000, 0x1f0_0000));
+    assert_eq!(info.reserve_high(0x100_0000, 0x100_0000), None);
+}
@@ -0,0 +1,373 @@
+//! Starting another kernel without going through the firmware, like Linux's
+//! kexec: `load` reads a kernel image from the VFS into memory, `exec` copies
+//! it to where it wants to be and enters it the way a Multiboot2 loader would.
+//!
+//! The image must be a kernel built with the `multiboot2` feature, such as
+//! this one. Its boot information holds the memory map this kernel booted
+//! with, so the persistent log (`klog::pstore`) ends up at the same place and
+//! `dmesg -p` in the new kernel shows this one's log.
+//!
+//! The image can't be copied to its place while this kernel runs there, so
+//! `load` stages it in frames that don't overlap it, and `exec` leaves the
+//! copying to a trampoline in one more such frame. The trampoline switches to
+//! page tables that identity map the first 4 GiB, copies the pages, leaves
+//! long mode for 32-bit protected mode without paging and jumps to the entry
+//! with the Multiboot2 magic in `eax` and the boot information in `ebx`.
+//! Everything it touches is below 4 GiB.
+
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::arch::{asm, global_asm};
+use core::convert::Infallible;
+use core::ops::Range;
+use spin::Mutex;
+use x86_64::structures::paging::{PageTableFlags, PhysFrame};
+use x86_64::VirtAddr;
+use crate::arch::{interrupts, paging, timer};
+use crate::boot::info::{MemoryKind, MemoryRegion};
+use crate::boot::multiboot2;
+use crate::drivers::pci;
+use crate::process::elf;
+use crate::{fs, klog, log_info, memory};
+
+/// The trampoline and the copy list live below this, in 32-bit reach.
+const LIMIT: u64 = 1 << 32;
+/// Pairs of a destination and a source page fitting in a page of the copy
+/// list, with room for the pair linking to the next one.
+const COPIES_PER_PAGE: usize = 4096 / 16 - 1;
+
+global_asm!(r#"
+.section .rodata
+.balign 16
+// copied to a page identity mapped in the kernel's table and entered with the
+// copy list in rdi, the boot information in rsi, the entry in rdx and the
+// table identity mapping the first 4 GiB in rcx
+.global kexec_trampoline
+kexec_trampoline:
+    mov r8, rdi
+    mov ebx, esi
+    mov r10, rdx
+    mov cr3, rcx
+    lea rsp, [rip + kexec_trampoline_stack]
+    cld
+    // pairs of destination and source pages; a destination of -1 links to
+    // the next page of the list, or ends it if the source is 0
+2:  mov rdi, [r8]
+    mov rsi, [r8 + 8]
+    add r8, 16
+    cmp rdi, -1
+    jne 3f
+    test rsi, rsi
+    jz 4f
+    mov r8, rsi
+    jmp 2b
+3:  mov ecx, 512
+    rep movsq
+    jmp 2b
+
+    // to compatibility mode, then off with paging and long mode
+4:  lea rax, [rip + kexec_trampoline_gdt]
+    mov [rip + kexec_trampoline_gdt_pointer + 2], rax
+    lgdt [rip + kexec_trampoline_gdt_pointer]
+    mov edx, r10d
+    lea rax, [rip + 5f]
+    push 0x08
+    push rax
+    retfq
+.code32
+5:  mov ax, 0x10
+    mov ds, ax
+    mov es, ax
+    mov fs, ax
+    mov gs, ax
+    mov ss, ax
+    mov eax, cr0
+    and eax, 0x7fffffff
+    mov cr0, eax
+    mov ecx, 0xc0000080
+    rdmsr
+    and eax, 0xfffffeff
+    wrmsr
+    mov eax, 0x36d76289
+    jmp edx
+.code64
+
+.balign 8
+kexec_trampoline_gdt:
+    .quad 0
+    // flat 32-bit code and data
+    .quad 0x00cf9a000000ffff
+    .quad 0x00cf92000000ffff
+kexec_trampoline_gdt_pointer:
+    .short 23
+    .quad 0
+.balign 16
+    .skip 64
+kexec_trampoline_stack:
+.global kexec_trampoline_end
+kexec_trampoline_end:
+"#);
+
+extern "C" {
+    static kexec_trampoline: u8;
+    static kexec_trampoline_end: u8;
+}
+
+/// A kernel image staged by `load`.
+struct Image {
+    path: String,
+    entry: u64,
+    /// The physical memory it is copied to.
+    target: Range<u64>,
+    /// The frame holding each of its pages, by destination.
+    pages: BTreeMap<u64, PhysFrame>,
+}
+
+impl Drop for Image {
+    fn drop(&mut self) {
+        unsafe { memory::free_frames(self.pages.values().copied()) };
+    }
+}
+
+static LOADED: Mutex<Option<Image>> = Mutex::new(None);
+
+/// Reads the kernel image at `path` and stages it for `exec`, replacing the
+/// one loaded before.
+pub fn load(path: &str) -> Result<(), &'static str> {
+    let data = fs::read_file(path).map_err(|_| "cannot read the image")?;
+    let executable = elf::parse(&data)?;
+    let entry = multiboot2::header_entry(&data)?.unwrap_or(executable.entry);
+    // the pages of every segment
+    let segments = executable.segments.iter()
+        .map(|segment| {
+            let end = segment.paddr.checked_add(segment.mem_size).and_then(|end| end.checked_add(0xfff));
+            Ok(segment.paddr & !0xfff..end.ok_or("a segment wraps around")? & !0xfff)
+        })
+        .collect::<Result<Vec<Range<u64>>, &'static str>>()?;
+    let (start, end) = segments.iter()
+        .fold((u64::MAX, 0), |(start, end), segment| (start.min(segment.start), end.max(segment.end)));
+    let target = start..end;
+    if target.start >= target.end || target.end > LIMIT || !(target.start..target.end).contains(&entry) {
+        return Err("the image must load and start below 4 GiB");
+    }
+    if !is_ram(&target) {
+        return Err("the image would load outside free memory");
+    }
+
+    let destinations: Vec<u64> = segments.into_iter()
+        .flat_map(|segment| segment.step_by(0x1000))
+        .collect::<BTreeSet<u64>>()
+        .into_iter()
+        .collect();
+    let frames = allocate_outside(destinations.len(), &target)?;
+    let image = Image { path: String::from(path), entry, target, pages: destinations.into_iter().zip(frames).collect() };
+    for segment in &executable.segments {
+        let mut bytes = &data[segment.offset..segment.offset + segment.file_size];
+        let mut addr = segment.paddr;
+        while !bytes.is_empty() {
+            let count = bytes.len().min(0x1000 - (addr & 0xfff) as usize);
+            write_phys(image.pages[&(addr & !0xfff)], (addr & 0xfff) as usize, &bytes[..count]);
+            addr += count as u64;
+            bytes = &bytes[count..];
+        }
+    }
+    log_info!("kexec: loaded {} for {:#x}-{:#x}, entry {:#x}", path, image.target.start, image.target.end, entry);
+    *LOADED.lock() = Some(image);
+    Ok(())
+}
+
+/// The path, entry and physical memory of the loaded image.
+pub fn loaded() -> Option<(String, u64, Range<u64>)> {
+    LOADED.lock().as_ref().map(|image| (image.path.clone(), image.entry, image.target.clone()))
+}
+
+/// Frees the loaded image.
+pub fn unload() -> bool {
+    LOADED.lock().take().is_some()
+}
+
+/// Hands the machine over to the loaded image. Returns only if that can't
+/// be prepared.
+pub fn exec() -> Result<Infallible, &'static str> {
+    // held until the end, the image is left alone by anybody else
+    let loaded = LOADED.lock();
+    let image = loaded.as_ref().ok_or("no kernel image loaded")?;
+    let info = boot_information(&image.target)?;
+    if info.len() > 4096 {
+        return Err("boot information too large");
+    }
+    // the trampoline, the boot information, the tables identity mapping 4 GiB
+    // (a level 4 table, a level 3 table and four level 2 tables) and the
+    // copy list
+    let list_pages = (image.pages.len() + COPIES_PER_PAGE - 1) / COPIES_PER_PAGE;
+    let mut frames = allocate_outside(8 + list_pages.max(1), &image.target)?;
+    // entered where it is in both tables, so its address must be free in ours
+    let identity = |frame: &PhysFrame| VirtAddr::new(frame.start_address().as_u64());
+    let trampoline = match frames.iter().position(|frame| memory::mapping_of(identity(frame)).is_none()) {
+        Some(index) => frames.swap_remove(index),
+        None => {
+            unsafe { memory::free_frames(frames) };
+            return Err("no free address for the trampoline");
+        }
+    };
+    if let Err(error) = memory::map_frames(identity(&trampoline), &[trampoline], PageTableFlags::WRITABLE) {
+        unsafe { memory::free_frames(frames.into_iter().chain(Some(trampoline))) };
+        return Err(error);
+    }
+    write_phys(trampoline, 0, trampoline_code());
+    write_phys(frames[0], 0, &info);
+    let level_4 = identity_map(&frames[1..7]);
+    let copies = copy_list(image, &frames[7..]);
+
+    log_info!("kexec: starting {}", image.path);
+    // devices must not write to memory the new kernel uses
+    for device in pci::enumerate() {
+        device.disable_bus_mastering();
+    }
+    interrupts::disable();
+    if timer::has_deadline() {
+        timer::clear_deadline();
+    }
+    crate::arch::x86_64::hypervisor::disable();
+    unsafe {
+        paging::switch_table(memory::kernel_page_table());
+        asm!("jmp {}", in(reg) trampoline.start_address().as_u64(), in("rdi") copies,
+             in("rsi") frames[0].start_address().as_u64(), in("rdx") image.entry, in("rcx") level_4,
+             options(noreturn));
+    }
+}
+
+/// The Multiboot2 boot information for a kernel loaded at `target`.
+fn boot_information(target: &Range<u64>) -> Result<Vec<u8>, &'static str> {
+    let info = crate::boot::info().ok_or("no boot information")?;
+    let pstore = klog::pstore::region().unwrap_or(0..0);
+    let mut regions: Vec<MemoryRegion> = info.memory_regions().iter()
+        .map(|&region| match region {
+            // reserved again at the same place by the new kernel
+            MemoryRegion { start, end, .. } if start == pstore.start && end == pstore.end =>
+                MemoryRegion { kind: MemoryKind::Usable, ..region },
+            region => region,
+        })
+        .collect();
+    // the new kernel won't know where it was loaded, so carve its image out
+    let mut carved = Vec::new();
+    for region in regions.iter_mut().filter(|region| region.kind != MemoryKind::Reserved) {
+        if region.start < target.end && target.start < region.end {
+            if region.end > target.end {
+                carved.push(MemoryRegion { start: target.end, ..*region });
+            }
+            if region.start < target.start {
+                carved.push(MemoryRegion { end: target.start, ..*region });
+            }
+            *region = MemoryRegion { start: region.start.max(target.start), end: region.end.min(target.end),
+                                     kind: MemoryKind::Reserved };
+        }
+    }
+    regions.extend(carved);
+    Ok(multiboot2::build(&regions, info.framebuffer))
+}
+
+/// Whether all of `range` is memory the kernel may use.
+fn is_ram(range: &Range<u64>) -> bool {
+    let info = match crate::boot::info() {
+        Some(info) => info,
+        None => return false,
+    };
+    (range.start..range.end).step_by(0x1000).all(|page| {
+        info.memory_regions().iter().any(|region| {
+            matches!(region.kind, MemoryKind::Usable | MemoryKind::Kernel) && region.start <= page && page + 0x1000 <= region.end
+        })
+    })
+}
+
+/// Allocates `count` zeroed frames below 4 GiB and outside `avoid`.
+fn allocate_outside(count: usize, avoid: &Range<u64>) -> Result<Vec<PhysFrame>, &'static str> {
+    let mut frames = Vec::with_capacity(count);
+    let mut rejected = Vec::new();
+    while frames.len() < count {
+        let frame = match memory::allocate_frames(1) {
+            Ok(mut frame) => frame.pop().unwrap(),
+            Err(error) => {
+                unsafe { memory::free_frames(frames.into_iter().chain(rejected)) };
+                return Err(error);
+            }
+        };
+        let addr = frame.start_address().as_u64();
+        if addr + 0x1000 > LIMIT || (addr < avoid.end && avoid.start < addr + 0x1000) {
+            rejected.push(frame);
+        } else {
+            frames.push(frame);
+        }
+    }
+    unsafe { memory::free_frames(rejected) };
+    Ok(frames)
+}
+
+/// Writes the copy list of `image` to `list` and returns its physical address.
+fn copy_list(image: &Image, list: &[PhysFrame]) -> u64 {
+    let pages: Vec<(u64, u64)> = image.pages.iter()
+        .map(|(&destination, frame)| (destination, frame.start_address().as_u64()))
+        .collect();
+    for (i, &frame) in list.iter().enumerate() {
+        let mut bytes = Vec::with_capacity(4096);
+        for &(destination, source) in pages.chunks(COPIES_PER_PAGE).nth(i).unwrap_or(&[]) {
+            bytes.extend_from_slice(&destination.to_le_bytes());
+            bytes.extend_from_slice(&source.to_le_bytes());
+        }
+        let next = list.get(i + 1).map_or(0, |next| next.start_address().as_u64());
+        bytes.extend_from_slice(&u64::MAX.to_le_bytes());
+        bytes.extend_from_slice(&next.to_le_bytes());
+        write_phys(frame, 0, &bytes);
+    }
+    list[0].start_address().as_u64()
+}
+
+/// Fills `tables` (a level 4 table, a level 3 table and four level 2 tables)
+/// to identity map the first 4 GiB with 2 MiB pages and returns the address
+/// of the level 4 table.
+fn identity_map(tables: &[PhysFrame]) -> u64 {
+    let addr = |frame: &PhysFrame| frame.start_address().as_u64();
+    let table = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let page = table | PageTableFlags::HUGE_PAGE;
+    write_phys(tables[0], 0, &(addr(&tables[1]) | table.bits()).to_le_bytes());
+    for (i, directory) in tables[2..6].iter().enumerate() {
+        write_phys(tables[1], i * 8, &(addr(directory) | table.bits()).to_le_bytes());
+        for entry in 0..512 {
+            let phys = ((i * 512 + entry) as u64) << 21;
+            write_phys(*directory, entry * 8, &(phys | page.bits()).to_le_bytes());
+        }
+    }
+    addr(&tables[0])
+}
+
+fn trampoline_code() -> &'static [u8] {
+    unsafe {
+        let start = &kexec_trampoline as *const u8;
+        let end = &kexec_trampoline_end as *const u8;
+        core::slice::from_raw_parts(start, end as usize - start as usize)
+    }
+}
+
+/// Copies `bytes` to `offset` in `frame`, through the physical memory mapping.
+fn write_phys(frame: PhysFrame, offset: usize, bytes: &[u8]) {
+    let virt = memory::physical_memory_offset() + frame.start_address().as_u64() + offset as u64;
+    unsafe { core::ptr::copy_nonoverlapping(bytes.as_ptr(), virt.as_mut_ptr::<u8>(), bytes.len()) };
+}
+
+#[test_case]
+fn test_boot_information_carves_out_the_image() {
+    let info = match crate::boot::info() {
+        Some(info) => info,
+        None => return,
+    };
+    let usable = info.memory_regions().iter()
+        .find(|region| region.kind == MemoryKind::Usable && region.size() >= 0x20_0000)
+        .unwrap();
+    let target = usable.start + 0x1000..usable.start + 0x10_0000;
+    let handoff = multiboot2::parse(&boot_information(&target).unwrap(), 0).unwrap();
+    let image = handoff.memory_regions().iter().find(|region| region.start == target.start).unwrap();
+    assert_eq!((image.end, image.kind), (target.end, MemoryKind::Reserved));
+    assert!(handoff.memory_regions().iter().any(|region| region.end == target.start && region.kind == MemoryKind::Usable));
+    assert!(trampoline_code().len() < 4096);
+}
@@ -0,0 +1,397 @@
+//! Booting from a Multiboot2 loader such as GRUB, as an alternative to the
+//! `bootloader` crate.
+//!
+//! With the `multiboot2` feature the kernel image carries a Multiboot2 header
+//! whose entry address points at `multiboot2_entry`, a 32-bit trampoline that
+//! identity maps and offset maps the first 4 GiB of physical memory (the same
+//! offset the `bootloader` crate uses), switches to long mode and calls
+//! `multiboot2_main` with the physical address of the boot information.
+//! The loader only looks for the header in the first 32 KiB of the image, so
+//! the `.multiboot2_header` section has to be linked first. Memory above 4 GiB
+//! is not mapped on this path.
+
+use alloc::vec::Vec;
+use crate::boot::info::{BootInformation, Framebuffer, MemoryKind, MemoryRegion};
+
+/// Value of `eax` when a Multiboot2 loader hands over control.
+pub const BOOTLOADER_MAGIC: u32 = 0x36d7_6289;
+/// Where the trampoline maps physical memory, same as in the `bootloader` config.
+pub const PHYSICAL_MEMORY_OFFSET: u64 = 0x0000_f000_0000_0000;
+/// Starts the Multiboot2 header of a kernel image.
+const HEADER_MAGIC: u32 = 0xe852_50d6;
+/// How far into the image a loader looks for the header.
+const HEADER_SEARCH: usize = 32 * 1024;
+
+const TAG_END: u32 = 0;
+const HEADER_TAG_ENTRY_ADDRESS: u16 = 3;
+const TAG_MEMORY_MAP: u32 = 6;
+const TAG_FRAMEBUFFER: u32 = 8;
+
+#[cfg(feature = "multiboot2")]
+core::arch::global_asm!(r#"
+.section .multiboot2_header, "a"
+.balign 8
+multiboot2_header_start:
+    .long 0xe85250d6
+    .long 0
+    .long multiboot2_header_end - multiboot2_header_start
+    .long 0x100000000 - (0xe85250d6 + (multiboot2_header_end - multiboot2_header_start))
+    // entry address tag
+    .balign 8
+    .short 3, 0
+    .long 12
+    .long multiboot2_entry
+    // end tag
+    .balign 8
+    .short 0, 0
+    .long 8
+multiboot2_header_end:
+
+.section .bss
+.balign 4096
+multiboot2_pml4:
+    .skip 4096
+multiboot2_pdpt:
+    .skip 4096
+multiboot2_pd:
+    .skip 4 * 4096
+multiboot2_stack:
+    .skip 64 * 1024
+multiboot2_stack_top:
+
+.section .rodata
+.balign 8
+multiboot2_gdt:
+    .quad 0
+    .quad 0x00af9a000000ffff
+multiboot2_gdt_pointer:
+    .short 15
+    .quad multiboot2_gdt
+
+.section .text
+.code32
+.global multiboot2_entry
+multiboot2_entry:
+    cli
+    mov $multiboot2_stack_top, %esp
+    mov %ebx, %edi
+    cmp $0x36d76289, %eax
+    jne 9f
+
+    // PML4[0] and PML4[480] (the physical memory offset) share one PDPT
+    mov $multiboot2_pdpt, %eax
+    or $3, %eax
+    mov %eax, multiboot2_pml4
+    mov %eax, multiboot2_pml4 + 480 * 8
+    // 4 page directories of 2 MiB pages for the first 4 GiB
+    xor %ecx, %ecx
+1:  mov %ecx, %eax
+    shl $12, %eax
+    add $multiboot2_pd, %eax
+    or $3, %eax
+    mov %eax, multiboot2_pdpt(, %ecx, 8)
+    inc %ecx
+    cmp $4, %ecx
+    jne 1b
+    xor %ecx, %ecx
+2:  mov %ecx, %eax
+    shl $21, %eax
+    or $0x83, %eax
+    mov %eax, multiboot2_pd(, %ecx, 8)
+    inc %ecx
+    cmp $2048, %ecx
+    jne 2b
+
+    mov $multiboot2_pml4, %eax
+    mov %eax, %cr3
+    mov %cr4, %eax
+    or $(1 << 5), %eax
+    mov %eax, %cr4
+    // long mode and no-execute enable in EFER
+    mov $0xc0000080, %ecx
+    rdmsr
+    or $((1 << 8) | (1 << 11)), %eax
+    wrmsr
+    mov %cr0, %eax
+    or $0x80000001, %eax
+    mov %eax, %cr0
+    lgdt multiboot2_gdt_pointer
+    ljmp $0x08, $multiboot2_long_mode
+9:  hlt
+    jmp 9b
+
+.code64
+multiboot2_long_mode:
+    xor %ax, %ax
+    mov %ax, %ss
+    mov %ax, %ds
+    mov %ax, %es
+    mov %ax, %fs
+    mov %ax, %gs
+    // the upper halves of the registers are undefined after the mode switch
+    mov %edi, %edi
+    call multiboot2_main
+8:  hlt
+    jmp 8b
+"#, options(att_syntax));
+
+/// Translates the Multiboot2 boot information `info` into a `BootInformation`.
+pub fn parse(info: &[u8], physical_memory_offset: u64) -> Result<BootInformation, &'static str> {
+    let total_size = read_u32(info, 0).ok_or("boot information too short")? as usize;
+    let info = info.get(..total_size).ok_or("boot information truncated")?;
+
+    let mut boot_info = BootInformation::new(physical_memory_offset);
+    let mut offset = 8;
+    loop {
+        let tag_type = read_u32(info, offset).ok_or("missing end tag")?;
+        let size = read_u32(info, offset + 4).ok_or("missing end tag")? as usize;
+        if size < 8 {
+            return Err("invalid boot information tag");
+        }
+        let tag = info.get(offset..offset + size).ok_or("tag exceeds boot information")?;
+        match tag_type {
+            TAG_END => break,
+            TAG_MEMORY_MAP => parse_memory_map(tag, &mut boot_info)?,
+            TAG_FRAMEBUFFER => boot_info.framebuffer = Some(parse_framebuffer(tag)?),
+            _ => {}
+        }
+        // tags are 8 byte aligned
+        offset += (size + 7) & !7;
+    }
+    Ok(boot_info)
+}
+
+/// Builds boot information with a memory map of `regions` and `framebuffer`,
+/// for a kernel we hand over to, see `kexec`. Usable and kernel memory are
+/// both available to it, and neighbouring regions of the same type are merged.
+pub fn build(regions: &[MemoryRegion], framebuffer: Option<Framebuffer>) -> Vec<u8> {
+    let mut entries: Vec<(u64, u64, u32)> = regions.iter()
+        .map(|region| {
+            let kind = match region.kind {
+                MemoryKind::Usable | MemoryKind::Kernel => 1,
+                MemoryKind::AcpiReclaimable => 3,
+                MemoryKind::AcpiNvs => 4,
+                MemoryKind::BadMemory => 5,
+                MemoryKind::Reserved => 2,
+            };
+            (region.start, region.end, kind)
+        })
+        .collect();
+    entries.sort_unstable();
+    entries.dedup_by(|next, previous| {
+        let merge = next.0 == previous.1 && next.2 == previous.2;
+        if merge {
+            previous.1 = next.1;
+        }
+        merge
+    });
+
+    let mut info = Vec::new();
+    let put_u32 = |info: &mut Vec<u8>, value: u32| info.extend_from_slice(&value.to_le_bytes());
+    let put_u64 = |info: &mut Vec<u8>, value: u64| info.extend_from_slice(&value.to_le_bytes());
+    // total size, patched below, and a reserved field
+    put_u64(&mut info, 0);
+    put_u32(&mut info, TAG_MEMORY_MAP);
+    put_u32(&mut info, 16 + 24 * entries.len() as u32);
+    // entry size and version
+    put_u32(&mut info, 24);
+    put_u32(&mut info, 0);
+    for &(start, end, kind) in &entries {
+        put_u64(&mut info, start);
+        put_u64(&mut info, end - start);
+        put_u32(&mut info, kind);
+        put_u32(&mut info, 0);
+    }
+    if let Some(framebuffer) = framebuffer {
+        put_u32(&mut info, TAG_FRAMEBUFFER);
+        put_u32(&mut info, 32);
+        put_u64(&mut info, framebuffer.addr);
+        put_u32(&mut info, framebuffer.pitch);
+        put_u32(&mut info, framebuffer.width);
+        put_u32(&mut info, framebuffer.height);
+        // type 1 is direct RGB color, 2 EGA text mode
+        info.extend_from_slice(&[framebuffer.bits_per_pixel, if framebuffer.text { 2 } else { 1 }, 0, 0]);
+    }
+    put_u32(&mut info, TAG_END);
+    put_u32(&mut info, 8);
+    let total_size = info.len() as u32;
+    info[..4].copy_from_slice(&total_size.to_le_bytes());
+    info
+}
+
+/// Finds the Multiboot2 header of the kernel `image` and returns the entry
+/// address it asks for, or `None` if it has no entry address tag and is
+/// entered at its ELF entry point.
+pub fn header_entry(image: &[u8]) -> Result<Option<u64>, &'static str> {
+    let search = &image[..image.len().min(HEADER_SEARCH)];
+    let start = (0..search.len()).step_by(8)
+        .find(|&offset| {
+            let words = [read_u32(search, offset), read_u32(search, offset + 4), read_u32(search, offset + 8),
+                         read_u32(search, offset + 12)];
+            match words {
+                [Some(HEADER_MAGIC), Some(0), Some(length), Some(checksum)] =>
+                    HEADER_MAGIC.wrapping_add(length).wrapping_add(checksum) == 0,
+                _ => false,
+            }
+        })
+        .ok_or("no Multiboot2 header")?;
+    let length = read_u32(search, start + 8).unwrap() as usize;
+    let header = image.get(start..start + length).ok_or("Multiboot2 header truncated")?;
+    let mut offset = 16;
+    // tags are a 16-bit type, 16 bits of flags and a 32-bit size
+    while let (Some(tag), Some(size)) = (read_u32(header, offset), read_u32(header, offset + 4)) {
+        let (kind, size) = (tag as u16, size as usize);
+        match kind {
+            0 => break,
+            HEADER_TAG_ENTRY_ADDRESS => {
+                let entry = read_u32(header, offset + 8).ok_or("entry address tag truncated")?;
+                return Ok(Some(u64::from(entry)));
+            }
+            _ => {}
+        }
+        if size < 8 {
+            return Err("invalid Multiboot2 header tag");
+        }
+        offset += (size + 7) & !7;
+    }
+    Ok(None)
+}
+
+/// Returns the boot information at physical address `addr` as a slice.
+///
+/// Unsafe because `addr` must point to valid Multiboot2 boot information that
+/// is mapped at `physical_memory_offset`.
+pub unsafe fn info_slice(addr: u64, physical_memory_offset: u64) -> &'static [u8] {
+    let ptr = (physical_memory_offset + addr) as *const u8;
+    let total_size = (ptr as *const u32).read_unaligned() as usize;
+    core::slice::from_raw_parts(ptr, total_size)
+}
+
+fn parse_memory_map(tag: &[u8], boot_info: &mut BootInformation) -> Result<(), &'static str> {
+    let entry_size = read_u32(tag, 8).ok_or("memory map tag too short")? as usize;
+    if entry_size < 24 {
+        return Err("invalid memory map entry size");
+    }
+    let entries = tag.get(16..).ok_or("memory map tag too short")?;
+    for entry in entries.chunks_exact(entry_size) {
+        let base = read_u64(entry, 0).unwrap();
+        let length = read_u64(entry, 8).unwrap();
+        let kind = match read_u32(entry, 16).unwrap() {
+            1 => MemoryKind::Usable,
+            3 => MemoryKind::AcpiReclaimable,
+            4 => MemoryKind::AcpiNvs,
+            5 => MemoryKind::BadMemory,
+            _ => MemoryKind::Reserved,
+        };
+        let end = base.checked_add(length).ok_or("memory map entry wraps around")?;
+        boot_info.add_memory_region(MemoryRegion { start: base, end, kind });
+    }
+    Ok(())
+}
+
+fn parse_framebuffer(tag: &[u8]) -> Result<Framebuffer, &'static str> {
+    let short = "framebuffer tag too short";
+    Ok(Framebuffer {
+        addr: read_u64(tag, 8).ok_or(short)?,
+        pitch: read_u32(tag, 16).ok_or(short)?,
+        width: read_u32(tag, 20).ok_or(short)?,
+        height: read_u32(tag, 24).ok_or(short)?,
+        bits_per_pixel: *tag.get(28).ok_or(short)?,
+        // type 2 is EGA text mode
+        text: *tag.get(29).ok_or(short)? == 2,
+    })
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    let bytes = bytes.get(offset..offset + 4)?;
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from(read_u32(bytes, offset)?) | u64::from(read_u32(bytes, offset + 4)?) << 32)
+}
+
+#[test_case]
+fn test_parse_boot_information() {
+    let mut info = [0u8; 112];
+    let mut put = |offset: usize, bytes: &[u8]| info[offset..offset + bytes.len()].copy_from_slice(bytes);
+    put(0, &112u32.to_le_bytes());
+    // memory map tag: two 24 byte entries
+    put(8, &TAG_MEMORY_MAP.to_le_bytes());
+    put(12, &64u32.to_le_bytes());
+    put(16, &24u32.to_le_bytes());
+    put(24, &0x10_0000u64.to_le_bytes());
+    put(32, &0x7f0_0000u64.to_le_bytes());
+    put(40, &1u32.to_le_bytes());
+    put(48, &0xfee0_0000u64.to_le_bytes());
+    put(56, &0x1000u64.to_le_bytes());
+    put(64, &2u32.to_le_bytes());
+    // framebuffer tag in EGA text mode
+    put(72, &TAG_FRAMEBUFFER.to_le_bytes());
+    put(76, &32u32.to_le_bytes());
+    put(80, &0xb8000u64.to_le_bytes());
+    put(88, &160u32.to_le_bytes());
+    put(92, &80u32.to_le_bytes());
+    put(96, &25u32.to_le_bytes());
+    put(100, &[16, 2]);
+    // end tag at 104
+    put(108, &8u32.to_le_bytes());
+
+    let boot_info = parse(&info, PHYSICAL_MEMORY_OFFSET).unwrap();
+    assert_eq!(boot_info.memory_regions().len(), 2);
+    assert_eq!(boot_info.usable_memory(), 0x7f0_0000);
+    assert_eq!(boot_info.memory_regions()[1].kind, MemoryKind::Reserved);
+    assert_eq!(boot_info.framebuffer.unwrap().addr, 0xb8000);
+    assert!(boot_info.framebuffer.unwrap().text);
+}
+
+#[test_case]
+fn test_reject_malformed_boot_information() {
+    fn put(info: &mut [u8], offset: usize, bytes: &[u8]) {
+        info[offset..offset + bytes.len()].copy_from_slice(bytes);
+    }
+    let mut info = [0u8; 56];
+    put(&mut info, 0, &56u32.to_le_bytes());
+    // a tag of size 0 would be read again and again
+    put(&mut info, 8, &TAG_MEMORY_MAP.to_le_bytes());
+    assert!(parse(&info, PHYSICAL_MEMORY_OFFSET).is_err());
+    // a memory map tag too short for its header
+    put(&mut info, 12, &12u32.to_le_bytes());
+    put(&mut info, 16, &24u32.to_le_bytes());
+    assert!(parse(&info, PHYSICAL_MEMORY_OFFSET).is_err());
+    // an entry ending past the top of the address space
+    put(&mut info, 12, &40u32.to_le_bytes());
+    put(&mut info, 24, &u64::MAX.to_le_bytes());
+    put(&mut info, 32, &2u64.to_le_bytes());
+    // end tag at 48
+    put(&mut info, 52, &8u32.to_le_bytes());
+    assert_eq!(parse(&info, PHYSICAL_MEMORY_OFFSET).err(), Some("memory map entry wraps around"));
+}
+
+#[test_case]
+fn test_build_boot_information() {
+    let regions = [
+        MemoryRegion { start: 0x10_0000, end: 0x40_0000, kind: MemoryKind::Kernel },
+        MemoryRegion { start: 0x40_0000, end: 0x7f0_0000, kind: MemoryKind::Usable },
+        MemoryRegion { start: 0x7f0_0000, end: 0x800_0000, kind: MemoryKind::Reserved },
+        MemoryRegion { start: 0, end: 0x9_f000, kind: MemoryKind::Usable },
+    ];
+    let framebuffer = Framebuffer { addr: 0xb8000, pitch: 160, width: 80, height: 25, bits_per_pixel: 16, text: true };
+    let info = build(&regions, Some(framebuffer));
+    let boot_info = parse(&info, PHYSICAL_MEMORY_OFFSET).unwrap();
+    assert_eq!(boot_info.memory_regions(), [
+        MemoryRegion { start: 0, end: 0x9_f000, kind: MemoryKind::Usable },
+        MemoryRegion { start: 0x10_0000, end: 0x7f0_0000, kind: MemoryKind::Usable },
+        MemoryRegion { start: 0x7f0_0000, end: 0x800_0000, kind: MemoryKind::Reserved },
+    ]);
+    assert_eq!(boot_info.framebuffer, Some(framebuffer));
+
+    let mut image = alloc::vec![0u8; 0x2000];
+    // an entry address tag, padded to 8 bytes, and the end tag
+    let header: [u32; 10] = [HEADER_MAGIC, 0, 40, 0u32.wrapping_sub(HEADER_MAGIC + 40), 3, 12, 0x10_0123, 0, 0, 8];
+    for (i, word) in header.iter().enumerate() {
+        image[0x1000 + i * 4..][..4].copy_from_slice(&word.to_le_bytes());
+    }
+    assert_eq!(header_entry(&image), Ok(Some(0x10_0123)));
+    assert!(header_entry(&image[..0x1000]).is_err());
+}
@@ -0,0 +1,72 @@
+//! Boot progress: the kernel comes up in stages, each run with `stage` or
+//! `try_stage`, which time it and log whether it went OK or FAILed.
+//!
+//! While a stage runs the status bar names it, so a boot that hangs shows
+//! where it stopped instead of looking like one that is merely slow. Usable
+//! before the heap, and before the TSC is calibrated, when stages take no
+//! time as far as the log can tell.
+
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::time::Duration;
+use crate::time::Instant;
+use crate::{log_info, log_warn, statusbar};
+
+static STAGES: AtomicUsize = AtomicUsize::new(0);
+static FAILED: AtomicUsize = AtomicUsize::new(0);
+
+/// Runs the boot stage `name`, which can't fail.
+pub fn stage<T>(name: &'static str, init: impl FnOnce() -> T) -> T {
+    let result: Result<T, &str> = try_stage(name, || Ok(init()));
+    result.unwrap_or_else(|_| unreachable!())
+}
+
+/// Runs the boot stage `name` and returns what it returns. The caller decides
+/// whether booting can go on after a failure.
+pub fn try_stage<T, E: fmt::Display>(name: &'static str, init: impl FnOnce() -> Result<T, E>) -> Result<T, E> {
+    statusbar::set_boot_stage(Some(name));
+    let start = Instant::now();
+    let result = init();
+    let elapsed = Instant::now().duration_since(start);
+    statusbar::set_boot_stage(None);
+    STAGES.fetch_add(1, Ordering::Relaxed);
+    match &result {
+        Ok(_) => log_info!("boot: {:<14} OK   {}", name, Millis(elapsed)),
+        Err(error) => {
+            FAILED.fetch_add(1, Ordering::Relaxed);
+            log_warn!("boot: {:<14} FAIL {}: {}", name, Millis(elapsed), error);
+        }
+    }
+    result
+}
+
+/// Logs that the boot stage `name` doesn't run, and why.
+pub fn skip(name: &'static str, reason: fmt::Arguments) {
+    STAGES.fetch_add(1, Ordering::Relaxed);
+    log_warn!("boot: {:<14} SKIP {}", name, reason);
+}
+
+/// Logs how long booting took and how many stages failed.
+pub fn finish() {
+    let (stages, failed) = (STAGES.load(Ordering::Relaxed), FAILED.load(Ordering::Relaxed));
+    log_info!("boot: {} stages in {}, {} failed", stages, Millis(crate::time::uptime()), failed);
+}
+
+/// A duration in milliseconds, to the microsecond.
+struct Millis(Duration);
+
+impl fmt::Display for Millis {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let micros = self.0.as_micros();
+        write!(f, "{:>4}.{:03} ms", micros / 1000, micros % 1000)
+    }
+}
+
+#[test_case]
+fn test_stages() {
+    assert_eq!(stage("test", || 42), 42);
+    let failed = FAILED.load(Ordering::Relaxed);
+    assert_eq!(try_stage::<(), _>("test", || Err("expected failure")), Err("expected failure"));
+    assert_eq!(FAILED.load(Ordering::Relaxed), failed + 1);
+    assert_eq!(alloc::format!("{}", Millis(Duration::from_micros(12_345))), "  12.345 ms");
+}
@@ -0,0 +1,363 @@
+//! The console output path behind `print!`.
+//!
+//! Printing doesn't write to the screen itself: the text is queued in a
+//! lock-free ring, which the console thread copies to `WRITER`. So printing
+//! never waits for a lock, not even in an interrupt handler that interrupted
+//! the holder of `WRITER`. Before the thread runs, when the ring is full and
+//! after `emergency`, text is written synchronously instead. Text written to
+//! the screen also goes to the remote shell sessions, see `shell::remote`.
+//!
+//! `screenshot` turns what the screen shows back into text, for reporting
+//! rendering bugs.
+//!
+//! Lines typed on the keyboard go to the kernel shell, or once init started,
+//! to the user programs reading `/dev/console`; the `switch-console` binding
+//! moves them between the two.
+
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use core::cell::UnsafeCell;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+use spin::Mutex;
+use crate::arch::interrupts::without_interrupts;
+use crate::sched::{self, Priority, WaitQueue};
+use crate::vga_buffer::{self, cp437, Color, Writer, BUFFER_HEIGHT, BUFFER_WIDTH, WRITER};
+use crate::{initcall, print, serial_print, shell};
+
+const SLOTS: usize = 256;
+/// Bytes typed for user space that it didn't read yet. Further lines are dropped.
+const INPUT_LIMIT: usize = 4096;
+/// Bytes of text per slot; longer text takes consecutive slots.
+const SLOT_SIZE: usize = 63;
+/// Text formatted at once before it is queued.
+const CHUNK_SIZE: usize = 4 * SLOT_SIZE;
+
+struct Slot {
+    /// Set by the writer once the text is in place, cleared by the reader.
+    ready: AtomicBool,
+    len: AtomicU8,
+    data: UnsafeCell<[u8; SLOT_SIZE]>,
+}
+
+impl Slot {
+    const EMPTY: Slot = Slot { ready: AtomicBool::new(false), len: AtomicU8::new(0), data: UnsafeCell::new([0; SLOT_SIZE]) };
+}
+
+/// A ring of slots with any number of writers and one reader at a time.
+///
+/// Writers claim consecutive slots by advancing `head`, fill them and mark
+/// them ready. The reader takes ready slots at `tail` in order, so a slot
+/// claimed but not filled yet holds back the ones after it.
+struct Ring {
+    slots: [Slot; SLOTS],
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+
+// slot data is only accessed by the writer that claimed it, and by the reader
+// once it is ready
+unsafe impl Sync for Ring {}
+
+impl Ring {
+    /// Queues `bytes`. Returns false, queueing nothing, if there is no room.
+    fn push(&self, bytes: &[u8]) -> bool {
+        let count = (bytes.len() + SLOT_SIZE - 1) / SLOT_SIZE;
+        let mut head = self.head.load(Ordering::Acquire);
+        loop {
+            if head + count - self.tail.load(Ordering::Acquire) > SLOTS {
+                return false;
+            }
+            match self.head.compare_exchange_weak(head, head + count, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => break,
+                Err(current) => head = current,
+            }
+        }
+        for (i, chunk) in bytes.chunks(SLOT_SIZE).enumerate() {
+            let slot = &self.slots[(head + i) % SLOTS];
+            let data = unsafe { &mut *slot.data.get() };
+            data[..chunk.len()].copy_from_slice(chunk);
+            slot.len.store(chunk.len() as u8, Ordering::Relaxed);
+            slot.ready.store(true, Ordering::Release);
+        }
+        true
+    }
+
+    /// Passes the queued text to `f` in order. Callers must make sure that
+    /// only one of them reads at a time.
+    fn pop_all(&self, mut f: impl FnMut(&[u8])) {
+        loop {
+            let tail = self.tail.load(Ordering::Relaxed);
+            let slot = &self.slots[tail % SLOTS];
+            if !slot.ready.load(Ordering::Acquire) {
+                return;
+            }
+            let len = usize::from(slot.len.load(Ordering::Relaxed));
+            let data = unsafe { &*slot.data.get() };
+            f(&data[..len]);
+            slot.ready.store(false, Ordering::Relaxed);
+            self.tail.store(tail + 1, Ordering::Release);
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.tail.load(Ordering::Acquire) == self.head.load(Ordering::Acquire)
+    }
+}
+
+static RING: Ring = Ring { slots: [Slot::EMPTY; SLOTS], head: AtomicUsize::new(0), tail: AtomicUsize::new(0) };
+static RUNNING: AtomicBool = AtomicBool::new(false);
+static EMERGENCY: AtomicBool = AtomicBool::new(false);
+/// Bytes lost because the ring was full while `WRITER` was busy.
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+static WAKEUP: WaitQueue = WaitQueue::new();
+/// Whether typed lines go to user space rather than to the kernel shell.
+static USER_INPUT: AtomicBool = AtomicBool::new(false);
+/// Typed lines for user space, only locked with interrupts disabled.
+static INPUT: Mutex<VecDeque<u8>> = Mutex::new(VecDeque::new());
+static INPUT_READY: WaitQueue = WaitQueue::new();
+
+/// Starts the console thread. Must be called after `sched::init`.
+pub fn init() -> Result<(), &'static str> {
+    sched::spawn_with_priority("console", Priority::Normal, || loop {
+        WAKEUP.wait_until(|| !RING.is_empty());
+        flush();
+    })?;
+    RUNNING.store(true, Ordering::Release);
+    Ok(())
+}
+initcall!(Core, "console", init, needs: &["sched"], required: true);
+
+/// Prints `args` to the screen, see the module documentation.
+pub fn print(args: fmt::Arguments) {
+    if !RUNNING.load(Ordering::Acquire) || EMERGENCY.load(Ordering::Acquire) {
+        without_interrupts(|| {
+            let mut writer = WRITER.lock();
+            drain(&mut writer);
+            writer.write_fmt(args).unwrap();
+        });
+        return;
+    }
+    let mut chunk = Chunk { data: [0; CHUNK_SIZE], len: 0 };
+    chunk.write_fmt(args).unwrap();
+    chunk.queue();
+}
+
+/// Writes the queued text to the screen now.
+pub fn flush() {
+    without_interrupts(|| drain(&mut WRITER.lock()));
+}
+
+/// Bytes of output lost so far because the console could not keep up.
+pub fn dropped() -> usize {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// Switches to synchronous output for good, for panics and fatal exceptions,
+/// after which the console thread may never run again. Breaks the `WRITER`
+/// lock, as its holder won't get to release it either.
+pub fn emergency() {
+    EMERGENCY.store(true, Ordering::Release);
+    unsafe { WRITER.force_unlock() };
+    flush();
+}
+
+/// Hands the lines typed from now on to user space if `user`, or else to the
+/// kernel shell.
+pub fn set_user_input(user: bool) {
+    USER_INPUT.store(user, Ordering::Release);
+}
+
+/// Whether typed lines go to user space.
+pub fn user_input() -> bool {
+    USER_INPUT.load(Ordering::Acquire)
+}
+
+/// Moves the typed lines to the other of the kernel shell and user space, for
+/// the `switch-console` binding.
+pub fn switch_input() {
+    if USER_INPUT.fetch_xor(true, Ordering::AcqRel) {
+        print!("\n[kernel shell]\n{}", shell::PROMPT);
+    } else {
+        print!("\n[user space]\n");
+    }
+}
+
+/// Hands a line typed on the keyboard, without its newline, to whoever has
+/// the console's input.
+pub fn submit_line(line: String) {
+    if !user_input() {
+        return shell::submit(line);
+    }
+    without_interrupts(|| {
+        let mut input = INPUT.lock();
+        if input.len() + line.len() < INPUT_LIMIT {
+            input.extend(line.bytes());
+            input.push_back(b'\n');
+        }
+    });
+    INPUT_READY.notify_all();
+}
+
+/// Reads typed input for `/dev/console` into `buf`, up to the end of the line,
+/// waiting for a line if there is none.
+pub fn read_input(buf: &mut [u8]) -> usize {
+    if buf.is_empty() {
+        return 0;
+    }
+    INPUT_READY.wait_until(|| !INPUT.lock().is_empty());
+    without_interrupts(|| {
+        let mut input = INPUT.lock();
+        let mut len = 0;
+        while len < buf.len() {
+            match input.pop_front() {
+                Some(byte) => {
+                    buf[len] = byte;
+                    len += 1;
+                    if byte == b'\n' {
+                        break;
+                    }
+                }
+                None => break,
+            }
+        }
+        len
+    })
+}
+
+/// What `screenshot` makes of the screen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScreenshotFormat {
+    /// The characters alone, without the blanks ending the rows.
+    Text,
+    /// The characters in their colors, as ANSI escape sequences for a terminal.
+    Ansi,
+}
+
+/// The colors in `Color` order, as ANSI numbers them.
+const ANSI_COLORS: [u8; 8] = [0, 4, 2, 6, 1, 5, 3, 7];
+
+/// The SGR parameter showing `color`, `base` being 30 for the foreground and
+/// 40 for the background.
+fn ansi_color(color: Color, base: u8) -> u8 {
+    let color = color as u8;
+    base + ANSI_COLORS[usize::from(color & 7)] + if color >= 8 { 60 } else { 0 }
+}
+
+/// The 80×25 characters on the screen, status bar included, one row per line.
+/// The queued text is written out first.
+pub fn screenshot(format: ScreenshotFormat) -> String {
+    flush();
+    let mut text = String::with_capacity(BUFFER_HEIGHT * (BUFFER_WIDTH + 1));
+    for row in 0..BUFFER_HEIGHT {
+        let mut line = String::with_capacity(BUFFER_WIDTH);
+        let mut colors = None;
+        for col in 0..BUFFER_WIDTH {
+            let (byte, foreground, background) = vga_buffer::read_cell(row, col);
+            if format == ScreenshotFormat::Ansi && colors != Some((foreground, background)) {
+                let _ = write!(line, "\x1b[{};{}m", ansi_color(foreground, 30), ansi_color(background, 40));
+                colors = Some((foreground, background));
+            }
+            line.push(cp437::decode(byte));
+        }
+        match format {
+            ScreenshotFormat::Text => text.push_str(line.trim_end_matches(' ')),
+            ScreenshotFormat::Ansi => {
+                text.push_str(&line);
+                text.push_str("\x1b[0m");
+            }
+        }
+        text.push('\n');
+    }
+    text
+}
+
+/// Sends a screenshot in colors to the host over the serial port, for the
+/// `screenshot` binding.
+pub fn screenshot_to_serial() {
+    serial_print!("{}", screenshot(ScreenshotFormat::Ansi));
+}
+
+/// Writes out the queued text. Holding `WRITER` makes the caller the only reader.
+fn drain(writer: &mut Writer) {
+    RING.pop_all(|bytes| write(writer, bytes));
+}
+
+/// Writes `bytes` to the screen and to the remote shell sessions.
+fn write(writer: &mut Writer, bytes: &[u8]) {
+    writer.write_bytes(bytes);
+    #[cfg(feature = "net")]
+    crate::shell::remote::mirror(bytes);
+}
+
+/// Queues `bytes`, or writes them out synchronously if the ring is full.
+fn queue(bytes: &[u8]) {
+    if RING.push(bytes) {
+        WAKEUP.notify_one();
+        return;
+    }
+    let written = without_interrupts(|| match WRITER.try_lock() {
+        Some(mut writer) => {
+            drain(&mut writer);
+            write(&mut writer, bytes);
+            true
+        }
+        None => false,
+    });
+    if !written {
+        DROPPED.fetch_add(bytes.len(), Ordering::Relaxed);
+    }
+}
+
+/// Formatted text collected on the stack and queued in pieces of `CHUNK_SIZE`.
+struct Chunk {
+    data: [u8; CHUNK_SIZE],
+    len: usize,
+}
+
+impl Chunk {
+    fn queue(&mut self) {
+        if self.len > 0 {
+            queue(&self.data[..self.len]);
+            self.len = 0;
+        }
+    }
+}
+
+impl fmt::Write for Chunk {
+    /// Collects `s` translated to code page 437, as the `Writer` would.
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        cp437::encode_str(s, |byte| {
+            self.data[self.len] = byte;
+            self.len += 1;
+            if self.len == CHUNK_SIZE {
+                self.queue();
+            }
+        });
+        Ok(())
+    }
+}
+
+#[test_case]
+fn test_print_while_writer_is_locked() {
+    assert!(RUNNING.load(Ordering::Acquire));
+    without_interrupts(|| {
+        let _writer = WRITER.lock();
+        // like an interrupt handler that interrupted the holder of `WRITER`
+        crate::print!("\nqueued while locked");
+    });
+    flush();
+    assert_eq!(without_interrupts(|| WRITER.lock().current_line()), "queued while locked");
+}
+
+#[test_case]
+fn test_screenshot() {
+    crate::print!("\nshown on the screen");
+    let text = screenshot(ScreenshotFormat::Text);
+    let rows: alloc::vec::Vec<&str> = text.lines().collect();
+    assert_eq!(rows.len(), BUFFER_HEIGHT);
+    assert_eq!(rows[BUFFER_HEIGHT - 2], "shown on the screen");
+    let ansi = screenshot(ScreenshotFormat::Ansi);
+    assert!(ansi.lines().all(|row| row.starts_with("\x1b[") && row.ends_with("\x1b[0m")));
+    crate::println!();
+}
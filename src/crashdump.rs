@@ -0,0 +1,674 @@
+//! Crash dumps: when the kernel panics or takes a fatal page fault, its state
+//! is written to a disk or streamed over a serial port for post-mortem
+//! debugging. The `crashdump` option and command choose where.
+//!
+//! A dump is a `Header`, then sections, each a `SectionHeader` followed by its
+//! data padded to 8 bytes, and ends with an `END` section holding the CRC-32
+//! of everything before it. On a disk it is written from a given sector, the
+//! start of the area reserved for it. Over a serial port it is framed as lines
+//! of hex, 32 bytes each, between
+//!
+//! ```text
+//! MAROS CRASH DUMP BEGIN
+//! MAROS CRASH DUMP END <length> <crc32>
+//! ```
+//!
+//! so `sed -n '/DUMP BEGIN/,/DUMP END/p' log | sed '1d;$d' | xxd -r -p`
+//! recovers it on the host.
+//!
+//! Writing a dump waits for no lock and allocates nothing; what is locked when
+//! the kernel goes down, like the thread list, is left out.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::fmt::{self, Write};
+use spin::Mutex;
+use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+use crate::arch::interrupts;
+use crate::debug::Backtrace;
+use crate::drivers::block::{self, BlockDevice, SECTOR_SIZE};
+use crate::drivers::uart::{Com, RawWriter};
+use crate::{allocator, initcall, klog, memory, sched};
+
+pub const MAGIC: [u8; 8] = *b"MAROSDMP";
+pub const VERSION: u32 = 1;
+
+// section kinds
+/// The CRC-32 (IEEE) of every byte of the dump before this section, as a `u32`.
+pub const END: u32 = 0;
+/// What went wrong, as text.
+pub const MESSAGE: u32 = 1;
+/// The `Registers`.
+pub const REGISTERS: u32 = 2;
+/// Return addresses as `u64`s, innermost first.
+pub const BACKTRACE: u32 = 3;
+/// A text line `<id> <state> <name>` per thread.
+pub const THREADS: u32 = 4;
+/// The last lines of the kernel log, as text.
+pub const LOG: u32 = 5;
+/// The heap's start, size and bytes allocated, as `u64`s.
+pub const HEAP: u32 = 6;
+/// A `u64` address, then the memory from it: the code around `rip`, and the
+/// page a fault was about.
+pub const MEMORY: u32 = 7;
+
+/// The most a dump read back from a disk may take.
+const MAX_DUMP: usize = 64 * 1024;
+/// Bytes of code dumped around `rip`.
+const CODE_BYTES: u64 = 256;
+
+/// The start of a dump. Every number in a dump is little endian, and every
+/// field of the structs that make it up is at the offset `repr(C)` gives it:
+///
+/// | offset | size | field |
+/// |--------|------|-------|
+/// | 0      | 8    | `magic`, `MAGIC` |
+/// | 8      | 4    | `version`, `VERSION` |
+/// | 12     | 4    | `header_size`, where the first section starts |
+/// | 16     | 8    | `uptime_ns`, when the dump was written |
+/// | 24     | 4    | `cpu`, the local APIC id of the CPU that wrote it |
+/// | 28     | 4    | `reason`, a `Reason` |
+///
+/// Each section is then a `SectionHeader`, `kind` at 0 and `len` at 4,
+/// followed by `len` bytes of data and zeros up to a multiple of 8.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub magic: [u8; 8],
+    pub version: u32,
+    pub header_size: u32,
+    pub uptime_ns: u64,
+    pub cpu: u32,
+    pub reason: u32,
+}
+
+impl Header {
+    pub const SIZE: usize = core::mem::size_of::<Header>();
+
+    fn to_bytes(self) -> [u8; Header::SIZE] {
+        let mut bytes = [0; Header::SIZE];
+        bytes[0..8].copy_from_slice(&self.magic);
+        bytes[8..12].copy_from_slice(&self.version.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.header_size.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.uptime_ns.to_le_bytes());
+        bytes[24..28].copy_from_slice(&self.cpu.to_le_bytes());
+        bytes[28..32].copy_from_slice(&self.reason.to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Header> {
+        if bytes.len() < Header::SIZE || bytes[0..8] != MAGIC {
+            return None;
+        }
+        Some(Header {
+            magic: MAGIC,
+            version: u32_at(bytes, 8),
+            header_size: u32_at(bytes, 12),
+            uptime_ns: u64_at(bytes, 16),
+            cpu: u32_at(bytes, 24),
+            reason: u32_at(bytes, 28),
+        })
+    }
+}
+
+/// Comes before the data of every section.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SectionHeader {
+    pub kind: u32,
+    /// Bytes of data, without the padding.
+    pub len: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Reason {
+    Panic = 1,
+    PageFault = 2,
+    /// The `crashdump write` command.
+    Requested = 3,
+}
+
+impl Reason {
+    pub fn name(number: u32) -> &'static str {
+        match number {
+            1 => "panic",
+            2 => "page fault",
+            3 => "requested",
+            _ => "unknown",
+        }
+    }
+}
+
+/// The CPU state in a dump, eleven `u64`s in this order.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Registers {
+    pub rip: u64,
+    pub rsp: u64,
+    pub rbp: u64,
+    pub rflags: u64,
+    pub cs: u64,
+    pub ss: u64,
+    pub cr0: u64,
+    pub cr2: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+    /// That of the exception, or 0.
+    pub error_code: u64,
+}
+
+impl Registers {
+    pub const SIZE: usize = core::mem::size_of::<Registers>();
+
+    /// The registers of the caller.
+    #[inline(always)]
+    pub fn capture() -> Registers {
+        let (rip, rsp, rbp, rflags, cs, ss): (u64, u64, u64, u64, u64, u64);
+        unsafe {
+            asm!("lea {}, [rip]", out(reg) rip, options(nomem, nostack, preserves_flags));
+            asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags));
+            asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+            asm!("pushfq; pop {}", out(reg) rflags, options(nomem, preserves_flags));
+            asm!("mov {:x}, cs", out(reg) cs, options(nomem, nostack, preserves_flags));
+            asm!("mov {:x}, ss", out(reg) ss, options(nomem, nostack, preserves_flags));
+        }
+        Registers {
+            rip,
+            rsp,
+            rbp,
+            rflags,
+            cs: cs & 0xffff,
+            ss: ss & 0xffff,
+            cr0: Cr0::read_raw(),
+            cr2: Cr2::read_raw(),
+            cr3: Cr3::read().0.start_address().as_u64(),
+            cr4: Cr4::read_raw(),
+            error_code: 0,
+        }
+    }
+
+    /// These registers with those the CPU saved for an exception. `rbp` stays
+    /// that of the handler.
+    pub fn with_frame(self, frame: &InterruptStackFrame, error_code: Option<u64>) -> Registers {
+        Registers {
+            rip: frame.instruction_pointer.as_u64(),
+            rsp: frame.stack_pointer.as_u64(),
+            rflags: frame.cpu_flags,
+            cs: frame.code_segment,
+            ss: frame.stack_segment,
+            error_code: error_code.unwrap_or(0),
+            ..self
+        }
+    }
+
+    fn to_bytes(self) -> [u8; Registers::SIZE] {
+        let fields = [self.rip, self.rsp, self.rbp, self.rflags, self.cs, self.ss, self.cr0, self.cr2, self.cr3,
+                      self.cr4, self.error_code];
+        let mut bytes = [0; Registers::SIZE];
+        for (chunk, field) in bytes.chunks_mut(8).zip(fields.iter()) {
+            chunk.copy_from_slice(&field.to_le_bytes());
+        }
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Registers> {
+        if bytes.len() < Registers::SIZE {
+            return None;
+        }
+        let field = |index: usize| u64_at(bytes, index * 8);
+        Some(Registers {
+            rip: field(0),
+            rsp: field(1),
+            rbp: field(2),
+            rflags: field(3),
+            cs: field(4),
+            ss: field(5),
+            cr0: field(6),
+            cr2: field(7),
+            cr3: field(8),
+            cr4: field(9),
+            error_code: field(10),
+        })
+    }
+}
+
+/// What went wrong, for `write`.
+pub struct Crash<'a> {
+    pub reason: Reason,
+    pub message: fmt::Arguments<'a>,
+    pub registers: Registers,
+    pub backtrace: &'a Backtrace,
+    /// The address a page fault was about, whose page is dumped if mapped.
+    pub fault_addr: Option<u64>,
+}
+
+/// Where dumps go.
+pub enum Target {
+    Serial(Com),
+    /// A block device, from the sector `start` on.
+    Disk { name: String, device: Arc<dyn BlockDevice>, start: u64 },
+}
+
+impl Target {
+    /// Parses `off`, a serial port like `com2`, or a block device with the
+    /// sector to start at, like `sdb@2048` (0 by default).
+    pub fn parse(spec: &str) -> Result<Option<Target>, &'static str> {
+        if spec == "off" {
+            return Ok(None);
+        }
+        if let Some(&com) = Com::ALL.iter().find(|com| com.name() == spec) {
+            return Ok(Some(Target::Serial(com)));
+        }
+        let (name, start) = match spec.split_once('@') {
+            Some((name, start)) => (name, start.parse().map_err(|_| "invalid start sector")?),
+            None => (spec, 0),
+        };
+        let device = block::get(name).ok_or("no such block device")?;
+        if start >= device.sector_count() {
+            return Err("start sector beyond the end of the device");
+        }
+        Ok(Some(Target::Disk { name: String::from(name), device, start }))
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Target::Serial(com) => write!(f, "{}", com.name()),
+            Target::Disk { name, start, .. } => write!(f, "{}@{}", name, start),
+        }
+    }
+}
+
+/// Taken with interrupts disabled, and only tried when the kernel goes down.
+static TARGET: Mutex<Option<Target>> = Mutex::new(None);
+
+/// Applies the `crashdump` option.
+fn init() -> Result<(), &'static str> {
+    if let Some(spec) = crate::boot::cmdline().get("crashdump") {
+        set_target(Target::parse(spec)?);
+        with_target(|target| if let Some(target) = target {
+            crate::log_info!("crashdump: dumps go to {}", target);
+        });
+    }
+    Ok(())
+}
+initcall!(Late, "crashdump", init, after: &["ahci"]);
+
+pub fn set_target(target: Option<Target>) {
+    interrupts::without_interrupts(|| *TARGET.lock() = target);
+}
+
+/// Runs `f` on the target, `None` when dumps are off.
+pub fn with_target<R>(f: impl FnOnce(Option<&Target>) -> R) -> R {
+    interrupts::without_interrupts(|| f(TARGET.lock().as_ref()))
+}
+
+/// Writes a dump of `crash` to the target, for when the kernel goes down.
+pub fn write(crash: &Crash) -> Result<(), &'static str> {
+    let target = TARGET.try_lock().ok_or("target locked")?;
+    write_to(target.as_ref().ok_or("no target")?, crash)
+}
+
+/// Writes a dump of `crash` to `target`.
+pub fn write_to(target: &Target, crash: &Crash) -> Result<(), &'static str> {
+    match target {
+        Target::Serial(com) => dump(&mut SerialSink::new(*com), crash),
+        Target::Disk { device, start, .. } => dump(&mut DiskSink::new(&**device, *start), crash),
+    }
+}
+
+/// Reads the dump on the disk of `target` back, up to its end.
+pub fn read(target: &Target) -> Result<Vec<u8>, &'static str> {
+    let (device, start) = match target {
+        Target::Disk { device, start, .. } => (device, *start),
+        Target::Serial(_) => return Err("dumps over a serial port are read on the host"),
+    };
+    let sectors = (device.sector_count() - start).min((MAX_DUMP / SECTOR_SIZE) as u64);
+    let mut data = alloc::vec![0; sectors as usize * SECTOR_SIZE];
+    device.read_sectors(start, &mut data)?;
+    let len = parse(&data)?.len;
+    data.truncate(len);
+    Ok(data)
+}
+
+/// Erases the dump on the disk of `target`, so it isn't read back again.
+pub fn clear(target: &Target) -> Result<(), &'static str> {
+    match target {
+        Target::Disk { device, start, .. } => device.write_sectors(*start, &[0; SECTOR_SIZE]),
+        Target::Serial(_) => Err("nothing to clear on a serial port"),
+    }
+}
+
+/// A dump read back.
+pub struct Dump<'a> {
+    pub header: Header,
+    /// Kind and data of every section but `END`.
+    pub sections: Vec<(u32, &'a [u8])>,
+    /// Bytes of the dump, `END` included.
+    pub len: usize,
+}
+
+impl Dump<'_> {
+    pub fn registers(&self) -> Option<Registers> {
+        self.section(REGISTERS).and_then(Registers::from_bytes)
+    }
+
+    pub fn backtrace(&self) -> impl Iterator<Item = u64> + '_ {
+        self.section(BACKTRACE).unwrap_or(&[]).chunks_exact(8).map(|chunk| u64_at(chunk, 0))
+    }
+
+    /// The data of the first section of `kind`.
+    pub fn section(&self, kind: u32) -> Option<&[u8]> {
+        self.sections.iter().find(|(other, _)| *other == kind).map(|(_, data)| *data)
+    }
+}
+
+/// Checks the dump at the start of `data` and splits it into its sections.
+pub fn parse(data: &[u8]) -> Result<Dump, &'static str> {
+    let header = Header::from_bytes(data).ok_or("no crash dump")?;
+    if header.version != VERSION {
+        return Err("unsupported crash dump version");
+    }
+    let mut offset = header.header_size as usize;
+    let mut sections = Vec::new();
+    loop {
+        if offset + 8 > data.len() {
+            return Err("crash dump truncated");
+        }
+        let (kind, len) = (u32_at(data, offset), u32_at(data, offset + 4) as usize);
+        let start = offset + 8;
+        if start + len > data.len() {
+            return Err("crash dump truncated");
+        }
+        if kind == END {
+            if len < 4 || u32_at(data, start) != crc32(CRC_INIT, &data[..offset]) ^ CRC_INIT {
+                return Err("crash dump corrupted");
+            }
+            let len = start + padded(len);
+            return Ok(Dump { header, sections, len });
+        }
+        sections.push((kind, &data[start..start + len]));
+        offset = start + padded(len);
+    }
+}
+
+fn dump(sink: &mut dyn Sink, crash: &Crash) -> Result<(), &'static str> {
+    let mut writer = Writer { sink, len: 0, crc: CRC_INIT, result: Ok(()) };
+    let header = Header {
+        magic: MAGIC,
+        version: VERSION,
+        header_size: Header::SIZE as u32,
+        uptime_ns: crate::time::uptime().as_nanos() as u64,
+        cpu: u32::from(crate::debug::cpu_id()),
+        reason: crash.reason as u32,
+    };
+    writer.bytes(&header.to_bytes());
+    writer.section(MESSAGE, |out| {
+        let _ = write!(Text(out), "{}", crash.message);
+    });
+    writer.section(REGISTERS, |out| out(&crash.registers.to_bytes()));
+    writer.section(BACKTRACE, |out| crash.backtrace.frames().iter().for_each(|addr| out(&addr.to_le_bytes())));
+    writer.section(THREADS, |out| {
+        sched::try_for_each_thread(|id, name, state, _| {
+            let _ = writeln!(Text(&mut *out), "{} {} {}", id.0, state.name(), name);
+        });
+    });
+    writer.section(LOG, |out| klog::recent_lines(|line| {
+        out(line);
+        out(b"\n");
+    }));
+    writer.section(HEAP, |out| {
+        for value in [allocator::heap_start() as u64, allocator::HEAP_SIZE as u64, allocator::heap_used() as u64].iter() {
+            out(&value.to_le_bytes());
+        }
+    });
+    let rip = crash.registers.rip;
+    writer.memory(rip & !(CODE_BYTES - 1), CODE_BYTES);
+    if let Some(addr) = crash.fault_addr {
+        writer.memory(addr & !0xfff, 0x1000);
+    }
+    let crc = writer.crc ^ CRC_INIT;
+    writer.section(END, |out| out(&crc.to_le_bytes()));
+    writer.result?;
+    let len = writer.len;
+    sink.finish(len, crc)
+}
+
+/// Where the bytes of a dump go.
+trait Sink {
+    fn write(&mut self, bytes: &[u8]) -> Result<(), &'static str>;
+
+    /// Called once all `len` bytes, with the CRC-32 `crc`, are written.
+    fn finish(&mut self, len: u64, crc: u32) -> Result<(), &'static str>;
+}
+
+struct SerialSink {
+    out: RawWriter,
+    /// Bytes on the current line.
+    column: usize,
+}
+
+impl SerialSink {
+    fn new(com: Com) -> SerialSink {
+        let mut out = RawWriter(com);
+        let _ = out.write_str("\nMAROS CRASH DUMP BEGIN\n");
+        SerialSink { out, column: 0 }
+    }
+}
+
+impl Sink for SerialSink {
+    fn write(&mut self, bytes: &[u8]) -> Result<(), &'static str> {
+        for &byte in bytes {
+            self.out.write_byte(b"0123456789abcdef"[usize::from(byte >> 4)]);
+            self.out.write_byte(b"0123456789abcdef"[usize::from(byte & 0xf)]);
+            self.column += 1;
+            if self.column == 32 {
+                self.out.write_byte(b'\n');
+                self.column = 0;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self, len: u64, crc: u32) -> Result<(), &'static str> {
+        if self.column != 0 {
+            self.out.write_byte(b'\n');
+        }
+        let _ = writeln!(self.out, "MAROS CRASH DUMP END {} {:08x}", len, crc);
+        Ok(())
+    }
+}
+
+struct DiskSink<'a> {
+    device: &'a dyn BlockDevice,
+    /// The sector `buf` goes to.
+    sector: u64,
+    buf: [u8; SECTOR_SIZE],
+    used: usize,
+}
+
+impl<'a> DiskSink<'a> {
+    fn new(device: &'a dyn BlockDevice, start: u64) -> DiskSink<'a> {
+        DiskSink { device, sector: start, buf: [0; SECTOR_SIZE], used: 0 }
+    }
+
+    fn flush(&mut self) -> Result<(), &'static str> {
+        self.buf[self.used..].iter_mut().for_each(|byte| *byte = 0);
+        self.device.write_sectors_polled(self.sector, &self.buf)?;
+        self.sector += 1;
+        self.used = 0;
+        Ok(())
+    }
+}
+
+impl Sink for DiskSink<'_> {
+    fn write(&mut self, mut bytes: &[u8]) -> Result<(), &'static str> {
+        while !bytes.is_empty() {
+            let count = bytes.len().min(SECTOR_SIZE - self.used);
+            self.buf[self.used..self.used + count].copy_from_slice(&bytes[..count]);
+            self.used += count;
+            bytes = &bytes[count..];
+            if self.used == SECTOR_SIZE {
+                self.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self, _len: u64, _crc: u32) -> Result<(), &'static str> {
+        if self.used > 0 {
+            self.flush()?;
+        }
+        Ok(())
+    }
+}
+
+struct Writer<'a> {
+    sink: &'a mut dyn Sink,
+    len: u64,
+    crc: u32,
+    /// The first error of the sink, after which nothing more is written.
+    result: Result<(), &'static str>,
+}
+
+impl Writer<'_> {
+    fn bytes(&mut self, bytes: &[u8]) {
+        if self.result.is_ok() {
+            self.crc = crc32(self.crc, bytes);
+            self.len += bytes.len() as u64;
+            self.result = self.sink.write(bytes);
+        }
+    }
+
+    /// Writes a section of `kind` with the data `data` passes to its argument.
+    /// `data` runs twice, to measure the data and to write it: what it passes
+    /// beyond the measured length the second time is cut, what is missing
+    /// becomes zeros.
+    fn section(&mut self, kind: u32, mut data: impl FnMut(&mut dyn FnMut(&[u8]))) {
+        let mut len = 0;
+        data(&mut |bytes| len += bytes.len());
+        let len = len.min(u32::MAX as usize);
+        self.bytes(&kind.to_le_bytes());
+        self.bytes(&(len as u32).to_le_bytes());
+        let mut left = len;
+        data(&mut |bytes| {
+            let count = bytes.len().min(left);
+            self.bytes(&bytes[..count]);
+            left -= count;
+        });
+        for _ in 0..left + padded(len) - len {
+            self.bytes(&[0]);
+        }
+    }
+
+    /// Writes a `MEMORY` section with the `len` bytes at `addr`, if they are
+    /// mapped on one page of RAM.
+    fn memory(&mut self, addr: u64, len: u64) {
+        let mapping = match VirtAddr::try_new(addr).ok().and_then(memory::mapping_of) {
+            Some(mapping) => mapping,
+            None => return,
+        };
+        // device registers may change when read
+        if mapping.flags.intersects(PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH)
+            || addr + len > mapping.virt.as_u64() + mapping.size {
+            return;
+        }
+        // through the physical memory mapping, which also reaches user pages
+        let phys = mapping.phys.as_u64() + (addr - mapping.virt.as_u64());
+        let virt = memory::physical_memory_offset() + phys;
+        let bytes = unsafe { core::slice::from_raw_parts(virt.as_ptr::<u8>(), len as usize) };
+        self.section(MEMORY, |out| {
+            out(&addr.to_le_bytes());
+            out(bytes);
+        });
+    }
+}
+
+/// Formats text into the data of a section.
+struct Text<'a>(&'a mut dyn FnMut(&[u8]));
+
+impl Write for Text<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        (self.0)(s.as_bytes());
+        Ok(())
+    }
+}
+
+fn padded(len: usize) -> usize {
+    (len + 7) & !7
+}
+
+const CRC_INIT: u32 = 0xffff_ffff;
+
+/// Continues the CRC-32 (IEEE, reflected) `crc` over `bytes`. Start with
+/// `CRC_INIT` and invert the result.
+fn crc32(mut crc: u32, bytes: &[u8]) -> u32 {
+    for &byte in bytes {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    crc
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&data[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&data[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+#[test_case]
+fn test_dump_to_disk() {
+    assert_eq!(crc32(CRC_INIT, b"123456789") ^ CRC_INIT, 0xcbf4_3926);
+    assert_eq!(Header::SIZE, 32);
+    assert_eq!(Registers::SIZE, 88);
+
+    crate::log_info!("test_dump_to_disk");
+    let device: Arc<dyn BlockDevice> = Arc::new(block::RamDisk::new(alloc::vec![0xaa; 64 * SECTOR_SIZE]));
+    let target = Target::Disk { name: String::from("test"), device, start: 8 };
+    let registers = Registers::capture();
+    let backtrace = Backtrace::capture();
+    static FAULTED: [u8; 16] = *b"the faulted page";
+    let fault_addr = FAULTED.as_ptr() as u64;
+    write_to(&target, &Crash {
+        reason: Reason::Requested,
+        message: format_args!("test dump {}", 42),
+        registers,
+        backtrace: &backtrace,
+        fault_addr: Some(fault_addr),
+    }).unwrap();
+
+    let data = read(&target).unwrap();
+    let dump = parse(&data).unwrap();
+    assert_eq!(dump.header.reason, Reason::Requested as u32);
+    assert_eq!(dump.section(MESSAGE), Some(&b"test dump 42"[..]));
+    assert_eq!(dump.registers(), Some(registers));
+    assert!(dump.backtrace().eq(backtrace.frames().iter().copied()));
+    let log = core::str::from_utf8(dump.section(LOG).unwrap()).unwrap();
+    assert!(log.lines().any(|line| line == "[INFO] test_dump_to_disk"));
+    let page = dump.sections.iter().rev().find(|(kind, _)| *kind == MEMORY).unwrap().1;
+    assert_eq!(u64_at(page, 0), fault_addr & !0xfff);
+    let offset = 8 + (fault_addr & 0xfff) as usize;
+    assert_eq!(&page[offset..offset + 16], &FAULTED[..]);
+
+    // a flipped bit is noticed
+    let mut corrupted = data.clone();
+    corrupted[Header::SIZE + 8] ^= 1;
+    assert!(parse(&corrupted).is_err());
+    clear(&target).unwrap();
+    assert!(read(&target).is_err());
+}
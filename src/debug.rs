@@ -0,0 +1,296 @@
+use core::arch::asm;
+use core::arch::x86_64::__cpuid;
+use core::fmt;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::registers::debug::{
+    BreakpointCondition, BreakpointSize, DebugAddressRegister, DebugAddressRegisterNumber,
+    Dr0, Dr1, Dr2, Dr3, Dr6, Dr6Flags, Dr7, Dr7Flags,
+};
+use x86_64::VirtAddr;
+use crate::serial::SERIAL1;
+use crate::{console, log_debug, memory, println, serial, serial_println, symbols};
+
+/// Deepest call chain recorded by a `Backtrace`.
+const MAX_FRAMES: usize = 16;
+
+/// What a watchpoint triggers on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    /// Writes to the watched bytes.
+    Write,
+    /// Reads or writes of the watched bytes.
+    Access,
+    /// Execution of the instruction at the watched address (a hardware breakpoint).
+    Execute,
+}
+
+impl WatchKind {
+    fn condition(self) -> BreakpointCondition {
+        match self {
+            WatchKind::Write => BreakpointCondition::DataWrites,
+            WatchKind::Access => BreakpointCondition::DataReadsWrites,
+            WatchKind::Execute => BreakpointCondition::InstructionExecution,
+        }
+    }
+}
+
+/// Which of the four debug address registers are in use.
+static IN_USE: Mutex<[bool; 4]> = Mutex::new([false; 4]);
+static HITS: [AtomicU64; 4] = [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)];
+
+/// An armed watchpoint. Dropping the handle disarms it.
+pub struct WatchpointHandle {
+    register: DebugAddressRegisterNumber,
+}
+
+/// Watches `len` bytes at `addr` (1, 2, 4 or 8, naturally aligned) using one of the
+/// four debug registers. Every hit is reported on the serial port with a backtrace.
+pub fn set_watchpoint(addr: VirtAddr, len: usize, kind: WatchKind) -> Result<WatchpointHandle, &'static str> {
+    let size = BreakpointSize::new(len).ok_or("watchpoint length must be 1, 2, 4 or 8")?;
+    if !addr.is_aligned(len as u64) {
+        return Err("watchpoint address must be aligned to its length");
+    }
+    if kind == WatchKind::Execute && len != 1 {
+        return Err("execution breakpoints must have length 1");
+    }
+
+    let mut in_use = IN_USE.lock();
+    let index = in_use.iter().position(|used| !used).ok_or("all debug registers are in use")?;
+    in_use[index] = true;
+    let register = DebugAddressRegisterNumber::new(index as u8).unwrap();
+    HITS[index].store(0, Ordering::SeqCst);
+
+    write_address(register, addr.as_u64());
+    let mut dr7 = Dr7::read();
+    dr7.set_condition(register, kind.condition());
+    dr7.set_size(register, size);
+    dr7.insert_flags(Dr7Flags::local_breakpoint_enable(register));
+    Dr7::write(dr7);
+    Ok(WatchpointHandle { register })
+}
+
+/// Places a hardware breakpoint on the instruction at `addr`. Unlike an `int3`
+/// breakpoint it does not modify the code.
+pub fn set_breakpoint(addr: VirtAddr) -> Result<WatchpointHandle, &'static str> {
+    set_watchpoint(addr, 1, WatchKind::Execute)
+}
+
+impl WatchpointHandle {
+    /// How many times the watchpoint fired.
+    pub fn hits(&self) -> u64 {
+        HITS[usize::from(self.register.get())].load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for WatchpointHandle {
+    fn drop(&mut self) {
+        let mut dr7 = Dr7::read();
+        dr7.remove_flags(Dr7Flags::local_breakpoint_enable(self.register));
+        Dr7::write(dr7);
+        IN_USE.lock()[usize::from(self.register.get())] = false;
+    }
+}
+
+fn write_address(register: DebugAddressRegisterNumber, addr: u64) {
+    match register {
+        DebugAddressRegisterNumber::Dr0 => Dr0::write(addr),
+        DebugAddressRegisterNumber::Dr1 => Dr1::write(addr),
+        DebugAddressRegisterNumber::Dr2 => Dr2::write(addr),
+        DebugAddressRegisterNumber::Dr3 => Dr3::write(addr),
+    }
+}
+
+fn read_address(register: DebugAddressRegisterNumber) -> u64 {
+    match register {
+        DebugAddressRegisterNumber::Dr0 => Dr0::read(),
+        DebugAddressRegisterNumber::Dr1 => Dr1::read(),
+        DebugAddressRegisterNumber::Dr2 => Dr2::read(),
+        DebugAddressRegisterNumber::Dr3 => Dr3::read(),
+    }
+}
+
+/// Called from the debug exception handler: reports every watchpoint that fired.
+///
+/// `rip` is the interrupted instruction; data watchpoints trap after the access,
+/// so the culprit is the instruction before it. Returns whether an execution
+/// breakpoint fired, in which case the handler must set the resume flag to be
+/// able to continue past it.
+pub fn handle_debug_exception(rip: u64, backtrace: &Backtrace) -> bool {
+    let status = Dr6::read();
+    // DR6 is never cleared by the CPU
+    unsafe { asm!("mov dr6, {}", in(reg) 0u64, options(nomem, nostack, preserves_flags)) };
+
+    // the watched data may be the serial port's own state, so don't wait for the lock
+    if SERIAL1.is_locked() {
+        unsafe { SERIAL1.force_unlock() };
+    }
+    let dr7 = Dr7::read();
+    let mut breakpoint = false;
+    let mut hit = false;
+    for index in 0..4 {
+        let register = DebugAddressRegisterNumber::new(index).unwrap();
+        if !status.contains(Dr6Flags::trap(register)) {
+            continue;
+        }
+        hit = true;
+        HITS[usize::from(index)].fetch_add(1, Ordering::SeqCst);
+        let condition = dr7.condition(register);
+        breakpoint |= condition == BreakpointCondition::InstructionExecution;
+        serial_println!("WATCHPOINT {} ({:?}, {:?}) hit at {:#x}, interrupted at {:#x}",
+            index, condition, dr7.size(register), read_address(register), rip);
+    }
+    if status.contains(Dr6Flags::STEP) {
+        log_debug!("single step at {:#x}", rip);
+    }
+    if hit {
+        serial_println!("{}", backtrace);
+    }
+    breakpoint
+}
+
+/// Panics being reported, counting the ones raised while reporting another.
+static PANIC_DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+/// The initial local APIC id of the CPU running the caller.
+pub fn cpu_id() -> u8 {
+    (unsafe { __cpuid(1) }.ebx >> 24) as u8
+}
+
+/// Reports a panic with its location, the CPU and a backtrace on the serial
+/// port and then on the screen.
+///
+/// The serial port is written without taking any lock first, so the report
+/// gets out even if the screen or its lock is what broke. A panic raised
+/// while writing to the screen is reported on the serial port only, and one
+/// raised while reporting that is not reported at all.
+pub fn report_panic(info: &PanicInfo) {
+    let depth = PANIC_DEPTH.fetch_add(1, Ordering::SeqCst);
+    crate::arch::interrupts::freeze_on_nmi();
+    if depth > 1 {
+        return;
+    }
+    let backtrace = Backtrace::capture();
+    let cpu = cpu_id();
+    serial::print_raw(format_args!("\nKERNEL PANIC on CPU {}: {}\n{}\n", cpu, info, backtrace));
+    crate::klog::pstore::record(format_args!("KERNEL PANIC on CPU {}: {}\n{}\n", cpu, info, backtrace));
+    if depth == 0 {
+        console::emergency();
+        println!("KERNEL PANIC on CPU {}: {}\n{}", cpu, info, backtrace);
+        let dumped = crate::crashdump::write(&crate::crashdump::Crash {
+            reason: crate::crashdump::Reason::Panic,
+            message: format_args!("{}", info),
+            registers: crate::crashdump::Registers::capture(),
+            backtrace: &backtrace,
+            fault_addr: None,
+        });
+        if dumped.is_ok() {
+            println!("crash dump written");
+        }
+    } else {
+        serial::print_raw(format_args!("(panicked while printing to the screen)\n"));
+    }
+}
+
+/// Return addresses of a call chain, found by following saved frame pointers.
+///
+/// Addresses are named if a symbol table was loaded (see `symbols`); otherwise
+/// resolve them on the host with `addr2line -e target/x86_64-MarOS/debug/MarOS`.
+#[derive(Clone, Copy)]
+pub struct Backtrace {
+    frames: [u64; MAX_FRAMES],
+    len: usize,
+}
+
+impl Backtrace {
+    /// Records the call chain of the caller.
+    #[inline(always)]
+    pub fn capture() -> Self {
+        let rbp: u64;
+        unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+        Self::walk(None, rbp)
+    }
+
+    /// Records the call chain of interrupted code, from its instruction and frame pointers.
+    pub fn from_frame(rip: u64, rbp: u64) -> Self {
+        Self::walk(Some(rip), rbp)
+    }
+
+    fn walk(first: Option<u64>, mut rbp: u64) -> Self {
+        let mut backtrace = Backtrace { frames: [0; MAX_FRAMES], len: 0 };
+        if let Some(rip) = first {
+            backtrace.push(rip);
+        }
+        let mut checked_page = None;
+        // frame pointers are 16 byte aligned, so a frame never crosses a page
+        while backtrace.len < MAX_FRAMES && rbp != 0 && rbp % 16 == 0 {
+            // the chain ends in whatever the bootloader left in rbp,
+            // so make sure the frame is readable before following it
+            let page = rbp & !0xfff;
+            if checked_page != Some(page) {
+                let mapped = VirtAddr::try_new(rbp).ok().and_then(memory::mapping_of).is_some();
+                if !mapped {
+                    break;
+                }
+                checked_page = Some(page);
+            }
+            let (next, return_addr) = unsafe { (*(rbp as *const u64), *((rbp + 8) as *const u64)) };
+            if return_addr == 0 {
+                break;
+            }
+            backtrace.push(return_addr);
+            // frames are further up the stack the further out they are
+            if next <= rbp {
+                break;
+            }
+            rbp = next;
+        }
+        backtrace
+    }
+
+    fn push(&mut self, addr: u64) {
+        self.frames[self.len] = addr;
+        self.len += 1;
+    }
+
+    pub fn frames(&self) -> &[u64] {
+        &self.frames[..self.len]
+    }
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "backtrace:")?;
+        for (i, addr) in self.frames().iter().enumerate() {
+            write!(f, "\n  #{:<2} {:#018x}", i, addr)?;
+            if let Some(symbol) = symbols::resolve(*addr) {
+                write!(f, " {}", symbol)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.frames().iter().map(|addr| VirtAddr::new(*addr))).finish()
+    }
+}
+
+#[test_case]
+fn test_write_watchpoint() {
+    static mut WATCHED: u64 = 0;
+    let addr = VirtAddr::from_ptr(unsafe { core::ptr::addr_of!(WATCHED) });
+    let watchpoint = set_watchpoint(addr, 8, WatchKind::Write).unwrap();
+    unsafe {
+        core::ptr::addr_of_mut!(WATCHED).write_volatile(1);
+        core::ptr::addr_of_mut!(WATCHED).read_volatile();
+    }
+    assert_eq!(watchpoint.hits(), 1);
+}
+
+#[test_case]
+fn test_backtrace_capture() {
+    assert!(Backtrace::capture().frames().len() > 1);
+}
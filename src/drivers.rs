@@ -0,0 +1,17 @@
+//! Device drivers.
+
+pub mod ac97;
+pub mod ahci;
+pub mod audio;
+pub mod block;
+#[cfg(feature = "net")]
+pub mod e1000;
+pub mod hpet;
+pub mod ide;
+pub mod pci;
+pub mod pit;
+pub mod rtc;
+pub mod ps2_mouse;
+pub mod speaker;
+pub mod uart;
+pub mod virtio;
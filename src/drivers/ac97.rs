@@ -0,0 +1,158 @@
+//! Intel ICH AC'97 audio controller, as emulated by QEMU with `-device AC97`.
+//!
+//! The PCM out channel plays a ring of `BUFFERS` DMA buffers described by a
+//! buffer descriptor list. Each buffer raises an interrupt on completion, whose
+//! handler refills it from the `audio` mixer and makes it the last valid buffer
+//! again, so playback never stops and plays silence when nothing is queued.
+
+use spin::Mutex;
+use crate::drivers::{audio, pci};
+use crate::arch::interrupts;
+use crate::arch::x86_64::ioport::{self, PortRange};
+use crate::memory::{self, DmaRegion};
+use crate::{initcall, log_info};
+
+const VENDOR_INTEL: u16 = 0x8086;
+const DEVICE_ICH_AC97: u16 = 0x2415;
+
+// native audio mixer registers (BAR 0)
+const NAM_RESET: u16 = 0x00;
+const NAM_MASTER_VOLUME: u16 = 0x02;
+const NAM_PCM_OUT_VOLUME: u16 = 0x18;
+
+// native audio bus master registers (BAR 1), PCM out box
+const PO_BDBAR: u16 = 0x10;
+const PO_CIV: u16 = 0x14;
+const PO_LVI: u16 = 0x15;
+const PO_SR: u16 = 0x16;
+const PO_CR: u16 = 0x1b;
+const GLOBAL_CONTROL: u16 = 0x2c;
+
+const CR_RUN: u8 = 1 << 0;
+const CR_RESET: u8 = 1 << 1;
+const CR_IOC_ENABLE: u8 = 1 << 4;
+const SR_DMA_HALTED: u16 = 1 << 0;
+const SR_LAST_VALID: u16 = 1 << 2;
+const SR_COMPLETION: u16 = 1 << 3;
+const SR_FIFO_ERROR: u16 = 1 << 4;
+const GLOBAL_COLD_RESET: u32 = 1 << 1;
+
+/// Size of the descriptor ring, fixed by the hardware.
+const BUFFERS: usize = 32;
+/// Samples per buffer. 32 buffers of 512 samples are about 170 ms of audio.
+const BUFFER_SAMPLES: usize = 512;
+const DESCRIPTOR_IOC: u16 = 1 << 15;
+
+/// An entry of the buffer descriptor list.
+#[repr(C)]
+struct Descriptor {
+    addr: u32,
+    samples: u16,
+    flags: u16,
+}
+
+struct Ac97 {
+    nam: PortRange,
+    nabm: PortRange,
+    buffers: DmaRegion,
+}
+
+static DEVICE: Mutex<Option<Ac97>> = Mutex::new(None);
+
+impl Ac97 {
+    fn buffer(&mut self, index: usize) -> &mut [i16] {
+        let start = self.buffers.virt.as_mut_ptr::<i16>();
+        unsafe { core::slice::from_raw_parts_mut(start.add(index * BUFFER_SAMPLES), BUFFER_SAMPLES) }
+    }
+
+    /// Refills the buffer played before the current one and appends it to the ring.
+    fn refill(&mut self) {
+        let current: u8 = self.nabm.read(PO_CIV);
+        let done = (usize::from(current) + BUFFERS - 1) % BUFFERS;
+        if audio::fill(self.buffer(done)) {
+            self.nabm.write(PO_LVI, done as u8);
+        }
+    }
+}
+
+/// Sets the master volume in percent. 0 mutes the output.
+pub fn set_volume(percent: u8) {
+    if let Some(device) = DEVICE.lock().as_ref() {
+        // 6 bits of attenuation per channel in 1.5 dB steps, bit 15 mutes
+        let attenuation = u16::from(100 - percent.min(100)) * 63 / 100;
+        let value = if percent == 0 { 0x8000 } else { attenuation << 8 | attenuation };
+        device.nam.write(NAM_MASTER_VOLUME, value);
+    }
+}
+
+/// Whether a controller was found and is playing.
+pub fn is_present() -> bool {
+    DEVICE.lock().is_some()
+}
+
+/// Finds the controller on the PCI bus, resets it and starts playing the mixer.
+pub fn init() -> Result<(), &'static str> {
+    let dev = pci::find(VENDOR_INTEL, DEVICE_ICH_AC97).ok_or("no AC'97 controller")?;
+    let (nam, nabm) = match (dev.bar(0), dev.bar(1)) {
+        (Some(pci::Bar::Io(nam)), Some(pci::Bar::Io(nabm))) => (nam, nabm),
+        _ => return Err("AC'97 controller without I/O BARs"),
+    };
+    let nam = ioport::claim(nam, 0x100, "ac97 mixer")?;
+    let nabm = ioport::claim(nabm, 0x40, "ac97 bus master")?;
+    let irq = dev.interrupt_line().ok_or("AC'97 controller without an IRQ line")?;
+    dev.enable_bus_mastering();
+
+    let descriptors = memory::allocate_dma((BUFFERS * core::mem::size_of::<Descriptor>()) as u64)?;
+    let buffers = memory::allocate_dma((BUFFERS * BUFFER_SAMPLES * 2) as u64)?;
+    let list = descriptors.virt.as_mut_ptr::<Descriptor>();
+    for i in 0..BUFFERS {
+        let addr = buffers.phys.as_u64() + (i * BUFFER_SAMPLES * 2) as u64;
+        let descriptor = Descriptor { addr: addr as u32, samples: BUFFER_SAMPLES as u16, flags: DESCRIPTOR_IOC };
+        unsafe { list.add(i).write_volatile(descriptor) };
+    }
+
+    let (nam_base, nabm_base) = (nam.start(), nabm.start());
+    let device = Ac97 { nam, nabm, buffers };
+    // leave cold reset, then reset the mixer to its defaults
+    device.nabm.write(GLOBAL_CONTROL, GLOBAL_COLD_RESET);
+    device.nam.write(NAM_RESET, 0_u16);
+    device.nam.write(NAM_MASTER_VOLUME, 0_u16);
+    // 0 dB gain
+    device.nam.write(NAM_PCM_OUT_VOLUME, 0x0808_u16);
+
+    device.nabm.write(PO_CR, CR_RESET);
+    while device.nabm.read::<u8>(PO_CR) & CR_RESET != 0 {
+        core::hint::spin_loop();
+    }
+    device.nabm.write(PO_BDBAR, descriptors.phys.as_u64() as u32);
+    device.nabm.write(PO_LVI, (BUFFERS - 1) as u8);
+    *DEVICE.lock() = Some(device);
+    interrupts::register_shared_irq(irq, handle_interrupt)?;
+    if let Some(device) = DEVICE.lock().as_ref() {
+        device.nabm.write(PO_CR, CR_RUN | CR_IOC_ENABLE);
+    }
+    log_info!("ac97: {:02x}:{:02x}.{} io {:#x}/{:#x} irq {}", dev.bus, dev.device, dev.function, nam_base, nabm_base, irq);
+    Ok(())
+}
+initcall!(Driver, "ac97", init);
+
+fn handle_interrupt() {
+    let mut device = match DEVICE.try_lock() {
+        Some(device) => device,
+        None => return,
+    };
+    let device = match device.as_mut() {
+        Some(device) => device,
+        None => return,
+    };
+    let value: u16 = device.nabm.read(PO_SR);
+    if value & SR_COMPLETION != 0 {
+        device.refill();
+    }
+    // status bits are cleared by writing 1
+    device.nabm.write(PO_SR, value & (SR_LAST_VALID | SR_COMPLETION | SR_FIFO_ERROR));
+    if value & SR_DMA_HALTED != 0 {
+        // missed completions let the controller catch up with the last valid buffer
+        device.nabm.write(PO_CR, CR_RUN | CR_IOC_ENABLE);
+    }
+}
@@ -0,0 +1,420 @@
+//! AHCI SATA controller, as emulated by QEMU with `-device ahci` (or built into
+//! the q35 machine) and an `ide-hd` drive on one of its ports.
+//!
+//! Every disk becomes a `block::BlockDevice`. It runs one command at a time,
+//! from command slot 0: the driver writes the command FIS and a single PRD
+//! pointing at a DMA bounce buffer into the command table, issues the slot
+//! and blocks until the controller clears it again, which it announces with
+//! an interrupt. A timer wakes the waiting thread if the interrupt never comes.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{fence, AtomicBool, AtomicU32, Ordering};
+use core::time::Duration;
+use spin::Once;
+use x86_64::PhysAddr;
+use crate::arch::interrupts;
+use crate::drivers::block::{self, BlockDevice, SECTOR_SIZE};
+use crate::drivers::pci;
+use crate::memory::{self, DmaRegion};
+use crate::sched::WaitQueue;
+use crate::time::{self, Instant};
+use crate::{initcall, log_info, mmio};
+
+// PCI class of AHCI controllers: mass storage, SATA, AHCI 1.0
+const CLASS_STORAGE: u8 = 0x01;
+const SUBCLASS_SATA: u8 = 0x06;
+const PROG_IF_AHCI: u8 = 0x01;
+
+const PORTS: usize = 32;
+
+crate::register_block! {
+    /// The generic host control registers, followed by the registers of
+    /// every port.
+    struct Registers, size 0x1100 {
+        capabilities: u32, ReadOnly @ 0x00;
+        global_control: u32, ReadWrite @ 0x04;
+        interrupt_status: u32, ReadWrite @ 0x08;
+        ports_implemented: u32, ReadOnly @ 0x0c;
+        command_list[port]: u32, ReadWrite @ 0x100, stride 0x80;
+        command_list_upper[port]: u32, ReadWrite @ 0x104, stride 0x80;
+        fis_base[port]: u32, ReadWrite @ 0x108, stride 0x80;
+        fis_base_upper[port]: u32, ReadWrite @ 0x10c, stride 0x80;
+        port_interrupt_status[port]: u32, ReadWrite @ 0x110, stride 0x80;
+        port_interrupt_enable[port]: u32, ReadWrite @ 0x114, stride 0x80;
+        command[port]: u32, ReadWrite @ 0x118, stride 0x80;
+        task_file[port]: u32, ReadOnly @ 0x120, stride 0x80;
+        signature[port]: u32, ReadOnly @ 0x124, stride 0x80;
+        sata_status[port]: u32, ReadOnly @ 0x128, stride 0x80;
+        sata_error[port]: u32, ReadWrite @ 0x130, stride 0x80;
+        command_issue[port]: u32, ReadWrite @ 0x138, stride 0x80;
+    }
+}
+
+// global host control
+const GHC_INTERRUPT_ENABLE: u32 = 1 << 1;
+const GHC_AHCI_ENABLE: u32 = 1 << 31;
+// port command and status
+const CMD_START: u32 = 1 << 0;
+const CMD_SPIN_UP: u32 = 1 << 1;
+const CMD_POWER_ON: u32 = 1 << 2;
+const CMD_FIS_RECEIVE: u32 = 1 << 4;
+const CMD_FIS_RUNNING: u32 = 1 << 14;
+const CMD_LIST_RUNNING: u32 = 1 << 15;
+// port interrupt status and enable
+const IS_D2H_REGISTER: u32 = 1 << 0;
+const IS_PIO_SETUP: u32 = 1 << 1;
+/// Interface, host bus data, host bus fatal and task file errors.
+const IS_ERROR: u32 = 0xf << 27;
+// task file data, the device's status register
+const TFD_ERROR: u32 = 1 << 0;
+const TFD_DRQ: u32 = 1 << 3;
+const TFD_BUSY: u32 = 1 << 7;
+/// Device detection in the SATA status: a device is present and talking.
+const DET_PRESENT: u32 = 3;
+const SIGNATURE_ATA: u32 = 0x0000_0101;
+
+// ATA commands
+const IDENTIFY: u8 = 0xec;
+const READ_DMA_EXT: u8 = 0x25;
+const WRITE_DMA_EXT: u8 = 0x35;
+
+const FIS_TYPE_REG_H2D: u8 = 0x27;
+/// Marks a host to device FIS as a command rather than a control update.
+const FIS_COMMAND: u8 = 1 << 7;
+const DEVICE_LBA: u8 = 1 << 6;
+/// Length of a host to device register FIS in dwords.
+const FIS_DWORDS: u32 = 5;
+
+// the page of every port: the command list, the received FIS area and the
+// command table of slot 0, whose PRD table starts at `PRDT_OFFSET`
+const FIS_OFFSET: u64 = 0x400;
+const TABLE_OFFSET: u64 = 0x500;
+const PRDT_OFFSET: u64 = 0x80;
+const HEADER_WRITE: u32 = 1 << 6;
+const PRD_INTERRUPT: u32 = 1 << 31;
+
+/// Sectors the bounce buffer holds, the most one command transfers.
+const BOUNCE_SECTORS: usize = 128;
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long starting and stopping the command engine may take.
+const ENGINE_TIMEOUT: Duration = Duration::from_millis(500);
+
+static REGISTERS: Once<Registers> = Once::new();
+/// Port interrupt status bits seen by the interrupt handler since the last
+/// command was issued.
+static STATUS: [AtomicU32; PORTS] = {
+    const ZERO: AtomicU32 = AtomicU32::new(0);
+    [ZERO; PORTS]
+};
+/// Threads waiting for the command of a port to complete.
+static COMPLETION: [WaitQueue; PORTS] = {
+    const EMPTY: WaitQueue = WaitQueue::new();
+    [EMPTY; PORTS]
+};
+
+/// A disk on a port of the controller.
+struct Disk {
+    registers: &'static Registers,
+    port: u64,
+    sectors: u64,
+    model: String,
+    /// The command list, received FIS area and command table.
+    memory: DmaRegion,
+    bounce: DmaRegion,
+    /// Whether a thread is running a command.
+    busy: AtomicBool,
+    idle: WaitQueue,
+}
+
+/// Spins until `done` returns true, or gives up after `timeout`.
+fn wait_for(mut done: impl FnMut() -> bool, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    while !done() {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        core::hint::spin_loop();
+    }
+    true
+}
+
+/// The host to device register FIS of an ATA command.
+fn command_fis(command: u8, lba: u64, count: u16) -> [u8; 20] {
+    let lba = lba.to_le_bytes();
+    let count = count.to_le_bytes();
+    [
+        FIS_TYPE_REG_H2D, FIS_COMMAND, command, 0,
+        lba[0], lba[1], lba[2], DEVICE_LBA,
+        lba[3], lba[4], lba[5], 0,
+        count[0], count[1], 0, 0,
+        0, 0, 0, 0,
+    ]
+}
+
+impl Disk {
+    /// Sets the port up and identifies the disk on it.
+    fn start(registers: &'static Registers, port: u64) -> Result<Disk, &'static str> {
+        let mut disk = Disk {
+            registers,
+            port,
+            sectors: 0,
+            model: String::new(),
+            memory: memory::allocate_dma(0x1000)?,
+            bounce: memory::allocate_dma((BOUNCE_SECTORS * SECTOR_SIZE) as u64)?,
+            busy: AtomicBool::new(false),
+            idle: WaitQueue::new(),
+        };
+        disk.stop_engine()?;
+        let phys = disk.memory.phys.as_u64();
+        registers.command_list(port).write(phys as u32);
+        registers.command_list_upper(port).write((phys >> 32) as u32);
+        registers.fis_base(port).write((phys + FIS_OFFSET) as u32);
+        registers.fis_base_upper(port).write(((phys + FIS_OFFSET) >> 32) as u32);
+        registers.port_interrupt_enable(port).write(IS_D2H_REGISTER | IS_PIO_SETUP | IS_ERROR);
+        disk.start_engine();
+
+        let identify = disk.identify()?;
+        // 48-bit addressing, which the DMA EXT commands need
+        if identify[83] & (1 << 10) == 0 {
+            return Err("disk without 48-bit addressing");
+        }
+        disk.sectors = identify[100..104].iter().rev().fold(0, |sectors, &word| sectors << 16 | u64::from(word));
+        disk.model = model_name(&identify);
+        Ok(disk)
+    }
+
+    /// Stops processing the command list and receiving FISes.
+    fn stop_engine(&self) -> Result<(), &'static str> {
+        let command = self.registers.command(self.port);
+        command.modify(|value| value & !(CMD_START | CMD_FIS_RECEIVE));
+        if !wait_for(|| command.read() & (CMD_LIST_RUNNING | CMD_FIS_RUNNING) == 0, ENGINE_TIMEOUT) {
+            return Err("port does not stop");
+        }
+        Ok(())
+    }
+
+    /// Clears the errors of the port and starts processing the command list.
+    fn start_engine(&self) {
+        // both are cleared by writing ones
+        self.registers.sata_error(self.port).write(u32::MAX);
+        self.registers.port_interrupt_status(self.port).write(u32::MAX);
+        let command = self.registers.command(self.port);
+        command.modify(|value| value | CMD_SPIN_UP | CMD_POWER_ON | CMD_FIS_RECEIVE);
+        command.modify(|value| value | CMD_START);
+    }
+
+    /// Runs `f` as the only thread using the disk.
+    fn exclusive<R>(&self, f: impl FnOnce() -> R) -> R {
+        self.idle.wait_until(|| !self.busy.swap(true, Ordering::Acquire));
+        let result = f();
+        self.busy.store(false, Ordering::Release);
+        self.idle.notify_one();
+        result
+    }
+
+    /// Runs `command` on `bytes` of the bounce buffer and waits for it, by
+    /// polling the port if `poll`.
+    fn issue(&self, command: u8, lba: u64, bytes: usize, write: bool, poll: bool) -> Result<(), &'static str> {
+        let (registers, port) = (self.registers, self.port);
+        if !wait_for(|| registers.task_file(port).read() & (TFD_BUSY | TFD_DRQ) == 0, COMMAND_TIMEOUT) {
+            return Err("disk is busy");
+        }
+        let table = self.memory.phys.as_u64() + TABLE_OFFSET;
+        let bounce = self.bounce.phys.as_u64();
+        let fis = command_fis(command, lba, (bytes / SECTOR_SIZE) as u16);
+        unsafe {
+            let header = self.memory.virt.as_mut_ptr::<u32>();
+            header.write_volatile(FIS_DWORDS | if write { HEADER_WRITE } else { 0 } | 1 << 16);
+            // bytes transferred, counted by the controller
+            header.add(1).write_volatile(0);
+            header.add(2).write_volatile(table as u32);
+            header.add(3).write_volatile((table >> 32) as u32);
+
+            let table = self.memory.virt + TABLE_OFFSET;
+            core::ptr::copy_nonoverlapping(fis.as_ptr(), table.as_mut_ptr::<u8>(), fis.len());
+            let prd = (table + PRDT_OFFSET).as_mut_ptr::<u32>();
+            prd.write_volatile(bounce as u32);
+            prd.add(1).write_volatile((bounce >> 32) as u32);
+            prd.add(2).write_volatile(0);
+            prd.add(3).write_volatile((bytes - 1) as u32 | PRD_INTERRUPT);
+        }
+        // the controller must see the command table before the slot is issued
+        fence(Ordering::SeqCst);
+
+        let status = &STATUS[port as usize];
+        status.store(0, Ordering::Relaxed);
+        registers.command_issue(port).write(1);
+        if poll {
+            // the interrupt handler may not run, so check the port's own status
+            wait_for(|| {
+                let done = registers.command_issue(port).read() & 1 == 0;
+                status.fetch_or(registers.port_interrupt_status(port).read(), Ordering::Relaxed);
+                done || status.load(Ordering::Relaxed) & IS_ERROR != 0
+            }, COMMAND_TIMEOUT);
+        } else {
+            let deadline = Instant::now() + COMMAND_TIMEOUT;
+            let timer = time::add_timer(deadline, wake_port, port);
+            COMPLETION[port as usize].wait_until(|| {
+                registers.command_issue(port).read() & 1 == 0
+                    || status.load(Ordering::Relaxed) & IS_ERROR != 0
+                    || Instant::now() >= deadline
+            });
+            time::cancel_timer(timer);
+        }
+
+        let failed = status.load(Ordering::Relaxed) & IS_ERROR != 0 || registers.task_file(port).read() & TFD_ERROR != 0;
+        if failed || registers.command_issue(port).read() & 1 != 0 {
+            // restarting the engine drops the command and clears the error
+            self.stop_engine()?;
+            self.start_engine();
+            return Err(if failed { "disk command failed" } else { "disk command timed out" });
+        }
+        Ok(())
+    }
+
+    /// The 256 words of the disk's IDENTIFY DEVICE data.
+    fn identify(&self) -> Result<[u16; 256], &'static str> {
+        self.exclusive(|| {
+            self.issue(IDENTIFY, 0, SECTOR_SIZE, false, false)?;
+            let mut words = [0u16; 256];
+            let data = self.bounce.virt.as_ptr::<u16>();
+            for (i, word) in words.iter_mut().enumerate() {
+                *word = unsafe { data.add(i).read_volatile() };
+            }
+            Ok(words)
+        })
+    }
+}
+
+/// The model name from the IDENTIFY DEVICE data, whose strings store the
+/// first character of every pair in the high byte.
+pub(crate) fn model_name(identify: &[u16; 256]) -> String {
+    let bytes: Vec<u8> = identify[27..47].iter().flat_map(|word| word.to_be_bytes()).collect();
+    String::from(String::from_utf8_lossy(&bytes).trim())
+}
+
+impl BlockDevice for Disk {
+    fn sector_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_sectors(&self, start: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        block::check_range(start, buf.len(), self.sectors)?;
+        self.exclusive(|| {
+            for (i, chunk) in buf.chunks_mut(BOUNCE_SECTORS * SECTOR_SIZE).enumerate() {
+                self.issue(READ_DMA_EXT, start + (i * BOUNCE_SECTORS) as u64, chunk.len(), false, false)?;
+                unsafe { core::ptr::copy_nonoverlapping(self.bounce.virt.as_ptr::<u8>(), chunk.as_mut_ptr(), chunk.len()) };
+            }
+            Ok(())
+        })
+    }
+
+    fn write_sectors(&self, start: u64, buf: &[u8]) -> Result<(), &'static str> {
+        block::check_range(start, buf.len(), self.sectors)?;
+        self.exclusive(|| {
+            for (i, chunk) in buf.chunks(BOUNCE_SECTORS * SECTOR_SIZE).enumerate() {
+                unsafe { core::ptr::copy_nonoverlapping(chunk.as_ptr(), self.bounce.virt.as_mut_ptr::<u8>(), chunk.len()) };
+                self.issue(WRITE_DMA_EXT, start + (i * BOUNCE_SECTORS) as u64, chunk.len(), true, false)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn write_sectors_polled(&self, start: u64, buf: &[u8]) -> Result<(), &'static str> {
+        block::check_range(start, buf.len(), self.sectors)?;
+        if self.busy.swap(true, Ordering::Acquire) {
+            return Err("disk in use");
+        }
+        let mut result = Ok(());
+        for (i, chunk) in buf.chunks(BOUNCE_SECTORS * SECTOR_SIZE).enumerate() {
+            unsafe { core::ptr::copy_nonoverlapping(chunk.as_ptr(), self.bounce.virt.as_mut_ptr::<u8>(), chunk.len()) };
+            result = self.issue(WRITE_DMA_EXT, start + (i * BOUNCE_SECTORS) as u64, chunk.len(), true, true);
+            if result.is_err() {
+                break;
+            }
+        }
+        self.busy.store(false, Ordering::Release);
+        self.idle.notify_one();
+        result
+    }
+}
+
+/// Finds the controller on the PCI bus and registers the disks on its ports
+/// as block devices.
+pub fn init() -> Result<(), &'static str> {
+    let dev = pci::find_class(CLASS_STORAGE, SUBCLASS_SATA, PROG_IF_AHCI).ok_or("no AHCI controller")?;
+    let abar = match dev.bar(5) {
+        Some(pci::Bar::Memory(addr)) => addr,
+        _ => return Err("AHCI controller without a memory BAR"),
+    };
+    let irq = dev.interrupt_line().ok_or("AHCI controller without an IRQ line")?;
+    dev.enable_bus_mastering();
+    let registers: Registers = mmio::map(PhysAddr::new(abar))?;
+    let registers = REGISTERS.call_once(|| registers);
+    registers.global_control().modify(|value| value | GHC_AHCI_ENABLE);
+    interrupts::register_shared_irq(irq, handle_interrupt)?;
+    registers.interrupt_status().write(u32::MAX);
+    registers.global_control().modify(|value| value | GHC_INTERRUPT_ENABLE);
+    log_info!("ahci: {:02x}:{:02x}.{} irq {}, {} ports", dev.bus, dev.device, dev.function, irq,
+        (registers.capabilities().read() & 0x1f) + 1);
+
+    let implemented = registers.ports_implemented().read();
+    for port in (0..PORTS as u64).filter(|port| implemented & (1 << port) != 0) {
+        if registers.sata_status(port).read() & 0xf != DET_PRESENT || registers.signature(port).read() != SIGNATURE_ATA {
+            continue;
+        }
+        match Disk::start(registers, port) {
+            Ok(disk) => {
+                let disk = Arc::new(disk);
+                let name = block::register("sd", disk.clone());
+                log_info!("ahci: {} on port {}: {}, {} MiB", name, port, disk.model, disk.sectors * SECTOR_SIZE as u64 >> 20);
+            }
+            Err(error) => log_info!("ahci: port {}: {}", port, error),
+        }
+    }
+    Ok(())
+}
+initcall!(Driver, "ahci", init, after: &["fs"]);
+
+/// Timer callback: wakes the thread waiting for a command of `port`, which
+/// then notices that the command timed out.
+fn wake_port(port: u64) {
+    COMPLETION[port as usize].notify_all();
+}
+
+fn handle_interrupt() {
+    let registers = match REGISTERS.get() {
+        Some(registers) => registers,
+        None => return,
+    };
+    let pending = registers.interrupt_status().read();
+    for port in (0..PORTS as u64).filter(|port| pending & (1 << port) != 0) {
+        let status = registers.port_interrupt_status(port).read();
+        registers.port_interrupt_status(port).write(status);
+        STATUS[port as usize].fetch_or(status, Ordering::Relaxed);
+        COMPLETION[port as usize].notify_all();
+    }
+    // cleared after the ports, whose pending bits would set it again
+    registers.interrupt_status().write(pending);
+}
+
+#[test_case]
+fn test_command_fis() {
+    let fis = command_fis(READ_DMA_EXT, 0x0605_0403_0201, 0x0180);
+    assert_eq!(&fis[..4], &[0x27, 0x80, 0x25, 0]);
+    assert_eq!(&fis[4..8], &[0x01, 0x02, 0x03, DEVICE_LBA]);
+    assert_eq!(&fis[8..12], &[0x04, 0x05, 0x06, 0]);
+    assert_eq!(&fis[12..14], &[0x80, 0x01]);
+
+    let mut identify = [0u16; 256];
+    identify[27] = u16::from_be_bytes(*b"QE");
+    identify[28] = u16::from_be_bytes(*b"MU");
+    identify[29] = u16::from_be_bytes(*b" H");
+    identify[30] = u16::from_be_bytes(*b"DD");
+    for word in &mut identify[31..47] {
+        *word = u16::from_be_bytes(*b"  ");
+    }
+    assert_eq!(model_name(&identify), "QEMU HDD");
+}
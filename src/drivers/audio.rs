@@ -0,0 +1,139 @@
+//! Software mixer between kernel tasks producing PCM audio and the sound card.
+//!
+//! Samples are signed 16-bit, stereo interleaved (left, right), at
+//! `SAMPLE_RATE`. Every `Stream` writes at its own position in a shared ring;
+//! where streams overlap their samples are added. The driver drains the ring
+//! from its completion interrupt, padding with silence when it runs dry.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::Mutex;
+use crate::arch::interrupts::without_interrupts;
+
+pub const SAMPLE_RATE: u32 = 48_000;
+pub const CHANNELS: usize = 2;
+/// Length of the ring in samples: half a second of audio.
+const RING_SAMPLES: usize = SAMPLE_RATE as usize * CHANNELS / 2;
+
+/// The mixing ring. Positions are absolute sample counts since boot.
+struct Mixer {
+    ring: Vec<i32>,
+    /// Next sample handed to the driver.
+    read: u64,
+    /// End of the furthest sample any stream has written.
+    end: u64,
+}
+
+impl Mixer {
+    fn new(len: usize) -> Mixer {
+        Mixer { ring: vec![0; len], read: 0, end: 0 }
+    }
+
+    /// Mixes `samples` in starting at `*position`, as far as the ring has room.
+    /// Returns how many were taken and advances `position` past them.
+    fn mix(&mut self, position: &mut u64, samples: &[i16]) -> usize {
+        // a stream that fell behind the driver continues from what is playing now,
+        // keeping the channels of a frame in order
+        if *position < self.read {
+            *position += (self.read - *position + CHANNELS as u64 - 1) / CHANNELS as u64 * CHANNELS as u64;
+        }
+        let room = (self.read + self.ring.len() as u64).saturating_sub(*position) as usize;
+        let count = samples.len().min(room);
+        let len = self.ring.len() as u64;
+        for (i, &sample) in samples[..count].iter().enumerate() {
+            self.ring[((*position + i as u64) % len) as usize] += i32::from(sample);
+        }
+        *position += count as u64;
+        self.end = self.end.max(*position);
+        count
+    }
+
+    /// Moves the next `out.len()` samples out of the ring, clipped to 16 bits.
+    fn drain(&mut self, out: &mut [i16]) {
+        let len = self.ring.len() as u64;
+        for sample in out {
+            let slot = &mut self.ring[(self.read % len) as usize];
+            *sample = (*slot).clamp(i32::from(i16::MIN), i32::from(i16::MAX)) as i16;
+            *slot = 0;
+            self.read += 1;
+        }
+        self.end = self.end.max(self.read);
+    }
+
+    fn queued(&self) -> usize {
+        (self.end - self.read) as usize
+    }
+}
+
+static MIXER: Mutex<Option<Mixer>> = Mutex::new(None);
+
+/// A producer of audio, mixed with all other streams.
+#[derive(Debug)]
+pub struct Stream {
+    position: u64,
+}
+
+impl Stream {
+    /// Opens a stream that starts playing after the audio already queued.
+    pub fn new() -> Stream {
+        let position = without_interrupts(|| MIXER.lock().as_ref().map_or(0, |mixer| mixer.end));
+        Stream { position: position + position % CHANNELS as u64 }
+    }
+
+    /// Queues interleaved stereo `samples` after the ones this stream wrote
+    /// before. Returns how many fit in the ring; the caller retries the rest later.
+    pub fn write(&mut self, samples: &[i16]) -> usize {
+        without_interrupts(|| {
+            let mut mixer = MIXER.lock();
+            let mixer = mixer.get_or_insert_with(|| Mixer::new(RING_SAMPLES));
+            mixer.mix(&mut self.position, samples)
+        })
+    }
+}
+
+impl Default for Stream {
+    fn default() -> Self {
+        Stream::new()
+    }
+}
+
+/// Number of samples waiting to be played.
+pub fn queued() -> usize {
+    without_interrupts(|| MIXER.lock().as_ref().map_or(0, Mixer::queued))
+}
+
+/// Called by the driver, usually from its interrupt handler, to fill `out` with
+/// the next samples to play. Returns false, leaving `out` untouched, if the
+/// mixer is busy.
+pub fn fill(out: &mut [i16]) -> bool {
+    let mut mixer = match MIXER.try_lock() {
+        Some(mixer) => mixer,
+        None => return false,
+    };
+    match mixer.as_mut() {
+        Some(mixer) => mixer.drain(out),
+        None => out.fill(0),
+    }
+    true
+}
+
+#[test_case]
+fn test_streams_are_mixed_and_clipped() {
+    let mut mixer = Mixer::new(8);
+    let (mut first, mut second) = (0, 0);
+    assert_eq!(mixer.mix(&mut first, &[100, 200, i16::MAX, -5]), 4);
+    assert_eq!(mixer.mix(&mut second, &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10]), 8);
+    assert_eq!(mixer.queued(), 8);
+
+    let mut out = [0; 6];
+    mixer.drain(&mut out);
+    assert_eq!(out, [101, 202, i16::MAX, -1, 5, 6]);
+    // the ring has room again behind the samples that were drained
+    assert_eq!(mixer.mix(&mut second, &[9, 10]), 2);
+    // a stream that fell behind restarts at the playback position
+    let mut late = 0;
+    assert_eq!(mixer.mix(&mut late, &[1, 1]), 2);
+    assert_eq!(late, 8);
+    mixer.drain(&mut out[..4]);
+    assert_eq!(out[..4], [8, 9, 9, 10]);
+}
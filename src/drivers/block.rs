@@ -0,0 +1,126 @@
+//! Block devices: disks and whatever else stores fixed-size sectors.
+//!
+//! Drivers register their devices with `register`, under names like `sda`,
+//! and filesystems find them with `get`.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+use crate::arch::interrupts::without_interrupts;
+
+/// Bytes per sector.
+pub const SECTOR_SIZE: usize = 512;
+
+/// A device that reads and writes whole sectors.
+pub trait BlockDevice: Send + Sync {
+    /// Number of sectors on the device.
+    fn sector_count(&self) -> u64;
+
+    /// Reads the sectors from `start` into `buf`, whose length must be a
+    /// multiple of `SECTOR_SIZE`.
+    fn read_sectors(&self, start: u64, buf: &mut [u8]) -> Result<(), &'static str>;
+
+    /// Writes `buf`, whose length must be a multiple of `SECTOR_SIZE`, to the
+    /// sectors from `start`.
+    fn write_sectors(&self, start: u64, buf: &[u8]) -> Result<(), &'static str>;
+
+    /// Like `write_sectors`, but polls the device instead of sleeping and
+    /// fails rather than waiting for another user of it, for when the kernel
+    /// is going down, like `crashdump`.
+    fn write_sectors_polled(&self, start: u64, buf: &[u8]) -> Result<(), &'static str> {
+        let _ = (start, buf);
+        Err("device can't write without interrupts")
+    }
+}
+
+/// Checks that `len` bytes are whole sectors and that they fit on a device of
+/// `sector_count` sectors from `start`. Returns the number of sectors.
+pub fn check_range(start: u64, len: usize, sector_count: u64) -> Result<u64, &'static str> {
+    if len % SECTOR_SIZE != 0 {
+        return Err("buffer is not a multiple of the sector size");
+    }
+    let count = (len / SECTOR_SIZE) as u64;
+    match start.checked_add(count) {
+        Some(end) if end <= sector_count => Ok(count),
+        _ => Err("sectors beyond the end of the device"),
+    }
+}
+
+/// A block device in memory.
+pub struct RamDisk {
+    data: Mutex<Vec<u8>>,
+}
+
+impl RamDisk {
+    /// A disk holding `data`, padded with zeros to whole sectors.
+    pub fn new(mut data: Vec<u8>) -> RamDisk {
+        let len = (data.len() + SECTOR_SIZE - 1) / SECTOR_SIZE * SECTOR_SIZE;
+        data.resize(len, 0);
+        RamDisk { data: Mutex::new(data) }
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn sector_count(&self) -> u64 {
+        (self.data.lock().len() / SECTOR_SIZE) as u64
+    }
+
+    fn read_sectors(&self, start: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        check_range(start, buf.len(), self.sector_count())?;
+        let offset = start as usize * SECTOR_SIZE;
+        buf.copy_from_slice(&self.data.lock()[offset..offset + buf.len()]);
+        Ok(())
+    }
+
+    fn write_sectors(&self, start: u64, buf: &[u8]) -> Result<(), &'static str> {
+        check_range(start, buf.len(), self.sector_count())?;
+        let offset = start as usize * SECTOR_SIZE;
+        self.data.lock()[offset..offset + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn write_sectors_polled(&self, start: u64, buf: &[u8]) -> Result<(), &'static str> {
+        let mut data = self.data.try_lock().ok_or("disk in use")?;
+        check_range(start, buf.len(), (data.len() / SECTOR_SIZE) as u64)?;
+        let offset = start as usize * SECTOR_SIZE;
+        data[offset..offset + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+}
+
+static DEVICES: Mutex<Vec<(String, Arc<dyn BlockDevice>)>> = Mutex::new(Vec::new());
+
+/// Makes `device` available under the next free name with `prefix`, like
+/// `sda` for the first device with the prefix `sd`. Returns the name.
+pub fn register(prefix: &str, device: Arc<dyn BlockDevice>) -> String {
+    without_interrupts(|| {
+        let mut devices = DEVICES.lock();
+        let name = (b'a'..=b'z')
+            .map(|letter| format!("{}{}", prefix, char::from(letter)))
+            .find(|name| !devices.iter().any(|(other, _)| other == name))
+            .expect("too many block devices");
+        devices.push((name.clone(), device));
+        name
+    })
+}
+
+/// The device called `name`.
+pub fn get(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    without_interrupts(|| DEVICES.lock().iter().find(|(other, _)| other == name).map(|(_, device)| device.clone()))
+}
+
+/// The names of the registered devices with their sizes in sectors.
+pub fn list() -> Vec<(String, u64)> {
+    without_interrupts(|| DEVICES.lock().iter().map(|(name, device)| (name.clone(), device.sector_count())).collect())
+}
+
+#[test_case]
+fn test_check_range() {
+    assert_eq!(check_range(0, 1024, 2), Ok(2));
+    assert_eq!(check_range(1, 512, 2), Ok(1));
+    assert!(check_range(2, 512, 2).is_err());
+    assert!(check_range(u64::MAX, 512, 2).is_err());
+    assert!(check_range(0, 100, 2).is_err());
+}
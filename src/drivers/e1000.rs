@@ -0,0 +1,347 @@
+//! Intel 8254x gigabit Ethernet controller, as emulated by QEMU with
+//! `-device e1000` (the default network card of the pc machine).
+//!
+//! The controller sends and receives through two rings of descriptors. Every
+//! receive descriptor points at an empty `PacketBuf` from the network buffer
+//! pool, which the controller writes a frame into and which is handed up the
+//! stack as it is, a fresh one taking its place. Every transmit descriptor
+//! points at the `PacketBuf` being sent, kept until the controller is done
+//! with it. The interrupt handler only wakes the `net` thread.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{fence, Ordering};
+use spin::{Mutex, Once};
+use x86_64::PhysAddr;
+use crate::arch::interrupts;
+use crate::drivers::pci;
+use crate::memory::{self, DmaRegion};
+use crate::net::{self, Device, MacAddress, PacketBuf};
+use crate::{initcall, log_info, mmio};
+
+const VENDOR_INTEL: u16 = 0x8086;
+const DEVICE_82540EM: u16 = 0x100e;
+
+crate::register_block! {
+    struct Registers, size 0x5800 {
+        control: u32, ReadWrite @ 0x0000;
+        status: u32, ReadOnly @ 0x0008;
+        eeprom_read: u32, ReadWrite @ 0x0014;
+        interrupt_cause: u32, ReadOnly @ 0x00c0;
+        interrupt_mask_set: u32, ReadWrite @ 0x00d0;
+        interrupt_mask_clear: u32, WriteOnly @ 0x00d8;
+        rx_control: u32, ReadWrite @ 0x0100;
+        tx_control: u32, ReadWrite @ 0x0400;
+        tx_ipg: u32, ReadWrite @ 0x0410;
+        rx_base: u32, ReadWrite @ 0x2800;
+        rx_base_upper: u32, ReadWrite @ 0x2804;
+        rx_length: u32, ReadWrite @ 0x2808;
+        rx_head: u32, ReadWrite @ 0x2810;
+        rx_tail: u32, ReadWrite @ 0x2818;
+        tx_base: u32, ReadWrite @ 0x3800;
+        tx_base_upper: u32, ReadWrite @ 0x3804;
+        tx_length: u32, ReadWrite @ 0x3808;
+        tx_head: u32, ReadWrite @ 0x3810;
+        tx_tail: u32, ReadWrite @ 0x3818;
+        multicast[n]: u32, ReadWrite @ 0x5200, stride 4;
+        receive_address_low: u32, ReadWrite @ 0x5400;
+        receive_address_high: u32, ReadWrite @ 0x5404;
+    }
+}
+
+// device control
+const CTRL_SET_LINK_UP: u32 = 1 << 6;
+const CTRL_RESET: u32 = 1 << 26;
+const STATUS_LINK_UP: u32 = 1 << 1;
+// EEPROM read
+const EERD_START: u32 = 1 << 0;
+const EERD_DONE: u32 = 1 << 4;
+// interrupt causes
+const ICR_LINK_CHANGE: u32 = 1 << 2;
+const ICR_RX_MIN_THRESHOLD: u32 = 1 << 4;
+const ICR_RX_OVERRUN: u32 = 1 << 6;
+const ICR_RX_TIMER: u32 = 1 << 7;
+// receive control: 2048 byte buffers, broadcasts accepted, CRC stripped
+const RCTL_ENABLE: u32 = 1 << 1;
+const RCTL_BROADCAST: u32 = 1 << 15;
+const RCTL_STRIP_CRC: u32 = 1 << 26;
+// transmit control, with the collision settings the manual recommends
+const TCTL_ENABLE: u32 = 1 << 1;
+const TCTL_PAD_SHORT: u32 = 1 << 3;
+const TCTL_COLLISION_THRESHOLD: u32 = 0x10 << 4;
+const TCTL_COLLISION_DISTANCE: u32 = 0x40 << 12;
+const TIPG_DEFAULT: u32 = 10 | 8 << 10 | 6 << 20;
+/// Marks the receive address as valid.
+const RAH_VALID: u32 = 1 << 31;
+
+// descriptor status and commands
+const DESC_DONE: u8 = 1 << 0;
+const DESC_END_OF_PACKET: u8 = 1 << 1;
+const CMD_END_OF_PACKET: u8 = 1 << 0;
+const CMD_INSERT_FCS: u8 = 1 << 1;
+const CMD_REPORT_STATUS: u8 = 1 << 3;
+
+/// Descriptors per ring, a multiple of 8 so the rings are multiples of 128 bytes.
+const RING: usize = 32;
+/// Largest frame sent, without the checksum.
+const MAX_FRAME: usize = 1514;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct RxDescriptor {
+    addr: u64,
+    length: u16,
+    checksum: u16,
+    status: u8,
+    errors: u8,
+    special: u16,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct TxDescriptor {
+    addr: u64,
+    length: u16,
+    checksum_offset: u8,
+    command: u8,
+    status: u8,
+    checksum_start: u8,
+    special: u16,
+}
+
+struct Rings {
+    /// The buffer of every receive descriptor.
+    rx: Vec<Option<PacketBuf>>,
+    /// The next receive descriptor the controller writes to.
+    rx_next: usize,
+    /// The frame of every transmit descriptor until it is sent.
+    tx: Vec<Option<PacketBuf>>,
+    /// The next free transmit descriptor.
+    tx_next: usize,
+    /// The oldest transmit descriptor that may still be in use.
+    tx_clean: usize,
+}
+
+struct E1000 {
+    registers: &'static Registers,
+    mac: MacAddress,
+    rx_ring: DmaRegion,
+    tx_ring: DmaRegion,
+    rings: Mutex<Rings>,
+}
+
+static REGISTERS: Once<Registers> = Once::new();
+
+impl E1000 {
+    fn rx_descriptor(&self, index: usize) -> *mut RxDescriptor {
+        unsafe { self.rx_ring.virt.as_mut_ptr::<RxDescriptor>().add(index) }
+    }
+
+    fn tx_descriptor(&self, index: usize) -> *mut TxDescriptor {
+        unsafe { self.tx_ring.virt.as_mut_ptr::<TxDescriptor>().add(index) }
+    }
+
+    /// Hands `buffer` to the controller in receive descriptor `index`.
+    fn give_rx(&self, rings: &mut Rings, index: usize, buffer: PacketBuf) {
+        let descriptor = RxDescriptor { addr: buffer.phys().as_u64(), length: 0, checksum: 0, status: 0, errors: 0, special: 0 };
+        unsafe { self.rx_descriptor(index).write_volatile(descriptor) };
+        rings.rx[index] = Some(buffer);
+    }
+
+    /// Forgets the frames the controller has sent.
+    fn clean_tx(&self, rings: &mut Rings) {
+        while rings.tx_clean != rings.tx_next {
+            let status = unsafe { self.tx_descriptor(rings.tx_clean).read_volatile() }.status;
+            if status & DESC_DONE == 0 {
+                break;
+            }
+            rings.tx[rings.tx_clean] = None;
+            rings.tx_clean = (rings.tx_clean + 1) % RING;
+        }
+    }
+}
+
+impl Device for E1000 {
+    fn mac(&self) -> MacAddress {
+        self.mac
+    }
+
+    fn transmit(&self, frame: PacketBuf) -> Result<(), &'static str> {
+        if frame.len() > MAX_FRAME {
+            return Err("frame too long");
+        }
+        let mut rings = self.rings.lock();
+        self.clean_tx(&mut rings);
+        let index = rings.tx_next;
+        if (index + 1) % RING == rings.tx_clean {
+            return Err("transmit ring full");
+        }
+        let descriptor = TxDescriptor {
+            addr: frame.phys().as_u64(),
+            length: frame.len() as u16,
+            checksum_offset: 0,
+            command: CMD_END_OF_PACKET | CMD_INSERT_FCS | CMD_REPORT_STATUS,
+            status: 0,
+            checksum_start: 0,
+            special: 0,
+        };
+        unsafe { self.tx_descriptor(index).write_volatile(descriptor) };
+        rings.tx[index] = Some(frame);
+        rings.tx_next = (index + 1) % RING;
+        // the controller must see the descriptor before the tail moves past it
+        fence(Ordering::SeqCst);
+        self.registers.tx_tail().write(rings.tx_next as u32);
+        Ok(())
+    }
+
+    fn receive(&self) -> Option<PacketBuf> {
+        let mut rings = self.rings.lock();
+        loop {
+            let index = rings.rx_next;
+            let descriptor = unsafe { self.rx_descriptor(index).read_volatile() };
+            if descriptor.status & DESC_DONE == 0 {
+                return None;
+            }
+            rings.rx_next = (index + 1) % RING;
+            let mut frame = rings.rx[index].take()?;
+            let valid = descriptor.status & DESC_END_OF_PACKET != 0 && descriptor.errors == 0;
+            // without a buffer to replace it, the frame is dropped and its buffer reused
+            let received = match PacketBuf::new(0) {
+                Ok(fresh) if valid => {
+                    self.give_rx(&mut rings, index, fresh);
+                    match frame.put(usize::from(descriptor.length)) {
+                        Ok(_) => Some(frame),
+                        Err(_) => None,
+                    }
+                }
+                _ => {
+                    self.give_rx(&mut rings, index, frame);
+                    None
+                }
+            };
+            fence(Ordering::SeqCst);
+            self.registers.rx_tail().write(index as u32);
+            if received.is_some() {
+                return received;
+            }
+        }
+    }
+}
+
+/// Reads word `address` of the EEPROM.
+fn read_eeprom(registers: &Registers, address: u8) -> u16 {
+    registers.eeprom_read().write(EERD_START | u32::from(address) << 8);
+    loop {
+        let value = registers.eeprom_read().read();
+        if value & EERD_DONE != 0 {
+            return (value >> 16) as u16;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// The address the controller was configured with, from the receive address
+/// registers or else the EEPROM.
+fn read_mac(registers: &Registers) -> MacAddress {
+    let (low, high) = (registers.receive_address_low().read(), registers.receive_address_high().read());
+    if high & RAH_VALID != 0 {
+        let [a, b, c, d] = low.to_le_bytes();
+        let [e, f, _, _] = high.to_le_bytes();
+        return MacAddress([a, b, c, d, e, f]);
+    }
+    let mut mac = [0; 6];
+    for (i, pair) in mac.chunks_mut(2).enumerate() {
+        pair.copy_from_slice(&read_eeprom(registers, i as u8).to_le_bytes());
+    }
+    MacAddress(mac)
+}
+
+/// Finds the controller on the PCI bus, resets it and registers it as a
+/// network interface. Must be called after `net::init`.
+pub fn init() -> Result<(), &'static str> {
+    let dev = pci::find(VENDOR_INTEL, DEVICE_82540EM).ok_or("no e1000 controller")?;
+    let base = match dev.bar(0) {
+        Some(pci::Bar::Memory(addr)) => addr,
+        _ => return Err("e1000 controller without a memory BAR"),
+    };
+    let irq = dev.interrupt_line().ok_or("e1000 controller without an IRQ line")?;
+    dev.enable_bus_mastering();
+    let registers: Registers = mmio::map(PhysAddr::new(base))?;
+    let registers = REGISTERS.call_once(|| registers);
+
+    registers.interrupt_mask_clear().write(u32::MAX);
+    registers.control().modify(|value| value | CTRL_RESET);
+    while registers.control().read() & CTRL_RESET != 0 {
+        core::hint::spin_loop();
+    }
+    registers.interrupt_mask_clear().write(u32::MAX);
+    registers.control().modify(|value| value | CTRL_SET_LINK_UP);
+    let mac = read_mac(registers);
+    (0..128).for_each(|n| registers.multicast(n).write(0));
+
+    let device = E1000 {
+        registers,
+        mac,
+        rx_ring: memory::allocate_dma((RING * core::mem::size_of::<RxDescriptor>()) as u64)?,
+        tx_ring: memory::allocate_dma((RING * core::mem::size_of::<TxDescriptor>()) as u64)?,
+        rings: Mutex::new(Rings {
+            rx: (0..RING).map(|_| None).collect(),
+            rx_next: 0,
+            tx: (0..RING).map(|_| None).collect(),
+            tx_next: 0,
+            tx_clean: 0,
+        }),
+    };
+    {
+        let mut rings = device.rings.lock();
+        for index in 0..RING {
+            device.give_rx(&mut rings, index, PacketBuf::new(0)?);
+        }
+    }
+    let (rx, tx) = (device.rx_ring.phys.as_u64(), device.tx_ring.phys.as_u64());
+    registers.rx_base().write(rx as u32);
+    registers.rx_base_upper().write((rx >> 32) as u32);
+    registers.rx_length().write((RING * core::mem::size_of::<RxDescriptor>()) as u32);
+    registers.rx_head().write(0);
+    // the controller owns every descriptor but the one at the tail
+    registers.rx_tail().write((RING - 1) as u32);
+    registers.rx_control().write(RCTL_ENABLE | RCTL_BROADCAST | RCTL_STRIP_CRC);
+
+    registers.tx_base().write(tx as u32);
+    registers.tx_base_upper().write((tx >> 32) as u32);
+    registers.tx_length().write((RING * core::mem::size_of::<TxDescriptor>()) as u32);
+    registers.tx_head().write(0);
+    registers.tx_tail().write(0);
+    registers.tx_ipg().write(TIPG_DEFAULT);
+    registers.tx_control().write(TCTL_ENABLE | TCTL_PAD_SHORT | TCTL_COLLISION_THRESHOLD | TCTL_COLLISION_DISTANCE);
+
+    let interface = net::register(Arc::new(device));
+    interrupts::register_shared_irq(irq, handle_interrupt)?;
+    // reading the cause clears it
+    registers.interrupt_cause().read();
+    registers.interrupt_mask_set().write(ICR_RX_TIMER | ICR_RX_OVERRUN | ICR_RX_MIN_THRESHOLD | ICR_LINK_CHANGE);
+    let link = if registers.status().read() & STATUS_LINK_UP != 0 { "up" } else { "down" };
+    log_info!("e1000: {} at {:02x}:{:02x}.{} irq {}, {}, link {}", interface.name, dev.bus, dev.device, dev.function,
+        irq, mac, link);
+    Ok(())
+}
+initcall!(Driver, "e1000", init, needs: &["net"]);
+
+fn handle_interrupt() {
+    let registers = match REGISTERS.get() {
+        Some(registers) => registers,
+        None => return,
+    };
+    // reading the cause acknowledges it; sent frames are forgotten on the
+    // next transmit, so only receiving needs attention
+    let cause = registers.interrupt_cause().read();
+    if cause & (ICR_RX_TIMER | ICR_RX_OVERRUN | ICR_RX_MIN_THRESHOLD) != 0 {
+        net::wake();
+    }
+}
+
+#[test_case]
+fn test_descriptor_layout() {
+    assert_eq!(core::mem::size_of::<RxDescriptor>(), 16);
+    assert_eq!(core::mem::size_of::<TxDescriptor>(), 16);
+}
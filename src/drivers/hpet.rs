@@ -0,0 +1,184 @@
+//! The High Precision Event Timer, found through its ACPI table.
+//!
+//! When there is one, `init` recalibrates the TSC against its counter, which is
+//! far more precise than the PIT. If the HPET can replace the legacy timers,
+//! timer 0 takes over the timer interrupt at `time::tick_rate`, and timer 1
+//! becomes a one-shot on IRQ 8 that `time::add_timer` arms, so timers expire
+//! at their deadline instead of at the next tick.
+
+use core::convert::TryInto;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Once;
+use x86_64::PhysAddr;
+use crate::arch::interrupts::{self, without_interrupts};
+use crate::time::{self, Instant};
+use crate::{acpi, initcall, mmio};
+
+crate::register_block! {
+    struct Registers, size 0x400 {
+        capabilities: u64, ReadOnly @ 0x000;
+        config: u64, ReadWrite @ 0x010;
+        counter: u64, ReadWrite @ 0x0f0;
+        timer_config[timer]: u64, ReadWrite @ 0x100, stride 0x20;
+        timer_comparator[timer]: u64, ReadWrite @ 0x108, stride 0x20;
+    }
+}
+
+// capabilities
+const WIDE_COUNTER: u64 = 1 << 13;
+const LEGACY_CAPABLE: u64 = 1 << 15;
+// general configuration
+const ENABLE: u64 = 1 << 0;
+const LEGACY_ROUTE: u64 = 1 << 1;
+// timer configuration
+const TIMER_INTERRUPT: u64 = 1 << 2;
+const TIMER_PERIODIC: u64 = 1 << 3;
+const PERIODIC_CAPABLE: u64 = 1 << 4;
+const TIMER_VALUE_SET: u64 = 1 << 6;
+
+/// Longest counter period the specification allows, 100 ns.
+const MAX_PERIOD_FS: u64 = 100_000_000;
+const FS_PER_SECOND: u64 = 1_000_000_000_000_000;
+/// Length of the TSC calibration.
+const CALIBRATION_MS: u64 = 10;
+/// The line timer 1 interrupts on in legacy replacement mode.
+const ONE_SHOT_IRQ: u8 = 8;
+
+/// The registers, set by `init` if there is an HPET.
+static REGISTERS: Once<Registers> = Once::new();
+/// Femtoseconds per counter increment.
+static PERIOD_FS: AtomicU64 = AtomicU64::new(0);
+/// Whether timer 1 is set up as the one-shot.
+static ONE_SHOT: AtomicBool = AtomicBool::new(false);
+/// TSC value the one-shot is armed for, zero when it is not armed.
+static ARMED: AtomicU64 = AtomicU64::new(0);
+
+/// Whether the HPET drives the timer interrupt instead of the PIT.
+pub fn drives_tick() -> bool {
+    ONE_SHOT.load(Ordering::Relaxed)
+}
+
+/// Whether `init` found an HPET.
+pub fn is_available() -> bool {
+    REGISTERS.get().is_some()
+}
+
+/// The main counter, or `None` without an HPET.
+pub fn counter() -> Option<u64> {
+    REGISTERS.get().map(|registers| registers.counter().read())
+}
+
+/// The counter frequency in Hz, zero without an HPET.
+pub fn frequency() -> u64 {
+    match PERIOD_FS.load(Ordering::Relaxed) {
+        0 => 0,
+        period => FS_PER_SECOND / period,
+    }
+}
+
+/// Finds and starts the HPET. Must be called after `memory::install`, with no
+/// timers pending.
+pub fn init() -> Result<(), &'static str> {
+    let table = acpi::find_table(b"HPET")?;
+    // the register block is a generic address structure at offset 40
+    if table.len() < 52 || table[40] != 0 {
+        return Err("HPET registers are not memory mapped");
+    }
+    let phys = u64::from_le_bytes(table[44..52].try_into().unwrap());
+    let registers: Registers = mmio::map(PhysAddr::new(phys))?;
+
+    let capabilities = registers.capabilities().read();
+    let period = capabilities >> 32;
+    if period == 0 || period > MAX_PERIOD_FS {
+        return Err("invalid HPET counter period");
+    }
+    let registers = REGISTERS.call_once(|| registers);
+    PERIOD_FS.store(period, Ordering::Relaxed);
+    let timers = (capabilities >> 8 & 0x1f) + 1;
+
+    registers.config().modify(|config| config & !(ENABLE | LEGACY_ROUTE));
+    registers.counter().write(0);
+    registers.config().modify(|config| config | ENABLE);
+    time::set_tsc_frequency(calibrate_tsc(registers));
+
+    let periodic = registers.timer_config(0).read() & PERIODIC_CAPABLE != 0;
+    if capabilities & LEGACY_CAPABLE == 0 || capabilities & WIDE_COUNTER == 0 || timers < 2 || !periodic {
+        crate::log_info!("hpet: {} Hz, timer interrupt left to the PIT", frequency());
+        return Ok(());
+    }
+    let tick_rate = time::tick_rate();
+    let tick = FS_PER_SECOND / tick_rate / period;
+    without_interrupts(|| {
+        registers.timer_config(0).write(TIMER_INTERRUPT | TIMER_PERIODIC | TIMER_VALUE_SET);
+        // with the value set bit, the second write sets the period
+        registers.timer_comparator(0).write(registers.counter().read() + tick);
+        registers.timer_comparator(0).write(tick);
+        registers.timer_config(1).write(TIMER_INTERRUPT);
+        registers.timer_comparator(1).write(u64::MAX);
+        // disconnects the PIT from IRQ 0 and the RTC from IRQ 8
+        registers.config().modify(|config| config | LEGACY_ROUTE);
+    });
+    interrupts::register_irq(ONE_SHOT_IRQ, one_shot_interrupt)?;
+    ONE_SHOT.store(true, Ordering::Relaxed);
+    crate::log_info!("hpet: {} Hz, timer interrupt at {} Hz", frequency(), tick_rate);
+    Ok(())
+}
+initcall!(Core, "hpet", init);
+
+/// Measures how many TSC cycles elapse in `CALIBRATION_MS` of the counter.
+fn calibrate_tsc(registers: &Registers) -> u64 {
+    let period = PERIOD_FS.load(Ordering::Relaxed);
+    let length = CALIBRATION_MS * 1_000_000_000_000 / period;
+    let start = registers.counter().read();
+    let start_tsc = time::rdtsc();
+    let mut end = start;
+    while end - start < length {
+        end = registers.counter().read();
+    }
+    let end_tsc = time::rdtsc();
+    (u128::from(end_tsc - start_tsc) * u128::from(FS_PER_SECOND) / (u128::from(end - start) * u128::from(period))) as u64
+}
+
+/// Makes the one-shot interrupt at `at`, unless it is armed for an earlier
+/// time already. Does nothing without a one-shot. A time that was not armed
+/// for is only noticed at the next tick.
+pub fn arm(at: Instant) {
+    let registers = match REGISTERS.get() {
+        Some(registers) if ONE_SHOT.load(Ordering::Relaxed) => registers,
+        _ => return,
+    };
+    let now = Instant::now();
+    if at <= now {
+        return;
+    }
+    let armed = ARMED.load(Ordering::Relaxed);
+    if armed > now.ticks() && armed <= at.ticks() {
+        return;
+    }
+    ARMED.store(at.ticks(), Ordering::Relaxed);
+    let period = u128::from(PERIOD_FS.load(Ordering::Relaxed));
+    // rounded up, so the interrupt never comes early
+    let delay = ((at - now).as_nanos() * 1_000_000 + period - 1) / period;
+    // the comparator only matches when the counter equals it, so it must be
+    // ahead of the counter by the time it is written
+    registers.timer_comparator(1).write(registers.counter().read() + delay as u64 + 1);
+}
+
+fn one_shot_interrupt() {
+    ARMED.store(0, Ordering::Relaxed);
+    time::tick();
+}
+
+#[test_case]
+fn test_counter() {
+    let start = match counter() {
+        Some(start) => start,
+        None => return,
+    };
+    let deadline = Instant::now() + core::time::Duration::from_millis(1);
+    while Instant::now() < deadline {}
+    let elapsed = counter().unwrap() - start;
+    // one millisecond, give or take the TSC calibration
+    let expected = frequency() / 1000;
+    assert!(elapsed > expected / 2 && elapsed < expected * 2);
+}
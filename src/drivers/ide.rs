@@ -0,0 +1,505 @@
+//! Parallel ATA disks on an IDE controller, like the PIIX3 of QEMU's pc
+//! machine that `-hda disk.img` attaches to.
+//!
+//! Each of the two channels runs one command at a time, for either of its
+//! drives. With the controller's bus master, reads and writes are DMA
+//! transfers through a bounce buffer described by a PRD table: the thread
+//! issuing the command blocks until the channel's interrupt says it is done,
+//! or a timer says it never will. Without a bus master, a drive without DMA
+//! or with `ide=pio`, sectors are moved through the data port instead,
+//! polling the drive between them.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{fence, AtomicBool, AtomicU8, Ordering};
+use core::time::Duration;
+use spin::Once;
+use crate::arch::interrupts;
+use crate::arch::x86_64::ioport::{self, PortRange};
+use crate::drivers::ahci::model_name;
+use crate::drivers::block::{self, BlockDevice, SECTOR_SIZE};
+use crate::drivers::pci::{self, Bar};
+use crate::memory::{self, DmaRegion};
+use crate::sched::WaitQueue;
+use crate::time::{self, Instant};
+use crate::{boot, initcall, log_info};
+
+// PCI class of IDE controllers: mass storage, IDE, any programming interface
+const CLASS_STORAGE: u8 = 0x01;
+const SUBCLASS_IDE: u8 = 0x01;
+/// Set in the programming interface for a channel in native mode, with its
+/// ports in BARs rather than at the ISA addresses.
+const PROG_IF_NATIVE: [u8; 2] = [1 << 0, 1 << 2];
+/// Command ports, control port and IRQ of the channels in compatibility mode.
+const COMPAT: [(u16, u16, u8); 2] = [(0x1f0, 0x3f6, 14), (0x170, 0x376, 15)];
+
+// command block registers
+const DATA: u16 = 0;
+const SECTOR_COUNT: u16 = 2;
+const LBA_MID: u16 = 4;
+const LBA_HIGH: u16 = 5;
+const DEVICE: u16 = 6;
+/// The status when read, the command when written.
+const STATUS: u16 = 7;
+const COMMAND: u16 = 7;
+// the control register: the alternate status when read
+const CONTROL_RESET: u8 = 1 << 2;
+
+const STATUS_ERROR: u8 = 1 << 0;
+const STATUS_DRQ: u8 = 1 << 3;
+const STATUS_FAULT: u8 = 1 << 5;
+const STATUS_BUSY: u8 = 1 << 7;
+const DEVICE_LBA: u8 = 1 << 6;
+const DEVICE_SLAVE: u8 = 1 << 4;
+
+// bus master registers of a channel
+const BM_COMMAND: u16 = 0;
+const BM_STATUS: u16 = 2;
+const BM_PRDT: u16 = 4;
+const BM_START: u8 = 1 << 0;
+/// The bus master writes to memory, reading from the disk.
+const BM_READ: u8 = 1 << 3;
+const BM_ERROR: u8 = 1 << 1;
+const BM_INTERRUPT: u8 = 1 << 2;
+/// Marks the last entry of a PRD table.
+const PRD_END: u32 = 1 << 31;
+/// PRD entries may not cross a boundary of this many bytes.
+const PRD_BOUNDARY: u64 = 0x10000;
+
+// ATA commands
+const IDENTIFY: u8 = 0xec;
+const READ_SECTORS_EXT: u8 = 0x24;
+const WRITE_SECTORS_EXT: u8 = 0x34;
+const READ_DMA_EXT: u8 = 0x25;
+const WRITE_DMA_EXT: u8 = 0x35;
+const FLUSH_CACHE_EXT: u8 = 0xea;
+
+/// Sectors the bounce buffer holds, the most one command transfers.
+const BOUNCE_SECTORS: usize = 128;
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a drive may take to answer IDENTIFY, or to say there is none.
+const IDENTIFY_TIMEOUT: Duration = Duration::from_millis(500);
+
+static CHANNELS: [Once<Channel>; 2] = [Once::new(), Once::new()];
+/// Set by the interrupt handler when the command of a channel completed.
+static DONE: [AtomicBool; 2] = [AtomicBool::new(false), AtomicBool::new(false)];
+/// Bus master status bits seen by the interrupt handler since the last command.
+static BM_SEEN: [AtomicU8; 2] = [AtomicU8::new(0), AtomicU8::new(0)];
+/// Threads waiting for the command of a channel to complete.
+static COMPLETION: [WaitQueue; 2] = [WaitQueue::new(), WaitQueue::new()];
+
+/// One of the two channels of the controller, with up to two drives.
+struct Channel {
+    index: usize,
+    command: PortRange,
+    control: PortRange,
+    bus_master: Option<PortRange>,
+    prdt: DmaRegion,
+    bounce: DmaRegion,
+    /// Whether a thread is running a command.
+    busy: AtomicBool,
+    idle: WaitQueue,
+}
+
+/// A drive on a channel.
+struct Disk {
+    channel: &'static Channel,
+    slave: bool,
+    sectors: u64,
+    model: String,
+    dma: bool,
+}
+
+/// Spins until `done` returns true, or gives up after `timeout`.
+fn wait_for(mut done: impl FnMut() -> bool, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    while !done() {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        core::hint::spin_loop();
+    }
+    true
+}
+
+/// What goes to the sector count and LBA registers of a 48-bit command, in
+/// the order it is written: the high bytes first, then the low ones.
+fn taskfile(lba: u64, count: u16) -> [[u8; 4]; 2] {
+    let lba = lba.to_le_bytes();
+    let count = count.to_le_bytes();
+    [[count[1], lba[3], lba[4], lba[5]], [count[0], lba[0], lba[1], lba[2]]]
+}
+
+/// The PRD entries for `len` bytes at `phys`, as address and byte count,
+/// split where they would cross a 64 KiB boundary.
+fn prd_entries(mut phys: u64, mut len: u64) -> Vec<(u32, u32)> {
+    let mut entries = Vec::new();
+    while len > 0 {
+        let size = len.min(PRD_BOUNDARY - phys % PRD_BOUNDARY);
+        entries.push((phys as u32, size as u32));
+        phys += size;
+        len -= size;
+    }
+    entries
+}
+
+impl Channel {
+    fn status(&self) -> u8 {
+        self.command.read(STATUS)
+    }
+
+    /// Waits the 400 ns the drive may take to show its status, by reading the
+    /// alternate status, which acknowledges nothing.
+    fn delay(&self) {
+        for _ in 0..4 {
+            self.control.read::<u8>(0);
+        }
+    }
+
+    fn wait_ready(&self, timeout: Duration) -> Result<(), &'static str> {
+        if !wait_for(|| self.status() & STATUS_BUSY == 0, timeout) {
+            return Err("disk is busy");
+        }
+        Ok(())
+    }
+
+    /// Selects the drive and writes the registers of a 48-bit command on
+    /// `count` sectors from `lba`.
+    fn select(&self, slave: bool, lba: u64, count: u16) {
+        self.command.write(DEVICE, DEVICE_LBA | if slave { DEVICE_SLAVE } else { 0 });
+        self.delay();
+        for bytes in &taskfile(lba, count) {
+            for (register, &byte) in (SECTOR_COUNT..=LBA_HIGH).zip(bytes) {
+                self.command.write(register, byte);
+            }
+        }
+    }
+
+    /// Resets both drives, dropping the command that got stuck.
+    fn reset(&self) {
+        self.control.write(0, CONTROL_RESET);
+        self.delay();
+        self.control.write(0, 0_u8);
+        let _ = self.wait_ready(COMMAND_TIMEOUT);
+    }
+
+    /// Runs `f` as the only thread using the channel.
+    fn exclusive<R>(&self, f: impl FnOnce() -> R) -> R {
+        self.idle.wait_until(|| !self.busy.swap(true, Ordering::Acquire));
+        let result = f();
+        self.busy.store(false, Ordering::Release);
+        self.idle.notify_one();
+        result
+    }
+
+    /// The 256 words of the IDENTIFY DEVICE data of a drive, or `None` if
+    /// there is no ATA drive.
+    fn identify(&self, slave: bool) -> Option<[u16; 256]> {
+        self.select(slave, 0, 0);
+        self.command.write(COMMAND, IDENTIFY);
+        self.delay();
+        // no drive, or no channel
+        if self.status() == 0 || !wait_for(|| self.status() & STATUS_BUSY == 0, IDENTIFY_TIMEOUT) {
+            return None;
+        }
+        // ATAPI and SATA drives put their signature there
+        if self.command.read::<u8>(LBA_MID) != 0 || self.command.read::<u8>(LBA_HIGH) != 0 {
+            return None;
+        }
+        if !wait_for(|| self.status() & (STATUS_DRQ | STATUS_ERROR) != 0, IDENTIFY_TIMEOUT)
+            || self.status() & STATUS_ERROR != 0 {
+            return None;
+        }
+        let mut words = [0u16; 256];
+        words.iter_mut().for_each(|word| *word = self.command.read(DATA));
+        Some(words)
+    }
+
+    /// Runs a DMA command on `bytes` of the bounce buffer and waits for its
+    /// interrupt.
+    fn dma(&self, slave: bool, command: u8, lba: u64, bytes: usize, write: bool) -> Result<(), &'static str> {
+        let bm = self.bus_master.as_ref().ok_or("no bus master")?;
+        self.wait_ready(COMMAND_TIMEOUT)?;
+        let entries = prd_entries(self.bounce.phys.as_u64(), bytes as u64);
+        let prdt = self.prdt.virt.as_mut_ptr::<u32>();
+        for (i, &(addr, size)) in entries.iter().enumerate() {
+            // a byte count of 0 is 64 KiB
+            let flags = if i + 1 == entries.len() { PRD_END } else { 0 };
+            unsafe {
+                prdt.add(2 * i).write_volatile(addr);
+                prdt.add(2 * i + 1).write_volatile((size & 0xffff) | flags);
+            }
+        }
+        // the controller must see the table before it starts
+        fence(Ordering::SeqCst);
+        bm.write(BM_COMMAND, 0_u8);
+        bm.write(BM_PRDT, self.prdt.phys.as_u64() as u32);
+        // both are cleared by writing ones
+        bm.write(BM_STATUS, BM_ERROR | BM_INTERRUPT);
+        let direction = if write { 0 } else { BM_READ };
+        bm.write(BM_COMMAND, direction);
+
+        let index = self.index;
+        DONE[index].store(false, Ordering::Relaxed);
+        BM_SEEN[index].store(0, Ordering::Relaxed);
+        self.select(slave, lba, (bytes / SECTOR_SIZE) as u16);
+        self.command.write(COMMAND, command);
+        bm.write(BM_COMMAND, direction | BM_START);
+
+        let deadline = Instant::now() + COMMAND_TIMEOUT;
+        let timer = time::add_timer(deadline, wake_channel, index as u64);
+        COMPLETION[index].wait_until(|| DONE[index].load(Ordering::Acquire) || Instant::now() >= deadline);
+        time::cancel_timer(timer);
+        bm.write(BM_COMMAND, 0_u8);
+
+        let done = DONE[index].load(Ordering::Acquire);
+        let bm_status = BM_SEEN[index].load(Ordering::Relaxed) | bm.read::<u8>(BM_STATUS);
+        let failed = bm_status & BM_ERROR != 0 || self.status() & (STATUS_ERROR | STATUS_FAULT) != 0;
+        if !done || failed {
+            self.reset();
+            return Err(if failed { "disk command failed" } else { "disk command timed out" });
+        }
+        Ok(())
+    }
+
+    /// Waits until the drive has a sector for us, or wants one.
+    fn wait_data(&self) -> Result<(), &'static str> {
+        self.delay();
+        if !wait_for(|| self.status() & STATUS_BUSY == 0, COMMAND_TIMEOUT) {
+            self.reset();
+            return Err("disk command timed out");
+        }
+        let status = self.status();
+        if status & (STATUS_ERROR | STATUS_FAULT) != 0 || status & STATUS_DRQ == 0 {
+            return Err("disk command failed");
+        }
+        Ok(())
+    }
+
+    /// Reads the sectors from `lba` into `buf` through the data port.
+    fn pio_read(&self, slave: bool, lba: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        self.wait_ready(COMMAND_TIMEOUT)?;
+        self.select(slave, lba, (buf.len() / SECTOR_SIZE) as u16);
+        self.command.write(COMMAND, READ_SECTORS_EXT);
+        for sector in buf.chunks_mut(SECTOR_SIZE) {
+            self.wait_data()?;
+            for pair in sector.chunks_mut(2) {
+                pair.copy_from_slice(&self.command.read::<u16>(DATA).to_le_bytes());
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes `buf` to the sectors from `lba` through the data port, and
+    /// flushes the drive's cache.
+    fn pio_write(&self, slave: bool, lba: u64, buf: &[u8]) -> Result<(), &'static str> {
+        self.wait_ready(COMMAND_TIMEOUT)?;
+        self.select(slave, lba, (buf.len() / SECTOR_SIZE) as u16);
+        self.command.write(COMMAND, WRITE_SECTORS_EXT);
+        for sector in buf.chunks(SECTOR_SIZE) {
+            self.wait_data()?;
+            for pair in sector.chunks(2) {
+                self.command.write(DATA, u16::from_le_bytes([pair[0], pair[1]]));
+            }
+        }
+        self.delay();
+        self.wait_ready(COMMAND_TIMEOUT)?;
+        self.command.write(COMMAND, FLUSH_CACHE_EXT);
+        self.delay();
+        self.wait_ready(COMMAND_TIMEOUT)?;
+        if self.status() & (STATUS_ERROR | STATUS_FAULT) != 0 {
+            return Err("disk command failed");
+        }
+        Ok(())
+    }
+}
+
+impl Disk {
+    fn read_chunk(&self, lba: u64, chunk: &mut [u8]) -> Result<(), &'static str> {
+        let channel = self.channel;
+        if !self.dma {
+            return channel.pio_read(self.slave, lba, chunk);
+        }
+        channel.dma(self.slave, READ_DMA_EXT, lba, chunk.len(), false)?;
+        unsafe { core::ptr::copy_nonoverlapping(channel.bounce.virt.as_ptr::<u8>(), chunk.as_mut_ptr(), chunk.len()) };
+        Ok(())
+    }
+
+    fn write_chunk(&self, lba: u64, chunk: &[u8]) -> Result<(), &'static str> {
+        let channel = self.channel;
+        if !self.dma {
+            return channel.pio_write(self.slave, lba, chunk);
+        }
+        unsafe { core::ptr::copy_nonoverlapping(chunk.as_ptr(), channel.bounce.virt.as_mut_ptr::<u8>(), chunk.len()) };
+        channel.dma(self.slave, WRITE_DMA_EXT, lba, chunk.len(), true)
+    }
+}
+
+impl BlockDevice for Disk {
+    fn sector_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_sectors(&self, start: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        block::check_range(start, buf.len(), self.sectors)?;
+        self.channel.exclusive(|| {
+            for (i, chunk) in buf.chunks_mut(BOUNCE_SECTORS * SECTOR_SIZE).enumerate() {
+                self.read_chunk(start + (i * BOUNCE_SECTORS) as u64, chunk)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn write_sectors(&self, start: u64, buf: &[u8]) -> Result<(), &'static str> {
+        block::check_range(start, buf.len(), self.sectors)?;
+        self.channel.exclusive(|| {
+            for (i, chunk) in buf.chunks(BOUNCE_SECTORS * SECTOR_SIZE).enumerate() {
+                self.write_chunk(start + (i * BOUNCE_SECTORS) as u64, chunk)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn write_sectors_polled(&self, start: u64, buf: &[u8]) -> Result<(), &'static str> {
+        block::check_range(start, buf.len(), self.sectors)?;
+        let channel = self.channel;
+        if channel.busy.swap(true, Ordering::Acquire) {
+            return Err("disk in use");
+        }
+        let mut result = Ok(());
+        for (i, chunk) in buf.chunks(BOUNCE_SECTORS * SECTOR_SIZE).enumerate() {
+            result = channel.pio_write(self.slave, start + (i * BOUNCE_SECTORS) as u64, chunk);
+            if result.is_err() {
+                break;
+            }
+        }
+        channel.busy.store(false, Ordering::Release);
+        channel.idle.notify_one();
+        result
+    }
+}
+
+/// Sets up channel `index` of the controller `dev`, and returns it with its IRQ
+/// and whether the IRQ is shared.
+fn start_channel(dev: &pci::PciDevice, index: usize, bus_master: Option<u16>) -> Result<(Channel, u8, bool), &'static str> {
+    let native = dev.prog_if & PROG_IF_NATIVE[index] != 0;
+    let (command, control, irq) = if native {
+        match (dev.bar(2 * index as u8), dev.bar(2 * index as u8 + 1), dev.interrupt_line()) {
+            (Some(Bar::Io(command)), Some(Bar::Io(control)), Some(irq)) => (command, control + 2, irq),
+            _ => return Err("IDE channel in native mode without its ports"),
+        }
+    } else {
+        COMPAT[index]
+    };
+    let channel = Channel {
+        index,
+        command: ioport::claim(command, 8, "ide")?,
+        control: ioport::claim(control, 1, "ide control")?,
+        bus_master: bus_master.map(|base| ioport::claim(base + 8 * index as u16, 8, "ide bus master")).transpose()?,
+        prdt: memory::allocate_dma(0x1000)?,
+        bounce: memory::allocate_dma((BOUNCE_SECTORS * SECTOR_SIZE) as u64)?,
+        busy: AtomicBool::new(false),
+        idle: WaitQueue::new(),
+    };
+    Ok((channel, irq, native))
+}
+
+/// Finds the controller on the PCI bus and registers the ATA drives on its
+/// channels as block devices.
+pub fn init() -> Result<(), &'static str> {
+    let dev = pci::enumerate()
+        .into_iter()
+        .find(|dev| (dev.class, dev.subclass) == (CLASS_STORAGE, SUBCLASS_IDE))
+        .ok_or("no IDE controller")?;
+    let pio = boot::cmdline().get("ide") == Some("pio");
+    let bus_master = match dev.bar(4) {
+        Some(Bar::Io(base)) if !pio => Some(base),
+        _ => None,
+    };
+    dev.enable_bus_mastering();
+    match bus_master {
+        Some(base) => log_info!("ide: {:02x}:{:02x}.{}, bus master at {:#x}", dev.bus, dev.device, dev.function, base),
+        None => log_info!("ide: {:02x}:{:02x}.{}, PIO only", dev.bus, dev.device, dev.function),
+    }
+
+    for index in 0..CHANNELS.len() {
+        let (channel, irq, shared) = match start_channel(&dev, index, bus_master) {
+            Ok(started) => started,
+            Err(error) => {
+                log_info!("ide: channel {}: {}", index, error);
+                continue;
+            }
+        };
+        let channel = CHANNELS[index].call_once(|| channel);
+        let handler: fn() = if index == 0 { || handle_interrupt(0) } else { || handle_interrupt(1) };
+        let registered = if shared {
+            interrupts::register_shared_irq(irq, handler)
+        } else {
+            interrupts::register_irq(irq, handler)
+        };
+        if let Err(error) = registered {
+            log_info!("ide: channel {}: irq {}: {}", index, irq, error);
+            continue;
+        }
+        for slave in [false, true] {
+            let identify = match channel.exclusive(|| channel.identify(slave)) {
+                Some(identify) => identify,
+                None => continue,
+            };
+            let position = if slave { "slave" } else { "master" };
+            // 48-bit addressing, which the EXT commands need
+            if identify[83] & (1 << 10) == 0 {
+                log_info!("ide: channel {} {}: disk without 48-bit addressing", index, position);
+                continue;
+            }
+            let disk = Arc::new(Disk {
+                channel,
+                slave,
+                sectors: identify[100..104].iter().rev().fold(0, |sectors, &word| sectors << 16 | u64::from(word)),
+                model: model_name(&identify),
+                dma: channel.bus_master.is_some() && identify[49] & (1 << 8) != 0,
+            });
+            let name = block::register("hd", disk.clone());
+            log_info!("ide: {} on channel {} {}, irq {}: {}, {} MiB, {}", name, index, position, irq, disk.model,
+                disk.sectors * SECTOR_SIZE as u64 >> 20, if disk.dma { "DMA" } else { "PIO" });
+        }
+    }
+    Ok(())
+}
+initcall!(Driver, "ide", init, after: &["fs"]);
+
+/// Timer callback: wakes the thread waiting for a command of channel
+/// `index`, which then notices that the command timed out.
+fn wake_channel(index: u64) {
+    COMPLETION[index as usize].notify_all();
+}
+
+fn handle_interrupt(index: usize) {
+    let channel = match CHANNELS[index].get() {
+        Some(channel) => channel,
+        None => return,
+    };
+    if let Some(bm) = &channel.bus_master {
+        let status: u8 = bm.read(BM_STATUS);
+        if status & BM_INTERRUPT == 0 {
+            return;
+        }
+        bm.write(BM_STATUS, status & (BM_ERROR | BM_INTERRUPT));
+        BM_SEEN[index].fetch_or(status, Ordering::Relaxed);
+    }
+    // reading the status acknowledges the drive's interrupt
+    channel.status();
+    DONE[index].store(true, Ordering::Release);
+    COMPLETION[index].notify_all();
+}
+
+#[test_case]
+fn test_taskfile() {
+    assert_eq!(taskfile(0x0605_0403_0201, 0x0180), [[0x01, 0x04, 0x05, 0x06], [0x80, 0x01, 0x02, 0x03]]);
+
+    assert_eq!(prd_entries(0x20_0000, 0x10000), [(0x20_0000, 0x10000)]);
+    // a bounce buffer that isn't aligned to 64 KiB takes two entries
+    assert_eq!(prd_entries(0x20_f000, 0x10000), [(0x20_f000, 0x1000), (0x21_0000, 0xf000)]);
+    assert_eq!(prd_entries(0x1000, 0), []);
+}
@@ -0,0 +1,187 @@
+//! PCI configuration space access through the legacy I/O ports (mechanism #1).
+
+use alloc::vec::Vec;
+use spin::Once;
+use crate::arch::x86_64::ioport::{self, PortRange};
+
+/// The configuration address port, followed by the data port at `CONFIG_DATA`.
+const CONFIG_ADDRESS: u16 = 0xcf8;
+const CONFIG_DATA: u16 = 0x4;
+
+static CONFIG_PORTS: Once<PortRange> = Once::new();
+
+const COMMAND_IO_SPACE: u16 = 1 << 0;
+const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+const COMMAND_BUS_MASTER: u16 = 1 << 2;
+/// Set in the status register if there is a capability list.
+const STATUS_CAPABILITIES: u32 = 1 << 4;
+/// Capabilities followed at most, in case the list loops.
+const MAX_CAPABILITIES: usize = 48;
+
+/// A function of a device on the PCI bus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciDevice {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+}
+
+/// A decoded base address register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Io(u16),
+    /// Physical address of a memory mapped region.
+    Memory(u64),
+}
+
+fn config_address(bus: u8, device: u8, function: u8, offset: u8) -> u32 {
+    1 << 31
+        | u32::from(bus) << 16
+        | u32::from(device) << 11
+        | u32::from(function) << 8
+        | u32::from(offset & 0xfc)
+}
+
+fn config_ports() -> &'static PortRange {
+    CONFIG_PORTS.call_once(|| ioport::claim(CONFIG_ADDRESS, 8, "pci").expect("PCI configuration ports in use"))
+}
+
+fn read_config(bus: u8, device: u8, function: u8, offset: u8) -> u32 {
+    let ports = config_ports();
+    ports.write(0, config_address(bus, device, function, offset));
+    ports.read(CONFIG_DATA)
+}
+
+fn write_config(bus: u8, device: u8, function: u8, offset: u8, value: u32) {
+    let ports = config_ports();
+    ports.write(0, config_address(bus, device, function, offset));
+    ports.write(CONFIG_DATA, value);
+}
+
+impl PciDevice {
+    fn probe(bus: u8, device: u8, function: u8) -> Option<PciDevice> {
+        let id = read_config(bus, device, function, 0x00);
+        if id & 0xffff == 0xffff {
+            return None;
+        }
+        let class = read_config(bus, device, function, 0x08);
+        Some(PciDevice {
+            bus,
+            device,
+            function,
+            vendor_id: id as u16,
+            device_id: (id >> 16) as u16,
+            class: (class >> 24) as u8,
+            subclass: (class >> 16) as u8,
+            prog_if: (class >> 8) as u8,
+        })
+    }
+
+    /// Reads the aligned 32-bit register at `offset` of the configuration space.
+    pub fn read(&self, offset: u8) -> u32 {
+        read_config(self.bus, self.device, self.function, offset)
+    }
+
+    pub fn write(&self, offset: u8, value: u32) {
+        write_config(self.bus, self.device, self.function, offset, value)
+    }
+
+    /// Decodes base address register `index` (0 to 5), or `None` if it is unused.
+    pub fn bar(&self, index: u8) -> Option<Bar> {
+        let offset = 0x10 + index * 4;
+        let value = self.read(offset);
+        if value & 1 == 1 {
+            return Some(Bar::Io((value & !0x3) as u16)).filter(|&bar| bar != Bar::Io(0));
+        }
+        let mut addr = u64::from(value & !0xf);
+        // type 2 is a 64-bit BAR spanning the next register too
+        if (value >> 1) & 0x3 == 2 {
+            addr |= u64::from(self.read(offset + 4)) << 32;
+        }
+        Some(Bar::Memory(addr)).filter(|&bar| bar != Bar::Memory(0))
+    }
+
+    /// The subsystem id, which some vendors, like virtio's, tell device
+    /// types apart with.
+    pub fn subsystem_id(&self) -> u16 {
+        (self.read(0x2c) >> 16) as u16
+    }
+
+    /// The capabilities of the device as their id and offset in the
+    /// configuration space, in list order.
+    pub fn capabilities(&self) -> Vec<(u8, u8)> {
+        let mut capabilities = Vec::new();
+        if (self.read(0x04) >> 16) & STATUS_CAPABILITIES == 0 {
+            return capabilities;
+        }
+        let mut offset = self.read(0x34) as u8 & 0xfc;
+        while offset != 0 && capabilities.len() < MAX_CAPABILITIES {
+            let header = self.read(offset);
+            capabilities.push((header as u8, offset));
+            offset = (header >> 8) as u8 & 0xfc;
+        }
+        capabilities
+    }
+
+    /// The legacy PIC line the device interrupts on, if any.
+    pub fn interrupt_line(&self) -> Option<u8> {
+        let line = self.read(0x3c) as u8;
+        Some(line).filter(|&line| line < 16)
+    }
+
+    /// Enables I/O and memory decoding and lets the device master the bus for DMA.
+    pub fn enable_bus_mastering(&self) {
+        let command = self.read(0x04);
+        let enable = COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE | COMMAND_BUS_MASTER;
+        // the upper half is the status register, whose bits are cleared by writing 1
+        self.write(0x04, (command & 0xffff) | u32::from(enable));
+    }
+
+    /// Stops the device from starting DMA, e.g. before handing the machine over.
+    pub fn disable_bus_mastering(&self) {
+        let command = self.read(0x04);
+        self.write(0x04, command & 0xffff & !u32::from(COMMAND_BUS_MASTER));
+    }
+}
+
+/// Lists the functions of all devices on all buses.
+pub fn enumerate() -> Vec<PciDevice> {
+    let mut devices = Vec::new();
+    for bus in 0..=255 {
+        for device in 0..32 {
+            let first = match PciDevice::probe(bus, device, 0) {
+                Some(first) => first,
+                None => continue,
+            };
+            devices.push(first);
+            // bit 7 of the header type marks multi-function devices
+            if (first.read(0x0c) >> 16) & 0x80 != 0 {
+                devices.extend((1..8).filter_map(|function| PciDevice::probe(bus, device, function)));
+            }
+        }
+    }
+    devices
+}
+
+/// Finds the first device with the given vendor and device id.
+pub fn find(vendor_id: u16, device_id: u16) -> Option<PciDevice> {
+    enumerate().into_iter().find(|dev| dev.vendor_id == vendor_id && dev.device_id == device_id)
+}
+
+/// Finds the first device of the given class, subclass and programming interface.
+pub fn find_class(class: u8, subclass: u8, prog_if: u8) -> Option<PciDevice> {
+    enumerate().into_iter().find(|dev| (dev.class, dev.subclass, dev.prog_if) == (class, subclass, prog_if))
+}
+
+#[test_case]
+fn test_host_bridge_is_found() {
+    // every PC, and every QEMU machine, has a host bridge at 00:00.0
+    let bridge = PciDevice::probe(0, 0, 0).expect("no device at 00:00.0");
+    assert_eq!((bridge.class, bridge.subclass), (0x06, 0x00));
+    assert!(enumerate().contains(&bridge));
+}
@@ -0,0 +1,41 @@
+//! The programmable interval timer: counters of `time::PIT_FREQUENCY` input
+//! cycles, channel 0 driving IRQ 0 and channel 2 behind the speaker gate.
+//!
+//! The channels share the mode port, which this module owns. Whoever drives
+//! a channel claims its data port and starts it with `start`.
+
+use spin::Once;
+use crate::arch::interrupts::without_interrupts;
+use crate::arch::x86_64::ioport::{self, PortRange};
+
+/// The slowest a channel counts, which a divisor of 0 stands for.
+pub const MAX_DIVISOR: u32 = 0x1_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Mode {
+    /// Counts down once; the output goes high at the end.
+    InterruptOnTerminalCount = 0,
+    /// Pulses the output once every `divisor` cycles.
+    RateGenerator = 2,
+    SquareWave = 3,
+}
+
+static MODE_PORT: Once<Option<PortRange>> = Once::new();
+
+/// Starts `channel`, whose data port is `data`, counting `divisor` input
+/// cycles in `mode`.
+pub fn start(channel: u8, data: &PortRange, mode: Mode, divisor: u32) -> Result<(), &'static str> {
+    if !(1..=MAX_DIVISOR).contains(&divisor) {
+        return Err("PIT divisor out of range");
+    }
+    let port = MODE_PORT.call_once(|| ioport::claim(0x43, 1, "pit").ok()).as_ref()
+        .ok_or("PIT mode port in use")?;
+    without_interrupts(|| {
+        // lobyte/hibyte access
+        port.write(0, channel << 6 | 0b11 << 4 | (mode as u8) << 1);
+        data.write(0, divisor as u8);
+        data.write(0, (divisor >> 8) as u8);
+    });
+    Ok(())
+}
@@ -0,0 +1,184 @@
+//! The PS/2 mouse, on the auxiliary port of the keyboard controller.
+//!
+//! The mouse reports in packets of three bytes: the buttons and the signs of
+//! the motion, then the motion along X and Y. The interrupt handler puts the
+//! packets together, and the work queue hands each to the handler set with
+//! `set_handler` as a `MouseEvent`.
+
+use spin::Mutex;
+use crate::arch::interrupts::without_interrupts;
+use crate::arch::x86_64::interrupts;
+use crate::arch::x86_64::ioport::{self, PortRange};
+use crate::workqueue;
+
+const IRQ: u8 = 12;
+
+// keyboard controller status bits and commands
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+const ENABLE_AUX: u8 = 0xa8;
+const READ_CONFIG: u8 = 0x20;
+const WRITE_CONFIG: u8 = 0x60;
+const WRITE_AUX: u8 = 0xd4;
+const CONFIG_AUX_IRQ: u8 = 1 << 1;
+const CONFIG_AUX_CLOCK_OFF: u8 = 1 << 5;
+
+// mouse commands, acknowledged with ACK
+const SET_DEFAULTS: u8 = 0xf6;
+const ENABLE_REPORTING: u8 = 0xf4;
+const ACK: u8 = 0xfa;
+
+/// Status reads before giving up on the controller.
+const POLL_LIMIT: u32 = 100_000;
+
+// bits of the first byte of a packet
+const LEFT: u8 = 1 << 0;
+const RIGHT: u8 = 1 << 1;
+const MIDDLE: u8 = 1 << 2;
+/// Always set, which keeps the packets in step.
+const ALWAYS_ONE: u8 = 1 << 3;
+const X_NEGATIVE: u8 = 1 << 4;
+const Y_NEGATIVE: u8 = 1 << 5;
+const OVERFLOW: u8 = 3 << 6;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Buttons {
+    pub left: bool,
+    pub right: bool,
+    pub middle: bool,
+}
+
+/// A packet: the motion since the previous one, and the buttons held.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MouseEvent {
+    pub dx: i16,
+    /// Positive upwards.
+    pub dy: i16,
+    pub buttons: Buttons,
+}
+
+struct Packet {
+    bytes: [u8; 3],
+    len: usize,
+}
+
+static PACKET: Mutex<Packet> = Mutex::new(Packet { bytes: [0; 3], len: 0 });
+static HANDLER: Mutex<Option<fn(MouseEvent)>> = Mutex::new(None);
+
+/// Enables the mouse and its interrupt.
+pub fn init() -> Result<(), &'static str> {
+    let data = interrupts::ps2_data_port().ok_or("keyboard controller not set up")?;
+    // released when done, for `reboot`
+    let command = ioport::claim(0x64, 1, "ps2 mouse")?;
+    without_interrupts(|| {
+        // bytes the keyboard sent would be taken for answers
+        for _ in 0..16 {
+            if command.read::<u8>(0) & STATUS_OUTPUT_FULL == 0 {
+                break;
+            }
+            data.read::<u8>(0);
+        }
+        write(&command, &command, ENABLE_AUX)?;
+        write(&command, &command, READ_CONFIG)?;
+        let config = read(&command, data)?;
+        write(&command, &command, WRITE_CONFIG)?;
+        write(&command, data, (config | CONFIG_AUX_IRQ) & !CONFIG_AUX_CLOCK_OFF)?;
+        send(&command, data, SET_DEFAULTS)?;
+        send(&command, data, ENABLE_REPORTING)
+    })?;
+    interrupts::register_irq(IRQ, handle_irq)
+}
+
+/// Has `handler` called from the work queue with every packet, or no one.
+pub fn set_handler(handler: Option<fn(MouseEvent)>) {
+    without_interrupts(|| *HANDLER.lock() = handler);
+}
+
+/// Writes `byte` to `port` once the controller takes input.
+fn write(command: &PortRange, port: &PortRange, byte: u8) -> Result<(), &'static str> {
+    for _ in 0..POLL_LIMIT {
+        if command.read::<u8>(0) & STATUS_INPUT_FULL == 0 {
+            port.write(0, byte);
+            return Ok(());
+        }
+    }
+    Err("keyboard controller not ready")
+}
+
+/// Reads the next byte of output of the controller.
+fn read(command: &PortRange, data: &PortRange) -> Result<u8, &'static str> {
+    for _ in 0..POLL_LIMIT {
+        if command.read::<u8>(0) & STATUS_OUTPUT_FULL != 0 {
+            return Ok(data.read(0));
+        }
+    }
+    Err("no answer from the keyboard controller")
+}
+
+/// Sends `byte` to the mouse and waits for its acknowledgment.
+fn send(command: &PortRange, data: &PortRange, byte: u8) -> Result<(), &'static str> {
+    write(command, command, WRITE_AUX)?;
+    write(command, data, byte)?;
+    match read(command, data)? {
+        ACK => Ok(()),
+        _ => Err("no mouse"),
+    }
+}
+
+/// The event a packet reports, if its motion didn't overflow.
+fn decode(bytes: [u8; 3]) -> Option<MouseEvent> {
+    let flags = bytes[0];
+    if flags & OVERFLOW != 0 {
+        return None;
+    }
+    // the motion is a 9-bit two's complement number, with the sign in the flags
+    let motion = |byte: u8, negative: u8| i16::from(byte) - if flags & negative != 0 { 0x100 } else { 0 };
+    Some(MouseEvent {
+        dx: motion(bytes[1], X_NEGATIVE),
+        dy: motion(bytes[2], Y_NEGATIVE),
+        buttons: Buttons { left: flags & LEFT != 0, right: flags & RIGHT != 0, middle: flags & MIDDLE != 0 },
+    })
+}
+
+fn handle_irq() {
+    let byte: u8 = match interrupts::ps2_data_port() {
+        Some(data) => data.read(0),
+        None => return,
+    };
+    let event = {
+        let mut packet = PACKET.lock();
+        // a byte lost on the way would shift every following packet
+        if packet.len == 0 && byte & ALWAYS_ONE == 0 {
+            return;
+        }
+        let len = packet.len;
+        packet.bytes[len] = byte;
+        packet.len = (len + 1) % 3;
+        match packet.len {
+            0 => decode(packet.bytes),
+            _ => None,
+        }
+    };
+    if let Some(event) = event {
+        if workqueue::queue(move || dispatch(event)).is_err() {
+            dispatch(event);
+        }
+    }
+}
+
+fn dispatch(event: MouseEvent) {
+    let handler = without_interrupts(|| *HANDLER.lock());
+    if let Some(handler) = handler {
+        handler(event);
+    }
+}
+
+#[test_case]
+fn test_decode() {
+    let event = decode([ALWAYS_ONE | LEFT | X_NEGATIVE, 0xfe, 3]).unwrap();
+    assert_eq!((event.dx, event.dy), (-2, 3));
+    assert_eq!(event.buttons, Buttons { left: true, right: false, middle: false });
+    let event = decode([ALWAYS_ONE | MIDDLE | Y_NEGATIVE, 5, 0]).unwrap();
+    assert_eq!((event.dx, event.dy, event.buttons.middle), (5, -256, true));
+    assert_eq!(decode([ALWAYS_ONE | OVERFLOW, 0, 0]), None);
+}
@@ -0,0 +1,359 @@
+//! The realtime clock of the CMOS: the date and time of day, and an alarm.
+//!
+//! The clock counts in BCD or binary, with 12 or 24 hours, as status
+//! register B says; `now` reads it as a `DateTime` either way. Its alarm only
+//! compares the time of day, so `alarm_at` arms it for the earliest pending
+//! alarm and the interrupt on IRQ 8 checks the date when it goes off. When
+//! the HPET has taken IRQ 8 over, the alarms become timers of `time`, at the
+//! `Instant` the clock reaches their time.
+
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+use spin::{Mutex, Once};
+use crate::arch::interrupts::{self, without_interrupts};
+use crate::arch::x86_64::ioport::{self, PortRange};
+use crate::time::{self, Callback, Instant, TimerId};
+use crate::initcall;
+
+const IRQ: u8 = 8;
+
+// registers, selected through the index port
+const SECONDS: u8 = 0x00;
+const SECONDS_ALARM: u8 = 0x01;
+const MINUTES: u8 = 0x02;
+const MINUTES_ALARM: u8 = 0x03;
+const HOURS: u8 = 0x04;
+const HOURS_ALARM: u8 = 0x05;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0a;
+const STATUS_B: u8 = 0x0b;
+const STATUS_C: u8 = 0x0c;
+
+const UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const HOURS_24: u8 = 1 << 1;
+const BINARY: u8 = 1 << 2;
+const ALARM_INTERRUPT: u8 = 1 << 5;
+const ALARM_FLAG: u8 = 1 << 5;
+const PM: u8 = 1 << 7;
+
+/// Alarms that can be pending at the same time.
+pub const MAX_ALARMS: usize = 16;
+
+/// A date and time of day, as the clock keeps it: local time, whatever the
+/// host says that is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Seconds since 1970-01-01 00:00:00.
+    pub fn timestamp(&self) -> u64 {
+        let days = days_from_civil(i64::from(self.year), i64::from(self.month), i64::from(self.day));
+        (days * 86_400) as u64 + u64::from(self.hour) * 3600 + u64::from(self.minute) * 60 + u64::from(self.second)
+    }
+
+    pub fn from_timestamp(timestamp: u64) -> DateTime {
+        let (year, month, day) = civil_from_days((timestamp / 86_400) as i64);
+        let seconds = timestamp % 86_400;
+        DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (seconds / 3600) as u8,
+            minute: (seconds / 60 % 60) as u8,
+            second: (seconds % 60) as u8,
+        }
+    }
+
+    /// Parses `YYYY-MM-DD HH:MM[:SS]`.
+    pub fn parse(text: &str) -> Option<DateTime> {
+        let (date, time) = text.trim().split_once(' ')?;
+        let mut date = date.split('-').map(|part| part.parse::<u16>().ok());
+        let (year, month, day) = (date.next()??, date.next()??, date.next()??);
+        let mut time = time.trim().split(':').map(|part| part.parse::<u8>().ok());
+        let (hour, minute) = (time.next()??, time.next()??);
+        let second = time.next().unwrap_or(Some(0))?;
+        if date.next().is_some() || time.next().is_some() {
+            return None;
+        }
+        let datetime = DateTime { year, month: month as u8, day: day as u8, hour, minute, second };
+        // out of range fields come back different
+        match DateTime::from_timestamp(datetime.timestamp()) == datetime && (1..=12).contains(&month) {
+            true => Some(datetime),
+            false => None,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02}", self.year, self.month, self.day, self.hour, self.minute,
+            self.second)
+    }
+}
+
+/// Days from 1970-01-01 to the date, in the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// The index and data ports. Locked with interrupts disabled, as selecting a
+/// register and accessing it must not be interleaved.
+static PORTS: Once<Option<Mutex<PortRange>>> = Once::new();
+
+fn ports() -> Result<&'static Mutex<PortRange>, &'static str> {
+    PORTS.call_once(|| ioport::claim(0x70, 2, "rtc").ok().map(Mutex::new)).as_ref()
+        .ok_or("RTC ports in use")
+}
+
+fn read_register(ports: &PortRange, register: u8) -> u8 {
+    ports.write(0, register);
+    ports.read(1)
+}
+
+fn write_register(ports: &PortRange, register: u8, value: u8) {
+    ports.write(0, register);
+    ports.write(1, value);
+}
+
+/// The fields of the clock as it stores them, not while it updates them.
+fn read_raw(ports: &PortRange) -> [u8; 6] {
+    let read = |ports: &PortRange| {
+        while read_register(ports, STATUS_A) & UPDATE_IN_PROGRESS != 0 {}
+        [SECONDS, MINUTES, HOURS, DAY, MONTH, YEAR].map(|register| read_register(ports, register))
+    };
+    // an update may start right after the check
+    let mut fields = read(ports);
+    loop {
+        let again = read(ports);
+        if again == fields {
+            return fields;
+        }
+        fields = again;
+    }
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0xf)
+}
+
+fn to_bcd(value: u8) -> u8 {
+    (value / 10) << 4 | value % 10
+}
+
+/// Decodes the fields `read_raw` returns in the format of status register B.
+fn decode(raw: [u8; 6], status_b: u8) -> DateTime {
+    let [second, minute, hour, day, month, year] = raw;
+    let binary = |value: u8| if status_b & BINARY != 0 { value } else { from_bcd(value) };
+    let mut hours = binary(hour & !PM);
+    if status_b & HOURS_24 == 0 {
+        // 12 is midnight or noon
+        hours = hours % 12 + if hour & PM != 0 { 12 } else { 0 };
+    }
+    DateTime {
+        // the century register is not where ACPI says on every machine
+        year: 2000 + u16::from(binary(year)),
+        month: binary(month),
+        day: binary(day),
+        hour: hours,
+        minute: binary(minute),
+        second: binary(second),
+    }
+}
+
+/// Encodes `value` for an alarm or time register, in the format of status
+/// register B.
+fn encode(value: u8, status_b: u8) -> u8 {
+    if status_b & BINARY != 0 { value } else { to_bcd(value) }
+}
+
+fn encode_hour(hour: u8, status_b: u8) -> u8 {
+    if status_b & HOURS_24 != 0 {
+        return encode(hour, status_b);
+    }
+    let twelve = match hour % 12 { 0 => 12, hour => hour };
+    encode(twelve, status_b) | if hour >= 12 { PM } else { 0 }
+}
+
+/// The date and time the clock shows.
+pub fn now() -> Result<DateTime, &'static str> {
+    let ports = ports()?;
+    Ok(without_interrupts(|| {
+        let ports = ports.lock();
+        decode(read_raw(&ports), read_register(&ports, STATUS_B))
+    }))
+}
+
+struct Alarm {
+    /// `DateTime::timestamp` of when it goes off.
+    at: u64,
+    callback: Callback,
+    arg: u64,
+}
+
+struct Alarms {
+    pending: [Option<Alarm>; MAX_ALARMS],
+    /// The timer standing in for the clock's alarm without IRQ 8.
+    timer: Option<TimerId>,
+}
+
+static ALARMS: Mutex<Alarms> = {
+    const NONE: Option<Alarm> = None;
+    Mutex::new(Alarms { pending: [NONE; MAX_ALARMS], timer: None })
+};
+/// Set by `init`.
+static READY: AtomicBool = AtomicBool::new(false);
+/// Whether the clock's own alarm interrupt is in use.
+static USES_IRQ: AtomicBool = AtomicBool::new(false);
+
+/// Reads the clock and takes IRQ 8 for its alarm, unless the HPET has it.
+pub fn init() -> Result<(), &'static str> {
+    let now = now()?;
+    let uses_irq = interrupts::register_irq(IRQ, handle_irq).is_ok();
+    USES_IRQ.store(uses_irq, Ordering::Relaxed);
+    READY.store(true, Ordering::Relaxed);
+    crate::log_info!("rtc: {}, alarms {}", now, if uses_irq { "on IRQ 8" } else { "on timers" });
+    Ok(())
+}
+initcall!(Driver, "rtc", init);
+
+/// Calls `callback(arg)` from an interrupt handler once the clock reaches
+/// `at`, like the callback of a timer of `time`. An alarm in the past goes
+/// off right away.
+pub fn alarm_at(at: DateTime, callback: Callback, arg: u64) -> Result<(), &'static str> {
+    if !READY.load(Ordering::Relaxed) {
+        return Err("the realtime clock is not set up");
+    }
+    without_interrupts(|| {
+        let mut alarms = ALARMS.lock();
+        let slot = alarms.pending.iter_mut().find(|slot| slot.is_none()).ok_or("too many alarms")?;
+        *slot = Some(Alarm { at: at.timestamp(), callback, arg });
+        Ok(())
+    })?;
+    expire();
+    Ok(())
+}
+
+/// Alarms waiting to go off.
+pub fn pending_alarms() -> usize {
+    without_interrupts(|| ALARMS.lock().pending.iter().flatten().count())
+}
+
+/// Runs the callbacks of the alarms that are due and arms for the next one.
+/// Called with interrupts disabled by the alarm interrupt or timer, and by
+/// `alarm_at`.
+fn expire() {
+    let now = match now() {
+        Ok(now) => now.timestamp(),
+        Err(_) => return,
+    };
+    loop {
+        // without the lock, so the callback can add alarms
+        let due = without_interrupts(|| {
+            let mut alarms = ALARMS.try_lock()?;
+            let slot = alarms.pending.iter_mut().find(|slot| matches!(slot, Some(alarm) if alarm.at <= now))?;
+            slot.take()
+        });
+        match due {
+            Some(alarm) => (alarm.callback)(alarm.arg),
+            None => break,
+        }
+    }
+    arm(now);
+}
+
+/// Arms the clock's alarm, or the timer standing in for it, for the earliest
+/// pending alarm.
+fn arm(now: u64) {
+    without_interrupts(|| {
+        let mut alarms = match ALARMS.try_lock() {
+            Some(alarms) => alarms,
+            None => return,
+        };
+        let next = alarms.pending.iter().flatten().map(|alarm| alarm.at).min();
+        if USES_IRQ.load(Ordering::Relaxed) {
+            if let Ok(ports) = ports() {
+                let ports = ports.lock();
+                let status_b = read_register(&ports, STATUS_B);
+                match next {
+                    Some(next) => {
+                        // goes off every day at that time, until the date is right too
+                        let next = DateTime::from_timestamp(next.max(now + 1));
+                        write_register(&ports, SECONDS_ALARM, encode(next.second, status_b));
+                        write_register(&ports, MINUTES_ALARM, encode(next.minute, status_b));
+                        write_register(&ports, HOURS_ALARM, encode_hour(next.hour, status_b));
+                        write_register(&ports, STATUS_B, status_b | ALARM_INTERRUPT);
+                    }
+                    None => write_register(&ports, STATUS_B, status_b & !ALARM_INTERRUPT),
+                }
+            }
+            return;
+        }
+        if let Some(timer) = alarms.timer.take() {
+            time::cancel_timer(timer);
+        }
+        if let Some(next) = next {
+            // late by the part of the current second that passed, never early
+            let deadline = Instant::now() + Duration::from_secs(next.saturating_sub(now).max(1));
+            alarms.timer = Some(time::add_timer(deadline, |_| expire(), 0));
+        }
+    });
+}
+
+fn handle_irq() {
+    let flags = match ports().ok().and_then(|ports| ports.try_lock()) {
+        // reading it acknowledges the interrupt
+        Some(ports) => read_register(&ports, STATUS_C),
+        None => return,
+    };
+    if flags & ALARM_FLAG != 0 {
+        expire();
+    }
+}
+
+#[test_case]
+fn test_datetime() {
+    let datetime = DateTime { year: 2024, month: 2, day: 29, hour: 23, minute: 59, second: 58 };
+    assert_eq!(datetime.timestamp(), 1_709_251_198);
+    assert_eq!(DateTime::from_timestamp(1_709_251_198), datetime);
+    assert_eq!(DateTime::from_timestamp(0).timestamp(), 0);
+    assert_eq!(DateTime::parse("2024-02-29 23:59:58"), Some(datetime));
+    assert_eq!(DateTime::parse("2024-03-01 07:30").map(|at| (at.hour, at.second)), Some((7, 0)));
+    assert_eq!(DateTime::parse("2023-02-29 12:00"), None);
+    assert_eq!(DateTime::parse("2024-01-01 24:00"), None);
+    assert_eq!(alloc::format!("{}", datetime), "2024-02-29 23:59:58");
+
+    let raw = [0x58, 0x59, 0x11 | PM, 0x29, 0x02, 0x24];
+    assert_eq!(decode(raw, 0), datetime);
+    assert_eq!(encode_hour(23, 0), 0x11 | PM);
+    assert_eq!(encode_hour(0, 0), 0x12);
+    assert_eq!(encode_hour(23, BINARY | HOURS_24), 23);
+    assert!(now().unwrap().year >= 2024);
+}
@@ -0,0 +1,148 @@
+use alloc::collections::VecDeque;
+use core::time::Duration;
+use spin::{Mutex, Once};
+use crate::arch::interrupts::without_interrupts;
+use crate::arch::x86_64::ioport::{self, PortRange};
+use crate::drivers::pit;
+use crate::sched;
+use crate::time::{tsc_frequency, Instant, PIT_FREQUENCY};
+
+/// A note of a melody. A frequency of 0 is a rest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Note {
+    pub frequency: u32,
+    pub duration: Duration,
+}
+
+impl Note {
+    pub const fn new(frequency: u32, millis: u64) -> Note {
+        Note { frequency, duration: Duration::from_millis(millis) }
+    }
+}
+
+/// Played by `main` once the kernel has booted.
+pub const BOOT_JINGLE: &[Note] = &[Note::new(523, 80), Note::new(659, 80), Note::new(784, 120)];
+
+struct Player {
+    queue: VecDeque<Note>,
+    /// When the note that is playing ends.
+    note_end: Option<Instant>,
+}
+
+static PLAYER: Mutex<Player> = Mutex::new(Player { queue: VecDeque::new(), note_end: None });
+
+struct Ports {
+    /// PIT channel 2.
+    pit: PortRange,
+    /// The system control port, which gates channel 2 and connects it to the speaker.
+    control: PortRange,
+}
+
+static PORTS: Once<Option<Ports>> = Once::new();
+
+/// The ports, claimed on first use. `None` if someone else has them.
+fn ports() -> Option<&'static Ports> {
+    PORTS.call_once(|| match (ioport::claim(0x42, 1, "speaker"), ioport::claim(0x61, 1, "speaker control")) {
+        (Ok(pit), Ok(control)) => Some(Ports { pit, control }),
+        _ => None,
+    }).as_ref()
+}
+
+/// Starts a continuous tone of `frequency` Hz on PIT channel 2.
+pub fn play(frequency: u32) {
+    if frequency == 0 {
+        return stop();
+    }
+    let ports = match ports() {
+        Some(ports) => ports,
+        None => return,
+    };
+    let divisor = (PIT_FREQUENCY / u64::from(frequency)).clamp(1, 0xffff) as u32;
+    if pit::start(2, &ports.pit, pit::Mode::SquareWave, divisor).is_err() {
+        return;
+    }
+    // enable the channel 2 gate and connect it to the speaker
+    let value: u8 = ports.control.read(0);
+    ports.control.write(0, value | 0x03);
+}
+
+/// Silences the speaker.
+pub fn stop() {
+    if let Some(ports) = ports() {
+        let value: u8 = ports.control.read(0);
+        ports.control.write(0, value & !0x03);
+    }
+}
+
+/// Plays a tone of `frequency` Hz for `duration`, blocking the caller.
+pub fn beep(frequency: u32, duration: Duration) {
+    // without a calibrated TSC the tone would never end
+    if tsc_frequency() == 0 {
+        return;
+    }
+    play(frequency);
+    sched::sleep(duration);
+    stop();
+}
+
+/// Plays a tone of `frequency` Hz for `duration`, spinning on the TSC with
+/// interrupts disabled instead of sleeping. For the panic handler, which may
+/// run with the scheduler or timer locks held.
+pub fn beep_spinning(frequency: u32, duration: Duration) {
+    if tsc_frequency() == 0 {
+        return;
+    }
+    without_interrupts(|| {
+        play(frequency);
+        let start = Instant::now();
+        while start.elapsed() < duration {
+            core::hint::spin_loop();
+        }
+        stop();
+    });
+}
+
+/// Queues `notes` to be played in the background after the notes already queued.
+pub fn play_melody(notes: &[Note]) {
+    without_interrupts(|| PLAYER.lock().queue.extend(notes.iter().copied()));
+}
+
+/// Whether a queued melody is still playing.
+pub fn is_playing() -> bool {
+    without_interrupts(|| {
+        let player = PLAYER.lock();
+        player.note_end.is_some() || !player.queue.is_empty()
+    })
+}
+
+/// Called from the timer interrupt: moves on to the next queued note when the
+/// current one has ended.
+pub fn tick() {
+    let mut player = match PLAYER.try_lock() {
+        Some(player) => player,
+        None => return,
+    };
+    let now = Instant::now();
+    if let Some(end) = player.note_end {
+        if now < end {
+            return;
+        }
+        player.note_end = None;
+        stop();
+    }
+    if let Some(note) = player.queue.pop_front() {
+        play(note.frequency);
+        player.note_end = now.checked_add(note.duration);
+    }
+}
+
+#[test_case]
+fn test_melody_plays_in_background() {
+    play_melody(&[Note::new(440, 20), Note::new(0, 10), Note::new(880, 20)]);
+    assert!(is_playing());
+    let start = Instant::now();
+    while is_playing() {
+        assert!(start.elapsed() < Duration::from_millis(500), "melody did not finish");
+        crate::arch::halt();
+    }
+}
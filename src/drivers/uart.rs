@@ -0,0 +1,385 @@
+//! Driver for the 16550 UART behind the PC's serial ports.
+//!
+//! Output goes through a software ring: once the port's interrupt is enabled
+//! with `enable_interrupts`, `send` only queues the byte and the interrupt
+//! handler moves queued bytes into the hardware FIFO whenever it runs empty.
+//! Until then, and when the ring is full, bytes are written by polling.
+
+use core::fmt;
+use x86_64::instructions::port::Port;
+use crate::arch::x86_64::ioport::{self, PortRange};
+
+/// Bytes queued for sending before `send` falls back to polling.
+const TX_BUFFER_SIZE: usize = 1024;
+/// Bytes the transmit FIFO takes after signalling that it is empty.
+const FIFO_SIZE: usize = 16;
+/// The clock of the baud rate generator, divided by 16.
+const BASE_BAUD: u32 = 115_200;
+
+// register offsets
+const DATA: u16 = 0;
+const INTERRUPT_ENABLE: u16 = 1;
+const FIFO_CONTROL: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+const SCRATCH: u16 = 7;
+
+// interrupt enable bits
+const RECEIVED_DATA: u8 = 1 << 0;
+const TRANSMITTER_EMPTY: u8 = 1 << 1;
+
+// line status bits
+const DATA_READY: u8 = 1 << 0;
+const THR_EMPTY: u8 = 1 << 5;
+
+/// The standard serial ports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Com {
+    Com1,
+    Com2,
+    Com3,
+    Com4,
+}
+
+impl Com {
+    pub const ALL: [Com; 4] = [Com::Com1, Com::Com2, Com::Com3, Com::Com4];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Com::Com1 => "com1",
+            Com::Com2 => "com2",
+            Com::Com3 => "com3",
+            Com::Com4 => "com4",
+        }
+    }
+
+    pub fn base(self) -> u16 {
+        match self {
+            Com::Com1 => 0x3f8,
+            Com::Com2 => 0x2f8,
+            Com::Com3 => 0x3e8,
+            Com::Com4 => 0x2e8,
+        }
+    }
+
+    /// The PIC line of the port, shared by COM1 and COM3 and by COM2 and COM4.
+    pub fn irq(self) -> u8 {
+        match self {
+            Com::Com1 | Com::Com3 => 4,
+            Com::Com2 | Com::Com4 => 3,
+        }
+    }
+
+    pub fn index(self) -> usize {
+        self as usize
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+    /// The parity bit is always 1.
+    Mark,
+    /// The parity bit is always 0.
+    Space,
+}
+
+/// Line settings of a port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    /// Bits per second; must divide 115200.
+    pub baud: u32,
+    /// 5 to 8.
+    pub data_bits: u8,
+    pub parity: Parity,
+    /// 1 or 2.
+    pub stop_bits: u8,
+    /// Whether the 16-byte FIFOs are used, rather than one byte buffers.
+    pub fifo: bool,
+}
+
+impl Default for Config {
+    /// 115200 baud, 8N1, with FIFOs.
+    fn default() -> Config {
+        Config { baud: BASE_BAUD, data_bits: 8, parity: Parity::None, stop_bits: 1, fifo: true }
+    }
+}
+
+impl Config {
+    fn line_control(&self) -> Result<u8, &'static str> {
+        if !(5..=8).contains(&self.data_bits) {
+            return Err("data bits must be 5 to 8");
+        }
+        let stop_bits = match self.stop_bits {
+            1 => 0,
+            2 => 1 << 2,
+            _ => return Err("stop bits must be 1 or 2"),
+        };
+        let parity = match self.parity {
+            Parity::None => 0,
+            Parity::Odd => 0b001 << 3,
+            Parity::Even => 0b011 << 3,
+            Parity::Mark => 0b101 << 3,
+            Parity::Space => 0b111 << 3,
+        };
+        Ok((self.data_bits - 5) | stop_bits | parity)
+    }
+
+    fn divisor(&self) -> Result<u16, &'static str> {
+        if self.baud == 0 || BASE_BAUD % self.baud != 0 {
+            return Err("unsupported baud rate");
+        }
+        Ok((BASE_BAUD / self.baud) as u16)
+    }
+}
+
+/// Bytes waiting to be sent.
+struct TxBuffer {
+    data: [u8; TX_BUFFER_SIZE],
+    start: usize,
+    len: usize,
+}
+
+impl TxBuffer {
+    fn push(&mut self, byte: u8) -> bool {
+        if self.len == TX_BUFFER_SIZE {
+            return false;
+        }
+        self.data[(self.start + self.len) % TX_BUFFER_SIZE] = byte;
+        self.len += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+        let byte = self.data[self.start];
+        self.start = (self.start + 1) % TX_BUFFER_SIZE;
+        self.len -= 1;
+        Some(byte)
+    }
+}
+
+/// One serial port. Wrap it in a lock taken with interrupts disabled once its
+/// interrupt is enabled, as the handler uses it too.
+pub struct Uart16550 {
+    com: Com,
+    /// The registers, claimed by `init`.
+    ports: Option<PortRange>,
+    initialized: bool,
+    /// Whether sending is driven by the transmitter empty interrupt.
+    interrupt_driven: bool,
+    tx: TxBuffer,
+}
+
+impl Uart16550 {
+    pub const fn new(com: Com) -> Uart16550 {
+        Uart16550 {
+            com,
+            ports: None,
+            initialized: false,
+            interrupt_driven: false,
+            tx: TxBuffer { data: [0; TX_BUFFER_SIZE], start: 0, len: 0 },
+        }
+    }
+
+    pub fn com(&self) -> Com {
+        self.com
+    }
+
+    pub fn is_initialized(&self) -> bool {
+        self.initialized
+    }
+
+    fn read(&self, register: u16) -> u8 {
+        match &self.ports {
+            Some(ports) => ports.read(register),
+            None => 0xff,
+        }
+    }
+
+    fn write(&mut self, register: u16, value: u8) {
+        if let Some(ports) = &self.ports {
+            ports.write(register, value);
+        }
+    }
+
+    /// Programs the port with `config`, with its interrupts disabled. Fails if
+    /// there is no UART at the port.
+    pub fn init(&mut self, config: Config) -> Result<(), &'static str> {
+        let line_control = config.line_control()?;
+        let divisor = config.divisor()?;
+        if self.ports.is_none() {
+            self.ports = Some(ioport::claim(self.com.base(), 8, self.com.name())?);
+        }
+        // a missing port reads back all ones
+        self.write(SCRATCH, 0x5a);
+        if self.read(SCRATCH) != 0x5a {
+            self.ports = None;
+            return Err("no UART at this port");
+        }
+        self.write(INTERRUPT_ENABLE, 0);
+        // the divisor latch is behind the data and interrupt enable registers
+        self.write(LINE_CONTROL, 0x80);
+        self.write(DATA, divisor as u8);
+        self.write(INTERRUPT_ENABLE, (divisor >> 8) as u8);
+        self.write(LINE_CONTROL, line_control);
+        // enable and clear the FIFOs, interrupt when 14 bytes were received
+        self.write(FIFO_CONTROL, if config.fifo { 0xc7 } else { 0 });
+        // DTR, RTS and OUT2, which connects the interrupt line
+        self.write(MODEM_CONTROL, 0x0b);
+        self.initialized = true;
+        self.interrupt_driven = false;
+        Ok(())
+    }
+
+    /// Makes sending interrupt driven and enables the receive interrupt.
+    /// `handle_interrupt` must be called from the handler of `com().irq()`.
+    pub fn enable_interrupts(&mut self) {
+        self.interrupt_driven = true;
+        self.write(INTERRUPT_ENABLE, RECEIVED_DATA);
+    }
+
+    fn can_send(&self) -> bool {
+        self.read(LINE_STATUS) & THR_EMPTY != 0
+    }
+
+    /// Sends `byte` right away, waiting for the transmitter by polling. Bytes
+    /// queued before are sent first.
+    pub fn send_raw(&mut self, byte: u8) {
+        self.flush();
+        while !self.can_send() {
+            core::hint::spin_loop();
+        }
+        self.write(DATA, byte);
+    }
+
+    /// Sends `byte`: queues it if sending is interrupt driven, and polls
+    /// otherwise or if the queue is full.
+    pub fn send(&mut self, byte: u8) {
+        if !self.interrupt_driven {
+            return self.send_raw(byte);
+        }
+        if !self.tx.push(byte) {
+            self.send_raw(byte);
+            return;
+        }
+        if self.can_send() {
+            self.fill_fifo();
+        }
+        // interrupts again once the FIFO is empty
+        self.write(INTERRUPT_ENABLE, RECEIVED_DATA | TRANSMITTER_EMPTY);
+    }
+
+    /// Moves queued bytes into the empty transmit FIFO.
+    fn fill_fifo(&mut self) {
+        for _ in 0..FIFO_SIZE {
+            match self.tx.pop() {
+                Some(byte) => self.write(DATA, byte),
+                None => break,
+            }
+        }
+    }
+
+    /// Sends every queued byte by polling.
+    pub fn flush(&mut self) {
+        while let Some(byte) = self.tx.pop() {
+            while !self.can_send() {
+                core::hint::spin_loop();
+            }
+            self.write(DATA, byte);
+        }
+    }
+
+    /// Bytes queued but not sent yet.
+    pub fn pending(&self) -> usize {
+        self.tx.len
+    }
+
+    pub fn try_receive(&mut self) -> Option<u8> {
+        if self.read(LINE_STATUS) & DATA_READY != 0 {
+            Some(self.read(DATA))
+        } else {
+            None
+        }
+    }
+
+    /// Waits for a byte by polling.
+    pub fn receive(&mut self) -> u8 {
+        loop {
+            if let Some(byte) = self.try_receive() {
+                return byte;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Refills the transmit FIFO if it is empty. Returns received bytes to
+    /// `received`, as the interrupt fires for them too.
+    pub fn handle_interrupt(&mut self, mut received: impl FnMut(u8)) {
+        if !self.initialized {
+            return;
+        }
+        while let Some(byte) = self.try_receive() {
+            received(byte);
+        }
+        if self.can_send() {
+            self.fill_fifo();
+        }
+        if self.tx.len == 0 {
+            // nothing more to send, stop the interrupts for an empty FIFO
+            self.write(INTERRUPT_ENABLE, RECEIVED_DATA);
+        }
+    }
+}
+
+impl fmt::Write for Uart16550 {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        s.bytes().for_each(|byte| self.send(byte));
+        Ok(())
+    }
+}
+
+/// Writes to a port by polling, without taking its lock or touching its
+/// queue, for when the kernel is too broken to use `Uart16550`. The port must
+/// have been initialized, and the output may interleave with queued bytes.
+pub struct RawWriter(pub Com);
+
+impl RawWriter {
+    pub fn write_byte(&mut self, byte: u8) {
+        let base = self.0.base();
+        unsafe {
+            while Port::<u8>::new(base + LINE_STATUS).read() & THR_EMPTY == 0 {
+                core::hint::spin_loop();
+            }
+            Port::new(base + DATA).write(byte);
+        }
+    }
+}
+
+impl fmt::Write for RawWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        s.bytes().for_each(|byte| self.write_byte(byte));
+        Ok(())
+    }
+}
+
+#[test_case]
+fn test_config() {
+    let config = Config::default();
+    assert_eq!(config.line_control(), Ok(0b11));
+    assert_eq!(config.divisor(), Ok(1));
+    let config = Config { baud: 9600, data_bits: 7, parity: Parity::Even, stop_bits: 2, fifo: false };
+    assert_eq!(config.line_control(), Ok(0b0001_1110));
+    assert_eq!(config.divisor(), Ok(12));
+    assert!(Config { baud: 7000, ..config }.divisor().is_err());
+    assert!(Config { data_bits: 9, ..config }.line_control().is_err());
+
+    let mut tx = TxBuffer { data: [0; TX_BUFFER_SIZE], start: TX_BUFFER_SIZE - 1, len: 0 };
+    assert!(tx.push(1) && tx.push(2));
+    assert_eq!((tx.pop(), tx.pop(), tx.pop()), (Some(1), Some(2), None));
+}
@@ -0,0 +1,14 @@
+//! Virtio devices on the PCI bus, as QEMU emulates them with
+//! `-device virtio-*-pci`.
+//!
+//! A virtqueue is a table of descriptors, each pointing at a buffer, and two
+//! rings: the driver puts chains of descriptors in the available ring for the
+//! device, which puts them in the used ring once it is done with them, along
+//! with how many bytes it wrote. `core` has the transports and the queues;
+//! a driver implements `VirtioDevice` and only deals with its queues.
+
+pub mod console;
+pub mod core;
+pub mod rng;
+
+pub use self::core::{handle_interrupt, probe, Buffer, Transport, VirtioDevice, Virtqueue};
@@ -0,0 +1,154 @@
+//! The virtio console, as QEMU emulates it with
+//! `-device virtio-serial-pci -device virtconsole,chardev=<id>`: a port to a
+//! character device of the host, `hvc0`, faster than a serial port as bytes
+//! move a buffer at a time. `serial_shell=hvc0` runs a remote shell session
+//! on it, see `shell::remote`.
+//!
+//! Without the multiport feature the device has a single port, whose bytes
+//! come in through the receive queue and go out through the transmit queue.
+
+use alloc::vec::Vec;
+use spin::Mutex;
+use crate::arch::interrupts::without_interrupts;
+use crate::memory::{self, DmaRegion};
+use crate::initcall;
+use super::core::TYPE_CONSOLE;
+use super::{Buffer, Transport, VirtioDevice, Virtqueue};
+
+pub const NAME: &str = "hvc0";
+
+const RECEIVE_QUEUE: u16 = 0;
+const TRANSMIT_QUEUE: u16 = 1;
+
+/// Buffers waiting for input, and their size.
+const RX_BUFFERS: usize = 16;
+const RX_BUFFER_SIZE: usize = 64;
+/// Buffers of output on their way, and their size.
+const TX_BUFFERS: usize = 16;
+const TX_BUFFER_SIZE: usize = 128;
+/// Where the transmit buffers start in the page of buffers.
+const TX_OFFSET: usize = RX_BUFFERS * RX_BUFFER_SIZE;
+
+struct Console {
+    transport: Transport,
+    rx: Virtqueue,
+    tx: Virtqueue,
+    /// The receive buffers, then the transmit buffers.
+    buffers: DmaRegion,
+    /// Transmit buffers the device is done with.
+    tx_free: Vec<usize>,
+}
+
+static CONSOLE: Mutex<Option<Console>> = Mutex::new(None);
+/// Called with the bytes received, see `set_receiver`.
+static RECEIVER: Mutex<Option<fn(u8)>> = Mutex::new(None);
+
+impl Console {
+    fn buffer(&self, offset: usize) -> *mut u8 {
+        (self.buffers.virt.as_u64() as usize + offset) as *mut u8
+    }
+
+    /// Hands receive buffer `index` to the device.
+    fn give_rx(&mut self, index: usize) -> Result<(), &'static str> {
+        let phys = self.buffers.phys.as_u64() + (index * RX_BUFFER_SIZE) as u64;
+        self.rx.push(&[Buffer { phys, len: RX_BUFFER_SIZE as u32, writable: true }], index)
+    }
+
+    /// Takes back the transmit buffers the device sent.
+    fn clean_tx(&mut self) {
+        while let Some((index, _)) = self.tx.pop_used() {
+            self.tx_free.push(index);
+        }
+    }
+
+    /// Sends `bytes`, at most a buffer of them, waiting for a free buffer.
+    fn send(&mut self, bytes: &[u8]) -> Result<(), &'static str> {
+        let index = loop {
+            self.clean_tx();
+            match self.tx_free.pop() {
+                Some(index) => break index,
+                None => core::hint::spin_loop(),
+            }
+        };
+        let offset = TX_OFFSET + index * TX_BUFFER_SIZE;
+        unsafe { core::ptr::copy_nonoverlapping(bytes.as_ptr(), self.buffer(offset), bytes.len()) };
+        let phys = self.buffers.phys.as_u64() + offset as u64;
+        self.tx.push(&[Buffer { phys, len: bytes.len() as u32, writable: false }], index)?;
+        self.transport.notify(&self.tx);
+        Ok(())
+    }
+}
+
+impl VirtioDevice for Console {
+    const TYPE: u16 = TYPE_CONSOLE;
+    const NAME: &'static str = "virtio-console";
+    /// Columns, rows, ports and the emergency write register.
+    const CONFIG_SIZE: u16 = 12;
+
+    fn new(transport: Transport) -> Result<Console, &'static str> {
+        let rx = transport.queue(RECEIVE_QUEUE)?;
+        let tx = transport.queue(TRANSMIT_QUEUE)?;
+        let buffers = memory::allocate_dma((TX_OFFSET + TX_BUFFERS * TX_BUFFER_SIZE) as u64)?;
+        let mut console = Console { transport, rx, tx, buffers, tx_free: (0..TX_BUFFERS).collect() };
+        for index in 0..RX_BUFFERS.min(usize::from(console.rx.size())) {
+            console.give_rx(index)?;
+        }
+        Ok(console)
+    }
+
+    fn transport(&self) -> &Transport {
+        &self.transport
+    }
+
+    fn start(&mut self) {
+        self.transport.notify(&self.rx);
+    }
+
+    fn used(&mut self) {
+        let receiver = *RECEIVER.lock();
+        let mut received = false;
+        while let Some((index, len)) = self.rx.pop_used() {
+            let len = (len as usize).min(RX_BUFFER_SIZE);
+            let data = unsafe { core::slice::from_raw_parts(self.buffer(index * RX_BUFFER_SIZE), len) };
+            if let Some(receiver) = receiver {
+                data.iter().for_each(|&byte| receiver(byte));
+            }
+            // it was in the queue a moment ago, so there is room
+            let _ = self.give_rx(index);
+            received = true;
+        }
+        if received {
+            self.transport.notify(&self.rx);
+        }
+        self.clean_tx();
+    }
+}
+
+/// Finds the device on the PCI bus and starts receiving.
+pub fn init() -> Result<(), &'static str> {
+    super::probe(&CONSOLE, handle_interrupt)
+}
+initcall!(Driver, "virtio-console", init);
+
+/// Whether there is a virtio console.
+pub fn is_present() -> bool {
+    without_interrupts(|| CONSOLE.lock().is_some())
+}
+
+/// Writes `bytes` to the console, waiting while the device is behind.
+pub fn write(bytes: &[u8]) -> Result<(), &'static str> {
+    for chunk in bytes.chunks(TX_BUFFER_SIZE) {
+        without_interrupts(|| CONSOLE.lock().as_mut().ok_or("no virtio console")?.send(chunk))?;
+    }
+    Ok(())
+}
+
+/// Makes `receiver` get the bytes received, or drops them. The receiver is
+/// called by the interrupt handler.
+pub fn set_receiver(receiver: Option<fn(u8)>) {
+    without_interrupts(|| *RECEIVER.lock() = receiver);
+}
+
+fn handle_interrupt() {
+    super::handle_interrupt(&CONSOLE);
+}
@@ -0,0 +1,600 @@
+//! What all virtio drivers share: finding the device and talking to it over
+//! either PCI transport, negotiating features, the virtqueues, and the
+//! `VirtioDevice` trait that ties a driver to them.
+//!
+//! Modern devices have their registers in memory BARs the vendor
+//! capabilities point at; legacy ones, or transitional ones with
+//! `virtio=legacy`, in an I/O BAR. `Transport` hides which one it is.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{fence, Ordering};
+use spin::Mutex;
+use x86_64::instructions::port::PortRead;
+use x86_64::{PhysAddr, VirtAddr};
+use crate::arch::interrupts::{self, without_interrupts};
+use crate::arch::x86_64::ioport::{self, PortRange};
+use crate::drivers::pci::{self, Bar, PciDevice};
+use crate::memory::{self, DmaRegion};
+use crate::{boot, log_info, mmio};
+
+pub const VENDOR: u16 = 0x1af4;
+
+// device types
+pub const TYPE_NET: u16 = 1;
+pub const TYPE_BLOCK: u16 = 2;
+pub const TYPE_CONSOLE: u16 = 3;
+pub const TYPE_RNG: u16 = 4;
+
+/// The device follows the virtio 1.0 specification, which the modern
+/// transport requires.
+pub const F_VERSION_1: u64 = 1 << 32;
+
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FEATURES_OK: u8 = 8;
+const STATUS_FAILED: u8 = 128;
+
+/// Set in the interrupt status when a queue has used buffers.
+pub const ISR_QUEUE: u8 = 1 << 0;
+/// Set in the interrupt status when the device configuration changed.
+pub const ISR_CONFIG: u8 = 1 << 1;
+
+const DESC_NEXT: u16 = 1 << 0;
+const DESC_WRITE: u16 = 1 << 1;
+
+// legacy registers, in the I/O BAR
+const LEGACY_DEVICE_FEATURES: u16 = 0x00;
+const LEGACY_DRIVER_FEATURES: u16 = 0x04;
+const LEGACY_QUEUE_PFN: u16 = 0x08;
+const LEGACY_QUEUE_SIZE: u16 = 0x0c;
+const LEGACY_QUEUE_SELECT: u16 = 0x0e;
+const LEGACY_QUEUE_NOTIFY: u16 = 0x10;
+const LEGACY_DEVICE_STATUS: u16 = 0x12;
+const LEGACY_ISR_STATUS: u16 = 0x13;
+/// Where the device specific configuration starts, without MSI-X.
+const LEGACY_DEVICE_CONFIG: u16 = 0x14;
+
+/// The legacy interface aligns the used ring, and queues, to pages.
+const QUEUE_ALIGN: usize = 4096;
+
+const CAPABILITY_VENDOR: u8 = 0x09;
+// vendor capability types
+const CAP_COMMON: u8 = 1;
+const CAP_NOTIFY: u8 = 2;
+const CAP_ISR: u8 = 3;
+const CAP_DEVICE: u8 = 4;
+
+crate::register_block! {
+    /// The common configuration of the modern transport.
+    struct CommonConfig, size 0x38 {
+        device_feature_select: u32, ReadWrite @ 0x00;
+        device_feature: u32, ReadOnly @ 0x04;
+        driver_feature_select: u32, ReadWrite @ 0x08;
+        driver_feature: u32, ReadWrite @ 0x0c;
+        device_status: u8, ReadWrite @ 0x14;
+        queue_select: u16, ReadWrite @ 0x16;
+        queue_size: u16, ReadWrite @ 0x18;
+        queue_enable: u16, ReadWrite @ 0x1c;
+        queue_notify_off: u16, ReadOnly @ 0x1e;
+        queue_desc: u64, ReadWrite @ 0x20;
+        queue_driver: u64, ReadWrite @ 0x28;
+        queue_device: u64, ReadWrite @ 0x30;
+    }
+}
+
+/// The structures of the modern transport, mapped.
+struct Modern {
+    common: CommonConfig,
+    notify: VirtAddr,
+    /// Bytes between the notification registers of consecutive queues.
+    notify_multiplier: u32,
+    isr: VirtAddr,
+    device: Option<VirtAddr>,
+}
+
+enum Registers {
+    Legacy(PortRange),
+    Modern(Modern),
+}
+
+/// A vendor capability of a modern device: which structure is where.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Capability {
+    kind: u8,
+    bar: u8,
+    offset: u32,
+    length: u32,
+    /// Only there for the notification structure.
+    notify_multiplier: u32,
+}
+
+impl Capability {
+    /// Decodes the words of the capability, starting at its header.
+    fn parse(words: [u32; 5]) -> Option<Capability> {
+        let bar = words[1] as u8;
+        if words[0] as u8 != CAPABILITY_VENDOR || bar > 5 {
+            return None;
+        }
+        let kind = (words[0] >> 24) as u8;
+        let notify_multiplier = if kind == CAP_NOTIFY { words[4] } else { 0 };
+        Some(Capability { kind, bar, offset: words[2], length: words[3], notify_multiplier })
+    }
+}
+
+/// The type of a virtio device, from the device id of a modern device or
+/// the subsystem id of a transitional one.
+fn device_type(device_id: u16, subsystem_id: u16) -> Option<u16> {
+    match device_id {
+        0x1000..=0x103f => Some(subsystem_id),
+        0x1040..=0x107f => Some(device_id - 0x1040),
+        _ => None,
+    }
+}
+
+/// The registers of a device, and the features agreed on.
+pub struct Transport {
+    pci: PciDevice,
+    registers: Registers,
+    features: u64,
+}
+
+impl Transport {
+    /// Finds the first device of `device_type`, whose configuration is
+    /// `config_size` bytes, resets it and tells it a driver is there.
+    pub fn find(device_type: u16, config_size: u16, owner: &'static str) -> Result<Transport, &'static str> {
+        let pci = pci::enumerate()
+            .into_iter()
+            .find(|dev| dev.vendor_id == VENDOR && self::device_type(dev.device_id, dev.subsystem_id()) == Some(device_type))
+            .ok_or("no such virtio device")?;
+        pci.enable_bus_mastering();
+        let legacy = boot::cmdline().get("virtio") == Some("legacy");
+        let registers = match modern_registers(&pci)? {
+            Some(modern) if !legacy => Registers::Modern(modern),
+            _ => {
+                let base = match pci.bar(0) {
+                    Some(Bar::Io(base)) => base,
+                    _ => return Err("virtio device without a legacy I/O BAR"),
+                };
+                Registers::Legacy(ioport::claim(base, LEGACY_DEVICE_CONFIG + config_size, owner)?)
+            }
+        };
+        let transport = Transport { pci, registers, features: 0 };
+        transport.set_status(0);
+        transport.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+        Ok(transport)
+    }
+
+    pub fn pci(&self) -> PciDevice {
+        self.pci
+    }
+
+    pub fn is_modern(&self) -> bool {
+        matches!(self.registers, Registers::Modern(_))
+    }
+
+    /// The features agreed on.
+    pub fn features(&self) -> u64 {
+        self.features
+    }
+
+    fn status(&self) -> u8 {
+        match &self.registers {
+            Registers::Legacy(ports) => ports.read(LEGACY_DEVICE_STATUS),
+            Registers::Modern(modern) => modern.common.device_status().read(),
+        }
+    }
+
+    fn set_status(&self, status: u8) {
+        match &self.registers {
+            Registers::Legacy(ports) => ports.write(LEGACY_DEVICE_STATUS, status),
+            Registers::Modern(modern) => modern.common.device_status().write(status),
+        }
+    }
+
+    /// Accepts the features of `supported` the device offers. A modern
+    /// device must accept them in turn, and offer `F_VERSION_1`.
+    pub fn negotiate(&mut self, supported: u64) -> Result<u64, &'static str> {
+        self.features = match &self.registers {
+            Registers::Legacy(ports) => {
+                let features = u64::from(ports.read::<u32>(LEGACY_DEVICE_FEATURES)) & supported & 0xffff_ffff;
+                ports.write(LEGACY_DRIVER_FEATURES, features as u32);
+                features
+            }
+            Registers::Modern(modern) => {
+                let common = &modern.common;
+                let mut offered = 0;
+                for half in 0..2 {
+                    common.device_feature_select().write(half);
+                    offered |= u64::from(common.device_feature().read()) << (32 * half);
+                }
+                let features = offered & (supported | F_VERSION_1);
+                if features & F_VERSION_1 == 0 {
+                    return Err("virtio device without version 1");
+                }
+                for half in 0..2 {
+                    common.driver_feature_select().write(half);
+                    common.driver_feature().write((features >> (32 * half)) as u32);
+                }
+                self.set_status(self.status() | STATUS_FEATURES_OK);
+                if self.status() & STATUS_FEATURES_OK == 0 {
+                    self.fail();
+                    return Err("virtio device refused the features");
+                }
+                features
+            }
+        };
+        Ok(self.features)
+    }
+
+    /// Sets up queue `index` at the size the device has for it.
+    pub fn queue(&self, index: u16) -> Result<Virtqueue, &'static str> {
+        match &self.registers {
+            Registers::Legacy(ports) => {
+                ports.write(LEGACY_QUEUE_SELECT, index);
+                let size = ports.read::<u16>(LEGACY_QUEUE_SIZE);
+                if size == 0 {
+                    return Err("no such virtqueue");
+                }
+                let queue = Virtqueue::new(index, size, index)?;
+                ports.write(LEGACY_QUEUE_PFN, (queue.region.phys.as_u64() / QUEUE_ALIGN as u64) as u32);
+                Ok(queue)
+            }
+            Registers::Modern(modern) => {
+                let common = &modern.common;
+                common.queue_select().write(index);
+                let size = common.queue_size().read();
+                if size == 0 {
+                    return Err("no such virtqueue");
+                }
+                let queue = Virtqueue::new(index, size, common.queue_notify_off().read())?;
+                let phys = queue.region.phys.as_u64();
+                common.queue_desc().write(phys);
+                common.queue_driver().write(phys + queue.avail as u64);
+                common.queue_device().write(phys + queue.used as u64);
+                common.queue_enable().write(1);
+                Ok(queue)
+            }
+        }
+    }
+
+    /// Lets the device work, once the queues are set up.
+    pub fn start(&self) {
+        self.set_status(self.status() | STATUS_DRIVER_OK);
+    }
+
+    /// Gives up on the device, which stops using the queues.
+    pub fn fail(&self) {
+        self.set_status(self.status() | STATUS_FAILED);
+    }
+
+    /// Tells the device there are new buffers in `queue`.
+    pub fn notify(&self, queue: &Virtqueue) {
+        // the device must see the ring before the notification
+        fence(Ordering::SeqCst);
+        match &self.registers {
+            Registers::Legacy(ports) => ports.write(LEGACY_QUEUE_NOTIFY, queue.index),
+            Registers::Modern(modern) => {
+                let offset = u64::from(queue.notify_off) * u64::from(modern.notify_multiplier);
+                unsafe { (modern.notify + offset).as_mut_ptr::<u16>().write_volatile(queue.index) };
+            }
+        }
+    }
+
+    /// Why the device interrupted, see `ISR_QUEUE` and `ISR_CONFIG`. Reading
+    /// it acknowledges the interrupt.
+    pub fn interrupt_status(&self) -> u8 {
+        match &self.registers {
+            Registers::Legacy(ports) => ports.read(LEGACY_ISR_STATUS),
+            Registers::Modern(modern) => unsafe { modern.isr.as_ptr::<u8>().read_volatile() },
+        }
+    }
+
+    /// Reads the device specific configuration at `offset`.
+    pub fn config<T: PortRead + Copy>(&self, offset: u16) -> Option<T> {
+        match &self.registers {
+            Registers::Legacy(ports) => Some(ports.read(LEGACY_DEVICE_CONFIG + offset)),
+            Registers::Modern(modern) => {
+                let device = modern.device?;
+                Some(unsafe { (device + u64::from(offset)).as_ptr::<T>().read_volatile() })
+            }
+        }
+    }
+}
+
+/// Maps the structures of the modern transport, if the device has them.
+fn modern_registers(pci: &PciDevice) -> Result<Option<Modern>, &'static str> {
+    let capabilities: Vec<Capability> = pci
+        .capabilities()
+        .into_iter()
+        .filter(|&(id, _)| id == CAPABILITY_VENDOR)
+        .filter_map(|(_, offset)| {
+            let words = [0, 4, 8, 12, 16].map(|at| pci.read(offset + at));
+            Capability::parse(words)
+        })
+        .collect();
+    let find = |kind| capabilities.iter().find(|capability| capability.kind == kind);
+    let (common, notify, isr) = match (find(CAP_COMMON), find(CAP_NOTIFY), find(CAP_ISR)) {
+        (Some(common), Some(notify), Some(isr)) => (common, notify, isr),
+        _ => return Ok(None),
+    };
+    let map = |capability: &Capability| match pci.bar(capability.bar) {
+        Some(Bar::Memory(base)) => {
+            memory::map_mmio(PhysAddr::new(base + u64::from(capability.offset)), u64::from(capability.length))
+        }
+        _ => Err("virtio capability outside of a memory BAR"),
+    };
+    let common_phys = match pci.bar(common.bar) {
+        Some(Bar::Memory(base)) => PhysAddr::new(base + u64::from(common.offset)),
+        _ => return Err("virtio capability outside of a memory BAR"),
+    };
+    Ok(Some(Modern {
+        common: mmio::map(common_phys)?,
+        notify: map(notify)?,
+        notify_multiplier: notify.notify_multiplier,
+        isr: map(isr)?,
+        device: find(CAP_DEVICE).map(map).transpose()?,
+    }))
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+/// A buffer of a request, in memory the device reaches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Buffer {
+    pub phys: u64,
+    pub len: u32,
+    /// Whether the device writes the buffer rather than reads it.
+    pub writable: bool,
+}
+
+/// The descriptor table and the rings of a queue, laid out as the legacy
+/// transport needs them, which suits the modern one too: the descriptors,
+/// the available ring right after them, and the used ring on the next page.
+pub struct Virtqueue {
+    index: u16,
+    size: u16,
+    /// Where the queue's notification register is, in multiples of the
+    /// transport's.
+    notify_off: u16,
+    region: DmaRegion,
+    /// Offsets of the rings in `region`.
+    avail: usize,
+    used: usize,
+    /// Descriptors not in a chain.
+    free: Vec<u16>,
+    /// The token of each chain, by its first descriptor.
+    tokens: Vec<usize>,
+    /// Entries put in the available ring, and taken from the used ring.
+    next_avail: u16,
+    last_used: u16,
+}
+
+/// Offsets of the available and used rings of a queue of `size` entries,
+/// and its size in bytes.
+fn layout(size: u16) -> (usize, usize, usize) {
+    let size = usize::from(size);
+    let avail = core::mem::size_of::<Descriptor>() * size;
+    let used = align_up(avail + 6 + 2 * size, QUEUE_ALIGN);
+    (avail, used, used + align_up(6 + 8 * size, QUEUE_ALIGN))
+}
+
+fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) / align * align
+}
+
+impl Virtqueue {
+    fn new(index: u16, size: u16, notify_off: u16) -> Result<Virtqueue, &'static str> {
+        let (avail, used, bytes) = layout(size);
+        Ok(Virtqueue {
+            index,
+            size,
+            notify_off,
+            region: memory::allocate_dma(bytes as u64)?,
+            avail,
+            used,
+            free: (0..size).rev().collect(),
+            tokens: alloc::vec![0; usize::from(size)],
+            next_avail: 0,
+            last_used: 0,
+        })
+    }
+
+    /// Entries of the queue, the most buffers it holds at once.
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// Buffers that can still be added.
+    pub fn free(&self) -> usize {
+        self.free.len()
+    }
+
+    fn at<T>(&self, offset: usize) -> *mut T {
+        (self.region.virt.as_u64() as usize + offset) as *mut T
+    }
+
+    fn descriptor(&self, id: u16) -> *mut Descriptor {
+        self.at(usize::from(id) * core::mem::size_of::<Descriptor>())
+    }
+
+    /// Hands the device a request made of `buffers`, the ones it reads first.
+    /// `token` comes back with it from `pop_used`.
+    pub fn push(&mut self, buffers: &[Buffer], token: usize) -> Result<(), &'static str> {
+        if buffers.is_empty() || buffers.len() > self.free.len() {
+            return Err("virtqueue full");
+        }
+        let ids: Vec<u16> = (0..buffers.len()).filter_map(|_| self.free.pop()).collect();
+        for (i, (buffer, &id)) in buffers.iter().zip(&ids).enumerate() {
+            let mut flags = if buffer.writable { DESC_WRITE } else { 0 };
+            let next = match ids.get(i + 1) {
+                Some(&next) => {
+                    flags |= DESC_NEXT;
+                    next
+                }
+                None => 0,
+            };
+            let descriptor = Descriptor { addr: buffer.phys, len: buffer.len, flags, next };
+            unsafe { self.descriptor(id).write_volatile(descriptor) };
+        }
+        let head = ids[0];
+        self.tokens[usize::from(head)] = token;
+        let slot = usize::from(self.next_avail % self.size);
+        unsafe { self.at::<u16>(self.avail + 4 + 2 * slot).write_volatile(head) };
+        self.next_avail = self.next_avail.wrapping_add(1);
+        // the entry must be in place before the index moves past it
+        fence(Ordering::SeqCst);
+        unsafe { self.at::<u16>(self.avail + 2).write_volatile(self.next_avail) };
+        Ok(())
+    }
+
+    /// Takes back a request the device is done with: its token, and how many
+    /// bytes the device wrote to its buffers.
+    pub fn pop_used(&mut self) -> Option<(usize, u32)> {
+        let index = unsafe { self.at::<u16>(self.used + 2).read_volatile() };
+        if index == self.last_used {
+            return None;
+        }
+        fence(Ordering::SeqCst);
+        let slot = usize::from(self.last_used % self.size);
+        let head = unsafe { self.at::<u32>(self.used + 4 + 8 * slot).read_volatile() } as u16;
+        let len = unsafe { self.at::<u32>(self.used + 8 + 8 * slot).read_volatile() };
+        self.last_used = self.last_used.wrapping_add(1);
+        let mut id = head;
+        loop {
+            self.free.push(id);
+            let descriptor = unsafe { self.descriptor(id).read_volatile() };
+            if descriptor.flags & DESC_NEXT == 0 {
+                break;
+            }
+            id = descriptor.next;
+        }
+        Some((self.tokens[usize::from(head)], len))
+    }
+}
+
+/// A driver of a type of virtio device, which `probe` sets up.
+pub trait VirtioDevice: Sized + Send + 'static {
+    /// The device type, e.g. `TYPE_CONSOLE`.
+    const TYPE: u16;
+    /// Names the driver in the log and as the owner of the device's ports.
+    const NAME: &'static str;
+    /// Bytes of the device specific configuration.
+    const CONFIG_SIZE: u16 = 0;
+    /// The features the driver can use.
+    const FEATURES: u64 = 0;
+
+    /// Sets up the queues of the device, with the features it agreed on in
+    /// `transport.features()`.
+    fn new(transport: Transport) -> Result<Self, &'static str>;
+
+    fn transport(&self) -> &Transport;
+
+    /// Called once the device runs, e.g. to give it the first buffers.
+    fn start(&mut self) {}
+
+    /// Takes what the device put in the used rings. Called by the interrupt
+    /// handler.
+    fn used(&mut self);
+
+    /// Called by the interrupt handler when the device configuration changed.
+    fn config_changed(&mut self) {}
+}
+
+/// Finds the device `D` drives, sets it up, puts it in `slot` and starts it.
+/// `handler` is registered for its interrupts, and should pass them on to
+/// `handle_interrupt` with `slot`.
+pub fn probe<D: VirtioDevice>(slot: &'static Mutex<Option<D>>, handler: fn()) -> Result<(), &'static str> {
+    let mut transport = Transport::find(D::TYPE, D::CONFIG_SIZE, D::NAME)?;
+    let pci = transport.pci();
+    let irq = match pci.interrupt_line() {
+        Some(irq) => irq,
+        None => {
+            transport.fail();
+            return Err("virtio device without an IRQ line");
+        }
+    };
+    transport.negotiate(D::FEATURES)?;
+    let kind = if transport.is_modern() { "modern" } else { "legacy" };
+    let device = D::new(transport)?;
+    without_interrupts(|| *slot.lock() = Some(device));
+    interrupts::register_shared_irq(irq, handler)?;
+    without_interrupts(|| {
+        if let Some(device) = slot.lock().as_mut() {
+            device.transport().start();
+            device.start();
+        }
+    });
+    log_info!("{}: {:02x}:{:02x}.{} irq {}, {}", D::NAME, pci.bus, pci.device, pci.function, irq, kind);
+    Ok(())
+}
+
+/// Serves an interrupt of the device in `slot`.
+pub fn handle_interrupt<D: VirtioDevice>(slot: &Mutex<Option<D>>) {
+    // the interrupted code may hold the lock; the device interrupts again
+    // for what it puts in the used rings later
+    let mut device = match slot.try_lock() {
+        Some(device) => device,
+        None => return,
+    };
+    let device = match device.as_mut() {
+        Some(device) => device,
+        None => return,
+    };
+    let status = device.transport().interrupt_status();
+    if status & ISR_QUEUE != 0 {
+        device.used();
+    }
+    if status & ISR_CONFIG != 0 {
+        device.config_changed();
+    }
+}
+
+#[test_case]
+fn test_virtqueue() {
+    assert_eq!(layout(8), (128, 4096, 8192));
+    assert_eq!(layout(256), (4096, 8192, 12288));
+
+    let mut queue = Virtqueue::new(0, 4, 0).unwrap();
+    let buffers = [Buffer { phys: 0x1000, len: 16, writable: false }, Buffer { phys: 0x2000, len: 512, writable: true }];
+    queue.push(&buffers, 7).unwrap();
+    assert_eq!(queue.free(), 2);
+    assert!(queue.push(&[buffers[0]; 3], 8).is_err());
+    let head = unsafe { queue.at::<u16>(queue.avail + 4).read_volatile() };
+    let first = unsafe { queue.descriptor(head).read_volatile() };
+    assert_eq!((first.addr, first.flags & DESC_NEXT), (0x1000, DESC_NEXT));
+    let second = unsafe { queue.descriptor(first.next).read_volatile() };
+    assert_eq!((second.addr, second.flags), (0x2000, DESC_WRITE));
+    assert_eq!(unsafe { queue.at::<u16>(queue.avail + 2).read_volatile() }, 1);
+
+    // what the device does once it wrote 100 bytes
+    assert_eq!(queue.pop_used(), None);
+    unsafe {
+        queue.at::<u32>(queue.used + 4).write_volatile(u32::from(head));
+        queue.at::<u32>(queue.used + 8).write_volatile(100);
+        queue.at::<u16>(queue.used + 2).write_volatile(1);
+    }
+    assert_eq!(queue.pop_used(), Some((7, 100)));
+    assert_eq!(queue.free(), 4);
+}
+
+#[test_case]
+fn test_device_discovery() {
+    assert_eq!(device_type(0x1003, TYPE_CONSOLE), Some(TYPE_CONSOLE));
+    assert_eq!(device_type(0x1044, 0), Some(TYPE_RNG));
+    assert_eq!(device_type(0x1110, 0), None);
+
+    // a notification structure in BAR 4, as QEMU has it
+    let notify = Capability::parse([0x0214_7009, 4, 0x3000, 0x1000, 4]).unwrap();
+    assert_eq!(notify, Capability { kind: CAP_NOTIFY, bar: 4, offset: 0x3000, length: 0x1000, notify_multiplier: 4 });
+    let common = Capability::parse([0x0110_6009, 4, 0, 0x1000, 0]).unwrap();
+    assert_eq!((common.kind, common.notify_multiplier), (CAP_COMMON, 0));
+    assert_eq!(Capability::parse([0x0110_6011, 4, 0, 0x1000, 0]), None);
+}
@@ -0,0 +1,120 @@
+//! The virtio entropy device, as QEMU emulates it with `-device virtio-rng-pci`:
+//! the host fills the buffers it is handed with random bytes, from its own
+//! `/dev/urandom` by default.
+//!
+//! The bytes are kept for the seeds `rand` makes, see `rand::set_source`, and
+//! each is handed out once: `rand` reseeds as soon as the first ones came. A
+//! new batch is asked for once they are used up, so the device is not kept
+//! busy for nothing.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use crate::arch::interrupts::without_interrupts;
+use crate::memory::{self, DmaRegion};
+use crate::{initcall, rand, workqueue};
+use super::core::TYPE_RNG;
+use super::{Buffer, Transport, VirtioDevice, Virtqueue};
+
+const REQUEST_QUEUE: u16 = 0;
+/// Bytes asked for at once.
+const BATCH: usize = 64;
+
+struct Rng {
+    transport: Transport,
+    queue: Virtqueue,
+    buffer: DmaRegion,
+    /// Random bytes at the start of the buffer not handed out yet.
+    available: usize,
+    /// Whether the device is filling the buffer.
+    requested: bool,
+}
+
+static RNG: Mutex<Option<Rng>> = Mutex::new(None);
+/// Set once the first batch came in.
+static SEEDED: AtomicBool = AtomicBool::new(false);
+
+impl Rng {
+    /// Asks for a batch, unless the device is filling the buffer already.
+    fn request(&mut self) {
+        if self.requested {
+            return;
+        }
+        let buffer = Buffer { phys: self.buffer.phys.as_u64(), len: BATCH as u32, writable: true };
+        if self.queue.push(&[buffer], 0).is_ok() {
+            self.requested = true;
+            self.transport.notify(&self.queue);
+        }
+    }
+
+    fn bytes(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.buffer.virt.as_mut_ptr::<u8>(), BATCH) }
+    }
+}
+
+impl VirtioDevice for Rng {
+    const TYPE: u16 = TYPE_RNG;
+    const NAME: &'static str = "virtio-rng";
+
+    fn new(transport: Transport) -> Result<Rng, &'static str> {
+        let queue = transport.queue(REQUEST_QUEUE)?;
+        let buffer = memory::allocate_dma(BATCH as u64)?;
+        Ok(Rng { transport, queue, buffer, available: 0, requested: false })
+    }
+
+    fn transport(&self) -> &Transport {
+        &self.transport
+    }
+
+    fn start(&mut self) {
+        self.request();
+    }
+
+    fn used(&mut self) {
+        while let Some((_, len)) = self.queue.pop_used() {
+            self.requested = false;
+            self.available = (len as usize).min(BATCH);
+        }
+        if self.available > 0 && !SEEDED.swap(true, Ordering::Relaxed) {
+            // reseeding takes the generator's lock, which the interrupted code may hold
+            let _ = workqueue::queue(rand::reseed);
+        }
+    }
+}
+
+/// Finds the device on the PCI bus and asks it for the first batch.
+pub fn init() -> Result<(), &'static str> {
+    super::probe(&RNG, handle_interrupt)?;
+    rand::set_source(read);
+    Ok(())
+}
+initcall!(Driver, "virtio-rng", init);
+
+/// Fills `buf` with the random bytes at hand, without waiting for the device,
+/// and returns how many it wrote.
+pub fn read(buf: &mut [u8]) -> usize {
+    without_interrupts(|| {
+        let mut rng = match RNG.try_lock() {
+            Some(rng) => rng,
+            None => return 0,
+        };
+        let rng = match rng.as_mut() {
+            Some(rng) => rng,
+            None => return 0,
+        };
+        let len = buf.len().min(rng.available);
+        let (start, end) = (rng.available - len, rng.available);
+        let bytes = &mut rng.bytes()[start..end];
+        buf[..len].copy_from_slice(bytes);
+        // handed out once
+        bytes.iter_mut().for_each(|byte| *byte = 0);
+        rng.available = start;
+        if rng.available == 0 {
+            rng.request();
+        }
+        len
+    })
+}
+
+fn handle_interrupt() {
+    super::handle_interrupt(&RNG);
+}
@@ -0,0 +1,262 @@
+//! A full-screen text editor in the spirit of nano, started with `edit <file>`.
+//!
+//! The file is loaded into a vector of lines and written back whole on save.
+//! While it runs, the editor grabs the keyboard, suspends the console and draws
+//! the text rows itself. The last of them is the help line.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use crate::arch::interrupts::without_interrupts;
+use crate::fs::{self, FsError};
+use crate::keyboard::{self, DecodedKey, KeyCode, KeyPress};
+use crate::vga_buffer::{self, Color, BUFFER_WIDTH, TEXT_HEIGHT, WRITER};
+
+/// Rows of text on the screen. The row below them is the help line.
+const ROWS: usize = TEXT_HEIGHT - 1;
+const TAB_WIDTH: usize = 4;
+
+pub struct Editor {
+    path: String,
+    lines: Vec<Vec<u8>>,
+    /// Line and column of the cursor.
+    row: usize,
+    col: usize,
+    /// First line and column on the screen.
+    top: usize,
+    left: usize,
+    modified: bool,
+    /// Shown on the help line until the next key.
+    message: Option<String>,
+    done: bool,
+}
+
+impl Editor {
+    /// Creates an editor for `path` holding `text`, without touching the file.
+    pub fn new(path: &str, text: &[u8]) -> Editor {
+        Editor {
+            path: String::from(path),
+            lines: text.split(|&byte| byte == b'\n').map(Vec::from).collect(),
+            row: 0,
+            col: 0,
+            top: 0,
+            left: 0,
+            modified: false,
+            message: None,
+            done: false,
+        }
+    }
+
+    /// Loads the file at `path`, or starts an empty buffer if it doesn't exist.
+    pub fn open(path: &str) -> Result<Editor, FsError> {
+        match fs::read_file(path) {
+            Ok(text) => Ok(Editor::new(path, &text)),
+            Err(FsError::NotFound) => {
+                let mut editor = Editor::new(path, &[]);
+                editor.message = Some(String::from("new file"));
+                Ok(editor)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The contents of the buffer, as they would be saved.
+    pub fn text(&self) -> Vec<u8> {
+        self.lines.join(&b'\n')
+    }
+
+    /// Whether the user asked to quit.
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    /// Edits until the user quits, then gives the screen back to the console.
+    pub fn run(&mut self) {
+        let _grab = keyboard::grab();
+        without_interrupts(|| WRITER.lock().suspend());
+        while !self.done {
+            self.render();
+            let press = keyboard::read_key();
+            self.handle_key(press);
+        }
+        without_interrupts(|| WRITER.lock().resume());
+    }
+
+    pub fn handle_key(&mut self, press: KeyPress) {
+        let quitting = self.message.as_deref() == Some(UNSAVED);
+        self.message = None;
+        match press.key {
+            DecodedKey::RawKey(code) => match code {
+                KeyCode::ArrowLeft => self.move_left(),
+                KeyCode::ArrowRight => self.move_right(),
+                KeyCode::ArrowUp => self.move_to_line(self.row.saturating_sub(1)),
+                KeyCode::ArrowDown => self.move_to_line(self.row + 1),
+                KeyCode::PageUp => self.move_to_line(self.row.saturating_sub(ROWS)),
+                KeyCode::PageDown => self.move_to_line(self.row + ROWS),
+                KeyCode::Home => self.col = 0,
+                KeyCode::End => self.col = self.lines[self.row].len(),
+                _ => {}
+            },
+            // Ctrl-S
+            DecodedKey::Unicode('\u{13}') => self.save(),
+            // Ctrl-Q, twice if there are unsaved changes
+            DecodedKey::Unicode('\u{11}') => {
+                if self.modified && !quitting {
+                    self.message = Some(String::from(UNSAVED));
+                } else {
+                    self.done = true;
+                }
+            }
+            DecodedKey::Unicode('\n') => self.split_line(),
+            DecodedKey::Unicode('\u{8}') => self.backspace(),
+            DecodedKey::Unicode('\u{7f}') => self.delete(),
+            DecodedKey::Unicode('\t') => {
+                for _ in self.col % TAB_WIDTH..TAB_WIDTH {
+                    self.insert(b' ');
+                }
+            }
+            DecodedKey::Unicode(character) if (' '..='~').contains(&character) => self.insert(character as u8),
+            DecodedKey::Unicode(_) => {}
+        }
+        self.scroll();
+    }
+
+    fn move_left(&mut self) {
+        if self.col > 0 {
+            self.col -= 1;
+        } else if self.row > 0 {
+            self.row -= 1;
+            self.col = self.lines[self.row].len();
+        }
+    }
+
+    fn move_right(&mut self) {
+        if self.col < self.lines[self.row].len() {
+            self.col += 1;
+        } else if self.row + 1 < self.lines.len() {
+            self.row += 1;
+            self.col = 0;
+        }
+    }
+
+    fn move_to_line(&mut self, row: usize) {
+        self.row = row.min(self.lines.len() - 1);
+        self.col = self.col.min(self.lines[self.row].len());
+    }
+
+    fn insert(&mut self, byte: u8) {
+        self.lines[self.row].insert(self.col, byte);
+        self.col += 1;
+        self.modified = true;
+    }
+
+    fn split_line(&mut self) {
+        let rest = self.lines[self.row].split_off(self.col);
+        self.row += 1;
+        self.col = 0;
+        self.lines.insert(self.row, rest);
+        self.modified = true;
+    }
+
+    fn backspace(&mut self) {
+        if self.col == 0 && self.row == 0 {
+            return;
+        }
+        self.move_left();
+        self.delete();
+    }
+
+    fn delete(&mut self) {
+        if self.col < self.lines[self.row].len() {
+            self.lines[self.row].remove(self.col);
+        } else if self.row + 1 < self.lines.len() {
+            let next = self.lines.remove(self.row + 1);
+            self.lines[self.row].extend_from_slice(&next);
+        } else {
+            return;
+        }
+        self.modified = true;
+    }
+
+    fn save(&mut self) {
+        match fs::write_file(&self.path, &self.text()) {
+            Ok(()) => {
+                self.modified = false;
+                self.message = Some(String::from("saved"));
+            }
+            Err(e) => self.message = Some(format!("save failed: {:?}", e)),
+        }
+    }
+
+    /// Moves the visible part of the buffer so the cursor is on the screen.
+    fn scroll(&mut self) {
+        if self.row < self.top {
+            self.top = self.row;
+        } else if self.row >= self.top + ROWS {
+            self.top = self.row + 1 - ROWS;
+        }
+        if self.col < self.left {
+            self.left = self.col;
+        } else if self.col >= self.left + BUFFER_WIDTH {
+            self.left = self.col + 1 - BUFFER_WIDTH;
+        }
+    }
+
+    fn render(&self) {
+        for row in 0..ROWS {
+            let mut cells = [b' '; BUFFER_WIDTH];
+            if let Some(line) = self.lines.get(self.top + row) {
+                for (cell, &byte) in cells.iter_mut().zip(line.iter().skip(self.left)) {
+                    *cell = printable(byte);
+                }
+            }
+            vga_buffer::draw_text(row, 0, &cells, Color::White, Color::Black);
+        }
+        let under = self.lines[self.row].get(self.col).copied().map_or(b' ', printable);
+        vga_buffer::draw_text(self.row - self.top, self.col - self.left, &[under], Color::Black, Color::LightCyan);
+
+        let help = format!(" {}{} | Ln {}, Col {} | {}",
+            self.path, if self.modified { " [+]" } else { "" },
+            self.row + 1, self.col + 1,
+            self.message.as_deref().unwrap_or("^S save  ^Q quit"));
+        let mut cells = [b' '; BUFFER_WIDTH];
+        for (cell, byte) in cells.iter_mut().zip(help.bytes()) {
+            *cell = byte;
+        }
+        vga_buffer::draw_text(ROWS, 0, &cells, Color::Black, Color::LightGray);
+    }
+}
+
+const UNSAVED: &str = "unsaved changes, ^Q again to quit";
+
+/// How `byte` is shown: bytes outside printable ASCII become `?`.
+fn printable(byte: u8) -> u8 {
+    if (0x20..=0x7e).contains(&byte) { byte } else { b'?' }
+}
+
+#[cfg(test)]
+fn key(key: DecodedKey) -> KeyPress {
+    KeyPress { key, shift: false, ctrl: false, alt: false }
+}
+
+#[test_case]
+fn test_editor_keys() {
+    let mut editor = Editor::new("/tmp/test", b"ab\ncd");
+    editor.handle_key(key(DecodedKey::RawKey(KeyCode::End)));
+    editor.handle_key(key(DecodedKey::Unicode('\n')));
+    editor.handle_key(key(DecodedKey::Unicode('x')));
+    assert_eq!(editor.text(), b"ab\nx\ncd");
+
+    editor.handle_key(key(DecodedKey::RawKey(KeyCode::ArrowDown)));
+    editor.handle_key(key(DecodedKey::Unicode('\u{7f}')));
+    editor.handle_key(key(DecodedKey::RawKey(KeyCode::Home)));
+    editor.handle_key(key(DecodedKey::Unicode('\u{8}')));
+    editor.handle_key(key(DecodedKey::Unicode('\u{8}')));
+    assert_eq!(editor.text(), b"ab\nc");
+
+    // unsaved changes need a second Ctrl-Q
+    editor.handle_key(key(DecodedKey::Unicode('\u{11}')));
+    assert!(!editor.is_done());
+    editor.handle_key(key(DecodedKey::Unicode('\u{11}')));
+    assert!(editor.is_done());
+}
@@ -0,0 +1,349 @@
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use crate::initcall;
+
+#[cfg(feature = "ext2")]
+pub mod block_cache;
+pub mod devfs;
+#[cfg(feature = "ext2")]
+pub mod ext2;
+pub mod page_cache;
+pub mod tmpfs;
+
+/// Errors returned by the VFS layer and the filesystems behind it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    AlreadyExists,
+    InvalidPath,
+    Unsupported,
+    /// Written to a pipe nobody reads anymore.
+    BrokenPipe,
+    /// The device behind the filesystem failed.
+    Io,
+    /// The filesystem's structures on the device are invalid.
+    Corrupted,
+    /// Removed a directory that still has entries.
+    NotEmpty,
+    /// No memory left for the data.
+    OutOfMemory,
+}
+
+/// The type of object an inode refers to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InodeKind {
+    File,
+    Directory,
+    CharDevice,
+}
+
+/// A node in the VFS tree: a regular file, a directory, or a device.
+///
+/// Every operation has a default implementation returning `FsError::Unsupported`,
+/// so a filesystem only implements what its nodes actually support.
+pub trait Inode: Send + Sync {
+    fn kind(&self) -> InodeKind;
+
+    fn size(&self) -> usize {
+        0
+    }
+
+    fn read_at(&self, _offset: usize, _buf: &mut [u8]) -> Result<usize, FsError> {
+        Err(FsError::Unsupported)
+    }
+
+    fn write_at(&self, _offset: usize, _buf: &[u8]) -> Result<usize, FsError> {
+        Err(FsError::Unsupported)
+    }
+
+    /// Looks up the direct child `name` of this directory.
+    fn lookup(&self, _name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        Err(FsError::NotADirectory)
+    }
+
+    /// Returns the names of all direct children of this directory.
+    fn read_dir(&self) -> Result<Vec<String>, FsError> {
+        Err(FsError::NotADirectory)
+    }
+
+    /// Creates the direct child `name` of this directory.
+    fn create(&self, _name: &str, _kind: InodeKind) -> Result<Arc<dyn Inode>, FsError> {
+        Err(FsError::Unsupported)
+    }
+
+    /// Cuts this file to `len` bytes, or extends it with zeros.
+    fn truncate(&self, _len: usize) -> Result<(), FsError> {
+        Err(FsError::Unsupported)
+    }
+
+    /// Adds `inode`, which belongs to the same filesystem, to this directory
+    /// as `name`.
+    fn link(&self, _name: &str, _inode: Arc<dyn Inode>) -> Result<(), FsError> {
+        Err(FsError::Unsupported)
+    }
+
+    /// Removes the direct child `name` of this directory. Directories must be
+    /// empty.
+    fn unlink(&self, _name: &str) -> Result<(), FsError> {
+        Err(FsError::Unsupported)
+    }
+
+    /// The page of this file's data at `index`, from the page cache, for
+    /// mapping the file into memory. Past the end of the file, it reads as
+    /// zeros.
+    fn page(&self, _index: u64) -> Result<Arc<page_cache::Page>, FsError> {
+        Err(FsError::Unsupported)
+    }
+}
+
+/// A mountable filesystem.
+pub trait FileSystem: Send + Sync {
+    fn name(&self) -> &str;
+    fn root(&self) -> Arc<dyn Inode>;
+}
+
+struct Mount {
+    path: String,
+    fs: Arc<dyn FileSystem>,
+}
+
+lazy_static! {
+    static ref MOUNTS: Mutex<Vec<Mount>> = Mutex::new(Vec::new());
+}
+
+/// Mounts the standard filesystems. Must be called after the heap is initialized.
+pub fn init() {
+    mount("/dev", Arc::new(devfs::DEVFS.clone()))
+        .expect("mounting devfs failed");
+    mount("/tmp", Arc::new(tmpfs::TmpFs::new()))
+        .expect("mounting tmpfs failed");
+}
+initcall!(Driver, "fs", || {
+    init();
+    Ok(())
+});
+
+/// Mounts `fs` at the absolute path `path`.
+pub fn mount(path: &str, fs: Arc<dyn FileSystem>) -> Result<(), FsError> {
+    let path = normalize(path)?;
+    let mut mounts = MOUNTS.lock();
+    if mounts.iter().any(|m| m.path == path) {
+        return Err(FsError::AlreadyExists);
+    }
+    mounts.push(Mount { path, fs });
+    Ok(())
+}
+
+/// Mounts the filesystem on the block device called `device` at `path`.
+#[cfg(feature = "ext2")]
+pub fn mount_device(device: &str, path: &str) -> Result<(), FsError> {
+    let device = crate::drivers::block::get(device).ok_or(FsError::NotFound)?;
+    ext2::Ext2::new(device).and_then(|ext2| mount(path, Arc::new(ext2)))
+}
+
+/// Without ext2 there is no filesystem to mount.
+#[cfg(not(feature = "ext2"))]
+pub fn mount_device(_device: &str, _path: &str) -> Result<(), FsError> {
+    Err(FsError::Unsupported)
+}
+
+/// Returns `(mount point, filesystem name)` for every mounted filesystem.
+pub fn mounts() -> Vec<(String, String)> {
+    MOUNTS.lock().iter()
+        .map(|m| (m.path.clone(), m.fs.name().to_string()))
+        .collect()
+}
+
+/// Resolves an absolute path to its inode.
+pub fn lookup(path: &str) -> Result<Arc<dyn Inode>, FsError> {
+    let path = normalize(path)?;
+    // pick the mount point that is the longest prefix of `path`
+    let (root, rest) = {
+        let mounts = MOUNTS.lock();
+        let mount = mounts.iter()
+            .filter(|m| is_prefix(&m.path, &path))
+            .max_by_key(|m| m.path.len())
+            .ok_or(FsError::NotFound)?;
+        let rest = String::from(&path[mount.path.len()..]);
+        (mount.fs.root(), rest)
+    };
+
+    let mut inode = root;
+    for component in rest.split('/').filter(|c| !c.is_empty()) {
+        inode = inode.lookup(component)?;
+    }
+    Ok(inode)
+}
+
+/// Splits the absolute path `path` into its parent directory and last component.
+fn split_parent(path: &str) -> Result<(String, String), FsError> {
+    let path = normalize(path)?;
+    let (parent, name) = path.rsplit_once('/').ok_or(FsError::InvalidPath)?;
+    Ok((String::from(if parent.is_empty() { "/" } else { parent }), String::from(name)))
+}
+
+/// The mount point whose filesystem `path` is on.
+fn mount_point(path: &str) -> Option<String> {
+    MOUNTS.lock().iter()
+        .filter(|m| is_prefix(&m.path, path))
+        .max_by_key(|m| m.path.len())
+        .map(|m| m.path.clone())
+}
+
+/// Creates a file or directory at the absolute path `path`.
+pub fn create(path: &str, kind: InodeKind) -> Result<Arc<dyn Inode>, FsError> {
+    let (parent, name) = split_parent(path)?;
+    if name.is_empty() {
+        return Err(FsError::AlreadyExists);
+    }
+    lookup(&parent)?.create(&name, kind)
+}
+
+/// Removes the file or empty directory at `path`.
+pub fn remove(path: &str) -> Result<(), FsError> {
+    let path = normalize(path)?;
+    let (parent, name) = split_parent(&path)?;
+    // neither the root nor a mount point
+    if name.is_empty() || mount_point(&path).as_deref() == Some(path.as_str()) {
+        return Err(FsError::InvalidPath);
+    }
+    lookup(&parent)?.unlink(&name)
+}
+
+/// Moves the file or directory at `from` to `to`, within one filesystem. A
+/// file at `to` is replaced.
+pub fn rename(from: &str, to: &str) -> Result<(), FsError> {
+    let (from_parent, from_name) = split_parent(from)?;
+    let (to_parent, to_name) = split_parent(to)?;
+    if from_name.is_empty() || to_name.is_empty() {
+        return Err(FsError::InvalidPath);
+    }
+    let (from, to) = (normalize(from)?, normalize(to)?);
+    if from == to {
+        return Ok(());
+    }
+    if mount_point(&from) != mount_point(&to) || mount_point(&from).as_deref() == Some(from.as_str()) {
+        return Err(FsError::Unsupported);
+    }
+    // a directory can't move into itself
+    if is_prefix(&from, &to) {
+        return Err(FsError::InvalidPath);
+    }
+    let (from_parent, to_parent) = (lookup(&from_parent)?, lookup(&to_parent)?);
+    let inode = from_parent.lookup(&from_name)?;
+    match to_parent.lookup(&to_name) {
+        Ok(existing) if existing.kind() == InodeKind::Directory => return Err(FsError::IsADirectory),
+        Ok(_) => to_parent.unlink(&to_name)?,
+        Err(FsError::NotFound) => {}
+        Err(error) => return Err(error),
+    }
+    to_parent.link(&to_name, inode)?;
+    from_parent.unlink(&from_name)
+}
+
+/// Reads the whole regular file at `path`.
+pub fn read_file(path: &str) -> Result<Vec<u8>, FsError> {
+    let mut file = File::open(path)?;
+    if file.inode().kind() != InodeKind::File {
+        // devices never end
+        return Err(FsError::Unsupported);
+    }
+    let mut data = Vec::new();
+    let mut chunk = [0u8; 512];
+    loop {
+        match file.read(&mut chunk)? {
+            0 => return Ok(data),
+            n => data.extend_from_slice(&chunk[..n]),
+        }
+    }
+}
+
+/// Replaces the contents of the file at `path` with `data`, creating the file
+/// if it doesn't exist.
+pub fn write_file(path: &str, data: &[u8]) -> Result<(), FsError> {
+    let inode = match lookup(path) {
+        Ok(inode) => inode,
+        Err(FsError::NotFound) => create(path, InodeKind::File)?,
+        Err(e) => return Err(e),
+    };
+    if inode.kind() == InodeKind::Directory {
+        return Err(FsError::IsADirectory);
+    }
+    inode.truncate(0)?;
+    let mut written = 0;
+    while written < data.len() {
+        match inode.write_at(written, &data[written..])? {
+            0 => return Err(FsError::Unsupported),
+            n => written += n,
+        }
+    }
+    Ok(())
+}
+
+/// An open file: an inode plus the current read/write offset.
+pub struct File {
+    inode: Arc<dyn Inode>,
+    offset: usize,
+}
+
+impl File {
+    /// Opens the file at the absolute path `path`.
+    pub fn open(path: &str) -> Result<File, FsError> {
+        let inode = lookup(path)?;
+        if inode.kind() == InodeKind::Directory {
+            return Err(FsError::IsADirectory);
+        }
+        Ok(File { inode, offset: 0 })
+    }
+
+    /// Opens `inode` directly, for files that are not in the VFS tree.
+    pub fn from_inode(inode: Arc<dyn Inode>) -> File {
+        File { inode, offset: 0 }
+    }
+
+    pub fn inode(&self) -> &Arc<dyn Inode> {
+        &self.inode
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, FsError> {
+        let n = self.inode.read_at(self.offset, buf)?;
+        self.offset += n;
+        Ok(n)
+    }
+
+    pub fn write(&mut self, buf: &[u8]) -> Result<usize, FsError> {
+        let n = self.inode.write_at(self.offset, buf)?;
+        self.offset += n;
+        Ok(n)
+    }
+
+    /// Moves the offset to `offset` bytes from the start of the file.
+    pub fn seek(&mut self, offset: usize) {
+        self.offset = offset;
+    }
+}
+
+/// Checks that `path` is absolute and strips trailing slashes (except for `/` itself).
+fn normalize(path: &str) -> Result<String, FsError> {
+    if !path.starts_with('/') {
+        return Err(FsError::InvalidPath);
+    }
+    let trimmed = path.trim_end_matches('/');
+    Ok(String::from(if trimmed.is_empty() { "/" } else { trimmed }))
+}
+
+/// Whether the mount point `mount` contains `path` (matching whole components only).
+fn is_prefix(mount: &str, path: &str) -> bool {
+    mount == "/"
+        || path == mount
+        || (path.starts_with(mount) && path.as_bytes()[mount.len()] == b'/')
+}
@@ -0,0 +1,86 @@
+//! The blocks a filesystem read from its device, kept in the page cache so
+//! walking the same directories and indirect blocks again doesn't go to the
+//! disk.
+
+use alloc::sync::Arc;
+use core::ops::Deref;
+use crate::drivers::block::{BlockDevice, SECTOR_SIZE};
+use crate::fs::page_cache::{self, Page, PAGE_SIZE};
+use crate::fs::FsError;
+
+/// The blocks of one device, of a size that is a multiple of the sector size
+/// and divides the page size.
+pub struct BlockCache {
+    device: Arc<dyn BlockDevice>,
+    block_size: usize,
+    pages: page_cache::Object,
+}
+
+impl BlockCache {
+    pub fn new(device: Arc<dyn BlockDevice>, block_size: usize) -> BlockCache {
+        assert!(block_size % SECTOR_SIZE == 0 && block_size > 0, "block size is not a multiple of the sector size");
+        assert!(PAGE_SIZE % block_size == 0, "block size does not divide the page size");
+        BlockCache { device, block_size, pages: page_cache::Object::new() }
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// The contents of block `block`.
+    pub fn read(&self, block: u64) -> Result<Block, FsError> {
+        let per_page = (PAGE_SIZE / self.block_size) as u64;
+        let sectors = (PAGE_SIZE / SECTOR_SIZE) as u64;
+        let index = block / per_page;
+        let page = self.pages.page(index, |data| {
+            let start = index.checked_mul(sectors).ok_or(FsError::Corrupted)?;
+            // the last page of the device may be partial
+            let count = self.device.sector_count().saturating_sub(start).min(sectors);
+            if count == 0 {
+                return Err(FsError::Io);
+            }
+            self.device.read_sectors(start, &mut data[..count as usize * SECTOR_SIZE]).map_err(|_| FsError::Io)
+        })?;
+        let offset = (block % per_page) as usize * self.block_size;
+        if (offset / SECTOR_SIZE) as u64 >= self.device.sector_count().saturating_sub(index * sectors) {
+            return Err(FsError::Io);
+        }
+        Ok(Block { page, offset, len: self.block_size })
+    }
+}
+
+/// A block read through a `BlockCache`.
+pub struct Block {
+    page: Arc<Page>,
+    offset: usize,
+    len: usize,
+}
+
+impl Deref for Block {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.page.data()[self.offset..self.offset + self.len]
+    }
+}
+
+#[test_case]
+fn test_block_cache() {
+    use alloc::vec::Vec;
+    use crate::drivers::block::RamDisk;
+
+    let image: Vec<u8> = (0..4096 + 1024).map(|i| (i / 1024) as u8).collect();
+    let disk = Arc::new(RamDisk::new(image));
+    let cache = BlockCache::new(disk.clone(), 1024);
+    assert!(cache.read(1).unwrap().iter().all(|&byte| byte == 1));
+    assert!(cache.read(3).unwrap().iter().all(|&byte| byte == 3));
+    // served from the cache, not the changed disk
+    disk.write_sectors(2, &[9; 1024]).unwrap();
+    assert!(cache.read(1).unwrap().iter().all(|&byte| byte == 1));
+    // a new cache of the device reads it again
+    let cache = BlockCache::new(disk, 1024);
+    assert!(cache.read(1).unwrap().iter().all(|&byte| byte == 9));
+    // the last block is alone in its page
+    assert!(cache.read(4).unwrap().iter().all(|&byte| byte == 4));
+    assert_eq!(cache.read(5).err(), Some(FsError::Io));
+}
@@ -0,0 +1,221 @@
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use crate::fs::{FileSystem, FsError, Inode, InodeKind};
+use crate::{print, rand, serial_print};
+
+/// A character device: a byte stream without a notion of offset or size.
+///
+/// Drivers implement this trait and register the device with `DevFs::register`
+/// to make it reachable under `/dev`.
+pub trait CharDevice: Send + Sync {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, FsError>;
+    fn write(&self, buf: &[u8]) -> Result<usize, FsError>;
+}
+
+/// `/dev/null`: reads return end of file, writes are discarded.
+pub struct Null;
+
+impl CharDevice for Null {
+    fn read(&self, _buf: &mut [u8]) -> Result<usize, FsError> {
+        Ok(0)
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, FsError> {
+        Ok(buf.len())
+    }
+}
+
+/// `/dev/zero`: reads return zero bytes, writes are discarded.
+pub struct Zero;
+
+impl CharDevice for Zero {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, FsError> {
+        buf.fill(0);
+        Ok(buf.len())
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, FsError> {
+        Ok(buf.len())
+    }
+}
+
+/// `/dev/random`: reads return bytes from the kernel CSPRNG, writes are mixed into
+/// its entropy pool.
+pub struct Random;
+
+impl CharDevice for Random {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, FsError> {
+        rand::fill(buf);
+        Ok(buf.len())
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, FsError> {
+        for chunk in buf.chunks(8) {
+            let mut word = [0u8; 8];
+            word[..chunk.len()].copy_from_slice(chunk);
+            rand::add_entropy(u64::from_le_bytes(word));
+        }
+        Ok(buf.len())
+    }
+}
+
+/// `/dev/console`: writes go to the VGA text buffer, reads wait for a line
+/// typed on the keyboard, see `console::read_input`.
+pub struct Console;
+
+impl CharDevice for Console {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, FsError> {
+        Ok(crate::console::read_input(buf))
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, FsError> {
+        write_lossy(buf, |s| { print!("{}", s); });
+        Ok(buf.len())
+    }
+}
+
+/// `/dev/ttyS0`: writes go to the first serial port.
+pub struct Serial;
+
+impl CharDevice for Serial {
+    fn read(&self, _buf: &mut [u8]) -> Result<usize, FsError> {
+        Ok(0)
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, FsError> {
+        write_lossy(buf, |s| { serial_print!("{}", s); });
+        Ok(buf.len())
+    }
+}
+
+/// Passes the valid UTF-8 parts of `buf` to `write`, replacing invalid bytes with `?`.
+fn write_lossy(mut buf: &[u8], mut write: impl FnMut(&str)) {
+    while !buf.is_empty() {
+        match core::str::from_utf8(buf) {
+            Ok(s) => {
+                write(s);
+                return;
+            }
+            Err(e) => {
+                let (valid, rest) = buf.split_at(e.valid_up_to());
+                write(core::str::from_utf8(valid).unwrap());
+                write("?");
+                buf = &rest[e.error_len().unwrap_or(rest.len())..];
+            }
+        }
+    }
+}
+
+/// Adapts a `CharDevice` to the VFS `Inode` interface, ignoring offsets.
+struct DeviceInode(Arc<dyn CharDevice>);
+
+impl Inode for DeviceInode {
+    fn kind(&self) -> InodeKind {
+        InodeKind::CharDevice
+    }
+
+    fn read_at(&self, _offset: usize, buf: &mut [u8]) -> Result<usize, FsError> {
+        self.0.read(buf)
+    }
+
+    fn write_at(&self, _offset: usize, buf: &[u8]) -> Result<usize, FsError> {
+        self.0.write(buf)
+    }
+}
+
+type DeviceTable = Arc<Mutex<BTreeMap<String, Arc<dyn CharDevice>>>>;
+
+/// The flat root directory of a `DevFs`.
+struct DevRoot(DeviceTable);
+
+impl Inode for DevRoot {
+    fn kind(&self) -> InodeKind {
+        InodeKind::Directory
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        let device = self.0.lock().get(name).cloned().ok_or(FsError::NotFound)?;
+        Ok(Arc::new(DeviceInode(device)))
+    }
+
+    fn read_dir(&self) -> Result<Vec<String>, FsError> {
+        Ok(self.0.lock().keys().cloned().collect())
+    }
+}
+
+lazy_static! {
+    /// The device filesystem mounted at `/dev`.
+    pub static ref DEVFS: DevFs = DevFs::with_default_devices();
+}
+
+/// Registers `device` as `/dev/<name>`.
+pub fn register(name: &str, device: Arc<dyn CharDevice>) -> Result<(), FsError> {
+    DEVFS.register(name, device)
+}
+
+/// A synthetic filesystem exposing registered character devices.
+///
+/// Clones share the same device table.
+#[derive(Clone)]
+pub struct DevFs {
+    devices: DeviceTable,
+}
+
+impl DevFs {
+    /// Creates an empty device filesystem.
+    pub fn new() -> Self {
+        DevFs { devices: Arc::new(Mutex::new(BTreeMap::new())) }
+    }
+
+    /// Creates a device filesystem containing the standard devices.
+    pub fn with_default_devices() -> Self {
+        let devfs = DevFs::new();
+        devfs.register("null", Arc::new(Null)).unwrap();
+        devfs.register("zero", Arc::new(Zero)).unwrap();
+        devfs.register("random", Arc::new(Random)).unwrap();
+        devfs.register("console", Arc::new(Console)).unwrap();
+        devfs.register("ttyS0", Arc::new(Serial)).unwrap();
+        devfs
+    }
+
+    /// Makes `device` available as `name` in this filesystem.
+    pub fn register(&self, name: &str, device: Arc<dyn CharDevice>) -> Result<(), FsError> {
+        let mut devices = self.devices.lock();
+        if devices.contains_key(name) {
+            return Err(FsError::AlreadyExists);
+        }
+        devices.insert(String::from(name), device);
+        Ok(())
+    }
+}
+
+impl FileSystem for DevFs {
+    fn name(&self) -> &str {
+        "devfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(DevRoot(self.devices.clone()))
+    }
+}
+
+#[test_case]
+fn test_dev_zero_and_null() {
+    let devfs = DevFs::with_default_devices();
+    let root = devfs.root();
+
+    let mut buf = [0xffu8; 16];
+    let zero = root.lookup("zero").unwrap();
+    assert_eq!(zero.read_at(0, &mut buf), Ok(16));
+    assert!(buf.iter().all(|&b| b == 0));
+
+    let null = root.lookup("null").unwrap();
+    assert_eq!(null.read_at(0, &mut buf), Ok(0));
+    assert_eq!(null.write_at(0, b"discarded"), Ok(9));
+
+    assert_eq!(root.lookup("missing").err(), Some(FsError::NotFound));
+}
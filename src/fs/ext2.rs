@@ -0,0 +1,386 @@
+//! Read-only ext2, as made by `mke2fs -t ext2` on Linux.
+//!
+//! The superblock is 1024 bytes into the device. It is followed by the block
+//! group descriptors, which say where the inode table of every group is. An
+//! inode lists the first 12 blocks of its data directly, then a block of
+//! block numbers, a block of those and a block of blocks of those. A
+//! directory's data is a list of entries mapping names to inode numbers.
+//! Every block is read through a `BlockCache`, and files are mapped from pages
+//! of their own in the page cache.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use spin::Mutex;
+use crate::drivers::block::{BlockDevice, SECTOR_SIZE};
+use crate::fs::block_cache::BlockCache;
+use crate::fs::page_cache::{self, Page, PAGE_SIZE};
+use crate::fs::{FileSystem, FsError, Inode, InodeKind};
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const MAGIC: u16 = 0xef53;
+const ROOT_INODE: u32 = 2;
+/// Size of the inodes of revision 0 filesystems.
+const OLD_INODE_SIZE: usize = 128;
+const GROUP_DESCRIPTOR_SIZE: usize = 32;
+const DIRECT_BLOCKS: u64 = 12;
+
+// incompatible features this driver can read
+const INCOMPAT_FILETYPE: u32 = 0x0002;
+const INCOMPAT_FLEX_BG: u32 = 0x0200;
+
+// file types in the inode mode
+const TYPE_MASK: u16 = 0xf000;
+const TYPE_DIRECTORY: u16 = 0x4000;
+const TYPE_CHAR_DEVICE: u16 = 0x2000;
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+/// The parts of an on-disk inode the driver uses.
+#[derive(Debug, Clone, Copy)]
+struct RawInode {
+    mode: u16,
+    size: u64,
+    blocks: [u32; 15],
+}
+
+struct Volume {
+    cache: BlockCache,
+    inode_count: u32,
+    inodes_per_group: u32,
+    inode_size: usize,
+    /// The first block of the inode table of every group.
+    inode_tables: Vec<u32>,
+    /// The cached pages of the files that were mapped, by inode number. Kept
+    /// as long as the filesystem, which is read-only.
+    files: Mutex<BTreeMap<u32, Arc<page_cache::Object>>>,
+}
+
+impl Volume {
+    fn block_size(&self) -> usize {
+        self.cache.block_size()
+    }
+
+    fn inode(&self, number: u32) -> Result<RawInode, FsError> {
+        if number == 0 || number > self.inode_count {
+            return Err(FsError::Corrupted);
+        }
+        let group = ((number - 1) / self.inodes_per_group) as usize;
+        let offset = ((number - 1) % self.inodes_per_group) as usize * self.inode_size;
+        let table = *self.inode_tables.get(group).ok_or(FsError::Corrupted)?;
+        let block = self.cache.read(u64::from(table) + (offset / self.block_size()) as u64)?;
+        let data = &block[offset % self.block_size()..];
+        let mode = read_u16(data, 0);
+        let mut size = u64::from(read_u32(data, 4));
+        // the upper half of the size of regular files, on revision 1
+        if mode & TYPE_MASK != TYPE_DIRECTORY {
+            size |= u64::from(read_u32(data, 108)) << 32;
+        }
+        let mut blocks = [0; 15];
+        for (i, block) in blocks.iter_mut().enumerate() {
+            *block = read_u32(data, 40 + i * 4);
+        }
+        Ok(RawInode { mode, size, blocks })
+    }
+
+    /// The device block holding block `index` of the data of `inode`, zero
+    /// for a hole.
+    fn data_block(&self, inode: &RawInode, index: u64) -> Result<u32, FsError> {
+        if index < DIRECT_BLOCKS {
+            return Ok(inode.blocks[index as usize]);
+        }
+        let per_block = (self.block_size() / 4) as u64;
+        let mut index = index - DIRECT_BLOCKS;
+        let mut span = per_block;
+        // singly, doubly and triply indirect
+        for depth in 1..=3 {
+            if index < span {
+                let mut block = inode.blocks[DIRECT_BLOCKS as usize + depth - 1];
+                for level in (0..depth as u32).rev() {
+                    if block == 0 {
+                        return Ok(0);
+                    }
+                    let entry = (index / per_block.pow(level) % per_block) as usize;
+                    block = read_u32(&self.cache.read(u64::from(block))?, entry * 4);
+                }
+                return Ok(block);
+            }
+            index -= span;
+            span *= per_block;
+        }
+        Err(FsError::Corrupted)
+    }
+
+    fn read(&self, inode: &RawInode, offset: usize, buf: &mut [u8]) -> Result<usize, FsError> {
+        let size = inode.size as usize;
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = buf.len().min(size - offset);
+        let block_size = self.block_size();
+        let mut done = 0;
+        while done < len {
+            let position = offset + done;
+            let within = position % block_size;
+            let n = (block_size - within).min(len - done);
+            match self.data_block(inode, (position / block_size) as u64)? {
+                0 => buf[done..done + n].iter_mut().for_each(|byte| *byte = 0),
+                block => buf[done..done + n].copy_from_slice(&self.cache.read(u64::from(block))?[within..within + n]),
+            }
+            done += n;
+        }
+        Ok(len)
+    }
+
+    /// The names and inode numbers in the directory `inode`.
+    fn entries(&self, inode: &RawInode) -> Result<Vec<(String, u32)>, FsError> {
+        let block_size = self.block_size();
+        let mut entries = Vec::new();
+        for index in 0..(inode.size as usize + block_size - 1) / block_size {
+            let block = match self.data_block(inode, index as u64)? {
+                0 => continue,
+                block => self.cache.read(u64::from(block))?,
+            };
+            let mut position = 0;
+            while position + 8 <= block_size {
+                let number = read_u32(&block, position);
+                let record_len = usize::from(read_u16(&block, position + 4));
+                let name_len = usize::from(block[position + 6]);
+                if record_len < 8 || position + record_len > block_size || 8 + name_len > record_len {
+                    return Err(FsError::Corrupted);
+                }
+                if number != 0 {
+                    let name = &block[position + 8..position + 8 + name_len];
+                    entries.push((String::from_utf8_lossy(name).into_owned(), number));
+                }
+                position += record_len;
+            }
+        }
+        Ok(entries)
+    }
+}
+
+/// A mounted ext2 filesystem.
+pub struct Ext2(Arc<Volume>);
+
+impl Ext2 {
+    /// Reads the superblock and the group descriptors of the filesystem on `device`.
+    pub fn new(device: Arc<dyn BlockDevice>) -> Result<Ext2, FsError> {
+        let mut superblock = [0u8; 1024];
+        device.read_sectors(SUPERBLOCK_OFFSET / SECTOR_SIZE as u64, &mut superblock).map_err(|_| FsError::Io)?;
+        if read_u16(&superblock, 56) != MAGIC {
+            return Err(FsError::Unsupported);
+        }
+        let incompat = read_u32(&superblock, 96);
+        if incompat & !(INCOMPAT_FILETYPE | INCOMPAT_FLEX_BG) != 0 {
+            return Err(FsError::Unsupported);
+        }
+        let log_block_size = read_u32(&superblock, 24);
+        if log_block_size > 6 {
+            return Err(FsError::Corrupted);
+        }
+        let block_size = 1024 << log_block_size;
+        if block_size > PAGE_SIZE {
+            return Err(FsError::Unsupported);
+        }
+        let inode_count = read_u32(&superblock, 0);
+        let block_count = read_u32(&superblock, 4);
+        let first_data_block = read_u32(&superblock, 20);
+        let blocks_per_group = read_u32(&superblock, 32);
+        let inodes_per_group = read_u32(&superblock, 40);
+        let inode_size = match read_u32(&superblock, 76) {
+            0 => OLD_INODE_SIZE,
+            _ => usize::from(read_u16(&superblock, 88)),
+        };
+        // a power of two, so no inode straddles two blocks
+        if blocks_per_group == 0 || inodes_per_group == 0 || !inode_size.is_power_of_two()
+            || inode_size < OLD_INODE_SIZE || inode_size > block_size
+        {
+            return Err(FsError::Corrupted);
+        }
+
+        let cache = BlockCache::new(device, block_size);
+        let groups = (u64::from(block_count.saturating_sub(first_data_block)) + u64::from(blocks_per_group) - 1)
+            / u64::from(blocks_per_group);
+        // the descriptors start in the block after the superblock
+        let table = u64::from(first_data_block) + 1;
+        let per_block = block_size / GROUP_DESCRIPTOR_SIZE;
+        let inode_tables = (0..groups as usize).map(|group| {
+            let block = cache.read(table + (group / per_block) as u64)?;
+            Ok(read_u32(&block, group % per_block * GROUP_DESCRIPTOR_SIZE + 8))
+        }).collect::<Result<Vec<u32>, FsError>>()?;
+
+        let files = Mutex::new(BTreeMap::new());
+        let volume = Volume { cache, inode_count, inodes_per_group, inode_size, inode_tables, files };
+        if volume.inode(ROOT_INODE)?.mode & TYPE_MASK != TYPE_DIRECTORY {
+            return Err(FsError::Corrupted);
+        }
+        Ok(Ext2(Arc::new(volume)))
+    }
+}
+
+impl FileSystem for Ext2 {
+    fn name(&self) -> &str {
+        "ext2"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        // checked to be a directory when mounting
+        let raw = self.0.inode(ROOT_INODE).expect("ext2 root inode unreadable");
+        Arc::new(Ext2Inode { volume: self.0.clone(), number: ROOT_INODE, raw })
+    }
+}
+
+struct Ext2Inode {
+    volume: Arc<Volume>,
+    number: u32,
+    raw: RawInode,
+}
+
+impl Inode for Ext2Inode {
+    fn kind(&self) -> InodeKind {
+        match self.raw.mode & TYPE_MASK {
+            TYPE_DIRECTORY => InodeKind::Directory,
+            TYPE_CHAR_DEVICE => InodeKind::CharDevice,
+            _ => InodeKind::File,
+        }
+    }
+
+    fn size(&self) -> usize {
+        self.raw.size as usize
+    }
+
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize, FsError> {
+        if self.kind() == InodeKind::Directory {
+            return Err(FsError::IsADirectory);
+        }
+        self.volume.read(&self.raw, offset, buf)
+    }
+
+    fn page(&self, index: u64) -> Result<Arc<Page>, FsError> {
+        if self.kind() != InodeKind::File {
+            return Err(FsError::Unsupported);
+        }
+        let offset = (index as usize).checked_mul(PAGE_SIZE).ok_or(FsError::InvalidPath)?;
+        let pages = self.volume.files.lock().entry(self.number)
+            .or_insert_with(|| Arc::new(page_cache::Object::new()))
+            .clone();
+        pages.page(index, |data| self.volume.read(&self.raw, offset, data).map(drop))
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        if self.kind() != InodeKind::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        let (_, number) = self.volume.entries(&self.raw)?
+            .into_iter()
+            .find(|(entry, _)| entry == name)
+            .ok_or(FsError::NotFound)?;
+        let raw = self.volume.inode(number)?;
+        Ok(Arc::new(Ext2Inode { volume: self.volume.clone(), number, raw }))
+    }
+
+    fn read_dir(&self) -> Result<Vec<String>, FsError> {
+        if self.kind() != InodeKind::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        Ok(self.volume.entries(&self.raw)?
+            .into_iter()
+            .map(|(name, _)| name)
+            .filter(|name| name != "." && name != "..")
+            .collect())
+    }
+}
+
+#[test_case]
+fn test_ext2() {
+    use alloc::vec;
+    use crate::drivers::block::RamDisk;
+
+    const BLOCK: usize = 1024;
+    let mut image = vec![0u8; 64 * BLOCK];
+    let put_u16 = |image: &mut Vec<u8>, offset: usize, value: u16| image[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+    let put_u32 = |image: &mut Vec<u8>, offset: usize, value: u32| image[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+
+    // superblock in block 1, 16 inodes in one group
+    let sb = BLOCK;
+    put_u32(&mut image, sb, 16);
+    put_u32(&mut image, sb + 4, 64);
+    put_u32(&mut image, sb + 20, 1);
+    put_u32(&mut image, sb + 32, 8192);
+    put_u32(&mut image, sb + 40, 16);
+    put_u16(&mut image, sb + 56, MAGIC);
+    put_u32(&mut image, sb + 76, 1);
+    put_u16(&mut image, sb + 88, 128);
+    put_u32(&mut image, sb + 96, INCOMPAT_FILETYPE);
+    // the group descriptor in block 2 puts the inode table in blocks 5 and 6
+    put_u32(&mut image, 2 * BLOCK + 8, 5);
+
+    // the root directory with its entries in block 7
+    let root = 5 * BLOCK + 128;
+    put_u16(&mut image, root, TYPE_DIRECTORY | 0o755);
+    put_u32(&mut image, root + 4, BLOCK as u32);
+    put_u32(&mut image, root + 40, 7);
+    let dir = 7 * BLOCK;
+    for &(offset, number, len, name) in &[(0, 2, 12, "."), (12, 2, 12, ".."), (24, 12, BLOCK - 24, "hello")] {
+        put_u32(&mut image, dir + offset, number);
+        put_u16(&mut image, dir + offset + 4, len as u16);
+        image[dir + offset + 6] = name.len() as u8;
+        image[dir + offset + 8..dir + offset + 8 + name.len()].copy_from_slice(name.as_bytes());
+    }
+
+    // inode 12: 12 direct blocks from block 8 with a hole at the second, then
+    // 100 bytes in block 21 through the indirect block 20
+    let file = 5 * BLOCK + 11 * 128;
+    put_u16(&mut image, file, 0x8000 | 0o644);
+    put_u32(&mut image, file + 4, (12 * BLOCK + 100) as u32);
+    for i in 0..12 {
+        if i != 1 {
+            put_u32(&mut image, file + 40 + i * 4, 8 + i as u32);
+            image[(8 + i) * BLOCK..(9 + i) * BLOCK].iter_mut().for_each(|byte| *byte = i as u8 + 1);
+        }
+    }
+    put_u32(&mut image, file + 40 + 12 * 4, 20);
+    put_u32(&mut image, 20 * BLOCK, 21);
+    image[21 * BLOCK..22 * BLOCK].iter_mut().for_each(|byte| *byte = 0xaa);
+
+    // an inode size that is not a power of two lets inodes straddle blocks
+    let mut corrupted = image.clone();
+    put_u16(&mut corrupted, sb + 88, 200);
+    assert_eq!(Ext2::new(Arc::new(RamDisk::new(corrupted))).err(), Some(FsError::Corrupted));
+
+    let fs = Ext2::new(Arc::new(RamDisk::new(image))).unwrap();
+    let root = fs.root();
+    assert_eq!(root.kind(), InodeKind::Directory);
+    assert_eq!(root.read_dir().unwrap(), vec![String::from("hello")]);
+    assert_eq!(root.lookup("missing").err(), Some(FsError::NotFound));
+
+    let hello = root.lookup("hello").unwrap();
+    assert_eq!((hello.kind(), hello.size()), (InodeKind::File, 12 * BLOCK + 100));
+    let mut buf = [0u8; 8];
+    assert_eq!(hello.read_at(BLOCK - 4, &mut buf), Ok(8));
+    assert_eq!(buf, [1, 1, 1, 1, 0, 0, 0, 0]);
+    assert_eq!(hello.read_at(11 * BLOCK + 4, &mut buf), Ok(8));
+    assert_eq!(buf, [12; 8]);
+    let mut tail = [0u8; 200];
+    assert_eq!(hello.read_at(12 * BLOCK, &mut tail), Ok(100));
+    assert!(tail[..100].iter().all(|&byte| byte == 0xaa));
+    assert_eq!(hello.lookup("x").err(), Some(FsError::NotADirectory));
+
+    // mapped pages hold whole pages of the file, zeros past its end
+    let page = hello.page(0).unwrap();
+    assert_eq!((page.data()[0], page.data()[BLOCK], page.data()[3 * BLOCK]), (1, 0, 4));
+    let page = hello.page(3).unwrap();
+    assert_eq!((page.data()[99], page.data()[100]), (0xaa, 0));
+    assert_eq!(root.page(0).err(), Some(FsError::Unsupported));
+
+    assert_eq!(Ext2::new(Arc::new(RamDisk::new(vec![0; 4096]))).err(), Some(FsError::Unsupported));
+}
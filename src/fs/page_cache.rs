@@ -0,0 +1,182 @@
+//! The page cache: file and device data kept in page frames, so it can be
+//! mapped into processes as well as read by the kernel.
+//!
+//! Cached data belongs to an `Object`, a file or a device, and is found by
+//! the object and the index of the page in it. All objects share one pool of
+//! `MAX_PAGES` pages, evicting the least recently used one when it is full,
+//! when memory is under pressure, or when `shrink` is asked for memory. Evicting a page only drops the cache's
+//! reference to it: the frame lives on while someone still reads it through a
+//! `Page` or has it mapped, and is freed with the last of them.
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::structures::paging::PhysFrame;
+use crate::fs::FsError;
+use crate::memory::{self, Pressure};
+
+pub const PAGE_SIZE: usize = 4096;
+
+/// Most pages cached at once, 4 MiB.
+const MAX_PAGES: usize = 1024;
+
+/// A page of cached data.
+pub struct Page {
+    frame: PhysFrame,
+}
+
+impl Page {
+    /// The frame holding the data, to map it.
+    pub fn frame(&self) -> PhysFrame {
+        self.frame
+    }
+
+    pub fn data(&self) -> &[u8] {
+        let virt = memory::physical_memory_offset() + self.frame.start_address().as_u64();
+        unsafe { core::slice::from_raw_parts(virt.as_ptr(), PAGE_SIZE) }
+    }
+
+    /// Only while the page is filled, before anyone else sees it.
+    fn data_mut(&mut self) -> &mut [u8] {
+        let virt = memory::physical_memory_offset() + self.frame.start_address().as_u64();
+        unsafe { core::slice::from_raw_parts_mut(virt.as_mut_ptr(), PAGE_SIZE) }
+    }
+}
+
+impl Drop for Page {
+    fn drop(&mut self) {
+        // the mappings of the frame hold references of their own
+        if memory::unshare_frame(self.frame) {
+            unsafe { memory::free_frames(Some(self.frame)) };
+        }
+    }
+}
+
+struct Entry {
+    page: Arc<Page>,
+    /// When the page was last used, in `Cache::clock` ticks.
+    used: u64,
+}
+
+struct Cache {
+    pages: BTreeMap<(u64, u64), Entry>,
+    clock: u64,
+}
+
+impl Cache {
+    /// Removes the least recently used page nobody else uses right now, and
+    /// returns it for the caller to drop outside of the lock.
+    fn evict(&mut self) -> Option<Arc<Page>> {
+        let key = *self.pages.iter()
+            .filter(|(_, entry)| Arc::strong_count(&entry.page) == 1 && !memory::is_frame_shared(entry.page.frame))
+            .min_by_key(|(_, entry)| entry.used)?
+            .0;
+        self.pages.remove(&key).map(|entry| entry.page)
+    }
+}
+
+static CACHE: Mutex<Cache> = Mutex::new(Cache { pages: BTreeMap::new(), clock: 0 });
+
+/// The pages of one file or device in the cache. They are dropped from the
+/// cache with it.
+pub struct Object {
+    id: u64,
+}
+
+impl Object {
+    pub fn new() -> Object {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        Object { id: NEXT.fetch_add(1, Ordering::Relaxed) }
+    }
+
+    /// The page at `index`, which `fill` reads into a zeroed page if it is
+    /// not cached.
+    pub fn page(&self, index: u64, fill: impl FnOnce(&mut [u8]) -> Result<(), FsError>)
+                -> Result<Arc<Page>, FsError> {
+        let key = (self.id, index);
+        {
+            let mut cache = CACHE.lock();
+            cache.clock += 1;
+            let clock = cache.clock;
+            if let Some(entry) = cache.pages.get_mut(&key) {
+                entry.used = clock;
+                return Ok(entry.page.clone());
+            }
+        }
+        // filled without the lock, the device may block
+        let frame = memory::allocate_frames(1).map_err(|_| FsError::OutOfMemory)?[0];
+        let mut page = Page { frame };
+        fill(page.data_mut())?;
+        let page = Arc::new(page);
+        // don't grow while memory is short
+        let full = memory::pressure() != Pressure::Normal;
+        let mut cache = CACHE.lock();
+        let clock = cache.clock;
+        // someone else may have read it meanwhile
+        if let Some(entry) = cache.pages.get(&key) {
+            return Ok(entry.page.clone());
+        }
+        let evicted = if full || cache.pages.len() >= MAX_PAGES { cache.evict() } else { None };
+        cache.pages.insert(key, Entry { page: page.clone(), used: clock });
+        drop(cache);
+        drop(evicted);
+        Ok(page)
+    }
+}
+
+impl Drop for Object {
+    fn drop(&mut self) {
+        let id = self.id;
+        let pages: Vec<Arc<Page>> = {
+            let mut cache = CACHE.lock();
+            let keys: Vec<(u64, u64)> = cache.pages.range((id, 0)..=(id, u64::MAX)).map(|(&key, _)| key).collect();
+            keys.iter().filter_map(|key| cache.pages.remove(key)).map(|entry| entry.page).collect()
+        };
+        drop(pages);
+    }
+}
+
+/// Evicts up to `count` pages nobody uses, least recently used first, freeing
+/// their frames. Returns how many were evicted.
+pub fn shrink(count: usize) -> usize {
+    let mut evicted = Vec::new();
+    {
+        let mut cache = CACHE.lock();
+        while evicted.len() < count {
+            match cache.evict() {
+                Some(page) => evicted.push(page),
+                None => break,
+            }
+        }
+    }
+    evicted.len()
+}
+
+/// Number of pages in the cache.
+pub fn cached_pages() -> usize {
+    CACHE.lock().pages.len()
+}
+
+#[test_case]
+fn test_page_cache() {
+    let object = Object::new();
+    let page = object.page(3, |data| {
+        data[0] = 3;
+        Ok(())
+    }).unwrap();
+    // cached, so not filled again
+    let again = object.page(3, |_| panic!("page read twice")).unwrap();
+    assert_eq!(again.frame(), page.frame());
+    assert_eq!(object.page(4, |_| Err(FsError::Io)).err(), Some(FsError::Io));
+
+    // in use, so it stays
+    drop(again);
+    while shrink(1) == 1 {}
+    assert_eq!(object.page(3, |_| panic!("page evicted while in use")).unwrap().data()[0], 3);
+    drop(page);
+    assert_eq!(shrink(1), 1);
+    assert_eq!(object.page(3, |_| Ok(())).unwrap().data()[0], 0);
+    drop(object);
+}
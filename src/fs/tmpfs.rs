@@ -0,0 +1,232 @@
+//! An in-memory filesystem, mounted at `/tmp`.
+//!
+//! Files keep their data in pages from a slab cache as they are written, so a
+//! file with holes only takes memory for the parts that were written, and
+//! none of it comes from the heap.
+//! Everything is lost when the filesystem is dropped.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+use crate::fs::{FileSystem, FsError, Inode, InodeKind};
+use crate::memory::slab::{Cache, SlabBox};
+
+/// Size of the pages file data is kept in.
+const PAGE_SIZE: usize = 4096;
+
+type Page = SlabBox<[u8; PAGE_SIZE]>;
+
+static PAGES: Cache<[u8; PAGE_SIZE]> = Cache::new("tmpfs pages", || [0; PAGE_SIZE]);
+
+/// A filesystem whose files live in memory.
+pub struct TmpFs {
+    root: Arc<Directory>,
+}
+
+impl TmpFs {
+    pub fn new() -> TmpFs {
+        TmpFs { root: Arc::new(Directory::default()) }
+    }
+}
+
+impl FileSystem for TmpFs {
+    fn name(&self) -> &str {
+        "tmpfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+#[derive(Default)]
+struct Directory {
+    children: Mutex<BTreeMap<String, Arc<dyn Inode>>>,
+}
+
+impl Inode for Directory {
+    fn kind(&self) -> InodeKind {
+        InodeKind::Directory
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        self.children.lock().get(name).cloned().ok_or(FsError::NotFound)
+    }
+
+    fn read_dir(&self) -> Result<Vec<String>, FsError> {
+        Ok(self.children.lock().keys().cloned().collect())
+    }
+
+    fn create(&self, name: &str, kind: InodeKind) -> Result<Arc<dyn Inode>, FsError> {
+        let inode: Arc<dyn Inode> = match kind {
+            InodeKind::File => Arc::new(File::default()),
+            InodeKind::Directory => Arc::new(Directory::default()),
+            InodeKind::CharDevice => return Err(FsError::Unsupported),
+        };
+        self.link(name, inode.clone())?;
+        Ok(inode)
+    }
+
+    fn link(&self, name: &str, inode: Arc<dyn Inode>) -> Result<(), FsError> {
+        if name.is_empty() || name.contains('/') {
+            return Err(FsError::InvalidPath);
+        }
+        let mut children = self.children.lock();
+        if children.contains_key(name) {
+            return Err(FsError::AlreadyExists);
+        }
+        children.insert(String::from(name), inode);
+        Ok(())
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), FsError> {
+        let mut children = self.children.lock();
+        let child = children.get(name).ok_or(FsError::NotFound)?;
+        if child.kind() == InodeKind::Directory && !child.read_dir()?.is_empty() {
+            return Err(FsError::NotEmpty);
+        }
+        children.remove(name);
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct FileData {
+    /// `None` for pages that were never written.
+    pages: Vec<Option<Page>>,
+    len: usize,
+}
+
+#[derive(Default)]
+struct File {
+    data: Mutex<FileData>,
+}
+
+impl Inode for File {
+    fn kind(&self) -> InodeKind {
+        InodeKind::File
+    }
+
+    fn size(&self) -> usize {
+        self.data.lock().len
+    }
+
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize, FsError> {
+        let data = self.data.lock();
+        if offset >= data.len {
+            return Ok(0);
+        }
+        let len = buf.len().min(data.len - offset);
+        let mut done = 0;
+        while done < len {
+            let position = offset + done;
+            let within = position % PAGE_SIZE;
+            let n = (PAGE_SIZE - within).min(len - done);
+            match &data.pages[position / PAGE_SIZE] {
+                Some(page) => buf[done..done + n].copy_from_slice(&page[within..within + n]),
+                None => buf[done..done + n].iter_mut().for_each(|byte| *byte = 0),
+            }
+            done += n;
+        }
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize, FsError> {
+        let mut data = self.data.lock();
+        let end = offset.checked_add(buf.len()).ok_or(FsError::InvalidPath)?;
+        if end > data.len {
+            data.pages.resize_with((end + PAGE_SIZE - 1) / PAGE_SIZE, || None);
+            data.len = end;
+        }
+        let mut done = 0;
+        while done < buf.len() {
+            let position = offset + done;
+            let within = position % PAGE_SIZE;
+            let n = (PAGE_SIZE - within).min(buf.len() - done);
+            let index = position / PAGE_SIZE;
+            if data.pages[index].is_none() {
+                let mut page = match PAGES.alloc() {
+                    Ok(page) => page,
+                    Err(_) if done > 0 => return Ok(done),
+                    Err(_) => return Err(FsError::OutOfMemory),
+                };
+                // a reused page still holds what a removed file wrote
+                page.iter_mut().for_each(|byte| *byte = 0);
+                data.pages[index] = Some(page);
+            }
+            if let Some(page) = &mut data.pages[index] {
+                page[within..within + n].copy_from_slice(&buf[done..done + n]);
+            }
+            done += n;
+        }
+        Ok(buf.len())
+    }
+
+    fn truncate(&self, len: usize) -> Result<(), FsError> {
+        let mut data = self.data.lock();
+        data.pages.resize_with((len + PAGE_SIZE - 1) / PAGE_SIZE, || None);
+        // what is cut off of the last page reads as zeros if the file grows again
+        if len % PAGE_SIZE != 0 {
+            if let Some(Some(page)) = data.pages.last_mut() {
+                page[len % PAGE_SIZE..].iter_mut().for_each(|byte| *byte = 0);
+            }
+        }
+        data.len = len;
+        Ok(())
+    }
+}
+
+#[test_case]
+fn test_tmpfs() {
+    let fs = TmpFs::new();
+    let root = fs.root();
+    let dir = root.create("dir", InodeKind::Directory).unwrap();
+    let file = dir.create("file", InodeKind::File).unwrap();
+    assert_eq!(root.create("dir", InodeKind::File).err(), Some(FsError::AlreadyExists));
+
+    // a write past the end leaves a hole
+    assert_eq!(file.write_at(PAGE_SIZE * 2 - 2, b"abcd"), Ok(4));
+    assert_eq!(file.size(), PAGE_SIZE * 2 + 2);
+    let mut buf = [0xff; 6];
+    assert_eq!(file.read_at(PAGE_SIZE * 2 - 4, &mut buf), Ok(6));
+    assert_eq!(&buf, b"\0\0abcd");
+    assert_eq!(file.read_at(0, &mut buf), Ok(6));
+    assert_eq!(buf, [0; 6]);
+
+    file.truncate(PAGE_SIZE * 2 - 1).unwrap();
+    file.truncate(PAGE_SIZE * 2 + 1).unwrap();
+    assert_eq!(file.read_at(PAGE_SIZE * 2 - 2, &mut buf), Ok(3));
+    assert_eq!(&buf[..3], b"a\0\0");
+
+    assert_eq!(root.unlink("dir").err(), Some(FsError::NotEmpty));
+    root.link("moved", file.clone()).unwrap();
+    dir.unlink("file").unwrap();
+    assert_eq!(root.read_dir().unwrap(), ["dir", "moved"]);
+    root.unlink("dir").unwrap();
+    assert_eq!(root.lookup("dir").err(), Some(FsError::NotFound));
+    assert_eq!(root.lookup("moved").unwrap().size(), PAGE_SIZE * 2 + 1);
+}
+
+#[test_case]
+fn test_rename() {
+    use crate::fs;
+
+    fs::mount("/test-tmpfs", Arc::new(TmpFs::new())).unwrap();
+    fs::create("/test-tmpfs/a", InodeKind::Directory).unwrap();
+    fs::write_file("/test-tmpfs/a/one", b"1").unwrap();
+    fs::write_file("/test-tmpfs/two", b"2").unwrap();
+
+    fs::rename("/test-tmpfs/a/one", "/test-tmpfs/two").unwrap();
+    assert_eq!(fs::read_file("/test-tmpfs/two").unwrap(), b"1");
+    assert_eq!(fs::lookup("/test-tmpfs/a/one").err(), Some(FsError::NotFound));
+    assert_eq!(fs::rename("/test-tmpfs/a", "/test-tmpfs/a/b").err(), Some(FsError::InvalidPath));
+    fs::rename("/test-tmpfs/a", "/test-tmpfs/b").unwrap();
+    assert_eq!(fs::rename("/test-tmpfs/two", "/dev/two").err(), Some(FsError::Unsupported));
+
+    assert_eq!(fs::remove("/test-tmpfs").err(), Some(FsError::InvalidPath));
+    fs::remove("/test-tmpfs/b").unwrap();
+    fs::remove("/test-tmpfs/two").unwrap();
+    assert!(fs::lookup("/test-tmpfs").unwrap().read_dir().unwrap().is_empty());
+}
@@ -0,0 +1,91 @@
+//! Waiting on a 32-bit word in memory, for locks and condition variables built
+//! on shared memory.
+//!
+//! Waiters are keyed by the physical address of the word, so threads using
+//! different mappings of the same `shm` object meet in the same queue.
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicU32, Ordering};
+use spin::Mutex;
+use x86_64::VirtAddr;
+use crate::memory;
+use crate::sched::WaitQueue;
+
+static QUEUES: Mutex<BTreeMap<u64, Arc<WaitQueue>>> = Mutex::new(BTreeMap::new());
+
+fn key(addr: VirtAddr) -> Result<u64, &'static str> {
+    let mapping = memory::mapping_of(addr).ok_or("futex word is not mapped")?;
+    Ok(mapping.phys.as_u64() + (addr.as_u64() - mapping.virt.as_u64()))
+}
+
+/// Blocks while `word` holds `expected`. Returns right away if it doesn't.
+///
+/// Whoever changes the word calls `wake` afterwards.
+pub fn wait(word: &AtomicU32, expected: u32) -> Result<(), &'static str> {
+    wait_at(VirtAddr::from_ptr(word), || word.load(Ordering::SeqCst) != expected)
+}
+
+/// Blocks until `changed`, which reads the word at `addr`, returns true. For
+/// words the kernel can't hold a reference to, like those of processes.
+///
+/// `changed` runs with interrupts disabled, so it must not block.
+pub fn wait_at(addr: VirtAddr, changed: impl FnMut() -> bool) -> Result<(), &'static str> {
+    let key = key(addr)?;
+    let queue = QUEUES.lock().entry(key).or_insert_with(|| Arc::new(WaitQueue::new())).clone();
+    queue.wait_until(changed);
+
+    let mut queues = QUEUES.lock();
+    // nobody else is waiting on the word when only the map and we hold it
+    if Arc::strong_count(&queue) == 2 {
+        queues.remove(&key);
+    }
+    Ok(())
+}
+
+/// Wakes up to `count` threads waiting on `word`, returning how many there were.
+pub fn wake(word: &AtomicU32, count: usize) -> Result<usize, &'static str> {
+    wake_at(VirtAddr::from_ptr(word), count)
+}
+
+/// Like `wake`, for the word at `addr`.
+pub fn wake_at(addr: VirtAddr, count: usize) -> Result<usize, &'static str> {
+    let key = key(addr)?;
+    let queue = match QUEUES.lock().get(&key) {
+        Some(queue) => queue.clone(),
+        None => return Ok(0),
+    };
+    if count == usize::MAX {
+        return Ok(queue.notify_all());
+    }
+    Ok((0..count).take_while(|_| queue.notify_one()).count())
+}
+
+#[test_case]
+fn test_wait_and_wake_through_shared_memory() {
+    use core::sync::atomic::AtomicBool;
+    use crate::{sched, shm};
+
+    static FINISHED: WaitQueue = WaitQueue::new();
+    static DONE: AtomicBool = AtomicBool::new(false);
+
+    shm::create("futex-test", 4).unwrap();
+    let first = shm::map("futex-test", shm::Protection::ReadWrite).unwrap();
+    let second = shm::map("futex-test", shm::Protection::ReadWrite).unwrap();
+    shm::unlink("futex-test").unwrap();
+    let word = unsafe { &*(first.as_ptr() as *const AtomicU32) };
+    assert_eq!(wait(word, 1), Ok(()));
+
+    let other = second.as_ptr() as u64;
+    sched::spawn("futex-test", move || {
+        let word = unsafe { &*(other as *const AtomicU32) };
+        word.store(1, Ordering::SeqCst);
+        wake(word, 1).unwrap();
+        DONE.store(true, Ordering::SeqCst);
+        FINISHED.notify_all();
+    }).unwrap();
+    wait(word, 0).unwrap();
+    assert_eq!(word.load(Ordering::SeqCst), 1);
+    FINISHED.wait_until(|| DONE.load(Ordering::SeqCst));
+    assert_eq!(wake(word, 1), Ok(0));
+}
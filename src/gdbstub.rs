@@ -0,0 +1,528 @@
+//! GDB remote serial protocol stub on the second serial port (COM2).
+//!
+//! Built with the `gdbstub` feature. Start QEMU with a second serial port, e.g.
+//! `-serial stdio -serial tcp::1234,server,nowait`, then attach with
+//! `gdb target/x86_64-MarOS/debug/MarOS -ex "target remote :1234"`.
+//! The kernel stops in the debugger once memory management is set up.
+//!
+//! Supported: reading and writing registers and memory, software breakpoints
+//! (`int3`) and single-stepping (the trap flag). The stub polls COM2 and does
+//! not use the heap, so it also works when the fault happened inside the
+//! allocator or with interrupts disabled.
+
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicBool, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::registers::control::{Cr0, Cr0Flags};
+use x86_64::structures::idt::InterruptDescriptorTable;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+use crate::debug::{self, Backtrace};
+use crate::drivers::uart::{Com, Config, Uart16550};
+use crate::{initcall, memory, println};
+
+const BREAKPOINT_VECTOR: u64 = 3;
+const TRAP_FLAG: u64 = 1 << 8;
+const RESUME_FLAG: u64 = 1 << 16;
+const MAX_PACKET: usize = 1024;
+const MAX_BREAKPOINTS: usize = 32;
+const INT3: u8 = 0xcc;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// Set while gdb waits for the kernel to stop after a `c` or `s` command.
+static RESUMED: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref COM2: &'static Mutex<Uart16550> =
+        crate::serial::open(Com::Com2, Config::default()).expect("COM2 initialization failed");
+}
+
+#[derive(Clone, Copy)]
+struct Breakpoint {
+    addr: u64,
+    original: u8,
+}
+
+static BREAKPOINTS: Mutex<[Option<Breakpoint>; MAX_BREAKPOINTS]> = Mutex::new([None; MAX_BREAKPOINTS]);
+
+/// Registers saved by the trap entry stub, in the order they are pushed.
+#[repr(C)]
+#[derive(Debug)]
+pub struct TrapFrame {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub vector: u64,
+    // pushed by the CPU
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+global_asm!(r#"
+.global gdbstub_breakpoint_entry
+gdbstub_breakpoint_entry:
+    push 3
+    jmp gdbstub_common_entry
+
+.global gdbstub_debug_entry
+gdbstub_debug_entry:
+    push 1
+    jmp gdbstub_common_entry
+
+gdbstub_common_entry:
+    push r15
+    push r14
+    push r13
+    push r12
+    push r11
+    push r10
+    push r9
+    push r8
+    push rbp
+    push rdi
+    push rsi
+    push rdx
+    push rcx
+    push rbx
+    push rax
+    mov rdi, rsp
+    cld
+    // the CPU aligned the stack before pushing its 5 words, so after 16 more
+    // it is 8 bytes off the 16 byte alignment the call needs
+    sub rsp, 8
+    call gdbstub_trap
+    add rsp, 8
+    pop rax
+    pop rbx
+    pop rcx
+    pop rdx
+    pop rsi
+    pop rdi
+    pop rbp
+    pop r8
+    pop r9
+    pop r10
+    pop r11
+    pop r12
+    pop r13
+    pop r14
+    pop r15
+    // drop the vector number
+    add rsp, 8
+    iretq
+"#);
+
+extern "C" {
+    fn gdbstub_breakpoint_entry();
+    fn gdbstub_debug_entry();
+}
+
+/// Routes the breakpoint and debug exceptions through the stub.
+///
+/// Until `init` is called both are handled as without the stub.
+pub fn set_handlers(idt: &mut InterruptDescriptorTable) {
+    let breakpoint_entry: unsafe extern "C" fn() = gdbstub_breakpoint_entry;
+    let debug_entry: unsafe extern "C" fn() = gdbstub_debug_entry;
+    unsafe {
+        idt.breakpoint.set_handler_addr(VirtAddr::new(breakpoint_entry as usize as u64));
+        idt.debug.set_handler_addr(VirtAddr::new(debug_entry as usize as u64));
+    }
+}
+
+/// Enables the stub and stops in the debugger, waiting for gdb to connect.
+///
+/// Memory access checks walk the page tables, so `memory::init` must have been called.
+pub fn init() {
+    ENABLED.store(true, Ordering::SeqCst);
+    println!("gdbstub: waiting for gdb on COM2");
+    x86_64::instructions::interrupts::int3();
+}
+initcall!(Late, "gdbstub", || {
+    init();
+    Ok(())
+});
+
+#[no_mangle]
+extern "C" fn gdbstub_trap(frame: &mut TrapFrame) {
+    crate::arch::x86_64::protection::forbid_user_access();
+    if !ENABLED.load(Ordering::SeqCst) {
+        if frame.vector == BREAKPOINT_VECTOR {
+            println!("EXCEPTION: BREAKPOINT\n{:#x?}", frame);
+        } else {
+            let backtrace = Backtrace::from_frame(frame.rip, frame.rbp);
+            if debug::handle_debug_exception(frame.rip, &backtrace) {
+                frame.rflags |= RESUME_FLAG;
+            }
+        }
+        frame.rflags &= !TRAP_FLAG;
+        return;
+    }
+
+    // report a hit of one of our breakpoints at the breakpoint address,
+    // as promised to gdb with `swbreak+`
+    let mut stop_reply: &[u8] = b"S05";
+    if frame.vector == BREAKPOINT_VECTOR && find_breakpoint(frame.rip.wrapping_sub(1)).is_some() {
+        frame.rip -= 1;
+        stop_reply = b"T05swbreak:;";
+    }
+    frame.rflags &= !TRAP_FLAG;
+
+    // the trap may have interrupted a holder of the lock
+    if COM2.is_locked() {
+        unsafe { COM2.force_unlock() };
+    }
+    let mut port = COM2.lock();
+    // before the first stop gdb is not attached yet: it asks with `?` once it is
+    if RESUMED.swap(false, Ordering::SeqCst) {
+        send(&mut port, stop_reply);
+    }
+    serve(&mut port, frame, stop_reply);
+}
+
+/// Handles commands until gdb resumes execution.
+fn serve(port: &mut Uart16550, frame: &mut TrapFrame, stop_reply: &[u8]) {
+    let mut packet = [0; MAX_PACKET];
+    let mut reply = Reply::new();
+    loop {
+        let len = receive(port, &mut packet);
+        let packet = &packet[..len];
+        reply.clear();
+        match packet.first() {
+            Some(b'?') => reply.push_bytes(stop_reply),
+            Some(b'g') => read_registers(frame, &mut reply),
+            Some(b'G') => {
+                write_registers(frame, &packet[1..]);
+                reply.push_bytes(b"OK");
+            }
+            Some(b'm') => read_memory(&packet[1..], &mut reply),
+            Some(b'M') => reply.push_bytes(match write_memory(&packet[1..]) {
+                Some(()) => b"OK",
+                None => b"E14",
+            }),
+            Some(b'Z') | Some(b'z') => breakpoint_command(packet, &mut reply),
+            Some(b'q') if packet.starts_with(b"qSupported") => {
+                reply.push_bytes(b"PacketSize=200;swbreak+");
+            }
+            Some(b'c') | Some(b's') => {
+                resume_at(frame, &packet[1..]);
+                if packet[0] == b's' {
+                    frame.rflags |= TRAP_FLAG;
+                }
+                RESUMED.store(true, Ordering::SeqCst);
+                return;
+            }
+            Some(b'D') => {
+                remove_all_breakpoints();
+                send(port, b"OK");
+                return;
+            }
+            Some(b'k') => crate::reboot(),
+            // unsupported commands get an empty reply
+            _ => {}
+        }
+        send(port, reply.as_bytes());
+    }
+}
+
+/// Receives the next packet with a valid checksum into `buffer` and returns its length.
+fn receive(port: &mut Uart16550, buffer: &mut [u8; MAX_PACKET]) -> usize {
+    loop {
+        while port.receive() != b'$' {}
+        let mut len = 0;
+        let mut checksum = 0u8;
+        loop {
+            let byte = port.receive();
+            if byte == b'#' {
+                break;
+            }
+            if len < MAX_PACKET {
+                buffer[len] = byte;
+                len += 1;
+            }
+            checksum = checksum.wrapping_add(byte);
+        }
+        let valid = match (hex_value(port.receive()), hex_value(port.receive())) {
+            (Some(high), Some(low)) => (high << 4 | low) == checksum,
+            _ => false,
+        };
+        if valid {
+            port.send_raw(b'+');
+            return len;
+        }
+        port.send_raw(b'-');
+    }
+}
+
+/// Sends `data` as a packet, repeating it until gdb acknowledges it.
+fn send(port: &mut Uart16550, data: &[u8]) {
+    loop {
+        port.send_raw(b'$');
+        let mut checksum = 0u8;
+        for &byte in data {
+            port.send_raw(byte);
+            checksum = checksum.wrapping_add(byte);
+        }
+        port.send_raw(b'#');
+        port.send_raw(HEX_DIGITS[usize::from(checksum >> 4)]);
+        port.send_raw(HEX_DIGITS[usize::from(checksum & 0xf)]);
+        if port.receive() == b'+' {
+            return;
+        }
+    }
+}
+
+/// A reply packet under construction. Fixed size, since the heap may be unusable.
+struct Reply {
+    data: [u8; MAX_PACKET],
+    len: usize,
+}
+
+impl Reply {
+    fn new() -> Self {
+        Reply { data: [0; MAX_PACKET], len: 0 }
+    }
+
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    fn push_bytes(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            if self.len < MAX_PACKET {
+                self.data[self.len] = byte;
+                self.len += 1;
+            }
+        }
+    }
+
+    /// Appends `byte` as two hex digits.
+    fn push_hex(&mut self, byte: u8) {
+        self.push_bytes(&[HEX_DIGITS[usize::from(byte >> 4)], HEX_DIGITS[usize::from(byte & 0xf)]]);
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+}
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+fn hex_value(digit: u8) -> Option<u8> {
+    match digit {
+        b'0'..=b'9' => Some(digit - b'0'),
+        b'a'..=b'f' => Some(digit - b'a' + 10),
+        b'A'..=b'F' => Some(digit - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// Parses a big-endian hex number, as used for addresses and lengths.
+fn parse_hex(digits: &[u8]) -> Option<u64> {
+    if digits.is_empty() || digits.len() > 16 {
+        return None;
+    }
+    digits.iter().try_fold(0u64, |value, &digit| Some(value << 4 | u64::from(hex_value(digit)?)))
+}
+
+/// Splits `addr,len` (optionally followed by `:data`) into its parts.
+fn parse_range(args: &[u8]) -> Option<(u64, u64, &[u8])> {
+    let (range, data) = match args.iter().position(|&b| b == b':') {
+        Some(colon) => (&args[..colon], &args[colon + 1..]),
+        None => (args, &args[args.len()..]),
+    };
+    let comma = range.iter().position(|&b| b == b',')?;
+    Some((parse_hex(&range[..comma])?, parse_hex(&range[comma + 1..])?, data))
+}
+
+/// The registers in the order of gdb's amd64 `g` packet: 64-bit general purpose
+/// registers and rip, then 32-bit eflags and segment selectors.
+fn registers(frame: &mut TrapFrame) -> ([&mut u64; 17], [&mut u64; 3]) {
+    let TrapFrame { rax, rbx, rcx, rdx, rsi, rdi, rbp, r8, r9, r10, r11, r12, r13, r14, r15,
+                    vector: _, rip, cs, rflags, rsp, ss } = frame;
+    ([rax, rbx, rcx, rdx, rsi, rdi, rbp, rsp, r8, r9, r10, r11, r12, r13, r14, r15, rip],
+     [rflags, cs, ss])
+}
+
+fn read_registers(frame: &mut TrapFrame, reply: &mut Reply) {
+    let (wide, narrow) = registers(frame);
+    for register in wide {
+        for byte in register.to_le_bytes() {
+            reply.push_hex(byte);
+        }
+    }
+    for register in narrow {
+        for byte in (*register as u32).to_le_bytes() {
+            reply.push_hex(byte);
+        }
+    }
+    // ds, es, fs and gs are not saved: report them as unavailable
+    reply.push_bytes(&[b'x'; 4 * 8]);
+}
+
+fn write_registers(frame: &mut TrapFrame, mut data: &[u8]) {
+    let (wide, narrow) = registers(frame);
+    for register in wide {
+        if let Some(value) = take_le(&mut data, 8) {
+            *register = value;
+        }
+    }
+    // the selectors are not writable, only rflags is
+    let [rflags, _, _] = narrow;
+    if let Some(value) = take_le(&mut data, 4) {
+        *rflags = (*rflags & !0xffff_ffff) | value;
+    }
+}
+
+/// Takes a little-endian value of `size` bytes off the front of the hex `data`.
+fn take_le(data: &mut &[u8], size: usize) -> Option<u64> {
+    if data.len() < size * 2 {
+        return None;
+    }
+    let (digits, rest) = data.split_at(size * 2);
+    *data = rest;
+    let mut value = 0;
+    for (i, pair) in digits.chunks(2).enumerate() {
+        value |= parse_hex(pair)? << (8 * i);
+    }
+    Some(value)
+}
+
+/// Whether `addr` can be accessed without faulting, and whether it is read-only.
+fn probe(addr: u64) -> Option<bool> {
+    let mapping = memory::mapping_of(VirtAddr::try_new(addr).ok()?)?;
+    Some(!mapping.flags.contains(PageTableFlags::WRITABLE))
+}
+
+fn read_memory(args: &[u8], reply: &mut Reply) {
+    let (addr, len, _) = match parse_range(args) {
+        Some(range) => range,
+        None => {
+            reply.push_bytes(b"E01");
+            return;
+        }
+    };
+    for addr in addr..addr.saturating_add(len.min(MAX_PACKET as u64 / 2)) {
+        if probe(addr).is_none() {
+            // gdb accepts a partial read, but not an empty one
+            if reply.len == 0 {
+                reply.push_bytes(b"E14");
+            }
+            return;
+        }
+        reply.push_hex(unsafe { (addr as *const u8).read_volatile() });
+    }
+}
+
+fn write_memory(args: &[u8]) -> Option<()> {
+    let (addr, len, data) = parse_range(args)?;
+    if data.len() as u64 != len * 2 {
+        return None;
+    }
+    for (addr, pair) in (addr..).zip(data.chunks(2)) {
+        poke(addr, parse_hex(pair)? as u8)?;
+    }
+    Some(())
+}
+
+/// Writes `value` to `addr`, even if it is in a read-only page like kernel code.
+fn poke(addr: u64, value: u8) -> Option<()> {
+    let read_only = probe(addr)?;
+    unsafe {
+        if read_only {
+            Cr0::update(|flags| flags.remove(Cr0Flags::WRITE_PROTECT));
+        }
+        (addr as *mut u8).write_volatile(value);
+        if read_only {
+            Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+        }
+    }
+    Some(())
+}
+
+fn find_breakpoint(addr: u64) -> Option<usize> {
+    BREAKPOINTS.lock().iter().position(|slot| matches!(slot, Some(bp) if bp.addr == addr))
+}
+
+/// Handles `Z0,addr,kind` and `z0,addr,kind`. Other breakpoint types are unsupported.
+fn breakpoint_command(packet: &[u8], reply: &mut Reply) {
+    let insert = packet[0] == b'Z';
+    let addr = match packet.get(1..3) {
+        Some(b"0,") => parse_range(&packet[3..]).map(|(addr, _, _)| addr),
+        _ => return,
+    };
+    let result = match addr {
+        Some(addr) if insert => insert_breakpoint(addr),
+        Some(addr) => remove_breakpoint(addr),
+        None => None,
+    };
+    reply.push_bytes(match result {
+        Some(()) => b"OK",
+        None => b"E01",
+    });
+}
+
+fn insert_breakpoint(addr: u64) -> Option<()> {
+    if find_breakpoint(addr).is_some() {
+        return Some(());
+    }
+    let mut breakpoints = BREAKPOINTS.lock();
+    let slot = breakpoints.iter_mut().find(|slot| slot.is_none())?;
+    probe(addr)?;
+    let original = unsafe { (addr as *const u8).read_volatile() };
+    poke(addr, INT3)?;
+    *slot = Some(Breakpoint { addr, original });
+    Some(())
+}
+
+fn remove_breakpoint(addr: u64) -> Option<()> {
+    let index = find_breakpoint(addr)?;
+    let mut breakpoints = BREAKPOINTS.lock();
+    let breakpoint = breakpoints[index].take()?;
+    poke(breakpoint.addr, breakpoint.original)
+}
+
+fn remove_all_breakpoints() {
+    for slot in BREAKPOINTS.lock().iter_mut() {
+        if let Some(breakpoint) = slot.take() {
+            poke(breakpoint.addr, breakpoint.original);
+        }
+    }
+}
+
+/// Applies the optional resume address of a `c` or `s` packet.
+fn resume_at(frame: &mut TrapFrame, addr: &[u8]) {
+    if let Some(addr) = parse_hex(addr) {
+        frame.rip = addr;
+    }
+}
+
+#[test_case]
+fn test_parse_range() {
+    assert_eq!(parse_range(b"ffff8000,10"), Some((0xffff_8000, 0x10, &b""[..])));
+    assert_eq!(parse_range(b"1000,2:abcd"), Some((0x1000, 2, &b"abcd"[..])));
+    assert_eq!(parse_range(b"1000"), None);
+}
+
+#[test_case]
+fn test_take_le() {
+    let mut data: &[u8] = b"78563412ff";
+    assert_eq!(take_le(&mut data, 4), Some(0x1234_5678));
+    assert_eq!(data, b"ff");
+    assert_eq!(take_le(&mut data, 4), None);
+}
@@ -0,0 +1,171 @@
+//! Init calls: modules declare how they are initialized with `initcall!`,
+//! next to their `init` function, and the kernel runs them level by level.
+//!
+//! The declarations are statics collected by the linker in the section
+//! `maros_initcalls`, found through the `__start_` and `__stop_` symbols it
+//! defines for it, so adding a driver takes no change anywhere else. Levels
+//! run in order: `Early` before the heap exists, from `crate::init`, then
+//! `Core`, `Driver` and `Late` once it does. Within a level, an init call runs
+//! after those it names in `after`, and after those in `needs`, which must
+//! also have succeeded, in any level so far, or it is skipped. Every init
+//! call is a boot stage, see `boot::progress`.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+use crate::boot::progress;
+
+/// Init calls that fit in the table of their states.
+pub const MAX_INITCALLS: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    /// CPU tables, interrupts and clocks; no heap yet.
+    Early,
+    /// Timers, the scheduler and what every kernel needs running.
+    Core,
+    Driver,
+    /// What uses the drivers, like the shell.
+    Late,
+}
+
+/// An init call, declared with `initcall!`.
+pub struct InitCall {
+    pub name: &'static str,
+    pub level: Level,
+    pub init: fn() -> Result<(), &'static str>,
+    /// Init calls of the same level to run first, whether they succeed or not.
+    pub after: &'static [&'static str],
+    /// Init calls that must have succeeded first.
+    pub needs: &'static [&'static str],
+    /// Whether booting stops if it fails.
+    pub required: bool,
+}
+
+impl InitCall {
+    /// For the fields `initcall!` is not given.
+    pub const DEFAULT: InitCall = InitCall {
+        name: "",
+        level: Level::Late,
+        init: || Ok(()),
+        after: &[],
+        needs: &[],
+        required: false,
+    };
+}
+
+/// Declares an init call of `level` named `name`, which calls `init`, a
+/// `fn() -> Result<(), &'static str>`. Further `field: value` pairs set the
+/// other fields of `InitCall`, e.g.
+/// `initcall!(Driver, "e1000", init, needs: &["net"]);`.
+#[macro_export]
+macro_rules! initcall {
+    ($level:ident, $name:expr, $init:expr $(, $field:ident: $value:expr)* $(,)?) => {
+        const _: () = {
+            #[used]
+            #[link_section = "maros_initcalls"]
+            static INITCALL: $crate::initcall::InitCall = $crate::initcall::InitCall {
+                name: $name,
+                level: $crate::initcall::Level::$level,
+                init: $init,
+                $($field: $value,)*
+                ..$crate::initcall::InitCall::DEFAULT
+            };
+        };
+    };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+enum State {
+    Pending,
+    Succeeded,
+    Failed,
+    Skipped,
+}
+
+/// The state of each init call, in the order of `initcalls`.
+static STATES: [AtomicU8; MAX_INITCALLS] = {
+    const PENDING: AtomicU8 = AtomicU8::new(State::Pending as u8);
+    [PENDING; MAX_INITCALLS]
+};
+
+// only their addresses are taken
+#[allow(improper_ctypes)]
+extern "C" {
+    static __start_maros_initcalls: InitCall;
+    static __stop_maros_initcalls: InitCall;
+}
+
+/// The init calls linked into the kernel, in no particular order.
+fn initcalls() -> &'static [InitCall] {
+    unsafe {
+        let start = &__start_maros_initcalls as *const InitCall;
+        let stop = &__stop_maros_initcalls as *const InitCall;
+        core::slice::from_raw_parts(start, stop.offset_from(start) as usize)
+    }
+}
+
+fn state(index: usize) -> State {
+    match STATES[index].load(Ordering::Relaxed) {
+        0 => State::Pending,
+        1 => State::Succeeded,
+        2 => State::Failed,
+        _ => State::Skipped,
+    }
+}
+
+fn set_state(index: usize, state: State) {
+    STATES[index].store(state as u8, Ordering::Relaxed);
+}
+
+/// Runs the init calls of `level`. Panics if a required one fails.
+pub fn run(level: Level) {
+    let calls = initcalls();
+    assert!(calls.len() <= MAX_INITCALLS, "too many init calls");
+    let pending = |name: &str| calls.iter().enumerate()
+        .any(|(index, call)| call.level == level && call.name == name && state(index) == State::Pending);
+    let succeeded = |name: &str| calls.iter().enumerate()
+        .any(|(index, call)| call.name == name && state(index) == State::Succeeded);
+    loop {
+        let mut ran = false;
+        for (index, call) in calls.iter().enumerate() {
+            if call.level != level || state(index) != State::Pending
+                || call.after.iter().chain(call.needs).any(|name| pending(name)) {
+                continue;
+            }
+            ran = true;
+            if let Some(missing) = call.needs.iter().find(|name| !succeeded(name)) {
+                set_state(index, State::Skipped);
+                progress::skip(call.name, format_args!("needs {}", missing));
+                continue;
+            }
+            match progress::try_stage(call.name, call.init) {
+                Ok(()) => set_state(index, State::Succeeded),
+                Err(error) if call.required => panic!("{} initialization failed: {}", call.name, error),
+                Err(_) => set_state(index, State::Failed),
+            }
+        }
+        if !ran {
+            break;
+        }
+    }
+    // the ones left wait for each other
+    for (index, call) in calls.iter().enumerate() {
+        if call.level == level && state(index) == State::Pending {
+            set_state(index, State::Skipped);
+            progress::skip(call.name, format_args!("its dependencies form a cycle"));
+        }
+    }
+}
+
+#[test_case]
+fn test_initcalls() {
+    let calls = initcalls();
+    for (index, call) in calls.iter().enumerate() {
+        assert!(calls[..index].iter().all(|other| other.name != call.name), "two init calls named {}", call.name);
+        if call.level == Level::Early {
+            assert_ne!(state(index), State::Pending, "{} did not run", call.name);
+        }
+    }
+    let gdt = calls.iter().position(|call| call.name == "gdt").expect("no gdt init call");
+    assert_eq!(state(gdt), State::Succeeded);
+}
@@ -1,128 +0,0 @@
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
-use crate::{gdt, hlt_loop, print, println};
-use lazy_static::lazy_static;
-use pc_keyboard::KeyCode;
-
-pub fn init_idt() {
-    IDT.load();
-}
-lazy_static! {
-    static ref IDT: InterruptDescriptorTable = {
-        let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
-        unsafe {
-            idt.double_fault.set_handler_fn(double_fault_handler)
-            .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
-            }
-        idt[InterruptIndex::Timer.as_usize()]
-            .set_handler_fn(timer_interrupt_handler);
-        idt[InterruptIndex::Keyboard.as_usize()]
-            .set_handler_fn(keyboard_interrupt_handler);
-        idt
-    };
-}
-
-extern "x86-interrupt" fn breakpoint_handler(
-    stack_frame: InterruptStackFrame,
-) {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
-}
-
-extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
-    _error_code: PageFaultErrorCode,
-) {
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed address: {:?}", Cr2::read());
-    println!("Error code: {:?}", _error_code);
-    println!("Stack_frame {:#?}", stack_frame);
-    hlt_loop();
-}
-
-extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: InterruptStackFrame,
-    _error_code: u64,
-) -> ! {
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
-}
-
-#[test_case]
-fn test_breakpoints_exception() {
-    x86_64::instructions::interrupts::int3();
-}
-
-use pic8259::ChainedPics;
-use spin;
-use x86_64::registers::control::Cr2;
-use crate::vga_buffer::WRITER;
-
-pub const PIC_1_OFFSET: u8 = 32;
-pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
-pub static PICS: spin::Mutex<ChainedPics> = spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) }); // range 32 - 47
-
-
-#[derive(Debug, Clone, Copy)]
-#[repr(u8)]
-pub enum InterruptIndex {
-    Timer = PIC_1_OFFSET,
-    Keyboard,
-}
-
-impl InterruptIndex {
-    fn as_u8(self) -> u8 {
-        self as u8
-    }
-    fn as_usize(self) -> usize {
-        usize::from(self.as_u8())
-    }
-}
-
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    // print!(".");
-    unsafe {
-        PICS.lock().notify_end_of_interrupt(InterruptIndex::Timer.as_u8())
-    }
-}
-
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    use x86_64::instructions::port::Port;
-    use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
-    use spin::Mutex;
-
-    lazy_static! {
-        static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
-            Mutex::new(Keyboard::new(ScancodeSet1::new(), layouts::Us104Key,HandleControl::MapLettersToUnicode)
-            );
-    }
-    let mut keyboard = KEYBOARD.lock();
-    let mut port = Port::new(0x60);
-    let scancode: u8 = unsafe { port.read() };
-    if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-        if let Some(key) = keyboard.process_keyevent(key_event) {
-            match key {
-                DecodedKey::RawKey(rk) => {
-                    // print!("{:?}", rk);
-                    match rk {
-                        KeyCode::ArrowLeft => WRITER.lock().move_left(),
-                        KeyCode::ArrowRight => WRITER.lock().move_right(),
-                        KeyCode::ArrowDown => WRITER.lock().move_down(),
-                        KeyCode::ArrowUp => WRITER.lock().move_up(),
-                        _ => {}
-                    }
-                }
-                DecodedKey::Unicode(character) => {
-                    print!("{}", character);
-                    // print!("{}: 0x{:02x}", character, character as u8)
-                }
-            }
-        }
-    }
-    unsafe {
-        PICS.lock().notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8())
-    }
-}
-
-
-
-
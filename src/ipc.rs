@@ -0,0 +1,219 @@
+//! Message passing between threads through named ports.
+//!
+//! A port belongs to the thread that created it, which is the only one that can
+//! receive from it. Who may send is decided per port. Messages have a fixed
+//! maximum size and are copied in and out, so no memory is shared. Processes
+//! use them through the `PORT_CREATE`, `PORT_DESTROY`, `SEND` and `RECEIVE`
+//! system calls, as the thread they run on.
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+use crate::arch::interrupts;
+use crate::sched::{self, ThreadId, WaitQueue};
+
+/// Largest message payload in bytes.
+pub const MESSAGE_SIZE: usize = 64;
+/// Messages a port holds before `send` fails with `Full`.
+const PORT_CAPACITY: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpcError {
+    NotFound,
+    AlreadyExists,
+    PermissionDenied,
+    /// The message is longer than `MESSAGE_SIZE`.
+    TooLong,
+    Full,
+    /// The port was destroyed while waiting on it.
+    Closed,
+}
+
+/// Who may send to a port. The owner always may.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Access {
+    Anyone,
+    Only(Vec<ThreadId>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Message {
+    pub sender: ThreadId,
+    len: usize,
+    data: [u8; MESSAGE_SIZE],
+}
+
+impl Message {
+    pub fn data(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+}
+
+struct Port {
+    owner: ThreadId,
+    access: Access,
+    queue: VecDeque<Message>,
+    closed: bool,
+}
+
+/// A port's state, locked with interrupts disabled so that `receive` can check
+/// it from a `WaitQueue` condition.
+struct Endpoint {
+    port: Mutex<Port>,
+    receivers: WaitQueue,
+}
+
+impl Endpoint {
+    fn with<R>(&self, f: impl FnOnce(&mut Port) -> R) -> R {
+        interrupts::without_interrupts(|| f(&mut self.port.lock()))
+    }
+}
+
+static PORTS: Mutex<BTreeMap<String, Arc<Endpoint>>> = Mutex::new(BTreeMap::new());
+
+/// The calling thread, or the boot thread before `sched::init`.
+fn caller() -> ThreadId {
+    sched::current().unwrap_or(ThreadId(0))
+}
+
+fn endpoint(name: &str) -> Result<Arc<Endpoint>, IpcError> {
+    PORTS.lock().get(name).cloned().ok_or(IpcError::NotFound)
+}
+
+/// Creates the port `name`, owned by the calling thread.
+pub fn create(name: &str, access: Access) -> Result<(), IpcError> {
+    let mut ports = PORTS.lock();
+    if ports.contains_key(name) {
+        return Err(IpcError::AlreadyExists);
+    }
+    let port = Port { owner: caller(), access, queue: VecDeque::with_capacity(PORT_CAPACITY), closed: false };
+    ports.insert(name.to_string(), Arc::new(Endpoint { port: Mutex::new(port), receivers: WaitQueue::new() }));
+    Ok(())
+}
+
+/// Removes the port `name`, dropping its messages. Only its owner may.
+pub fn destroy(name: &str) -> Result<(), IpcError> {
+    let mut ports = PORTS.lock();
+    let endpoint = ports.get(name).ok_or(IpcError::NotFound)?;
+    endpoint.with(|port| {
+        if port.owner != caller() {
+            return Err(IpcError::PermissionDenied);
+        }
+        port.closed = true;
+        port.queue.clear();
+        Ok(())
+    })?;
+    endpoint.receivers.notify_all();
+    ports.remove(name);
+    Ok(())
+}
+
+/// Changes who may send to `name`. Only its owner may.
+pub fn set_access(name: &str, access: Access) -> Result<(), IpcError> {
+    endpoint(name)?.with(|port| {
+        if port.owner != caller() {
+            return Err(IpcError::PermissionDenied);
+        }
+        port.access = access;
+        Ok(())
+    })
+}
+
+/// Queues `data` on the port `name` without blocking.
+pub fn send(name: &str, data: &[u8]) -> Result<(), IpcError> {
+    if data.len() > MESSAGE_SIZE {
+        return Err(IpcError::TooLong);
+    }
+    let sender = caller();
+    let mut message = Message { sender, len: data.len(), data: [0; MESSAGE_SIZE] };
+    message.data[..data.len()].copy_from_slice(data);
+
+    let endpoint = endpoint(name)?;
+    endpoint.with(|port| {
+        let allowed = port.owner == sender || match &port.access {
+            Access::Anyone => true,
+            Access::Only(senders) => senders.contains(&sender),
+        };
+        if !allowed {
+            return Err(IpcError::PermissionDenied);
+        }
+        if port.queue.len() >= PORT_CAPACITY {
+            return Err(IpcError::Full);
+        }
+        port.queue.push_back(message);
+        Ok(())
+    })?;
+    endpoint.receivers.notify_one();
+    Ok(())
+}
+
+/// Takes the oldest message from the port `name`, if any. Only its owner may.
+pub fn try_receive(name: &str) -> Result<Option<Message>, IpcError> {
+    let me = caller();
+    endpoint(name)?.with(|port| {
+        if port.owner != me {
+            return Err(IpcError::PermissionDenied);
+        }
+        Ok(port.queue.pop_front())
+    })
+}
+
+/// Waits for the next message on the port `name`. Only its owner may.
+pub fn receive(name: &str) -> Result<Message, IpcError> {
+    let me = caller();
+    let endpoint = endpoint(name)?;
+    if endpoint.with(|port| port.owner) != me {
+        return Err(IpcError::PermissionDenied);
+    }
+    let mut result = Err(IpcError::Closed);
+    // the condition runs with interrupts disabled, as `with` needs
+    endpoint.receivers.wait_until(|| {
+        let mut port = endpoint.port.lock();
+        match port.queue.pop_front() {
+            Some(message) => {
+                result = Ok(message);
+                true
+            }
+            None => port.closed,
+        }
+    });
+    result
+}
+
+#[test_case]
+fn test_ports() {
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    static STEPS: WaitQueue = WaitQueue::new();
+    static DENIED: AtomicBool = AtomicBool::new(false);
+    static ALLOWED: AtomicBool = AtomicBool::new(false);
+
+    create("test", Access::Only(Vec::new())).unwrap();
+    assert_eq!(create("test", Access::Anyone), Err(IpcError::AlreadyExists));
+    assert_eq!(send("test", &[0; MESSAGE_SIZE + 1]), Err(IpcError::TooLong));
+    assert_eq!(try_receive("test"), Ok(None));
+
+    let sender = sched::spawn("test-sender", || {
+        assert_eq!(send("test", b"denied"), Err(IpcError::PermissionDenied));
+        assert_eq!(try_receive("test"), Err(IpcError::PermissionDenied));
+        DENIED.store(true, Ordering::SeqCst);
+        STEPS.notify_all();
+        STEPS.wait_until(|| ALLOWED.load(Ordering::SeqCst));
+        send("test", b"hello").unwrap();
+    }).unwrap();
+    STEPS.wait_until(|| DENIED.load(Ordering::SeqCst));
+    // the owner may always send to itself
+    send("test", b"self").unwrap();
+    assert_eq!(receive("test").unwrap().data(), b"self");
+
+    set_access("test", Access::Only(alloc::vec![sender])).unwrap();
+    ALLOWED.store(true, Ordering::SeqCst);
+    STEPS.notify_all();
+    let message = receive("test").unwrap();
+    assert_eq!(message.sender, sender);
+    assert_eq!(message.data(), b"hello");
+    destroy("test").unwrap();
+    assert_eq!(send("test", b"gone"), Err(IpcError::NotFound));
+}
@@ -0,0 +1,249 @@
+//! Keyboard input: decodes the PS/2 scancodes and hands the keys to the console,
+//! or to a program that grabbed the keyboard with `grab`. Chords run the
+//! actions `bindings` binds them to, and keys can be recorded and replayed,
+//! see `macros`.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use lazy_static::lazy_static;
+use pc_keyboard::{layouts, HandleControl, KeyEvent, KeyState, Keyboard, ScancodeSet1};
+use spin::Mutex;
+use crate::arch::interrupts::without_interrupts;
+use crate::keyboard::bindings::Action;
+use crate::vga_buffer::writer::SelectionMode;
+use crate::vga_buffer::WRITER;
+use crate::task::channel::{channel, Receiver, Sender};
+use crate::{console, print, println, signal, statusbar, task, workqueue};
+
+pub use pc_keyboard::{DecodedKey, KeyCode};
+
+pub mod bindings;
+pub mod macros;
+
+/// Name of the keyboard layout scancodes are decoded with.
+pub const LAYOUT: &str = "us104";
+
+/// Keys a grabbing program hasn't read yet. Further keys are dropped.
+const QUEUE_LIMIT: usize = 64;
+
+lazy_static! {
+    static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
+        Mutex::new(Keyboard::new(ScancodeSet1::new(), layouts::Us104Key, HandleControl::MapLettersToUnicode));
+    /// Keys for the program that grabbed the keyboard.
+    static ref KEYS: (Sender<KeyPress>, Mutex<Receiver<KeyPress>>) = {
+        let (sender, receiver) = channel(QUEUE_LIMIT);
+        (sender, Mutex::new(receiver))
+    };
+}
+
+static GRABBED: AtomicBool = AtomicBool::new(false);
+static MODIFIERS: Mutex<Modifiers> = Mutex::new(Modifiers::new());
+
+/// The modifier keys held and the lock keys on, followed from the key events
+/// as the decoder keeps its own to itself.
+struct Modifiers {
+    lshift: bool,
+    rshift: bool,
+    lctrl: bool,
+    rctrl: bool,
+    lalt: bool,
+    ralt: bool,
+    capslock: bool,
+    numlock: bool,
+    /// The hidden control key sent before Num Lock when Pause is pressed.
+    pause: bool,
+}
+
+impl Modifiers {
+    const fn new() -> Modifiers {
+        Modifiers {
+            lshift: false,
+            rshift: false,
+            lctrl: false,
+            rctrl: false,
+            lalt: false,
+            ralt: false,
+            capslock: false,
+            numlock: false,
+            pause: false,
+        }
+    }
+
+    fn update(&mut self, event: &KeyEvent) {
+        let down = match event.state {
+            KeyState::Down => true,
+            KeyState::Up => false,
+            KeyState::SingleShot => return,
+        };
+        match event.code {
+            KeyCode::LShift => self.lshift = down,
+            KeyCode::RShift => self.rshift = down,
+            KeyCode::LControl => self.lctrl = down,
+            KeyCode::RControl => self.rctrl = down,
+            KeyCode::LAlt => self.lalt = down,
+            KeyCode::RAltGr => self.ralt = down,
+            KeyCode::RControl2 => self.pause = down,
+            KeyCode::CapsLock if down => self.capslock = !self.capslock,
+            KeyCode::NumpadLock if down && !self.pause => self.numlock = !self.numlock,
+            _ => {}
+        }
+    }
+
+    fn shift(&self) -> bool {
+        self.lshift || self.rshift
+    }
+
+    fn ctrl(&self) -> bool {
+        self.lctrl || self.rctrl
+    }
+
+    fn alt(&self) -> bool {
+        self.lalt || self.ralt
+    }
+}
+
+/// A decoded key with the modifiers that were held when it was pressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyPress {
+    pub key: DecodedKey,
+    pub shift: bool,
+    pub ctrl: bool,
+    pub alt: bool,
+}
+
+/// Called from the keyboard interrupt with the byte read from the controller.
+/// Decoding is deferred to the work queue once it runs.
+pub fn handle_scancode(scancode: u8) {
+    if workqueue::queue(move || decode(scancode)).is_err() {
+        decode(scancode);
+    }
+}
+
+fn decode(scancode: u8) {
+    let press = {
+        let mut keyboard = KEYBOARD.lock();
+        let event = match keyboard.add_byte(scancode) {
+            Ok(Some(event)) => event,
+            _ => return,
+        };
+        let mut modifiers = MODIFIERS.lock();
+        modifiers.update(&event);
+        statusbar::set_lock_keys(modifiers.capslock, modifiers.numlock);
+        match keyboard.process_keyevent(event) {
+            Some(key) => KeyPress {
+                key,
+                shift: modifiers.shift(),
+                ctrl: modifiers.ctrl(),
+                alt: modifiers.alt(),
+            },
+            None => return,
+        }
+    };
+
+    match bindings::lookup(&press) {
+        Some(Action::RecordMacro) => macros::toggle_recording(),
+        Some(Action::PlayMacro) => macros::play_last(),
+        Some(Action::Screenshot) => console::screenshot_to_serial(),
+        _ => {
+            macros::record(press);
+            deliver(press);
+        }
+    }
+}
+
+/// Hands `press` to the program that grabbed the keyboard, or to the console.
+fn deliver(press: KeyPress) {
+    if GRABBED.load(Ordering::Acquire) {
+        let _ = KEYS.0.try_send(press);
+    } else {
+        to_console(press);
+    }
+}
+
+/// Edits the console line with `press`, submitting it on Enter.
+fn to_console(press: KeyPress) {
+    // Ctrl-C interrupts a running command whatever it is bound to
+    if press.key == DecodedKey::Unicode('\u{3}') && signal::interrupt_foreground() {
+        return println!("^C");
+    }
+    let action = bindings::lookup(&press);
+    if action == Some(Action::SwitchConsole) {
+        return console::switch_input();
+    }
+    if let Some(action) = action {
+        return without_interrupts(|| {
+            let mut writer = WRITER.lock();
+            match action {
+                Action::ClearScreen => writer.clear_screen(),
+                Action::Copy => writer.copy(),
+                Action::Paste => writer.paste(),
+                Action::Delete => writer.delete(),
+                Action::NextTheme => {
+                    let theme = writer.theme().next();
+                    writer.set_theme(theme);
+                }
+                // handled before the key gets here
+                Action::RecordMacro | Action::PlayMacro | Action::Screenshot | Action::SwitchConsole => {}
+            }
+        });
+    }
+    match press.key {
+        DecodedKey::RawKey(rk) => without_interrupts(|| {
+            let mut writer = WRITER.lock();
+            let is_arrow = matches!(rk, KeyCode::ArrowLeft | KeyCode::ArrowRight | KeyCode::ArrowDown | KeyCode::ArrowUp);
+            if is_arrow {
+                // Shift selects, Shift+Alt selects a rectangle
+                if press.shift && press.alt {
+                    writer.start_selection(SelectionMode::Rectangle);
+                } else if press.shift {
+                    writer.start_selection(SelectionMode::Stream);
+                } else {
+                    writer.clear_selection();
+                }
+            }
+            match rk {
+                KeyCode::ArrowLeft => writer.move_left(),
+                KeyCode::ArrowRight => writer.move_right(),
+                KeyCode::ArrowDown => writer.move_down(),
+                KeyCode::ArrowUp => writer.move_up(),
+                _ => {}
+            }
+        }),
+        DecodedKey::Unicode('\n') => {
+            let line = without_interrupts(|| WRITER.lock().current_line());
+            print!("\n");
+            console::submit_line(line);
+        }
+        DecodedKey::Unicode(character) => {
+            print!("{}", character);
+        }
+    }
+}
+
+/// Keeps the keyboard grabbed while alive.
+pub struct Grab(());
+
+impl Drop for Grab {
+    fn drop(&mut self) {
+        GRABBED.store(false, Ordering::Release);
+    }
+}
+
+/// Delivers the keys to `read_key` instead of the console until the returned
+/// guard is dropped.
+pub fn grab() -> Grab {
+    KEYS.1.lock().clear();
+    GRABBED.store(true, Ordering::Release);
+    Grab(())
+}
+
+/// The next key pressed while the keyboard is grabbed, if there is one.
+pub fn try_read_key() -> Option<KeyPress> {
+    KEYS.1.lock().try_recv().ok()
+}
+
+/// Waits for the next key pressed while the keyboard is grabbed, running the
+/// ready tasks meanwhile.
+pub fn read_key() -> KeyPress {
+    let mut keys = KEYS.1.lock();
+    task::block_on(keys.recv()).expect("the keyboard interrupt keeps its sender")
+}
@@ -0,0 +1,252 @@
+//! Key bindings: the chords that run an action instead of typing a character.
+//!
+//! Each action has at most one chord, the default one to begin with, and
+//! `bind` and `unbind` change them at runtime, e.g. with the `bind` command.
+//! The console actions only apply to the keys the console gets; the macro
+//! and screenshot ones apply even while a program grabbed the keyboard.
+
+use core::fmt;
+use spin::Mutex;
+use crate::arch::interrupts::without_interrupts;
+use crate::keyboard::{DecodedKey, KeyCode, KeyPress};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    ClearScreen,
+    Copy,
+    Paste,
+    Delete,
+    NextTheme,
+    RecordMacro,
+    PlayMacro,
+    Screenshot,
+    SwitchConsole,
+}
+
+impl Action {
+    pub const ALL: [Action; 9] = [Action::ClearScreen, Action::Copy, Action::Paste, Action::Delete,
+        Action::NextTheme, Action::RecordMacro, Action::PlayMacro, Action::Screenshot, Action::SwitchConsole];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Action::ClearScreen => "clear-screen",
+            Action::Copy => "copy",
+            Action::Paste => "paste",
+            Action::Delete => "delete",
+            Action::NextTheme => "next-theme",
+            Action::RecordMacro => "record-macro",
+            Action::PlayMacro => "play-macro",
+            Action::Screenshot => "screenshot",
+            Action::SwitchConsole => "switch-console",
+        }
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            Action::ClearScreen => "clear the screen",
+            Action::Copy => "copy the selection, or the current line",
+            Action::Paste => "paste at the cursor",
+            Action::Delete => "delete the character under the cursor",
+            Action::NextTheme => "switch to the next color theme",
+            Action::RecordMacro => "start or stop recording a keyboard macro",
+            Action::PlayMacro => "replay the last keyboard macro",
+            Action::Screenshot => "send the screen in colors to the host over the serial port",
+            Action::SwitchConsole => "send the typed lines to the kernel shell or to user space",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Action> {
+        Action::ALL.iter().copied().find(|action| action.name() == name)
+    }
+
+    /// Where the action is in `ALL`, which lists them in order.
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// A key, as chords name it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    /// Lowercase for letters.
+    Char(char),
+    Raw(KeyCode),
+}
+
+/// A key with the modifiers held while pressing it. Shift is not part of it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Chord {
+    pub ctrl: bool,
+    pub alt: bool,
+    pub key: Key,
+}
+
+/// Function keys by number, F1 first.
+const FUNCTION_KEYS: [KeyCode; 12] = [KeyCode::F1, KeyCode::F2, KeyCode::F3, KeyCode::F4, KeyCode::F5, KeyCode::F6,
+    KeyCode::F7, KeyCode::F8, KeyCode::F9, KeyCode::F10, KeyCode::F11, KeyCode::F12];
+/// Keys that decode to control characters without Ctrl, with their names.
+const NAMED_KEYS: [(char, &str); 5] = [('\u{7f}', "delete"), ('\u{1b}', "esc"), ('\u{8}', "backspace"),
+    ('\t', "tab"), (' ', "space")];
+
+impl Chord {
+    const fn ctrl(letter: char) -> Chord {
+        Chord { ctrl: true, alt: false, key: Key::Char(letter) }
+    }
+
+    const fn ctrl_alt(letter: char) -> Chord {
+        Chord { ctrl: true, alt: true, key: Key::Char(letter) }
+    }
+
+    /// The chord `press` is. With Ctrl, letters decode to control characters,
+    /// which are turned back into their letters.
+    pub fn of(press: &KeyPress) -> Chord {
+        let key = match press.key {
+            DecodedKey::Unicode(character @ '\u{1}'..='\u{1a}') if press.ctrl =>
+                Key::Char((character as u8 - 1 + b'a') as char),
+            DecodedKey::Unicode(character) => Key::Char(character.to_ascii_lowercase()),
+            DecodedKey::RawKey(code) => Key::Raw(code),
+        };
+        Chord { ctrl: press.ctrl, alt: press.alt, key }
+    }
+
+    /// Parses modifiers and a key joined by `+`, like `ctrl+alt+t`, `ctrl+l`,
+    /// `alt+f5` or `delete`. Printable characters, space included, need a
+    /// modifier.
+    pub fn parse(text: &str) -> Option<Chord> {
+        let text = text.to_ascii_lowercase();
+        let mut parts = text.rsplit('+');
+        let key = parts.next()?;
+        let (mut ctrl, mut alt) = (false, false);
+        for modifier in parts {
+            match modifier {
+                "ctrl" => ctrl = true,
+                "alt" => alt = true,
+                _ => return None,
+            }
+        }
+        let key = if let Some(&(character, _)) = NAMED_KEYS.iter().find(|(_, name)| *name == key) {
+            Key::Char(character)
+        } else if let Some(n) = key.strip_prefix('f').and_then(|n| n.parse::<usize>().ok()) {
+            Key::Raw(*FUNCTION_KEYS.get(n.checked_sub(1)?)?)
+        } else {
+            let mut characters = key.chars();
+            match (characters.next(), characters.next()) {
+                (Some(character), None) => Key::Char(character),
+                _ => return None,
+            }
+        };
+        match key {
+            Key::Char(character) if !character.is_control() && !ctrl && !alt => None,
+            key => Some(Chord { ctrl, alt, key }),
+        }
+    }
+}
+
+impl fmt::Display for Chord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.ctrl {
+            f.write_str("Ctrl+")?;
+        }
+        if self.alt {
+            f.write_str("Alt+")?;
+        }
+        match self.key {
+            Key::Char(character) => match NAMED_KEYS.iter().find(|(named, _)| *named == character) {
+                Some((_, name)) => write!(f, "{}{}", name[..1].to_ascii_uppercase(), &name[1..]),
+                None => write!(f, "{}", character.to_ascii_uppercase()),
+            },
+            Key::Raw(code) => match FUNCTION_KEYS.iter().position(|&key| key == code) {
+                Some(n) => write!(f, "F{}", n + 1),
+                None => write!(f, "{:?}", code),
+            },
+        }
+    }
+}
+
+const DEFAULTS: [Option<Chord>; Action::ALL.len()] = [
+    Some(Chord::ctrl('l')),
+    Some(Chord::ctrl('c')),
+    Some(Chord::ctrl('v')),
+    Some(Chord { ctrl: false, alt: false, key: Key::Char('\u{7f}') }),
+    Some(Chord::ctrl_alt('t')),
+    Some(Chord::ctrl_alt('r')),
+    Some(Chord::ctrl_alt('p')),
+    Some(Chord::ctrl_alt('s')),
+    Some(Chord::ctrl_alt('k')),
+];
+
+/// The chord of each action, in the order of `Action::ALL`. Read by the
+/// keyboard interrupt when the work queue is full.
+static BINDINGS: Mutex<[Option<Chord>; Action::ALL.len()]> = Mutex::new(DEFAULTS);
+
+/// The action `press` is bound to, if any.
+pub fn lookup(press: &KeyPress) -> Option<Action> {
+    let chord = Chord::of(press);
+    let bindings = without_interrupts(|| *BINDINGS.lock());
+    Action::ALL.iter().copied().find(|action| bindings[action.index()] == Some(chord))
+}
+
+/// The actions with their chords.
+pub fn bindings() -> [(Action, Option<Chord>); Action::ALL.len()] {
+    let bindings = without_interrupts(|| *BINDINGS.lock());
+    let mut list = [(Action::ClearScreen, None); Action::ALL.len()];
+    for (entry, &action) in list.iter_mut().zip(Action::ALL.iter()) {
+        *entry = (action, bindings[action.index()]);
+    }
+    list
+}
+
+pub fn chord(action: Action) -> Option<Chord> {
+    without_interrupts(|| BINDINGS.lock()[action.index()])
+}
+
+/// Binds `action` to `chord`, which is taken from the action it was bound
+/// to before. Returns that action.
+pub fn bind(action: Action, chord: Chord) -> Option<Action> {
+    without_interrupts(|| {
+        let mut bindings = BINDINGS.lock();
+        let previous = Action::ALL.iter().copied()
+            .find(|&other| other != action && bindings[other.index()] == Some(chord));
+        if let Some(previous) = previous {
+            bindings[previous.index()] = None;
+        }
+        bindings[action.index()] = Some(chord);
+        previous
+    })
+}
+
+/// Leaves `action` without a chord.
+pub fn unbind(action: Action) {
+    without_interrupts(|| BINDINGS.lock()[action.index()] = None);
+}
+
+/// Restores the default bindings.
+pub fn reset() {
+    without_interrupts(|| *BINDINGS.lock() = DEFAULTS);
+}
+
+#[test_case]
+fn test_bindings() {
+    let press = |key, ctrl, alt| KeyPress { key, shift: false, ctrl, alt };
+    assert_eq!(Chord::parse("Ctrl+Alt+T"), Some(Chord::ctrl_alt('t')));
+    assert_eq!(Chord::parse("alt+f5"), Some(Chord { ctrl: false, alt: true, key: Key::Raw(KeyCode::F5) }));
+    assert_eq!(Chord::parse("x"), None);
+    assert_eq!(Chord::parse("space"), None);
+    assert_eq!(Chord::parse("shift+x"), None);
+    assert_eq!(Chord::parse("f13"), None);
+    assert_eq!(alloc::format!("{}", Chord::parse("delete").unwrap()), "Delete");
+    assert_eq!(alloc::format!("{}", Chord::ctrl('l')), "Ctrl+L");
+
+    assert_eq!(lookup(&press(DecodedKey::Unicode('\u{16}'), true, false)), Some(Action::Paste));
+    assert_eq!(lookup(&press(DecodedKey::Unicode('v'), false, false)), None);
+    // a chord moves from the action it was bound to
+    let f5 = press(DecodedKey::RawKey(KeyCode::F5), false, false);
+    assert_eq!(bind(Action::Paste, Chord::parse("f5").unwrap()), None);
+    assert_eq!(lookup(&f5), Some(Action::Paste));
+    assert_eq!(bind(Action::Copy, Chord::parse("f5").unwrap()), Some(Action::Paste));
+    assert_eq!(bindings()[Action::Paste.index()], (Action::Paste, None));
+    unbind(Action::Copy);
+    assert_eq!(lookup(&f5), None);
+    reset();
+    assert_eq!(lookup(&press(DecodedKey::Unicode('\u{3}'), true, false)), Some(Action::Copy));
+}
@@ -0,0 +1,157 @@
+//! Keyboard macros: sequences of keys recorded once and replayed as if typed
+//! again.
+//!
+//! The `record-macro` binding, Ctrl+Alt+R by default, starts recording the
+//! keys pressed and stops it again. The macro is stored under the name given
+//! to `start`, or the first free `m<n>` when the chord started it. The
+//! `play-macro` binding, Ctrl+Alt+P, replays the macro recorded last, and
+//! `play` any of them. The chords of these two are never recorded.
+//! Replayed keys go where typed ones would: to the console, or to the
+//! program that grabbed the keyboard, whose queue may drop some of a long
+//! macro.
+
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+use crate::arch::interrupts::without_interrupts;
+use crate::keyboard::KeyPress;
+use crate::statusbar;
+
+/// Keys kept of a recording; the following ones are not recorded.
+pub const MAX_KEYS: usize = 256;
+pub const MAX_MACROS: usize = 32;
+
+struct Recording {
+    name: Option<String>,
+    keys: Vec<KeyPress>,
+}
+
+struct Macros {
+    macros: BTreeMap<String, Arc<[KeyPress]>>,
+    recording: Option<Recording>,
+    /// The macro recorded last, which Ctrl+Alt+P plays.
+    last: Option<String>,
+}
+
+/// Also used by the keyboard interrupt when the work queue is full.
+static MACROS: Mutex<Macros> = Mutex::new(Macros { macros: BTreeMap::new(), recording: None, last: None });
+
+/// Starts recording the keys pressed, to be stored as `name` when `stop`ped.
+pub fn start(name: Option<&str>) -> Result<(), &'static str> {
+    // room for every key now, so recording never allocates
+    let keys = Vec::with_capacity(MAX_KEYS);
+    without_interrupts(|| {
+        let mut macros = MACROS.lock();
+        if macros.recording.is_some() {
+            return Err("already recording");
+        }
+        macros.recording = Some(Recording { name: name.map(String::from), keys });
+        Ok(())
+    })?;
+    statusbar::set_recording(true);
+    Ok(())
+}
+
+/// Stops recording and stores the macro. Returns its name.
+pub fn stop() -> Result<String, &'static str> {
+    let name = without_interrupts(|| {
+        let mut macros = MACROS.lock();
+        let recording = macros.recording.take().ok_or("not recording")?;
+        let name = match recording.name {
+            Some(name) => name,
+            None => (1..).map(|n| format!("m{}", n)).find(|name| !macros.macros.contains_key(name))
+                .expect("fewer macros than numbers"),
+        };
+        if macros.macros.len() >= MAX_MACROS && !macros.macros.contains_key(&name) {
+            return Err("too many macros");
+        }
+        macros.macros.insert(name.clone(), Arc::from(recording.keys));
+        macros.last = Some(name.clone());
+        Ok(name)
+    });
+    statusbar::set_recording(false);
+    name
+}
+
+pub fn is_recording() -> bool {
+    without_interrupts(|| MACROS.lock().recording.is_some())
+}
+
+/// The names of the macros with their number of keys.
+pub fn list() -> Vec<(String, usize)> {
+    without_interrupts(|| MACROS.lock().macros.iter().map(|(name, keys)| (name.clone(), keys.len())).collect())
+}
+
+pub fn delete(name: &str) -> Result<(), &'static str> {
+    match without_interrupts(|| MACROS.lock().macros.remove(name)) {
+        Some(_) => Ok(()),
+        None => Err("no such macro"),
+    }
+}
+
+/// Hands the keys of the macro `name` on as if they were pressed.
+pub fn play(name: &str) -> Result<(), &'static str> {
+    let keys = without_interrupts(|| MACROS.lock().macros.get(name).cloned()).ok_or("no such macro")?;
+    for &press in keys.iter() {
+        super::deliver(press);
+    }
+    Ok(())
+}
+
+/// Starts or stops recording, for the `record-macro` binding.
+pub(super) fn toggle_recording() {
+    if is_recording() {
+        let _ = stop();
+    } else {
+        let _ = start(None);
+    }
+}
+
+/// Replays the macro recorded last, for the `play-macro` binding.
+pub(super) fn play_last() {
+    let last = without_interrupts(|| MACROS.lock().last.clone());
+    if let Some(last) = last {
+        let _ = play(&last);
+    }
+}
+
+/// Called by the keyboard with the keys pressed that are not bound to the
+/// macro actions: records `press` while recording.
+pub(super) fn record(press: KeyPress) {
+    without_interrupts(|| {
+        if let Some(recording) = MACROS.lock().recording.as_mut() {
+            if recording.keys.len() < MAX_KEYS {
+                recording.keys.push(press);
+            }
+        }
+    });
+}
+
+#[test_case]
+fn test_macros() {
+    use crate::keyboard::DecodedKey;
+
+    let key = |character| KeyPress { key: DecodedKey::Unicode(character), shift: false, ctrl: false, alt: false };
+    start(Some("test")).unwrap();
+    assert_eq!(start(None), Err("already recording"));
+    record(key('a'));
+    record(key('b'));
+    toggle_recording();
+    assert!(!is_recording());
+    assert!(list().contains(&(String::from("test"), 2)));
+
+    let grab = super::grab();
+    play_last();
+    play("test").unwrap();
+    let keys: Vec<KeyPress> = core::iter::from_fn(super::try_read_key).collect();
+    assert_eq!(keys, [key('a'), key('b'), key('a'), key('b')]);
+    drop(grab);
+
+    delete("test").unwrap();
+    assert_eq!(delete("test"), Err("no such macro"));
+    assert_eq!(play("test"), Err("no such macro"));
+    assert_eq!(stop(), Err("not recording"));
+}
@@ -0,0 +1,220 @@
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+
+pub mod pstore;
+
+/// Log lines kept for crash reports.
+pub const HISTORY_LINES: usize = 8;
+/// Bytes kept of each of them; the rest is cut off.
+pub const HISTORY_LINE_SIZE: usize = 96;
+
+/// Severity of a log message. Messages above the configured level are dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    /// Parses a level name as used on the kernel command line, e.g. `debug`.
+    pub fn parse(name: &str) -> Option<Level> {
+        match name {
+            "error" => Some(Level::Error),
+            "warn" => Some(Level::Warn),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            "trace" => Some(Level::Trace),
+            _ => None,
+        }
+    }
+
+    fn from_u8(n: u8) -> Level {
+        match n {
+            0 => Level::Error,
+            1 => Level::Warn,
+            2 => Level::Info,
+            3 => Level::Debug,
+            _ => Level::Trace,
+        }
+    }
+
+    fn tag(self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+}
+
+/// Where log messages are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Console {
+    Vga,
+    Serial,
+    Both,
+}
+
+impl Console {
+    /// Parses a console name as used on the kernel command line, e.g. `serial`.
+    pub fn parse(name: &str) -> Option<Console> {
+        match name {
+            "vga" => Some(Console::Vga),
+            "serial" => Some(Console::Serial),
+            "both" => Some(Console::Both),
+            _ => None,
+        }
+    }
+
+    fn from_u8(n: u8) -> Console {
+        match n {
+            0 => Console::Vga,
+            1 => Console::Serial,
+            _ => Console::Both,
+        }
+    }
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+static CONSOLE: AtomicU8 = AtomicU8::new(Console::Both as u8);
+
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn level() -> Level {
+    Level::from_u8(LEVEL.load(Ordering::Relaxed))
+}
+
+pub fn set_console(console: Console) {
+    CONSOLE.store(console as u8, Ordering::Relaxed);
+}
+
+pub fn console() -> Console {
+    Console::from_u8(CONSOLE.load(Ordering::Relaxed))
+}
+
+/// A log line in the history. Bytes are stored one at a time, so a line being
+/// overwritten while it is read comes out garbled but never blocks anybody.
+struct HistoryLine {
+    len: AtomicUsize,
+    bytes: [AtomicU8; HISTORY_LINE_SIZE],
+}
+
+impl HistoryLine {
+    const EMPTY: HistoryLine = {
+        const ZERO: AtomicU8 = AtomicU8::new(0);
+        HistoryLine { len: AtomicUsize::new(0), bytes: [ZERO; HISTORY_LINE_SIZE] }
+    };
+}
+
+impl Write for &HistoryLine {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let len = self.len.load(Ordering::Relaxed);
+        let count = s.len().min(HISTORY_LINE_SIZE - len);
+        for (slot, &byte) in self.bytes[len..len + count].iter().zip(s.as_bytes()) {
+            slot.store(byte, Ordering::Relaxed);
+        }
+        self.len.store(len + count, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+static HISTORY: [HistoryLine; HISTORY_LINES] = [HistoryLine::EMPTY; HISTORY_LINES];
+/// Number of lines ever logged; the next one goes to this index modulo `HISTORY_LINES`.
+static HISTORY_NEXT: AtomicUsize = AtomicUsize::new(0);
+
+fn remember(level: Level, args: fmt::Arguments) {
+    let mut line = &HISTORY[HISTORY_NEXT.fetch_add(1, Ordering::Relaxed) % HISTORY_LINES];
+    line.len.store(0, Ordering::Relaxed);
+    let _ = write!(line, "[{}] {}", level.tag(), args);
+}
+
+/// Passes the last logged lines to `f`, oldest first, without taking any lock.
+/// For crash reports.
+pub fn recent_lines(mut f: impl FnMut(&[u8])) {
+    let next = HISTORY_NEXT.load(Ordering::Relaxed);
+    for index in next.saturating_sub(HISTORY_LINES)..next {
+        let line = &HISTORY[index % HISTORY_LINES];
+        let mut bytes = [0; HISTORY_LINE_SIZE];
+        let len = line.len.load(Ordering::Relaxed).min(HISTORY_LINE_SIZE);
+        for (byte, slot) in bytes[..len].iter_mut().zip(line.bytes.iter()) {
+            *byte = slot.load(Ordering::Relaxed);
+        }
+        f(&bytes[..len]);
+    }
+}
+
+#[doc(hidden)]
+pub fn _log(level: Level, args: fmt::Arguments) {
+    if level > self::level() {
+        return;
+    }
+    remember(level, args);
+    pstore::record(format_args!("[{}] {}\n", level.tag(), args));
+    #[cfg(feature = "net")]
+    crate::net::syslog::queue(level, args);
+    let console = console();
+    if console != Console::Serial {
+        crate::vga_buffer::_print(format_args!("[{}] {}\n", level.tag(), args));
+    }
+    if console != Console::Vga {
+        crate::serial::_print(format_args!("[{}] {}\n", level.tag(), args));
+    }
+}
+
+/// Logs a message at the given `klog::Level` to the configured console.
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => ($crate::klog::_log($level, format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! log_error {
+    ($($arg:tt)*) => ($crate::log!($crate::klog::Level::Error, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! log_warn {
+    ($($arg:tt)*) => ($crate::log!($crate::klog::Level::Warn, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! log_info {
+    ($($arg:tt)*) => ($crate::log!($crate::klog::Level::Info, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! log_debug {
+    ($($arg:tt)*) => ($crate::log!($crate::klog::Level::Debug, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! log_trace {
+    ($($arg:tt)*) => ($crate::log!($crate::klog::Level::Trace, $($arg)*));
+}
+
+#[test_case]
+fn test_level_order() {
+    assert!(Level::Error < Level::Info);
+    assert_eq!(Level::parse("debug"), Some(Level::Debug));
+    assert_eq!(Level::parse("verbose"), None);
+}
+
+#[test_case]
+fn test_recent_lines() {
+    use alloc::vec::Vec;
+    crate::log_error!("test_recent_lines {}", "x".repeat(2 * HISTORY_LINE_SIZE));
+    let mut lines = Vec::new();
+    recent_lines(|line| lines.push(line.to_vec()));
+    let last = lines.last().unwrap();
+    assert!(last.starts_with(b"[ERROR] test_recent_lines xxx"));
+    assert_eq!(last.len(), HISTORY_LINE_SIZE);
+}
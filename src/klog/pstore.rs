@@ -0,0 +1,176 @@
+//! The kernel log kept in RAM across reboots, like Linux's pstore/ramoops.
+//!
+//! A region the frame allocator leaves alone holds two rings, for this boot's
+//! log and the previous boot's. RAM keeps its contents over a reset (`reboot`,
+//! a triple fault, QEMU's `system_reset`) but not a power cycle, so after a
+//! crash that rebooted the machine, `dmesg -p` shows what led to it. The
+//! region is at the same place on every boot as long as the memory map is the
+//! same.
+
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use core::ops::Range;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use crate::boot::info::BootInformation;
+
+/// Bytes taken from the top of memory.
+pub const SIZE: u64 = 64 * 1024;
+/// Left between the region and the end of memory, which the firmware may
+/// use while it starts again.
+const MARGIN: u64 = 8 * 1024 * 1024;
+/// Where the header ends and the rings start.
+const HEADER_SIZE: usize = 4096;
+const RING_SIZE: usize = (SIZE as usize - HEADER_SIZE) / 2;
+const MAGIC: u64 = u64::from_le_bytes(*b"MAROSLOG");
+
+/// The start of the region.
+#[repr(C)]
+struct Header {
+    magic: AtomicU64,
+    /// This boot's ring, 0 or 1.
+    current: AtomicU64,
+    /// Bytes ever written to each ring.
+    written: [AtomicU64; 2],
+    /// Boots that found the region, counting this one.
+    boots: AtomicU64,
+}
+
+/// Virtual address of the region, 0 without one.
+static REGION: AtomicUsize = AtomicUsize::new(0);
+/// Its physical address.
+static PHYS: AtomicU64 = AtomicU64::new(0);
+
+/// Takes the region out of the usable memory of `info`, makes the ring the
+/// last boot wrote to the previous one and starts an empty ring for this boot.
+pub fn reserve(info: &mut BootInformation) {
+    let phys = match info.reserve_high(SIZE, MARGIN) {
+        Some(phys) => phys,
+        None => return,
+    };
+    let virt = (info.physical_memory_offset + phys) as usize;
+    let header = unsafe { &*(virt as *const Header) };
+    let current = header.current.load(Ordering::Relaxed);
+    if header.magic.load(Ordering::Relaxed) == MAGIC && current < 2 {
+        header.current.store(1 - current, Ordering::Relaxed);
+        header.boots.fetch_add(1, Ordering::Relaxed);
+    } else {
+        // powered on, or the memory map changed
+        header.written[0].store(0, Ordering::Relaxed);
+        header.written[1].store(0, Ordering::Relaxed);
+        header.current.store(0, Ordering::Relaxed);
+        header.boots.store(1, Ordering::Relaxed);
+        header.magic.store(MAGIC, Ordering::Relaxed);
+    }
+    header.written[header.current.load(Ordering::Relaxed) as usize].store(0, Ordering::Relaxed);
+    PHYS.store(phys, Ordering::Relaxed);
+    REGION.store(virt, Ordering::Release);
+}
+
+/// The physical memory of the region, if there is one.
+pub fn region() -> Option<Range<u64>> {
+    header()?;
+    let phys = PHYS.load(Ordering::Relaxed);
+    Some(phys..phys + SIZE)
+}
+
+fn header() -> Option<&'static Header> {
+    match REGION.load(Ordering::Acquire) {
+        0 => None,
+        virt => Some(unsafe { &*(virt as *const Header) }),
+    }
+}
+
+fn ring(index: u64) -> *mut u8 {
+    (REGION.load(Ordering::Acquire) + HEADER_SIZE + index as usize * RING_SIZE) as *mut u8
+}
+
+/// Appends `args` to this boot's ring, without taking any lock.
+pub fn record(args: fmt::Arguments) {
+    let header = match header() {
+        Some(header) => header,
+        None => return,
+    };
+    let mut count = Count(0);
+    let _ = count.write_fmt(args);
+    let len = count.0.min(RING_SIZE);
+    let current = header.current.load(Ordering::Relaxed);
+    // writers append to the space they claimed
+    let start = header.written[current as usize].fetch_add(len as u64, Ordering::Relaxed);
+    let mut out = RingWriter { ring: ring(current), pos: start as usize, left: len };
+    let _ = out.write_fmt(args);
+    // formatting came out shorter the second time
+    while out.left > 0 {
+        out.put(b' ');
+    }
+}
+
+/// This boot's log, or the previous boot's, oldest line first.
+pub fn read(previous: bool) -> Option<Vec<u8>> {
+    let header = header()?;
+    let mut index = header.current.load(Ordering::Relaxed);
+    if previous {
+        index = 1 - index;
+    }
+    let written = header.written[index as usize].load(Ordering::Relaxed) as usize;
+    if previous && written == 0 {
+        return None;
+    }
+    let ring = unsafe { core::slice::from_raw_parts(ring(index), RING_SIZE) };
+    if written <= RING_SIZE {
+        return Some(ring[..written].to_vec());
+    }
+    let mut log = Vec::with_capacity(RING_SIZE);
+    log.extend_from_slice(&ring[written % RING_SIZE..]);
+    log.extend_from_slice(&ring[..written % RING_SIZE]);
+    // the oldest line was partly overwritten
+    let start = log.iter().position(|&byte| byte == b'\n').map_or(0, |newline| newline + 1);
+    Some(log.split_off(start))
+}
+
+/// Boots since the machine was powered on, as far as the region knows.
+pub fn boots() -> u64 {
+    header().map_or(0, |header| header.boots.load(Ordering::Relaxed))
+}
+
+struct Count(usize);
+
+impl Write for Count {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0 += s.len();
+        Ok(())
+    }
+}
+
+struct RingWriter {
+    ring: *mut u8,
+    pos: usize,
+    /// Bytes left of the space claimed.
+    left: usize,
+}
+
+impl RingWriter {
+    fn put(&mut self, byte: u8) {
+        unsafe { self.ring.add(self.pos % RING_SIZE).write_volatile(byte) };
+        self.pos += 1;
+        self.left -= 1;
+    }
+}
+
+impl Write for RingWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes().iter().take(self.left) {
+            self.put(byte);
+        }
+        Ok(())
+    }
+}
+
+#[test_case]
+fn test_record() {
+    if header().is_none() {
+        return;
+    }
+    record(format_args!("pstore test line {}\n", 7));
+    let log = read(false).unwrap();
+    assert!(alloc::string::String::from_utf8_lossy(&log).lines().any(|line| line == "pstore test line 7"));
+}
@@ -12,38 +12,263 @@
 
 pub mod serial;
 pub mod vga_buffer;
-pub mod interrupts;
-pub mod gdt;
+pub mod console;
+pub mod arch;
 pub mod memory;
+pub mod mmio;
+pub mod acpi;
 pub mod allocator;
+pub mod fs;
+pub mod rand;
+pub mod time;
+pub mod profiler;
+pub mod watchdog;
+pub mod bench;
+pub mod shell;
+pub mod debug;
+pub mod crashdump;
+pub mod symbols;
+pub mod aslr;
+pub mod klog;
+pub mod boot;
+pub mod initcall;
+pub mod drivers;
+pub mod statusbar;
+pub mod top;
+pub mod tui;
+pub mod keyboard;
+pub mod mouse;
+pub mod editor;
+pub mod task;
+pub mod sched;
+pub mod workqueue;
+pub mod ipc;
+pub mod shm;
+pub mod futex;
+pub mod pipe;
+pub mod signal;
+pub mod process;
+#[cfg(feature = "net")]
+pub mod net;
+#[cfg(feature = "gdbstub")]
+pub mod gdbstub;
 
 extern crate alloc;
 
 use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use core::time::Duration;
 
 pub trait Testable {
     fn run(&self) -> ();
+
+    /// The name the test is reported and filtered by.
+    fn name(&self) -> &'static str;
+
+    /// Whether the test passes by panicking.
+    fn should_panic(&self) -> bool {
+        false
+    }
 }
 
 impl<T> Testable for T where T: Fn(),  {
     fn run(&self) -> () {
-        serial_print!("{}...\t", core::any::type_name::<T>());
+        let name = self.name();
+        serial_print!("{}...\t", name);
+        arm_test_deadline(name);
         self();
+        TEST_DEADLINE.store(0, Ordering::SeqCst);
         serial_println!("[ok]");
+        report_result(name, TestStatus::Passed);
+    }
+
+    fn name(&self) -> &'static str {
+        core::any::type_name::<T>()
+    }
+}
+
+/// A test that passes only if it panics. Declare it with `should_panic_test!`.
+pub struct ShouldPanic(pub &'static str, pub fn());
+
+impl Testable for ShouldPanic {
+    fn run(&self) -> () {
+        serial_print!("{}...\t", self.0);
+        arm_test_deadline(self.0);
+        (self.1)();
+        TEST_DEADLINE.store(0, Ordering::SeqCst);
+        serial_println!("[failed]\n");
+        serial_println!("Error: {} did not panic\n", self.0);
+        report_result(self.0, TestStatus::Failed);
+        exit_qemu(QemuExitCode::Failed);
+        hlt_loop()
+    }
+
+    fn name(&self) -> &'static str {
+        self.0
+    }
+
+    fn should_panic(&self) -> bool {
+        true
+    }
+}
+
+/// Declares a `#[test_case]` that passes only if `$body` panics.
+///
+/// The panic handler cannot unwind, so it resumes the test run at the next test
+/// instead; a test that panics while in an interrupt handler therefore continues
+/// the run with interrupts disabled.
+#[macro_export]
+macro_rules! should_panic_test {
+    ($name:ident, $body:block) => {
+        #[test_case]
+        #[allow(non_upper_case_globals)]
+        const $name: $crate::ShouldPanic = $crate::ShouldPanic(
+            concat!(module_path!(), "::", stringify!($name)),
+            || $body,
+        );
+    };
+}
+
+/// How a test ended, as reported in its result line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TestStatus {
+    Passed,
+    Failed,
+    TimedOut,
+    /// Not run because `test=<pattern>` doesn't select it.
+    Skipped,
+}
+
+impl TestStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TestStatus::Passed => "passed",
+            TestStatus::Failed => "failed",
+            TestStatus::TimedOut => "timed-out",
+            TestStatus::Skipped => "skipped",
+        }
+    }
+}
+
+/// Starts the line reporting the result of a test, for external runners.
+///
+/// After the human readable output of every test, the runner prints a line of
+/// the form `@test-result name=<name> status=<status> duration_us=<n>`, with
+/// the status from `TestStatus::as_str`. Test names contain no spaces.
+pub const RESULT_PREFIX: &str = "@test-result";
+
+/// When the running test started.
+static TEST_START: AtomicU64 = AtomicU64::new(0);
+
+/// Prints the result line of the test `name`, see `RESULT_PREFIX`.
+pub fn report_result(name: &str, status: TestStatus) {
+    let duration = match status {
+        TestStatus::Skipped => Duration::ZERO,
+        _ => time::Instant::now().duration_since(time::Instant::from_ticks(TEST_START.load(Ordering::SeqCst))),
+    };
+    serial_println!("{} name={} status={} duration_us={}", RESULT_PREFIX, name, status.as_str(), duration.as_micros());
+}
+
+/// Maximum time a single test may run before the harness reports it as hung.
+///
+/// Enforced from the timer interrupt, so it only catches hangs with interrupts
+/// enabled, after `init` has run.
+pub const TEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// TSC value after which the running test times out (0 if no test is running).
+static TEST_DEADLINE: AtomicU64 = AtomicU64::new(0);
+static CURRENT_TEST: spin::Mutex<&'static str> = spin::Mutex::new("");
+
+fn arm_test_deadline(name: &'static str) {
+    *CURRENT_TEST.lock() = name;
+    if time::tsc_frequency() != 0 {
+        if let Some(deadline) = time::Instant::now().checked_add(TEST_TIMEOUT) {
+            TEST_DEADLINE.store(deadline.ticks(), Ordering::SeqCst);
+        }
+    }
+}
+
+/// The name of the running test, or `<unknown>` if it can't be told.
+fn current_test() -> &'static str {
+    CURRENT_TEST.try_lock().map(|name| *name).unwrap_or("<unknown>")
+}
+
+/// Called from the timer interrupt: fails the run if the current test is past its deadline.
+pub fn check_test_deadline() {
+    let deadline = TEST_DEADLINE.load(Ordering::SeqCst);
+    if deadline == 0 || time::rdtsc() < deadline {
+        return;
     }
+    TEST_DEADLINE.store(0, Ordering::SeqCst);
+    // the hung test may hold the serial lock
+    if serial::SERIAL1.is_locked() {
+        unsafe { serial::SERIAL1.force_unlock() };
+    }
+    let name = current_test();
+    serial_println!("[timed out]\n");
+    serial_println!("Error: {} did not finish within {:?}\n", name, TEST_TIMEOUT);
+    report_result(name, TestStatus::TimedOut);
+    exit_qemu(QemuExitCode::TimedOut);
+    hlt_loop()
 }
 
+/// The test list of the current run, kept so the panic handler can resume it.
+static TESTS_PTR: AtomicUsize = AtomicUsize::new(0);
+static TESTS_LEN: AtomicUsize = AtomicUsize::new(0);
+/// Index of the test after the one that is running.
+static NEXT_TEST: AtomicUsize = AtomicUsize::new(0);
+static EXPECT_PANIC: AtomicBool = AtomicBool::new(false);
+/// Tests of the current run that were not skipped.
+static TESTS_RUN: AtomicUsize = AtomicUsize::new(0);
+
 pub fn test_runner(tests: &[&dyn Testable]) {
     serial_println!("Running {} tests", tests.len());
-    for test in tests{
-       test.run(); 
+    TESTS_PTR.store(tests.as_ptr() as usize, Ordering::SeqCst);
+    TESTS_LEN.store(tests.len(), Ordering::SeqCst);
+    run_tests_from(0);
+} // tests is a list of closures which only take object as references.#[cfg(test)]
+
+fn run_tests_from(first: usize) -> ! {
+    // the list is the static array generated by the test harness, so it outlives
+    // every call of this function
+    let tests = unsafe {
+        core::slice::from_raw_parts(
+            TESTS_PTR.load(Ordering::SeqCst) as *const &dyn Testable,
+            TESTS_LEN.load(Ordering::SeqCst),
+        )
+    };
+    // `test=<pattern>` on the kernel command line selects the tests to run
+    let filter = boot::cmdline().get("test").unwrap_or("");
+    for (i, test) in tests.iter().enumerate().skip(first) {
+        if !test.name().contains(filter) {
+            report_result(test.name(), TestStatus::Skipped);
+            continue;
+        }
+        NEXT_TEST.store(i + 1, Ordering::SeqCst);
+        EXPECT_PANIC.store(test.should_panic(), Ordering::SeqCst);
+        TESTS_RUN.fetch_add(1, Ordering::SeqCst);
+        TEST_START.store(time::rdtsc(), Ordering::SeqCst);
+        test.run();
+    }
+    EXPECT_PANIC.store(false, Ordering::SeqCst);
+    if TESTS_RUN.load(Ordering::SeqCst) == 0 && !tests.is_empty() {
+        exit_qemu(QemuExitCode::Skipped);
     }
     exit_qemu(QemuExitCode::Success);
-} // tests is a list of closures which only take object as references.#[cfg(test)]
+    hlt_loop()
+}
 
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
+    if EXPECT_PANIC.swap(false, Ordering::SeqCst) {
+        TEST_DEADLINE.store(0, Ordering::SeqCst);
+        serial_println!("[ok]");
+        report_result(current_test(), TestStatus::Passed);
+        // the panicking frames are abandoned; continue on top of them
+        run_tests_from(NEXT_TEST.load(Ordering::SeqCst));
+    }
     serial_println!("[failed]\n");
     serial_println!("Error: {}\n", info);
+    report_result(current_test(), TestStatus::Failed);
     exit_qemu(QemuExitCode::Failed);
     hlt_loop()
 }
@@ -60,32 +285,79 @@ fn panic(info: &PanicInfo) -> ! {
 pub enum QemuExitCode {
     Success = 0x10,
     Failed = 0x11,
+    TimedOut = 0x12,
+    /// `test=<pattern>` selected none of the tests.
+    Skipped = 0x13,
 }
 
 pub fn exit_qemu(exit_code: QemuExitCode) {
-    use x86_64::instructions::port::Port;
-
-    unsafe {
-        let mut port = Port::new(0xf4);
-        port.write(exit_code as u32);
+    serial::flush();
+    // QEMU's isa-debug-exit device, see the test arguments in Cargo.toml
+    if let Ok(port) = arch::x86_64::ioport::claim(0xf4, 4, "qemu-exit") {
+        port.write(0, exit_code as u32);
     }
 }
 
+/// Runs the early init calls and enables interrupts. The rest of the kernel
+/// comes up once there is a heap, with the other levels of `initcall`.
 pub fn init() {
     use vga_buffer::WRITER;
-    gdt::init();
-    interrupts::init_idt();
-    unsafe {interrupts::PICS.lock().initialize();}
-    x86_64::instructions::interrupts::enable();
     WRITER.lock().clear_all();
+    initcall::run(initcall::Level::Early);
+    arch::interrupts::enable();
 }
 
 pub fn hlt_loop() -> ! {
     loop {
-        x86_64::instructions::hlt();
+        arch::halt();
     }
 }
 
+/// Resets the machine by pulsing the reset line of the keyboard controller.
+pub fn reboot() -> ! {
+    arch::interrupts::disable();
+    if let Ok(port) = arch::x86_64::ioport::claim(0x64, 1, "reboot") {
+        port.write(0, 0xfe_u8);
+    }
+    hlt_loop()
+}
+
+/// The start of a panic message, for `expect_panic_handler`.
+struct PanicMessage {
+    bytes: [u8; 256],
+    len: usize,
+}
+
+impl core::fmt::Write for PanicMessage {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let n = s.len().min(self.bytes.len() - self.len);
+        self.bytes[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+/// Panic handler of test binaries whose test `name` passes by panicking with
+/// a message that contains `expected`, like the report of an exception handler.
+pub fn expect_panic_handler(name: &'static str, info: &PanicInfo, expected: &str) -> ! {
+    use core::fmt::Write;
+
+    let mut message = PanicMessage { bytes: [0; 256], len: 0 };
+    let _ = write!(message, "{}", info);
+    let message = &message.bytes[..message.len];
+    if message.windows(expected.len()).any(|window| window == expected.as_bytes()) {
+        serial_println!("[ok]");
+        report_result(name, TestStatus::Passed);
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!("Error: {}\nexpected: {}\n", info, expected);
+        report_result(name, TestStatus::Failed);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    hlt_loop()
+}
+
 #[cfg(test)]
 use bootloader::{entry_point, BootInfo};
 use crate::vga_buffer::Writer;
@@ -95,9 +367,22 @@ entry_point!(test_kernel_main);
 
 /// Entry point for `cargo test`
 #[cfg(test)]
-fn test_kernel_main(_boot_info: &'static BootInfo) -> ! {
-    // like before
+fn test_kernel_main(boot_info: &'static BootInfo) -> ! {
+    use x86_64::VirtAddr;
+    use boot::info::BootInformation;
+    use memory::BootInfoFrameAllocator;
+
+    let boot_info = boot::set_info(BootInformation::from(boot_info));
     init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(boot_info.memory_regions())
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+    initcall::run(initcall::Level::Core);
     test_main();
     hlt_loop();
 }
@@ -7,7 +7,12 @@
 use core::panic::PanicInfo;
 use bootloader::{BootInfo, entry_point};
 use x86_64::VirtAddr;
-use MarOS::{allocator, hlt_loop, memory, println};
+use core::time::Duration;
+use MarOS::{allocator, boot, initcall, memory, println, shell, watchdog};
+use MarOS::boot::info::BootInformation;
+use MarOS::boot::progress::{self, stage, try_stage};
+use MarOS::initcall::Level;
+use MarOS::drivers::speaker;
 use MarOS::memory::BootInfoFrameAllocator;
 
 extern crate alloc;
@@ -15,17 +20,32 @@ extern crate alloc;
 entry_point!(kernel_main);
 
  fn kernel_main(boot_info: &'static BootInfo) -> ! {
+     kernel_start(boot::set_info(BootInformation::from(boot_info)))
+ }
+
+/// Boots the kernel, independently of the boot loader that started it.
+ fn kernel_start(boot_info: &'static BootInformation) -> ! {
      MarOS::init();
      println!("MarOS");
 
      let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-     let mut mapper = unsafe { memory::init(phys_mem_offset)};
+     let mut mapper = stage("memory", || unsafe { memory::init(phys_mem_offset) });
      let mut frame_allocator = unsafe {
-         BootInfoFrameAllocator::init(&boot_info.memory_map)
+         BootInfoFrameAllocator::init(boot_info.memory_regions())
      };
 
-     allocator::init_heap(&mut mapper, &mut frame_allocator)
-         .expect("heap initialization failed");
+     try_stage("heap", || {
+         allocator::init_heap(&mut mapper, &mut frame_allocator).map_err(|_| "mapping the heap failed")
+     }).expect("heap initialization failed");
+     memory::install(mapper, frame_allocator);
+     initcall::run(Level::Core);
+
+     let boot_watchdog = watchdog::register("boot", Duration::from_secs(5));
+     initcall::run(Level::Driver);
+     initcall::run(Level::Late);
+     progress::finish();
+     drop(boot_watchdog);
+     speaker::play_melody(speaker::BOOT_JINGLE);
 
      // // allocate a number on the heap
      // let heap_value = Box::new(41);
@@ -73,14 +93,27 @@ entry_point!(kernel_main);
      #[cfg(test)]
      test_main();
 
-     hlt_loop()
+     shell::run()
  }
 
+/// Entry point when started by a Multiboot2 loader, see `MarOS::boot::multiboot2`.
+#[cfg(feature = "multiboot2")]
+#[no_mangle]
+extern "C" fn multiboot2_main(info_addr: u64) -> ! {
+    use MarOS::boot::multiboot2::{self, PHYSICAL_MEMORY_OFFSET};
+
+    let info = unsafe { multiboot2::info_slice(info_addr, PHYSICAL_MEMORY_OFFSET) };
+    let boot_info = multiboot2::parse(info, PHYSICAL_MEMORY_OFFSET)
+        .expect("invalid multiboot2 boot information");
+    kernel_start(boot::set_info(boot_info))
+}
+
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    println!("{}", info);
-    hlt_loop()
+    MarOS::debug::report_panic(info);
+    speaker::beep_spinning(220, Duration::from_millis(500));
+    MarOS::hlt_loop()
 }
 
 #[cfg(test)]
@@ -3,7 +3,21 @@ use x86_64::{
     VirtAddr,
     PhysAddr
 };
-use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PhysFrame, Size4KiB};
+use core::ops::Range;
+use core::sync::atomic::{AtomicU64, Ordering};
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use crate::arch::interrupts::without_interrupts;
+use crate::arch::paging;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB};
+use spin::Mutex;
+use crate::println;
+
+pub mod fault;
+pub mod pressure;
+pub mod slab;
+
+pub use self::pressure::{pressure, Pressure};
 
 mod deprecated {
 // /// Translates the given virtual address to the mapped physical address, or
@@ -53,9 +67,7 @@ mod deprecated {
 /// `physical_memory_offset`. Also, this function must be only called once
 /// to avoid aliasing `&mut` references (which is undefined behavior).
 unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
-    use x86_64::registers::control::Cr3;
-
-    let (level_4_table_frame, _) = Cr3::read();
+    let level_4_table_frame = paging::active_table();
     let phys = level_4_table_frame.start_address();
     let virt = physical_memory_offset + phys.as_u64();
     let page_table_ptr: *mut PageTable = virt.as_mut_ptr();
@@ -70,10 +82,371 @@ use x86_64::structures::paging::OffsetPageTable;
 /// `physical_memory_offset`. Also, this function must be only called once
 /// to avoid aliasing `&mut` references (which is undefined behavior).
 pub unsafe fn init(phys_mem_offset: VirtAddr) -> OffsetPageTable<'static> {
+    PHYSICAL_MEMORY_OFFSET.store(phys_mem_offset.as_u64(), Ordering::Relaxed);
+    KERNEL_PAGE_TABLE.store(paging::active_table().start_address().as_u64(), Ordering::Relaxed);
     let level_4_table = active_level_4_table(phys_mem_offset);
     OffsetPageTable::new(level_4_table,phys_mem_offset)
 }
 
+/// The kernel's page table mapper, available once `install` has been called.
+pub static MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
+/// The kernel's physical frame allocator, available once `install` has been called.
+pub static FRAME_ALLOCATOR: Mutex<Option<BootInfoFrameAllocator>> = Mutex::new(None);
+
+/// Hands the mapper and frame allocator created during boot over to the kernel,
+/// so code that doesn't get them passed in (like the page fault handler) can use them.
+pub fn install(mapper: OffsetPageTable<'static>, frame_allocator: BootInfoFrameAllocator) {
+    *MAPPER.lock() = Some(mapper);
+    *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+}
+
+/// Usage of the physical frame allocator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameStats {
+    /// Usable frames in the memory map.
+    pub total: usize,
+    /// Frames handed out and not given back.
+    pub allocated: usize,
+}
+
+/// Usage of the physical frame allocator, or `None` before `install`.
+pub fn frame_stats() -> Option<FrameStats> {
+    without_interrupts(|| FRAME_ALLOCATOR.lock().as_ref().map(BootInfoFrameAllocator::stats))
+}
+
+/// Physically contiguous, zeroed memory for device DMA, accessed through the
+/// physical memory mapping.
+#[derive(Debug, Clone, Copy)]
+pub struct DmaRegion {
+    pub phys: PhysAddr,
+    pub virt: VirtAddr,
+    pub size: u64,
+}
+
+/// Allocates `size` bytes of physically contiguous memory below 4 GiB, so
+/// devices with 32-bit DMA addresses can reach it. The frames are never freed.
+pub fn allocate_dma(size: u64) -> Result<DmaRegion, &'static str> {
+    let pages = ((size + 0xfff) / 0x1000).max(1);
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    let allocator = allocator.as_mut().ok_or("frame allocator not installed")?;
+
+    // take frames until the last `pages` of them are contiguous, restarting the
+    // run at every gap, then give back the ones before the run
+    let mut taken: Vec<PhysFrame> = Vec::new();
+    let mut run = 0;
+    let found = loop {
+        let frame = match allocator.allocate_frame() {
+            Some(frame) => frame,
+            None => break Err("out of physical memory"),
+        };
+        if taken.last().map_or(false, |&last| frame != last + 1) {
+            run = taken.len();
+        }
+        taken.push(frame);
+        if (taken.len() - run) as u64 == pages {
+            break match taken[run].start_address().as_u64() + pages * 0x1000 {
+                end if end > 1 << 32 => Err("no contiguous memory below 4 GiB"),
+                _ => Ok(taken[run]),
+            };
+        }
+    };
+    let skipped = if found.is_ok() { run } else { taken.len() };
+    taken[..skipped].iter().for_each(|&frame| unsafe { allocator.deallocate_frame(frame) });
+    let phys = found?.start_address();
+    let virt = physical_memory_offset() + phys.as_u64();
+    unsafe { core::ptr::write_bytes(virt.as_mut_ptr::<u8>(), 0, (pages * 0x1000) as usize) };
+    Ok(DmaRegion { phys, virt, size: pages * 0x1000 })
+}
+
+/// Maps zeroed frames at every page of `range`. The frames are never freed.
+pub fn map_range(range: Range<VirtAddr>, flags: PageTableFlags) -> Result<(), &'static str> {
+    let mut mapper = MAPPER.lock();
+    let mapper = mapper.as_mut().ok_or("memory management is not initialized")?;
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    let allocator = allocator.as_mut().ok_or("frame allocator not installed")?;
+
+    let start: Page<Size4KiB> = Page::containing_address(range.start);
+    let end: Page<Size4KiB> = Page::containing_address(range.end - 1u64);
+    for page in Page::range_inclusive(start, end) {
+        let frame = allocator.allocate_frame().ok_or("out of physical memory")?;
+        unsafe {
+            let virt = physical_memory_offset() + frame.start_address().as_u64();
+            core::ptr::write_bytes(virt.as_mut_ptr::<u8>(), 0, 0x1000);
+            mapper.map_to(page, frame, flags | PageTableFlags::PRESENT, allocator)
+                .map_err(|_| "mapping the page failed")?
+                .flush();
+        }
+    }
+    Ok(())
+}
+
+/// Allocates `count` zeroed frames, which need not be contiguous. Give them
+/// back with `free_frames`. When there are not enough free frames, memory is
+/// reclaimed (see `pressure`) and the allocation tried once more.
+pub fn allocate_frames(count: usize) -> Result<Vec<PhysFrame>, &'static str> {
+    let frames = match take_frames(count)? {
+        Some(frames) => frames,
+        None if pressure::reclaim(count) => take_frames(count)?.ok_or("out of physical memory")?,
+        None => return Err("out of physical memory"),
+    };
+    for frame in &frames {
+        let virt = physical_memory_offset() + frame.start_address().as_u64();
+        unsafe { core::ptr::write_bytes(virt.as_mut_ptr::<u8>(), 0, 0x1000) };
+    }
+    Ok(frames)
+}
+
+/// Takes `count` frames from the allocator, or none if it doesn't have as many.
+fn take_frames(count: usize) -> Result<Option<Vec<PhysFrame>>, &'static str> {
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    let allocator = allocator.as_mut().ok_or("frame allocator not installed")?;
+    let mut frames = Vec::with_capacity(count);
+    for _ in 0..count {
+        match allocator.allocate_frame() {
+            Some(frame) => frames.push(frame),
+            None => {
+                frames.into_iter().for_each(|frame| unsafe { allocator.deallocate_frame(frame) });
+                return Ok(None);
+            }
+        }
+    }
+    Ok(Some(frames))
+}
+
+/// Returns frames from `allocate_frames` for reuse.
+///
+/// The caller must guarantee that none of them is mapped anymore.
+pub unsafe fn free_frames(frames: impl IntoIterator<Item = PhysFrame>) {
+    if let Some(allocator) = FRAME_ALLOCATOR.lock().as_mut() {
+        frames.into_iter().for_each(|frame| allocator.deallocate_frame(frame));
+    }
+}
+
+/// Maps `frames` at consecutive pages starting at `start`, which must be page aligned.
+pub fn map_frames(start: VirtAddr, frames: &[PhysFrame], flags: PageTableFlags) -> Result<(), &'static str> {
+    let mut mapper = MAPPER.lock();
+    let mapper = mapper.as_mut().ok_or("memory management is not initialized")?;
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    let allocator = allocator.as_mut().ok_or("frame allocator not installed")?;
+
+    let first: Page<Size4KiB> = Page::from_start_address(start).map_err(|_| "start is not page aligned")?;
+    for (page, &frame) in Page::range(first, first + frames.len() as u64).zip(frames) {
+        unsafe {
+            mapper.map_to(page, frame, flags | PageTableFlags::PRESENT, allocator)
+                .map_err(|_| "mapping the page failed")?
+                .flush();
+        }
+    }
+    Ok(())
+}
+
+/// The virtual address of the `size` bytes of device registers at `phys`, in
+/// the physical memory mapping. Pages the boot loader did not map there are
+/// mapped uncached.
+pub fn map_mmio(phys: PhysAddr, size: u64) -> Result<VirtAddr, &'static str> {
+    let mut mapper = MAPPER.lock();
+    let mapper = mapper.as_mut().ok_or("memory management is not initialized")?;
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    let allocator = allocator.as_mut().ok_or("frame allocator not installed")?;
+
+    let virt = physical_memory_offset() + phys.as_u64();
+    let first: PhysFrame<Size4KiB> = PhysFrame::containing_address(phys);
+    let last: PhysFrame<Size4KiB> = PhysFrame::containing_address(phys + size.max(1) - 1u64);
+    for frame in PhysFrame::range_inclusive(first, last) {
+        let page = Page::containing_address(physical_memory_offset() + frame.start_address().as_u64());
+        if mapping_of(page.start_address()).is_some() {
+            continue;
+        }
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE;
+        unsafe {
+            mapper.map_to(page, frame, flags, allocator)
+                .map_err(|_| "mapping the registers failed")?
+                .flush();
+        }
+    }
+    Ok(virt)
+}
+
+/// Unmaps every mapped page of `range`. The frames are left to their owner.
+pub fn unmap_range(range: Range<VirtAddr>) {
+    let mut mapper = MAPPER.lock();
+    let mapper = match mapper.as_mut() {
+        Some(mapper) => mapper,
+        None => return,
+    };
+    let start: Page<Size4KiB> = Page::containing_address(range.start);
+    let end: Page<Size4KiB> = Page::containing_address(range.end - 1u64);
+    for page in Page::range_inclusive(start, end) {
+        if let Ok((_, flush)) = mapper.unmap(page) {
+            flush.flush();
+        }
+    }
+}
+
+/// Allocates a level 4 table for the top-level slot of `addr` in the kernel's
+/// page tables, unless there is one.
+///
+/// Process address spaces copy the kernel's top-level entries when they are
+/// created, so kernel memory that is used while a process runs must be in
+/// slots that existed by then.
+pub fn reserve_kernel_slot(addr: VirtAddr) -> Result<(), &'static str> {
+    let mut mapper = MAPPER.lock();
+    let mapper = mapper.as_mut().ok_or("memory management is not initialized")?;
+    let entry = &mut mapper.level_4_table()[addr.p4_index()];
+    if entry.is_unused() {
+        let frame = allocate_frames(1)?[0];
+        entry.set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+    }
+    Ok(())
+}
+
+/// Extra references to frames that are mapped more than once, like the pages
+/// of a forked process. Locked with interrupts disabled, so the page fault
+/// handler can take it.
+static FRAME_SHARES: Mutex<BTreeMap<PhysFrame, usize>> = Mutex::new(BTreeMap::new());
+
+/// Records one more reference to `frame`.
+pub fn share_frame(frame: PhysFrame) {
+    without_interrupts(|| *FRAME_SHARES.lock().entry(frame).or_insert(0) += 1);
+}
+
+/// Whether `frame` is referenced more than once.
+pub fn is_frame_shared(frame: PhysFrame) -> bool {
+    without_interrupts(|| FRAME_SHARES.lock().contains_key(&frame))
+}
+
+/// Drops one reference to `frame`, returning whether it was the last one, in
+/// which case the caller owns the frame and may free it.
+pub fn unshare_frame(frame: PhysFrame) -> bool {
+    without_interrupts(|| {
+        let mut shares = FRAME_SHARES.lock();
+        match shares.get_mut(&frame) {
+            Some(1) => {
+                shares.remove(&frame);
+                false
+            }
+            Some(count) => {
+                *count -= 1;
+                false
+            }
+            None => true,
+        }
+    })
+}
+
+/// The level 4 table the kernel booted with, which kernel threads run on.
+pub fn kernel_page_table() -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(KERNEL_PAGE_TABLE.load(Ordering::Relaxed)))
+}
+
+/// Returns the page table in `frame` through the physical memory mapping.
+///
+/// The caller must make sure there are no other references to the table.
+pub unsafe fn page_table(frame: PhysFrame) -> &'static mut PageTable {
+    let virt = physical_memory_offset() + frame.start_address().as_u64();
+    &mut *virt.as_mut_ptr::<PageTable>()
+}
+
+static KERNEL_PAGE_TABLE: AtomicU64 = AtomicU64::new(0);
+
+/// The virtual address at which the complete physical memory is mapped, set by `init`.
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+pub fn physical_memory_offset() -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed))
+}
+
+/// A single present leaf entry of the active page tables.
+#[derive(Debug, Clone, Copy)]
+pub struct Mapping {
+    pub virt: VirtAddr,
+    pub phys: PhysAddr,
+    /// Size of the page: 4 KiB, 2 MiB or 1 GiB.
+    pub size: u64,
+    pub flags: PageTableFlags,
+}
+
+/// Calls `f` for every mapped page overlapping the virtual address `range`, in
+/// ascending address order.
+///
+/// Walks the active tables through the physical memory mapping, so
+/// `init` must have been called.
+pub fn walk_mappings(range: Range<u64>, mut f: impl FnMut(Mapping)) {
+    let level_4_table_frame = paging::active_table();
+    unsafe { walk_table(level_4_table_frame.start_address(), 4, 0, &range, &mut f) };
+}
+
+/// Recursively visits the present entries of the table at physical address `table`.
+///
+/// `base` is the (not sign-extended) virtual address mapped by the first entry.
+unsafe fn walk_table(table: PhysAddr, level: u32, base: u64, range: &Range<u64>,
+                     f: &mut dyn FnMut(Mapping)) {
+    let virt = physical_memory_offset() + table.as_u64();
+    let table = &*(virt.as_ptr::<PageTable>());
+    let entry_size = 1u64 << (12 + 9 * (level - 1));
+
+    for (i, entry) in table.iter().enumerate() {
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        let entry_base = base + i as u64 * entry_size;
+        let virt = VirtAddr::new_truncate(entry_base);
+        if virt.as_u64().saturating_add(entry_size) <= range.start || virt.as_u64() >= range.end {
+            continue;
+        }
+        if level == 1 || flags.contains(PageTableFlags::HUGE_PAGE) {
+            f(Mapping { virt, phys: entry.addr(), size: entry_size, flags });
+        } else {
+            walk_table(entry.addr(), level - 1, entry_base, range, f);
+        }
+    }
+}
+
+/// Returns the mapping of the page containing `addr`, if it is mapped.
+pub fn mapping_of(addr: VirtAddr) -> Option<Mapping> {
+    let mut found = None;
+    walk_mappings(addr.as_u64()..addr.as_u64().saturating_add(1), |mapping| found = Some(mapping));
+    found
+}
+
+/// Prints the mappings in the virtual address `range`, merging neighbouring pages
+/// that map contiguous physical memory with the same flags.
+pub fn dump_mappings(range: Range<u64>) {
+    fn print_region(region: &Mapping, pages: u64) {
+        let flags = region.flags;
+        println!("{:016x}-{:016x} -> {:012x} {:>4} x{:<6} {}{}{}{}",
+            region.virt.as_u64(),
+            region.virt.as_u64().wrapping_add(region.size * pages),
+            region.phys.as_u64(),
+            match region.size { 0x1000 => "4K", 0x20_0000 => "2M", _ => "1G" },
+            pages,
+            if flags.contains(PageTableFlags::WRITABLE) { 'w' } else { 'r' },
+            if flags.contains(PageTableFlags::USER_ACCESSIBLE) { 'u' } else { 'k' },
+            if flags.contains(PageTableFlags::NO_EXECUTE) { '-' } else { 'x' },
+            if flags.contains(PageTableFlags::GLOBAL) { 'g' } else { ' ' });
+    }
+
+    let mut current: Option<(Mapping, u64)> = None;
+    walk_mappings(range, |mapping| {
+        if let Some((region, pages)) = current.as_mut() {
+            let length = region.size * *pages;
+            if region.size == mapping.size
+                && region.flags == mapping.flags
+                && region.virt.as_u64().wrapping_add(length) == mapping.virt.as_u64()
+                && region.phys.as_u64() + length == mapping.phys.as_u64() {
+                *pages += 1;
+                return;
+            }
+            print_region(region, *pages);
+        }
+        current = Some((mapping, 1));
+    });
+    if let Some((region, pages)) = current {
+        print_region(&region, pages);
+    }
+}
+
 
 /// Creates an example mapping for the given page to frame `0xb8000`.
 pub fn create_example_mapping(
@@ -104,12 +477,14 @@ unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
     }
 }
 
-use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use crate::boot::info::{MemoryKind, MemoryRegion};
 
-/// A FrameAllocator that returns usable frames from the bootloader's memory map.
+/// A FrameAllocator that returns usable frames from the boot loader's memory map.
 pub struct BootInfoFrameAllocator {
-    memory_map: &'static MemoryMap,
+    memory_regions: &'static [MemoryRegion],
     next: usize,
+    /// Frames given back with `deallocate_frame`, handed out again first.
+    recycled: Vec<PhysFrame>,
 }
 
 impl BootInfoFrameAllocator {
@@ -117,26 +492,35 @@ impl BootInfoFrameAllocator {
     ///
     /// This function is unsafe because the caller must guarantee that the passed
     /// memory map is valid. The main requirement is that all frames that are marked
-    /// as `USABLE` in it are really unused.
-    pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
+    /// as `Usable` in it are really unused.
+    pub unsafe fn init(memory_regions: &'static [MemoryRegion]) -> Self {
         BootInfoFrameAllocator {
-            memory_map,
+            memory_regions,
             next: 0,
+            recycled: Vec::new(),
         }
     }
 }
 
 
 impl BootInfoFrameAllocator {
+    fn stats(&self) -> FrameStats {
+        let total = self.memory_regions.iter()
+            .filter(|region| region.kind == MemoryKind::Usable)
+            .map(|region| ((region.end - region.start) / 4096) as usize)
+            .sum();
+        FrameStats { total, allocated: self.next.min(total) - self.recycled.len() }
+    }
+
     /// Returns an iterator over the usable frames specified in the memory map.
     fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
         // get usable regions from memory map
-        let regions = self.memory_map.iter();
+        let regions = self.memory_regions.iter();
         let usable_regions = regions
-            .filter(|r| r.region_type == MemoryRegionType::Usable);
+            .filter(|r| r.kind == MemoryKind::Usable);
         // map each region to its address range
         let addr_ranges = usable_regions
-            .map(|r| r.range.start_addr()..r.range.end_addr());
+            .map(|r| r.start..r.end);
         // transform to an iterator of frame start addresses
         let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
         // create `PhysFrame` types from the start addresses
@@ -147,17 +531,28 @@ impl BootInfoFrameAllocator {
 
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        if let Some(frame) = self.recycled.pop() {
+            return Some(frame);
+        }
         let frame = self.usable_frames().nth(self.next);
         self.next += 1;
         frame
     }
 }
 
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        self.recycled.push(frame);
+    }
+}
 
-
-
-
-
-
-
-
+#[test_case]
+fn test_allocate_dma_gives_back_skipped_frames() {
+    // a recycled frame comes out first and is not followed by its neighbour
+    let frames = allocate_frames(1).unwrap();
+    unsafe { free_frames(frames) };
+    let before = frame_stats().unwrap().allocated;
+    let region = allocate_dma(2 * 0x1000).unwrap();
+    assert_eq!(frame_stats().unwrap().allocated, before + 2);
+    assert!(region.phys.as_u64() + region.size <= 1 << 32);
+}
@@ -0,0 +1,187 @@
+use alloc::vec::Vec;
+use core::ops::Range;
+use spin::Mutex;
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::VirtAddr;
+use crate::memory::{mapping_of, physical_memory_offset, FRAME_ALLOCATOR, MAPPER};
+
+/// Software-defined page table bit marking a read-only page whose first write
+/// gives the writer a private copy.
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+/// A virtual address range whose pages are only mapped when first touched.
+struct LazyRegion {
+    range: Range<VirtAddr>,
+    flags: PageTableFlags,
+}
+
+static LAZY_REGIONS: Mutex<Vec<LazyRegion>> = Mutex::new(Vec::new());
+
+/// What caused a page fault, and therefore how it is handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultKind {
+    /// First access to a page of a demand-paged region: map a zeroed frame.
+    DemandPaging,
+    /// Write to a copy-on-write page: give the page a private copy.
+    CopyOnWrite,
+    /// Invalid access from user mode. Processes end on these before they are
+    /// classified (see `process::handle_page_fault`), so this is fatal.
+    User,
+    /// Invalid access from the kernel itself: always fatal.
+    Kernel,
+}
+
+/// Registers `range` to be mapped lazily with `flags` on first access.
+pub fn register_lazy_region(range: Range<VirtAddr>, flags: PageTableFlags) {
+    LAZY_REGIONS.lock().push(LazyRegion { range, flags: flags | PageTableFlags::PRESENT });
+}
+
+/// Removes the demand-paged region starting at `start`. Already mapped pages stay mapped.
+pub fn unregister_lazy_region(start: VirtAddr) {
+    LAZY_REGIONS.lock().retain(|region| region.range.start != start);
+}
+
+/// Returns the flags of the lazy region containing `addr`, if any.
+fn lazy_region_flags(addr: VirtAddr) -> Option<PageTableFlags> {
+    // a fault while the list is being modified can't be serviced
+    let regions = LAZY_REGIONS.try_lock()?;
+    regions.iter()
+        .find(|region| region.range.contains(&addr))
+        .map(|region| region.flags)
+}
+
+/// Classifies the page fault at `addr` with the given error code.
+pub fn classify(addr: VirtAddr, error_code: PageFaultErrorCode) -> FaultKind {
+    let present = error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION);
+    let write = error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE);
+
+    if !present && lazy_region_flags(addr).is_some() {
+        FaultKind::DemandPaging
+    } else if present && write && is_copy_on_write(addr) {
+        FaultKind::CopyOnWrite
+    } else if error_code.contains(PageFaultErrorCode::USER_MODE) {
+        FaultKind::User
+    } else {
+        FaultKind::Kernel
+    }
+}
+
+fn is_copy_on_write(addr: VirtAddr) -> bool {
+    match mapping_of(addr) {
+        Some(mapping) => mapping.size == 4096 && mapping.flags.contains(COPY_ON_WRITE),
+        None => false,
+    }
+}
+
+/// Resolves a `DemandPaging` or `CopyOnWrite` fault at `addr`.
+///
+/// Returns an error describing why the fault could not be serviced, in which case
+/// it has to be treated as fatal.
+pub fn service(kind: FaultKind, addr: VirtAddr) -> Result<(), &'static str> {
+    // the faulting code may hold these locks, so never wait for them here
+    let mut mapper = MAPPER.try_lock().ok_or("page tables are locked")?;
+    let mapper = mapper.as_mut().ok_or("memory management is not initialized")?;
+    let mut frame_allocator = FRAME_ALLOCATOR.try_lock().ok_or("frame allocator is locked")?;
+    let frame_allocator = frame_allocator.as_mut().ok_or("memory management is not initialized")?;
+
+    let page: Page<Size4KiB> = Page::containing_address(addr);
+    // validated before taking a frame, which a rejected fault would leak
+    let (flags, old_frame) = match kind {
+        FaultKind::DemandPaging => (lazy_region_flags(addr).ok_or("address is not demand-paged")?, None),
+        FaultKind::CopyOnWrite => {
+            let mapping = mapping_of(addr).ok_or("page is not mapped")?;
+            let flags = (mapping.flags - COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+            (flags, Some(PhysFrame::containing_address(mapping.phys)))
+        }
+        FaultKind::User | FaultKind::Kernel => return Err("fault is not recoverable"),
+    };
+    let frame = frame_allocator.allocate_frame().ok_or("out of physical frames")?;
+
+    unsafe {
+        match old_frame {
+            Some(old_frame) => {
+                core::ptr::copy_nonoverlapping(frame_ptr(old_frame), frame_ptr(frame), 4096);
+                // the old frame may still be shared with other mappings, so keep it
+                if mapper.unmap(page).map(|(_, flush)| flush.flush()).is_err() {
+                    frame_allocator.deallocate_frame(frame);
+                    return Err("unmapping the page failed");
+                }
+            }
+            None => frame_ptr(frame).write_bytes(0, 4096),
+        }
+        match mapper.map_to(page, frame, flags, frame_allocator) {
+            Ok(flush) => flush.flush(),
+            Err(_) => {
+                frame_allocator.deallocate_frame(frame);
+                return Err("mapping the page failed");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Makes the mapped 4 KiB `page` read-only so that the next write copies it.
+pub fn mark_copy_on_write(page: Page<Size4KiB>) -> Result<(), &'static str> {
+    let mapping = mapping_of(page.start_address()).ok_or("page is not mapped")?;
+    let flags = (mapping.flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE;
+    let mut mapper = MAPPER.lock();
+    let mapper = mapper.as_mut().ok_or("memory management is not initialized")?;
+    unsafe {
+        mapper.update_flags(page, flags)
+            .map_err(|_| "updating the page flags failed")?
+            .flush();
+    }
+    Ok(())
+}
+
+/// Returns a pointer to the contents of `frame` through the physical memory mapping.
+fn frame_ptr(frame: PhysFrame) -> *mut u8 {
+    (physical_memory_offset() + frame.start_address().as_u64()).as_mut_ptr()
+}
+
+#[test_case]
+fn test_demand_paging() {
+    let start = VirtAddr::new(0x5555_0000_0000);
+    register_lazy_region(start..start + 0x4000u64,
+                         PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE);
+    let ptr: *mut u64 = (start + 0x2008u64).as_mut_ptr();
+    unsafe {
+        assert_eq!(ptr.read_volatile(), 0);
+        ptr.write_volatile(0xdead_beef);
+        assert_eq!(ptr.read_volatile(), 0xdead_beef);
+    }
+    assert!(mapping_of(start + 0x2000u64).is_some());
+    assert!(mapping_of(start).is_none());
+    unregister_lazy_region(start);
+}
+
+#[test_case]
+fn test_copy_on_write() {
+    let page: Page<Size4KiB> = Page::containing_address(VirtAddr::new(0x5555_1000_0000));
+    {
+        let mut mapper = MAPPER.lock();
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        let frame_allocator = frame_allocator.as_mut().unwrap();
+        let frame = frame_allocator.allocate_frame().unwrap();
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        unsafe {
+            mapper.as_mut().unwrap().map_to(page, frame, flags, frame_allocator).unwrap().flush();
+        }
+    }
+    let ptr: *mut u64 = page.start_address().as_mut_ptr();
+    unsafe { ptr.write_volatile(42) };
+    let old_frame = PhysFrame::containing_address(mapping_of(page.start_address()).unwrap().phys);
+
+    mark_copy_on_write(page).unwrap();
+    unsafe { ptr.write_volatile(43) };
+
+    let new_mapping = mapping_of(page.start_address()).unwrap();
+    assert_ne!(new_mapping.phys, old_frame.start_address());
+    assert!(new_mapping.flags.contains(PageTableFlags::WRITABLE));
+    assert!(!new_mapping.flags.contains(COPY_ON_WRITE));
+    unsafe {
+        assert_eq!(ptr.read_volatile(), 43);
+        assert_eq!((frame_ptr(old_frame) as *const u64).read_volatile(), 42);
+    }
+}
@@ -0,0 +1,120 @@
+//! What the kernel does when physical memory runs low.
+//!
+//! The number of free frames against two watermarks gives the `Pressure`,
+//! which subsystems check to adapt: the page cache stops growing once it is
+//! not `Normal`. When an allocation finds no free frame, `reclaim` shrinks
+//! the page cache and the slab caches. If that doesn't free enough, it is an out-of-memory event:
+//! it is logged, and unless disabled with `set_oom_killer`, the user process
+//! with the most memory is sent `SIGKILL`. A process only handles signals
+//! when it enters the kernel, so it frees its memory some time later and the
+//! allocation that ran out still fails.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use crate::fs::page_cache;
+use crate::memory::{frame_stats, slab, FrameStats};
+use crate::signal::{self, Signal};
+
+/// Below this fraction of free frames (1/8), pressure is `Low`.
+const LOW_WATERMARK: usize = 8;
+/// Below this fraction of free frames (1/32), pressure is `Critical`.
+const MIN_WATERMARK: usize = 32;
+/// Pages evicted from the page cache beyond what an allocation needs, so the
+/// next ones don't end up reclaiming again.
+const RECLAIM_BATCH: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Pressure {
+    /// Above the low watermark.
+    Normal,
+    /// Between the watermarks: caches should give memory back.
+    Low,
+    /// Below the min watermark: allocations are about to fail.
+    Critical,
+}
+
+impl Pressure {
+    pub fn name(self) -> &'static str {
+        match self {
+            Pressure::Normal => "normal",
+            Pressure::Low => "low",
+            Pressure::Critical => "critical",
+        }
+    }
+}
+
+static OOM_KILLER: AtomicBool = AtomicBool::new(true);
+static OOM_EVENTS: AtomicU64 = AtomicU64::new(0);
+
+/// How short of free frames the kernel is, `Normal` before the frame
+/// allocator is installed.
+pub fn pressure() -> Pressure {
+    frame_stats().map_or(Pressure::Normal, level)
+}
+
+fn level(stats: FrameStats) -> Pressure {
+    let free = stats.total.saturating_sub(stats.allocated);
+    if free < stats.total / MIN_WATERMARK {
+        Pressure::Critical
+    } else if free < stats.total / LOW_WATERMARK {
+        Pressure::Low
+    } else {
+        Pressure::Normal
+    }
+}
+
+/// Turns killing a process on out-of-memory events on or off.
+pub fn set_oom_killer(enabled: bool) {
+    OOM_KILLER.store(enabled, Ordering::Relaxed);
+}
+
+pub fn oom_killer_enabled() -> bool {
+    OOM_KILLER.load(Ordering::Relaxed)
+}
+
+/// Number of out-of-memory events since boot.
+pub fn oom_events() -> u64 {
+    OOM_EVENTS.load(Ordering::Relaxed)
+}
+
+/// Frees memory after an allocation of `count` frames failed. Must be called
+/// without the frame allocator locked. Returns whether frames were freed, so
+/// the allocation is worth retrying.
+pub(super) fn reclaim(count: usize) -> bool {
+    let freed = page_cache::shrink(count + RECLAIM_BATCH) + slab::shrink();
+    if freed >= count {
+        return true;
+    }
+    OOM_EVENTS.fetch_add(1, Ordering::Relaxed);
+    let stats = frame_stats().unwrap_or(FrameStats { total: 0, allocated: 0 });
+    crate::log_warn!("out of memory: {} frames wanted, {}/{} in use, {} freed from caches",
+        count, stats.allocated, stats.total, freed);
+    if oom_killer_enabled() {
+        kill_largest();
+    }
+    freed > 0
+}
+
+/// Sends `SIGKILL` to the user process with the most pages mapped.
+fn kill_largest() {
+    let (process, pages) = match crate::process::largest() {
+        Some(largest) => largest,
+        None => {
+            crate::log_warn!("out of memory: no process to kill");
+            return;
+        }
+    };
+    crate::log_warn!("out of memory: killing process {} ({}) with {} pages", process.pid.0, process.name(), pages);
+    if let Some(thread) = process.thread() {
+        let _ = signal::kill(thread, Signal::Kill);
+    }
+}
+
+#[test_case]
+fn test_level() {
+    let stats = |allocated| FrameStats { total: 3200, allocated };
+    assert_eq!(level(stats(0)), Pressure::Normal);
+    assert_eq!(level(stats(2800)), Pressure::Normal);
+    assert_eq!(level(stats(2801)), Pressure::Low);
+    assert_eq!(level(stats(3101)), Pressure::Critical);
+    assert_eq!(level(stats(4000)), Pressure::Critical);
+}
@@ -0,0 +1,270 @@
+//! Caches of fixed-size kernel objects, kept in whole frames instead of on the
+//! heap.
+//!
+//! A `Cache` hands out objects of one type from frames of its own, each cut
+//! into slots the size of the type, so the small heap is left to everything
+//! else and objects of a kind sit next to each other. A freed object stays in
+//! its slot as it is and is handed out again without running the constructor:
+//! objects that allocate when constructed, like buffers, keep what they
+//! allocated. Whoever frees an object leaves it fit to be used again.
+//!
+//! Frames whose objects are all free go back to the frame allocator with
+//! `shrink`, which `pressure::reclaim` calls when frames run out.
+
+use alloc::vec::Vec;
+use core::mem;
+use core::ops::{Deref, DerefMut};
+use core::ptr::NonNull;
+use spin::Mutex;
+use x86_64::structures::paging::PhysFrame;
+use crate::memory;
+
+const PAGE_SIZE: usize = 4096;
+
+/// One frame of a cache.
+struct Slab {
+    frame: PhysFrame,
+    /// Indices of the free slots.
+    free: Vec<u16>,
+    /// Which slots hold a constructed object, free or not.
+    constructed: Vec<bool>,
+}
+
+impl Slab {
+    fn base(&self) -> usize {
+        (memory::physical_memory_offset() + self.frame.start_address().as_u64()).as_u64() as usize
+    }
+}
+
+struct Slabs {
+    slabs: Vec<Slab>,
+    allocations: u64,
+    constructions: u64,
+    /// Whether the cache is in `CACHES`, which happens when it gets its first frame.
+    registered: bool,
+}
+
+/// A cache of objects of type `T`, which must fit in a page. Meant to be a `static`.
+pub struct Cache<T: 'static> {
+    name: &'static str,
+    constructor: fn() -> T,
+    slabs: Mutex<Slabs>,
+}
+
+/// What a cache holds.
+#[derive(Debug, Clone, Copy)]
+pub struct Stats {
+    pub name: &'static str,
+    pub object_size: usize,
+    pub pages: usize,
+    /// Slots in the pages, free or not.
+    pub objects: usize,
+    pub in_use: usize,
+    /// Objects handed out since boot.
+    pub allocations: u64,
+    /// Times the constructor ran, the allocations that found no object to reuse.
+    pub constructions: u64,
+}
+
+/// The caches that have pages, for `stats` and `shrink`.
+trait Shrink: Sync {
+    fn stats(&self) -> Stats;
+    fn shrink(&self) -> usize;
+}
+
+static CACHES: Mutex<Vec<&'static dyn Shrink>> = Mutex::new(Vec::new());
+
+impl<T: Send> Cache<T> {
+    /// A cache named `name` in the statistics, which runs `constructor` for
+    /// slots that never held an object.
+    pub const fn new(name: &'static str, constructor: fn() -> T) -> Cache<T> {
+        Cache {
+            name,
+            constructor,
+            slabs: Mutex::new(Slabs { slabs: Vec::new(), allocations: 0, constructions: 0, registered: false }),
+        }
+    }
+
+    /// Distance between the objects in a slab.
+    fn stride() -> usize {
+        mem::size_of::<T>().max(1)
+    }
+
+    fn per_slab() -> usize {
+        PAGE_SIZE / Self::stride()
+    }
+
+    /// An object from the cache, either one freed before or a new one.
+    pub fn alloc(&'static self) -> Result<SlabBox<T>, &'static str> {
+        assert!(Self::stride() <= PAGE_SIZE && mem::align_of::<T>() <= PAGE_SIZE, "object does not fit in a page");
+        loop {
+            if let Some((ptr, constructed)) = self.take() {
+                if !constructed {
+                    // outside of the lock, the constructor may use the cache itself
+                    unsafe { ptr.as_ptr().write((self.constructor)()) };
+                }
+                return Ok(SlabBox { cache: self, ptr });
+            }
+            self.grow()?;
+        }
+    }
+
+    /// Takes a free slot, and returns whether it holds an object already.
+    fn take(&self) -> Option<(NonNull<T>, bool)> {
+        let mut slabs = self.slabs.lock();
+        let slabs = &mut *slabs;
+        // fill the oldest slabs first, so the newer ones empty out and can be freed
+        let slab = slabs.slabs.iter_mut().find(|slab| !slab.free.is_empty())?;
+        let index = slab.free.pop()? as usize;
+        let constructed = mem::replace(&mut slab.constructed[index], true);
+        slabs.allocations += 1;
+        if !constructed {
+            slabs.constructions += 1;
+        }
+        NonNull::new((slab.base() + index * Self::stride()) as *mut T).map(|ptr| (ptr, constructed))
+    }
+
+    /// Adds a frame of free slots.
+    fn grow(&'static self) -> Result<(), &'static str> {
+        let frame = memory::allocate_frames(1)?[0];
+        let count = Self::per_slab();
+        let register = {
+            let mut slabs = self.slabs.lock();
+            // the last slot is taken last
+            slabs.slabs.push(Slab { frame, free: (0..count as u16).rev().collect(), constructed: alloc::vec![false; count] });
+            !mem::replace(&mut slabs.registered, true)
+        };
+        if register {
+            CACHES.lock().push(self);
+        }
+        Ok(())
+    }
+
+    pub fn stats(&self) -> Stats {
+        let slabs = self.slabs.lock();
+        let objects = slabs.slabs.len() * Self::per_slab();
+        let free: usize = slabs.slabs.iter().map(|slab| slab.free.len()).sum();
+        Stats {
+            name: self.name,
+            object_size: mem::size_of::<T>(),
+            pages: slabs.slabs.len(),
+            objects,
+            in_use: objects - free,
+            allocations: slabs.allocations,
+            constructions: slabs.constructions,
+        }
+    }
+
+    /// Gives the frames whose objects are all free back to the frame
+    /// allocator, dropping the objects. Returns how many were freed.
+    pub fn shrink(&self) -> usize {
+        let count = Self::per_slab();
+        let empty: Vec<Slab> = {
+            let mut slabs = self.slabs.lock();
+            let (empty, used) = mem::take(&mut slabs.slabs).into_iter().partition(|slab| slab.free.len() == count);
+            slabs.slabs = used;
+            empty
+        };
+        // dropped outside of the lock, objects may hold objects of the same cache
+        for slab in &empty {
+            for (index, _) in slab.constructed.iter().enumerate().filter(|(_, &constructed)| constructed) {
+                unsafe { core::ptr::drop_in_place((slab.base() + index * Self::stride()) as *mut T) };
+            }
+        }
+        let freed = empty.len();
+        unsafe { memory::free_frames(empty.into_iter().map(|slab| slab.frame)) };
+        freed
+    }
+
+    fn free(&self, ptr: NonNull<T>) {
+        let addr = ptr.as_ptr() as usize;
+        let mut slabs = self.slabs.lock();
+        if let Some(slab) = slabs.slabs.iter_mut().find(|slab| (slab.base()..slab.base() + PAGE_SIZE).contains(&addr)) {
+            let index = (addr - slab.base()) / Self::stride();
+            slab.free.push(index as u16);
+        }
+    }
+}
+
+impl<T: Send> Shrink for Cache<T> {
+    fn stats(&self) -> Stats {
+        Cache::stats(self)
+    }
+
+    fn shrink(&self) -> usize {
+        Cache::shrink(self)
+    }
+}
+
+/// An object from a `Cache`, which goes back to it when dropped.
+pub struct SlabBox<T: Send + 'static> {
+    cache: &'static Cache<T>,
+    ptr: NonNull<T>,
+}
+
+unsafe impl<T: Send> Send for SlabBox<T> {}
+unsafe impl<T: Send + Sync> Sync for SlabBox<T> {}
+
+impl<T: Send> Deref for SlabBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T: Send> DerefMut for SlabBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T: Send> Drop for SlabBox<T> {
+    fn drop(&mut self) {
+        // the object stays constructed, to be handed out again
+        self.cache.free(self.ptr);
+    }
+}
+
+/// The statistics of every cache that has had pages.
+pub fn stats() -> Vec<Stats> {
+    let caches = CACHES.lock().clone();
+    caches.iter().map(|cache| cache.stats()).collect()
+}
+
+/// Shrinks every cache. Returns how many frames were freed.
+pub fn shrink() -> usize {
+    // copied, so dropped objects can register caches of their own
+    let caches = CACHES.lock().clone();
+    caches.iter().map(|cache| cache.shrink()).sum()
+}
+
+#[test_case]
+fn test_cache() {
+    static BUFFERS: Cache<Vec<u8>> = Cache::new("test buffers", || Vec::with_capacity(64));
+
+    let mut buffer = BUFFERS.alloc().unwrap();
+    buffer.extend_from_slice(b"abc");
+    let other = BUFFERS.alloc().unwrap();
+    let addr = &*buffer as *const Vec<u8>;
+    assert_ne!(addr, &*other as *const Vec<u8>);
+    assert_eq!(BUFFERS.stats().in_use, 2);
+
+    // freed as it is, and reused without constructing it again
+    drop(buffer);
+    let buffer = BUFFERS.alloc().unwrap();
+    assert_eq!(&*buffer as *const Vec<u8>, addr);
+    assert_eq!(&buffer[..], b"abc");
+    let stats = BUFFERS.stats();
+    assert_eq!((stats.allocations, stats.constructions, stats.pages), (3, 2, 1));
+    assert_eq!(stats.objects, PAGE_SIZE / mem::size_of::<Vec<u8>>());
+
+    // the page is only freed once all of its objects are
+    drop(buffer);
+    assert_eq!(BUFFERS.shrink(), 0);
+    drop(other);
+    assert_eq!(BUFFERS.shrink(), 1);
+    assert_eq!(BUFFERS.stats().pages, 0);
+    assert!(self::stats().iter().any(|cache| cache.name == "test buffers"));
+    assert!(BUFFERS.alloc().unwrap().is_empty());
+}
@@ -0,0 +1,181 @@
+//! Typed access to memory-mapped device registers.
+//!
+//! Device registers must be read and written with volatile accesses of their
+//! exact width, since every access may have side effects. A `Register` is one
+//! register at a fixed address, with whether it may be read and written in its
+//! type. `register_block!` declares the registers of a device by offset, and
+//! `map` maps a block of them uncached with `memory::map_mmio`.
+
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use x86_64::{PhysAddr, VirtAddr};
+use crate::memory;
+
+/// A value that is only ever read and written with volatile accesses, for
+/// memory that something other than the CPU reads or writes too.
+#[repr(transparent)]
+pub struct VolatileCell<T: Copy> {
+    value: UnsafeCell<T>,
+}
+
+impl<T: Copy> VolatileCell<T> {
+    pub const fn new(value: T) -> VolatileCell<T> {
+        VolatileCell { value: UnsafeCell::new(value) }
+    }
+
+    pub fn get(&self) -> T {
+        unsafe { core::ptr::read_volatile(self.value.get()) }
+    }
+
+    pub fn set(&self, value: T) {
+        unsafe { core::ptr::write_volatile(self.value.get(), value) }
+    }
+
+    /// Reads the value, then writes back what `f` makes of it. The two
+    /// accesses are separate, so this is not atomic.
+    pub fn update(&self, f: impl FnOnce(T) -> T) {
+        self.set(f(self.get()));
+    }
+}
+
+/// Access of a register that may only be read.
+pub struct ReadOnly;
+/// Access of a register that may only be written.
+pub struct WriteOnly;
+/// Access of a register that may be read and written.
+pub struct ReadWrite;
+
+pub trait Readable {}
+pub trait Writable {}
+
+impl Readable for ReadOnly {}
+impl Readable for ReadWrite {}
+impl Writable for WriteOnly {}
+impl Writable for ReadWrite {}
+
+/// The register of type `T` at an address, with access `A`.
+pub struct Register<T: Copy, A> {
+    cell: *const VolatileCell<T>,
+    _access: PhantomData<A>,
+}
+
+impl<T: Copy, A> Register<T, A> {
+    /// The register at `addr`, which must be mapped and aligned for `T` for as
+    /// long as the register is used.
+    pub unsafe fn new(addr: VirtAddr) -> Register<T, A> {
+        Register { cell: addr.as_ptr(), _access: PhantomData }
+    }
+}
+
+impl<T: Copy, A: Readable> Register<T, A> {
+    pub fn read(&self) -> T {
+        unsafe { (*self.cell).get() }
+    }
+}
+
+impl<T: Copy, A: Writable> Register<T, A> {
+    pub fn write(&self, value: T) {
+        unsafe { (*self.cell).set(value) }
+    }
+}
+
+impl<T: Copy, A: Readable + Writable> Register<T, A> {
+    /// Writes back what `f` makes of the current value.
+    pub fn modify(&self, f: impl FnOnce(T) -> T) {
+        unsafe { (*self.cell).update(f) }
+    }
+}
+
+/// A block of registers declared with `register_block!`.
+pub trait RegisterBlock: Sized {
+    /// Bytes the registers take up, starting at the base address.
+    const SIZE: u64;
+
+    /// The registers at `base`, which must stay mapped while they are used.
+    unsafe fn at(base: VirtAddr) -> Self;
+}
+
+/// Maps the registers at `phys` uncached and returns them. Must be called
+/// after `memory::install`.
+pub fn map<B: RegisterBlock>(phys: PhysAddr) -> Result<B, &'static str> {
+    let base = memory::map_mmio(phys, B::SIZE)?;
+    Ok(unsafe { B::at(base) })
+}
+
+/// Declares a register block: a struct implementing `mmio::RegisterBlock`
+/// with a method for every register, which takes an index for registers that
+/// repeat every `stride` bytes.
+///
+/// ```ignore
+/// register_block! {
+///     struct Registers, size 0x400 {
+///         counter: u64, ReadWrite @ 0x0f0;
+///         timer_config[timer]: u64, ReadWrite @ 0x100, stride 0x20;
+///     }
+/// }
+/// ```
+#[macro_export]
+macro_rules! register_block {
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident, size $size:literal {
+            $(
+                $(#[$register_meta:meta])*
+                $register:ident $([$index:ident])?: $ty:ty, $access:ident @ $offset:expr $(, stride $stride:expr)?;
+            )*
+        }
+    ) => {
+        $(#[$meta])*
+        $vis struct $name {
+            base: x86_64::VirtAddr,
+        }
+
+        impl $crate::mmio::RegisterBlock for $name {
+            const SIZE: u64 = $size;
+
+            unsafe fn at(base: x86_64::VirtAddr) -> $name {
+                $name { base }
+            }
+        }
+
+        #[allow(dead_code)]
+        impl $name {
+            $(
+                $crate::register_block!(@register $(#[$register_meta])* $register $([$index])?: $ty, $access @ $offset $(, stride $stride)?);
+            )*
+        }
+    };
+    (@register $(#[$meta:meta])* $register:ident: $ty:ty, $access:ident @ $offset:expr) => {
+        $(#[$meta])*
+        pub fn $register(&self) -> $crate::mmio::Register<$ty, $crate::mmio::$access> {
+            unsafe { $crate::mmio::Register::new(self.base + ($offset as u64)) }
+        }
+    };
+    (@register $(#[$meta:meta])* $register:ident [$index:ident]: $ty:ty, $access:ident @ $offset:expr, stride $stride:expr) => {
+        $(#[$meta])*
+        pub fn $register(&self, $index: u64) -> $crate::mmio::Register<$ty, $crate::mmio::$access> {
+            unsafe { $crate::mmio::Register::new(self.base + ($offset as u64) + $index * ($stride as u64)) }
+        }
+    };
+}
+
+#[test_case]
+fn test_register_block() {
+    crate::register_block! {
+        struct Test, size 32 {
+            id: u32, ReadOnly @ 0x00;
+            control: u32, ReadWrite @ 0x04;
+            data[n]: u64, WriteOnly @ 0x08, stride 8;
+        }
+    }
+
+    let mut words = [0x1234u64, 0, 0, 0];
+    let test = unsafe { Test::at(VirtAddr::from_ptr(words.as_mut_ptr())) };
+    assert_eq!(test.id().read(), 0x1234);
+    test.control().write(5);
+    test.control().modify(|value| value | 2);
+    assert_eq!(test.control().read(), 7);
+    test.data(2).write(9);
+    assert_eq!(unsafe { core::ptr::read_volatile(&words[3]) }, 9);
+    assert_eq!(unsafe { core::ptr::read_volatile(&words[0]) }, 7 << 32 | 0x1234);
+}
@@ -0,0 +1,56 @@
+//! The mouse in the text console: the pointer is drawn in inverted colors,
+//! a left click moves the cursor, dragging with the left button selects the
+//! text and releasing it copies the selection into the clipboard, and a
+//! middle click pastes the clipboard at the cursor.
+
+use spin::Mutex;
+use crate::arch::interrupts::without_interrupts;
+use crate::drivers::ps2_mouse::{self, Buttons, MouseEvent};
+use crate::vga_buffer::{BUFFER_WIDTH, TEXT_HEIGHT, WRITER};
+use crate::initcall;
+
+/// Motion counts per column and per row of the screen.
+const COUNTS_PER_COLUMN: i32 = 8;
+const COUNTS_PER_ROW: i32 = 16;
+
+struct Pointer {
+    /// Position in motion counts, from the top left corner.
+    x: i32,
+    y: i32,
+    buttons: Buttons,
+}
+
+static POINTER: Mutex<Pointer> = Mutex::new(Pointer { x: 0, y: 0, buttons: Buttons { left: false, right: false, middle: false } });
+
+/// Enables the mouse and shows its pointer on the console.
+pub fn init() -> Result<(), &'static str> {
+    ps2_mouse::init()?;
+    ps2_mouse::set_handler(Some(handle));
+    Ok(())
+}
+initcall!(Driver, "mouse", init);
+
+/// Called from the work queue, or from the interrupt when it is full.
+fn handle(event: MouseEvent) {
+    without_interrupts(|| {
+        let mut pointer = POINTER.lock();
+        pointer.x = (pointer.x + i32::from(event.dx)).clamp(0, BUFFER_WIDTH as i32 * COUNTS_PER_COLUMN - 1);
+        pointer.y = (pointer.y - i32::from(event.dy)).clamp(0, TEXT_HEIGHT as i32 * COUNTS_PER_ROW - 1);
+        let (row, col) = ((pointer.y / COUNTS_PER_ROW) as usize, (pointer.x / COUNTS_PER_COLUMN) as usize);
+        let (before, buttons) = (pointer.buttons, event.buttons);
+        pointer.buttons = buttons;
+
+        let mut writer = WRITER.lock();
+        if buttons.left && !before.left {
+            writer.click(row, col);
+        } else if buttons.left {
+            writer.drag_to(row, col);
+        } else if before.left {
+            writer.copy_selection();
+        }
+        if buttons.middle && !before.middle {
+            writer.paste();
+        }
+        writer.set_pointer(Some((row, col)));
+    });
+}
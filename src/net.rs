@@ -0,0 +1,219 @@
+//! Networking: the network devices and the frames they send and receive.
+//!
+//! Drivers register their devices with `register`, under names like `eth0`.
+//! Receiving allocates fresh buffers for the device, which interrupt handlers
+//! must not do, so the handlers only call `wake`, and the `net` thread takes
+//! the frames the devices received and passes them up the stack: `ethernet`,
+//! then `arp` or `ipv4`, then `tcp` or `udp`. The same thread runs the
+//! protocols' timers every `TICK`.
+//!
+//! The first interface registered gets the IPv4 configuration from the
+//! command line, see `ipv4::Config`.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+use crate::sched::{self, Priority, WaitQueue};
+use crate::time;
+use crate::initcall;
+
+pub mod arp;
+pub mod dns;
+pub mod ethernet;
+pub mod http;
+pub mod ipv4;
+pub mod packet;
+pub mod syslog;
+pub mod tcp;
+pub mod udp;
+
+pub use self::packet::PacketBuf;
+
+/// An Ethernet hardware address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MacAddress(pub [u8; 6]);
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}", a, b, c, d, e, g)
+    }
+}
+
+/// Errors of the sockets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetError {
+    /// Another socket is bound to the port.
+    AddressInUse,
+    /// Nobody listens on the remote port.
+    ConnectionRefused,
+    /// The peer aborted the connection.
+    ConnectionReset,
+    /// The peer stopped answering.
+    TimedOut,
+    /// The connection is closed, or closing in the direction used.
+    NotConnected,
+    /// No interface is configured to reach the address.
+    Unreachable,
+    /// No memory left for a buffer, or no room in the device's queue.
+    OutOfMemory,
+    /// The datagram doesn't fit in a packet.
+    MessageTooLong,
+    /// The resolver knows no address for the name.
+    HostNotFound,
+}
+
+impl NetError {
+    pub fn name(self) -> &'static str {
+        match self {
+            NetError::AddressInUse => "address in use",
+            NetError::ConnectionRefused => "connection refused",
+            NetError::ConnectionReset => "connection reset",
+            NetError::TimedOut => "timed out",
+            NetError::NotConnected => "not connected",
+            NetError::Unreachable => "network unreachable",
+            NetError::OutOfMemory => "out of memory",
+            NetError::MessageTooLong => "message too long",
+            NetError::HostNotFound => "host not found",
+        }
+    }
+}
+
+/// A device that sends and receives Ethernet frames.
+pub trait Device: Send + Sync {
+    fn mac(&self) -> MacAddress;
+
+    /// Queues `frame`, a whole Ethernet frame without the checksum, to be sent.
+    fn transmit(&self, frame: PacketBuf) -> Result<(), &'static str>;
+
+    /// The next frame the device received, if any.
+    fn receive(&self) -> Option<PacketBuf>;
+}
+
+/// Frames and bytes through an interface since it was registered.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Stats {
+    pub rx_packets: u64,
+    pub rx_bytes: u64,
+    pub tx_packets: u64,
+    pub tx_bytes: u64,
+    /// Frames the device refused to send.
+    pub tx_errors: u64,
+}
+
+/// A registered device.
+pub struct Interface {
+    pub name: String,
+    pub device: Arc<dyn Device>,
+    stats: Mutex<Stats>,
+}
+
+impl Interface {
+    pub fn transmit(&self, frame: PacketBuf) -> Result<(), &'static str> {
+        let len = frame.len() as u64;
+        let result = self.device.transmit(frame);
+        let mut stats = self.stats.lock();
+        match result {
+            Ok(()) => {
+                stats.tx_packets += 1;
+                stats.tx_bytes += len;
+            }
+            Err(_) => stats.tx_errors += 1,
+        }
+        result
+    }
+
+    pub fn stats(&self) -> Stats {
+        *self.stats.lock()
+    }
+}
+
+/// How often the protocols' timers run.
+const TICK: Duration = Duration::from_millis(100);
+
+static INTERFACES: Mutex<Vec<Arc<Interface>>> = Mutex::new(Vec::new());
+/// Set by `wake` when a device may have received frames.
+static RECEIVED: AtomicBool = AtomicBool::new(false);
+/// Set every `TICK`.
+static TICKED: AtomicBool = AtomicBool::new(false);
+static RECEIVER: WaitQueue = WaitQueue::new();
+
+/// Starts the thread that takes the received frames and runs the timers.
+/// Must be called after `sched::init`.
+pub fn init() -> Result<(), &'static str> {
+    sched::spawn_with_priority("net", Priority::Realtime, receiver)?;
+    time::add_periodic_timer(TICK, tick, 0);
+    dns::init();
+    syslog::init()
+}
+initcall!(Driver, "net", init);
+
+/// Makes `device` available under the next free name, `eth0` for the first.
+/// Returns the interface.
+pub fn register(device: Arc<dyn Device>) -> Arc<Interface> {
+    let mut interfaces = INTERFACES.lock();
+    let name = (0..).map(|n| format!("eth{}", n)).find(|name| !interfaces.iter().any(|other| &other.name == name))
+        .expect("too many network interfaces");
+    let interface = Arc::new(Interface { name, device, stats: Mutex::new(Stats::default()) });
+    interfaces.push(interface.clone());
+    let first = interfaces.len() == 1;
+    drop(interfaces);
+    if first {
+        ipv4::configure(interface.clone(), ipv4::Config::from_cmdline());
+    }
+    interface
+}
+
+/// The interface called `name`.
+pub fn get(name: &str) -> Option<Arc<Interface>> {
+    INTERFACES.lock().iter().find(|interface| interface.name == name).cloned()
+}
+
+pub fn interfaces() -> Vec<Arc<Interface>> {
+    INTERFACES.lock().clone()
+}
+
+/// Has the `net` thread look for received frames. Usable from interrupt handlers.
+pub fn wake() {
+    RECEIVED.store(true, Ordering::Release);
+    RECEIVER.notify_all();
+}
+
+/// Timer callback.
+fn tick(_: u64) {
+    TICKED.store(true, Ordering::Release);
+    RECEIVER.notify_all();
+}
+
+fn receiver() {
+    loop {
+        RECEIVER.wait_until(|| RECEIVED.load(Ordering::Acquire) || TICKED.load(Ordering::Acquire));
+        if RECEIVED.swap(false, Ordering::Acquire) {
+            for interface in interfaces() {
+                while let Some(frame) = interface.device.receive() {
+                    {
+                        let mut stats = interface.stats.lock();
+                        stats.rx_packets += 1;
+                        stats.rx_bytes += frame.len() as u64;
+                    }
+                    ethernet::input(&interface, frame);
+                }
+            }
+        }
+        if TICKED.swap(false, Ordering::Acquire) {
+            arp::tick();
+            tcp::tick();
+        }
+    }
+}
+
+#[test_case]
+fn test_mac_address() {
+    let mac = MacAddress([0x52, 0x54, 0x00, 0x12, 0x34, 0x5f]);
+    assert_eq!(format!("{}", mac), "52:54:00:12:34:5f");
+}
@@ -0,0 +1,263 @@
+//! ARP: the hardware addresses of the IPv4 neighbors.
+//!
+//! Addresses are learned from the ARP packets received and kept for
+//! `REACHABLE_TIME`, then asked for again. Packets to a neighbor whose
+//! address is not known yet wait for it in a short queue, while a request
+//! goes out every `REQUEST_INTERVAL`; after `MAX_REQUESTS` unanswered ones
+//! the neighbor is given up, along with its queue. An address is announced
+//! with a gratuitous ARP request when it is configured, which updates the
+//! neighbors' caches and shows up conflicts.
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+use crate::net::ethernet::{self, mac_at, BROADCAST, TYPE_ARP, TYPE_IPV4};
+use crate::net::ipv4::{self, addr_at, Ipv4Addr};
+use crate::net::packet::HEADROOM;
+use crate::net::{Interface, MacAddress, NetError, PacketBuf};
+use crate::log_warn;
+use crate::time::Instant;
+
+/// Length of an ARP packet for IPv4 over Ethernet.
+const PACKET_LEN: usize = 28;
+/// Hardware type, protocol type and the lengths of their addresses.
+const ETHERNET_IPV4: [u8; 6] = [0, 1, 0x08, 0x00, 6, 4];
+const REQUEST: u16 = 1;
+const REPLY: u16 = 2;
+
+/// How long a learned address is used before it is asked for again.
+const REACHABLE_TIME: Duration = Duration::from_secs(60);
+const REQUEST_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_REQUESTS: u32 = 3;
+/// Packets that wait for a neighbor's address; more are dropped.
+const MAX_PENDING: usize = 4;
+/// Neighbors in the table; the one expiring first makes room for a new one.
+const MAX_NEIGHBORS: usize = 64;
+
+const UNKNOWN: MacAddress = MacAddress([0; 6]);
+
+enum State {
+    /// Asked for, with the packets waiting for the answer.
+    Incomplete {
+        requests: u32,
+        next_request: Instant,
+        pending: VecDeque<PacketBuf>,
+    },
+    Reachable {
+        mac: MacAddress,
+        expires: Instant,
+    },
+}
+
+static NEIGHBORS: Mutex<BTreeMap<Ipv4Addr, State>> = Mutex::new(BTreeMap::new());
+/// Packets dropped for lack of an answer or of room in a queue.
+static UNRESOLVED: AtomicU64 = AtomicU64::new(0);
+
+/// A neighbor, as `neighbors` lists it.
+#[derive(Debug, Clone, Copy)]
+pub struct Neighbor {
+    pub addr: Ipv4Addr,
+    /// `None` while it is asked for.
+    pub mac: Option<MacAddress>,
+    /// Time left until the address expires, or until the next request.
+    pub expires: Duration,
+    /// Packets waiting for the address.
+    pub pending: usize,
+}
+
+pub fn neighbors() -> Vec<Neighbor> {
+    let now = Instant::now();
+    let left = |at: Instant| if at > now { at.duration_since(now) } else { Duration::from_secs(0) };
+    NEIGHBORS.lock().iter().map(|(&addr, state)| match state {
+        State::Incomplete { next_request, pending, .. } =>
+            Neighbor { addr, mac: None, expires: left(*next_request), pending: pending.len() },
+        State::Reachable { mac, expires } => Neighbor { addr, mac: Some(*mac), expires: left(*expires), pending: 0 },
+    }).collect()
+}
+
+/// Forgets every neighbor, dropping the packets waiting for them.
+pub fn flush() {
+    let neighbors = core::mem::take(&mut *NEIGHBORS.lock());
+    drop(neighbors);
+}
+
+/// Packets dropped so far because a neighbor didn't answer or too many waited.
+pub fn unresolved() -> u64 {
+    UNRESOLVED.load(Ordering::Relaxed)
+}
+
+/// Learns the sender of a received packet, and answers requests for our address.
+pub fn input(interface: &Interface, packet: PacketBuf) {
+    let data = packet.data();
+    if data.len() < PACKET_LEN || data[..6] != ETHERNET_IPV4 {
+        return;
+    }
+    let operation = u16::from_be_bytes([data[6], data[7]]);
+    let (sender_mac, sender) = (mac_at(data, 8), addr_at(data, 14));
+    let target = addr_at(data, 24);
+    let local = ipv4::local_address();
+    if Some(sender) == local && sender_mac != interface.device.mac() {
+        log_warn!("arp: {} is also used by {}", sender, sender_mac);
+        return;
+    }
+    // only neighbors talking to us or already known are remembered
+    for packet in learn(sender, sender_mac, Some(target) == local) {
+        let _ = ethernet::send(interface, sender_mac, TYPE_IPV4, packet);
+    }
+    if let Some(local) = local {
+        if operation == REQUEST && target == local {
+            let _ = transmit(interface, REPLY, local, sender_mac, sender);
+        }
+    }
+}
+
+/// Records that `addr` is at `mac`, if it is known already or `for_us`.
+/// Returns the packets that waited for the address.
+fn learn(addr: Ipv4Addr, mac: MacAddress, for_us: bool) -> VecDeque<PacketBuf> {
+    let mut neighbors = NEIGHBORS.lock();
+    if addr == Ipv4Addr::UNSPECIFIED || !(for_us || neighbors.contains_key(&addr)) {
+        return VecDeque::new();
+    }
+    if !neighbors.contains_key(&addr) {
+        make_room(&mut neighbors);
+    }
+    let expires = Instant::now() + REACHABLE_TIME;
+    match neighbors.insert(addr, State::Reachable { mac, expires }) {
+        Some(State::Incomplete { pending, .. }) => pending,
+        _ => VecDeque::new(),
+    }
+}
+
+/// Forgets a neighbor if the table is full: the address that expires first,
+/// or one still asked for if none is known.
+fn make_room(neighbors: &mut BTreeMap<Ipv4Addr, State>) {
+    if neighbors.len() < MAX_NEIGHBORS {
+        return;
+    }
+    let oldest = neighbors.iter()
+        .min_by_key(|(_, state)| match state {
+            State::Reachable { expires, .. } => (0, expires.ticks()),
+            State::Incomplete { .. } => (1, 0),
+        })
+        .map(|(&addr, _)| addr);
+    if let Some(State::Incomplete { pending, .. }) = oldest.and_then(|addr| neighbors.remove(&addr)) {
+        UNRESOLVED.fetch_add(pending.len() as u64, Ordering::Relaxed);
+    }
+}
+
+/// Sends an IPv4 packet to the neighbor `next_hop`, once its address is known.
+pub fn send(interface: &Interface, next_hop: Ipv4Addr, packet: PacketBuf) -> Result<(), NetError> {
+    if next_hop == Ipv4Addr::BROADCAST {
+        return ethernet::send(interface, BROADCAST, TYPE_IPV4, packet);
+    }
+    let local = ipv4::local_address().ok_or(NetError::Unreachable)?;
+    let now = Instant::now();
+    let mut neighbors = NEIGHBORS.lock();
+    match neighbors.get_mut(&next_hop) {
+        Some(State::Reachable { mac, expires }) if *expires > now => {
+            let mac = *mac;
+            drop(neighbors);
+            ethernet::send(interface, mac, TYPE_IPV4, packet)
+        }
+        Some(State::Incomplete { pending, .. }) => {
+            if pending.len() < MAX_PENDING {
+                pending.push_back(packet);
+            } else {
+                UNRESOLVED.fetch_add(1, Ordering::Relaxed);
+            }
+            Ok(())
+        }
+        // unknown, or known for too long
+        _ => {
+            if !neighbors.contains_key(&next_hop) {
+                make_room(&mut neighbors);
+            }
+            let pending = core::iter::once(packet).collect();
+            neighbors.insert(next_hop, State::Incomplete { requests: 1, next_request: now + REQUEST_INTERVAL, pending });
+            drop(neighbors);
+            transmit(interface, REQUEST, local, UNKNOWN, next_hop)
+        }
+    }
+}
+
+/// Asks again for the addresses that were not answered, gives up on those
+/// asked for too often, and forgets the expired ones. Called by the `net`
+/// thread every `TICK`.
+pub(super) fn tick() {
+    let (interface, config) = match ipv4::route() {
+        Some(route) => route,
+        None => return,
+    };
+    let now = Instant::now();
+    let mut requests = Vec::new();
+    NEIGHBORS.lock().retain(|&addr, state| match state {
+        State::Reachable { expires, .. } => *expires > now,
+        State::Incomplete { next_request, .. } if *next_request > now => true,
+        State::Incomplete { requests: sent, pending, .. } if *sent == MAX_REQUESTS => {
+            UNRESOLVED.fetch_add(pending.len() as u64, Ordering::Relaxed);
+            false
+        }
+        State::Incomplete { requests: sent, next_request, .. } => {
+            *sent += 1;
+            *next_request = now + REQUEST_INTERVAL;
+            requests.push(addr);
+            true
+        }
+    });
+    for addr in requests {
+        let _ = transmit(&interface, REQUEST, config.address, UNKNOWN, addr);
+    }
+}
+
+/// Tells the neighbors that `addr` is at `interface`, with a gratuitous request.
+pub fn announce(interface: &Interface, addr: Ipv4Addr) -> Result<(), NetError> {
+    transmit(interface, REQUEST, addr, UNKNOWN, addr)
+}
+
+/// Sends an ARP packet from us to `target`, broadcast if it is a request.
+fn transmit(interface: &Interface, operation: u16, local: Ipv4Addr, target_mac: MacAddress, target: Ipv4Addr)
+            -> Result<(), NetError> {
+    let mut packet = PacketBuf::new(HEADROOM).map_err(|_| NetError::OutOfMemory)?;
+    let data = packet.put(PACKET_LEN).map_err(|_| NetError::OutOfMemory)?;
+    data[..6].copy_from_slice(&ETHERNET_IPV4);
+    data[6..8].copy_from_slice(&operation.to_be_bytes());
+    data[8..14].copy_from_slice(&interface.device.mac().0);
+    data[14..18].copy_from_slice(&local.0);
+    data[18..24].copy_from_slice(&target_mac.0);
+    data[24..28].copy_from_slice(&target.0);
+    let destination = if operation == REQUEST { BROADCAST } else { target_mac };
+    ethernet::send(interface, destination, TYPE_ARP, packet)
+}
+
+#[test_case]
+fn test_neighbors() {
+    // from TEST-NET-2, which no real neighbor uses
+    let addr = |n: usize| Ipv4Addr([198, 51, 100, n as u8]);
+    let mac = MacAddress([0x52, 0x54, 0, 0, 0, 1]);
+    let packet = || PacketBuf::from_slice(b"ip").unwrap();
+    {
+        let mut neighbors = NEIGHBORS.lock();
+        let next_request = Instant::now() + REQUEST_INTERVAL;
+        let pending: VecDeque<PacketBuf> = (0..2).map(|_| packet()).collect();
+        neighbors.insert(addr(1), State::Incomplete { requests: 1, next_request, pending });
+    }
+    // strangers are only remembered when they talk to us
+    assert!(learn(addr(2), mac, false).is_empty());
+    assert!(NEIGHBORS.lock().get(&addr(2)).is_none());
+    assert_eq!(learn(addr(1), mac, false).len(), 2);
+    assert!(matches!(NEIGHBORS.lock().get(&addr(1)), Some(State::Reachable { .. })));
+
+    // a full table makes room by forgetting the address that expires first
+    let mut neighbors = NEIGHBORS.lock();
+    for n in 2..2 + MAX_NEIGHBORS {
+        make_room(&mut neighbors);
+        let expires = Instant::now() + REACHABLE_TIME + Duration::from_secs(n as u64);
+        neighbors.insert(addr(n), State::Reachable { mac, expires });
+    }
+    assert_eq!(neighbors.len(), MAX_NEIGHBORS);
+    assert!(!neighbors.contains_key(&addr(1)));
+    assert!(neighbors.contains_key(&addr(1 + MAX_NEIGHBORS)));
+    neighbors.retain(|addr, _| addr.0[..3] != [198, 51, 100]);
+}
@@ -0,0 +1,207 @@
+//! DNS: the addresses of host names, asked of the resolver given by the
+//! command line option `dns=<address>`, QEMU's user network resolver
+//! `10.0.2.3` by default.
+//!
+//! Only A records are asked for, recursively, over UDP. Answers are kept for
+//! their time to live, at most `MAX_TTL`, in a small cache.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::time::Duration;
+use spin::Mutex;
+use crate::net::ipv4::Ipv4Addr;
+use crate::net::udp::UdpSocket;
+use crate::net::NetError;
+use crate::time::Instant;
+use crate::{boot, log_warn, rand, sched};
+
+pub const DEFAULT_SERVER: Ipv4Addr = Ipv4Addr([10, 0, 2, 3]);
+const PORT: u16 = 53;
+
+const HEADER_LEN: usize = 12;
+/// Largest message over UDP.
+const MAX_MESSAGE: usize = 512;
+/// Recursion desired.
+const FLAG_RD: u16 = 0x0100;
+const FLAG_RESPONSE: u16 = 0x8000;
+const TYPE_A: u16 = 1;
+const CLASS_IN: u16 = 1;
+
+const ATTEMPTS: u32 = 3;
+const TIMEOUT: Duration = Duration::from_secs(2);
+/// How often a pending query looks for the answer.
+const POLL: Duration = Duration::from_millis(10);
+const MAX_TTL: Duration = Duration::from_secs(3600);
+const CACHE_SIZE: usize = 16;
+
+static SERVER: Mutex<Ipv4Addr> = Mutex::new(DEFAULT_SERVER);
+/// Names resolved, with their address and when it expires.
+static CACHE: Mutex<BTreeMap<String, (Ipv4Addr, Instant)>> = Mutex::new(BTreeMap::new());
+
+/// Takes the resolver from the command line, if it is given there.
+pub fn init() {
+    if let Some(value) = boot::cmdline().get("dns") {
+        match Ipv4Addr::parse(value) {
+            Some(server) => set_server(server),
+            None => log_warn!("cmdline: invalid dns={}", value),
+        }
+    }
+}
+
+pub fn server() -> Ipv4Addr {
+    *SERVER.lock()
+}
+
+pub fn set_server(server: Ipv4Addr) {
+    *SERVER.lock() = server;
+    CACHE.lock().clear();
+}
+
+/// The address of `name`, which may also be an address itself. Blocks the
+/// calling thread until the resolver answers, so it must not be called from
+/// the `net` thread.
+pub fn resolve(name: &str) -> Result<Ipv4Addr, NetError> {
+    if let Some(addr) = Ipv4Addr::parse(name) {
+        return Ok(addr);
+    }
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+    let now = Instant::now();
+    if let Some(&(addr, expires)) = CACHE.lock().get(&name) {
+        if expires > now {
+            return Ok(addr);
+        }
+    }
+    let socket = UdpSocket::bind(0)?;
+    let server = server();
+    let mut buf = [0; MAX_MESSAGE];
+    for _ in 0..ATTEMPTS {
+        let id = rand::u64() as u16;
+        let message = query(id, &name).ok_or(NetError::HostNotFound)?;
+        socket.send_to(&message, server, PORT)?;
+        let deadline = Instant::now() + TIMEOUT;
+        while Instant::now() < deadline {
+            let len = match socket.try_recv_from(&mut buf) {
+                Some((len, source, PORT)) if source == server => len,
+                Some(_) => continue,
+                None => {
+                    sched::sleep(POLL);
+                    continue;
+                }
+            };
+            // answers to earlier attempts are ignored
+            if let Some(answer) = parse_response(id, &buf[..len]) {
+                let (addr, ttl) = answer.ok_or(NetError::HostNotFound)?;
+                remember(name, addr, Instant::now() + ttl.min(MAX_TTL));
+                return Ok(addr);
+            }
+        }
+    }
+    Err(NetError::TimedOut)
+}
+
+fn remember(name: String, addr: Ipv4Addr, expires: Instant) {
+    let mut cache = CACHE.lock();
+    let now = Instant::now();
+    cache.retain(|_, &mut (_, expires)| expires > now);
+    if cache.len() >= CACHE_SIZE && !cache.contains_key(&name) {
+        let first = cache.iter().min_by_key(|(_, (_, expires))| expires.ticks()).map(|(name, _)| name.clone());
+        if let Some(first) = first {
+            cache.remove(&first);
+        }
+    }
+    cache.insert(name, (addr, expires));
+}
+
+/// A query for the A records of `name`, or `None` if it is no valid name.
+fn query(id: u16, name: &str) -> Option<Vec<u8>> {
+    if name.is_empty() || name.len() > 253 {
+        return None;
+    }
+    let mut message = Vec::with_capacity(HEADER_LEN + name.len() + 6);
+    message.extend_from_slice(&id.to_be_bytes());
+    message.extend_from_slice(&FLAG_RD.to_be_bytes());
+    // one question, no records
+    message.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return None;
+        }
+        message.push(label.len() as u8);
+        message.extend_from_slice(label.as_bytes());
+    }
+    message.push(0);
+    message.extend_from_slice(&TYPE_A.to_be_bytes());
+    message.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Some(message)
+}
+
+/// Parses the response to query `id`. Returns `None` if it isn't one, and
+/// `Some(None)` if the name has no address: the resolver reported an error,
+/// or answered without A records.
+fn parse_response(id: u16, message: &[u8]) -> Option<Option<(Ipv4Addr, Duration)>> {
+    let u16_at = |offset: usize| message.get(offset..offset + 2).map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]));
+    let flags = u16_at(2)?;
+    if message.len() < HEADER_LEN || u16_at(0)? != id || flags & FLAG_RESPONSE == 0 {
+        return None;
+    }
+    if flags & 0xf != 0 {
+        return Some(None);
+    }
+    let (questions, answers) = (u16_at(4)?, u16_at(6)?);
+    let mut offset = HEADER_LEN;
+    for _ in 0..questions {
+        offset = skip_name(message, offset)? + 4;
+    }
+    // CNAME records come before the A records of the name they point to
+    for _ in 0..answers {
+        offset = skip_name(message, offset)?;
+        let (kind, class) = (u16_at(offset)?, u16_at(offset + 2)?);
+        let ttl = message.get(offset + 4..offset + 8)?;
+        let ttl = u32::from_be_bytes([ttl[0], ttl[1], ttl[2], ttl[3]]);
+        let len = usize::from(u16_at(offset + 8)?);
+        let data = message.get(offset + 10..offset + 10 + len)?;
+        if kind == TYPE_A && class == CLASS_IN && len == 4 {
+            let addr = Ipv4Addr([data[0], data[1], data[2], data[3]]);
+            return Some(Some((addr, Duration::from_secs(ttl.into()))));
+        }
+        offset += 10 + len;
+    }
+    Some(None)
+}
+
+/// The offset after the name at `offset`, which ends with a zero length or
+/// a pointer to the rest of the name.
+fn skip_name(message: &[u8], mut offset: usize) -> Option<usize> {
+    loop {
+        let len = *message.get(offset)?;
+        match len {
+            0 => return Some(offset + 1),
+            len if len & 0xc0 == 0xc0 => return Some(offset + 2),
+            len => offset += 1 + usize::from(len),
+        }
+    }
+}
+
+#[test_case]
+fn test_response() {
+    let message = query(0x1234, "example.com").unwrap();
+    assert_eq!(&message[HEADER_LEN..], b"\x07example\x03com\x00\x00\x01\x00\x01");
+    assert!(query(1, "a..b").is_none());
+
+    // a CNAME to a compressed name, then its A record
+    let mut response = message.clone();
+    response[2..4].copy_from_slice(&(FLAG_RESPONSE | FLAG_RD | 0x80).to_be_bytes());
+    response[6..8].copy_from_slice(&2u16.to_be_bytes());
+    response.extend_from_slice(b"\xc0\x0c\x00\x05\x00\x01\x00\x00\x00\x3c\x00\x06\x03www\xc0\x0c");
+    response.extend_from_slice(b"\xc0\x29\x00\x01\x00\x01\x00\x00\x01\x2c\x00\x04\x5d\xb8\xd8\x22");
+    let answer = Some(Some((Ipv4Addr([93, 184, 216, 34]), Duration::from_secs(300))));
+    assert_eq!(parse_response(0x1234, &response), answer);
+    assert_eq!(parse_response(0x4321, &response), None);
+    assert_eq!(parse_response(0x1234, &response[..response.len() - 1]), None);
+
+    // no such name
+    response[3] |= 3;
+    assert_eq!(parse_response(0x1234, &response), Some(None));
+    assert_eq!(resolve("10.0.2.2"), Ok(Ipv4Addr([10, 0, 2, 2])));
+}
@@ -0,0 +1,44 @@
+//! Ethernet II framing.
+
+use crate::net::{arp, ipv4, Interface, MacAddress, NetError, PacketBuf};
+
+pub const HEADER_LEN: usize = 14;
+pub const BROADCAST: MacAddress = MacAddress([0xff; 6]);
+
+pub const TYPE_IPV4: u16 = 0x0800;
+pub const TYPE_ARP: u16 = 0x0806;
+
+/// Strips the header off a received frame and passes it on by type. Frames
+/// for other hosts are dropped.
+pub fn input(interface: &Interface, mut frame: PacketBuf) {
+    let (destination, ethertype) = match frame.pull(HEADER_LEN) {
+        Some(header) => (mac_at(header, 0), u16::from_be_bytes([header[12], header[13]])),
+        None => return,
+    };
+    if destination != interface.device.mac() && destination != BROADCAST {
+        return;
+    }
+    match ethertype {
+        TYPE_IPV4 => ipv4::input(interface, frame),
+        TYPE_ARP => arp::input(interface, frame),
+        _ => {}
+    }
+}
+
+/// Sends `packet` to `destination` with a header of type `ethertype` in front.
+pub fn send(interface: &Interface, destination: MacAddress, ethertype: u16, mut packet: PacketBuf)
+            -> Result<(), NetError> {
+    let source = interface.device.mac();
+    let header = packet.push(HEADER_LEN).map_err(|_| NetError::OutOfMemory)?;
+    header[0..6].copy_from_slice(&destination.0);
+    header[6..12].copy_from_slice(&source.0);
+    header[12..14].copy_from_slice(&ethertype.to_be_bytes());
+    interface.transmit(packet).map_err(|_| NetError::OutOfMemory)
+}
+
+/// The hardware address at `offset` of `bytes`.
+pub fn mac_at(bytes: &[u8], offset: usize) -> MacAddress {
+    let mut mac = [0; 6];
+    mac.copy_from_slice(&bytes[offset..offset + 6]);
+    MacAddress(mac)
+}
@@ -0,0 +1,371 @@
+//! HTTP/1.1: a client for GET requests, over `tcp`.
+//!
+//! `get` sends the request with `Connection: close` and passes the body of a
+//! successful answer to a callback as it arrives, whether it is chunked,
+//! sized by `Content-Length` or ends with the connection. `fetch` also looks
+//! up the host with `dns` and follows redirections. Only `http://` URLs are
+//! supported: there is no TLS.
+
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+use crate::net::dns;
+use crate::net::ipv4::Ipv4Addr;
+use crate::net::tcp::TcpStream;
+use crate::net::NetError;
+use crate::task;
+
+pub const DEFAULT_PORT: u16 = 80;
+
+/// Longest status or header line.
+const MAX_LINE: usize = 1024;
+const MAX_HEADERS: usize = 64;
+const MAX_REDIRECTS: usize = 5;
+/// Bytes read from the connection at a time.
+const READ_SIZE: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HttpError {
+    InvalidUrl,
+    /// Only `http://` URLs are supported.
+    UnsupportedScheme,
+    Net(NetError),
+    /// The answer isn't HTTP/1.x, or its body is framed wrong.
+    Malformed,
+    /// The connection ended before the answer did.
+    Truncated,
+    TooManyRedirects,
+    /// The body callback gave up, for that reason.
+    Aborted(&'static str),
+}
+
+impl From<NetError> for HttpError {
+    fn from(error: NetError) -> HttpError {
+        HttpError::Net(error)
+    }
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HttpError::InvalidUrl => f.write_str("invalid URL"),
+            HttpError::UnsupportedScheme => f.write_str("only http:// URLs are supported"),
+            HttpError::Net(error) => f.write_str(error.name()),
+            HttpError::Malformed => f.write_str("malformed response"),
+            HttpError::Truncated => f.write_str("connection closed early"),
+            HttpError::TooManyRedirects => f.write_str("too many redirections"),
+            HttpError::Aborted(reason) => f.write_str(reason),
+        }
+    }
+}
+
+/// An `http://` URL.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Url {
+    pub host: String,
+    pub port: u16,
+    /// The path and query, `/` at least.
+    pub path: String,
+}
+
+impl Url {
+    /// Parses `http://<host>[:<port>][<path>]`. The fragment is dropped.
+    pub fn parse(text: &str) -> Result<Url, HttpError> {
+        let rest = match text.find("://") {
+            Some(at) if text[..at].eq_ignore_ascii_case("http") => &text[at + 3..],
+            Some(_) => return Err(HttpError::UnsupportedScheme),
+            None => return Err(HttpError::InvalidUrl),
+        };
+        let rest = rest.split('#').next().unwrap_or("");
+        let (authority, path) = match rest.find(|c| c == '/' || c == '?') {
+            Some(at) => (&rest[..at], &rest[at..]),
+            None => (rest, ""),
+        };
+        let (host, port) = match authority.find(':') {
+            Some(at) => (&authority[..at], authority[at + 1..].parse().map_err(|_| HttpError::InvalidUrl)?),
+            None => (authority, DEFAULT_PORT),
+        };
+        // user information is not supported either
+        if host.is_empty() || host.contains('@') {
+            return Err(HttpError::InvalidUrl);
+        }
+        let path = if path.starts_with('/') { path.to_string() } else { format!("/{}", path) };
+        Ok(Url { host: host.to_ascii_lowercase(), port, path })
+    }
+
+    /// The URL a `Location` header refers to, absolute or relative to the
+    /// root of this one's host.
+    fn join(&self, location: &str) -> Result<Url, HttpError> {
+        if location.starts_with('/') && !location.starts_with("//") {
+            let path = location.split('#').next().unwrap_or("").to_string();
+            Ok(Url { path, ..self.clone() })
+        } else {
+            Url::parse(location)
+        }
+    }
+
+    /// The host as the `Host` header gives it, with the port if it isn't the default.
+    fn authority(&self) -> String {
+        match self.port {
+            DEFAULT_PORT => self.host.clone(),
+            port => format!("{}:{}", self.host, port),
+        }
+    }
+}
+
+impl fmt::Display for Url {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "http://{}{}", self.authority(), self.path)
+    }
+}
+
+/// The status and headers of an answer.
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub status: u16,
+    pub reason: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl Response {
+    /// The value of the first header called `name`, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(key, _)| key.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.status / 100 == 2
+    }
+
+    pub fn is_redirect(&self) -> bool {
+        matches!(self.status, 301 | 302 | 303 | 307 | 308)
+    }
+
+    /// Whether an answer with this status has a body; 1xx, 204 and 304 don't.
+    fn has_body(&self) -> bool {
+        self.status / 100 != 1 && self.status != 204 && self.status != 304
+    }
+}
+
+/// Reads the lines and pieces of body of an answer through a buffer.
+struct Reader<'a> {
+    /// `None` when everything to read is in the buffer already.
+    stream: Option<&'a TcpStream>,
+    buf: Vec<u8>,
+    /// Where the bytes not read yet start.
+    start: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(stream: Option<&'a TcpStream>, buf: Vec<u8>) -> Reader<'a> {
+        Reader { stream, buf, start: 0 }
+    }
+
+    fn buffered(&self) -> &[u8] {
+        &self.buf[self.start..]
+    }
+
+    /// Reads more of the answer into the buffer. Returns false at its end.
+    async fn fill(&mut self) -> Result<bool, HttpError> {
+        self.buf.drain(..self.start);
+        self.start = 0;
+        let stream = match self.stream {
+            Some(stream) => stream,
+            None => return Ok(false),
+        };
+        let len = self.buf.len();
+        self.buf.resize(len + READ_SIZE, 0);
+        let read = stream.read(&mut self.buf[len..]).await;
+        self.buf.truncate(len + *read.as_ref().unwrap_or(&0));
+        Ok(read? > 0)
+    }
+
+    /// The next line, without its line ending.
+    async fn read_line(&mut self) -> Result<String, HttpError> {
+        loop {
+            if let Some(at) = self.buffered().iter().position(|&byte| byte == b'\n') {
+                let mut line = &self.buffered()[..at];
+                if line.last() == Some(&b'\r') {
+                    line = &line[..line.len() - 1];
+                }
+                let line = String::from_utf8_lossy(line).into_owned();
+                self.start += at + 1;
+                return Ok(line);
+            }
+            if self.buffered().len() > MAX_LINE {
+                return Err(HttpError::Malformed);
+            }
+            if !self.fill().await? {
+                return Err(HttpError::Truncated);
+            }
+        }
+    }
+
+    /// At most `limit` bytes of the answer, none at its end.
+    async fn read_some(&mut self, limit: usize) -> Result<&[u8], HttpError> {
+        if self.buffered().is_empty() {
+            self.fill().await?;
+        }
+        let start = self.start;
+        self.start += self.buffered().len().min(limit);
+        Ok(&self.buf[start..self.start])
+    }
+
+    /// Passes the next `len` bytes to `body`.
+    async fn read_exactly<F>(&mut self, mut len: usize, body: &mut F) -> Result<(), HttpError>
+        where F: FnMut(&[u8]) -> Result<(), &'static str> {
+        while len > 0 {
+            let data = self.read_some(len).await?;
+            if data.is_empty() {
+                return Err(HttpError::Truncated);
+            }
+            len -= data.len();
+            body(data).map_err(HttpError::Aborted)?;
+        }
+        Ok(())
+    }
+
+    /// Reads the status line and the headers, skipping interim 1xx answers.
+    async fn read_head(&mut self) -> Result<Response, HttpError> {
+        loop {
+            let line = self.read_line().await?;
+            let mut parts = line.splitn(3, ' ');
+            if !parts.next().map_or(false, |version| version.starts_with("HTTP/1.")) {
+                return Err(HttpError::Malformed);
+            }
+            let status = parts.next().and_then(|status| status.parse().ok())
+                .filter(|status| (100..600).contains(status))
+                .ok_or(HttpError::Malformed)?;
+            let reason = parts.next().unwrap_or("").to_string();
+            let mut headers = Vec::new();
+            loop {
+                let line = self.read_line().await?;
+                if line.is_empty() {
+                    break;
+                }
+                let colon = line.find(':').ok_or(HttpError::Malformed)?;
+                if headers.len() == MAX_HEADERS {
+                    return Err(HttpError::Malformed);
+                }
+                headers.push((line[..colon].trim().to_string(), line[colon + 1..].trim().to_string()));
+            }
+            let response = Response { status, reason, headers };
+            if response.status / 100 != 1 {
+                return Ok(response);
+            }
+        }
+    }
+
+    /// Passes the body of `response` to `body`, however it is framed.
+    async fn read_body<F>(&mut self, response: &Response, body: &mut F) -> Result<(), HttpError>
+        where F: FnMut(&[u8]) -> Result<(), &'static str> {
+        if !response.has_body() {
+            return Ok(());
+        }
+        let chunked = response.header("transfer-encoding")
+            .map_or(false, |coding| coding.to_ascii_lowercase().contains("chunked"));
+        if chunked {
+            loop {
+                // the size may be followed by extensions
+                let line = self.read_line().await?;
+                let size = line.split(';').next().unwrap_or("").trim();
+                let size = usize::from_str_radix(size, 16).map_err(|_| HttpError::Malformed)?;
+                if size == 0 {
+                    break;
+                }
+                self.read_exactly(size, body).await?;
+                if !self.read_line().await?.is_empty() {
+                    return Err(HttpError::Malformed);
+                }
+            }
+            // the trailer fields are ignored
+            while !self.read_line().await?.is_empty() {}
+            Ok(())
+        } else if let Some(len) = response.header("content-length") {
+            let len = len.parse().map_err(|_| HttpError::Malformed)?;
+            self.read_exactly(len, body).await
+        } else {
+            loop {
+                let data = self.read_some(usize::MAX).await?;
+                if data.is_empty() {
+                    return Ok(());
+                }
+                body(data).map_err(HttpError::Aborted)?;
+            }
+        }
+    }
+}
+
+/// Asks `addr`, the address of `url`'s host, for `url`. The body is passed
+/// to `body` piece by piece if the answer is a success, and dropped otherwise.
+pub async fn get<F>(url: &Url, addr: Ipv4Addr, mut body: F) -> Result<Response, HttpError>
+    where F: FnMut(&[u8]) -> Result<(), &'static str> {
+    let stream = TcpStream::connect(addr, url.port).await?;
+    let request = format!("GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: MarOS\r\nAccept: */*\r\nConnection: close\r\n\r\n",
+        url.path, url.authority());
+    stream.write_all(request.as_bytes()).await?;
+    let mut reader = Reader::new(Some(&stream), Vec::with_capacity(READ_SIZE));
+    let response = reader.read_head().await?;
+    if response.is_success() {
+        reader.read_body(&response, &mut body).await?;
+    }
+    Ok(response)
+}
+
+/// Looks up the host of `url` and gets it, following at most `MAX_REDIRECTS`
+/// redirections. Blocks the calling thread, which must not be the `net` thread.
+pub fn fetch<F>(url: &str, mut body: F) -> Result<Response, HttpError>
+    where F: FnMut(&[u8]) -> Result<(), &'static str> {
+    let mut url = Url::parse(url)?;
+    for _ in 0..=MAX_REDIRECTS {
+        let addr = dns::resolve(&url.host)?;
+        let response = task::block_on(get(&url, addr, &mut body))?;
+        match response.header("location") {
+            Some(location) if response.is_redirect() => url = url.join(location)?,
+            _ => return Ok(response),
+        }
+    }
+    Err(HttpError::TooManyRedirects)
+}
+
+#[test_case]
+fn test_url() {
+    let url = Url::parse("http://Example.com:8080/a/b?c=d#e").unwrap();
+    assert_eq!(url, Url { host: "example.com".to_string(), port: 8080, path: "/a/b?c=d".to_string() });
+    assert_eq!(format!("{}", url), "http://example.com:8080/a/b?c=d");
+    assert_eq!(Url::parse("http://10.0.2.2?x").unwrap().path, "/?x");
+    assert_eq!(format!("{}", url.join("/f").unwrap()), "http://example.com:8080/f");
+    assert_eq!(url.join("http://other/").unwrap().port, DEFAULT_PORT);
+    assert_eq!(Url::parse("https://example.com"), Err(HttpError::UnsupportedScheme));
+    assert_eq!(Url::parse("example.com"), Err(HttpError::InvalidUrl));
+    assert_eq!(Url::parse("http://:80/"), Err(HttpError::InvalidUrl));
+}
+
+#[test_case]
+fn test_body() {
+    let read = |answer: &[u8]| -> Result<(Response, Vec<u8>), HttpError> {
+        let mut reader = Reader::new(None, answer.to_vec());
+        let mut body = Vec::new();
+        let response = task::block_on(reader.read_head())?;
+        let mut collect = |data: &[u8]| {
+            body.extend_from_slice(data);
+            Ok(())
+        };
+        task::block_on(reader.read_body(&response, &mut collect))?;
+        Ok((response, body))
+    };
+    let (response, body) = read(b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+        5;ext=1\r\nhello\r\n7\r\n, world\r\n0\r\nTrailer: x\r\n\r\n").unwrap();
+    assert_eq!((response.status, response.reason.as_str()), (200, "OK"));
+    assert_eq!(response.header("TRANSFER-ENCODING"), Some("chunked"));
+    assert_eq!(body, b"hello, world");
+
+    let (_, body) = read(b"HTTP/1.0 200 OK\nContent-Length: 3\n\nabcdef").unwrap();
+    assert_eq!(body, b"abc");
+    let (response, body) = read(b"HTTP/1.1 204 No Content\r\n\r\n").unwrap();
+    assert!(response.is_success() && body.is_empty());
+    assert_eq!(read(b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nabc").err(), Some(HttpError::Truncated));
+    assert_eq!(read(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nz\r\n").err(), Some(HttpError::Malformed));
+    assert_eq!(read(b"SSH-2.0\r\n\r\n").err(), Some(HttpError::Malformed));
+}
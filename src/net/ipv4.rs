@@ -0,0 +1,270 @@
+//! IPv4: addresses, the configuration of the interface packets go out on, and
+//! the header.
+//!
+//! There is one configured interface, which every packet is routed through:
+//! straight to destinations on its subnet, through the gateway to the rest.
+//! Packets are never fragmented, and fragments received are dropped.
+
+use alloc::sync::Arc;
+use core::fmt;
+use core::sync::atomic::{AtomicU16, Ordering};
+use spin::Mutex;
+use crate::net::{arp, tcp, udp, Interface, NetError, PacketBuf};
+use crate::{boot, log_info, log_warn};
+
+pub const HEADER_LEN: usize = 20;
+pub const PROTOCOL_TCP: u8 = 6;
+pub const PROTOCOL_UDP: u8 = 17;
+
+const VERSION_IHL: u8 = 0x45;
+const TTL: u8 = 64;
+const FLAG_DONT_FRAGMENT: u16 = 0x4000;
+/// The more fragments flag and the fragment offset.
+const FRAGMENT: u16 = 0x3fff;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Ipv4Addr(pub [u8; 4]);
+
+impl Ipv4Addr {
+    pub const UNSPECIFIED: Ipv4Addr = Ipv4Addr([0; 4]);
+    pub const BROADCAST: Ipv4Addr = Ipv4Addr([255; 4]);
+
+    /// Parses dotted decimal, like `10.0.2.15`.
+    pub fn parse(text: &str) -> Option<Ipv4Addr> {
+        let mut octets = [0; 4];
+        let mut parts = text.split('.');
+        for octet in octets.iter_mut() {
+            *octet = parts.next()?.parse().ok()?;
+        }
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(Ipv4Addr(octets))
+    }
+
+    pub fn to_u32(self) -> u32 {
+        u32::from_be_bytes(self.0)
+    }
+}
+
+impl fmt::Display for Ipv4Addr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [a, b, c, d] = self.0;
+        write!(f, "{}.{}.{}.{}", a, b, c, d)
+    }
+}
+
+/// The address at `offset` of `bytes`.
+pub fn addr_at(bytes: &[u8], offset: usize) -> Ipv4Addr {
+    Ipv4Addr([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
+
+/// How an interface is configured, from the command line options
+/// `ip=<address>/<prefix length>` and `gateway=<address>|none`. Defaults to
+/// what QEMU's user networking expects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    pub address: Ipv4Addr,
+    pub prefix_len: u8,
+    pub gateway: Option<Ipv4Addr>,
+}
+
+impl Config {
+    pub const DEFAULT: Config = Config {
+        address: Ipv4Addr([10, 0, 2, 15]),
+        prefix_len: 24,
+        gateway: Some(Ipv4Addr([10, 0, 2, 2])),
+    };
+
+    pub fn from_cmdline() -> Config {
+        let cmdline = boot::cmdline();
+        let (ip, gateway) = (cmdline.get("ip"), cmdline.get("gateway"));
+        Config::parse(ip, gateway).unwrap_or_else(|| {
+            log_warn!("cmdline: invalid ip={} gateway={}", ip.unwrap_or(""), gateway.unwrap_or(""));
+            Config::DEFAULT
+        })
+    }
+
+    /// The configuration from the values of the options, the defaults for
+    /// those not given.
+    fn parse(ip: Option<&str>, gateway: Option<&str>) -> Option<Config> {
+        let mut config = Config::DEFAULT;
+        if let Some(ip) = ip {
+            let mut parts = ip.splitn(2, '/');
+            config.address = Ipv4Addr::parse(parts.next()?)?;
+            config.prefix_len = match parts.next() {
+                Some(len) => len.parse().ok().filter(|&len| len <= 32)?,
+                None => 24,
+            };
+        }
+        match gateway {
+            Some("none") => config.gateway = None,
+            Some(gateway) => config.gateway = Some(Ipv4Addr::parse(gateway)?),
+            None => {}
+        }
+        Some(config)
+    }
+
+    pub fn netmask(&self) -> u32 {
+        u32::MAX.checked_shl(32 - u32::from(self.prefix_len)).unwrap_or(0)
+    }
+
+    /// Whether `addr` is on the interface's subnet.
+    pub fn is_local(&self, addr: Ipv4Addr) -> bool {
+        addr.to_u32() & self.netmask() == self.address.to_u32() & self.netmask()
+    }
+
+    /// The neighbor packets to `destination` are sent to.
+    pub fn next_hop(&self, destination: Ipv4Addr) -> Option<Ipv4Addr> {
+        if destination == Ipv4Addr::BROADCAST || self.is_local(destination) {
+            Some(destination)
+        } else {
+            self.gateway
+        }
+    }
+
+    /// Whether packets to `destination` are for us.
+    fn accepts(&self, destination: Ipv4Addr) -> bool {
+        let subnet_broadcast = self.address.to_u32() | !self.netmask();
+        destination == self.address || destination == Ipv4Addr::BROADCAST || destination.to_u32() == subnet_broadcast
+    }
+}
+
+static ROUTE: Mutex<Option<(Arc<Interface>, Config)>> = Mutex::new(None);
+
+/// Makes `interface` the one packets go out on, with `config`, and announces
+/// its address.
+pub fn configure(interface: Arc<Interface>, config: Config) {
+    match config.gateway {
+        Some(gateway) => log_info!("{}: {}/{} gateway {}", interface.name, config.address, config.prefix_len, gateway),
+        None => log_info!("{}: {}/{}", interface.name, config.address, config.prefix_len),
+    }
+    *ROUTE.lock() = Some((interface.clone(), config));
+    let _ = arp::announce(&interface, config.address);
+}
+
+/// The configured interface and its configuration.
+pub fn route() -> Option<(Arc<Interface>, Config)> {
+    ROUTE.lock().clone()
+}
+
+/// Our address, once an interface is configured.
+pub fn local_address() -> Option<Ipv4Addr> {
+    ROUTE.lock().as_ref().map(|(_, config)| config.address)
+}
+
+/// The ones' complement sum of `data` as 16-bit words, added to `sum`.
+pub fn sum(mut sum: u32, data: &[u8]) -> u32 {
+    let mut words = data.chunks_exact(2);
+    for word in &mut words {
+        sum += u32::from(u16::from_be_bytes([word[0], word[1]]));
+    }
+    if let [last] = words.remainder() {
+        sum += u32::from(*last) << 8;
+    }
+    sum
+}
+
+/// The checksum of the words summed up in `sum`.
+pub fn checksum(mut sum: u32) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// The sum of the pseudo header that TCP and UDP checksums cover.
+pub fn pseudo_header_sum(source: Ipv4Addr, destination: Ipv4Addr, protocol: u8, len: usize) -> u32 {
+    let sum = self::sum(0, &source.0);
+    self::sum(sum, &destination.0) + u32::from(protocol) + len as u32
+}
+
+/// Strips the header off a received packet and passes it on by protocol.
+pub fn input(_interface: &Interface, mut packet: PacketBuf) {
+    let config = match ROUTE.lock().as_ref() {
+        Some((_, config)) => *config,
+        None => return,
+    };
+    let header = packet.data();
+    if header.len() < HEADER_LEN || header[0] >> 4 != 4 {
+        return;
+    }
+    let header_len = usize::from(header[0] & 0xf) * 4;
+    let total_len = usize::from(u16::from_be_bytes([header[2], header[3]]));
+    if header_len < HEADER_LEN || total_len < header_len || total_len > header.len() {
+        return;
+    }
+    if checksum(sum(0, &header[..header_len])) != 0 {
+        return;
+    }
+    if u16::from_be_bytes([header[6], header[7]]) & FRAGMENT != 0 {
+        return;
+    }
+    let (protocol, source, destination) = (header[9], addr_at(header, 12), addr_at(header, 16));
+    if !config.accepts(destination) {
+        return;
+    }
+    // Ethernet pads short frames
+    packet.truncate(total_len);
+    packet.pull(header_len);
+    match protocol {
+        PROTOCOL_TCP => tcp::input(source, destination, packet),
+        PROTOCOL_UDP => udp::input(source, destination, packet),
+        _ => {}
+    }
+}
+
+/// Sends `packet` to `destination` with a header for `protocol` in front.
+pub fn send(destination: Ipv4Addr, protocol: u8, mut packet: PacketBuf) -> Result<(), NetError> {
+    static IDENTIFICATION: AtomicU16 = AtomicU16::new(0);
+
+    let (interface, config) = route().ok_or(NetError::Unreachable)?;
+    let next_hop = config.next_hop(destination).ok_or(NetError::Unreachable)?;
+    let total_len = packet.len() + HEADER_LEN;
+    let header = packet.push(HEADER_LEN).map_err(|_| NetError::OutOfMemory)?;
+    header[0] = VERSION_IHL;
+    header[1] = 0;
+    header[2..4].copy_from_slice(&(total_len as u16).to_be_bytes());
+    header[4..6].copy_from_slice(&IDENTIFICATION.fetch_add(1, Ordering::Relaxed).to_be_bytes());
+    header[6..8].copy_from_slice(&FLAG_DONT_FRAGMENT.to_be_bytes());
+    header[8] = TTL;
+    header[9] = protocol;
+    header[10..12].copy_from_slice(&[0, 0]);
+    header[12..16].copy_from_slice(&config.address.0);
+    header[16..20].copy_from_slice(&destination.0);
+    let checksum = checksum(sum(0, header));
+    header[10..12].copy_from_slice(&checksum.to_be_bytes());
+    arp::send(&interface, next_hop, packet)
+}
+
+#[test_case]
+fn test_config() {
+    assert_eq!(Ipv4Addr::parse("10.0.2.15"), Some(Ipv4Addr([10, 0, 2, 15])));
+    assert_eq!(Ipv4Addr::parse("10.0.2"), None);
+    assert_eq!(Ipv4Addr::parse("10.0.2.256"), None);
+    assert_eq!(Ipv4Addr::parse("10.0.2.1.1"), None);
+    assert_eq!(Config::parse(None, None), Some(Config::DEFAULT));
+
+    let config = Config::parse(Some("192.168.1.20/16"), Some("none")).unwrap();
+    assert_eq!((config.address, config.prefix_len, config.gateway), (Ipv4Addr([192, 168, 1, 20]), 16, None));
+    assert_eq!(config.netmask(), 0xffff_0000);
+    assert_eq!(config.next_hop(Ipv4Addr([192, 168, 7, 1])), Some(Ipv4Addr([192, 168, 7, 1])));
+    assert_eq!(config.next_hop(Ipv4Addr([10, 0, 0, 1])), None);
+    assert!(config.accepts(Ipv4Addr([192, 168, 255, 255])));
+    assert!(!config.accepts(Ipv4Addr([192, 168, 1, 21])));
+    assert_eq!(Config::parse(Some("10.0.2.15/33"), None), None);
+    assert_eq!(Config::DEFAULT.next_hop(Ipv4Addr([1, 1, 1, 1])), Some(Ipv4Addr([10, 0, 2, 2])));
+    assert_eq!(Config { prefix_len: 0, ..Config::DEFAULT }.netmask(), 0);
+}
+
+#[test_case]
+fn test_checksum() {
+    // a common textbook example, whose checksum is b861
+    let header = [0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0x00, 0x00,
+                  0xc0, 0xa8, 0x00, 0x01, 0xc0, 0xa8, 0x00, 0xc7];
+    assert_eq!(checksum(sum(0, &header)), 0xb861);
+    let mut with_checksum = header;
+    with_checksum[10..12].copy_from_slice(&[0xb8, 0x61]);
+    assert_eq!(checksum(sum(0, &with_checksum)), 0);
+    assert_eq!(sum(0, &[0x01]), 0x0100);
+}
@@ -0,0 +1,157 @@
+//! Packet buffers, which carry a frame through the layers of the network
+//! stack without copying it.
+//!
+//! A `PacketBuf` is a window into a buffer from a slab cache of its own. On
+//! the way down, every layer `push`es its header into the headroom reserved
+//! in front of the data; on the way up, every layer `pull`s its header off
+//! again. Clones share the buffer, so a segment kept for retransmission and
+//! the frame handed to the driver are one buffer. Writing to a shared buffer
+//! copies it first.
+
+use alloc::sync::Arc;
+use x86_64::PhysAddr;
+use crate::memory::{self, slab::{Cache, SlabBox}};
+
+/// Bytes in a buffer, enough for a whole Ethernet frame. Divides the page
+/// size, so a buffer is physically contiguous and can be handed to devices.
+pub const BUFFER_SIZE: usize = 2048;
+/// Room for the Ethernet, IPv4 and TCP headers with options, reserved in
+/// front of data to be sent.
+pub const HEADROOM: usize = 128;
+
+type Buffer = SlabBox<[u8; BUFFER_SIZE]>;
+
+static POOL: Cache<[u8; BUFFER_SIZE]> = Cache::new("net buffers", || [0; BUFFER_SIZE]);
+
+/// Packet data and the room around it for headers and trailers.
+#[derive(Clone)]
+pub struct PacketBuf {
+    buffer: Arc<Buffer>,
+    start: usize,
+    end: usize,
+}
+
+impl PacketBuf {
+    /// An empty packet with `headroom` bytes reserved in front of it.
+    pub fn new(headroom: usize) -> Result<PacketBuf, &'static str> {
+        if headroom > BUFFER_SIZE {
+            return Err("headroom larger than a buffer");
+        }
+        Ok(PacketBuf { buffer: Arc::new(POOL.alloc()?), start: headroom, end: headroom })
+    }
+
+    /// A packet holding a copy of `data`, with `HEADROOM` in front of it.
+    pub fn from_slice(data: &[u8]) -> Result<PacketBuf, &'static str> {
+        let mut packet = PacketBuf::new(HEADROOM)?;
+        packet.put(data.len())?.copy_from_slice(data);
+        Ok(packet)
+    }
+
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    /// Bytes that can be pushed in front.
+    pub fn headroom(&self) -> usize {
+        self.start
+    }
+
+    /// Bytes that can be put at the end.
+    pub fn tailroom(&self) -> usize {
+        BUFFER_SIZE - self.end
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.buffer[self.start..self.end]
+    }
+
+    pub fn data_mut(&mut self) -> Result<&mut [u8], &'static str> {
+        let (start, end) = (self.start, self.end);
+        Ok(&mut self.unshare()?[start..end])
+    }
+
+    /// Adds `len` bytes in front, for a header the caller fills in.
+    pub fn push(&mut self, len: usize) -> Result<&mut [u8], &'static str> {
+        if len > self.start {
+            return Err("not enough headroom");
+        }
+        self.unshare()?;
+        self.start -= len;
+        let start = self.start;
+        Ok(&mut self.unshare()?[start..start + len])
+    }
+
+    /// Removes the first `len` bytes, a header that was read, and returns
+    /// them, or `None` if the packet is shorter.
+    pub fn pull(&mut self, len: usize) -> Option<&[u8]> {
+        if len > self.len() {
+            return None;
+        }
+        self.start += len;
+        Some(&self.buffer[self.start - len..self.start])
+    }
+
+    /// Adds `len` bytes at the end, for the caller to fill in.
+    pub fn put(&mut self, len: usize) -> Result<&mut [u8], &'static str> {
+        if len > self.tailroom() {
+            return Err("not enough tailroom");
+        }
+        self.unshare()?;
+        let end = self.end;
+        self.end += len;
+        Ok(&mut self.unshare()?[end..end + len])
+    }
+
+    /// Drops everything after the first `len` bytes, like the padding of a
+    /// short frame.
+    pub fn truncate(&mut self, len: usize) {
+        self.end = self.start + len.min(self.len());
+    }
+
+    /// Physical address of the data, for devices to read it from or write
+    /// the tailroom from there on.
+    pub fn phys(&self) -> PhysAddr {
+        let virt = self.buffer.as_ptr() as u64 + self.start as u64;
+        PhysAddr::new(virt - memory::physical_memory_offset().as_u64())
+    }
+
+    /// The buffer, copied first if clones share it.
+    fn unshare(&mut self) -> Result<&mut [u8; BUFFER_SIZE], &'static str> {
+        if Arc::get_mut(&mut self.buffer).is_none() {
+            let mut copy = POOL.alloc()?;
+            copy[self.start..self.end].copy_from_slice(self.data());
+            self.buffer = Arc::new(copy);
+        }
+        Arc::get_mut(&mut self.buffer).map(|buffer| &mut **buffer).ok_or("packet buffer shared")
+    }
+}
+
+#[test_case]
+fn test_packet_buf() {
+    let mut packet = PacketBuf::from_slice(b"payload").unwrap();
+    assert_eq!((packet.headroom(), packet.len()), (HEADROOM, 7));
+    packet.push(2).unwrap().copy_from_slice(b"h2");
+    packet.push(2).unwrap().copy_from_slice(b"h1");
+    assert_eq!(packet.data(), b"h1h2payload");
+    assert!(packet.push(HEADROOM).is_err());
+
+    // clones share the buffer until one is written to
+    let mut clone = packet.clone();
+    assert_eq!(clone.pull(2), Some(&b"h1"[..]));
+    assert_eq!(clone.data().as_ptr(), packet.data()[2..].as_ptr());
+    clone.data_mut().unwrap()[0] = b'H';
+    assert_eq!(clone.data(), b"H2payload");
+    assert_eq!(packet.data(), b"h1h2payload");
+    assert_ne!(clone.data().as_ptr(), packet.data()[2..].as_ptr());
+
+    assert_eq!(clone.pull(10), None);
+    packet.put(3).unwrap().copy_from_slice(b"pad");
+    packet.truncate(packet.len() - 3);
+    assert_eq!(packet.data(), b"h1h2payload");
+    assert_eq!(packet.tailroom(), BUFFER_SIZE - HEADROOM - 7);
+    assert_eq!(packet.phys().as_u64() % BUFFER_SIZE as u64, (HEADROOM - 4) as u64);
+}
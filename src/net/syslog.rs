@@ -0,0 +1,220 @@
+//! Ships the kernel log to a syslog server over UDP, for long runs whose log
+//! outgrows the screen. Turned on by the command line option
+//! `syslog=<address>[:<port>]`, or by the `syslog` command.
+//!
+//! Messages are logged anywhere, interrupt handlers included, so `queue`
+//! only copies them into a fixed ring. The `syslog` thread sends them, one
+//! RFC 5424 datagram each, once an interface is configured. There is no
+//! wall clock, so the uptime goes in front of the text instead of a
+//! timestamp. Messages logged while the ring is full are dropped.
+
+use alloc::format;
+use alloc::string::String;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+use crate::arch::interrupts::without_interrupts;
+use crate::klog::Level;
+use crate::net::ipv4::{self, Ipv4Addr};
+use crate::net::udp;
+use crate::sched::{self, WaitQueue};
+use crate::{boot, log_warn, time};
+
+pub const DEFAULT_PORT: u16 = 514;
+
+/// Port the datagrams are sent from.
+const SOURCE_PORT: u16 = 514;
+const RECORDS: usize = 32;
+/// Bytes kept of a message; the rest is cut off.
+const TEXT_SIZE: usize = 200;
+/// How often the thread looks for a configured interface.
+const ROUTE_POLL: Duration = Duration::from_millis(500);
+
+/// A message waiting to be sent.
+#[derive(Clone, Copy)]
+struct Record {
+    level: Level,
+    uptime: Duration,
+    len: usize,
+    text: [u8; TEXT_SIZE],
+}
+
+impl Record {
+    const EMPTY: Record = Record { level: Level::Info, uptime: Duration::from_secs(0), len: 0, text: [0; TEXT_SIZE] };
+
+    fn text(&self) -> &str {
+        core::str::from_utf8(&self.text[..self.len]).unwrap_or("")
+    }
+}
+
+impl Write for Record {
+    /// Appends `s`, cut at a character boundary where it doesn't fit.
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut len = s.len().min(TEXT_SIZE - self.len);
+        while !s.is_char_boundary(len) {
+            len -= 1;
+        }
+        self.text[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+struct Ring {
+    records: [Record; RECORDS],
+    start: usize,
+    len: usize,
+}
+
+static RING: Mutex<Ring> = Mutex::new(Ring { records: [Record::EMPTY; RECORDS], start: 0, len: 0 });
+static TARGET: Mutex<Option<(Ipv4Addr, u16)>> = Mutex::new(None);
+/// Whether there is a target, checked before taking any lock.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static STARTED: AtomicBool = AtomicBool::new(false);
+static WAKEUP: WaitQueue = WaitQueue::new();
+static SENT: AtomicU64 = AtomicU64::new(0);
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+static FAILED: AtomicU64 = AtomicU64::new(0);
+
+/// Messages handled since boot.
+#[derive(Debug, Clone, Copy)]
+pub struct Stats {
+    pub sent: u64,
+    /// Logged while the ring was full.
+    pub dropped: u64,
+    /// Refused by the network stack, like for lack of buffers.
+    pub failed: u64,
+}
+
+/// Ships the log to the target from the command line, if there is one.
+/// Must be called after `sched::init`.
+pub fn init() -> Result<(), &'static str> {
+    match boot::cmdline().get("syslog") {
+        Some(value) => match parse_target(value) {
+            Some(target) => set_target(Some(target)),
+            None => {
+                log_warn!("cmdline: invalid syslog={}", value);
+                Ok(())
+            }
+        },
+        None => Ok(()),
+    }
+}
+
+/// Parses `<address>[:<port>]`.
+pub fn parse_target(text: &str) -> Option<(Ipv4Addr, u16)> {
+    let mut parts = text.splitn(2, ':');
+    let addr = Ipv4Addr::parse(parts.next()?)?;
+    let port = match parts.next() {
+        Some(port) => port.parse().ok()?,
+        None => DEFAULT_PORT,
+    };
+    Some((addr, port))
+}
+
+/// Ships the log messages from now on to `target`, or stops shipping them.
+pub fn set_target(target: Option<(Ipv4Addr, u16)>) -> Result<(), &'static str> {
+    if target.is_some() && !STARTED.swap(true, Ordering::AcqRel) {
+        if let Err(error) = sched::spawn("syslog", sender) {
+            STARTED.store(false, Ordering::Release);
+            return Err(error);
+        }
+    }
+    *TARGET.lock() = target;
+    ENABLED.store(target.is_some(), Ordering::Release);
+    Ok(())
+}
+
+pub fn target() -> Option<(Ipv4Addr, u16)> {
+    *TARGET.lock()
+}
+
+pub fn stats() -> Stats {
+    Stats {
+        sent: SENT.load(Ordering::Relaxed),
+        dropped: DROPPED.load(Ordering::Relaxed),
+        failed: FAILED.load(Ordering::Relaxed),
+    }
+}
+
+/// Queues a message to be shipped, if shipping is on. Called by `klog` for
+/// every message logged.
+pub(crate) fn queue(level: Level, args: fmt::Arguments) {
+    if !ENABLED.load(Ordering::Acquire) {
+        return;
+    }
+    let uptime = time::uptime();
+    let queued = without_interrupts(|| {
+        // only a panic can leave it locked here
+        let mut ring = match RING.try_lock() {
+            Some(ring) if ring.len < RECORDS => ring,
+            _ => return false,
+        };
+        let index = (ring.start + ring.len) % RECORDS;
+        let record = &mut ring.records[index];
+        *record = Record { level, uptime, ..Record::EMPTY };
+        let _ = record.write_fmt(args);
+        ring.len += 1;
+        true
+    });
+    if queued {
+        WAKEUP.notify_all();
+    } else {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// The syslog severity of `level`.
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+/// Formats `record` as an RFC 5424 message from the kernel facility, which is 0.
+fn message(record: &Record) -> String {
+    format!("<{}>1 - maros kernel - - - [{:>5}.{:06}] {}", severity(record.level), record.uptime.as_secs(),
+        record.uptime.subsec_micros(), record.text())
+}
+
+/// Sends the queued records, waiting for an interface to be configured first.
+fn sender() {
+    loop {
+        WAKEUP.wait_until(|| RING.lock().len > 0);
+        if ipv4::route().is_none() {
+            sched::sleep(ROUTE_POLL);
+            continue;
+        }
+        let record = without_interrupts(|| {
+            let mut ring = RING.lock();
+            let record = ring.records[ring.start];
+            ring.start = (ring.start + 1) % RECORDS;
+            ring.len -= 1;
+            record
+        });
+        let target = *TARGET.lock();
+        if let Some((addr, port)) = target {
+            match udp::send(SOURCE_PORT, addr, port, message(&record).as_bytes()) {
+                Ok(()) => SENT.fetch_add(1, Ordering::Relaxed),
+                Err(_) => FAILED.fetch_add(1, Ordering::Relaxed),
+            };
+        }
+    }
+}
+
+#[test_case]
+fn test_message() {
+    assert_eq!(parse_target("10.0.2.2"), Some((Ipv4Addr([10, 0, 2, 2]), DEFAULT_PORT)));
+    assert_eq!(parse_target("10.0.2.2:5140"), Some((Ipv4Addr([10, 0, 2, 2]), 5140)));
+    assert_eq!(parse_target("10.0.2.2:x"), None);
+
+    let mut record = Record { level: Level::Warn, uptime: Duration::from_micros(12_345_678), ..Record::EMPTY };
+    write!(record, "disk {}", "é".repeat(TEXT_SIZE)).unwrap();
+    // cut before the character that didn't fit
+    assert_eq!(record.len, TEXT_SIZE - 1);
+    assert!(message(&record).starts_with("<4>1 - maros kernel - - - [   12.345678] disk éé"));
+}
@@ -0,0 +1,999 @@
+//! TCP: reliable byte streams, through `TcpListener` and `TcpStream`.
+//!
+//! Every connection has a send and a receive buffer, sized by `Buffers`.
+//! Writing waits while the send buffer is full; it empties as the peer
+//! acknowledges data, of which no more is in flight than the peer's window
+//! allows. The window advertised to the peer is the free room in the receive
+//! buffer, so a reader that falls behind slows the sender down instead of
+//! losing data. Accepting, connecting, reading and writing are futures for
+//! the executor in `task`, woken by the `net` thread as segments arrive.
+//!
+//! Kept simple: segments that arrive out of order are dropped for the peer
+//! to send again, lost segments are sent again from the first byte not
+//! acknowledged after a timeout that doubles every time, and there is no
+//! congestion control.
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::future::{poll_fn, Future};
+use core::ops::RangeInclusive;
+use core::task::{Poll, Waker};
+use core::time::Duration;
+use spin::Mutex;
+use crate::net::ipv4::{self, Ipv4Addr, PROTOCOL_TCP};
+use crate::net::packet::HEADROOM;
+use crate::net::{NetError, PacketBuf};
+use crate::rand;
+use crate::time::Instant;
+
+const HEADER_LEN: usize = 20;
+const FIN: u8 = 1 << 0;
+const SYN: u8 = 1 << 1;
+const RST: u8 = 1 << 2;
+const PSH: u8 = 1 << 3;
+const ACK: u8 = 1 << 4;
+const OPTION_END: u8 = 0;
+const OPTION_NOP: u8 = 1;
+const OPTION_MSS: u8 = 2;
+
+/// Largest segment received, what fits in an Ethernet frame.
+const MSS: usize = 1460;
+/// Largest segment sent to peers that don't tell theirs.
+const DEFAULT_MSS: usize = 536;
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+const MAX_RTO: Duration = Duration::from_secs(30);
+/// Timeouts in a row before a connection is given up.
+const MAX_RETRIES: u32 = 8;
+/// How long a closed connection keeps its port, to acknowledge the peer's
+/// last segments again if they are sent again.
+const TIME_WAIT: Duration = Duration::from_secs(2);
+/// Connections a listener holds that were not accepted yet.
+const BACKLOG: usize = 8;
+const EPHEMERAL_PORTS: RangeInclusive<u16> = 49152..=65535;
+/// Largest window the header can carry, without window scaling.
+const MAX_WINDOW: usize = 0xffff;
+
+/// Sizes of the buffers of a connection, in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Buffers {
+    pub send: usize,
+    /// Also the largest window advertised, which is at most 64 KiB.
+    pub receive: usize,
+}
+
+impl Default for Buffers {
+    fn default() -> Buffers {
+        Buffers { send: 4096, receive: 4096 }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    SynSent,
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait,
+    Closed,
+}
+
+impl State {
+    pub fn name(self) -> &'static str {
+        match self {
+            State::SynSent => "SYN-SENT",
+            State::SynReceived => "SYN-RECEIVED",
+            State::Established => "ESTABLISHED",
+            State::FinWait1 => "FIN-WAIT-1",
+            State::FinWait2 => "FIN-WAIT-2",
+            State::CloseWait => "CLOSE-WAIT",
+            State::Closing => "CLOSING",
+            State::LastAck => "LAST-ACK",
+            State::TimeWait => "TIME-WAIT",
+            State::Closed => "CLOSED",
+        }
+    }
+}
+
+/// The local port, remote address and remote port of a connection.
+type Key = (u16, Ipv4Addr, u16);
+
+/// The fields of a received segment's header.
+#[derive(Debug, Clone, Copy, Default)]
+struct Header {
+    source_port: u16,
+    destination_port: u16,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    window: u16,
+    mss: Option<u16>,
+}
+
+/// What became of a connection through a segment or a timeout, for the
+/// caller to act on once it no longer holds its lock.
+#[derive(Debug, Default)]
+struct Events {
+    established: bool,
+    closed: bool,
+}
+
+/// The state of a connection.
+struct Tcb {
+    state: State,
+    /// Initial send sequence number, of our SYN.
+    iss: u32,
+    /// Oldest byte not acknowledged.
+    snd_una: u32,
+    /// Next byte to send, moved back to `snd_una` to send again.
+    snd_nxt: u32,
+    /// The farthest `snd_nxt` was.
+    snd_max: u32,
+    /// The peer's window, from `snd_una`.
+    snd_wnd: u32,
+    /// Next byte expected from the peer.
+    rcv_nxt: u32,
+    /// Largest segment sent.
+    mss: usize,
+    buffers: Buffers,
+    /// Data written from `snd_una` on, sent or not.
+    send: VecDeque<u8>,
+    received: VecDeque<u8>,
+    /// Set by `close`: a FIN follows the data in `send`.
+    closing: bool,
+    /// The peer's FIN arrived: no data follows `received`.
+    fin_received: bool,
+    error: Option<NetError>,
+    rto: Duration,
+    /// When to send again what is in flight, or probe a closed window.
+    retransmit_at: Option<Instant>,
+    retries: u32,
+    /// Send one byte even though the window is closed.
+    probe: bool,
+    time_wait_until: Option<Instant>,
+    /// Window advertised in the last segment sent.
+    advertised: u32,
+    reader: Option<Waker>,
+    writer: Option<Waker>,
+}
+
+fn wake(waker: &mut Option<Waker>) {
+    if let Some(waker) = waker.take() {
+        waker.wake();
+    }
+}
+
+/// Whether sequence number `a` comes before `b`.
+fn before(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+impl Tcb {
+    fn new(state: State, buffers: Buffers) -> Tcb {
+        let iss = rand::u64() as u32;
+        let receive = buffers.receive.min(MAX_WINDOW);
+        Tcb {
+            state,
+            iss,
+            snd_una: iss,
+            snd_nxt: iss.wrapping_add(1),
+            snd_max: iss.wrapping_add(1),
+            snd_wnd: 0,
+            rcv_nxt: 0,
+            mss: DEFAULT_MSS,
+            buffers: Buffers { receive, ..buffers },
+            send: VecDeque::new(),
+            received: VecDeque::new(),
+            closing: false,
+            fin_received: false,
+            error: None,
+            rto: INITIAL_RTO,
+            retransmit_at: Some(Instant::now() + INITIAL_RTO),
+            retries: 0,
+            probe: false,
+            time_wait_until: None,
+            advertised: 0,
+            reader: None,
+            writer: None,
+        }
+    }
+
+    /// The window to advertise: the free room in the receive buffer.
+    fn window(&self) -> u32 {
+        (self.buffers.receive - self.received.len()) as u32
+    }
+
+    fn wake_all(&mut self) {
+        wake(&mut self.reader);
+        wake(&mut self.writer);
+    }
+
+    /// Sends a segment with `payload` from sequence number `seq`, and with
+    /// what we acknowledge and our window.
+    fn transmit(&mut self, key: Key, seq: u32, flags: u8, payload: PacketBuf) {
+        let flags = if self.state == State::SynSent { flags } else { flags | ACK };
+        let mss = if flags & SYN != 0 { Some(MSS as u16) } else { None };
+        self.advertised = self.window();
+        let header = Header { seq, ack: self.rcv_nxt, flags, window: self.advertised as u16, mss, ..Header::default() };
+        let _ = send_segment(key, header, payload);
+    }
+
+    fn transmit_empty(&mut self, key: Key, seq: u32, flags: u8) {
+        if let Ok(payload) = PacketBuf::new(HEADROOM) {
+            self.transmit(key, seq, flags, payload);
+        }
+    }
+
+    fn send_ack(&mut self, key: Key) {
+        self.transmit_empty(key, self.snd_nxt, 0);
+    }
+
+    /// Gives the connection up with `error`.
+    fn abort(&mut self, error: NetError) {
+        self.state = State::Closed;
+        self.error = Some(error);
+        self.send.clear();
+        self.retransmit_at = None;
+        self.wake_all();
+    }
+
+    /// Sends the data and FIN the window allows.
+    fn output(&mut self, key: Key) {
+        if !matches!(self.state, State::Established | State::CloseWait | State::FinWait1 | State::Closing | State::LastAck) {
+            return;
+        }
+        let now = Instant::now();
+        loop {
+            let offset = self.snd_nxt.wrapping_sub(self.snd_una) as usize;
+            if offset > self.send.len() {
+                // the FIN went out
+                break;
+            }
+            let window = self.snd_wnd.max(self.probe as u32);
+            let room = self.snd_una.wrapping_add(window).wrapping_sub(self.snd_nxt) as i32;
+            let len = (self.send.len() - offset).min(room.max(0) as usize).min(self.mss);
+            let fin = self.closing && offset + len == self.send.len();
+            if len == 0 && !fin {
+                break;
+            }
+            let mut payload = match PacketBuf::new(HEADROOM) {
+                Ok(payload) => payload,
+                Err(_) => break,
+            };
+            if let Ok(data) = payload.put(len) {
+                data.iter_mut().zip(self.send.iter().skip(offset)).for_each(|(to, &from)| *to = from);
+            }
+            let flags = if len > 0 { PSH } else { 0 } | if fin { FIN } else { 0 };
+            self.transmit(key, self.snd_nxt, flags, payload);
+            self.probe = false;
+            self.snd_nxt = self.snd_nxt.wrapping_add(len as u32 + fin as u32);
+            if before(self.snd_max, self.snd_nxt) {
+                self.snd_max = self.snd_nxt;
+            }
+            if self.retransmit_at.is_none() {
+                self.retransmit_at = Some(now + self.rto);
+            }
+            if fin {
+                break;
+            }
+        }
+        // data waits for the window to open: probe it when the timer runs out
+        if self.snd_una == self.snd_max && !self.send.is_empty() && self.retransmit_at.is_none() {
+            self.retransmit_at = Some(now + self.rto);
+        }
+    }
+
+    /// Handles a segment received in `SynSent`.
+    fn input_syn_sent(&mut self, key: Key, header: &Header) -> Events {
+        let mut events = Events::default();
+        let flags = header.flags;
+        if flags & ACK != 0 && header.ack != self.snd_nxt {
+            if flags & RST == 0 {
+                reset(key, header.ack, 0, RST);
+            }
+            return events;
+        }
+        if flags & RST != 0 {
+            if flags & ACK != 0 {
+                self.abort(NetError::ConnectionRefused);
+                events.closed = true;
+            }
+            return events;
+        }
+        if flags & SYN == 0 {
+            return events;
+        }
+        self.rcv_nxt = header.seq.wrapping_add(1);
+        self.mss = header.mss.map_or(DEFAULT_MSS, usize::from).min(MSS);
+        self.snd_wnd = u32::from(header.window);
+        if flags & ACK != 0 {
+            self.snd_una = header.ack;
+            self.state = State::Established;
+            self.retransmit_at = None;
+            self.retries = 0;
+            self.send_ack(key);
+            self.wake_all();
+        } else {
+            // both sides opened at once
+            self.state = State::SynReceived;
+            self.transmit_empty(key, self.iss, SYN);
+        }
+        events
+    }
+
+    /// Handles a received segment.
+    fn input(&mut self, key: Key, header: &Header, mut payload: PacketBuf) -> Events {
+        if self.state == State::SynSent {
+            return self.input_syn_sent(key, header);
+        }
+        let mut events = Events::default();
+        let flags = header.flags;
+        if flags & RST != 0 {
+            // only a reset at the expected place is believed
+            if header.seq == self.rcv_nxt {
+                self.abort(NetError::ConnectionReset);
+                events.closed = true;
+            }
+            return events;
+        }
+        if flags & SYN != 0 {
+            // our SYN-ACK or ACK got lost, the peer sent its SYN again
+            self.send_ack(key);
+            return events;
+        }
+
+        // trim off what was received already
+        let mut fin = flags & FIN != 0;
+        let ahead = header.seq.wrapping_sub(self.rcv_nxt) as i32;
+        let consumes = payload.len() + fin as usize;
+        if ahead > 0 {
+            // out of order, the peer sends it again
+            self.send_ack(key);
+            return events;
+        }
+        let old = (-ahead) as usize;
+        if old > 0 {
+            if old > payload.len() {
+                fin = false;
+            }
+            payload.pull(old.min(payload.len()));
+        }
+        let mut need_ack = consumes > 0 && old >= consumes;
+
+        if flags & ACK == 0 {
+            return events;
+        }
+        if self.state == State::SynReceived {
+            if header.ack != self.snd_nxt {
+                reset(key, header.ack, 0, RST);
+                return events;
+            }
+            self.state = State::Established;
+            self.snd_una = header.ack;
+            self.retransmit_at = None;
+            self.retries = 0;
+            events.established = true;
+        }
+        let acked = header.ack.wrapping_sub(self.snd_una);
+        if acked > self.snd_max.wrapping_sub(self.snd_una) {
+            // acknowledges what was never sent
+            self.send_ack(key);
+            return events;
+        }
+        self.snd_wnd = u32::from(header.window);
+        if acked > 0 {
+            let data = (acked as usize).min(self.send.len());
+            self.send.drain(..data);
+            self.snd_una = header.ack;
+            if before(self.snd_nxt, self.snd_una) {
+                self.snd_nxt = self.snd_una;
+            }
+            self.retries = 0;
+            self.rto = INITIAL_RTO;
+            self.retransmit_at = if self.snd_una == self.snd_max { None } else { Some(Instant::now() + self.rto) };
+            wake(&mut self.writer);
+            // our FIN was acknowledged
+            if self.closing && acked as usize > data {
+                match self.state {
+                    State::FinWait1 => self.state = State::FinWait2,
+                    State::Closing => self.enter_time_wait(),
+                    State::LastAck => {
+                        self.state = State::Closed;
+                        events.closed = true;
+                        self.wake_all();
+                        return events;
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        if !payload.is_empty() && matches!(self.state, State::Established | State::FinWait1 | State::FinWait2) {
+            let len = payload.len().min(self.window() as usize);
+            self.received.extend(payload.data()[..len].iter().copied());
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(len as u32);
+            if len < payload.len() {
+                // past our window, the rest and the FIN come again
+                fin = false;
+            }
+            wake(&mut self.reader);
+            need_ack = true;
+        }
+        if fin && matches!(self.state, State::Established | State::FinWait1 | State::FinWait2) {
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+            self.fin_received = true;
+            self.state = match self.state {
+                State::FinWait1 => State::Closing,
+                State::FinWait2 => {
+                    self.enter_time_wait();
+                    State::TimeWait
+                }
+                _ => State::CloseWait,
+            };
+            wake(&mut self.reader);
+            need_ack = true;
+        }
+        let sent = self.snd_nxt;
+        self.output(key);
+        if need_ack && self.snd_nxt == sent {
+            self.send_ack(key);
+        }
+        events
+    }
+
+    fn enter_time_wait(&mut self) {
+        self.state = State::TimeWait;
+        self.retransmit_at = None;
+        self.time_wait_until = Some(Instant::now() + TIME_WAIT);
+    }
+
+    /// Runs the timers. Returns whether the connection is closed.
+    fn tick(&mut self, key: Key, now: Instant) -> bool {
+        if let Some(until) = self.time_wait_until {
+            if now >= until {
+                self.state = State::Closed;
+                self.wake_all();
+            }
+            return self.state == State::Closed;
+        }
+        match self.retransmit_at {
+            Some(at) if now >= at => {}
+            _ => return self.state == State::Closed,
+        }
+        // probing a closed window goes on as long as the peer acknowledges it
+        let probing = self.snd_wnd == 0 && !matches!(self.state, State::SynSent | State::SynReceived);
+        if !probing {
+            self.retries += 1;
+        }
+        if self.retries > MAX_RETRIES {
+            reset(key, self.snd_nxt, self.rcv_nxt, RST | ACK);
+            self.abort(NetError::TimedOut);
+            return true;
+        }
+        self.rto = (self.rto * 2).min(MAX_RTO);
+        self.retransmit_at = Some(now + self.rto);
+        match self.state {
+            State::SynSent | State::SynReceived => self.transmit_empty(key, self.iss, SYN),
+            _ => {
+                self.snd_nxt = self.snd_una;
+                self.probe = probing;
+                self.output(key);
+            }
+        }
+        false
+    }
+
+    /// Tells the peer about room the reader made, once it is worth a segment.
+    fn update_window(&mut self, key: Key) {
+        let threshold = self.mss.min(self.buffers.receive / 2) as u32;
+        if self.window() >= self.advertised + threshold && matches!(self.state, State::Established | State::FinWait1 | State::FinWait2) {
+            self.send_ack(key);
+        }
+    }
+
+    /// Queues a FIN after the data written.
+    fn close(&mut self, key: Key) -> bool {
+        match self.state {
+            State::SynSent | State::SynReceived => {
+                self.state = State::Closed;
+                self.wake_all();
+                return true;
+            }
+            State::Established => self.state = State::FinWait1,
+            State::CloseWait => self.state = State::LastAck,
+            _ => return false,
+        }
+        self.closing = true;
+        self.output(key);
+        false
+    }
+}
+
+struct Connection {
+    key: Key,
+    /// Port of the listener that accepts the connection once it is established.
+    listener: Option<u16>,
+    tcb: Mutex<Tcb>,
+}
+
+struct Backlog {
+    /// Established connections waiting to be accepted.
+    queue: VecDeque<Arc<Connection>>,
+    waker: Option<Waker>,
+}
+
+struct Listener {
+    port: u16,
+    buffers: Buffers,
+    backlog: Mutex<Backlog>,
+}
+
+struct Sockets {
+    listeners: BTreeMap<u16, Arc<Listener>>,
+    connections: BTreeMap<Key, Arc<Connection>>,
+    next_port: u16,
+}
+
+/// Locked before the `tcb` of any connection, never while one is.
+static SOCKETS: Mutex<Sockets> = Mutex::new(Sockets {
+    listeners: BTreeMap::new(),
+    connections: BTreeMap::new(),
+    next_port: *EPHEMERAL_PORTS.start(),
+});
+
+/// Forgets `connection`, unless another one took its key already.
+fn remove(connection: &Arc<Connection>) {
+    let mut sockets = SOCKETS.lock();
+    if sockets.connections.get(&connection.key).map_or(false, |other| Arc::ptr_eq(other, connection)) {
+        sockets.connections.remove(&connection.key);
+    }
+}
+
+/// Acts on what happened to `connection`.
+fn handle(connection: &Arc<Connection>, events: Events) {
+    if events.established {
+        let listener = connection.listener.and_then(|port| SOCKETS.lock().listeners.get(&port).cloned());
+        match listener {
+            Some(listener) => {
+                let mut backlog = listener.backlog.lock();
+                backlog.queue.push_back(connection.clone());
+                wake(&mut backlog.waker);
+            }
+            None => {
+                // the listener is gone
+                let mut tcb = connection.tcb.lock();
+                reset(connection.key, tcb.snd_nxt, tcb.rcv_nxt, RST | ACK);
+                tcb.abort(NetError::ConnectionReset);
+                drop(tcb);
+                remove(connection);
+            }
+        }
+    }
+    if events.closed {
+        remove(connection);
+    }
+}
+
+/// Builds a segment in front of `payload` and sends it.
+fn send_segment(key: Key, header: Header, mut payload: PacketBuf) -> Result<(), NetError> {
+    let (local_port, remote, remote_port) = key;
+    let local = ipv4::local_address().ok_or(NetError::Unreachable)?;
+    let header_len = HEADER_LEN + if header.mss.is_some() { 4 } else { 0 };
+    let bytes = payload.push(header_len).map_err(|_| NetError::OutOfMemory)?;
+    bytes[0..2].copy_from_slice(&local_port.to_be_bytes());
+    bytes[2..4].copy_from_slice(&remote_port.to_be_bytes());
+    bytes[4..8].copy_from_slice(&header.seq.to_be_bytes());
+    bytes[8..12].copy_from_slice(&header.ack.to_be_bytes());
+    bytes[12] = ((header_len / 4) as u8) << 4;
+    bytes[13] = header.flags;
+    bytes[14..16].copy_from_slice(&header.window.to_be_bytes());
+    bytes[16..20].copy_from_slice(&[0; 4]);
+    if let Some(mss) = header.mss {
+        bytes[20..24].copy_from_slice(&[OPTION_MSS, 4, (mss >> 8) as u8, mss as u8]);
+    }
+    let len = payload.len();
+    let sum = ipv4::pseudo_header_sum(local, remote, PROTOCOL_TCP, len);
+    let checksum = ipv4::checksum(ipv4::sum(sum, payload.data()));
+    payload.data_mut().map_err(|_| NetError::OutOfMemory)?[16..18].copy_from_slice(&checksum.to_be_bytes());
+    ipv4::send(remote, PROTOCOL_TCP, payload)
+}
+
+/// Sends a segment without a connection, to reset one the peer believes in.
+fn reset(key: Key, seq: u32, ack: u32, flags: u8) {
+    if let Ok(payload) = PacketBuf::new(HEADROOM) {
+        let _ = send_segment(key, Header { seq, ack, flags, ..Header::default() }, payload);
+    }
+}
+
+/// Checks a received segment, strips its header off and returns it.
+fn parse(packet: &mut PacketBuf, source: Ipv4Addr, destination: Ipv4Addr) -> Option<Header> {
+    let data = packet.data();
+    if data.len() < HEADER_LEN {
+        return None;
+    }
+    let sum = ipv4::pseudo_header_sum(source, destination, PROTOCOL_TCP, data.len());
+    if ipv4::checksum(ipv4::sum(sum, data)) != 0 {
+        return None;
+    }
+    let header_len = usize::from(data[12] >> 4) * 4;
+    if header_len < HEADER_LEN || header_len > data.len() {
+        return None;
+    }
+    let word = |at: usize| u16::from_be_bytes([data[at], data[at + 1]]);
+    let long = |at: usize| u32::from_be_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]]);
+    let mut header = Header {
+        source_port: word(0),
+        destination_port: word(2),
+        seq: long(4),
+        ack: long(8),
+        flags: data[13],
+        window: word(14),
+        mss: None,
+    };
+    let mut options = &data[HEADER_LEN..header_len];
+    while let Some(&kind) = options.first() {
+        match kind {
+            OPTION_END => break,
+            OPTION_NOP => options = &options[1..],
+            _ => {
+                let len = usize::from(*options.get(1)?);
+                if len < 2 || len > options.len() {
+                    break;
+                }
+                if kind == OPTION_MSS && len == 4 {
+                    header.mss = Some(u16::from_be_bytes([options[2], options[3]]));
+                }
+                options = &options[len..];
+            }
+        }
+    }
+    packet.pull(header_len);
+    Some(header)
+}
+
+/// Handles a received segment.
+pub fn input(source: Ipv4Addr, destination: Ipv4Addr, mut packet: PacketBuf) {
+    let header = match parse(&mut packet, source, destination) {
+        Some(header) => header,
+        None => return,
+    };
+    let key = (header.destination_port, source, header.source_port);
+    let (connection, listener) = {
+        let sockets = SOCKETS.lock();
+        (sockets.connections.get(&key).cloned(), sockets.listeners.get(&key.0).cloned())
+    };
+    if let Some(connection) = connection {
+        let events = connection.tcb.lock().input(key, &header, packet);
+        handle(&connection, events);
+        return;
+    }
+    match listener {
+        Some(listener) if header.flags & (SYN | ACK | RST) == SYN => listener.connect(key, &header),
+        _ if header.flags & RST == 0 => {
+            // nobody listens: refuse
+            let len = packet.len() as u32 + (header.flags & (SYN | FIN) != 0) as u32;
+            if header.flags & ACK != 0 {
+                reset(key, header.ack, 0, RST);
+            } else {
+                reset(key, 0, header.seq.wrapping_add(len), RST | ACK);
+            }
+        }
+        _ => {}
+    }
+}
+
+impl Listener {
+    /// Answers a SYN with a new connection in `SynReceived`.
+    fn connect(&self, key: Key, header: &Header) {
+        let mut sockets = SOCKETS.lock();
+        let half_open = sockets.connections.values()
+            .filter(|connection| connection.listener == Some(self.port))
+            .filter(|connection| connection.tcb.lock().state == State::SynReceived)
+            .count();
+        if half_open + self.backlog.lock().queue.len() >= BACKLOG {
+            return;
+        }
+        let mut tcb = Tcb::new(State::SynReceived, self.buffers);
+        tcb.rcv_nxt = header.seq.wrapping_add(1);
+        tcb.snd_wnd = u32::from(header.window);
+        tcb.mss = header.mss.map_or(DEFAULT_MSS, usize::from).min(MSS);
+        tcb.transmit_empty(key, tcb.iss, SYN);
+        let connection = Arc::new(Connection { key, listener: Some(self.port), tcb: Mutex::new(tcb) });
+        sockets.connections.insert(key, connection);
+    }
+}
+
+/// Runs the timers of every connection. Called by the `net` thread.
+pub(super) fn tick() {
+    let now = Instant::now();
+    let connections: Vec<Arc<Connection>> = SOCKETS.lock().connections.values().cloned().collect();
+    for connection in connections {
+        let closed = connection.tcb.lock().tick(connection.key, now);
+        if closed {
+            remove(&connection);
+        }
+    }
+}
+
+/// A port that accepts connections.
+pub struct TcpListener {
+    listener: Arc<Listener>,
+}
+
+impl TcpListener {
+    /// Listens on `port`, with connections that get the default buffers.
+    pub fn bind(port: u16) -> Result<TcpListener, NetError> {
+        TcpListener::bind_with(port, Buffers::default())
+    }
+
+    pub fn bind_with(port: u16, buffers: Buffers) -> Result<TcpListener, NetError> {
+        let mut sockets = SOCKETS.lock();
+        if sockets.listeners.contains_key(&port) {
+            return Err(NetError::AddressInUse);
+        }
+        let backlog = Mutex::new(Backlog { queue: VecDeque::new(), waker: None });
+        let listener = Arc::new(Listener { port, buffers, backlog });
+        sockets.listeners.insert(port, listener.clone());
+        Ok(TcpListener { listener })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.listener.port
+    }
+
+    /// Waits for the next established connection.
+    pub fn accept(&self) -> impl Future<Output = TcpStream> + '_ {
+        poll_fn(move |cx| {
+            let mut backlog = self.listener.backlog.lock();
+            match backlog.queue.pop_front() {
+                Some(connection) => Poll::Ready(TcpStream { connection }),
+                None => {
+                    backlog.waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        })
+    }
+}
+
+impl Drop for TcpListener {
+    fn drop(&mut self) {
+        SOCKETS.lock().listeners.remove(&self.listener.port);
+        // dropping the streams nobody accepted closes them
+        let queue = core::mem::take(&mut self.listener.backlog.lock().queue);
+        drop(queue.into_iter().map(|connection| TcpStream { connection }).collect::<Vec<_>>());
+    }
+}
+
+/// A connection.
+pub struct TcpStream {
+    connection: Arc<Connection>,
+}
+
+impl TcpStream {
+    /// Connects to `port` on `remote`, with the default buffers.
+    pub async fn connect(remote: Ipv4Addr, port: u16) -> Result<TcpStream, NetError> {
+        TcpStream::connect_with(remote, port, Buffers::default()).await
+    }
+
+    pub async fn connect_with(remote: Ipv4Addr, port: u16, buffers: Buffers) -> Result<TcpStream, NetError> {
+        ipv4::route().ok_or(NetError::Unreachable)?;
+        let connection = {
+            let mut sockets = SOCKETS.lock();
+            let ports = usize::from(EPHEMERAL_PORTS.end() - EPHEMERAL_PORTS.start()) + 1;
+            let mut key = None;
+            for _ in 0..ports {
+                let local = sockets.next_port;
+                sockets.next_port = if local == *EPHEMERAL_PORTS.end() { *EPHEMERAL_PORTS.start() } else { local + 1 };
+                if !sockets.connections.contains_key(&(local, remote, port)) && !sockets.listeners.contains_key(&local) {
+                    key = Some((local, remote, port));
+                    break;
+                }
+            }
+            let key = key.ok_or(NetError::AddressInUse)?;
+            let connection = Arc::new(Connection { key, listener: None, tcb: Mutex::new(Tcb::new(State::SynSent, buffers)) });
+            sockets.connections.insert(key, connection.clone());
+            connection
+        };
+        let stream = TcpStream { connection };
+        {
+            let mut tcb = stream.connection.tcb.lock();
+            let iss = tcb.iss;
+            tcb.transmit_empty(stream.connection.key, iss, SYN);
+        }
+        poll_fn(|cx| {
+            let mut tcb = stream.connection.tcb.lock();
+            match tcb.state {
+                State::SynSent | State::SynReceived => {
+                    tcb.writer = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+                State::Closed => Poll::Ready(Err(tcb.error.unwrap_or(NetError::ConnectionRefused))),
+                _ => Poll::Ready(Ok(())),
+            }
+        }).await?;
+        Ok(stream)
+    }
+
+    /// The remote address and port.
+    pub fn peer(&self) -> (Ipv4Addr, u16) {
+        (self.connection.key.1, self.connection.key.2)
+    }
+
+    pub fn local_port(&self) -> u16 {
+        self.connection.key.0
+    }
+
+    pub fn state(&self) -> State {
+        self.connection.tcb.lock().state
+    }
+
+    /// Waits for data and reads as much of it as fits into `buf`. Returns 0
+    /// once the peer closed its side and everything was read.
+    pub fn read<'a>(&'a self, buf: &'a mut [u8]) -> impl Future<Output = Result<usize, NetError>> + 'a {
+        poll_fn(move |cx| {
+            let mut tcb = self.connection.tcb.lock();
+            if !tcb.received.is_empty() || buf.is_empty() {
+                let len = buf.len().min(tcb.received.len());
+                buf.iter_mut().zip(tcb.received.drain(..len)).for_each(|(to, from)| *to = from);
+                tcb.update_window(self.connection.key);
+                return Poll::Ready(Ok(len));
+            }
+            if let Some(error) = tcb.error {
+                return Poll::Ready(Err(error));
+            }
+            if tcb.fin_received || tcb.state == State::Closed {
+                return Poll::Ready(Ok(0));
+            }
+            tcb.reader = Some(cx.waker().clone());
+            Poll::Pending
+        })
+    }
+
+    /// Waits for room in the send buffer and writes as much of `buf` as fits.
+    pub fn write<'a>(&'a self, buf: &'a [u8]) -> impl Future<Output = Result<usize, NetError>> + 'a {
+        poll_fn(move |cx| {
+            let mut tcb = self.connection.tcb.lock();
+            if let Some(error) = tcb.error {
+                return Poll::Ready(Err(error));
+            }
+            if tcb.closing || !matches!(tcb.state, State::Established | State::CloseWait) {
+                return Poll::Ready(Err(NetError::NotConnected));
+            }
+            let room = tcb.buffers.send - tcb.send.len();
+            if room == 0 && !buf.is_empty() {
+                tcb.writer = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+            let len = room.min(buf.len());
+            tcb.send.extend(buf[..len].iter().copied());
+            tcb.output(self.connection.key);
+            Poll::Ready(Ok(len))
+        })
+    }
+
+    /// Writes all of `buf`, waiting for room as often as needed.
+    pub async fn write_all(&self, mut buf: &[u8]) -> Result<(), NetError> {
+        while !buf.is_empty() {
+            let len = self.write(buf).await?;
+            buf = &buf[len..];
+        }
+        Ok(())
+    }
+
+    /// Closes our side: the peer gets a FIN after the data written. Reading
+    /// goes on until the peer closes its side.
+    pub fn close(&self) {
+        let closed = self.connection.tcb.lock().close(self.connection.key);
+        if closed {
+            remove(&self.connection);
+        }
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// A socket, as `sockets` lists it.
+#[derive(Debug, Clone, Copy)]
+pub struct SocketInfo {
+    pub local_port: u16,
+    /// `None` for listeners.
+    pub remote: Option<(Ipv4Addr, u16)>,
+    /// `None` for listeners.
+    pub state: Option<State>,
+    /// Bytes waiting in the send and receive buffers.
+    pub queued: (usize, usize),
+}
+
+/// The listeners and connections.
+pub fn sockets() -> Vec<SocketInfo> {
+    let (listeners, connections): (Vec<u16>, Vec<Arc<Connection>>) = {
+        let sockets = SOCKETS.lock();
+        (sockets.listeners.keys().copied().collect(), sockets.connections.values().cloned().collect())
+    };
+    let listening = listeners.into_iter().map(|port| SocketInfo { local_port: port, remote: None, state: None, queued: (0, 0) });
+    let connected = connections.into_iter().map(|connection| {
+        let tcb = connection.tcb.lock();
+        SocketInfo {
+            local_port: connection.key.0,
+            remote: Some((connection.key.1, connection.key.2)),
+            state: Some(tcb.state),
+            queued: (tcb.send.len(), tcb.received.len()),
+        }
+    });
+    listening.chain(connected).collect()
+}
+
+#[test_case]
+fn test_parse_options() {
+    let source = Ipv4Addr([10, 0, 2, 2]);
+    let destination = Ipv4Addr([10, 0, 2, 15]);
+    let mut segment = [0u8; 30];
+    segment[0..2].copy_from_slice(&4000u16.to_be_bytes());
+    segment[2..4].copy_from_slice(&23u16.to_be_bytes());
+    segment[4..8].copy_from_slice(&7u32.to_be_bytes());
+    segment[12] = 7 << 4;
+    segment[13] = SYN;
+    segment[14..16].copy_from_slice(&1000u16.to_be_bytes());
+    segment[20..28].copy_from_slice(&[OPTION_NOP, OPTION_MSS, 4, 0x05, 0xb4, OPTION_END, 0, 0]);
+    segment[28..30].copy_from_slice(b"hi");
+    let sum = ipv4::pseudo_header_sum(source, destination, PROTOCOL_TCP, segment.len());
+    let checksum = ipv4::checksum(ipv4::sum(sum, &segment));
+    segment[16..18].copy_from_slice(&checksum.to_be_bytes());
+
+    let mut packet = PacketBuf::from_slice(&segment).unwrap();
+    let header = parse(&mut packet, source, destination).unwrap();
+    assert_eq!((header.source_port, header.destination_port, header.seq), (4000, 23, 7));
+    assert_eq!((header.flags, header.window, header.mss), (SYN, 1000, Some(1460)));
+    assert_eq!(packet.data(), b"hi");
+
+    let mut corrupted = PacketBuf::from_slice(&segment).unwrap();
+    corrupted.data_mut().unwrap()[4] ^= 1;
+    assert!(parse(&mut corrupted, source, destination).is_none());
+}
+
+#[test_case]
+fn test_connection() {
+    // no interface is configured, so nothing is sent
+    let key = (23, Ipv4Addr([10, 0, 2, 2]), 4000);
+    let segment = |seq: u32, ack: u32, flags: u8, window: u16| Header { seq, ack, flags, window, ..Header::default() };
+    let mut tcb = Tcb::new(State::SynReceived, Buffers { send: 8, receive: 4 });
+    tcb.rcv_nxt = 101;
+    let iss = tcb.iss;
+    let empty = || PacketBuf::new(HEADROOM).unwrap();
+
+    // the handshake completes
+    let events = tcb.input(key, &segment(101, iss.wrapping_add(1), ACK, 2), empty());
+    assert!(events.established && tcb.state == State::Established);
+
+    // data beyond the receive buffer is dropped, and the window closes
+    let events = tcb.input(key, &segment(101, iss.wrapping_add(1), ACK | PSH, 2), PacketBuf::from_slice(b"hello").unwrap());
+    assert!(!events.closed);
+    assert_eq!((tcb.received.len(), tcb.rcv_nxt, tcb.window()), (4, 105, 0));
+    tcb.received.clear();
+    // the rest again, partly received already, then the FIN
+    tcb.input(key, &segment(104, iss.wrapping_add(1), ACK | FIN, 2), PacketBuf::from_slice(b"lo").unwrap());
+    assert_eq!(tcb.received.iter().copied().collect::<Vec<u8>>(), b"o");
+    assert_eq!((tcb.rcv_nxt, tcb.state, tcb.fin_received), (107, State::CloseWait, true));
+
+    // only as much is in flight as the peer's window allows
+    tcb.send.extend(b"abcdef".iter().copied());
+    tcb.output(key);
+    assert_eq!(tcb.snd_nxt, iss.wrapping_add(3));
+    tcb.input(key, &segment(107, iss.wrapping_add(3), ACK, 8), empty());
+    assert_eq!((tcb.send.len(), tcb.snd_nxt), (4, iss.wrapping_add(7)));
+
+    // closing sends the FIN after the data, and its acknowledgment closes
+    assert!(!tcb.close(key));
+    assert_eq!((tcb.state, tcb.snd_nxt), (State::LastAck, iss.wrapping_add(8)));
+    let events = tcb.input(key, &segment(107, iss.wrapping_add(8), ACK, 8), empty());
+    assert!(events.closed && tcb.state == State::Closed && tcb.send.is_empty());
+}
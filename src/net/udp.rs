@@ -0,0 +1,179 @@
+//! UDP: datagrams, through `UdpSocket`.
+//!
+//! A socket keeps the datagrams received on its port until they are read, up
+//! to `QUEUE_LEN` of them; more are dropped, as are datagrams to ports no
+//! socket is bound to.
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::Arc;
+use core::future::{poll_fn, Future};
+use core::ops::RangeInclusive;
+use core::task::{Poll, Waker};
+use spin::Mutex;
+use crate::net::ipv4::{self, Ipv4Addr, PROTOCOL_UDP};
+use crate::net::packet::{BUFFER_SIZE, HEADROOM};
+use crate::net::{NetError, PacketBuf};
+
+pub const HEADER_LEN: usize = 8;
+/// Largest payload of a datagram, which isn't fragmented: what fits in a
+/// packet buffer after the headers.
+pub const MAX_PAYLOAD: usize = BUFFER_SIZE - HEADROOM;
+
+const QUEUE_LEN: usize = 16;
+const EPHEMERAL_PORTS: RangeInclusive<u16> = 49152..=65535;
+
+struct Received {
+    datagrams: VecDeque<(Ipv4Addr, u16, PacketBuf)>,
+    waker: Option<Waker>,
+}
+
+struct Socket {
+    received: Mutex<Received>,
+}
+
+static SOCKETS: Mutex<BTreeMap<u16, Arc<Socket>>> = Mutex::new(BTreeMap::new());
+
+/// Checks a received datagram and queues it on its port's socket.
+pub fn input(source: Ipv4Addr, destination: Ipv4Addr, mut packet: PacketBuf) {
+    let data = packet.data();
+    if data.len() < HEADER_LEN {
+        return;
+    }
+    let (source_port, port) = (u16::from_be_bytes([data[0], data[1]]), u16::from_be_bytes([data[2], data[3]]));
+    let len = usize::from(u16::from_be_bytes([data[4], data[5]]));
+    if len < HEADER_LEN || len > data.len() {
+        return;
+    }
+    // a checksum of zero means the sender computed none
+    if data[6..8] != [0, 0] {
+        let sum = ipv4::pseudo_header_sum(source, destination, PROTOCOL_UDP, len);
+        if ipv4::checksum(ipv4::sum(sum, &data[..len])) != 0 {
+            return;
+        }
+    }
+    let socket = match SOCKETS.lock().get(&port) {
+        Some(socket) => socket.clone(),
+        None => return,
+    };
+    packet.truncate(len);
+    packet.pull(HEADER_LEN);
+    let mut received = socket.received.lock();
+    if received.datagrams.len() < QUEUE_LEN {
+        received.datagrams.push_back((source, source_port, packet));
+        if let Some(waker) = received.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// Sends `data` in a datagram from `source_port` to `port` on `destination`.
+pub fn send(source_port: u16, destination: Ipv4Addr, port: u16, data: &[u8]) -> Result<(), NetError> {
+    if data.len() > MAX_PAYLOAD {
+        return Err(NetError::MessageTooLong);
+    }
+    let local = ipv4::local_address().ok_or(NetError::Unreachable)?;
+    let mut packet = PacketBuf::new(HEADROOM).map_err(|_| NetError::OutOfMemory)?;
+    packet.put(data.len()).map_err(|_| NetError::OutOfMemory)?.copy_from_slice(data);
+    let len = HEADER_LEN + data.len();
+    let header = packet.push(HEADER_LEN).map_err(|_| NetError::OutOfMemory)?;
+    header[0..2].copy_from_slice(&source_port.to_be_bytes());
+    header[2..4].copy_from_slice(&port.to_be_bytes());
+    header[4..6].copy_from_slice(&(len as u16).to_be_bytes());
+    header[6..8].copy_from_slice(&[0, 0]);
+    let sum = ipv4::pseudo_header_sum(local, destination, PROTOCOL_UDP, len);
+    // zero is sent as all ones, zero means no checksum
+    let checksum = match ipv4::checksum(ipv4::sum(sum, packet.data())) {
+        0 => 0xffff,
+        checksum => checksum,
+    };
+    packet.data_mut().map_err(|_| NetError::OutOfMemory)?[6..8].copy_from_slice(&checksum.to_be_bytes());
+    ipv4::send(destination, PROTOCOL_UDP, packet)
+}
+
+/// A port that sends and receives datagrams.
+pub struct UdpSocket {
+    port: u16,
+    socket: Arc<Socket>,
+}
+
+impl UdpSocket {
+    /// Binds `port`, or a free port from the ephemeral range if it is 0.
+    pub fn bind(port: u16) -> Result<UdpSocket, NetError> {
+        let mut sockets = SOCKETS.lock();
+        let port = match port {
+            0 => EPHEMERAL_PORTS.clone().find(|port| !sockets.contains_key(port)).ok_or(NetError::AddressInUse)?,
+            port if sockets.contains_key(&port) => return Err(NetError::AddressInUse),
+            port => port,
+        };
+        let socket = Arc::new(Socket { received: Mutex::new(Received { datagrams: VecDeque::new(), waker: None }) });
+        sockets.insert(port, socket.clone());
+        Ok(UdpSocket { port, socket })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn send_to(&self, data: &[u8], destination: Ipv4Addr, port: u16) -> Result<(), NetError> {
+        send(self.port, destination, port, data)
+    }
+
+    /// Copies the next datagram received like `recv_from`, if there is one.
+    pub fn try_recv_from(&self, buf: &mut [u8]) -> Option<(usize, Ipv4Addr, u16)> {
+        let (source, port, packet) = self.socket.received.lock().datagrams.pop_front()?;
+        let len = packet.len().min(buf.len());
+        buf[..len].copy_from_slice(&packet.data()[..len]);
+        Some((len, source, port))
+    }
+
+    /// Waits for a datagram and copies as much of it as fits into `buf`; the
+    /// rest is dropped. Returns its length and sender.
+    pub fn recv_from<'a>(&'a self, buf: &'a mut [u8]) -> impl Future<Output = (usize, Ipv4Addr, u16)> + 'a {
+        poll_fn(move |cx| {
+            let mut received = self.socket.received.lock();
+            match received.datagrams.pop_front() {
+                Some((source, port, packet)) => {
+                    let len = packet.len().min(buf.len());
+                    buf[..len].copy_from_slice(&packet.data()[..len]);
+                    Poll::Ready((len, source, port))
+                }
+                None => {
+                    received.waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        })
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        SOCKETS.lock().remove(&self.port);
+    }
+}
+
+#[test_case]
+fn test_input() {
+    let socket = UdpSocket::bind(0).unwrap();
+    assert!(EPHEMERAL_PORTS.contains(&socket.port()));
+    assert_eq!(UdpSocket::bind(socket.port()).err(), Some(NetError::AddressInUse));
+
+    let (source, destination) = (Ipv4Addr([10, 0, 2, 2]), Ipv4Addr([10, 0, 2, 15]));
+    let mut datagram = [0u8; 12];
+    datagram[0..2].copy_from_slice(&53u16.to_be_bytes());
+    datagram[2..4].copy_from_slice(&socket.port().to_be_bytes());
+    datagram[4..6].copy_from_slice(&11u16.to_be_bytes());
+    datagram[8..12].copy_from_slice(b"abc\0");
+    let sum = ipv4::pseudo_header_sum(source, destination, PROTOCOL_UDP, 11);
+    let checksum = ipv4::checksum(ipv4::sum(sum, &datagram[..11]));
+    datagram[6..8].copy_from_slice(&checksum.to_be_bytes());
+    // with Ethernet padding after it
+    input(source, destination, PacketBuf::from_slice(&datagram).unwrap());
+    datagram[8] ^= 1;
+    input(source, destination, PacketBuf::from_slice(&datagram).unwrap());
+
+    let mut buf = [0; 8];
+    assert_eq!(crate::task::block_on(socket.recv_from(&mut buf)), (3, source, 53));
+    assert_eq!(&buf[..3], b"abc");
+    assert!(socket.socket.received.lock().datagrams.is_empty());
+}
@@ -0,0 +1,159 @@
+//! Anonymous pipes: a byte stream from a writing end to a reading end.
+//!
+//! Both ends are inodes outside the VFS tree, so processes get them as
+//! descriptors like any other file. Reads block until there is data, writes
+//! until everything fit into the buffer. Once every writing end is gone,
+//! reads return end of file; once every reading end is gone, writes fail
+//! with `FsError::BrokenPipe`.
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use spin::Mutex;
+use crate::arch::interrupts;
+use crate::fs::{FsError, Inode, InodeKind};
+use crate::sched::WaitQueue;
+
+/// Bytes a pipe holds before writers block.
+pub const CAPACITY: usize = 4096;
+
+struct Buffer {
+    data: VecDeque<u8>,
+    reader_open: bool,
+    writer_open: bool,
+}
+
+/// The state both ends share, locked with interrupts disabled so that it can
+/// be checked from `WaitQueue` conditions.
+struct Pipe {
+    buffer: Mutex<Buffer>,
+    readers: WaitQueue,
+    writers: WaitQueue,
+}
+
+impl Pipe {
+    fn with<R>(&self, f: impl FnOnce(&mut Buffer) -> R) -> R {
+        interrupts::without_interrupts(|| f(&mut self.buffer.lock()))
+    }
+}
+
+/// The reading end of a pipe.
+pub struct Reader(Arc<Pipe>);
+
+/// The writing end of a pipe.
+pub struct Writer(Arc<Pipe>);
+
+/// Creates a pipe and returns its two ends.
+pub fn pipe() -> (Arc<Reader>, Arc<Writer>) {
+    let pipe = Arc::new(Pipe {
+        buffer: Mutex::new(Buffer {
+            data: VecDeque::with_capacity(CAPACITY),
+            reader_open: true,
+            writer_open: true,
+        }),
+        readers: WaitQueue::new(),
+        writers: WaitQueue::new(),
+    });
+    (Arc::new(Reader(pipe.clone())), Arc::new(Writer(pipe)))
+}
+
+impl Inode for Reader {
+    fn kind(&self) -> InodeKind {
+        InodeKind::CharDevice
+    }
+
+    /// Waits for data and reads what is there, up to `buf.len()` bytes. Returns
+    /// 0 at end of file.
+    fn read_at(&self, _offset: usize, buf: &mut [u8]) -> Result<usize, FsError> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let mut read = 0;
+        self.0.readers.wait_until(|| {
+            let mut buffer = self.0.buffer.lock();
+            if buffer.data.is_empty() {
+                return !buffer.writer_open;
+            }
+            read = buf.len().min(buffer.data.len());
+            for (slot, byte) in buf.iter_mut().zip(buffer.data.drain(..read)) {
+                *slot = byte;
+            }
+            true
+        });
+        self.0.writers.notify_all();
+        Ok(read)
+    }
+}
+
+impl Drop for Reader {
+    fn drop(&mut self) {
+        self.0.with(|buffer| buffer.reader_open = false);
+        self.0.writers.notify_all();
+    }
+}
+
+impl Inode for Writer {
+    fn kind(&self) -> InodeKind {
+        InodeKind::CharDevice
+    }
+
+    /// Writes all of `buf`, waiting for readers to make room as needed.
+    fn write_at(&self, _offset: usize, buf: &[u8]) -> Result<usize, FsError> {
+        let mut written = 0;
+        while written < buf.len() {
+            let mut broken = false;
+            self.0.writers.wait_until(|| {
+                let mut buffer = self.0.buffer.lock();
+                if !buffer.reader_open {
+                    broken = true;
+                    return true;
+                }
+                let room = CAPACITY - buffer.data.len();
+                let len = room.min(buf.len() - written);
+                buffer.data.extend(&buf[written..written + len]);
+                written += len;
+                len > 0
+            });
+            if broken {
+                // what was written before is lost anyway
+                return Err(FsError::BrokenPipe);
+            }
+            self.0.readers.notify_all();
+        }
+        Ok(written)
+    }
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        self.0.with(|buffer| buffer.writer_open = false);
+        self.0.readers.notify_all();
+    }
+}
+
+#[test_case]
+fn test_pipe() {
+    use crate::sched;
+
+    let (reader, writer) = pipe();
+    let data: alloc::vec::Vec<u8> = (0..3 * CAPACITY).map(|i| i as u8).collect();
+    let expected = data.clone();
+    // more than fits, so the writer has to wait for the reader
+    let producer = sched::spawn("test-pipe", move || {
+        assert_eq!(writer.write_at(0, &data), Ok(data.len()));
+    }).unwrap();
+
+    let mut received = alloc::vec::Vec::new();
+    let mut buf = [0; 1000];
+    loop {
+        match reader.read_at(0, &mut buf).unwrap() {
+            0 => break,
+            n => received.extend_from_slice(&buf[..n]),
+        }
+    }
+    sched::join(producer);
+    assert_eq!(received, expected);
+
+    let (reader, writer) = pipe();
+    drop(reader);
+    assert_eq!(writer.write_at(0, b"lost"), Err(FsError::BrokenPipe));
+}
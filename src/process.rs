@@ -0,0 +1,936 @@
+//! User processes: a thread running ring 3 code in an address space of its own.
+//!
+//! Programs are statically linked ELF executables read from the VFS, linked
+//! to load inside `aslr::USER_IMAGE_WINDOW`. A process enters the kernel only
+//! through system calls (see `syscall`) and interrupts; `fork` duplicates it
+//! with copy-on-write pages and `exec` replaces its program. Files are mapped
+//! read-only with pages shared through the page cache, `shm` objects with
+//! their frames shared across forks too. The heap follows the
+//! program's segments and grows with `brk`. The time page of `time::vdso` is
+//! mapped read-only into every process. A process that exited
+//! stays in the process table with its exit status until its parent waits for
+//! it. Its children still running are handed to init, PID 1, or reaped as
+//! soon as they exit when init is not running.
+//! What a process may use of memory, descriptors and CPU time is limited, see
+//! `rlimit`. Its system calls can be traced, see `trace`.
+
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+use crate::arch::usercopy;
+use crate::fs;
+use crate::sched::{self, ThreadId, WaitQueue};
+use crate::signal::{self, Signals};
+use crate::{aslr, futex, initcall, memory, shm, time};
+use self::address_space::AddressSpace;
+use self::fd::FdTable;
+use self::rlimit::{Limits, Usage};
+use self::syscall::{errno, Registers, ECHILD, EFAULT, EINVAL, ENOEXEC, ENOMEM, ESRCH};
+
+pub mod address_space;
+pub mod elf;
+pub mod fd;
+pub mod init;
+pub mod programs;
+pub mod rlimit;
+pub mod syscall;
+pub mod trace;
+
+/// Size of the stack a program starts with.
+const STACK_SIZE: u64 = 16 * 4096;
+/// Longest path `exec` accepts.
+const PATH_MAX: u64 = 256;
+/// Most bytes of arguments `exec` passes to a program.
+const ARG_MAX: u64 = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Pid(pub u64);
+
+/// The process orphans are handed to.
+pub const INIT: Pid = Pid(1);
+
+impl Pid {
+    fn new() -> Pid {
+        static NEXT: AtomicU64 = AtomicU64::new(1);
+        Pid(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+pub struct Process {
+    pub pid: Pid,
+    /// `None` for processes spawned by kernel threads and orphans nobody
+    /// adopted.
+    parent: Mutex<Option<Pid>>,
+    /// Reaped when it exits, as no process will wait for it.
+    orphaned: AtomicBool,
+    name: Mutex<String>,
+    thread: Mutex<Option<ThreadId>>,
+    /// `None` once the process exited.
+    space: Mutex<Option<AddressSpace>>,
+    files: Mutex<FdTable>,
+    limits: Mutex<Limits>,
+    heap: Mutex<Heap>,
+    trace: Mutex<Option<trace::Target>>,
+    status: Mutex<Option<i64>>,
+}
+
+/// A program ready to run: its address space, the registers it continues
+/// with, its heap and its signal state.
+struct Image {
+    space: AddressSpace,
+    registers: Registers,
+    heap: Heap,
+    signals: Signals,
+}
+
+/// The heap of a process: the pages from `start` up to `end`, rounded up.
+#[derive(Debug, Clone, Copy)]
+struct Heap {
+    start: u64,
+    end: u64,
+}
+
+impl Process {
+    /// The name of the program the process runs.
+    pub fn name(&self) -> String {
+        self.name.lock().clone()
+    }
+
+    pub fn thread(&self) -> Option<ThreadId> {
+        *self.thread.lock()
+    }
+
+    pub fn parent(&self) -> Option<Pid> {
+        *self.parent.lock()
+    }
+
+    /// Its exit status once it exited.
+    pub fn status(&self) -> Option<i64> {
+        *self.status.lock()
+    }
+
+    pub fn limits(&self) -> Limits {
+        *self.limits.lock()
+    }
+
+    /// Whether its system calls are traced.
+    pub fn is_traced(&self) -> bool {
+        self.trace.lock().is_some()
+    }
+
+    /// What the process uses now; nothing but CPU time once it exited.
+    pub fn usage(&self) -> Usage {
+        let (frames, pages) = self.space.lock().as_ref()
+            .map_or((0, 0), |space| (space.private_frames() as u64, space.mapped_pages() as u64));
+        let cpu_time = self.thread().and_then(sched::cpu_time).unwrap_or_default();
+        Usage {
+            frames,
+            pages,
+            files: self.files.lock().count() as u64,
+            cpu_time: cpu_time.as_millis() as u64,
+        }
+    }
+}
+
+static PROCESSES: Mutex<BTreeMap<Pid, Arc<Process>>> = Mutex::new(BTreeMap::new());
+/// Counts the processes that exited, for those waiting for one.
+static EXITS: AtomicU64 = AtomicU64::new(0);
+static EXITED: WaitQueue = WaitQueue::new();
+
+/// Prepares the kernel for processes. Must be called after `sched::init`.
+pub fn init() -> Result<(), &'static str> {
+    // kernel memory a process may touch in system calls must be in slots that
+    // exist when its address space is created
+    for window in [aslr::HEAP_WINDOW, aslr::KERNEL_STACK_WINDOW, aslr::SHARED_MEMORY_WINDOW].iter() {
+        memory::reserve_kernel_slot(VirtAddr::new(window.start))?;
+    }
+    Ok(())
+}
+initcall!(Core, "process", init, needs: &["sched"], required: true);
+
+/// The process the calling thread belongs to, if any.
+pub fn current() -> Option<Arc<Process>> {
+    let pid = sched::current_process()?;
+    PROCESSES.lock().get(&pid).cloned()
+}
+
+/// Every process that was not waited for yet.
+pub fn list() -> Vec<Arc<Process>> {
+    PROCESSES.lock().values().cloned().collect()
+}
+
+/// The running process with the most user pages mapped, and their number.
+/// Skips processes whose tables are being changed, so it can be called while
+/// allocating frames for them.
+pub fn largest() -> Option<(Arc<Process>, usize)> {
+    let processes: Vec<Arc<Process>> = PROCESSES.try_lock()?.values().cloned().collect();
+    processes.into_iter()
+        .filter_map(|process| {
+            let pages = process.space.try_lock()?.as_ref()?.mapped_pages();
+            Some((process, pages))
+        })
+        .max_by_key(|&(_, pages)| pages)
+}
+
+/// Runs `f` on the descriptor table of the calling process.
+pub fn files<R>(f: impl FnOnce(&mut FdTable) -> Result<R, i64>) -> Result<R, i64> {
+    f(&mut current().ok_or(EINVAL)?.files.lock())
+}
+
+/// Reads the program at `path` into a new address space and returns it with
+/// the registers to start it with and its empty heap. `args`, each argument
+/// followed by a NUL, go on top of its stack, their address in `rdi` and their
+/// length in `rsi`. Fails with `ENOMEM` if the space takes more memory than
+/// `limits` allow.
+fn load(path: &str, args: &[u8], limits: &Limits) -> Result<Image, i64> {
+    let data = fs::read_file(path).map_err(errno)?;
+    let executable = elf::parse(&data).map_err(|_| ENOEXEC)?;
+    let mut space = AddressSpace::new().map_err(|_| ENOMEM)?;
+
+    // the image gets what the stack and the time page leave, counted as its
+    // pages are collected: a huge segment must fail before it takes the heap
+    // and every frame
+    let stack_pages = STACK_SIZE / 0x1000;
+    let shared_pages = if time::vdso::frame().is_some() { 1 } else { 0 };
+    let budget = limits.frames.saturating_sub(stack_pages)
+        .min(limits.pages.saturating_sub(stack_pages + shared_pages));
+    // segments may share pages, so collect the flags of every page first
+    let mut pages: BTreeMap<u64, PageTableFlags> = BTreeMap::new();
+    for segment in &executable.segments {
+        let end = segment.vaddr.checked_add(segment.mem_size).ok_or(ENOEXEC)?;
+        if segment.vaddr < aslr::USER_IMAGE_WINDOW.start || end > aslr::USER_IMAGE_WINDOW.end {
+            return Err(ENOEXEC);
+        }
+        for page in (segment.vaddr & !0xfff..end).step_by(0x1000) {
+            if pages.len() as u64 >= budget && !pages.contains_key(&page) {
+                return Err(ENOMEM);
+            }
+            let flags = pages.entry(page).or_insert(PageTableFlags::NO_EXECUTE);
+            if segment.writable {
+                *flags |= PageTableFlags::WRITABLE;
+            }
+            if segment.executable {
+                flags.remove(PageTableFlags::NO_EXECUTE);
+            }
+        }
+    }
+    for (&page, &flags) in &pages {
+        let page = VirtAddr::new(page);
+        space.map(page..page + 0x1000u64, flags).map_err(|_| ENOMEM)?;
+    }
+    for segment in &executable.segments {
+        let bytes = &data[segment.offset..segment.offset + segment.file_size];
+        space.write(VirtAddr::new(segment.vaddr), bytes).map_err(|_| ENOMEM)?;
+    }
+
+    let stack = aslr::random_base(aslr::USER_STACK_WINDOW, STACK_SIZE, 0x1000);
+    let stack = VirtAddr::new(stack)..VirtAddr::new(stack + STACK_SIZE);
+    space.map(stack.clone(), PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE).map_err(|_| ENOMEM)?;
+    if let Some(frame) = time::vdso::frame() {
+        let page = VirtAddr::new(time::vdso::ADDRESS);
+        space.map_shared(page, &[frame], PageTableFlags::NO_EXECUTE).map_err(|_| ENOMEM)?;
+    }
+    if space.private_frames() as u64 > limits.frames || space.mapped_pages() as u64 > limits.pages {
+        return Err(ENOMEM);
+    }
+    let heap = pages.keys().next_back().map_or(aslr::USER_IMAGE_WINDOW.start, |&page| page + 0x1000);
+    let args_start = VirtAddr::new((stack.end.as_u64() - args.len() as u64) & !0xf);
+    space.write(args_start, args).map_err(|_| ENOMEM)?;
+    let mut registers = Registers::user(VirtAddr::new(executable.entry), args_start);
+    registers.rdi = args_start.as_u64();
+    registers.rsi = args.len() as u64;
+    Ok(Image { space, registers, heap: Heap { start: heap, end: heap }, signals: Signals::new() })
+}
+
+/// Starts the program at `path` in a new process, without arguments, with the
+/// console as its standard input and output and the default limits.
+pub fn spawn(path: &str) -> Result<Pid, i64> {
+    spawn_traced(path, None)
+}
+
+/// Like `spawn`, but traces the system calls of the process from its first
+/// one to `trace`.
+pub fn spawn_traced(path: &str, trace: Option<trace::Target>) -> Result<Pid, i64> {
+    let limits = rlimit::defaults();
+    let image = load(path, &[], &limits)?;
+    start(path.to_string(), None, image, FdTable::standard(), limits, trace)
+}
+
+/// Registers a process for `image` and starts its thread.
+fn start(name: String, parent: Option<Pid>, image: Image, mut files: FdTable, limits: Limits,
+         trace: Option<trace::Target>) -> Result<Pid, i64> {
+    files.set_limit(limits.files);
+    let pid = Pid::new();
+    let Image { space, registers, heap, signals } = image;
+    let root = space.root();
+    let process = Arc::new(Process {
+        pid,
+        parent: Mutex::new(parent),
+        orphaned: AtomicBool::new(false),
+        name: Mutex::new(name),
+        thread: Mutex::new(None),
+        space: Mutex::new(Some(space)),
+        files: Mutex::new(files),
+        limits: Mutex::new(limits),
+        heap: Mutex::new(heap),
+        trace: Mutex::new(trace),
+        status: Mutex::new(None),
+    });
+    PROCESSES.lock().insert(pid, process.clone());
+    match sched::spawn_process("process", pid, root, signals, move || syscall::enter_user(registers)) {
+        Ok(thread) => {
+            *process.thread.lock() = Some(thread);
+            Ok(pid)
+        }
+        Err(_) => {
+            PROCESSES.lock().remove(&pid);
+            Err(ENOMEM)
+        }
+    }
+}
+
+/// Duplicates the calling process. The child continues with `registers`, but
+/// sees 0 returned; the parent gets the child's id. The child's descriptors
+/// refer to the same open files, offsets included, and it has the same limits,
+/// signal actions and blocked signals and is traced like its parent.
+pub fn fork(registers: &Registers) -> Result<Pid, i64> {
+    let parent = current().ok_or(EINVAL)?;
+    let space = parent.space.lock().as_mut().ok_or(EINVAL)?.fork().map_err(|_| ENOMEM)?;
+    let files = parent.files.lock().clone();
+    let mut child = *registers;
+    child.rax = 0;
+    let image = Image { space, registers: child, heap: *parent.heap.lock(), signals: signal::inherited() };
+    let trace = parent.trace.lock().clone();
+    start(parent.name(), Some(parent.pid), image, files, parent.limits(), trace)
+}
+
+/// Replaces the program of the calling process with the one at `path`, which
+/// keeps its descriptors and the signals it ignores and gets `args`, each
+/// argument followed by a NUL. On success, `registers` start the new program
+/// when the system call returns.
+pub fn exec(path: &str, args: &[u8], registers: &mut Registers) -> Result<(), i64> {
+    if args.len() as u64 > ARG_MAX || args.last().map_or(false, |&last| last != 0) {
+        return Err(EINVAL);
+    }
+    let process = current().ok_or(EINVAL)?;
+    let image = load(path, args, &process.limits())?;
+    sched::set_page_table(image.space.root());
+    // dropped after the switch, it must not be active
+    let old = process.space.lock().replace(image.space);
+    drop(old);
+    *process.heap.lock() = image.heap;
+    *process.name.lock() = path.to_string();
+    signal::reset_user_handlers();
+    *registers = image.registers;
+    Ok(())
+}
+
+/// Ends the calling thread, and with it its process if it belongs to one.
+pub fn exit(code: i64) -> ! {
+    if let Some(process) = current() {
+        crate::log_info!("process {} ({}) exited with {}", process.pid.0, process.name(), code);
+        sched::set_page_table(memory::kernel_page_table());
+        process.space.lock().take();
+        process.files.lock().clear();
+        // under the table's lock, so a parent exiting at the same time either
+        // sees the status or leaves the orphan to be reaped here
+        let mut processes = PROCESSES.lock();
+        *process.status.lock() = Some(code);
+        if process.orphaned.load(Ordering::Relaxed) {
+            processes.remove(&process.pid);
+        }
+        reparent_children(&mut processes, process.pid);
+        drop(processes);
+        EXITS.fetch_add(1, Ordering::Release);
+        EXITED.notify_all();
+    }
+    sched::exit()
+}
+
+/// Sends `signal` to the process `pid`.
+pub fn kill(pid: Pid, signal: signal::Signal) -> Result<(), i64> {
+    let process = PROCESSES.lock().get(&pid).cloned().ok_or(ESRCH)?;
+    if process.status().is_some() {
+        return Err(ESRCH);
+    }
+    let thread = process.thread().ok_or(ESRCH)?;
+    signal::kill(thread, signal).map_err(|_| ESRCH)
+}
+
+/// Hands the children of the exiting process `pid` to init. Without init
+/// running, they are reaped when they exit, right away for those that exited
+/// already.
+fn reparent_children(processes: &mut BTreeMap<Pid, Arc<Process>>, pid: Pid) {
+    let init = processes.get(&INIT)
+        .filter(|init| init.pid != pid && init.status().is_none())
+        .map(|init| init.pid);
+    let orphans: Vec<Arc<Process>> = processes.values().filter(|process| process.parent() == Some(pid)).cloned().collect();
+    for orphan in orphans {
+        *orphan.parent.lock() = init;
+        if init.is_none() {
+            orphan.orphaned.store(true, Ordering::Relaxed);
+            if orphan.status().is_some() {
+                processes.remove(&orphan.pid);
+            }
+        }
+    }
+}
+
+/// Options of `waitpid`: return at once if no child exited yet.
+pub const WNOHANG: u64 = 1;
+
+/// Waits for the process `pid` to exit, removes it from the process table and
+/// returns its exit status. Processes can only wait for their children, kernel
+/// threads for the processes they spawned.
+pub fn wait(pid: Pid) -> Result<i64, i64> {
+    let (_, status) = waitpid(Some(pid), 0)?.ok_or(ECHILD)?;
+    Ok(status)
+}
+
+/// Like `wait`, but for any child if `pid` is `None`, and returns the id of the
+/// child with its status. With `WNOHANG` in `options`, returns `None` if no
+/// child exited yet.
+pub fn waitpid(pid: Option<Pid>, options: u64) -> Result<Option<(Pid, i64)>, i64> {
+    let parent = sched::current_process();
+    loop {
+        let exits = EXITS.load(Ordering::Acquire);
+        if let Some(child) = reap(parent, pid)? {
+            if let Some(thread) = child.thread() {
+                sched::join(thread);
+            }
+            return Ok(Some((child.pid, child.status().ok_or(ECHILD)?)));
+        }
+        if options & WNOHANG != 0 {
+            return Ok(None);
+        }
+        EXITED.wait_until(|| EXITS.load(Ordering::Acquire) != exits);
+    }
+}
+
+/// Removes a child of `parent` that exited from the process table, `pid` or
+/// any if `None`, and returns it. Returns `None` if none exited yet, and fails
+/// with `ECHILD` if there is no such child.
+fn reap(parent: Option<Pid>, pid: Option<Pid>) -> Result<Option<Arc<Process>>, i64> {
+    let mut processes = PROCESSES.lock();
+    let (mut children, mut exited) = (0, None);
+    for process in processes.values() {
+        if process.parent() != parent || pid.map_or(false, |pid| pid != process.pid)
+            || process.orphaned.load(Ordering::Relaxed) {
+            continue;
+        }
+        children += 1;
+        if process.status().is_some() {
+            exited = Some(process.pid);
+            break;
+        }
+    }
+    if children == 0 {
+        return Err(ECHILD);
+    }
+    Ok(exited.and_then(|pid| processes.remove(&pid)))
+}
+
+/// Maps the `len` bytes of `file` from `offset`, which must be page aligned,
+/// read-only into the calling process. The pages come from the page cache, so
+/// processes mapping the same file share them. Returns the address.
+///
+/// Every page is mapped right away: faulting them in later would mean reading
+/// the file in the page fault handler, which can't wait for the disk.
+pub fn map_file(file: &fd::OpenFile, offset: u64, len: u64) -> Result<u64, i64> {
+    if len == 0 || offset % 0x1000 != 0 {
+        return Err(EINVAL);
+    }
+    let inode = file.lock().inode().clone();
+    let end = offset.checked_add(len).ok_or(EINVAL)?;
+    // pages past the one the file ends in are not mapped
+    if end > (inode.size() as u64 + 0xfff) & !0xfff {
+        return Err(EINVAL);
+    }
+    let pages = (offset / 0x1000..(end + 0xfff) / 0x1000)
+        .map(|index| inode.page(index))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(errno)?;
+    let frames: Vec<_> = pages.iter().map(|page| page.frame()).collect();
+
+    let process = current().ok_or(EINVAL)?;
+    let mut space = process.space.lock();
+    let space = space.as_mut().ok_or(EINVAL)?;
+    if space.mapped_pages() as u64 + frames.len() as u64 > process.limits().pages {
+        return Err(ENOMEM);
+    }
+    let size = frames.len() as u64 * 0x1000;
+    let start = aslr::random_free_base(aslr::USER_MMAP_WINDOW, size, 0x1000).ok_or(ENOMEM)?;
+    space.map_shared(VirtAddr::new(start), &frames, PageTableFlags::NO_EXECUTE).map_err(|_| ENOMEM)?;
+    Ok(start)
+}
+
+/// Maps the shared memory object `name` into the calling process, writable if
+/// `protection` allows it. The mapping stays shared with the children the
+/// process forks. Returns the address.
+pub fn map_shm(name: &str, protection: shm::Protection) -> Result<u64, i64> {
+    let mut flags = PageTableFlags::NO_EXECUTE | address_space::SHARED;
+    if protection == shm::Protection::ReadWrite {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    let process = current().ok_or(EINVAL)?;
+    let mut space = process.space.lock();
+    let space = space.as_mut().ok_or(EINVAL)?;
+    let limits = process.limits();
+    shm::with_frames(name, |frames| {
+        if space.mapped_pages() as u64 + frames.len() as u64 > limits.pages {
+            return Err(ENOMEM);
+        }
+        let size = frames.len() as u64 * 0x1000;
+        let start = aslr::random_free_base(aslr::USER_MMAP_WINDOW, size, 0x1000).ok_or(ENOMEM)?;
+        space.map_shared(VirtAddr::new(start), frames, flags).map_err(|_| ENOMEM)?;
+        Ok(start)
+    }).map_err(syscall::shm_errno)?
+}
+
+/// Blocks while the 32-bit word at `addr` in the calling process holds
+/// `expected`, like `futex::wait`.
+pub fn futex_wait(addr: u64, expected: u32) -> Result<(), i64> {
+    check_futex_word(addr)?;
+    futex::wait_at(VirtAddr::new(addr), || {
+        let mut word = [0; 4];
+        // a word that can't be read anymore ends the wait like a change
+        let left = unsafe { usercopy::copy(word.as_mut_ptr(), addr as *const u8, word.len()) };
+        left != 0 || u32::from_le_bytes(word) != expected
+    }).map_err(|_| EFAULT)
+}
+
+/// Wakes up to `count` threads waiting on the 32-bit word at `addr` in the
+/// calling process, like `futex::wake`, and returns how many there were.
+pub fn futex_wake(addr: u64, count: u64) -> Result<usize, i64> {
+    check_futex_word(addr)?;
+    futex::wake_at(VirtAddr::new(addr), count as usize).map_err(|_| EFAULT)
+}
+
+fn check_futex_word(addr: u64) -> Result<(), i64> {
+    if addr % 4 != 0 {
+        return Err(EINVAL);
+    }
+    check_user_range(addr, 4, false)
+}
+
+/// Moves the end of the calling process's heap to `end`, mapping zeroed pages
+/// or unmapping them, and returns it; with `end` 0, just returns it. The heap
+/// can't shrink below its start, nor grow past the image window or the
+/// process's limits.
+pub fn brk(end: u64) -> Result<u64, i64> {
+    let process = current().ok_or(EINVAL)?;
+    let limits = process.limits();
+    let mut heap = process.heap.lock();
+    if end == 0 {
+        return Ok(heap.end);
+    }
+    if end < heap.start || end > aslr::USER_IMAGE_WINDOW.end {
+        return Err(ENOMEM);
+    }
+    let mut space = process.space.lock();
+    let space = space.as_mut().ok_or(EINVAL)?;
+    let (old, new) = ((heap.end + 0xfff) & !0xfff, (end + 0xfff) & !0xfff);
+    if new > old {
+        let pages = (new - old) / 0x1000;
+        if space.mapped_pages() as u64 + pages > limits.pages || space.private_frames() as u64 + pages > limits.frames {
+            return Err(ENOMEM);
+        }
+        let range = VirtAddr::new(old)..VirtAddr::new(new);
+        if space.map(range.clone(), PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE).is_err() {
+            space.unmap(range);
+            return Err(ENOMEM);
+        }
+    } else if new < old {
+        space.unmap(VirtAddr::new(new)..VirtAddr::new(old));
+    }
+    heap.end = end;
+    Ok(end)
+}
+
+/// Unmaps the pages of the `len` bytes at `addr`, which must be page aligned,
+/// from the calling process.
+pub fn unmap(addr: u64, len: u64) -> Result<(), i64> {
+    if addr % 0x1000 != 0 {
+        return Err(EINVAL);
+    }
+    let start = VirtAddr::try_new(addr).map_err(|_| EINVAL)?;
+    let end = addr.checked_add(len).and_then(|end| VirtAddr::try_new(end).ok()).ok_or(EINVAL)?;
+    let process = current().ok_or(EINVAL)?;
+    let mut space = process.space.lock();
+    let space = space.as_mut().ok_or(EINVAL)?;
+    if len != 0 && !space.is_user_range(&(start..end)) {
+        return Err(EINVAL);
+    }
+    space.unmap(start..end);
+    Ok(())
+}
+
+/// Resolves a copy-on-write fault at `addr` in `space` like
+/// `AddressSpace::copy_on_write`, unless the copy would take the process over
+/// its limit of frames.
+fn copy_on_write(space: &mut AddressSpace, limits: &Limits, addr: VirtAddr) -> Result<bool, &'static str> {
+    if space.is_copy_on_write(addr) && space.private_frames() as u64 >= limits.frames {
+        return Err("frame limit reached");
+    }
+    space.copy_on_write(addr)
+}
+
+/// Checks that the `len` bytes at `addr` are mapped user memory of the calling
+/// process, writable if `write`. Copy-on-write pages are copied first then.
+fn check_user_range(addr: u64, len: u64, write: bool) -> Result<(), i64> {
+    if len == 0 {
+        return Ok(());
+    }
+    let start = VirtAddr::try_new(addr).map_err(|_| EFAULT)?;
+    let end = addr.checked_add(len).and_then(|end| VirtAddr::try_new(end).ok()).ok_or(EFAULT)?;
+    let process = current().ok_or(EINVAL)?;
+    let limits = process.limits();
+    let mut space = process.space.lock();
+    let space = space.as_mut().ok_or(EINVAL)?;
+    if !space.is_user_range(&(start..end)) {
+        return Err(EFAULT);
+    }
+    for page in (addr & !0xfff..end.as_u64()).step_by(0x1000) {
+        let page = VirtAddr::new(page);
+        if write {
+            copy_on_write(space, &limits, page).map_err(|_| ENOMEM)?;
+        }
+        let flags = memory::mapping_of(page).ok_or(EFAULT)?.flags;
+        if !flags.contains(PageTableFlags::USER_ACCESSIBLE) || (write && !flags.contains(PageTableFlags::WRITABLE)) {
+            return Err(EFAULT);
+        }
+    }
+    Ok(())
+}
+
+/// Copies the user memory at `addr` into `buf`. Fails with `EFAULT` unless it
+/// is mapped user memory of the calling process, and if it faults anyway, e.g.
+/// because it was unmapped in between.
+pub fn copy_from_user(buf: &mut [u8], addr: u64) -> Result<(), i64> {
+    check_user_range(addr, buf.len() as u64, false)?;
+    match unsafe { usercopy::copy(buf.as_mut_ptr(), addr as *const u8, buf.len()) } {
+        0 => Ok(()),
+        _ => Err(EFAULT),
+    }
+}
+
+/// Like `copy_from_user`, but copies `buf` to the user memory at `addr`.
+pub fn copy_to_user(addr: u64, buf: &[u8]) -> Result<(), i64> {
+    check_user_range(addr, buf.len() as u64, true)?;
+    match unsafe { usercopy::copy(addr as *mut u8, buf.as_ptr(), buf.len()) } {
+        0 => Ok(()),
+        _ => Err(EFAULT),
+    }
+}
+
+/// Copies the UTF-8 string of `len` bytes at `addr` in user memory.
+pub fn user_str(addr: u64, len: u64) -> Result<String, i64> {
+    if len > PATH_MAX {
+        return Err(EINVAL);
+    }
+    let mut buf = alloc::vec![0; len as usize];
+    copy_from_user(&mut buf, addr)?;
+    String::from_utf8(buf).map_err(|_| EINVAL)
+}
+
+/// Handles a page fault at `addr` in the calling process: resolves
+/// copy-on-write faults in its user space and ends it on invalid accesses from
+/// user mode. Returns false if the fault is none of the process's business.
+pub fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    let process = match current() {
+        Some(process) => process,
+        None => return false,
+    };
+    let write = error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+        && error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION);
+    let limits = process.limits();
+    let resolved = if !write {
+        Ok(false)
+    } else {
+        match process.space.try_lock() {
+            // the faulting code may hold the lock, so never wait for it here
+            Some(mut space) => match space.as_mut() {
+                Some(space) if space.is_user_range(&(addr..addr + 1u64)) => copy_on_write(space, &limits, addr),
+                _ => Ok(false),
+            },
+            None => Ok(false),
+        }
+    };
+    if resolved == Ok(true) {
+        return true;
+    }
+    if !error_code.contains(PageFaultErrorCode::USER_MODE) {
+        return false;
+    }
+    match resolved {
+        Err(reason) => crate::log_info!("process {} ({}): {} at {:#x}", process.pid.0, process.name(), reason,
+                                        addr.as_u64()),
+        _ => crate::log_info!("process {} ({}): invalid access at {:#x}", process.pid.0, process.name(),
+                              addr.as_u64()),
+    }
+    drop(process);
+    // killed like by SIGSEGV
+    exit(128 + 11)
+}
+
+/// Ends the calling process if it went over its CPU time. Called by the timer
+/// interrupt when it interrupts user mode, where the process holds no locks.
+pub fn enforce_cpu_limit() {
+    let pid = match sched::current_process() {
+        Some(pid) => pid,
+        None => return,
+    };
+    // a preempted kernel thread may hold the table, never wait for it here
+    let process = match PROCESSES.try_lock().and_then(|processes| processes.get(&pid).cloned()) {
+        Some(process) => process,
+        None => return,
+    };
+    let limits = match process.limits.try_lock() {
+        Some(limits) => *limits,
+        None => return,
+    };
+    let cpu_time = sched::current().and_then(sched::cpu_time).unwrap_or_default();
+    if !limits.cpu_time_exceeded(cpu_time) {
+        return;
+    }
+    crate::log_info!("process {} ({}): CPU time limit reached", process.pid.0, process.name());
+    drop(process);
+    // killed like by SIGXCPU
+    exit(128 + 24)
+}
+
+/// A filesystem that is a single file, found at its mount point.
+#[cfg(test)]
+struct TestFile(Vec<u8>, fs::page_cache::Object);
+
+/// Mounts a program running `code` at `path`, for tests.
+#[cfg(test)]
+fn mount_test_program(path: &str, code: &[u8]) {
+    let file = Arc::new(TestFile(elf::build(aslr::USER_IMAGE_WINDOW.start + 0x1000, code), fs::page_cache::Object::new()));
+    fs::mount(path, Arc::new(file)).unwrap();
+}
+
+#[cfg(test)]
+impl fs::Inode for TestFile {
+    fn kind(&self) -> fs::InodeKind {
+        fs::InodeKind::File
+    }
+
+    fn size(&self) -> usize {
+        self.0.len()
+    }
+
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize, fs::FsError> {
+        let data = self.0.get(offset..).unwrap_or(&[]);
+        let len = data.len().min(buf.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        Ok(len)
+    }
+
+    fn page(&self, index: u64) -> Result<Arc<fs::page_cache::Page>, fs::FsError> {
+        self.1.page(index, |data| self.read_at(index as usize * 0x1000, data).map(drop))
+    }
+}
+
+#[cfg(test)]
+impl fs::FileSystem for Arc<TestFile> {
+    fn name(&self) -> &str {
+        "test"
+    }
+
+    fn root(&self) -> Arc<dyn fs::Inode> {
+        self.clone()
+    }
+}
+
+#[test_case]
+fn test_programs() {
+    let base = aslr::USER_IMAGE_WINDOW.start;
+    // fork; the child exits with 7, the parent pushes the child's id on its
+    // copy-on-write stack, waits for it and exits with its status + 1
+    let fork: &[u8] = &[
+        0xb8, 0x02, 0x00, 0x00, 0x00, // mov eax, FORK
+        0xcd, 0x80, // int 0x80
+        0x48, 0x85, 0xc0, // test rax, rax
+        0x75, 0x0c, // jnz parent
+        0xb8, 0x00, 0x00, 0x00, 0x00, // mov eax, EXIT
+        0xbf, 0x07, 0x00, 0x00, 0x00, // mov edi, 7
+        0xcd, 0x80, // int 0x80
+        // parent:
+        0x50, // push rax
+        0x5f, // pop rdi
+        0xb8, 0x04, 0x00, 0x00, 0x00, // mov eax, WAIT
+        0xcd, 0x80, // int 0x80
+        0x48, 0x89, 0xc7, // mov rdi, rax
+        0x48, 0x83, 0xc7, 0x01, // add rdi, 1
+        0xb8, 0x00, 0x00, 0x00, 0x00, // mov eax, EXIT
+        0xcd, 0x80, // int 0x80
+    ];
+    // exec the program above, or exit with 1 if that fails
+    let path = b"/test-fork";
+    let mut exec = alloc::vec![
+        0x48, 0x8d, 0x3d, 0x18, 0x00, 0x00, 0x00, // lea rdi, [rip + path]
+        0xbe, path.len() as u8, 0x00, 0x00, 0x00, // mov esi, path.len()
+        0xb8, 0x03, 0x00, 0x00, 0x00, // mov eax, EXEC
+        0xcd, 0x80, // int 0x80
+        0xbf, 0x01, 0x00, 0x00, 0x00, // mov edi, 1
+        0xb8, 0x00, 0x00, 0x00, 0x00, // mov eax, EXIT
+        0xcd, 0x80, // int 0x80
+    ];
+    exec.extend_from_slice(path);
+    // read the first bytes of the program above onto the stack and exit with
+    // the second one, b'E'
+    let mut read = alloc::vec![
+        0x48, 0x8d, 0x3d, 0x30, 0x00, 0x00, 0x00, // lea rdi, [rip + path]
+        0xbe, path.len() as u8, 0x00, 0x00, 0x00, // mov esi, path.len()
+        0x31, 0xd2, // xor edx, edx
+        0xb8, 0x05, 0x00, 0x00, 0x00, // mov eax, OPEN
+        0xcd, 0x80, // int 0x80
+        0x48, 0x89, 0xc7, // mov rdi, rax
+        0x48, 0x83, 0xec, 0x08, // sub rsp, 8
+        0x48, 0x89, 0xe6, // mov rsi, rsp
+        0xba, 0x04, 0x00, 0x00, 0x00, // mov edx, 4
+        0xb8, 0x07, 0x00, 0x00, 0x00, // mov eax, READ
+        0xcd, 0x80, // int 0x80
+        0x0f, 0xb6, 0x7c, 0x24, 0x01, // movzx edi, byte [rsp + 1]
+        0xb8, 0x00, 0x00, 0x00, 0x00, // mov eax, EXIT
+        0xcd, 0x80, // int 0x80
+    ];
+    read.extend_from_slice(path);
+    // map its own first page and exit with its second byte, b'E', read from
+    // the mapping
+    let path = b"/test-mmap";
+    let mut mmap = alloc::vec![
+        0x48, 0x8d, 0x3d, 0x3b, 0x00, 0x00, 0x00, // lea rdi, [rip + path]
+        0xbe, path.len() as u8, 0x00, 0x00, 0x00, // mov esi, path.len()
+        0x31, 0xd2, // xor edx, edx
+        0xb8, 0x05, 0x00, 0x00, 0x00, // mov eax, OPEN
+        0xcd, 0x80, // int 0x80
+        0x48, 0x89, 0xc7, // mov rdi, rax
+        0x31, 0xf6, // xor esi, esi
+        0xba, 0x00, 0x10, 0x00, 0x00, // mov edx, 0x1000
+        0xb8, 0x0c, 0x00, 0x00, 0x00, // mov eax, MMAP
+        0xcd, 0x80, // int 0x80
+        0x0f, 0xb6, 0x58, 0x01, // movzx ebx, byte [rax + 1]
+        0x48, 0x89, 0xc7, // mov rdi, rax
+        0xbe, 0x00, 0x10, 0x00, 0x00, // mov esi, 0x1000
+        0xb8, 0x0d, 0x00, 0x00, 0x00, // mov eax, MUNMAP
+        0xcd, 0x80, // int 0x80
+        0x89, 0xdf, // mov edi, ebx
+        0xb8, 0x00, 0x00, 0x00, 0x00, // mov eax, EXIT
+        0xcd, 0x80, // int 0x80
+    ];
+    mmap.extend_from_slice(path);
+    // grow the heap by two pages and exit with 42, written to its last byte
+    let grow_heap: &[u8] = &[
+        0xb8, 0x11, 0x00, 0x00, 0x00, // mov eax, BRK
+        0x31, 0xff, // xor edi, edi
+        0xcd, 0x80, // int 0x80
+        0x48, 0x89, 0xc3, // mov rbx, rax
+        0x48, 0x8d, 0xb8, 0x00, 0x20, 0x00, 0x00, // lea rdi, [rax + 0x2000]
+        0xb8, 0x11, 0x00, 0x00, 0x00, // mov eax, BRK
+        0xcd, 0x80, // int 0x80
+        0xc6, 0x83, 0xff, 0x1f, 0x00, 0x00, 0x2a, // mov byte [rbx + 0x1fff], 42
+        0x0f, 0xb6, 0xbb, 0xff, 0x1f, 0x00, 0x00, // movzx edi, byte [rbx + 0x1fff]
+        0xb8, 0x00, 0x00, 0x00, 0x00, // mov eax, EXIT
+        0xcd, 0x80, // int 0x80
+    ];
+    // fork; the child forks a grandchild, and both exit with 5 without waiting;
+    // the parent waits for any child and exits with the status it stored
+    let wait_any: &[u8] = &[
+        0xb8, 0x02, 0x00, 0x00, 0x00, // mov eax, FORK
+        0xcd, 0x80, // int 0x80
+        0x48, 0x85, 0xc0, // test rax, rax
+        0x75, 0x13, // jnz parent
+        0xb8, 0x02, 0x00, 0x00, 0x00, // mov eax, FORK
+        0xcd, 0x80, // int 0x80
+        0xb8, 0x00, 0x00, 0x00, 0x00, // mov eax, EXIT
+        0xbf, 0x05, 0x00, 0x00, 0x00, // mov edi, 5
+        0xcd, 0x80, // int 0x80
+        // parent:
+        0x48, 0x83, 0xec, 0x08, // sub rsp, 8
+        0x48, 0xc7, 0xc7, 0xff, 0xff, 0xff, 0xff, // mov rdi, -1
+        0x48, 0x89, 0xe6, // mov rsi, rsp
+        0x31, 0xd2, // xor edx, edx
+        0xb8, 0x0e, 0x00, 0x00, 0x00, // mov eax, WAITPID
+        0xcd, 0x80, // int 0x80
+        0x48, 0x8b, 0x3c, 0x24, // mov rdi, [rsp]
+        0xb8, 0x00, 0x00, 0x00, 0x00, // mov eax, EXIT
+        0xcd, 0x80, // int 0x80
+    ];
+    // exit with the TSC frequency read from the time page, or fault writing it
+    let tsc_hz = (time::vdso::ADDRESS + 24).to_le_bytes();
+    let mut read_time = alloc::vec![0x48, 0xa1]; // movabs rax, [tsc_hz]
+    read_time.extend_from_slice(&tsc_hz);
+    read_time.extend_from_slice(&[
+        0x48, 0x89, 0xc7, // mov rdi, rax
+        0x31, 0xc0, // xor eax, eax (EXIT)
+        0xcd, 0x80, // int 0x80
+    ]);
+    let mut write_time = alloc::vec![0x48, 0xa3]; // movabs [tsc_hz], rax
+    write_time.extend_from_slice(&tsc_hz);
+    write_time.extend_from_slice(&[0x31, 0xff, 0x31, 0xc0, 0xcd, 0x80]); // exit(0)
+    let programs = [
+        ("/test-fork", fork), ("/test-exec", &exec[..]), ("/test-read", &read[..]), ("/test-mmap", &mmap[..]),
+        ("/test-waitpid", wait_any), ("/test-brk", grow_heap), ("/test-read-time", &read_time[..]),
+        ("/test-write-time", &write_time[..]),
+    ];
+    for (name, code) in programs.iter().copied() {
+        mount_test_program(name, code);
+    }
+    // a segment of 256 GiB, past any limit: the size of its only segment is
+    // after the 64 byte file header
+    let mut huge = elf::build(base + 0x1000, &[0xcc]);
+    huge[64 + 40..64 + 48].copy_from_slice(&0x40_0000_0000u64.to_le_bytes());
+    let file = Arc::new(TestFile(huge, fs::page_cache::Object::new()));
+    fs::mount("/test-huge", Arc::new(file)).unwrap();
+
+    let pid = spawn("/test-fork").unwrap();
+    assert_eq!(wait(pid), Ok(8));
+    assert!(list().iter().all(|process| process.pid != pid));
+    let pid = spawn("/test-exec").unwrap();
+    assert_eq!(wait(pid), Ok(8));
+    assert_eq!(wait(pid), Err(ECHILD));
+    let pid = spawn("/test-read").unwrap();
+    assert_eq!(wait(pid), Ok(i64::from(b'E')));
+    let pid = spawn("/test-mmap").unwrap();
+    assert_eq!(wait(pid), Ok(i64::from(b'E')));
+    // its code page, stack and the time page fit, the mapping doesn't: the
+    // byte at the error returned faults
+    let pages = 2 + STACK_SIZE / 0x1000;
+    rlimit::set_default(rlimit::Resource::Pages, pages);
+    let pid = spawn("/test-mmap").unwrap();
+    assert_eq!(wait(pid), Ok(128 + 11));
+    rlimit::set_default(rlimit::Resource::Pages, pages - 1);
+    assert_eq!(spawn("/test-mmap"), Err(ENOMEM));
+    rlimit::set_default(rlimit::Resource::Pages, rlimit::Limits::DEFAULT.pages);
+    assert_eq!(spawn("/test-missing"), Err(syscall::ENOENT));
+    let free = memory::frame_stats().unwrap().allocated;
+    assert_eq!(spawn("/test-huge"), Err(ENOMEM));
+    assert_eq!(memory::frame_stats().unwrap().allocated, free);
+
+    let pid = spawn("/test-brk").unwrap();
+    assert_eq!(wait(pid), Ok(42));
+
+    let pid = spawn("/test-read-time").unwrap();
+    assert_eq!(wait(pid), Ok(time::tsc_frequency() as i64));
+    let pid = spawn("/test-write-time").unwrap();
+    assert_eq!(wait(pid), Ok(128 + 11));
+
+    let pid = spawn("/test-waitpid").unwrap();
+    assert_eq!(waitpid(None, WNOHANG), Ok(None));
+    assert_eq!(wait(pid), Ok(5));
+    // the grandchild, orphaned with no init running, is reaped on its own
+    let deadline = crate::time::Instant::now() + core::time::Duration::from_secs(1);
+    while list().iter().any(|process| process.name() == "/test-waitpid") {
+        assert!(crate::time::Instant::now() < deadline, "orphan not reaped");
+        sched::sleep_ms(10);
+    }
+    assert_eq!(waitpid(None, 0), Err(ECHILD));
+}
@@ -0,0 +1,326 @@
+use core::ops::Range;
+use x86_64::structures::paging::page_table::PageTableEntry;
+use x86_64::structures::paging::{Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame,
+                                 Size4KiB, Translate};
+use x86_64::VirtAddr;
+use crate::arch::paging;
+use crate::memory::fault::COPY_ON_WRITE;
+use crate::memory::{self, FRAME_ALLOCATOR};
+
+/// Flags of the page table entries leading to user pages.
+const TABLE_FLAGS: PageTableFlags = PageTableFlags::PRESENT.union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::USER_ACCESSIBLE);
+
+/// Software-defined page table bit marking a page that stays shared, and
+/// writable if it is, in the child of a fork, like those of shared memory.
+pub const SHARED: PageTableFlags = PageTableFlags::BIT_10;
+
+/// The page tables of a process.
+///
+/// The upper half and every top-level slot the kernel uses are shared with the
+/// kernel's tables; their entries never have `USER_ACCESSIBLE` set. Everything
+/// below the other slots belongs to the process, and its frames are freed
+/// with it, unless another process still shares them after a fork.
+pub struct AddressSpace {
+    root: PhysFrame,
+}
+
+impl AddressSpace {
+    /// Creates an address space with only the kernel mapped.
+    pub fn new() -> Result<AddressSpace, &'static str> {
+        let root = memory::allocate_frames(1)?[0];
+        unsafe {
+            let kernel = memory::page_table(memory::kernel_page_table());
+            let table = memory::page_table(root);
+            for (entry, kernel_entry) in table.iter_mut().zip(kernel.iter()) {
+                *entry = kernel_entry.clone();
+            }
+        }
+        Ok(AddressSpace { root })
+    }
+
+    /// The level 4 table, to be loaded into CR3.
+    pub fn root(&self) -> PhysFrame {
+        self.root
+    }
+
+    fn mapper(&mut self) -> OffsetPageTable<'_> {
+        unsafe { OffsetPageTable::new(memory::page_table(self.root), memory::physical_memory_offset()) }
+    }
+
+    /// Whether `range` lies in top-level slots that belong to the process.
+    pub fn is_user_range(&self, range: &Range<VirtAddr>) -> bool {
+        if range.start >= range.end || range.end.as_u64() > 0x0000_8000_0000_0000 {
+            return false;
+        }
+        let table = unsafe { memory::page_table(self.root) };
+        let first = u16::from(range.start.p4_index());
+        let last = u16::from((range.end - 1u64).p4_index());
+        (first..=last).all(|index| {
+            let entry = &table[usize::from(index)];
+            entry.is_unused() || entry.flags().contains(PageTableFlags::USER_ACCESSIBLE)
+        })
+    }
+
+    /// Maps zeroed frames at every page of `range`, accessible from user mode
+    /// with `flags`.
+    pub fn map(&mut self, range: Range<VirtAddr>, flags: PageTableFlags) -> Result<(), &'static str> {
+        if !self.is_user_range(&range) {
+            return Err("range is not in user space");
+        }
+        let start: Page<Size4KiB> = Page::containing_address(range.start);
+        let end: Page<Size4KiB> = Page::containing_address(range.end - 1u64);
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        for page in Page::range_inclusive(start, end) {
+            let frame = memory::allocate_frames(1)?[0];
+            let mut allocator = FRAME_ALLOCATOR.lock();
+            let allocator = allocator.as_mut().ok_or("frame allocator not installed")?;
+            let result = unsafe { self.mapper().map_to_with_table_flags(page, frame, flags, TABLE_FLAGS, allocator) };
+            match result {
+                // only this address space's tables changed, flushed by the next CR3 load
+                Ok(flush) => flush.ignore(),
+                Err(_) => {
+                    unsafe { x86_64::structures::paging::FrameDeallocator::deallocate_frame(allocator, frame) };
+                    return Err("mapping the page failed");
+                }
+            }
+        }
+        self.flush_if_active();
+        Ok(())
+    }
+
+    /// Maps `frames`, which stay owned by someone else as well, at the pages
+    /// from `start`, accessible from user mode with `flags`. Each mapping holds
+    /// a reference to its frame.
+    pub fn map_shared(&mut self, start: VirtAddr, frames: &[PhysFrame], flags: PageTableFlags)
+                      -> Result<(), &'static str> {
+        let first: Page<Size4KiB> = Page::from_start_address(start).map_err(|_| "start is not page aligned")?;
+        let end = start + frames.len() as u64 * 0x1000;
+        if !self.is_user_range(&(start..end)) {
+            return Err("range is not in user space");
+        }
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        for (page, &frame) in Page::range(first, first + frames.len() as u64).zip(frames) {
+            let result = {
+                let mut allocator = FRAME_ALLOCATOR.lock();
+                let allocator = allocator.as_mut().ok_or("frame allocator not installed")?;
+                unsafe { self.mapper().map_to_with_table_flags(page, frame, flags, TABLE_FLAGS, allocator) }
+            };
+            match result {
+                Ok(flush) => flush.ignore(),
+                Err(_) => {
+                    self.unmap(start..page.start_address());
+                    return Err("mapping the page failed");
+                }
+            }
+            memory::share_frame(frame);
+        }
+        self.flush_if_active();
+        Ok(())
+    }
+
+    /// Unmaps the pages of `range` that are mapped, freeing their frames
+    /// unless they are shared. The page tables stay until the address space is
+    /// dropped.
+    pub fn unmap(&mut self, range: Range<VirtAddr>) {
+        if range.start >= range.end || !self.is_user_range(&range) {
+            return;
+        }
+        let start: Page<Size4KiB> = Page::containing_address(range.start);
+        let end: Page<Size4KiB> = Page::containing_address(range.end - 1u64);
+        for page in Page::range_inclusive(start, end) {
+            if let Some(entry) = unsafe { leaf_entry(self.root, page.start_address()) } {
+                unsafe { free_entry(entry, 1) };
+            }
+        }
+        self.flush_if_active();
+    }
+
+    /// Copies `data` to `addr`, which must be mapped, whatever the page permissions.
+    pub fn write(&mut self, addr: VirtAddr, data: &[u8]) -> Result<(), &'static str> {
+        let mut done = 0;
+        while done < data.len() {
+            let at = addr + done as u64;
+            let phys = self.mapper().translate_addr(at).ok_or("address is not mapped")?;
+            let len = (0x1000 - (at.as_u64() % 0x1000) as usize).min(data.len() - done);
+            let virt = memory::physical_memory_offset() + phys.as_u64();
+            unsafe { core::ptr::copy_nonoverlapping(data[done..].as_ptr(), virt.as_mut_ptr::<u8>(), len) };
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Creates a copy of the address space. Pages are shared copy-on-write, so
+    /// only those written to later are actually copied.
+    pub fn fork(&mut self) -> Result<AddressSpace, &'static str> {
+        let child = AddressSpace::new()?;
+        let (parent_table, child_table) = unsafe { (memory::page_table(self.root), memory::page_table(child.root)) };
+        for (entry, child_entry) in parent_table.iter_mut().zip(child_table.iter_mut()) {
+            if entry.flags().contains(PageTableFlags::USER_ACCESSIBLE) {
+                // on failure, dropping the child frees what was copied
+                unsafe { fork_entry(entry, child_entry, 4)? };
+            }
+        }
+        // the parent's writable pages became read-only
+        self.flush_if_active();
+        Ok(child)
+    }
+
+    /// Gives the page at `addr` a private, writable copy if it is copy-on-write.
+    /// Returns whether it was.
+    pub fn copy_on_write(&mut self, addr: VirtAddr) -> Result<bool, &'static str> {
+        let entry = match unsafe { leaf_entry(self.root, addr) } {
+            Some(entry) if entry.flags().contains(COPY_ON_WRITE) => entry,
+            _ => return Ok(false),
+        };
+        let old = PhysFrame::containing_address(entry.addr());
+        let flags = (entry.flags() - COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+        if memory::is_frame_shared(old) {
+            let new = memory::allocate_frames(1)?[0];
+            unsafe { core::ptr::copy_nonoverlapping(frame_ptr(old), frame_ptr(new), 4096) };
+            entry.set_frame(new, flags);
+            memory::unshare_frame(old);
+        } else {
+            // the other sharers are gone already
+            entry.set_flags(flags);
+        }
+        paging::flush(addr);
+        Ok(true)
+    }
+
+    /// Whether the page at `addr` is copy-on-write.
+    pub fn is_copy_on_write(&self, addr: VirtAddr) -> bool {
+        unsafe { leaf_entry(self.root, addr) }.map_or(false, |entry| entry.flags().contains(COPY_ON_WRITE))
+    }
+
+    /// Number of frames only this address space holds: its page tables, and
+    /// the user pages not shared with another one or the page cache.
+    pub fn private_frames(&self) -> usize {
+        let table = unsafe { memory::page_table(self.root) };
+        let pages: usize = table.iter()
+            .filter(|entry| entry.flags().contains(PageTableFlags::USER_ACCESSIBLE))
+            .map(|entry| unsafe { count_private_frames(entry, 4) })
+            .sum();
+        pages + 1
+    }
+
+    /// Number of user pages mapped, shared ones included.
+    pub fn mapped_pages(&self) -> usize {
+        let table = unsafe { memory::page_table(self.root) };
+        table.iter()
+            .filter(|entry| entry.flags().contains(PageTableFlags::USER_ACCESSIBLE))
+            .map(|entry| unsafe { count_pages(entry, 4) })
+            .sum()
+    }
+
+    fn flush_if_active(&self) {
+        if paging::active_table() == self.root {
+            paging::flush_all();
+        }
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        debug_assert!(paging::active_table() != self.root, "dropping the active address space");
+        let table = unsafe { memory::page_table(self.root) };
+        for entry in table.iter_mut() {
+            if entry.flags().contains(PageTableFlags::USER_ACCESSIBLE) {
+                unsafe { free_entry(entry, 4) };
+            }
+        }
+        unsafe { memory::free_frames(Some(self.root)) };
+    }
+}
+
+fn frame_ptr(frame: PhysFrame) -> *mut u8 {
+    (memory::physical_memory_offset() + frame.start_address().as_u64()).as_mut_ptr()
+}
+
+/// Copies the user entry `entry` of a table at `level` into `child`: tables
+/// are copied, pages are shared copy-on-write.
+unsafe fn fork_entry(entry: &mut PageTableEntry, child: &mut PageTableEntry, level: u32)
+                     -> Result<(), &'static str> {
+    if level == 1 {
+        let frame = PhysFrame::containing_address(entry.addr());
+        let mut flags = entry.flags();
+        if flags.contains(PageTableFlags::WRITABLE) && !flags.contains(SHARED) {
+            flags = (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE;
+            entry.set_flags(flags);
+        }
+        memory::share_frame(frame);
+        child.set_frame(frame, flags);
+        return Ok(());
+    }
+    if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+        return Err("huge pages in user space are not supported");
+    }
+    let table = memory::allocate_frames(1)?[0];
+    child.set_frame(table, entry.flags());
+    let source = memory::page_table(PhysFrame::containing_address(entry.addr()));
+    let copy = memory::page_table(table);
+    for (entry, child) in source.iter_mut().zip(copy.iter_mut()) {
+        if !entry.is_unused() {
+            fork_entry(entry, child, level - 1)?;
+        }
+    }
+    Ok(())
+}
+
+/// Frees the user entry `entry` of a table at `level`, with everything below it.
+unsafe fn free_entry(entry: &mut PageTableEntry, level: u32) {
+    let frame = PhysFrame::containing_address(entry.addr());
+    if level > 1 {
+        for child in memory::page_table(frame).iter_mut() {
+            if !child.is_unused() {
+                free_entry(child, level - 1);
+            }
+        }
+        memory::free_frames(Some(frame));
+    } else if memory::unshare_frame(frame) {
+        memory::free_frames(Some(frame));
+    }
+    entry.set_unused();
+}
+
+/// Counts the pages mapped below the user entry `entry` of a table at `level`.
+unsafe fn count_pages(entry: &PageTableEntry, level: u32) -> usize {
+    if level == 1 {
+        return 1;
+    }
+    memory::page_table(PhysFrame::containing_address(entry.addr())).iter()
+        .filter(|child| !child.is_unused())
+        .map(|child| count_pages(child, level - 1))
+        .sum()
+}
+
+/// Counts the frames below the user entry `entry` of a table at `level`,
+/// tables included, that are not shared.
+unsafe fn count_private_frames(entry: &PageTableEntry, level: u32) -> usize {
+    let frame = PhysFrame::containing_address(entry.addr());
+    if level == 1 {
+        return if memory::is_frame_shared(frame) { 0 } else { 1 };
+    }
+    1 + memory::page_table(frame).iter()
+        .filter(|child| !child.is_unused())
+        .map(|child| count_private_frames(child, level - 1))
+        .sum::<usize>()
+}
+
+/// The level 1 entry mapping `addr` in the tables at `root`, if there is one.
+unsafe fn leaf_entry(root: PhysFrame, addr: VirtAddr) -> Option<&'static mut PageTableEntry> {
+    let indices = [addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()];
+    let mut table: &'static mut PageTable = memory::page_table(root);
+    for (level, &index) in indices.iter().enumerate() {
+        let entry = &mut table[index];
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) || flags.contains(PageTableFlags::HUGE_PAGE) {
+            return None;
+        }
+        if level == 3 {
+            return Some(entry);
+        }
+        table = memory::page_table(PhysFrame::containing_address(entry.addr()));
+    }
+    None
+}
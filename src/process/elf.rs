@@ -0,0 +1,149 @@
+//! Parsing of statically linked ELF64 executables for x86_64.
+
+use alloc::vec::Vec;
+
+const PT_LOAD: u32 = 1;
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 0x3e;
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+/// A loadable segment: `file_size` bytes from `offset` in the file, followed
+/// by zeroes up to `mem_size`, at `vaddr`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment {
+    pub vaddr: u64,
+    /// Where a kernel loaded without paging wants it in physical memory.
+    pub paddr: u64,
+    pub mem_size: u64,
+    pub offset: usize,
+    pub file_size: usize,
+    pub writable: bool,
+    pub executable: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Executable {
+    pub entry: u64,
+    pub segments: Vec<Segment>,
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&data[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&data[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+/// Reads the headers of the executable in `data`. Segments are checked to lie
+/// inside the file, but not where they are loaded.
+pub fn parse(data: &[u8]) -> Result<Executable, &'static str> {
+    if data.len() < HEADER_SIZE || data[..4] != *b"\x7fELF" {
+        return Err("not an ELF file");
+    }
+    // 64-bit, little endian
+    if data[4] != 2 || data[5] != 1 {
+        return Err("not a 64-bit little-endian ELF file");
+    }
+    if u16_at(data, 16) != ET_EXEC {
+        return Err("not a static executable");
+    }
+    if u16_at(data, 18) != EM_X86_64 {
+        return Err("not an x86_64 executable");
+    }
+    let entry = u64_at(data, 24);
+    let ph_offset = u64_at(data, 32) as usize;
+    let ph_size = u16_at(data, 54) as usize;
+    let ph_count = u16_at(data, 56) as usize;
+    if ph_size != PROGRAM_HEADER_SIZE {
+        return Err("unexpected program header size");
+    }
+    let ph_end = ph_count.checked_mul(ph_size).and_then(|size| size.checked_add(ph_offset));
+    if ph_end.map_or(true, |end| end > data.len()) {
+        return Err("program headers outside the file");
+    }
+
+    let mut segments = Vec::new();
+    for i in 0..ph_count {
+        let header = &data[ph_offset + i * ph_size..][..ph_size];
+        if u32_at(header, 0) != PT_LOAD {
+            continue;
+        }
+        let flags = u32_at(header, 4);
+        let segment = Segment {
+            offset: u64_at(header, 8) as usize,
+            vaddr: u64_at(header, 16),
+            paddr: u64_at(header, 24),
+            file_size: u64_at(header, 32) as usize,
+            mem_size: u64_at(header, 40),
+            executable: flags & 1 != 0,
+            writable: flags & 2 != 0,
+        };
+        if segment.file_size as u64 > segment.mem_size {
+            return Err("segment is larger in the file than in memory");
+        }
+        if segment.offset.checked_add(segment.file_size).map_or(true, |end| end > data.len()) {
+            return Err("segment outside the file");
+        }
+        segments.push(segment);
+    }
+    Ok(Executable { entry, segments })
+}
+
+/// Builds an executable with one loadable segment holding `code` at `vaddr`,
+/// entered at its start. For tests and the programs built into the kernel.
+pub fn build(vaddr: u64, code: &[u8]) -> Vec<u8> {
+    let code_offset = HEADER_SIZE + PROGRAM_HEADER_SIZE;
+    let mut data = Vec::new();
+    data.extend_from_slice(b"\x7fELF\x02\x01\x01");
+    data.resize(16, 0);
+    data.extend_from_slice(&ET_EXEC.to_le_bytes());
+    data.extend_from_slice(&EM_X86_64.to_le_bytes());
+    data.extend_from_slice(&1u32.to_le_bytes());
+    data.extend_from_slice(&vaddr.to_le_bytes());
+    data.extend_from_slice(&(HEADER_SIZE as u64).to_le_bytes());
+    data.extend_from_slice(&0u64.to_le_bytes());
+    data.extend_from_slice(&0u32.to_le_bytes());
+    for value in [HEADER_SIZE as u16, PROGRAM_HEADER_SIZE as u16, 1, 0, 0, 0].iter() {
+        data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    data.extend_from_slice(&PT_LOAD.to_le_bytes());
+    data.extend_from_slice(&5u32.to_le_bytes()); // readable, executable
+    for value in [code_offset as u64, vaddr, vaddr, code.len() as u64, code.len() as u64, 0x1000].iter() {
+        data.extend_from_slice(&value.to_le_bytes());
+    }
+    data.extend_from_slice(code);
+    data
+}
+
+#[test_case]
+fn test_parse() {
+    let data = build(0x0800_0000_1000, &[0x90, 0xcc]);
+    let executable = parse(&data).unwrap();
+    assert_eq!(executable.entry, 0x0800_0000_1000);
+    assert_eq!(executable.segments, [Segment {
+        vaddr: 0x0800_0000_1000,
+        paddr: 0x0800_0000_1000,
+        mem_size: 2,
+        offset: HEADER_SIZE + PROGRAM_HEADER_SIZE,
+        file_size: 2,
+        writable: false,
+        executable: true,
+    }]);
+
+    assert!(parse(b"\x7fELF").is_err());
+    assert!(parse(&data[..data.len() - 1]).is_err());
+    let mut wrong_machine = data.clone();
+    wrong_machine[18] = 3;
+    assert!(parse(&wrong_machine).is_err());
+}
@@ -0,0 +1,199 @@
+//! File descriptors: the small integers a process names its open files by.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+use crate::fs::{self, FsError, InodeKind};
+use crate::process::{self, syscall::{errno, EBADF, EINVAL, EMFILE}};
+
+/// Most descriptors a process can have open at once.
+pub const MAX_FILES: usize = 64;
+
+pub const O_CREAT: u64 = 0o100;
+pub const O_TRUNC: u64 = 0o1000;
+
+pub const SEEK_SET: u64 = 0;
+pub const SEEK_CUR: u64 = 1;
+pub const SEEK_END: u64 = 2;
+
+/// An open file, shared by the descriptors duplicated from the same `open`,
+/// in this process and in its forked children.
+pub type OpenFile = Arc<Mutex<fs::File>>;
+
+/// The descriptors of a process.
+#[derive(Clone, Default)]
+pub struct FdTable {
+    files: Vec<Option<OpenFile>>,
+    /// The most descriptors open at once, below `MAX_FILES`; `None` for `MAX_FILES`.
+    limit: Option<usize>,
+}
+
+impl FdTable {
+    /// A table with the console open as standard input, output and error, or
+    /// an empty one if there is no `/dev/console`.
+    pub fn standard() -> FdTable {
+        let mut table = FdTable::default();
+        if let Ok(console) = fs::File::open("/dev/console") {
+            let console = Arc::new(Mutex::new(console));
+            for _ in 0..3 {
+                let _ = table.insert(console.clone());
+            }
+        }
+        table
+    }
+
+    /// Adds `file` under the lowest free descriptor and returns it.
+    pub fn insert(&mut self, file: OpenFile) -> Result<usize, i64> {
+        if self.count() >= self.limit.unwrap_or(MAX_FILES) {
+            return Err(EMFILE);
+        }
+        match self.files.iter().position(Option::is_none) {
+            Some(fd) => {
+                self.files[fd] = Some(file);
+                Ok(fd)
+            }
+            None => {
+                self.files.push(Some(file));
+                Ok(self.files.len() - 1)
+            }
+        }
+    }
+
+    /// Number of open descriptors.
+    pub fn count(&self) -> usize {
+        self.files.iter().filter(|file| file.is_some()).count()
+    }
+
+    /// Lets at most `limit` descriptors be open at once, from the next one
+    /// opened on; those already open stay so.
+    pub fn set_limit(&mut self, limit: u64) {
+        self.limit = Some(limit.min(MAX_FILES as u64) as usize);
+    }
+
+    pub fn get(&self, fd: u64) -> Result<OpenFile, i64> {
+        self.files.get(fd as usize).cloned().flatten().ok_or(EBADF)
+    }
+
+    /// Closes `fd`. The file itself is closed with its last descriptor.
+    pub fn remove(&mut self, fd: u64) -> Result<OpenFile, i64> {
+        let file = self.files.get_mut(fd as usize).and_then(Option::take).ok_or(EBADF)?;
+        while let Some(None) = self.files.last() {
+            self.files.pop();
+        }
+        Ok(file)
+    }
+
+    /// Opens the file of `fd` under a second descriptor, sharing its offset.
+    pub fn dup(&mut self, fd: u64) -> Result<usize, i64> {
+        let file = self.get(fd)?;
+        self.insert(file)
+    }
+
+    /// Closes every descriptor.
+    pub fn clear(&mut self) {
+        self.files.clear();
+    }
+}
+
+/// Creates a pipe and opens its reading and writing ends in the calling
+/// process, returning their descriptors.
+pub fn pipe() -> Result<(usize, usize), i64> {
+    let (reader, writer) = crate::pipe::pipe();
+    let reader: OpenFile = Arc::new(Mutex::new(fs::File::from_inode(reader)));
+    let writer: OpenFile = Arc::new(Mutex::new(fs::File::from_inode(writer)));
+    process::files(|files| {
+        let reader = files.insert(reader)?;
+        match files.insert(writer) {
+            Ok(writer) => Ok((reader, writer)),
+            Err(error) => {
+                files.remove(reader as u64)?;
+                Err(error)
+            }
+        }
+    })
+}
+
+/// Opens the file at `path`, creating it with `O_CREAT` and emptying it with
+/// `O_TRUNC`.
+pub fn open(path: &str, flags: u64) -> Result<fs::File, i64> {
+    let file = match fs::File::open(path) {
+        Err(FsError::NotFound) if flags & O_CREAT != 0 => {
+            fs::create(path, InodeKind::File).map_err(errno)?;
+            fs::File::open(path)
+        }
+        result => result,
+    }.map_err(errno)?;
+    if flags & O_TRUNC != 0 && file.inode().kind() == InodeKind::File {
+        file.inode().truncate(0).map_err(errno)?;
+    }
+    Ok(file)
+}
+
+/// Reads from `file` at its offset into `buf` and advances the offset. The
+/// file is not locked while reading, which may block.
+pub fn read(file: &OpenFile, buf: &mut [u8]) -> Result<usize, i64> {
+    let (inode, offset) = {
+        let file = file.lock();
+        (file.inode().clone(), file.offset())
+    };
+    let read = inode.read_at(offset, buf).map_err(errno)?;
+    file.lock().seek(offset + read);
+    Ok(read)
+}
+
+/// Writes `buf` to `file` at its offset and advances the offset.
+pub fn write(file: &OpenFile, buf: &[u8]) -> Result<usize, i64> {
+    let (inode, offset) = {
+        let file = file.lock();
+        (file.inode().clone(), file.offset())
+    };
+    let written = inode.write_at(offset, buf).map_err(errno)?;
+    file.lock().seek(offset + written);
+    Ok(written)
+}
+
+/// Moves the offset of `file` to `offset` from the start, the current offset
+/// or the end, as `whence` says, and returns the new offset.
+pub fn seek(file: &OpenFile, offset: i64, whence: u64) -> Result<usize, i64> {
+    let mut file = file.lock();
+    let base = match whence {
+        SEEK_SET => 0,
+        SEEK_CUR => file.offset(),
+        SEEK_END => file.inode().size(),
+        _ => return Err(EINVAL),
+    };
+    let offset = (base as i64).checked_add(offset).filter(|&offset| offset >= 0).ok_or(EINVAL)?;
+    file.seek(offset as usize);
+    Ok(offset as usize)
+}
+
+#[test_case]
+fn test_descriptors() {
+    use crate::fs::FileSystem;
+
+    let null = fs::devfs::DEVFS.root().lookup("null").unwrap();
+    let mut table = FdTable::default();
+    for fd in 0..3 {
+        let file = Arc::new(Mutex::new(fs::File::from_inode(null.clone())));
+        assert_eq!(table.insert(file), Ok(fd));
+    }
+    assert!(table.remove(1).is_ok());
+    assert_eq!(table.remove(1).err(), Some(EBADF));
+    assert_eq!(table.dup(2), Ok(1));
+    assert!(Arc::ptr_eq(&table.get(1).unwrap(), &table.get(2).unwrap()));
+
+    let file = table.get(0).unwrap();
+    assert_eq!(write(&file, b"discarded"), Ok(9));
+    assert_eq!(seek(&file, -4, SEEK_CUR), Ok(5));
+    assert_eq!(seek(&file, -1, SEEK_SET), Err(EINVAL));
+    assert_eq!(read(&file, &mut [0; 4]), Ok(0));
+
+    while table.insert(file.clone()).is_ok() {}
+    assert_eq!(table.count(), MAX_FILES);
+    assert_eq!(table.dup(0), Err(EMFILE));
+    table.set_limit(4);
+    assert!(table.remove(1).is_ok());
+    assert_eq!(table.dup(0), Err(EMFILE));
+    table.clear();
+    assert_eq!(table.get(0).err(), Some(EBADF));
+}
@@ -0,0 +1,57 @@
+//! Starting init, the first process, which the rest of user space grows from.
+//!
+//! The kernel runs `/bin/init` as PID 1, or the program the command line
+//! names with `init=<path>`; `init=none` runs none. Its config,
+//! `/etc/init.conf`, is the QEMU fw_cfg file `opt/maros/init.conf` if there
+//! is one, e.g. `-fw_cfg name=opt/maros/init.conf,file=init.conf`, or a
+//! default one. It has an instruction per line:
+//! - `mount <block device> <path>`: mount the filesystem on the device
+//! - `shell <path>`: the program to run, again whenever it exits, `/bin/sh`
+//!   by default
+//!
+//! Lines starting with `#` are comments.
+//!
+//! Once init runs, the lines typed on the console go to user space, see
+//! `console`.
+
+use alloc::sync::Arc;
+use alloc::vec;
+use crate::boot::fw_cfg;
+use crate::fs::{self, tmpfs::TmpFs};
+use crate::{initcall, log_info, log_warn};
+use super::INIT;
+
+const FW_CFG_FILE: &str = "opt/maros/init.conf";
+/// Longest config init reads.
+const MAX_CONFIG: usize = 4096;
+
+const DEFAULT_CONFIG: &str = "\
+# mount <block device> <path>
+# shell <path>
+shell /bin/sh
+";
+
+/// Writes the config and starts init.
+pub fn start() -> Result<(), &'static str> {
+    let path = crate::boot::cmdline().get("init").unwrap_or("/bin/init");
+    if path == "none" {
+        log_info!("init: not started");
+        return Ok(());
+    }
+    fs::mount("/etc", Arc::new(TmpFs::new())).map_err(|_| "mounting /etc failed")?;
+    let mut buf = vec![0; MAX_CONFIG];
+    let config = match fw_cfg::read_file(FW_CFG_FILE, &mut buf) {
+        Some(len) => &buf[..len],
+        None => DEFAULT_CONFIG.as_bytes(),
+    };
+    fs::write_file("/etc/init.conf", config).map_err(|_| "writing /etc/init.conf failed")?;
+    let pid = super::spawn(path).map_err(|_| "starting init failed")?;
+    crate::console::set_user_input(true);
+    if pid != INIT {
+        log_warn!("init: {} is PID {}, orphans won't be handed to it", path, pid.0);
+    } else {
+        log_info!("init: {} started", path);
+    }
+    Ok(())
+}
+initcall!(Late, "init", start, needs: &["programs", "process"]);
@@ -0,0 +1,601 @@
+//! User programs built into the kernel, installed in `/bin` at boot.
+//!
+//! There is no toolchain for user programs yet, so they are written in
+//! assembly here, position independent and without data segments. Their code
+//! is copied into executables with a single loadable segment; their buffers
+//! are on their stack.
+
+use alloc::sync::Arc;
+use core::arch::global_asm;
+use crate::fs::{self, tmpfs::TmpFs};
+use crate::{aslr, initcall, log_info};
+use super::elf;
+
+global_asm!(r#"
+// init: mounts the filesystems listed in /etc/init.conf, then runs the shell
+// it names, again whenever it exits, and reaps the orphans handed to it
+.section .rodata.maros_programs, "a"
+.global user_init_start
+user_init_start:
+    // read the config into a buffer on the stack
+    sub rsp, 4096
+    mov r12, rsp
+    xor r13, r13
+    lea rdi, [rip + init_conf]
+    mov esi, 14
+    xor edx, edx
+    mov eax, 5 // OPEN
+    int 0x80
+    test rax, rax
+    js init_parse
+    mov rbx, rax
+init_read:
+    mov rdi, rbx
+    lea rsi, [r12 + r13]
+    mov edx, 4096
+    sub rdx, r13
+    jz init_close
+    mov eax, 7 // READ
+    int 0x80
+    test rax, rax
+    jle init_close
+    add r13, rax
+    jmp init_read
+init_close:
+    mov rdi, rbx
+    mov eax, 6 // CLOSE
+    int 0x80
+
+    // parse it line by line, r14 at the next character, r15 at the end
+init_parse:
+    mov r14, r12
+    lea r15, [r12 + r13]
+    lea r12, [rip + init_default_shell]
+    mov r13d, 7
+init_line:
+    cmp r14, r15
+    jae init_spawn
+    call init_word
+    test rdx, rdx
+    jz init_next
+    cmp byte ptr [rax], 35 // '#'
+    je init_next
+    lea rdi, [rip + init_mount]
+    mov esi, 5
+    call init_equals
+    je init_do_mount
+    lea rdi, [rip + init_shell]
+    mov esi, 5
+    call init_equals
+    je init_do_shell
+init_invalid:
+    lea rsi, [rip + init_invalid_message]
+    mov edx, 37
+    call init_print
+init_next:
+    call init_skip_line
+    jmp init_line
+
+    // mount <device> <path>
+init_do_mount:
+    call init_word
+    mov rbx, rax
+    mov rbp, rdx
+    call init_word
+    test rbp, rbp
+    jz init_invalid
+    test rdx, rdx
+    jz init_invalid
+    mov rdi, rbx
+    mov rsi, rbp
+    mov r10, rdx
+    mov rdx, rax
+    mov eax, 16 // MOUNT
+    int 0x80
+    test rax, rax
+    jns init_next
+    lea rsi, [rip + init_mount_message]
+    mov edx, 19
+    call init_print
+    mov rsi, rbx
+    mov rdx, rbp
+    call init_print
+    lea rsi, [rip + init_newline]
+    mov edx, 1
+    call init_print
+    jmp init_next
+
+    // shell <path>
+init_do_shell:
+    call init_word
+    test rdx, rdx
+    jz init_invalid
+    mov r12, rax
+    mov r13, rdx
+    jmp init_next
+
+    // run the shell, r12 and r13 its path
+init_spawn:
+    mov eax, 2 // FORK
+    int 0x80
+    test rax, rax
+    js init_restart
+    jnz init_wait
+    mov rdi, r12
+    mov rsi, r13
+    xor edx, edx
+    xor r10d, r10d
+    mov eax, 3 // EXEC
+    int 0x80
+    lea rsi, [rip + init_run_message]
+    mov edx, 17
+    call init_print
+    mov rsi, r12
+    mov rdx, r13
+    call init_print
+    lea rsi, [rip + init_newline]
+    mov edx, 1
+    call init_print
+    mov edi, 127
+    mov eax, 0 // EXIT
+    int 0x80
+init_wait:
+    mov rbx, rax
+init_reap:
+    mov rdi, -1
+    xor esi, esi
+    xor edx, edx
+    mov eax, 14 // WAITPID
+    int 0x80
+    test rax, rax
+    js init_restart
+    cmp rax, rbx
+    jne init_reap
+init_restart:
+    // so a shell that fails at once doesn't keep the CPU busy
+    mov edi, 1000
+    mov eax, 15 // SLEEP
+    int 0x80
+    jmp init_spawn
+
+// the next word of the line at r14, its start in rax and its length in rdx,
+// 0 at the end of the line; leaves r14 after it
+init_word:
+    cmp r14, r15
+    jae init_word_start
+    movzx ecx, byte ptr [r14]
+    cmp ecx, 32
+    je init_word_blank
+    cmp ecx, 9
+    jne init_word_start
+init_word_blank:
+    inc r14
+    jmp init_word
+init_word_start:
+    mov rax, r14
+init_word_char:
+    cmp r14, r15
+    jae init_word_end
+    movzx ecx, byte ptr [r14]
+    cmp ecx, 32
+    je init_word_end
+    cmp ecx, 9
+    je init_word_end
+    cmp ecx, 10
+    je init_word_end
+    inc r14
+    jmp init_word_char
+init_word_end:
+    mov rdx, r14
+    sub rdx, rax
+    ret
+
+// moves r14 past the end of its line
+init_skip_line:
+    cmp r14, r15
+    jae init_skip_line_end
+    movzx ecx, byte ptr [r14]
+    inc r14
+    cmp ecx, 10
+    jne init_skip_line
+init_skip_line_end:
+    ret
+
+// whether the word at rax of rdx bytes is the rsi bytes at rdi, in ZF
+init_equals:
+    cmp rdx, rsi
+    jne init_equals_end
+    xor ecx, ecx
+init_equals_char:
+    cmp rcx, rdx
+    je init_equals_end
+    mov r8b, byte ptr [rax + rcx]
+    cmp r8b, byte ptr [rdi + rcx]
+    jne init_equals_end
+    inc rcx
+    jmp init_equals_char
+init_equals_end:
+    ret
+
+// writes the rdx bytes at rsi to standard output
+init_print:
+    push rax
+    push rdi
+    mov edi, 1
+    mov eax, 8 // WRITE
+    int 0x80
+    pop rdi
+    pop rax
+    ret
+
+init_conf:
+    .ascii "/etc/init.conf"
+init_default_shell:
+    .ascii "/bin/sh"
+init_mount:
+    .ascii "mount"
+init_shell:
+    .ascii "shell"
+init_invalid_message:
+    .ascii "init: invalid line in /etc/init.conf\n"
+init_mount_message:
+    .ascii "init: cannot mount "
+init_run_message:
+    .ascii "init: cannot run "
+init_newline:
+    .ascii "\n"
+.global user_init_end
+user_init_end:
+
+// sh: reads command lines from standard input and runs the program each one
+// names, looked up in /bin unless it is a path, with the words after it as its
+// arguments, waiting for it to exit. Its builtins are `echo`, `exit [status]`
+// and `help`
+.global user_sh_start
+user_sh_start:
+    // the line at r12, the path to run at r13, the exit status at r12 + 512,
+    // the arguments at r12 + 528
+    sub rsp, 784
+    mov r12, rsp
+    lea r13, [rsp + 256]
+sh_prompt:
+    lea rsi, [rip + sh_prompt_text]
+    mov edx, 2
+    call sh_print
+    xor ebx, ebx
+sh_read:
+    xor edi, edi
+    lea rsi, [r12 + rbx]
+    mov edx, 255
+    sub rdx, rbx
+    jz sh_line
+    mov eax, 7 // READ
+    int 0x80
+    test rax, rax
+    jle sh_exit
+    add rbx, rax
+    cmp byte ptr [r12 + rbx - 1], 10
+    jne sh_read
+
+    // the words from r14 to r15, after the prompt the console hands back
+sh_line:
+    mov r14, r12
+    lea r15, [r12 + rbx]
+    cmp rbx, 2
+    jb sh_command
+    cmp word ptr [r12], 0x2024 // "$ "
+    jne sh_command
+    add r14, 2
+sh_command:
+    call sh_word
+    test rdx, rdx
+    jz sh_prompt
+    lea rdi, [rip + sh_exit_word]
+    mov esi, 4
+    call sh_equals
+    je sh_do_exit
+    lea rdi, [rip + sh_echo_word]
+    mov esi, 4
+    call sh_equals
+    je sh_do_echo
+    lea rdi, [rip + sh_help_word]
+    mov esi, 4
+    call sh_equals
+    je sh_do_help
+    cmp rdx, 250
+    ja sh_too_long
+
+    // the path: the word, in /bin unless it has a slash
+    mov rdi, r13
+    xor ecx, ecx
+sh_slash:
+    cmp rcx, rdx
+    je sh_in_bin
+    cmp byte ptr [rax + rcx], 47 // '/'
+    je sh_copy
+    inc rcx
+    jmp sh_slash
+sh_in_bin:
+    mov dword ptr [rdi], 0x6e69622f // "/bin"
+    mov byte ptr [rdi + 4], 47
+    add rdi, 5
+sh_copy:
+    mov rsi, rax
+    mov rcx, rdx
+    rep movsb
+    mov rbp, rdi
+    sub rbp, r13
+
+    // the arguments, each followed by a NUL, rbx bytes of them; they fit, as
+    // the line is shorter than their buffer
+    lea rdi, [r12 + 528]
+sh_argument:
+    call sh_word
+    test rdx, rdx
+    jz sh_arguments_end
+    mov rsi, rax
+    mov rcx, rdx
+    rep movsb
+    mov byte ptr [rdi], 0
+    inc rdi
+    jmp sh_argument
+sh_arguments_end:
+    lea rbx, [r12 + 528]
+    sub rdi, rbx
+    mov rbx, rdi
+
+    mov eax, 2 // FORK
+    int 0x80
+    test rax, rax
+    js sh_fork_failed
+    jnz sh_wait
+    mov rdi, r13
+    mov rsi, rbp
+    lea rdx, [r12 + 528]
+    mov r10, rbx
+    mov eax, 3 // EXEC
+    int 0x80
+    lea rsi, [rip + sh_run_message]
+    mov edx, 15
+    call sh_print
+    mov rsi, r13
+    mov rdx, rbp
+    call sh_print
+    lea rsi, [rip + sh_newline]
+    mov edx, 1
+    call sh_print
+    mov edi, 127
+    mov eax, 0 // EXIT
+    int 0x80
+sh_wait:
+    mov rdi, rax
+    lea rsi, [r12 + 512]
+    xor edx, edx
+    mov eax, 14 // WAITPID
+    int 0x80
+    test rax, rax
+    js sh_prompt
+    mov rax, [r12 + 512]
+    test rax, rax
+    jz sh_prompt
+    lea rsi, [rip + sh_status_message]
+    mov edx, 16
+    call sh_print
+    call sh_print_number
+    lea rsi, [rip + sh_newline]
+    mov edx, 1
+    call sh_print
+    jmp sh_prompt
+sh_fork_failed:
+    lea rsi, [rip + sh_fork_message]
+    mov edx, 16
+    call sh_print
+    jmp sh_prompt
+sh_too_long:
+    lea rsi, [rip + sh_long_message]
+    mov edx, 18
+    call sh_print
+    jmp sh_prompt
+
+    // echo <words>: writes them separated by spaces, r8 the separator's length
+sh_do_echo:
+    xor r8d, r8d
+sh_echo_next:
+    call sh_word
+    test rdx, rdx
+    jz sh_echo_end
+    mov rbx, rax
+    mov rbp, rdx
+    lea rsi, [rip + sh_space]
+    mov rdx, r8
+    call sh_print
+    mov rsi, rbx
+    mov rdx, rbp
+    call sh_print
+    mov r8d, 1
+    jmp sh_echo_next
+sh_echo_end:
+    lea rsi, [rip + sh_newline]
+    mov edx, 1
+    call sh_print
+    jmp sh_prompt
+
+sh_do_help:
+    lea rsi, [rip + sh_help_message]
+    mov edx, 122
+    call sh_print
+    jmp sh_prompt
+
+    // exit [status]: the status in decimal, 0 without one
+sh_do_exit:
+    call sh_word
+    xor edi, edi
+    xor ecx, ecx
+sh_exit_digit:
+    cmp rcx, rdx
+    je sh_exit_status
+    movzx r8d, byte ptr [rax + rcx]
+    sub r8d, 48 // '0'
+    cmp r8d, 9
+    ja sh_bad_status
+    imul rdi, rdi, 10
+    add rdi, r8
+    inc rcx
+    jmp sh_exit_digit
+sh_bad_status:
+    lea rsi, [rip + sh_status_usage]
+    mov edx, 25
+    call sh_print
+    jmp sh_prompt
+sh_exit:
+    xor edi, edi
+sh_exit_status:
+    mov eax, 0 // EXIT
+    int 0x80
+
+// the next word from r14, like init_word
+sh_word:
+    cmp r14, r15
+    jae sh_word_start
+    movzx ecx, byte ptr [r14]
+    cmp ecx, 32
+    je sh_word_blank
+    cmp ecx, 9
+    jne sh_word_start
+sh_word_blank:
+    inc r14
+    jmp sh_word
+sh_word_start:
+    mov rax, r14
+sh_word_char:
+    cmp r14, r15
+    jae sh_word_end
+    movzx ecx, byte ptr [r14]
+    cmp ecx, 32
+    je sh_word_end
+    cmp ecx, 9
+    je sh_word_end
+    cmp ecx, 10
+    je sh_word_end
+    inc r14
+    jmp sh_word_char
+sh_word_end:
+    mov rdx, r14
+    sub rdx, rax
+    ret
+
+// like init_equals
+sh_equals:
+    cmp rdx, rsi
+    jne sh_equals_end
+    xor ecx, ecx
+sh_equals_char:
+    cmp rcx, rdx
+    je sh_equals_end
+    mov r8b, byte ptr [rax + rcx]
+    cmp r8b, byte ptr [rdi + rcx]
+    jne sh_equals_end
+    inc rcx
+    jmp sh_equals_char
+sh_equals_end:
+    ret
+
+// writes rax in decimal to standard output
+sh_print_number:
+    push rbx
+    sub rsp, 32
+    mov rbx, rax
+    lea rsi, [rsp + 32]
+    test rax, rax
+    jns sh_number_digit
+    neg rax
+sh_number_digit:
+    xor edx, edx
+    mov ecx, 10
+    div rcx
+    add dl, 48
+    dec rsi
+    mov byte ptr [rsi], dl
+    test rax, rax
+    jnz sh_number_digit
+    test rbx, rbx
+    jns sh_number_print
+    dec rsi
+    mov byte ptr [rsi], 45 // '-'
+sh_number_print:
+    lea rdx, [rsp + 32]
+    sub rdx, rsi
+    call sh_print
+    add rsp, 32
+    pop rbx
+    ret
+
+// writes the rdx bytes at rsi to standard output
+sh_print:
+    push rax
+    push rdi
+    mov edi, 1
+    mov eax, 8 // WRITE
+    int 0x80
+    pop rdi
+    pop rax
+    ret
+
+sh_prompt_text:
+    .ascii "$ "
+sh_exit_word:
+    .ascii "exit"
+sh_echo_word:
+    .ascii "echo"
+sh_help_word:
+    .ascii "help"
+sh_run_message:
+    .ascii "sh: cannot run "
+sh_status_message:
+    .ascii "sh: exited with "
+sh_fork_message:
+    .ascii "sh: cannot fork\n"
+sh_long_message:
+    .ascii "sh: name too long\n"
+sh_status_usage:
+    .ascii "sh: usage: exit [status]\n"
+sh_help_message:
+    .ascii "echo <words>, exit [status] and help are built in; anything else\n"
+    .ascii "runs the program it names, from /bin unless it is a path\n"
+sh_space:
+    .ascii " "
+sh_newline:
+    .ascii "\n"
+.global user_sh_end
+user_sh_end:
+.previous
+"#);
+
+extern "C" {
+    // only their addresses are taken
+    static user_init_start: u8;
+    static user_init_end: u8;
+    static user_sh_start: u8;
+    static user_sh_end: u8;
+}
+
+/// The code of a program, between its start and end symbols.
+unsafe fn code(start: &'static u8, end: &'static u8) -> &'static [u8] {
+    let start = start as *const u8;
+    core::slice::from_raw_parts(start, end as *const u8 as usize - start as usize)
+}
+
+/// Mounts a tmpfs at `/bin` with the built-in programs in it.
+pub fn install() -> Result<(), &'static str> {
+    fs::mount("/bin", Arc::new(TmpFs::new())).map_err(|_| "mounting /bin failed")?;
+    let programs = unsafe {
+        [("/bin/init", code(&user_init_start, &user_init_end)), ("/bin/sh", code(&user_sh_start, &user_sh_end))]
+    };
+    for &(path, code) in programs.iter() {
+        let executable = elf::build(aslr::USER_IMAGE_WINDOW.start + 0x1000, code);
+        fs::write_file(path, &executable).map_err(|_| "writing a program failed")?;
+    }
+    log_info!("programs: {} installed in /bin", programs.len());
+    Ok(())
+}
+initcall!(Late, "programs", install, needs: &["fs"]);
@@ -0,0 +1,130 @@
+//! Resource limits of processes, and what they use of each resource.
+//!
+//! A process gets the default limits when it is spawned, which the `ulimit`
+//! command changes, and those of its parent when it is forked. Going over a
+//! limit makes what needs more fail: `exec` and `mmap` with `ENOMEM`, `open`
+//! with `EMFILE`, a copy-on-write fault by ending the process like an invalid
+//! access. A process over its CPU time is ended at the next timer interrupt
+//! that finds it in user mode.
+
+use core::time::Duration;
+use spin::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resource {
+    /// Frames only the process holds: its private pages and page tables.
+    Frames,
+    /// User pages mapped, the shared ones of mapped files included.
+    Pages,
+    /// Open descriptors.
+    Files,
+    /// CPU time of its thread, in milliseconds.
+    CpuTime,
+}
+
+impl Resource {
+    pub const ALL: [Resource; 4] = [Resource::Frames, Resource::Pages, Resource::Files, Resource::CpuTime];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Resource::Frames => "frames",
+            Resource::Pages => "pages",
+            Resource::Files => "files",
+            Resource::CpuTime => "cpu",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Resource> {
+        Resource::ALL.iter().copied().find(|resource| resource.name() == name)
+    }
+}
+
+/// A limit that is no limit.
+pub const UNLIMITED: u64 = u64::MAX;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    pub frames: u64,
+    pub pages: u64,
+    pub files: u64,
+    /// In milliseconds.
+    pub cpu_time: u64,
+}
+
+impl Limits {
+    pub const DEFAULT: Limits = Limits {
+        // 16 MiB
+        frames: 4096,
+        // 64 MiB
+        pages: 16384,
+        files: super::fd::MAX_FILES as u64,
+        cpu_time: UNLIMITED,
+    };
+
+    pub fn get(&self, resource: Resource) -> u64 {
+        match resource {
+            Resource::Frames => self.frames,
+            Resource::Pages => self.pages,
+            Resource::Files => self.files,
+            Resource::CpuTime => self.cpu_time,
+        }
+    }
+
+    pub fn set(&mut self, resource: Resource, limit: u64) {
+        match resource {
+            Resource::Frames => self.frames = limit,
+            Resource::Pages => self.pages = limit,
+            Resource::Files => self.files = limit,
+            Resource::CpuTime => self.cpu_time = limit,
+        }
+    }
+
+    /// Whether `cpu_time` is over the limit of CPU time.
+    pub fn cpu_time_exceeded(&self, cpu_time: Duration) -> bool {
+        self.cpu_time != UNLIMITED && cpu_time.as_millis() > u128::from(self.cpu_time)
+    }
+}
+
+/// What a process uses of each resource.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Usage {
+    pub frames: u64,
+    pub pages: u64,
+    pub files: u64,
+    /// In milliseconds.
+    pub cpu_time: u64,
+}
+
+impl Usage {
+    pub fn get(&self, resource: Resource) -> u64 {
+        match resource {
+            Resource::Frames => self.frames,
+            Resource::Pages => self.pages,
+            Resource::Files => self.files,
+            Resource::CpuTime => self.cpu_time,
+        }
+    }
+}
+
+static DEFAULTS: Mutex<Limits> = Mutex::new(Limits::DEFAULT);
+
+/// The limits of the processes spawned from now on.
+pub fn defaults() -> Limits {
+    *DEFAULTS.lock()
+}
+
+pub fn set_default(resource: Resource, limit: u64) {
+    DEFAULTS.lock().set(resource, limit);
+}
+
+#[test_case]
+fn test_limits() {
+    let mut limits = Limits::DEFAULT;
+    for (value, &resource) in Resource::ALL.iter().enumerate() {
+        assert_eq!(Resource::from_name(resource.name()), Some(resource));
+        limits.set(resource, value as u64);
+        assert_eq!(limits.get(resource), value as u64);
+    }
+    assert!(limits.cpu_time_exceeded(Duration::from_millis(4)));
+    assert!(!Limits::DEFAULT.cpu_time_exceeded(Duration::from_secs(1_000_000)));
+}
@@ -0,0 +1,758 @@
+//! The system call interface: `int 0x80` with the call number in `rax` and
+//! the arguments in `rdi`, `rsi`, `rdx`, `r10`, `r8` and `r9`. The result is
+//! returned in `rax`, negative values being errors (`-errno`).
+
+use core::arch::global_asm;
+use core::convert::TryFrom;
+use crate::arch::interrupts;
+use crate::arch::x86_64::protection;
+use alloc::sync::Arc;
+use alloc::vec;
+use spin::Mutex;
+use x86_64::VirtAddr;
+use crate::fs::FsError;
+use crate::ipc::{self, IpcError};
+use crate::process::{self, fd, Pid};
+use crate::shm::{self, ShmError};
+use crate::sched::tls::{self, Slot};
+use crate::signal::{Action, Signal};
+use crate::{sched, signal};
+
+/// Interrupt vector of system calls.
+pub const VECTOR: u8 = 0x80;
+
+pub const EXIT: u64 = 0;
+pub const GETPID: u64 = 1;
+pub const FORK: u64 = 2;
+pub const EXEC: u64 = 3;
+pub const WAIT: u64 = 4;
+pub const OPEN: u64 = 5;
+pub const CLOSE: u64 = 6;
+pub const READ: u64 = 7;
+pub const WRITE: u64 = 8;
+pub const LSEEK: u64 = 9;
+pub const DUP: u64 = 10;
+pub const PIPE: u64 = 11;
+pub const MMAP: u64 = 12;
+pub const MUNMAP: u64 = 13;
+pub const WAITPID: u64 = 14;
+pub const SLEEP: u64 = 15;
+pub const MOUNT: u64 = 16;
+pub const BRK: u64 = 17;
+pub const PORT_CREATE: u64 = 18;
+pub const PORT_DESTROY: u64 = 19;
+pub const SEND: u64 = 20;
+pub const RECEIVE: u64 = 21;
+pub const SHM_CREATE: u64 = 22;
+pub const SHM_MAP: u64 = 23;
+pub const SHM_UNLINK: u64 = 24;
+pub const FUTEX_WAIT: u64 = 25;
+pub const FUTEX_WAKE: u64 = 26;
+pub const KILL: u64 = 27;
+pub const SIGACTION: u64 = 28;
+pub const SIGRETURN: u64 = 29;
+
+/// `PORT_CREATE` flag: only the owner may send to the port.
+pub const PORT_PRIVATE: u64 = 1;
+/// `SHM_MAP` flag: the mapping is writable.
+pub const SHM_WRITE: u64 = 1;
+/// `SIGACTION` handlers: the default action and ignoring the signal.
+pub const SIG_DFL: u64 = 0;
+pub const SIG_IGN: u64 = 1;
+
+pub const EPERM: i64 = 1;
+pub const ENOENT: i64 = 2;
+pub const ESRCH: i64 = 3;
+pub const EIO: i64 = 5;
+pub const ENOEXEC: i64 = 8;
+pub const EBADF: i64 = 9;
+pub const ECHILD: i64 = 10;
+pub const EAGAIN: i64 = 11;
+pub const ENOMEM: i64 = 12;
+pub const EFAULT: i64 = 14;
+pub const EEXIST: i64 = 17;
+pub const ENOTDIR: i64 = 20;
+pub const EISDIR: i64 = 21;
+pub const EINVAL: i64 = 22;
+pub const EMFILE: i64 = 24;
+pub const EPIPE: i64 = 32;
+pub const ENOSYS: i64 = 38;
+pub const ENOTEMPTY: i64 = 39;
+pub const EMSGSIZE: i64 = 90;
+
+/// Most bytes a `read` or `write` moves at once, through a kernel buffer.
+const IO_CHUNK: u64 = 64 * 1024;
+/// The bytes below the stack pointer user code may use without moving it,
+/// which a signal handler's frame leaves alone.
+const RED_ZONE: u64 = 128;
+/// The flags a signal handler may change in the registers it returns to:
+/// CF, PF, AF, ZF, SF, TF, DF, OF, RF and AC.
+const USER_FLAGS: u64 = 0x4_0dd5;
+
+/// The error number a VFS error is reported as.
+pub fn errno(error: FsError) -> i64 {
+    match error {
+        FsError::NotFound => ENOENT,
+        FsError::NotADirectory => ENOTDIR,
+        FsError::IsADirectory => EISDIR,
+        FsError::AlreadyExists => EEXIST,
+        FsError::InvalidPath | FsError::Unsupported => EINVAL,
+        FsError::BrokenPipe => EPIPE,
+        FsError::Io | FsError::Corrupted => EIO,
+        FsError::NotEmpty => ENOTEMPTY,
+        FsError::OutOfMemory => ENOMEM,
+    }
+}
+
+/// The error number an IPC error is reported as.
+pub fn ipc_errno(error: IpcError) -> i64 {
+    match error {
+        // destroyed while waiting on it is the same as gone
+        IpcError::NotFound | IpcError::Closed => ENOENT,
+        IpcError::AlreadyExists => EEXIST,
+        IpcError::PermissionDenied => EPERM,
+        IpcError::TooLong => EMSGSIZE,
+        IpcError::Full => EAGAIN,
+    }
+}
+
+/// The error number a shared memory error is reported as.
+pub fn shm_errno(error: ShmError) -> i64 {
+    match error {
+        ShmError::NotFound => ENOENT,
+        ShmError::AlreadyExists => EEXIST,
+        ShmError::InvalidSize => EINVAL,
+        ShmError::OutOfMemory | ShmError::NoAddressSpace => ENOMEM,
+    }
+}
+
+/// The user registers saved on the kernel stack while the kernel runs on
+/// behalf of a process, restored by `iretq` when it returns.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Registers {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    // pushed by the CPU
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl Registers {
+    /// Registers to start running user code at `entry` with the stack at `stack_top`.
+    pub fn user(entry: VirtAddr, stack_top: VirtAddr) -> Registers {
+        let (code, data) = crate::arch::x86_64::gdt::user_selectors();
+        Registers {
+            rip: entry.as_u64(),
+            cs: u64::from(code.0),
+            // interrupts enabled
+            rflags: 0x202,
+            rsp: stack_top.as_u64(),
+            ss: u64::from(data.0),
+            ..Registers::default()
+        }
+    }
+}
+
+/// What a user-mode signal handler finds on the stack above its return
+/// address, the trampoline's, for `SIGRETURN` to continue with.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct SignalFrame {
+    registers: Registers,
+    blocked: u64,
+}
+
+impl SignalFrame {
+    const SIZE: u64 = core::mem::size_of::<SignalFrame>() as u64;
+
+    fn as_bytes(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self as *const SignalFrame as *const u8, Self::SIZE as usize) }
+    }
+
+    fn as_bytes_mut(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self as *mut SignalFrame as *mut u8, Self::SIZE as usize) }
+    }
+}
+
+global_asm!(r#"
+// the system call gate: saves the registers in a `Registers` on the kernel
+// stack, calls `process_syscall` with it and returns to user mode
+.global process_syscall_entry
+process_syscall_entry:
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+    mov rdi, rsp
+    call process_syscall
+    jmp process_return
+
+// loads the `Registers` at rdi and returns to user mode with them
+.global process_enter_user
+process_enter_user:
+    mov rsp, rdi
+process_return:
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+    iretq
+"#);
+
+extern "C" {
+    pub fn process_syscall_entry();
+    fn process_enter_user(registers: *const Registers) -> !;
+}
+
+/// Continues in user mode with `registers`, leaving whatever is on the kernel
+/// stack behind.
+pub fn enter_user(registers: Registers) -> ! {
+    interrupts::disable();
+    unsafe { process_enter_user(&registers) }
+}
+
+/// The error number of the last system call of the calling thread that
+/// failed, or zero.
+pub fn last_error() -> i64 {
+    Slot::SYSCALL_ERROR.get() as i64
+}
+
+#[no_mangle]
+extern "C" fn process_syscall(registers: &mut Registers) {
+    // user mode may have set the access flag with `popf`, and the gate keeps
+    // it: clear it before anything else, or SMAP protects nothing
+    protection::forbid_user_access();
+    #[cfg(test)]
+    record_user_access();
+    tls::restore();
+    interrupts::enable();
+    let call = process::trace::start(registers);
+    let result = dispatch(registers);
+    if let Some(call) = call {
+        call.finish(result);
+    }
+    let result = result.unwrap_or_else(|error| {
+        Slot::SYSCALL_ERROR.set(error as u64);
+        -error
+    });
+    registers.rax = result as u64;
+    sched::check_stack();
+    // handled before returning to user mode, where they can't be
+    signal::deliver();
+    enter_signal_handler(registers);
+    interrupts::disable();
+}
+
+/// Makes the system call return into the user-mode handler of the next
+/// signal pending on the process, if any: saves `registers` in a
+/// `SignalFrame` on the user stack, under the trampoline's address for the
+/// handler to return to, and calls the handler with the signal's number. A
+/// stack without room for the frame ends the process like SIGSEGV.
+fn enter_signal_handler(registers: &mut Registers) {
+    let next = match signal::next_user_signal() {
+        Some(next) => next,
+        None => return,
+    };
+    let frame = SignalFrame { registers: *registers, blocked: u64::from(next.blocked) };
+    // aligned like the stack after a call
+    let frame_addr = registers.rsp.wrapping_sub(RED_ZONE + SignalFrame::SIZE) & !0xf;
+    let rsp = frame_addr.wrapping_sub(8);
+    if process::copy_to_user(frame_addr, frame.as_bytes()).is_err()
+        || process::copy_to_user(rsp, &next.trampoline.to_le_bytes()).is_err() {
+        process::exit(128 + 11);
+    }
+    registers.rip = next.handler;
+    registers.rsp = rsp;
+    registers.rdi = next.signal as u64;
+}
+
+/// Continues with the `SignalFrame` at the stack pointer of `registers`, as a
+/// signal handler returned to its trampoline. Only the flags user code may
+/// change are taken from it, and a frame that would return anywhere but to
+/// user mode ends the process like SIGSEGV.
+fn return_from_signal_handler(registers: &mut Registers) -> i64 {
+    let mut frame = SignalFrame::default();
+    if process::copy_from_user(frame.as_bytes_mut(), registers.rsp).is_err()
+        || frame.registers.rip >= 0x0000_8000_0000_0000 {
+        process::exit(128 + 11);
+    }
+    let (cs, ss, rflags) = (registers.cs, registers.ss, registers.rflags);
+    *registers = frame.registers;
+    registers.cs = cs;
+    registers.ss = ss;
+    registers.rflags = (rflags & !USER_FLAGS) | (frame.registers.rflags & USER_FLAGS);
+    signal::set_blocked(frame.blocked as u32);
+    // what the interrupted system call returned, kept by the caller
+    registers.rax as i64
+}
+
+fn dispatch(registers: &mut Registers) -> Result<i64, i64> {
+    let [arg0, arg1, arg2, arg3] = [registers.rdi, registers.rsi, registers.rdx, registers.r10];
+    match registers.rax {
+        EXIT => process::exit(arg0 as i64),
+        GETPID => Ok(process::current().ok_or(EINVAL)?.pid.0 as i64),
+        FORK => Ok(process::fork(registers)?.0 as i64),
+        EXEC => {
+            // the program's path, then its arguments, each followed by a NUL
+            let path = process::user_str(arg0, arg1)?;
+            let mut args = vec![0; arg3.min(process::ARG_MAX + 1) as usize];
+            process::copy_from_user(&mut args, arg2)?;
+            process::exec(&path, &args, registers)?;
+            Ok(0)
+        }
+        WAIT => process::wait(Pid(arg0)),
+        OPEN => {
+            let file = fd::open(&process::user_str(arg0, arg1)?, arg2)?;
+            let fd = process::files(|files| files.insert(Arc::new(Mutex::new(file))))?;
+            Ok(fd as i64)
+        }
+        CLOSE => process::files(|files| files.remove(arg0)).map(|_| 0),
+        READ => {
+            let file = process::files(|files| files.get(arg0))?;
+            let mut buf = vec![0; arg2.min(IO_CHUNK) as usize];
+            // fails before consuming anything if it could not be copied out
+            process::check_user_range(arg1, buf.len() as u64, true)?;
+            let len = fd::read(&file, &mut buf)?;
+            process::copy_to_user(arg1, &buf[..len])?;
+            Ok(len as i64)
+        }
+        WRITE => {
+            let file = process::files(|files| files.get(arg0))?;
+            let mut buf = vec![0; arg2.min(IO_CHUNK) as usize];
+            process::copy_from_user(&mut buf, arg1)?;
+            Ok(fd::write(&file, &buf)? as i64)
+        }
+        LSEEK => {
+            let file = process::files(|files| files.get(arg0))?;
+            Ok(fd::seek(&file, arg1 as i64, arg2)? as i64)
+        }
+        DUP => Ok(process::files(|files| files.dup(arg0))? as i64),
+        PIPE => {
+            // two 32-bit descriptors, the reading end first
+            process::check_user_range(arg0, 8, true)?;
+            let (reader, writer) = fd::pipe()?;
+            let mut fds = [0; 8];
+            fds[..4].copy_from_slice(&(reader as u32).to_le_bytes());
+            fds[4..].copy_from_slice(&(writer as u32).to_le_bytes());
+            process::copy_to_user(arg0, &fds)?;
+            Ok(0)
+        }
+        MMAP => {
+            // fd, offset, length; read-only for now
+            let file = process::files(|files| files.get(arg0))?;
+            Ok(process::map_file(&file, arg1, arg2)? as i64)
+        }
+        MUNMAP => process::unmap(arg0, arg1).map(|_| 0),
+        WAITPID => {
+            // pid or -1 for any child, where to store the 64-bit status or 0,
+            // options; returns the child's id, 0 if WNOHANG found none
+            let pid = match arg0 as i64 {
+                -1 => None,
+                pid if pid > 0 => Some(Pid(pid as u64)),
+                _ => return Err(EINVAL),
+            };
+            if arg1 != 0 {
+                process::check_user_range(arg1, 8, true)?;
+            }
+            match process::waitpid(pid, arg2)? {
+                Some((pid, status)) => {
+                    if arg1 != 0 {
+                        process::copy_to_user(arg1, &status.to_le_bytes())?;
+                    }
+                    Ok(pid.0 as i64)
+                }
+                None => Ok(0),
+            }
+        }
+        SLEEP => {
+            // milliseconds
+            sched::sleep_ms(arg0);
+            Ok(0)
+        }
+        MOUNT => {
+            // the block device's name, then the mount point
+            let device = process::user_str(arg0, arg1)?;
+            let path = process::user_str(arg2, arg3)?;
+            crate::fs::mount_device(&device, &path).map_err(errno)?;
+            Ok(0)
+        }
+        // the new end of the heap, or 0 to get it
+        BRK => Ok(process::brk(arg0)? as i64),
+        PORT_CREATE => {
+            // the port's name, then `PORT_PRIVATE` or 0 to let anyone send
+            let access = match arg2 {
+                0 => ipc::Access::Anyone,
+                PORT_PRIVATE => ipc::Access::Only(vec::Vec::new()),
+                _ => return Err(EINVAL),
+            };
+            ipc::create(&process::user_str(arg0, arg1)?, access).map_err(ipc_errno)?;
+            Ok(0)
+        }
+        PORT_DESTROY => {
+            ipc::destroy(&process::user_str(arg0, arg1)?).map_err(ipc_errno)?;
+            Ok(0)
+        }
+        SEND => {
+            // the port's name, then the message; fails with EAGAIN if the port is full
+            if arg3 > ipc::MESSAGE_SIZE as u64 {
+                return Err(EMSGSIZE);
+            }
+            let name = process::user_str(arg0, arg1)?;
+            let mut message = [0; ipc::MESSAGE_SIZE];
+            process::copy_from_user(&mut message[..arg3 as usize], arg2)?;
+            ipc::send(&name, &message[..arg3 as usize]).map_err(ipc_errno)?;
+            Ok(0)
+        }
+        RECEIVE => {
+            // the port's name, then a buffer with room for any message;
+            // waits for one and returns its length
+            if arg3 < ipc::MESSAGE_SIZE as u64 {
+                return Err(EINVAL);
+            }
+            let name = process::user_str(arg0, arg1)?;
+            // fails before taking a message if it could not be copied out
+            process::check_user_range(arg2, ipc::MESSAGE_SIZE as u64, true)?;
+            let message = ipc::receive(&name).map_err(ipc_errno)?;
+            process::copy_to_user(arg2, message.data())?;
+            Ok(message.data().len() as i64)
+        }
+        SHM_CREATE => {
+            // the object's name, then its size in bytes
+            shm::create(&process::user_str(arg0, arg1)?, arg2 as usize).map_err(shm_errno)?;
+            Ok(0)
+        }
+        SHM_MAP => {
+            // the object's name, then `SHM_WRITE` or 0 to map it read-only
+            let protection = match arg2 {
+                0 => shm::Protection::ReadOnly,
+                SHM_WRITE => shm::Protection::ReadWrite,
+                _ => return Err(EINVAL),
+            };
+            Ok(process::map_shm(&process::user_str(arg0, arg1)?, protection)? as i64)
+        }
+        SHM_UNLINK => {
+            shm::unlink(&process::user_str(arg0, arg1)?).map_err(shm_errno)?;
+            Ok(0)
+        }
+        FUTEX_WAIT => {
+            // the word's address, then the value to wait while it holds
+            process::futex_wait(arg0, arg1 as u32)?;
+            Ok(0)
+        }
+        // the word's address, then the most threads to wake
+        FUTEX_WAKE => Ok(process::futex_wake(arg0, arg1)? as i64),
+        KILL => {
+            let signal = u32::try_from(arg1).ok().and_then(Signal::from_number).ok_or(EINVAL)?;
+            process::kill(Pid(arg0), signal)?;
+            Ok(0)
+        }
+        SIGACTION => {
+            // the signal, then `SIG_DFL`, `SIG_IGN` or the handler's address,
+            // and the trampoline it returns to, which makes `SIGRETURN`
+            let signal = u32::try_from(arg0).ok().and_then(Signal::from_number).ok_or(EINVAL)?;
+            let action = match arg1 {
+                SIG_DFL => Action::Default,
+                SIG_IGN => Action::Ignore,
+                // `iretq` faults in the kernel on non-canonical addresses
+                handler if handler.max(arg2) >= 0x0000_8000_0000_0000 => return Err(EINVAL),
+                handler => Action::User { handler, trampoline: arg2 },
+            };
+            signal::set_action(signal, action).map_err(|_| EINVAL)?;
+            Ok(0)
+        }
+        SIGRETURN => Ok(return_from_signal_handler(registers)),
+        _ => Err(ENOSYS),
+    }
+}
+
+/// Set if a system call ever ran with user accesses allowed.
+#[cfg(test)]
+static USER_ACCESS_SEEN: core::sync::atomic::AtomicBool = core::sync::atomic::AtomicBool::new(false);
+
+#[cfg(test)]
+fn record_user_access() {
+    use x86_64::registers::rflags::{self, RFlags};
+    if protection::smap_enabled() && rflags::read().contains(RFlags::ALIGNMENT_CHECK) {
+        USER_ACCESS_SEEN.store(true, core::sync::atomic::Ordering::Relaxed);
+    }
+}
+
+#[test_case]
+fn test_syscall_clears_user_access() {
+    use core::sync::atomic::Ordering;
+
+    // set the access flag, then call GETPID and EXIT with it still set
+    let code: &[u8] = &[
+        0x9c, // pushfq
+        0x81, 0x0c, 0x24, 0x00, 0x00, 0x04, 0x00, // or dword [rsp], 0x40000
+        0x9d, // popfq
+        0xb8, 0x01, 0x00, 0x00, 0x00, // mov eax, GETPID
+        0xcd, 0x80, // int 0x80
+        0x31, 0xff, // xor edi, edi
+        0x31, 0xc0, // xor eax, eax (EXIT)
+        0xcd, 0x80, // int 0x80
+    ];
+    process::mount_test_program("/test-smap", code);
+    let pid = process::spawn("/test-smap").unwrap();
+    assert_eq!(process::wait(pid), Ok(0));
+    assert!(!USER_ACCESS_SEEN.load(Ordering::Relaxed));
+}
+
+#[test_case]
+fn test_ipc_syscalls() {
+    // send "ping" to the kernel's port, then "pi" to a private port of its own
+    // and exit with the length received from it
+    let mut code = vec![
+        0x48, 0x8d, 0x3d, 0x8e, 0x00, 0x00, 0x00, // lea rdi, [rip + kernel_port]
+        0xbe, 0x0f, 0x00, 0x00, 0x00, // mov esi, 15
+        0x48, 0x8d, 0x15, 0x95, 0x00, 0x00, 0x00, // lea rdx, [rip + ping]
+        0x41, 0xba, 0x04, 0x00, 0x00, 0x00, // mov r10d, 4
+        0xb8, 0x14, 0x00, 0x00, 0x00, // mov eax, SEND
+        0xcd, 0x80, // int 0x80
+        0x48, 0x8d, 0x3d, 0x7d, 0x00, 0x00, 0x00, // lea rdi, [rip + user_port]
+        0xbe, 0x04, 0x00, 0x00, 0x00, // mov esi, 4
+        0xba, 0x01, 0x00, 0x00, 0x00, // mov edx, PORT_PRIVATE
+        0xb8, 0x12, 0x00, 0x00, 0x00, // mov eax, PORT_CREATE
+        0xcd, 0x80, // int 0x80
+        0x48, 0x8d, 0x3d, 0x65, 0x00, 0x00, 0x00, // lea rdi, [rip + user_port]
+        0xbe, 0x04, 0x00, 0x00, 0x00, // mov esi, 4
+        0x48, 0x8d, 0x15, 0x5d, 0x00, 0x00, 0x00, // lea rdx, [rip + ping]
+        0x41, 0xba, 0x02, 0x00, 0x00, 0x00, // mov r10d, 2
+        0xb8, 0x14, 0x00, 0x00, 0x00, // mov eax, SEND
+        0xcd, 0x80, // int 0x80
+        0x48, 0x83, 0xec, 0x40, // sub rsp, 64
+        0x48, 0x8d, 0x3d, 0x41, 0x00, 0x00, 0x00, // lea rdi, [rip + user_port]
+        0xbe, 0x04, 0x00, 0x00, 0x00, // mov esi, 4
+        0x48, 0x89, 0xe2, // mov rdx, rsp
+        0x41, 0xba, 0x40, 0x00, 0x00, 0x00, // mov r10d, 64
+        0xb8, 0x15, 0x00, 0x00, 0x00, // mov eax, RECEIVE
+        0xcd, 0x80, // int 0x80
+        0x48, 0x89, 0xc3, // mov rbx, rax
+        0x48, 0x8d, 0x3d, 0x22, 0x00, 0x00, 0x00, // lea rdi, [rip + user_port]
+        0xbe, 0x04, 0x00, 0x00, 0x00, // mov esi, 4
+        0xb8, 0x13, 0x00, 0x00, 0x00, // mov eax, PORT_DESTROY
+        0xcd, 0x80, // int 0x80
+        0x48, 0x89, 0xdf, // mov rdi, rbx
+        0x31, 0xc0, // xor eax, eax (EXIT)
+        0xcd, 0x80, // int 0x80
+    ];
+    code.extend_from_slice(b"test-ipc-kernel");
+    code.extend_from_slice(b"mine");
+    code.extend_from_slice(b"ping");
+    ipc::create("test-ipc-kernel", ipc::Access::Anyone).unwrap();
+    process::mount_test_program("/test-ipc", &code);
+    let pid = process::spawn("/test-ipc").unwrap();
+    assert_eq!(process::wait(pid), Ok(2));
+    assert_eq!(ipc::receive("test-ipc-kernel").unwrap().data(), b"ping");
+    assert_eq!(ipc::try_receive("mine"), Err(IpcError::NotFound));
+    ipc::destroy("test-ipc-kernel").unwrap();
+}
+
+#[test_case]
+fn test_shm_and_futex_syscalls() {
+    use core::sync::atomic::{AtomicU32, Ordering};
+    use crate::futex;
+
+    // create and unlink an object of its own, then map the kernel's, store 1
+    // in it and wake the kernel, wait while it holds 1 and exit with it, or
+    // exit with 1 if a call fails
+    let mut code = vec![
+        0x48, 0x8d, 0x3d, 0x84, 0x00, 0x00, 0x00, // lea rdi, [rip + user_object]
+        0xbe, 0x0d, 0x00, 0x00, 0x00, // mov esi, 13
+        0xba, 0x01, 0x00, 0x00, 0x00, // mov edx, 1
+        0xb8, 0x16, 0x00, 0x00, 0x00, // mov eax, SHM_CREATE
+        0xcd, 0x80, // int 0x80
+        0x48, 0x85, 0xc0, // test rax, rax
+        0x75, 0x5d, // jnz fail
+        0x48, 0x8d, 0x3d, 0x67, 0x00, 0x00, 0x00, // lea rdi, [rip + user_object]
+        0xbe, 0x0d, 0x00, 0x00, 0x00, // mov esi, 13
+        0xb8, 0x18, 0x00, 0x00, 0x00, // mov eax, SHM_UNLINK
+        0xcd, 0x80, // int 0x80
+        0x48, 0x8d, 0x3d, 0x4c, 0x00, 0x00, 0x00, // lea rdi, [rip + object]
+        0xbe, 0x08, 0x00, 0x00, 0x00, // mov esi, 8
+        0xba, 0x01, 0x00, 0x00, 0x00, // mov edx, SHM_WRITE
+        0xb8, 0x17, 0x00, 0x00, 0x00, // mov eax, SHM_MAP
+        0xcd, 0x80, // int 0x80
+        0x48, 0x85, 0xc0, // test rax, rax
+        0x78, 0x2d, // js fail
+        0x48, 0x89, 0xc3, // mov rbx, rax
+        0xc7, 0x03, 0x01, 0x00, 0x00, 0x00, // mov dword [rbx], 1
+        0x48, 0x89, 0xdf, // mov rdi, rbx
+        0xbe, 0x01, 0x00, 0x00, 0x00, // mov esi, 1
+        0xb8, 0x1a, 0x00, 0x00, 0x00, // mov eax, FUTEX_WAKE
+        0xcd, 0x80, // int 0x80
+        0x48, 0x89, 0xdf, // mov rdi, rbx
+        0xbe, 0x01, 0x00, 0x00, 0x00, // mov esi, 1
+        0xb8, 0x19, 0x00, 0x00, 0x00, // mov eax, FUTEX_WAIT
+        0xcd, 0x80, // int 0x80
+        0x8b, 0x3b, // mov edi, dword [rbx]
+        0x31, 0xc0, // xor eax, eax (EXIT)
+        0xcd, 0x80, // int 0x80
+        // fail:
+        0xbf, 0x01, 0x00, 0x00, 0x00, // mov edi, 1
+        0x31, 0xc0, // xor eax, eax (EXIT)
+        0xcd, 0x80, // int 0x80
+    ];
+    code.extend_from_slice(b"test-shm");
+    code.extend_from_slice(b"test-shm-user");
+    shm::create("test-shm", 4).unwrap();
+    let mapping = shm::map("test-shm", shm::Protection::ReadWrite).unwrap();
+    process::mount_test_program("/test-shm", &code);
+    let pid = process::spawn("/test-shm").unwrap();
+
+    let word = unsafe { &*(mapping.as_ptr() as *const AtomicU32) };
+    futex::wait(word, 0).unwrap();
+    assert_eq!(word.load(Ordering::SeqCst), 1);
+    word.store(2, Ordering::SeqCst);
+    futex::wake(word, 1).unwrap();
+    // the process's mapping keeps the frames once the object is gone
+    shm::unlink("test-shm").unwrap();
+    drop(mapping);
+    assert_eq!(process::wait(pid), Ok(2));
+    assert!(shm::map("test-shm-user", shm::Protection::ReadOnly).is_err());
+}
+
+#[test_case]
+fn test_signal_syscalls() {
+    // handle SIGUSR1 and ignore SIGUSR2, send both to itself and exit with
+    // what the handler stored, or with 1 if KILL's result didn't survive it
+    let handler: &[u8] = &[
+        0xbf, 0x0a, 0x00, 0x00, 0x00, // mov edi, SIGUSR1
+        0x48, 0x8d, 0x35, 0x63, 0x00, 0x00, 0x00, // lea rsi, [rip + handler]
+        0x48, 0x8d, 0x15, 0x60, 0x00, 0x00, 0x00, // lea rdx, [rip + trampoline]
+        0xb8, 0x1c, 0x00, 0x00, 0x00, // mov eax, SIGACTION
+        0xcd, 0x80, // int 0x80
+        0xbf, 0x0c, 0x00, 0x00, 0x00, // mov edi, SIGUSR2
+        0xbe, 0x01, 0x00, 0x00, 0x00, // mov esi, SIG_IGN
+        0x31, 0xd2, // xor edx, edx
+        0xb8, 0x1c, 0x00, 0x00, 0x00, // mov eax, SIGACTION
+        0xcd, 0x80, // int 0x80
+        0x6a, 0x00, // push 0
+        0x48, 0x89, 0xe3, // mov rbx, rsp
+        0xb8, 0x01, 0x00, 0x00, 0x00, // mov eax, GETPID
+        0xcd, 0x80, // int 0x80
+        0x49, 0x89, 0xc4, // mov r12, rax
+        0x4c, 0x89, 0xe7, // mov rdi, r12
+        0xbe, 0x0c, 0x00, 0x00, 0x00, // mov esi, SIGUSR2
+        0xb8, 0x1b, 0x00, 0x00, 0x00, // mov eax, KILL
+        0xcd, 0x80, // int 0x80
+        0x4c, 0x89, 0xe7, // mov rdi, r12
+        0xbe, 0x0a, 0x00, 0x00, 0x00, // mov esi, SIGUSR1
+        0xb8, 0x1b, 0x00, 0x00, 0x00, // mov eax, KILL
+        0xcd, 0x80, // int 0x80
+        0x48, 0x85, 0xc0, // test rax, rax
+        0x75, 0x07, // jnz fail
+        0x48, 0x8b, 0x3b, // mov rdi, [rbx]
+        0x31, 0xc0, // xor eax, eax (EXIT)
+        0xcd, 0x80, // int 0x80
+        // fail:
+        0xbf, 0x01, 0x00, 0x00, 0x00, // mov edi, 1
+        0x31, 0xc0, // xor eax, eax (EXIT)
+        0xcd, 0x80, // int 0x80
+        // handler, with the registers of the interrupted code but rdi:
+        0x48, 0x89, 0x3b, // mov [rbx], rdi
+        0xc3, // ret
+        // trampoline:
+        0xb8, 0x1d, 0x00, 0x00, 0x00, // mov eax, SIGRETURN
+        0xcd, 0x80, // int 0x80
+    ];
+    // fork a child that sleeps forever, terminate it and exit with its status
+    let terminate: &[u8] = &[
+        0xb8, 0x02, 0x00, 0x00, 0x00, // mov eax, FORK
+        0xcd, 0x80, // int 0x80
+        0x48, 0x85, 0xc0, // test rax, rax
+        0x75, 0x0e, // jnz parent
+        // child:
+        0xbf, 0xe8, 0x03, 0x00, 0x00, // mov edi, 1000
+        0xb8, 0x0f, 0x00, 0x00, 0x00, // mov eax, SLEEP
+        0xcd, 0x80, // int 0x80
+        0xeb, 0xf2, // jmp child
+        // parent:
+        0x48, 0x89, 0xc3, // mov rbx, rax
+        0x48, 0x89, 0xc7, // mov rdi, rax
+        0xbe, 0x0f, 0x00, 0x00, 0x00, // mov esi, SIGTERM
+        0xb8, 0x1b, 0x00, 0x00, 0x00, // mov eax, KILL
+        0xcd, 0x80, // int 0x80
+        0x48, 0x89, 0xdf, // mov rdi, rbx
+        0xb8, 0x04, 0x00, 0x00, 0x00, // mov eax, WAIT
+        0xcd, 0x80, // int 0x80
+        0x48, 0x89, 0xc7, // mov rdi, rax
+        0x31, 0xc0, // xor eax, eax (EXIT)
+        0xcd, 0x80, // int 0x80
+    ];
+    process::mount_test_program("/test-signal", handler);
+    process::mount_test_program("/test-kill", terminate);
+    let pid = process::spawn("/test-signal").unwrap();
+    assert_eq!(process::wait(pid), Ok(Signal::User1 as i64));
+    let pid = process::spawn("/test-kill").unwrap();
+    assert_eq!(process::wait(pid), Ok(128 + Signal::Terminate as i64));
+    assert_eq!(process::kill(pid, Signal::Terminate), Err(ESRCH));
+}
+
+#[test_case]
+fn test_exec_passes_arguments() {
+    // exit with the first byte of the arguments plus their length
+    let args: &[u8] = &[
+        0x0f, 0xb6, 0x3f, // movzx edi, byte [rdi]
+        0x48, 0x01, 0xf7, // add rdi, rsi
+        0x31, 0xc0, // xor eax, eax (EXIT)
+        0xcd, 0x80, // int 0x80
+    ];
+    // exec the program above with "a" and "bc", or exit with 1 if that fails
+    let mut exec = vec![
+        0x48, 0x8d, 0x3d, 0x22, 0x00, 0x00, 0x00, // lea rdi, [rip + path]
+        0xbe, 0x0a, 0x00, 0x00, 0x00, // mov esi, 10
+        0x48, 0x8d, 0x15, 0x20, 0x00, 0x00, 0x00, // lea rdx, [rip + args]
+        0x41, 0xba, 0x05, 0x00, 0x00, 0x00, // mov r10d, 5
+        0xb8, 0x03, 0x00, 0x00, 0x00, // mov eax, EXEC
+        0xcd, 0x80, // int 0x80
+        0xbf, 0x01, 0x00, 0x00, 0x00, // mov edi, 1
+        0x31, 0xc0, // xor eax, eax (EXIT)
+        0xcd, 0x80, // int 0x80
+    ];
+    exec.extend_from_slice(b"/test-args");
+    exec.extend_from_slice(b"a\0bc\0");
+    process::mount_test_program("/test-args", args);
+    process::mount_test_program("/test-exec-args", &exec);
+    let pid = process::spawn("/test-exec-args").unwrap();
+    assert_eq!(process::wait(pid), Ok(i64::from(b'a') + 5));
+}
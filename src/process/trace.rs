@@ -0,0 +1,263 @@
+//! Tracing of system calls, like strace: each call of a traced process is
+//! written with its decoded arguments and result to the kernel log or a file.
+//! The `strace` command turns it on and off. Children forked by a traced
+//! process are traced too, to the same place.
+
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use core::convert::TryFrom;
+use core::fmt::Write;
+use spin::Mutex;
+use crate::fs::{self, FsError};
+use super::syscall::{self, Registers};
+use super::Pid;
+
+/// Most bytes of a buffer shown in a trace.
+const DATA_SHOWN: usize = 32;
+
+/// Where the trace of a process goes.
+#[derive(Clone)]
+pub enum Target {
+    /// The kernel log, at info level.
+    Log,
+    /// A file, written from its start.
+    File(Arc<Mutex<fs::File>>),
+}
+
+impl Target {
+    /// A trace to the file at `path`, created or emptied.
+    pub fn file(path: &str) -> Result<Target, FsError> {
+        fs::write_file(path, b"")?;
+        Ok(Target::File(Arc::new(Mutex::new(fs::File::open(path)?))))
+    }
+}
+
+/// Traces the system calls of the process `pid` to `target`, or stops with `None`.
+pub fn set(pid: Pid, target: Option<Target>) -> Result<(), &'static str> {
+    let process = super::list().into_iter()
+        .find(|process| process.pid == pid)
+        .ok_or("no such process")?;
+    if process.status().is_some() {
+        return Err("the process exited");
+    }
+    *process.trace.lock() = target;
+    Ok(())
+}
+
+/// A system call of a traced process, written out with its result by `finish`.
+pub struct Call {
+    target: Target,
+    pid: Pid,
+    number: u64,
+    args: [u64; 4],
+    text: String,
+}
+
+/// Describes the system call in `registers` if the calling process is traced.
+/// `exit` is written out right away, as it doesn't return.
+pub fn start(registers: &Registers) -> Option<Call> {
+    let process = super::current()?;
+    let target = process.trace.lock().clone()?;
+    let args = [registers.rdi, registers.rsi, registers.rdx, registers.r10];
+    let call = Call { target, pid: process.pid, number: registers.rax, args, text: describe(registers.rax, args) };
+    if call.number == syscall::EXIT {
+        call.write("?");
+        return None;
+    }
+    Some(call)
+}
+
+impl Call {
+    pub fn finish(self, result: Result<i64, i64>) {
+        let result = match result {
+            Ok(len) if self.number == syscall::READ && len > 0 => {
+                format!("{} {}", len, user_data(self.args[1], len as u64))
+            }
+            Ok(len) if self.number == syscall::RECEIVE => format!("{} {}", len, user_data(self.args[2], len as u64)),
+            Ok(addr) if [syscall::MMAP, syscall::BRK, syscall::SHM_MAP].contains(&self.number) => {
+                format!("{:#x}", addr)
+            }
+            Ok(value) => value.to_string(),
+            Err(error) => format!("-1 {} ({})", errno_name(error), error),
+        };
+        self.write(&result);
+    }
+
+    fn write(&self, result: &str) {
+        match &self.target {
+            Target::Log => crate::log_info!("trace: [pid {}] {} = {}", self.pid.0, self.text, result),
+            Target::File(file) => {
+                let line = format!("[pid {}] {} = {}\n", self.pid.0, self.text, result);
+                // a full or failing file loses the line rather than the call
+                let _ = file.lock().write(line.as_bytes());
+            }
+        }
+    }
+}
+
+/// `name(arguments)` of the system call `number`.
+fn describe(number: u64, args: [u64; 4]) -> String {
+    let [arg0, arg1, arg2, arg3] = args;
+    match number {
+        syscall::EXIT => format!("exit({})", arg0 as i64),
+        syscall::GETPID => String::from("getpid()"),
+        syscall::FORK => String::from("fork()"),
+        syscall::EXEC => format!("exec({}, {}, {})", user_string(arg0, arg1), user_data(arg2, arg3), arg3),
+        syscall::WAIT => format!("wait({})", arg0),
+        syscall::OPEN => format!("open({}, {})", user_string(arg0, arg1), open_flags(arg2)),
+        syscall::CLOSE => format!("close({})", arg0),
+        syscall::READ => format!("read({}, {:#x}, {})", arg0, arg1, arg2),
+        syscall::WRITE => format!("write({}, {}, {})", arg0, user_data(arg1, arg2), arg2),
+        syscall::LSEEK => {
+            let whence = match arg2 {
+                super::fd::SEEK_SET => String::from("SEEK_SET"),
+                super::fd::SEEK_CUR => String::from("SEEK_CUR"),
+                super::fd::SEEK_END => String::from("SEEK_END"),
+                whence => whence.to_string(),
+            };
+            format!("lseek({}, {}, {})", arg0, arg1 as i64, whence)
+        }
+        syscall::DUP => format!("dup({})", arg0),
+        syscall::PIPE => format!("pipe({:#x})", arg0),
+        syscall::MMAP => format!("mmap({}, {:#x}, {})", arg0, arg1, arg2),
+        syscall::MUNMAP => format!("munmap({:#x}, {})", arg0, arg1),
+        syscall::WAITPID => {
+            let options = if arg2 == super::WNOHANG { String::from("WNOHANG") } else { arg2.to_string() };
+            format!("waitpid({}, {:#x}, {})", arg0 as i64, arg1, options)
+        }
+        syscall::SLEEP => format!("sleep({})", arg0),
+        syscall::MOUNT => format!("mount({}, {})", user_string(arg0, arg1), user_string(arg2, arg3)),
+        syscall::BRK => format!("brk({:#x})", arg0),
+        syscall::PORT_CREATE => {
+            let flags = if arg2 == syscall::PORT_PRIVATE { String::from("PORT_PRIVATE") } else { arg2.to_string() };
+            format!("port_create({}, {})", user_string(arg0, arg1), flags)
+        }
+        syscall::PORT_DESTROY => format!("port_destroy({})", user_string(arg0, arg1)),
+        syscall::SEND => format!("send({}, {}, {})", user_string(arg0, arg1), user_data(arg2, arg3), arg3),
+        syscall::RECEIVE => format!("receive({}, {:#x}, {})", user_string(arg0, arg1), arg2, arg3),
+        syscall::SHM_CREATE => format!("shm_create({}, {})", user_string(arg0, arg1), arg2),
+        syscall::SHM_MAP => {
+            let flags = if arg2 == syscall::SHM_WRITE { String::from("SHM_WRITE") } else { arg2.to_string() };
+            format!("shm_map({}, {})", user_string(arg0, arg1), flags)
+        }
+        syscall::SHM_UNLINK => format!("shm_unlink({})", user_string(arg0, arg1)),
+        syscall::FUTEX_WAIT => format!("futex_wait({:#x}, {})", arg0, arg1 as u32),
+        syscall::FUTEX_WAKE => format!("futex_wake({:#x}, {})", arg0, arg1),
+        syscall::KILL => format!("kill({}, {})", arg0, signal_name(arg1)),
+        syscall::SIGACTION => {
+            let handler = match arg1 {
+                syscall::SIG_DFL => String::from("SIG_DFL"),
+                syscall::SIG_IGN => String::from("SIG_IGN"),
+                handler => format!("{:#x}", handler),
+            };
+            format!("sigaction({}, {}, {:#x})", signal_name(arg0), handler, arg2)
+        }
+        syscall::SIGRETURN => String::from("sigreturn()"),
+        number => format!("syscall_{}({:#x}, {:#x}, {:#x}, {:#x})", number, arg0, arg1, arg2, arg3),
+    }
+}
+
+fn signal_name(number: u64) -> String {
+    match u32::try_from(number).ok().and_then(crate::signal::Signal::from_number) {
+        Some(signal) => String::from(signal.name()),
+        None => number.to_string(),
+    }
+}
+
+fn open_flags(flags: u64) -> String {
+    let mut names = String::new();
+    for &(flag, name) in [(super::fd::O_CREAT, "O_CREAT"), (super::fd::O_TRUNC, "O_TRUNC")].iter() {
+        if flags & flag != 0 {
+            if !names.is_empty() {
+                names.push('|');
+            }
+            names.push_str(name);
+        }
+    }
+    let rest = flags & !(super::fd::O_CREAT | super::fd::O_TRUNC);
+    match (names.is_empty(), rest) {
+        (true, 0) => String::from("0"),
+        (true, rest) => format!("{:#o}", rest),
+        (false, 0) => names,
+        (false, rest) => format!("{}|{:#o}", names, rest),
+    }
+}
+
+/// The string of `len` bytes at `addr` in user memory, quoted, or its address
+/// if it can't be read.
+fn user_string(addr: u64, len: u64) -> String {
+    match super::user_str(addr, len) {
+        Ok(string) => quote(string.as_bytes(), false),
+        Err(_) => format!("{:#x}", addr),
+    }
+}
+
+/// The first bytes of the `len` at `addr` in user memory, quoted, or their
+/// address if they can't be read.
+fn user_data(addr: u64, len: u64) -> String {
+    let mut buf = alloc::vec![0; len.min(DATA_SHOWN as u64) as usize];
+    match super::copy_from_user(&mut buf, addr) {
+        Ok(()) => quote(&buf, len > DATA_SHOWN as u64),
+        Err(_) => format!("{:#x}", addr),
+    }
+}
+
+fn quote(bytes: &[u8], truncated: bool) -> String {
+    let mut quoted = String::from("\"");
+    for &byte in bytes {
+        match byte {
+            b'\n' => quoted.push_str("\\n"),
+            b'\t' => quoted.push_str("\\t"),
+            b'"' => quoted.push_str("\\\""),
+            b'\\' => quoted.push_str("\\\\"),
+            0x20..=0x7e => quoted.push(char::from(byte)),
+            _ => {
+                let _ = write!(quoted, "\\x{:02x}", byte);
+            }
+        }
+    }
+    quoted.push('"');
+    if truncated {
+        quoted.push_str("...");
+    }
+    quoted
+}
+
+fn errno_name(errno: i64) -> &'static str {
+    match errno {
+        syscall::EPERM => "EPERM",
+        syscall::ENOENT => "ENOENT",
+        syscall::ESRCH => "ESRCH",
+        syscall::EIO => "EIO",
+        syscall::ENOEXEC => "ENOEXEC",
+        syscall::EBADF => "EBADF",
+        syscall::ECHILD => "ECHILD",
+        syscall::EAGAIN => "EAGAIN",
+        syscall::ENOMEM => "ENOMEM",
+        syscall::EFAULT => "EFAULT",
+        syscall::EEXIST => "EEXIST",
+        syscall::ENOTDIR => "ENOTDIR",
+        syscall::EISDIR => "EISDIR",
+        syscall::EINVAL => "EINVAL",
+        syscall::EMFILE => "EMFILE",
+        syscall::EPIPE => "EPIPE",
+        syscall::ENOSYS => "ENOSYS",
+        syscall::ENOTEMPTY => "ENOTEMPTY",
+        syscall::EMSGSIZE => "EMSGSIZE",
+        _ => "E?",
+    }
+}
+
+#[test_case]
+fn test_describe() {
+    assert_eq!(describe(syscall::CLOSE, [3, 0, 0, 0]), "close(3)");
+    assert_eq!(describe(syscall::BRK, [0x1000, 0, 0, 0]), "brk(0x1000)");
+    assert_eq!(describe(syscall::KILL, [2, 15, 0, 0]), "kill(2, SIGTERM)");
+    assert_eq!(describe(99, [1, 2, 3, 4]), "syscall_99(0x1, 0x2, 0x3, 0x4)");
+    assert_eq!(open_flags(super::fd::O_CREAT | super::fd::O_TRUNC), "O_CREAT|O_TRUNC");
+    assert_eq!(open_flags(0), "0");
+    assert_eq!(open_flags(0o2), "0o2");
+    assert_eq!(quote(b"a\"b\n\x01", true), "\"a\\\"b\\n\\x01\"...");
+    assert_eq!(errno_name(syscall::ENOENT), "ENOENT");
+}
@@ -0,0 +1,154 @@
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::fmt;
+use spin::Mutex;
+use crate::arch::interrupts::without_interrupts;
+use crate::{serial_println, symbols};
+
+/// The running profile, if any. Samples are pushed from the timer interrupt.
+static PROFILER: Mutex<Option<Profile>> = Mutex::new(None);
+
+/// Where samples are counted: the function they fell in, or the address
+/// itself when it resolves to no symbol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Location {
+    Symbol(&'static str),
+    Address(u64),
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Location::Symbol(name) => f.write_str(name),
+            Location::Address(addr) => write!(f, "{:#018x}", addr),
+        }
+    }
+}
+
+/// A set of instruction pointer samples taken on timer ticks.
+pub struct Profile {
+    samples: Vec<u64>,
+    dropped: usize,
+}
+
+impl Profile {
+    fn with_capacity(capacity: usize) -> Self {
+        Profile { samples: Vec::with_capacity(capacity), dropped: 0 }
+    }
+
+    /// Records one sample without allocating, dropping it if the buffer is full.
+    fn record(&mut self, rip: u64) {
+        if self.samples.len() < self.samples.capacity() {
+            self.samples.push(rip);
+        } else {
+            self.dropped += 1;
+        }
+    }
+
+    pub fn samples(&self) -> &[u64] {
+        &self.samples
+    }
+
+    /// Number of samples lost because the buffer was full.
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    /// Returns `(location, hits)` pairs sorted by descending hit count, with
+    /// the samples counted by the symbol they resolve to.
+    pub fn flat_profile(&self) -> Vec<(Location, usize)> {
+        self.flat_profile_by(|rip| symbols::resolve(rip).map(|symbol| symbol.name))
+    }
+
+    fn flat_profile_by(&self, resolve: impl Fn(u64) -> Option<&'static str>) -> Vec<(Location, usize)> {
+        let mut hits: BTreeMap<Location, usize> = BTreeMap::new();
+        for &rip in &self.samples {
+            let location = resolve(rip).map_or(Location::Address(rip), Location::Symbol);
+            *hits.entry(location).or_insert(0) += 1;
+        }
+        let mut profile: Vec<(Location, usize)> = hits.into_iter().collect();
+        profile.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        profile
+    }
+
+    /// Prints the `top` hottest functions over serial. Without a symbol table,
+    /// see `symbols`, addresses are printed raw; resolve them on the host with
+    /// `addr2line -f -e target/x86_64-MarOS/debug/MarOS`.
+    pub fn dump(&self, top: usize) {
+        let total = self.samples.len();
+        serial_println!("flat profile: {} samples ({} dropped)", total, self.dropped);
+        serial_println!("    hits       %  location");
+        for (location, hits) in self.flat_profile().into_iter().take(top) {
+            let tenths = hits * 1000 / total;
+            serial_println!("{:>8} {:>5}.{}  {}", hits, tenths / 10, tenths % 10, location);
+        }
+    }
+}
+
+/// Starts sampling, keeping at most `capacity` samples. Restarts if already running.
+pub fn start(capacity: usize) {
+    let profile = Profile::with_capacity(capacity);
+    without_interrupts(|| *PROFILER.lock() = Some(profile));
+}
+
+/// Stops sampling and returns the collected profile.
+pub fn stop() -> Option<Profile> {
+    without_interrupts(|| PROFILER.lock().take())
+}
+
+pub fn is_running() -> bool {
+    without_interrupts(|| PROFILER.lock().is_some())
+}
+
+/// Prints the current profile over serial without stopping it.
+pub fn dump(top: usize) {
+    without_interrupts(|| match PROFILER.lock().as_ref() {
+        Some(profile) => profile.dump(top),
+        None => {
+            serial_println!("profiler is not running");
+        }
+    });
+}
+
+/// Called from the timer interrupt with the interrupted instruction pointer.
+pub fn record_sample(rip: u64) {
+    // never spin in interrupt context: if the profile is being read, skip the tick
+    if let Some(mut profiler) = PROFILER.try_lock() {
+        if let Some(profile) = profiler.as_mut() {
+            profile.record(rip);
+        }
+    }
+}
+
+#[test_case]
+fn test_flat_profile_is_sorted_by_hits() {
+    let mut profile = Profile::with_capacity(8);
+    for &rip in &[0x30, 0x10, 0x20, 0x10, 0x30, 0x10, 0x40, 0x30, 0x50] {
+        profile.record(rip);
+    }
+    assert_eq!(profile.dropped(), 1);
+    assert_eq!(profile.flat_profile_by(|_| None), [
+        (Location::Address(0x10), 3),
+        (Location::Address(0x30), 3),
+        (Location::Address(0x20), 1),
+        (Location::Address(0x40), 1),
+    ]);
+}
+
+#[test_case]
+fn test_flat_profile_groups_by_symbol() {
+    let mut profile = Profile::with_capacity(8);
+    for &rip in &[0x10, 0x18, 0x30, 0x14, 0x38, 0x50] {
+        profile.record(rip);
+    }
+    let resolve = |rip| match rip {
+        0x10..=0x1f => Some("memcpy"),
+        0x30..=0x3f => Some("schedule"),
+        _ => None,
+    };
+    assert_eq!(profile.flat_profile_by(resolve), [
+        (Location::Symbol("memcpy"), 3),
+        (Location::Symbol("schedule"), 2),
+        (Location::Address(0x50), 1),
+    ]);
+}
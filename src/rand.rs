@@ -0,0 +1,267 @@
+use core::arch::asm;
+use core::arch::x86_64::{__cpuid, __cpuid_count, _rdtsc};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use lazy_static::lazy_static;
+use spin::{Mutex, Once};
+use crate::arch::interrupts::without_interrupts;
+use crate::workqueue;
+
+/// Number of 64-bit words in the interrupt timing entropy pool.
+const POOL_WORDS: usize = 4;
+/// Samples mixed into the pool between reseeds of the generator, a few
+/// seconds' worth of timer ticks.
+const RESEED_SAMPLES: usize = 4096;
+
+const POOL_INIT: AtomicU64 = AtomicU64::new(0);
+static POOL: [AtomicU64; POOL_WORDS] = [POOL_INIT; POOL_WORDS];
+static POOL_INDEX: AtomicUsize = AtomicUsize::new(0);
+/// Samples mixed into the pool so far.
+static SAMPLES: AtomicUsize = AtomicUsize::new(0);
+static SOURCE: Once<Source> = Once::new();
+
+/// A device handing out random bytes without waiting, like virtio-rng: fills
+/// the start of the buffer and returns how many bytes it wrote.
+pub type Source = fn(&mut [u8]) -> usize;
+
+lazy_static! {
+    static ref FEATURES: HardwareFeatures = HardwareFeatures::detect();
+    static ref RNG: Mutex<ChaCha20Rng> = Mutex::new(ChaCha20Rng::from_seed(gather_seed()));
+}
+
+/// Which hardware random number instructions the CPU supports.
+#[derive(Debug, Clone, Copy)]
+pub struct HardwareFeatures {
+    pub rdrand: bool,
+    pub rdseed: bool,
+}
+
+impl HardwareFeatures {
+    fn detect() -> Self {
+        let leaf1 = unsafe { __cpuid(1) };
+        let max_leaf = unsafe { __cpuid(0) }.eax;
+        let rdseed = max_leaf >= 7 && unsafe { __cpuid_count(7, 0) }.ebx & (1 << 18) != 0;
+        HardwareFeatures {
+            rdrand: leaf1.ecx & (1 << 30) != 0,
+            rdseed,
+        }
+    }
+}
+
+/// Returns the hardware random number features detected via CPUID.
+pub fn hardware_features() -> HardwareFeatures {
+    *FEATURES
+}
+
+/// Returns a random `u64` from the kernel CSPRNG.
+pub fn u64() -> u64 {
+    without_interrupts(|| RNG.lock().next_u64())
+}
+
+/// Fills `buf` with random bytes from the kernel CSPRNG.
+pub fn fill(buf: &mut [u8]) {
+    without_interrupts(|| RNG.lock().fill_bytes(buf))
+}
+
+/// Re-keys the kernel CSPRNG with fresh hardware and interrupt timing entropy.
+pub fn reseed() {
+    let fresh = gather_seed();
+    without_interrupts(|| {
+        let mut rng = RNG.lock();
+        let mut seed = [0u8; 32];
+        rng.fill_bytes(&mut seed);
+        for (s, f) in seed.iter_mut().zip(fresh.iter()) {
+            *s ^= f;
+        }
+        *rng = ChaCha20Rng::from_seed(seed);
+    });
+}
+
+/// Makes `source` contribute to every seed from now on.
+pub fn set_source(source: Source) {
+    SOURCE.call_once(|| source);
+}
+
+/// Mixes `sample` into the entropy pool, and has the generator reseeded from
+/// it every `RESEED_SAMPLES` samples.
+///
+/// Lock-free, so it can be called from interrupt handlers: the reseed takes
+/// the generator's lock, so it is left to the work queue.
+pub fn add_entropy(sample: u64) {
+    let index = POOL_INDEX.fetch_add(1, Ordering::Relaxed) % POOL_WORDS;
+    let mixed = sample.wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    let _ = POOL[index].fetch_update(Ordering::Relaxed, Ordering::Relaxed, |old| {
+        Some(old.rotate_left(17) ^ mixed)
+    });
+    if (SAMPLES.fetch_add(1, Ordering::Relaxed) + 1) % RESEED_SAMPLES == 0 {
+        // before the work queue runs, the next round will do
+        let _ = workqueue::queue(reseed);
+    }
+}
+
+/// Mixes the current time stamp counter into the entropy pool.
+///
+/// Called from the timer and keyboard interrupt handlers, where the exact arrival
+/// time of the interrupt is the source of jitter.
+pub fn add_interrupt_entropy() {
+    add_entropy(unsafe { _rdtsc() });
+}
+
+/// Returns a random word from RDRAND, or `None` if it is unavailable or failed.
+pub fn rdrand() -> Option<u64> {
+    if !FEATURES.rdrand {
+        return None;
+    }
+    retry(|| {
+        let value: u64;
+        let ok: u8;
+        unsafe {
+            asm!("rdrand {}", "setc {}", out(reg) value, out(reg_byte) ok, options(nomem, nostack));
+        }
+        (ok == 1).then(|| value)
+    })
+}
+
+/// Returns a random word from RDSEED, or `None` if it is unavailable or failed.
+pub fn rdseed() -> Option<u64> {
+    if !FEATURES.rdseed {
+        return None;
+    }
+    retry(|| {
+        let value: u64;
+        let ok: u8;
+        unsafe {
+            asm!("rdseed {}", "setc {}", out(reg) value, out(reg_byte) ok, options(nomem, nostack));
+        }
+        (ok == 1).then(|| value)
+    })
+}
+
+/// The hardware instructions may transiently fail, so retry them a few times.
+fn retry(mut f: impl FnMut() -> Option<u64>) -> Option<u64> {
+    (0..10).find_map(|_| f())
+}
+
+/// Builds a 256-bit seed from RDSEED/RDRAND (when present), the entropy pool,
+/// the TSC and the source device, if there is one.
+fn gather_seed() -> [u8; 32] {
+    let mut seed = [0u8; 32];
+    for (i, chunk) in seed.chunks_mut(8).enumerate() {
+        let mut word = POOL[i % POOL_WORDS].load(Ordering::Relaxed) ^ unsafe { _rdtsc() };
+        if let Some(hw) = rdseed().or_else(rdrand) {
+            word ^= hw;
+        }
+        chunk.copy_from_slice(&word.to_le_bytes());
+    }
+    if let Some(source) = SOURCE.get() {
+        let mut bytes = [0u8; 32];
+        let len = source(&mut bytes);
+        seed.iter_mut().zip(&bytes[..len]).for_each(|(s, b)| *s ^= b);
+    }
+    seed
+}
+
+/// A cryptographically secure pseudo random number generator based on ChaCha20.
+pub struct ChaCha20Rng {
+    key: [u32; 8],
+    counter: u64,
+    block: [u32; 16],
+    index: usize,
+}
+
+impl ChaCha20Rng {
+    /// Creates a generator from a 256-bit seed. The same seed always yields the same stream.
+    pub fn from_seed(seed: [u8; 32]) -> Self {
+        let mut key = [0u32; 8];
+        for (word, bytes) in key.iter_mut().zip(seed.chunks(4)) {
+            *word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        ChaCha20Rng { key, counter: 0, block: [0; 16], index: 16 }
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        if self.index == 16 {
+            self.block = chacha20_block(&self.key, self.counter, 0);
+            self.counter = self.counter.wrapping_add(1);
+            self.index = 0;
+        }
+        let value = self.block[self.index];
+        self.index += 1;
+        value
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        (u64::from(self.next_u32()) << 32) | u64::from(self.next_u32())
+    }
+
+    pub fn fill_bytes(&mut self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(4) {
+            let bytes = self.next_u32().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+}
+
+fn quarter_round(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(16);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(12);
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(8);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(7);
+}
+
+/// The ChaCha20 block function with a 64-bit block counter and a 64-bit stream id.
+fn chacha20_block(key: &[u32; 8], counter: u64, stream: u64) -> [u32; 16] {
+    let mut input = [0u32; 16];
+    input[..4].copy_from_slice(&[0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574]);
+    input[4..12].copy_from_slice(key);
+    input[12] = counter as u32;
+    input[13] = (counter >> 32) as u32;
+    input[14] = stream as u32;
+    input[15] = (stream >> 32) as u32;
+
+    let mut state = input;
+    for _ in 0..10 {
+        // column rounds
+        quarter_round(&mut state, 0, 4, 8, 12);
+        quarter_round(&mut state, 1, 5, 9, 13);
+        quarter_round(&mut state, 2, 6, 10, 14);
+        quarter_round(&mut state, 3, 7, 11, 15);
+        // diagonal rounds
+        quarter_round(&mut state, 0, 5, 10, 15);
+        quarter_round(&mut state, 1, 6, 11, 12);
+        quarter_round(&mut state, 2, 7, 8, 13);
+        quarter_round(&mut state, 3, 4, 9, 14);
+    }
+    for (s, i) in state.iter_mut().zip(input.iter()) {
+        *s = s.wrapping_add(*i);
+    }
+    state
+}
+
+#[test_case]
+fn test_chacha20_block_rfc7539() {
+    // test vector from RFC 7539, section 2.3.2
+    let mut key = [0u32; 8];
+    for (i, word) in key.iter_mut().enumerate() {
+        let b = 4 * i as u8;
+        *word = u32::from_le_bytes([b, b + 1, b + 2, b + 3]);
+    }
+    let block = chacha20_block(&key, 0x0900_0000_0000_0001, 0x4a00_0000);
+    assert_eq!(&block[..4], &[0xe4e7_f110, 0x1559_3bd1, 0x1fdd_0f50, 0xc471_20a3]);
+    assert_eq!(block[15], 0x4e3c_50a2);
+}
+
+#[test_case]
+fn test_chacha20_rng_is_deterministic() {
+    let mut a = ChaCha20Rng::from_seed([7; 32]);
+    let mut b = ChaCha20Rng::from_seed([7; 32]);
+    let mut c = ChaCha20Rng::from_seed([8; 32]);
+    for _ in 0..40 {
+        let value = a.next_u64();
+        assert_eq!(value, b.next_u64());
+        assert_ne!(value, c.next_u64());
+    }
+}
@@ -0,0 +1,828 @@
+//! Preemptive kernel threads.
+//!
+//! Every thread has its own stack from `stack`, recycled once it exited, and
+//! its own lazily switched FPU state. Ready threads wait in one run queue per
+//! priority class, and the highest class always runs first. The timer interrupt
+//! switches between the threads of that class in round-robin order. Threads
+//! block on a `WaitQueue` or in `sleep` and use no CPU until they are woken; a
+//! thread woken by an interrupt handler is boosted one class up until it blocks
+//! again or uses up a time slice, so interactive threads stay responsive next
+//! to busy ones. The boot code keeps running as the first thread once `init` is
+//! called. When no thread is ready, the idle thread halts the CPU, and the time
+//! it runs is what `cpu_usage` reports as idle.
+//!
+//! The scheduler state is only locked with interrupts disabled, and nothing on
+//! the interrupt path allocates, so interrupt handlers can wake threads.
+
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::structures::paging::PhysFrame;
+use x86_64::VirtAddr;
+use crate::arch::context::{self, FpuState};
+use crate::arch::{interrupts, paging};
+use crate::signal::{self, Signals};
+use crate::time::{self, Instant};
+use crate::process::Pid;
+use crate::{initcall, log_warn, memory, workqueue};
+
+pub mod stack;
+pub mod tls;
+mod wait_queue;
+
+pub use stack::Stack;
+pub use wait_queue::WaitQueue;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(pub u64);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Running,
+    Ready,
+    /// Waiting on a `WaitQueue` or sleeping.
+    Blocked,
+    /// Finished, waiting for its stack to be freed.
+    Exited,
+}
+
+impl State {
+    pub fn name(self) -> &'static str {
+        match self {
+            State::Running => "running",
+            State::Ready => "ready",
+            State::Blocked => "blocked",
+            State::Exited => "exited",
+        }
+    }
+}
+
+/// Priority classes, most urgent first. A thread only runs when no thread of a
+/// more urgent class is ready.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Realtime,
+    Normal,
+    /// Runs only when nothing else wants the CPU.
+    Idle,
+}
+
+const CLASSES: usize = 3;
+
+impl Priority {
+    pub fn name(self) -> &'static str {
+        match self {
+            Priority::Realtime => "realtime",
+            Priority::Normal => "normal",
+            Priority::Idle => "idle",
+        }
+    }
+
+    /// The class one step more urgent.
+    fn boosted(self) -> Priority {
+        match self {
+            Priority::Realtime | Priority::Normal => Priority::Realtime,
+            Priority::Idle => Priority::Normal,
+        }
+    }
+}
+
+/// Time the CPU spent running threads and halted in the idle thread since `init`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuUsage {
+    pub busy: Duration,
+    pub idle: Duration,
+}
+
+impl CpuUsage {
+    /// Busy time in percent of the total.
+    pub fn busy_percent(&self) -> u64 {
+        let total = (self.busy + self.idle).as_micros();
+        if total == 0 {
+            return 0;
+        }
+        (self.busy.as_micros() * 100 / total) as u64
+    }
+}
+
+/// A snapshot of a thread, for diagnostics like `ps`.
+#[derive(Debug, Clone, Copy)]
+pub struct ThreadInfo {
+    pub id: ThreadId,
+    pub name: &'static str,
+    pub state: State,
+    pub priority: Priority,
+    /// Whether the thread currently runs one class above its priority.
+    pub boosted: bool,
+    pub cpu_time: Duration,
+    /// Bytes of its stack in use, and its size. Both zero for the boot thread,
+    /// whose stack is not the scheduler's.
+    pub stack_used: u64,
+    pub stack_size: u64,
+    /// Most bytes of its stack in use so far.
+    pub stack_peak: u64,
+    /// Bytes the thread allocated on the heap, freed or not.
+    pub heap_allocated: u64,
+}
+
+struct Thread {
+    name: &'static str,
+    state: State,
+    priority: Priority,
+    boosted: bool,
+    /// Saved stack pointer while the thread is not running.
+    rsp: u64,
+    fpu: Box<FpuState>,
+    /// When a sleeping thread becomes ready again.
+    wake_at: Option<Instant>,
+    cpu_time: Duration,
+    signals: Signals,
+    /// The CPU enters at its top on interrupts from user mode. `None` for the
+    /// boot thread, which never runs user code.
+    stack: Option<Stack>,
+    /// Whether its stack usage was logged for crossing `stack::WARN_PERCENT`.
+    stack_warned: bool,
+    /// The level 4 page table the thread runs on.
+    page_table: PhysFrame,
+    /// Where GS points while the thread runs. Also holds its id and process.
+    tls: tls::Block,
+}
+
+impl Thread {
+    fn new(name: &'static str, state: State, priority: Priority, rsp: u64, stack: Option<Stack>) -> Thread {
+        Thread {
+            name,
+            state,
+            priority,
+            boosted: false,
+            rsp,
+            fpu: Box::new(FpuState::new()),
+            wake_at: None,
+            cpu_time: Duration::ZERO,
+            signals: Signals::new(),
+            stack,
+            stack_warned: false,
+            page_table: memory::kernel_page_table(),
+            tls: tls::Block::new(),
+        }
+    }
+
+    /// The class the thread is scheduled in.
+    fn class(&self) -> Priority {
+        if self.boosted { self.priority.boosted() } else { self.priority }
+    }
+}
+
+struct Scheduler {
+    threads: BTreeMap<ThreadId, Box<Thread>>,
+    /// The ready threads of each class, in the order they run. Every queue has
+    /// room for all threads, so interrupt handlers never allocate.
+    queues: [VecDeque<ThreadId>; CLASSES],
+    current: ThreadId,
+    /// Runs when no other thread is ready. Never queued.
+    idle: ThreadId,
+    next_id: u64,
+    /// When the current thread started running, for its CPU time.
+    switched_at: Instant,
+    usage: CpuUsage,
+}
+
+static SCHEDULER: Mutex<Option<Scheduler>> = Mutex::new(None);
+/// One more than the id of a thread whose stack usage is to be logged, or 0.
+static STACK_WARNING: AtomicU64 = AtomicU64::new(0);
+/// Notified whenever a thread exits, for `join`.
+static EXITED: WaitQueue = WaitQueue::new();
+
+/// Runs `f` on the scheduler state with interrupts disabled, or returns `None`
+/// before `init`.
+fn with<R>(f: impl FnOnce(&mut Scheduler) -> R) -> Option<R> {
+    interrupts::without_interrupts(|| SCHEDULER.lock().as_mut().map(f))
+}
+
+/// What `schedule` has to do next.
+enum Switch {
+    Stay,
+    /// Save the stack pointer at the first address, load the second one.
+    To(*mut u64, u64),
+    /// The running thread overwrote the canary of its stack.
+    Overflowed(ThreadId, &'static str),
+}
+
+impl Scheduler {
+    fn new(boot: Thread, mut idle: Thread) -> Scheduler {
+        idle.tls.id = ThreadId(1);
+        let mut threads = BTreeMap::new();
+        threads.insert(ThreadId(0), Box::new(boot));
+        threads.insert(ThreadId(1), Box::new(idle));
+        Scheduler {
+            threads,
+            queues: [VecDeque::with_capacity(2), VecDeque::with_capacity(2), VecDeque::with_capacity(2)],
+            current: ThreadId(0),
+            idle: ThreadId(1),
+            next_id: 2,
+            switched_at: Instant::now(),
+            usage: CpuUsage { busy: Duration::ZERO, idle: Duration::ZERO },
+        }
+    }
+
+    fn current_thread(&mut self) -> &mut Thread {
+        let current = self.current;
+        self.threads.get_mut(&current).unwrap()
+    }
+
+    /// Marks `id` ready and queues it behind the ready threads of its class.
+    fn make_ready(&mut self, id: ThreadId) {
+        let thread = self.threads.get_mut(&id).unwrap();
+        thread.state = State::Ready;
+        thread.wake_at = None;
+        let class = thread.class();
+        self.queues[class as usize].push_back(id);
+    }
+
+    /// The most urgent class with a ready thread.
+    fn ready_class(&self) -> Option<Priority> {
+        [Priority::Realtime, Priority::Normal, Priority::Idle].iter().copied()
+            .find(|&class| !self.queues[class as usize].is_empty())
+    }
+
+    /// Picks the thread to run next and marks it running. A running current
+    /// thread is queued again behind the other ready threads of its class.
+    fn pick_next(&mut self) -> Switch {
+        let id = self.current;
+        let thread = self.current_thread();
+        if !thread.stack.as_ref().map_or(true, Stack::canary_intact) {
+            return Switch::Overflowed(id, thread.name);
+        }
+        let now = Instant::now();
+        let ran = now - self.switched_at;
+        self.switched_at = now;
+        let current = self.current;
+        if current == self.idle {
+            self.usage.idle += ran;
+        } else {
+            self.usage.busy += ran;
+        }
+        let is_idle = current == self.idle;
+        let thread = self.current_thread();
+        thread.cpu_time += ran;
+        if thread.state == State::Running {
+            if is_idle {
+                thread.state = State::Ready;
+            } else {
+                self.make_ready(current);
+            }
+        }
+
+        let next = match self.ready_class() {
+            Some(class) => self.queues[class as usize].pop_front().unwrap(),
+            None => self.idle,
+        };
+        self.threads.get_mut(&next).unwrap().state = State::Running;
+        if next == current {
+            return Switch::Stay;
+        }
+        if current == self.idle {
+            time::restart_tick();
+        }
+        self.current = next;
+        let new = self.threads.get_mut(&next).unwrap();
+        let new_rsp = new.rsp;
+        unsafe { context::switch_to(&mut *new.fpu) };
+        if let Some(stack) = &new.stack {
+            context::set_kernel_stack(VirtAddr::new(stack.top()));
+        }
+        // kernel stacks are mapped in every address space
+        if paging::active_table() != new.page_table {
+            unsafe { paging::switch_table(new.page_table) };
+        }
+        tls::activate(&mut new.tls);
+        let old = self.threads.get_mut(&current).unwrap();
+        if !old.stack_warned && old.stack.as_ref().map_or(false, Stack::above_warning) {
+            // logged by `schedule`, without the scheduler locked
+            old.stack_warned = STACK_WARNING.compare_exchange(0, current.0 + 1, Ordering::Relaxed,
+                Ordering::Relaxed).is_ok();
+        }
+        Switch::To(&mut old.rsp, new_rsp)
+    }
+
+    /// Whether a ready thread should take the CPU from the current one: one of a
+    /// more urgent class, or with `round_robin` also one of the same class.
+    fn should_preempt(&mut self, round_robin: bool) -> bool {
+        if self.current == self.idle {
+            return self.ready_class().is_some();
+        }
+        let class = self.current_thread().class();
+        match self.ready_class() {
+            Some(ready) => ready < class || (round_robin && ready == class),
+            None => false,
+        }
+    }
+
+    /// Blocks the current thread, unless it is `interruptible` and has a signal
+    /// to handle.
+    fn block_current(&mut self, wake_at: Option<Instant>, interruptible: bool) {
+        let thread = self.current_thread();
+        if interruptible && thread.signals.deliverable() {
+            return;
+        }
+        thread.state = State::Blocked;
+        thread.boosted = false;
+        thread.wake_at = wake_at;
+    }
+
+    fn spawn(&mut self, mut thread: Thread) -> ThreadId {
+        let id = ThreadId(self.next_id);
+        self.next_id += 1;
+        thread.tls.id = id;
+        self.threads.insert(id, Box::new(thread));
+        let threads = self.threads.len();
+        for queue in self.queues.iter_mut() {
+            queue.reserve(threads.saturating_sub(queue.len()));
+        }
+        self.make_ready(id);
+        id
+    }
+}
+
+/// Makes the running code the first thread. Must be called after the heap is
+/// initialized.
+///
+/// Panics if the stack of the idle thread can't be mapped.
+pub fn init() {
+    let boot = Thread::new("boot", State::Running, Priority::Normal, 0, None);
+    let (idle_rsp, idle_stack) = new_stack(Box::new(|| idle())).expect("creating the idle thread failed");
+    let idle = Thread::new("idle", State::Ready, Priority::Idle, idle_rsp, Some(idle_stack));
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let scheduler = scheduler.insert(Scheduler::new(boot, idle));
+        let boot = scheduler.current_thread();
+        unsafe { context::switch_to(&mut *boot.fpu) };
+        tls::activate(&mut boot.tls);
+    });
+}
+initcall!(Core, "sched", || {
+    init();
+    Ok(())
+});
+
+/// Body of the idle thread: halts until an interrupt makes a thread ready.
+fn idle() -> ! {
+    loop {
+        // check and halt with interrupts disabled so a wakeup in between is
+        // not left waiting for the next interrupt
+        interrupts::disable();
+        if with(|scheduler| scheduler.ready_class().is_some()) == Some(true) {
+            schedule();
+        } else {
+            // without the tick, only the interrupts that matter wake the CPU
+            time::stop_tick();
+            interrupts::enable_and_hlt();
+        }
+    }
+}
+
+/// The thread that is running, or `None` before `init`.
+pub fn current() -> Option<ThreadId> {
+    tls::current_id()
+}
+
+/// The state of the thread `id`, or `None` if there is no such thread.
+pub fn state(id: ThreadId) -> Option<State> {
+    with(|scheduler| scheduler.threads.get(&id).map(|thread| thread.state)).flatten()
+}
+
+/// Blocks until the thread `id` has exited.
+pub fn join(id: ThreadId) {
+    EXITED.wait_until(|| matches!(state(id), None | Some(State::Exited)));
+}
+
+/// Runs `f` on the signal state of the thread `id`, unless it exited.
+pub(crate) fn with_signals<R>(id: ThreadId, f: impl FnOnce(&mut Signals) -> R) -> Option<R> {
+    with(|scheduler| match scheduler.threads.get_mut(&id) {
+        Some(thread) if thread.state != State::Exited => Some(f(&mut thread.signals)),
+        _ => None,
+    }).flatten()
+}
+
+type Entry = Box<dyn FnOnce() + Send>;
+
+extern "C" fn thread_entry(entry: u64) -> ! {
+    interrupts::enable();
+    let entry = unsafe { Box::from_raw(entry as *mut Entry) };
+    entry();
+    exit()
+}
+
+/// A snapshot of every thread.
+pub fn threads() -> Vec<ThreadInfo> {
+    with(|scheduler| {
+        // charge the running thread up to now
+        let now = Instant::now();
+        let running = now - scheduler.switched_at;
+        let current = scheduler.current;
+        let current_rsp = context::stack_pointer();
+        scheduler.threads.iter()
+            .map(|(&id, thread)| {
+                let rsp = if id == current { current_rsp } else { thread.rsp };
+                let (stack_used, stack_size, stack_peak) = match &thread.stack {
+                    None => (0, 0, 0),
+                    Some(stack) => (stack.top().saturating_sub(rsp), stack::STACK_SIZE, stack.peak()),
+                };
+                ThreadInfo {
+                    id,
+                    name: thread.name,
+                    state: thread.state,
+                    priority: thread.priority,
+                    boosted: thread.boosted,
+                    cpu_time: thread.cpu_time + if id == current { running } else { Duration::ZERO },
+                    stack_used,
+                    stack_size,
+                    stack_peak,
+                    heap_allocated: thread.tls.slot(tls::Slot::HEAP_ALLOCATED),
+                }
+            })
+            .collect()
+    }).unwrap_or_default()
+}
+
+/// Calls `f` with the id, name and state of every thread, and where it resumes
+/// unless it is running, without waiting for the scheduler's lock or
+/// allocating, for crash dumps. Returns false if the scheduler is locked or
+/// not set up.
+pub fn try_for_each_thread(mut f: impl FnMut(ThreadId, &'static str, State, Option<u64>)) -> bool {
+    interrupts::without_interrupts(|| match SCHEDULER.try_lock() {
+        Some(scheduler) => match scheduler.as_ref() {
+            Some(scheduler) => {
+                scheduler.threads.iter().for_each(|(&id, thread)| {
+                    let rip = (id != scheduler.current).then(|| unsafe { context::saved_rip(thread.rsp) });
+                    f(id, thread.name, thread.state, rip)
+                });
+                true
+            }
+            None => false,
+        },
+        None => false,
+    })
+}
+
+/// How the CPU time since `init` was spent.
+pub fn cpu_usage() -> CpuUsage {
+    with(|scheduler| {
+        let mut usage = scheduler.usage;
+        let running = Instant::now() - scheduler.switched_at;
+        if scheduler.current == scheduler.idle {
+            usage.idle += running;
+        } else {
+            usage.busy += running;
+        }
+        usage
+    }).unwrap_or(CpuUsage { busy: Duration::ZERO, idle: Duration::ZERO })
+}
+
+/// Changes the priority class of thread `id`.
+pub fn set_priority(id: ThreadId, priority: Priority) {
+    with(|scheduler| {
+        if let Some(thread) = scheduler.threads.get_mut(&id) {
+            thread.priority = priority;
+            if thread.state == State::Ready {
+                for queue in scheduler.queues.iter_mut() {
+                    queue.retain(|&queued| queued != id);
+                }
+                scheduler.make_ready(id);
+            }
+        }
+    });
+    preempt();
+}
+
+/// Starts a thread running `f` with normal priority.
+pub fn spawn(name: &'static str, f: impl FnOnce() + Send + 'static) -> Result<ThreadId, &'static str> {
+    spawn_with_priority(name, Priority::Normal, f)
+}
+
+/// Starts a thread running `f` in the priority class `priority`.
+pub fn spawn_with_priority(name: &'static str, priority: Priority, f: impl FnOnce() + Send + 'static)
+                           -> Result<ThreadId, &'static str> {
+    reap();
+    let (rsp, stack) = new_stack(Box::new(f))?;
+    let thread = Thread::new(name, State::Blocked, priority, rsp, Some(stack));
+    let id = with(|scheduler| scheduler.spawn(thread)).ok_or("scheduler is not initialized")?;
+    preempt();
+    Ok(id)
+}
+
+/// Starts a thread of the process `pid` running `f` on the page tables in
+/// `page_table`, with normal priority and the signal state `signals`.
+pub(crate) fn spawn_process(name: &'static str, pid: Pid, page_table: PhysFrame, signals: Signals,
+                            f: impl FnOnce() + Send + 'static) -> Result<ThreadId, &'static str> {
+    reap();
+    let (rsp, stack) = new_stack(Box::new(f))?;
+    let mut thread = Thread::new(name, State::Blocked, Priority::Normal, rsp, Some(stack));
+    thread.page_table = page_table;
+    thread.signals = signals;
+    thread.tls.process = Some(pid);
+    let id = with(|scheduler| scheduler.spawn(thread)).ok_or("scheduler is not initialized")?;
+    preempt();
+    Ok(id)
+}
+
+/// The process the calling thread belongs to, if any.
+pub fn current_process() -> Option<Pid> {
+    tls::current_process()
+}
+
+/// Switches the calling thread to the page tables in `page_table`.
+pub fn set_page_table(page_table: PhysFrame) {
+    with(|scheduler| {
+        scheduler.current_thread().page_table = page_table;
+        unsafe { paging::switch_table(page_table) };
+    });
+}
+
+/// Allocates a stack for a new thread running `entry` and returns it with its
+/// initial stack pointer.
+fn new_stack(entry: Entry) -> Result<(u64, Stack), &'static str> {
+    let stack = stack::allocate()?;
+    let entry: *mut Entry = Box::into_raw(Box::new(entry));
+    let rsp = unsafe { context::init_stack(stack.top(), thread_entry, entry as u64) };
+    Ok((rsp, stack))
+}
+
+/// Ends the calling thread.
+pub fn exit() -> ! {
+    interrupts::disable();
+    with(|scheduler| {
+        let thread = scheduler.current_thread();
+        context::release(&mut *thread.fpu);
+        thread.state = State::Exited;
+    });
+    EXITED.notify_all();
+    schedule();
+    unreachable!("exited thread was scheduled again");
+}
+
+/// Drops the exited threads and frees their stacks.
+fn reap() {
+    let exited: Vec<Box<Thread>> = with(|scheduler| {
+        let current = scheduler.current;
+        let ids: Vec<ThreadId> = scheduler.threads.iter()
+            .filter(|&(&id, thread)| thread.state == State::Exited && id != current)
+            .map(|(&id, _)| id)
+            .collect();
+        ids.iter().filter_map(|id| scheduler.threads.remove(id)).collect()
+    }).unwrap_or_default();
+    for thread in exited {
+        if let Some(stack) = thread.stack {
+            stack::free(stack);
+        }
+    }
+}
+
+/// Switches to the next ready thread, or the idle thread if none is ready. The
+/// current thread stays blocked or exited if it was.
+fn schedule() {
+    let enabled = interrupts::are_enabled();
+    interrupts::disable();
+    let switch = SCHEDULER.lock().as_mut().map_or(Switch::Stay, Scheduler::pick_next);
+    match STACK_WARNING.swap(0, Ordering::Relaxed) {
+        0 => {}
+        id => {
+            let _ = workqueue::queue(move || warn_stack_usage(ThreadId(id - 1)));
+        }
+    }
+    match switch {
+        Switch::Stay => {}
+        Switch::To(old_rsp, new_rsp) => unsafe { context::switch(old_rsp, new_rsp) },
+        Switch::Overflowed(id, name) => panic!("thread {} ({}) overflowed its stack", id.0, name),
+    }
+    if enabled {
+        interrupts::enable();
+    }
+}
+
+fn warn_stack_usage(id: ThreadId) {
+    if let Some(thread) = threads().into_iter().find(|thread| thread.id == id) {
+        log_warn!("thread {} ({}) used {} of its {} stack bytes", id.0, thread.name, thread.stack_peak,
+            thread.stack_size);
+    }
+}
+
+/// The CPU time of the thread `id` so far, or `None` if there is no such thread.
+pub fn cpu_time(id: ThreadId) -> Option<Duration> {
+    with(|scheduler| {
+        let running = if id == scheduler.current { Instant::now() - scheduler.switched_at } else { Duration::ZERO };
+        scheduler.threads.get(&id).map(|thread| thread.cpu_time + running)
+    }).flatten()
+}
+
+/// The most bytes of its stack the thread `id` used so far, and the size of
+/// the stack. `None` for the boot thread and threads that exited.
+pub fn stack_usage(id: ThreadId) -> Option<(u64, u64)> {
+    with(|scheduler| {
+        let thread = scheduler.threads.get(&id).filter(|thread| thread.state != State::Exited)?;
+        thread.stack.as_ref().map(|stack| (stack.peak(), stack::STACK_SIZE))
+    }).flatten()
+}
+
+/// Panics if the calling thread overwrote the canary of its stack, see
+/// `stack`. The scheduler checks it at every switch as well.
+pub fn check_stack() {
+    let overflowed = with(|scheduler| {
+        let thread = scheduler.current_thread();
+        match &thread.stack {
+            Some(stack) if !stack.canary_intact() => Some(thread.name),
+            _ => None,
+        }
+    }).flatten();
+    if let Some(name) = overflowed {
+        panic!("thread {} ({}) overflowed its stack", current().map_or(0, |id| id.0), name);
+    }
+}
+
+/// Lets the other ready threads run before the calling one continues.
+pub fn yield_now() {
+    schedule();
+    signal::deliver();
+}
+
+/// Blocks the calling thread until `wake` is called for it. Must be called with
+/// interrupts disabled, after registering the thread where it will be woken.
+///
+/// An `interruptible` thread doesn't block while it has a signal to handle.
+fn block(interruptible: bool) {
+    with(|scheduler| scheduler.block_current(None, interruptible));
+    schedule();
+}
+
+/// Makes the blocked thread `id` ready. Usable from interrupt handlers, where
+/// the thread is boosted one priority class up, as it is by work they deferred.
+pub fn wake(id: ThreadId) {
+    let from_interrupt = crate::arch::interrupts::in_irq() || crate::workqueue::in_worker();
+    with(|scheduler| {
+        if let Some(thread) = scheduler.threads.get_mut(&id) {
+            if thread.state == State::Blocked {
+                thread.boosted = from_interrupt;
+                scheduler.make_ready(id);
+            }
+        }
+    });
+}
+
+/// Blocks the calling thread for at least `duration`.
+///
+/// Threads are woken by a timer, so the delay is rounded up to the next timer
+/// tick. Before `init`, the CPU halts until the time has passed.
+pub fn sleep(duration: Duration) {
+    let deadline = Instant::now() + duration;
+    let timer = current().map(|id| time::add_timer(deadline, wake_sleeper, id.0));
+    let enabled = interrupts::are_enabled();
+    interrupts::disable();
+    while Instant::now() < deadline {
+        let blocked = with(|scheduler| scheduler.block_current(Some(deadline), enabled));
+        match blocked {
+            Some(()) => {
+                schedule();
+                if enabled {
+                    interrupts::enable();
+                    signal::deliver();
+                    interrupts::disable();
+                }
+            }
+            None => {
+                interrupts::enable_and_hlt();
+                interrupts::disable();
+            }
+        }
+    }
+    if let Some(timer) = timer {
+        time::cancel_timer(timer);
+    }
+    if enabled {
+        interrupts::enable();
+    }
+}
+
+/// Timer callback of `sleep`: makes the thread `id` ready if it still sleeps.
+fn wake_sleeper(id: u64) {
+    with(|scheduler| {
+        let id = ThreadId(id);
+        if let Some(thread) = scheduler.threads.get(&id) {
+            if thread.state == State::Blocked && thread.wake_at.is_some() {
+                scheduler.make_ready(id);
+            }
+        }
+    });
+}
+
+pub fn sleep_ms(ms: u64) {
+    sleep(Duration::from_millis(ms));
+}
+
+/// Called from the timer interrupt after the end of interrupt was sent: gives
+/// the next thread of the running class its time slice. Sleepers were already
+/// woken by their timers.
+pub fn tick() {
+    let preempt = match SCHEDULER.try_lock() {
+        Some(mut scheduler) => match scheduler.as_mut() {
+            Some(scheduler) => {
+                // a boost lasts one time slice
+                scheduler.current_thread().boosted = false;
+                scheduler.should_preempt(true)
+            }
+            None => false,
+        },
+        None => false,
+    };
+    if preempt {
+        schedule();
+    }
+}
+
+/// Switches to a ready thread of a more urgent class than the running one, if
+/// there is one. Called at the end of interrupt handlers that may wake threads.
+pub fn preempt() {
+    if with(|scheduler| scheduler.should_preempt(false)) == Some(true) {
+        schedule();
+    }
+}
+
+#[test_case]
+fn test_threads_run_and_wait() {
+    use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    static QUEUE: WaitQueue = WaitQueue::new();
+    static GO: AtomicBool = AtomicBool::new(false);
+    static DONE: AtomicUsize = AtomicUsize::new(0);
+
+    for _ in 0..2 {
+        spawn("test", || {
+            QUEUE.wait_until(|| GO.load(Ordering::SeqCst));
+            DONE.fetch_add(1, Ordering::SeqCst);
+            QUEUE.notify_all();
+        }).unwrap();
+    }
+    yield_now();
+    assert_eq!(DONE.load(Ordering::SeqCst), 0);
+    GO.store(true, Ordering::SeqCst);
+    QUEUE.notify_all();
+    QUEUE.wait_until(|| DONE.load(Ordering::SeqCst) == 2);
+}
+
+#[test_case]
+fn test_priority_classes() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static ORDER: AtomicUsize = AtomicUsize::new(0);
+    static IDLE_RAN_AT: AtomicUsize = AtomicUsize::new(0);
+    static REALTIME_RAN_AT: AtomicUsize = AtomicUsize::new(0);
+
+    spawn_with_priority("test-idle", Priority::Idle, || {
+        IDLE_RAN_AT.store(ORDER.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+    }).unwrap();
+    // starts running right away, before this thread continues
+    spawn_with_priority("test-rt", Priority::Realtime, || {
+        REALTIME_RAN_AT.store(ORDER.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+    }).unwrap();
+    assert_eq!(REALTIME_RAN_AT.load(Ordering::SeqCst), 1);
+    assert_eq!(IDLE_RAN_AT.load(Ordering::SeqCst), 0);
+    // the idle thread only runs once this one blocks
+    sleep_ms(20);
+    assert_eq!(IDLE_RAN_AT.load(Ordering::SeqCst), 2);
+}
+
+#[test_case]
+fn test_sleep_waits() {
+    let start = Instant::now();
+    sleep_ms(60);
+    assert!(start.elapsed() >= Duration::from_millis(60));
+}
+
+#[test_case]
+fn test_stack_usage() {
+    use core::sync::atomic::AtomicBool;
+
+    static QUEUE: WaitQueue = WaitQueue::new();
+    static USED: AtomicBool = AtomicBool::new(false);
+    static DONE: AtomicBool = AtomicBool::new(false);
+
+    let id = spawn("test", || {
+        let mut buffer = [0u8; 4096];
+        for byte in buffer.iter_mut() {
+            unsafe { core::ptr::write_volatile(byte, 1) };
+        }
+        USED.store(true, Ordering::SeqCst);
+        QUEUE.notify_all();
+        QUEUE.wait_until(|| DONE.load(Ordering::SeqCst));
+    }).unwrap();
+    QUEUE.wait_until(|| USED.load(Ordering::SeqCst));
+    let (peak, size) = stack_usage(id).unwrap();
+    assert!(peak > 4096 && peak <= size, "peak {} of {}", peak, size);
+    assert_eq!(stack_usage(current().unwrap()), None, "the boot thread has a stack");
+    DONE.store(true, Ordering::SeqCst);
+    QUEUE.notify_all();
+    join(id);
+}
@@ -0,0 +1,160 @@
+//! Kernel thread stacks: `STACK_SIZE` bytes each, above an unmapped guard
+//! page that turns an overflow into a page fault, placed in
+//! `aslr::KERNEL_STACK_WINDOW`.
+//!
+//! The stacks of exited threads are kept on a free list, still mapped, and
+//! handed out again before new ones are mapped. Beyond `MAX_FREE` of them,
+//! a freed stack is unmapped and its frames go back to the frame allocator.
+//!
+//! `allocate` fills every stack with `PATTERN`, so the deepest the thread
+//! ever got is where the pattern first stops, scanning up from the bottom:
+//! `Stack::peak`. The scheduler checks the word `WARN_PERCENT` deep at every
+//! switch, which is cheap, and logs the first time a thread got past it.
+//!
+//! The lowest word holds `CANARY` instead. A thread that overwrote it ran
+//! off the end of its stack without touching the guard page, e.g. with a
+//! large array it did not write all of; the scheduler checks it at every
+//! switch and system call return and panics.
+
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::structures::paging::{PageTableFlags, PhysFrame};
+use x86_64::VirtAddr;
+use crate::arch::interrupts::without_interrupts;
+use crate::{aslr, memory};
+
+/// Usable bytes of a stack.
+pub const STACK_SIZE: u64 = 4 * 4096;
+/// The unmapped bytes below it.
+pub const GUARD_SIZE: u64 = 4096;
+/// Freed stacks kept mapped for the next threads.
+pub const MAX_FREE: usize = 16;
+/// What an unused stack word holds.
+pub const PATTERN: u64 = 0x5354_4143_4b5f_5354;
+/// Usage in percent of `STACK_SIZE` that is worth a warning.
+pub const WARN_PERCENT: u64 = 80;
+/// What the lowest word of a stack holds as long as it did not overflow.
+pub const CANARY: u64 = 0x4341_4e41_5259_2121;
+
+/// A mapped stack, from `allocate`. Give it back with `free`.
+pub struct Stack {
+    /// Lowest mapped address, right above the guard page.
+    bottom: u64,
+    frames: Vec<PhysFrame>,
+}
+
+impl Stack {
+    /// The address above the highest byte, where the stack pointer starts.
+    pub fn top(&self) -> u64 {
+        self.bottom + STACK_SIZE
+    }
+
+    pub fn bottom(&self) -> u64 {
+        self.bottom
+    }
+
+    /// Most bytes in use at any time since `allocate`, the canary not
+    /// counted as unused.
+    pub fn peak(&self) -> u64 {
+        let words = unsafe { self.words() };
+        let unused = words[1..].iter().take_while(|&&word| word == PATTERN).count() as u64;
+        STACK_SIZE - 8 - unused * 8
+    }
+
+    /// Whether the lowest word still holds `CANARY`.
+    pub fn canary_intact(&self) -> bool {
+        unsafe { core::ptr::read_volatile(self.bottom as *const u64) == CANARY }
+    }
+
+    /// Whether more than `WARN_PERCENT` of the stack was in use at some time.
+    pub fn above_warning(&self) -> bool {
+        let index = (STACK_SIZE - STACK_SIZE * WARN_PERCENT / 100) / 8;
+        unsafe { core::ptr::read_volatile((self.bottom as *const u64).add(index as usize)) != PATTERN }
+    }
+
+    /// The stack as words, which the thread running on it may be changing.
+    unsafe fn words(&self) -> &[u64] {
+        core::slice::from_raw_parts(self.bottom as *const u64, (STACK_SIZE / 8) as usize)
+    }
+
+    fn fill(&mut self) {
+        let words = unsafe { core::slice::from_raw_parts_mut(self.bottom as *mut u64, (STACK_SIZE / 8) as usize) };
+        words.fill(PATTERN);
+        words[0] = CANARY;
+    }
+}
+
+static FREE: Mutex<Vec<Stack>> = Mutex::new(Vec::new());
+
+/// A stack from the free list, or a newly mapped one, filled with `PATTERN`
+/// above `CANARY`.
+pub fn allocate() -> Result<Stack, &'static str> {
+    let mut stack = match without_interrupts(|| FREE.lock().pop()) {
+        Some(stack) => stack,
+        None => map()?,
+    };
+    stack.fill();
+    Ok(stack)
+}
+
+fn map() -> Result<Stack, &'static str> {
+    let base = aslr::random_free_base(aslr::KERNEL_STACK_WINDOW, GUARD_SIZE + STACK_SIZE, 4096)
+        .ok_or("no room for a thread stack")?;
+    let bottom = base + GUARD_SIZE;
+    let frames = memory::allocate_frames((STACK_SIZE / 4096) as usize)?;
+    if let Err(error) = memory::map_frames(VirtAddr::new(bottom), &frames, PageTableFlags::WRITABLE) {
+        memory::unmap_range(VirtAddr::new(bottom)..VirtAddr::new(bottom + STACK_SIZE));
+        unsafe { memory::free_frames(frames) };
+        return Err(error);
+    }
+    Ok(Stack { bottom, frames })
+}
+
+/// Gives `stack` back. No thread may run on it anymore.
+pub fn free(stack: Stack) {
+    let stack = without_interrupts(|| {
+        let mut free = FREE.lock();
+        if free.len() < MAX_FREE {
+            free.push(stack);
+            None
+        } else {
+            Some(stack)
+        }
+    });
+    if let Some(stack) = stack {
+        memory::unmap_range(VirtAddr::new(stack.bottom)..VirtAddr::new(stack.top()));
+        unsafe { memory::free_frames(stack.frames) };
+    }
+}
+
+/// Stacks on the free list.
+pub fn free_count() -> usize {
+    without_interrupts(|| FREE.lock().len())
+}
+
+#[test_case]
+fn test_recycling() {
+    let stack = allocate().unwrap();
+    let bottom = stack.bottom();
+    unsafe { ((stack.top() - 8) as *mut u64).write_volatile(42) };
+    assert!(memory::mapping_of(VirtAddr::new(bottom - 1)).is_none(), "no guard page");
+    assert_eq!(stack.peak(), 8);
+    assert!(!stack.above_warning());
+    unsafe { ((stack.bottom() + 64) as *mut u64).write_volatile(0) };
+    assert_eq!(stack.peak(), STACK_SIZE - 64);
+    assert!(stack.above_warning());
+    assert!(stack.canary_intact());
+    unsafe { (stack.bottom() as *mut u64).write_volatile(0) };
+    assert!(!stack.canary_intact());
+    let count = free_count();
+    free(stack);
+    if count < MAX_FREE {
+        assert_eq!(free_count(), count + 1);
+        // the stack freed last comes back first
+        let stack = allocate().unwrap();
+        assert_eq!(stack.bottom(), bottom);
+        assert_eq!(stack.peak(), 0, "not filled again");
+        assert!(stack.canary_intact());
+        free(stack);
+    }
+}
@@ -0,0 +1,137 @@
+//! Thread-local storage: a block per thread that the GS base points to while
+//! the thread runs, so the running code finds out who it is with one memory
+//! access instead of locking the scheduler.
+//!
+//! The block holds the thread and process ids and a few `Slot`s of scratch
+//! storage for subsystems. User code can load GS and with it a different base,
+//! so interrupt handlers that may run on top of user mode call `restore`
+//! before they use the block. Device interrupts don't use it, and a thread
+//! switch points GS at the block of the next thread anyway.
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::registers::model_specific::GsBase;
+use x86_64::VirtAddr;
+use crate::process::Pid;
+use crate::sched::ThreadId;
+
+/// Number of `Slot`s every thread has.
+const SLOTS: usize = 4;
+
+/// The block of a thread, part of its scheduler state.
+#[repr(C)]
+pub(super) struct Block {
+    /// Address of the block itself, read through `gs:0`.
+    this: u64,
+    pub(super) id: ThreadId,
+    pub(super) process: Option<Pid>,
+    slots: [AtomicU64; SLOTS],
+}
+
+impl Block {
+    pub(super) fn new() -> Block {
+        const ZERO: AtomicU64 = AtomicU64::new(0);
+        Block { this: 0, id: ThreadId(0), process: None, slots: [ZERO; SLOTS] }
+    }
+
+    pub(super) fn slot(&self, slot: Slot) -> u64 {
+        self.slots[slot.0].load(Ordering::Relaxed)
+    }
+}
+
+/// Address of the block of the running thread, zero before `sched::init`.
+/// What the GS base should be, for `restore`.
+static ACTIVE: AtomicU64 = AtomicU64::new(0);
+
+/// Points GS at `block`, whose thread is about to run. The block must not move
+/// while it is active.
+pub(super) fn activate(block: &mut Block) {
+    let address = block as *mut Block as u64;
+    block.this = address;
+    ACTIVE.store(address, Ordering::Relaxed);
+    GsBase::write(VirtAddr::new(address));
+}
+
+/// Points GS at the block of the running thread again, after user code may
+/// have changed it.
+pub fn restore() {
+    let address = ACTIVE.load(Ordering::Relaxed);
+    if address != 0 {
+        GsBase::write(VirtAddr::new(address));
+    }
+}
+
+/// Calls `f` with the block of the running thread, or returns `None` before
+/// `sched::init`.
+fn with_block<R>(f: impl FnOnce(*const Block) -> R) -> Option<R> {
+    if ACTIVE.load(Ordering::Relaxed) == 0 {
+        return None;
+    }
+    let block: u64;
+    unsafe { asm!("mov {}, gs:[0]", out(reg) block, options(nostack, readonly, preserves_flags)) };
+    Some(f(block as *const Block))
+}
+
+/// The running thread, or `None` before `sched::init`.
+pub fn current_id() -> Option<ThreadId> {
+    // the fields other than the slots don't change while the thread runs
+    with_block(|block| unsafe { core::ptr::addr_of!((*block).id).read() })
+}
+
+/// The process the running thread belongs to, if any.
+pub fn current_process() -> Option<Pid> {
+    with_block(|block| unsafe { core::ptr::addr_of!((*block).process).read() }).flatten()
+}
+
+/// A word of storage every thread has its own copy of, zero when the thread
+/// starts. Each user gets a constant here.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Slot(usize);
+
+impl Slot {
+    /// The error number of the last failed system call, see
+    /// `process::syscall::last_error`.
+    pub const SYSCALL_ERROR: Slot = Slot(0);
+    /// Bytes the thread allocated on the heap, freed or not.
+    pub const HEAP_ALLOCATED: Slot = Slot(1);
+    /// Free for tests.
+    #[cfg(test)]
+    const TEST: Slot = Slot(SLOTS - 1);
+
+    /// The value of the running thread, zero before `sched::init`.
+    pub fn get(self) -> u64 {
+        with_block(|block| unsafe { (*block).slots[self.0].load(Ordering::Relaxed) }).unwrap_or(0)
+    }
+
+    /// Sets the value of the running thread. Does nothing before `sched::init`.
+    pub fn set(self, value: u64) {
+        with_block(|block| unsafe { (*block).slots[self.0].store(value, Ordering::Relaxed) });
+    }
+
+    /// Adds `value` to the value of the running thread.
+    pub fn add(self, value: u64) {
+        with_block(|block| unsafe { (*block).slots[self.0].fetch_add(value, Ordering::Relaxed) });
+    }
+}
+
+#[test_case]
+fn test_slots() {
+    use core::sync::atomic::AtomicBool;
+    use crate::sched;
+
+    static OTHER_DONE: AtomicBool = AtomicBool::new(false);
+    let running = sched::threads().iter().find(|thread| thread.state == sched::State::Running).map(|thread| thread.id);
+    assert_eq!(current_id(), running);
+    Slot::TEST.set(7);
+    let other = sched::spawn("test-tls", || {
+        assert_eq!(Slot::TEST.get(), 0);
+        Slot::TEST.set(3);
+        Slot::TEST.add(2);
+        assert_eq!(Slot::TEST.get(), 5);
+        OTHER_DONE.store(true, Ordering::Relaxed);
+    }).unwrap();
+    sched::join(other);
+    assert!(OTHER_DONE.load(Ordering::Relaxed));
+    assert_eq!(Slot::TEST.get(), 7);
+    assert_eq!(current_process(), None);
+}
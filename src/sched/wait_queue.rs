@@ -0,0 +1,74 @@
+use alloc::collections::VecDeque;
+use spin::Mutex;
+use crate::arch::interrupts;
+use crate::sched::{self, ThreadId};
+use crate::signal;
+
+/// Threads waiting for a condition that another thread or an interrupt handler
+/// makes true, and then announces with `notify_one` or `notify_all`.
+pub struct WaitQueue {
+    waiters: Mutex<VecDeque<ThreadId>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> WaitQueue {
+        WaitQueue { waiters: Mutex::new(VecDeque::new()) }
+    }
+
+    /// Blocks the calling thread until `condition` returns true.
+    ///
+    /// The condition is checked with interrupts disabled, so a notification
+    /// can't get lost between the check and blocking. Before `sched::init`,
+    /// the CPU halts between the checks instead. Called with interrupts
+    /// enabled, the thread handles its signals while waiting.
+    pub fn wait_until(&self, mut condition: impl FnMut() -> bool) {
+        let enabled = interrupts::are_enabled();
+        interrupts::disable();
+        while !condition() {
+            match sched::current() {
+                Some(id) => {
+                    self.waiters.lock().push_back(id);
+                    sched::block(enabled);
+                    // woken by something else, like a sleep ending or a signal
+                    self.waiters.lock().retain(|&waiter| waiter != id);
+                    if enabled {
+                        interrupts::enable();
+                        signal::deliver();
+                        interrupts::disable();
+                    }
+                }
+                None => {
+                    interrupts::enable_and_hlt();
+                    interrupts::disable();
+                }
+            }
+        }
+        if enabled {
+            interrupts::enable();
+        }
+    }
+
+    /// Wakes the thread that has waited longest. Returns whether there was one.
+    /// Usable from interrupt handlers.
+    pub fn notify_one(&self) -> bool {
+        let waiter = interrupts::without_interrupts(|| self.waiters.lock().pop_front());
+        match waiter {
+            Some(id) => {
+                sched::wake(id);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Wakes every waiting thread and returns how many there were. Usable from
+    /// interrupt handlers.
+    pub fn notify_all(&self) -> usize {
+        interrupts::without_interrupts(|| {
+            let mut waiters = self.waiters.lock();
+            let count = waiters.len();
+            waiters.drain(..).for_each(sched::wake);
+            count
+        })
+    }
+}
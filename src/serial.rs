@@ -1,22 +1,113 @@
+//! The serial ports, with COM1 as the kernel's debug console.
+//!
+//! Each port is a `Uart16550` behind a lock taken with interrupts disabled.
+//! COM1 is set up with the default configuration on first use; the other
+//! ports with `open`. `init_interrupts` makes sending interrupt driven, so
+//! `serial_print!` no longer waits for the UART.
+
 use core::fmt;
-use uart_16550::SerialPort;
 use spin::Mutex;
-use lazy_static::lazy_static;
-use x86_64::instructions::interrupts::without_interrupts;
-
-lazy_static! {
-    pub static ref SERIAL1: Mutex<SerialPort> = {
-        let mut serial_port = unsafe { SerialPort::new(0x3F8) };
-        serial_port.init();
-        Mutex::new(serial_port)
-    };
+use crate::arch::interrupts::without_interrupts;
+use crate::drivers::uart::{Com, Config, RawWriter, Uart16550};
+use crate::arch::interrupts;
+use crate::initcall;
+
+static PORTS: [Mutex<Uart16550>; 4] = [
+    Mutex::new(Uart16550::new(Com::Com1)),
+    Mutex::new(Uart16550::new(Com::Com2)),
+    Mutex::new(Uart16550::new(Com::Com3)),
+    Mutex::new(Uart16550::new(Com::Com4)),
+];
+
+/// Called with the bytes received on each port, see `set_receiver`.
+static RECEIVERS: Mutex<[Option<fn(u8)>; 4]> = Mutex::new([None; 4]);
+
+/// COM1, where `serial_print!` writes.
+pub static SERIAL1: &Mutex<Uart16550> = &PORTS[0];
+
+/// The port `com`, which must have been set up with `open` to be usable.
+pub fn port(com: Com) -> &'static Mutex<Uart16550> {
+    &PORTS[com.index()]
+}
+
+/// Programs the port `com` with `config`.
+pub fn open(com: Com, config: Config) -> Result<&'static Mutex<Uart16550>, &'static str> {
+    let port = port(com);
+    without_interrupts(|| port.lock().init(config))?;
+    Ok(port)
+}
+
+/// Makes sending interrupt driven on COM1 and on every other port that is set
+/// up. Must be called after the IDT and the PICs are initialized.
+pub fn init_interrupts() -> Result<(), &'static str> {
+    interrupts::register_irq(Com::Com1.irq(), || handle_irq(Com::Com1.irq()))?;
+    interrupts::register_irq(Com::Com2.irq(), || handle_irq(Com::Com2.irq()))?;
+    without_interrupts(|| {
+        let mut serial = SERIAL1.lock();
+        if !serial.is_initialized() {
+            serial.init(Config::default())?;
+        }
+        drop(serial);
+        for port in PORTS.iter() {
+            let mut port = port.lock();
+            if port.is_initialized() {
+                port.enable_interrupts();
+            }
+        }
+        Ok(())
+    })
+}
+initcall!(Early, "serial", init_interrupts, after: &["pic"], required: true);
+
+/// Makes `receiver` get the bytes received on `com`, or drops them. The
+/// receiver is called by the interrupt handler.
+pub fn set_receiver(com: Com, receiver: Option<fn(u8)>) {
+    without_interrupts(|| RECEIVERS.lock()[com.index()] = receiver);
+}
+
+/// Serves the ports on PIC line `irq`, passing received bytes to their
+/// receivers.
+fn handle_irq(irq: u8) {
+    for com in Com::ALL.iter().copied().filter(|com| com.irq() == irq) {
+        let receiver = RECEIVERS.lock()[com.index()];
+        // the interrupted code may hold the lock; it sends the queued bytes then
+        if let Some(mut port) = port(com).try_lock() {
+            port.handle_interrupt(|byte| {
+                if let Some(receiver) = receiver {
+                    receiver(byte);
+                }
+            });
+        }
+    }
+}
+
+/// Sends everything queued on COM1, for before the kernel stops running.
+/// Does nothing if COM1 is locked, as its holder may never release it.
+pub fn flush() {
+    without_interrupts(|| {
+        if let Some(mut serial) = SERIAL1.try_lock() {
+            serial.flush();
+        }
+    });
+}
+
+/// Prints `args` to COM1 without taking any lock, for panics and fatal
+/// exceptions. Bytes still queued on the port may come out after them.
+pub fn print_raw(args: fmt::Arguments) {
+    use core::fmt::Write;
+    let _ = RawWriter(Com::Com1).write_fmt(args);
 }
 
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
     without_interrupts(|| {
-        SERIAL1.lock().write_fmt(args).expect("Printing to serial failed");
+        let mut serial = SERIAL1.lock();
+        if !serial.is_initialized() {
+            // there is nothing to report failures to
+            let _ = serial.init(Config::default());
+        }
+        serial.write_fmt(args).expect("Printing to serial failed");
     });
 }
 
@@ -0,0 +1,709 @@
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use crate::arch::interrupts::without_interrupts;
+use crate::arch::x86_64::ioport;
+use crate::drivers::block;
+use crate::fs::page_cache;
+use crate::task::channel::{channel, Receiver, Sender};
+use crate::vga_buffer::theme::{Theme, THEMES};
+use crate::vga_buffer::WRITER;
+use crate::{editor, eprintln, fs, initcall, memory, print, println, process, sched, signal, task, top};
+
+#[cfg(feature = "net")]
+mod network;
+#[cfg(feature = "net")]
+pub mod remote;
+
+/// Printed before every command line. Stripped from submitted lines.
+pub const PROMPT: &str = "> ";
+
+/// A shell command, called with the whitespace-separated arguments after its name.
+pub type CommandFn = fn(&[&str]);
+
+struct Command {
+    help: &'static str,
+    run: CommandFn,
+}
+
+static COMMANDS: Mutex<BTreeMap<&'static str, Command>> = Mutex::new(BTreeMap::new());
+
+lazy_static! {
+    /// Lines submitted by the keyboard interrupt, waiting to be executed by `run`.
+    static ref LINES: (Sender<String>, Mutex<Receiver<String>>) = {
+        let (sender, receiver) = channel(16);
+        (sender, Mutex::new(receiver))
+    };
+}
+
+/// Registers the built-in commands.
+pub fn init() {
+    // allocate the channel now rather than in the keyboard interrupt
+    lazy_static::initialize(&LINES);
+    register("help", "list the available commands", help);
+    register("vmmap", "vmmap [start end]: show page table mappings (hex addresses)", vmmap);
+    register("edit", "edit <file>: full-screen text editor", edit);
+    register("ps", "list the kernel threads, and the processes with their resource usage and limits", ps);
+    register("top", "full-screen system monitor, q to quit", |_| top::run());
+    register("kill", "kill <id> [signal]: send a signal (default 15, SIGTERM) to a thread", kill);
+    register("run", "run <path>: run a user program and wait for it", run_program);
+    register("strace", "strace <pid> [file|off]|run <path> [file]: trace the system calls of a process and its children to the log or a file, or stop; run starts a program traced", strace);
+    register("kexec", "kexec [load <path>|exec|unload]: load a kernel image built with the multiboot2 feature, or start it without going through the firmware", kexec);
+    register("dmesg", "dmesg [-p]: print the kernel log of this boot, or with -p that of the previous boot, kept in memory across reboots", dmesg);
+    register("crashdump", "crashdump [off|com1|com2|<device>[@sector]|write|show|clear]: show or set where a dump goes when the kernel crashes, write one now, or show or erase the one on disk", crashdump);
+    register("ulimit", "ulimit [<frames|pages|files|cpu> <limit|unlimited>]: show or set the resource limits of the user programs run from now on, cpu in milliseconds", ulimit);
+    register("theme", "theme [name]: list the color themes or switch to one (also Ctrl+Alt+T)", theme);
+    register("bind", "bind [<action> <chord>|none]|[reset]: list the key bindings, or bind an action to a chord like ctrl+alt+t", bind);
+    register("macro", "macro [record|play|delete <name>]: list the keyboard macros, or record (until the record-macro chord), replay or delete one", macros);
+    register("screenshot", "screenshot [ansi] [path]: save the screen's text, or with ansi its colors too, to a file or send it over the serial port (also Ctrl+Alt+S)", screenshot);
+    register("ioports", "list the claimed I/O port ranges and their owners", ioports);
+    register("hz", "hz [rate]: show or set the number of timer interrupts per second", hz);
+    register("date", "show the date and time of the realtime clock", date);
+    register("alarm", "alarm [YYYY-MM-DD] HH:MM[:SS]: print a message when the realtime clock reaches a time, today by default", alarm);
+    #[cfg(feature = "net")]
+    network::register_commands();
+    register("leaks", "leaks on|off|mark|[from [to]]: trace heap allocations and show those made between checkpoints that are still live", leaks);
+    register("mem", "mem [oom on|off]: show memory usage and pressure, or turn the OOM killer on or off", mem);
+    register("slabs", "show the slab caches of kernel objects", slabs);
+    register("mount", "mount [device path]: list the mounted filesystems, or mount the ext2 filesystem on a block device", mount);
+}
+initcall!(Late, "shell", || {
+    init();
+    Ok(())
+});
+
+/// Makes `run` available as the command `name`, replacing any previous command
+/// with the same name.
+pub fn register(name: &'static str, help: &'static str, run: CommandFn) {
+    COMMANDS.lock().insert(name, Command { help, run });
+}
+
+/// Queues a line typed by the user, or sent by a remote session, for execution.
+///
+/// Called from the keyboard interrupt handler, so it never runs the command itself.
+/// Lines typed while too many are waiting are dropped.
+pub fn submit(line: String) {
+    let _ = LINES.0.try_send(line);
+}
+
+/// Parses and runs a single command line.
+pub fn execute(line: &str) {
+    let line = line.trim();
+    let line = line.strip_prefix(PROMPT.trim_end()).unwrap_or(line);
+    let mut words = line.split_whitespace();
+    let name = match words.next() {
+        Some(name) => name,
+        None => return,
+    };
+    let args: Vec<&str> = words.collect();
+
+    // copy the function out so commands can use the registry themselves
+    let run = COMMANDS.lock().get(name).map(|command| command.run);
+    match run {
+        Some(run) => run(&args),
+        None => eprintln!("unknown command: {} (try `help`)", name),
+    }
+}
+
+/// Runs submitted command lines forever, running the spawned tasks while
+/// waiting for them.
+pub fn run() -> ! {
+    let mut lines = LINES.1.lock();
+    loop {
+        // user space prints its own prompt while it has the console
+        if !crate::console::user_input() {
+            print!("{}", PROMPT);
+        }
+        if let Some(line) = task::block_on(lines.recv()) {
+            run_foreground(line);
+        }
+    }
+}
+
+/// Executes `line` in a thread of its own, which Ctrl-C interrupts, and waits
+/// for it to finish.
+fn run_foreground(line: String) {
+    match sched::spawn("command", move || execute(&line)) {
+        Ok(id) => {
+            signal::set_foreground(Some(id));
+            sched::join(id);
+            signal::set_foreground(None);
+        }
+        Err(error) => println!("shell: {}", error),
+    }
+}
+
+/// Parses a hexadecimal number with an optional `0x` prefix.
+pub fn parse_hex(s: &str) -> Option<u64> {
+    u64::from_str_radix(s.trim_start_matches("0x"), 16).ok()
+}
+
+fn help(_args: &[&str]) {
+    for (name, command) in COMMANDS.lock().iter() {
+        println!("{:<10} {}", name, command.help);
+    }
+}
+
+fn vmmap(args: &[&str]) {
+    let range = match args {
+        [] => 0..u64::MAX,
+        [start, end] => match (parse_hex(start), parse_hex(end)) {
+            (Some(start), Some(end)) => start..end,
+            _ => {
+                println!("vmmap: invalid address");
+                return;
+            }
+        },
+        _ => {
+            println!("usage: vmmap [start end]");
+            return;
+        }
+    };
+    memory::dump_mappings(range);
+}
+
+fn edit(args: &[&str]) {
+    match args {
+        [path] => match editor::Editor::open(path) {
+            Ok(mut editor) => editor.run(),
+            Err(e) => println!("edit: {}: {:?}", path, e),
+        },
+        _ => println!("usage: edit <file>"),
+    }
+}
+
+fn ps(_args: &[&str]) {
+    let usage = sched::cpu_usage();
+    let total = (usage.busy + usage.idle).as_micros().max(1);
+    println!("{:>4} {:<12} {:<8} {:<10} {:>10} {:>4} {:>8} {:>11}", "ID", "NAME", "STATE", "PRIORITY", "CPU", "CPU%",
+        "HEAP", "STACK PEAK");
+    for thread in sched::threads() {
+        let cpu = thread.cpu_time.as_millis();
+        let stack = match thread.stack_size {
+            0 => String::from("-"),
+            size => format!("{}/{}", thread.stack_peak, size),
+        };
+        println!("{:>4} {:<12} {:<8} {:<9}{} {:>6}.{:03}s {:>3}% {:>7}K {:>11}",
+            thread.id.0, thread.name, thread.state.name(), thread.priority.name(),
+            if thread.boosted { "+" } else { " " }, cpu / 1000, cpu % 1000,
+            thread.cpu_time.as_micros() * 100 / total, (thread.heap_allocated + 1023) / 1024, stack);
+    }
+    println!("cpu {}% busy", usage.busy_percent());
+
+    let processes = process::list();
+    if processes.is_empty() {
+        return;
+    }
+    println!();
+    print!("{:>4} {:>4} {:<16} {:<10}", "PID", "PPID", "NAME", "STATE");
+    for resource in process::rlimit::Resource::ALL.iter() {
+        print!(" {:>15}", resource.name().to_uppercase());
+    }
+    println!();
+    for process in processes {
+        let (usage, limits) = (process.usage(), process.limits());
+        let parent = process.parent().map_or(String::from("-"), |parent| format!("{}", parent.0));
+        let state = match process.status() {
+            Some(status) => format!("exited {}", status),
+            None if process.is_traced() => String::from("traced"),
+            None => String::from("running"),
+        };
+        print!("{:>4} {:>4} {:<16} {:<10}", process.pid.0, parent, process.name(), state);
+        for &resource in process::rlimit::Resource::ALL.iter() {
+            print!(" {:>15}", format!("{}/{}", usage.get(resource), limit_name(limits.get(resource))));
+        }
+        println!();
+    }
+}
+
+fn limit_name(limit: u64) -> String {
+    match limit {
+        process::rlimit::UNLIMITED => String::from("-"),
+        limit => format!("{}", limit),
+    }
+}
+
+fn ulimit(args: &[&str]) {
+    use process::rlimit::{self, Resource};
+
+    match args {
+        [] => {
+            let limits = rlimit::defaults();
+            for &resource in Resource::ALL.iter() {
+                let limit = limits.get(resource);
+                let limit = if limit == rlimit::UNLIMITED { String::from("unlimited") } else { format!("{}", limit) };
+                println!("{:<8} {}", resource.name(), limit);
+            }
+        }
+        [resource, limit] => {
+            let resource = match Resource::from_name(resource) {
+                Some(resource) => resource,
+                None => return eprintln!("ulimit: unknown resource {}", resource),
+            };
+            let limit = match *limit {
+                "unlimited" => rlimit::UNLIMITED,
+                limit => match limit.parse() {
+                    Ok(limit) => limit,
+                    Err(_) => return eprintln!("ulimit: invalid limit {}", limit),
+                },
+            };
+            rlimit::set_default(resource, limit);
+        }
+        _ => println!("usage: ulimit [<resource> <limit|unlimited>]"),
+    }
+}
+
+fn hz(args: &[&str]) {
+    use crate::time;
+
+    match args {
+        [] => println!("{} Hz, a tick every {:?}", time::tick_rate(), time::tick_period()),
+        [rate] => match rate.parse() {
+            Ok(rate) => match time::set_tick_rate(rate) {
+                Ok(()) => println!("{} Hz", time::tick_rate()),
+                Err(error) => eprintln!("hz: {}", error),
+            },
+            Err(_) => eprintln!("hz: invalid rate {}", rate),
+        },
+        _ => println!("usage: hz [rate]"),
+    }
+}
+
+fn date(_args: &[&str]) {
+    match crate::drivers::rtc::now() {
+        Ok(now) => println!("{}", now),
+        Err(error) => eprintln!("date: {}", error),
+    }
+}
+
+fn alarm(args: &[&str]) {
+    use crate::time::{self, DateTime};
+
+    let today = match crate::drivers::rtc::now() {
+        Ok(now) => now,
+        Err(error) => return eprintln!("alarm: {}", error),
+    };
+    let text = match args {
+        [time] => format!("{:04}-{:02}-{:02} {}", today.year, today.month, today.day, time),
+        [date, time] => format!("{} {}", date, time),
+        _ => return println!("usage: alarm [YYYY-MM-DD] HH:MM[:SS]"),
+    };
+    let at = match DateTime::parse(&text) {
+        Some(at) => at,
+        None => return eprintln!("alarm: invalid time {}", text),
+    };
+    // from the interrupt handler, so the printing is left to the work queue
+    let ring = |timestamp| {
+        let _ = crate::workqueue::queue(move || println!("alarm: {}", DateTime::from_timestamp(timestamp)));
+    };
+    match time::alarm_at(at, ring, at.timestamp()) {
+        Ok(()) => println!("alarm at {}", at),
+        Err(error) => eprintln!("alarm: {}", error),
+    }
+}
+
+fn ioports(_args: &[&str]) {
+    for claim in ioport::claims() {
+        println!("{:04x}-{:04x} {}", claim.start, u32::from(claim.start) + u32::from(claim.len) - 1, claim.owner);
+    }
+}
+
+fn leaks(args: &[&str]) {
+    use crate::allocator::trace;
+
+    let current = match args {
+        ["on"] => match trace::start() {
+            Ok(()) => return println!("tracing heap allocations, checkpoint 1"),
+            Err(error) => return eprintln!("leaks: {}", error),
+        },
+        ["off"] => return trace::stop(),
+        ["mark"] => return match trace::checkpoint() {
+            Some(checkpoint) => println!("checkpoint {}", checkpoint),
+            None => eprintln!("leaks: tracing is off, start it with leaks on"),
+        },
+        _ => match trace::current_checkpoint() {
+            Some(current) => current,
+            None => return eprintln!("leaks: tracing is off, start it with leaks on"),
+        },
+    };
+    let numbers: Option<Vec<u32>> = args.iter().map(|arg| arg.parse().ok()).collect();
+    // between the last two checkpoints by default
+    let (from, to) = match numbers.as_deref() {
+        Some([]) => (current.saturating_sub(1), current),
+        Some([from]) => (*from, current + 1),
+        Some([from, to]) => (*from, *to),
+        _ => return println!("usage: leaks on|off|mark|[from [to]]"),
+    };
+    let found = match trace::leaks(from, to) {
+        Some(found) => found,
+        None => return eprintln!("leaks: tracing is off"),
+    };
+    println!("live allocations made from checkpoint {} to {}:", from, to);
+    println!("{:>6} {:>7} {:>7}  CALL SITE", "COUNT", "BYTES", "AGE");
+    for site in found.sites() {
+        let age = crate::time::Instant::from_ticks(site.oldest).elapsed().as_secs();
+        print!("{:>6} {:>7} {:>6}s  ", site.count, site.bytes, age);
+        match site.caller() {
+            Some(caller) => println!("{}", caller),
+            None => {
+                for &addr in site.frames.iter().take_while(|&&addr| addr != 0) {
+                    print!("{:#x} ", addr);
+                }
+                println!();
+            }
+        }
+    }
+    if found.others.0 > 0 {
+        println!("{:>6} {:>7}  from other call sites", found.others.0, found.others.1);
+    }
+}
+
+fn mem(args: &[&str]) {
+    match args {
+        [] => {
+            if let Some(stats) = memory::frame_stats() {
+                println!("frames:   {}/{} in use", stats.allocated, stats.total);
+            }
+            println!("cached:   {} pages", page_cache::cached_pages());
+            println!("pressure: {}", memory::pressure().name());
+            println!("oom:      {} events, killer {}", memory::pressure::oom_events(),
+                if memory::pressure::oom_killer_enabled() { "on" } else { "off" });
+        }
+        ["oom", "on"] => memory::pressure::set_oom_killer(true),
+        ["oom", "off"] => memory::pressure::set_oom_killer(false),
+        _ => println!("usage: mem [oom on|off]"),
+    }
+}
+
+fn slabs(_args: &[&str]) {
+    println!("{:<16} {:>6} {:>8} {:>6} {:>10} {:>10}", "cache", "size", "in use", "pages", "allocs", "ctors");
+    for stats in memory::slab::stats() {
+        println!("{:<16} {:>6} {:>4}/{:<4}{:>6} {:>10} {:>10}", stats.name, stats.object_size,
+            stats.in_use, stats.objects, stats.pages, stats.allocations, stats.constructions);
+    }
+}
+
+fn mount(args: &[&str]) {
+    match args {
+        [] => {
+            for (path, name) in fs::mounts() {
+                println!("{:<12} {}", path, name);
+            }
+        }
+        [device, path] => {
+            if block::get(device).is_none() {
+                println!("mount: no block device {}", device);
+                return;
+            }
+            if let Err(error) = fs::mount_device(device, path) {
+                println!("mount: {:?}", error);
+            }
+        }
+        _ => println!("usage: mount [device path]"),
+    }
+}
+
+fn kill(args: &[&str]) {
+    let (id, number) = match args {
+        [id] => (id.parse().ok(), Some(signal::Signal::Terminate as u32)),
+        [id, number] => (id.parse().ok(), number.parse().ok()),
+        _ => {
+            println!("usage: kill <id> [signal]");
+            return;
+        }
+    };
+    let signal = match number.and_then(signal::Signal::from_number) {
+        Some(signal) => signal,
+        None => {
+            println!("kill: unknown signal");
+            return;
+        }
+    };
+    match id {
+        Some(id) => if let Err(error) = signal::kill(sched::ThreadId(id), signal) {
+            println!("kill: {}", error);
+        },
+        None => println!("kill: invalid thread id"),
+    }
+}
+
+fn run_program(args: &[&str]) {
+    let path = match args {
+        [path] => path,
+        _ => {
+            println!("usage: run <path>");
+            return;
+        }
+    };
+    run_and_wait("run", path, None);
+}
+
+/// Runs the program at `path`, traced to `trace`, and waits for it, for the
+/// command `command`.
+fn run_and_wait(command: &str, path: &str, trace: Option<process::trace::Target>) {
+    let pid = match process::spawn_traced(path, trace) {
+        Ok(pid) => pid,
+        Err(errno) => {
+            println!("{}: cannot start {} (error {})", command, path, errno);
+            return;
+        }
+    };
+    // Ctrl-C goes to the program rather than to this command
+    let thread = process::list().into_iter()
+        .find(|process| process.pid == pid)
+        .and_then(|process| process.thread());
+    signal::set_foreground(thread);
+    match process::wait(pid) {
+        Ok(0) => {}
+        Ok(status) => println!("{}: {} exited with {}", command, path, status),
+        Err(errno) => println!("{}: wait failed (error {})", command, errno),
+    }
+}
+
+fn strace(args: &[&str]) {
+    use process::trace::{self, Target};
+
+    let target = |path: Option<&&str>| match path {
+        None => Ok(Target::Log),
+        Some(path) => Target::file(path).map_err(|error| eprintln!("strace: cannot create {}: {:?}", path, error)),
+    };
+    match args {
+        ["run", path, rest @ ..] if rest.len() <= 1 => {
+            if let Ok(target) = target(rest.first()) {
+                run_and_wait("strace", path, Some(target));
+            }
+        }
+        [pid, rest @ ..] if rest.len() <= 1 => {
+            let pid = match pid.parse() {
+                Ok(pid) => process::Pid(pid),
+                Err(_) => return eprintln!("strace: invalid process id {}", pid),
+            };
+            let target = match rest.first() {
+                Some(&"off") => None,
+                path => match target(path) {
+                    Ok(target) => Some(target),
+                    Err(()) => return,
+                },
+            };
+            if let Err(error) = trace::set(pid, target) {
+                eprintln!("strace: {}", error);
+            }
+        }
+        _ => println!("usage: strace <pid> [file|off] | strace run <path> [file]"),
+    }
+}
+
+fn kexec(args: &[&str]) {
+    use crate::boot::kexec;
+
+    let result = match args {
+        [] => {
+            match kexec::loaded() {
+                Some((path, entry, target)) =>
+                    println!("{} loaded at {:#x}-{:#x}, entry {:#x}", path, target.start, target.end, entry),
+                None => println!("no kernel image loaded"),
+            }
+            Ok(())
+        }
+        ["load", path] => kexec::load(path),
+        ["exec"] => kexec::exec().map(|_| ()),
+        ["unload"] => {
+            kexec::unload();
+            Ok(())
+        }
+        _ => return println!("usage: kexec [load <path>|exec|unload]"),
+    };
+    if let Err(error) = result {
+        eprintln!("kexec: {}", error);
+    }
+}
+
+fn dmesg(args: &[&str]) {
+    use crate::klog::pstore;
+
+    let previous = match args {
+        [] => false,
+        ["-p"] => true,
+        _ => return println!("usage: dmesg [-p]"),
+    };
+    match pstore::read(previous) {
+        Some(log) => print!("{}", String::from_utf8_lossy(&log)),
+        None if previous => println!("dmesg: no log of a previous boot ({} boots since power on)", pstore::boots()),
+        None => eprintln!("dmesg: no memory reserved for the log"),
+    }
+}
+
+fn crashdump(args: &[&str]) {
+    use crate::crashdump::{self, Crash, Reason, Registers, Target};
+    use crate::debug::Backtrace;
+
+    match args {
+        [] => crashdump::with_target(|target| match target {
+            Some(target) => println!("crash dumps go to {}", target),
+            None => println!("crash dumps are off"),
+        }),
+        ["write"] => {
+            let result = crashdump::with_target(|target| {
+                crashdump::write_to(target.ok_or("no target")?, &Crash {
+                    reason: Reason::Requested,
+                    message: format_args!("requested from the shell"),
+                    registers: Registers::capture(),
+                    backtrace: &Backtrace::capture(),
+                    fault_addr: None,
+                })
+            });
+            if let Err(error) = result {
+                eprintln!("crashdump: {}", error);
+            }
+        }
+        ["show"] => match crashdump::with_target(|target| crashdump::read(target.ok_or("no target")?)) {
+            Ok(data) => show_crashdump(&data),
+            Err(error) => eprintln!("crashdump: {}", error),
+        },
+        ["clear"] => if let Err(error) = crashdump::with_target(|target| crashdump::clear(target.ok_or("no target")?)) {
+            eprintln!("crashdump: {}", error);
+        },
+        [spec] => match Target::parse(spec) {
+            Ok(target) => crashdump::set_target(target),
+            Err(error) => eprintln!("crashdump: {}", error),
+        },
+        _ => println!("usage: crashdump [off|com1|com2|<device>[@sector]|write|show|clear]"),
+    }
+}
+
+fn show_crashdump(data: &[u8]) {
+    use crate::crashdump::{self, Reason};
+
+    let dump = match crashdump::parse(data) {
+        Ok(dump) => dump,
+        Err(error) => return eprintln!("crashdump: {}", error),
+    };
+    let header = dump.header;
+    println!("{} on CPU {} at {} ms, {} bytes", Reason::name(header.reason), header.cpu,
+             header.uptime_ns / 1_000_000, dump.len);
+    let text = |kind| String::from_utf8_lossy(dump.section(kind).unwrap_or(&[])).into_owned();
+    println!("{}", text(crashdump::MESSAGE));
+    if let Some(registers) = dump.registers() {
+        println!("rip {:#018x} rsp {:#018x} rbp {:#018x} rflags {:#x}", registers.rip, registers.rsp,
+                 registers.rbp, registers.rflags);
+        println!("cr2 {:#018x} cr3 {:#018x} error code {:#x}", registers.cr2, registers.cr3, registers.error_code);
+    }
+    println!("backtrace:");
+    for (i, addr) in dump.backtrace().enumerate() {
+        match crate::symbols::resolve(addr) {
+            Some(symbol) => println!("  #{:<2} {:#018x} {}", i, addr, symbol),
+            None => println!("  #{:<2} {:#018x}", i, addr),
+        }
+    }
+    for (kind, data) in dump.sections.iter() {
+        match *kind {
+            crashdump::THREADS => print!("threads:\n{}", text(*kind)),
+            crashdump::LOG => print!("log:\n{}", text(*kind)),
+            crashdump::MEMORY if data.len() >= 8 => {
+                let addr = u64::from_le_bytes([data[0], data[1], data[2], data[3], data[4], data[5], data[6], data[7]]);
+                println!("memory at {:#018x}: {} bytes", addr, data.len() - 8);
+            }
+            _ => {}
+        }
+    }
+}
+
+fn bind(args: &[&str]) {
+    use crate::keyboard::bindings::{self, Action, Chord};
+
+    let (action, chord) = match args {
+        [] => {
+            for (action, chord) in bindings::bindings().iter() {
+                let chord = chord.map_or(String::from("none"), |chord| format!("{}", chord));
+                println!("{:<14} {:<14} {}", action.name(), chord, action.description());
+            }
+            return;
+        }
+        ["reset"] => return bindings::reset(),
+        [action, chord] => (action, chord),
+        _ => return println!("usage: bind [<action> <chord>|none]|[reset]"),
+    };
+    let action = match Action::from_name(action) {
+        Some(action) => action,
+        None => return eprintln!("bind: no action named {}", action),
+    };
+    match (*chord, Chord::parse(chord)) {
+        ("none", _) => bindings::unbind(action),
+        (_, Some(chord)) => if let Some(previous) = bindings::bind(action, chord) {
+            println!("{} was bound to {}, which is now unbound", chord, previous.name());
+        },
+        (chord, None) => eprintln!("bind: invalid chord {}", chord),
+    }
+}
+
+fn macros(args: &[&str]) {
+    use crate::keyboard::bindings::{self, Action};
+    use crate::keyboard::macros;
+
+    let result = match args {
+        [] => {
+            for (name, keys) in macros::list() {
+                println!("{:<16} {} keys", name, keys);
+            }
+            return;
+        }
+        ["record", name] => match bindings::chord(Action::RecordMacro) {
+            Some(chord) => macros::start(Some(name)).map(|()| println!("recording {}, {} stops", name, chord)),
+            None => Err("record-macro has no key binding to stop recording"),
+        },
+        ["play", name] => macros::play(name),
+        ["delete", name] => macros::delete(name),
+        _ => return println!("usage: macro [record|play|delete <name>]"),
+    };
+    if let Err(error) = result {
+        eprintln!("macro: {}", error);
+    }
+}
+
+fn screenshot(args: &[&str]) {
+    use crate::console::{self, ScreenshotFormat};
+    use crate::serial_print;
+
+    let (format, path) = match args {
+        [] => (ScreenshotFormat::Text, None),
+        ["ansi"] => (ScreenshotFormat::Ansi, None),
+        ["ansi", path] => (ScreenshotFormat::Ansi, Some(*path)),
+        [path] => (ScreenshotFormat::Text, Some(*path)),
+        _ => return println!("usage: screenshot [ansi] [path]"),
+    };
+    let text = console::screenshot(format);
+    match path {
+        Some(path) => if let Err(error) = fs::write_file(path, text.as_bytes()) {
+            eprintln!("screenshot: {}: {:?}", path, error);
+        },
+        None => {
+            serial_print!("{}", text);
+        }
+    }
+}
+
+fn theme(args: &[&str]) {
+    match args {
+        [] => {
+            let current = without_interrupts(|| WRITER.lock().theme());
+            for theme in THEMES.iter() {
+                println!("{} {}", if theme == current { '*' } else { ' ' }, theme.name);
+            }
+        }
+        [name] => match Theme::find(name) {
+            Some(theme) => without_interrupts(|| WRITER.lock().set_theme(theme)),
+            None => eprintln!("theme: no theme named {}", name),
+        },
+        _ => println!("usage: theme [name]"),
+    }
+}
+
+#[test_case]
+fn test_parse_hex() {
+    assert_eq!(parse_hex("0x444444440000"), Some(0x4444_4444_0000));
+    assert_eq!(parse_hex("b8000"), Some(0xb8000));
+    assert_eq!(parse_hex("xyz"), None);
+}
@@ -0,0 +1,138 @@
+//! The shell commands of the network stack.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use crate::shell::register;
+use crate::{eprintln, fs, net, print, println};
+
+pub(super) fn register_commands() {
+    register("ifconfig", "list the network interfaces with their addresses and counters", ifconfig);
+    register("arp", "arp [flush]: list the IPv4 neighbors and their hardware addresses, or forget them", arp);
+    register("netstat", "list the TCP listeners and connections", netstat);
+    register("fetch", "fetch <url> [path]: get an http:// URL and print its body or save it to a file", fetch);
+    register("syslog", "syslog [address[:port]|off]: show where the kernel log is shipped over UDP, or change it", syslog);
+}
+
+fn ifconfig(_args: &[&str]) {
+    for interface in net::interfaces() {
+        let stats = interface.stats();
+        println!("{:<6} {}", interface.name, interface.device.mac());
+        if let Some((_, config)) = net::ipv4::route().filter(|(routed, _)| Arc::ptr_eq(routed, &interface)) {
+            match config.gateway {
+                Some(gateway) => println!("       inet {}/{} gateway {}", config.address, config.prefix_len, gateway),
+                None => println!("       inet {}/{}", config.address, config.prefix_len),
+            }
+        }
+        println!("       rx {} packets {} bytes, tx {} packets {} bytes {} errors", stats.rx_packets, stats.rx_bytes,
+            stats.tx_packets, stats.tx_bytes, stats.tx_errors);
+    }
+}
+
+fn arp(args: &[&str]) {
+    use crate::net::arp;
+
+    match args {
+        [] => {}
+        ["flush"] => return arp::flush(),
+        _ => return println!("usage: arp [flush]"),
+    }
+    println!("{:<16} {:<18} {:>8} {:>7}", "address", "hardware address", "expires", "waiting");
+    for neighbor in arp::neighbors() {
+        match neighbor.mac {
+            Some(mac) => println!("{:<16} {:<18} {:>7}s", neighbor.addr, mac, neighbor.expires.as_secs()),
+            None => println!("{:<16} {:<18} {:>8} {:>7}", neighbor.addr, "(incomplete)", "", neighbor.pending),
+        }
+    }
+    println!("{} packets dropped unresolved", arp::unresolved());
+}
+
+fn netstat(_args: &[&str]) {
+    println!("{:<22} {:<22} {:<13} {:>6} {:>6}", "local", "remote", "state", "send-q", "recv-q");
+    for socket in net::tcp::sockets() {
+        let local = format!("*:{}", socket.local_port);
+        let remote = socket.remote.map_or(String::from("*:*"), |(addr, port)| format!("{}:{}", addr, port));
+        let state = socket.state.map_or("LISTEN", |state| state.name());
+        println!("{:<22} {:<22} {:<13} {:>6} {:>6}", local, remote, state, socket.queued.0, socket.queued.1);
+    }
+}
+
+fn fetch(args: &[&str]) {
+    use crate::net::http;
+
+    let (url, path) = match args {
+        [url] => (url, None),
+        [url, path] => (url, Some(*path)),
+        _ => return println!("usage: fetch <url> [path]"),
+    };
+    let mut file = match path {
+        Some(path) => match fs::write_file(path, b"").and_then(|()| fs::File::open(path)) {
+            Ok(file) => Some(file),
+            Err(error) => return eprintln!("fetch: {}: {:?}", path, error),
+        },
+        None => None,
+    };
+    let (mut saved, mut write_error) = (0, None);
+    // the bytes of a character split between two pieces of the body
+    let mut partial = Vec::new();
+    let result = http::fetch(url, |mut data| {
+        match &mut file {
+            Some(file) => while !data.is_empty() {
+                let len = match file.write(data) {
+                    Ok(0) => Err(fs::FsError::Unsupported),
+                    result => result,
+                };
+                let len = len.map_err(|error| {
+                    write_error = Some(error);
+                    "write failed"
+                })?;
+                data = &data[len..];
+                saved += len;
+            },
+            None => {
+                partial.extend_from_slice(data);
+                let valid = match core::str::from_utf8(&partial) {
+                    Ok(text) => text.len(),
+                    Err(error) if error.error_len().is_none() => error.valid_up_to(),
+                    Err(_) => partial.len(),
+                };
+                print!("{}", String::from_utf8_lossy(&partial[..valid]));
+                partial.drain(..valid);
+            }
+        }
+        Ok(())
+    });
+    print!("{}", String::from_utf8_lossy(&partial));
+    match (result, path, write_error) {
+        (Ok(response), _, _) if !response.is_success() => eprintln!("fetch: {} {}", response.status, response.reason),
+        (Ok(_), Some(path), _) => println!("saved {} bytes to {}", saved, path),
+        (Ok(_), None, _) => {}
+        (Err(_), Some(path), Some(error)) => eprintln!("fetch: {}: {:?}", path, error),
+        (Err(error), _, _) => eprintln!("fetch: {}", error),
+    }
+}
+
+fn syslog(args: &[&str]) {
+    use crate::net::syslog;
+
+    let target = match args {
+        [] => {
+            let stats = syslog::stats();
+            match syslog::target() {
+                Some((addr, port)) => println!("shipping to {}:{}", addr, port),
+                None => println!("off"),
+            }
+            return println!("{} sent, {} dropped, {} failed", stats.sent, stats.dropped, stats.failed);
+        }
+        ["off"] => None,
+        [target] => match syslog::parse_target(target) {
+            Some(target) => Some(target),
+            None => return eprintln!("syslog: invalid address {}", target),
+        },
+        _ => return println!("usage: syslog [address[:port]|off]"),
+    };
+    if let Err(error) = syslog::set_target(target) {
+        eprintln!("syslog: {}", error);
+    }
+}
@@ -0,0 +1,475 @@
+//! Remote shell sessions, over telnet and on a serial port, for using the
+//! kernel without a screen or keyboard.
+//!
+//! A session gets a copy of everything printed to the console from when it
+//! starts, and the lines it sends go to the shell like typed ones; the local
+//! console keeps working alongside. Telnet clients edit and echo lines
+//! themselves, in their default line mode. On a serial port the session
+//! echoes and handles backspace. Ctrl-C interrupts the running command. A
+//! telnet session ends when its connection closes, and the next connection
+//! waiting gets the shell.
+//!
+//! Options:
+//! - `telnet=<port>|off`: port of the telnet server, 23 by default
+//! - `serial_shell=com1|com2|hvc0`: also run a session on that port, `hvc0`
+//!   being the virtio console. With `console=serial` and COM1, the kernel
+//!   runs headless on a single port.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use spin::Mutex;
+use crate::arch::interrupts::without_interrupts;
+use crate::drivers::uart::{Com, Config};
+use crate::drivers::virtio;
+use crate::net::tcp::{TcpListener, TcpStream};
+use crate::sched::{self, WaitQueue};
+use crate::shell::{self, PROMPT};
+use crate::{boot, initcall, log_info, log_warn, println, serial, signal, task};
+
+pub const TELNET_PORT: u16 = 23;
+
+const GREETING: &[u8] = b"MarOS remote shell, Ctrl-C interrupts a command\n";
+/// Bytes of console output kept for sessions that fall behind.
+const OUTPUT_SIZE: usize = 4096;
+/// Longest line a session sends; the rest is dropped.
+const MAX_LINE: usize = 256;
+const SERIAL_INPUT_SIZE: usize = 64;
+
+// telnet commands
+const SE: u8 = 240;
+const IP: u8 = 244;
+const SB: u8 = 250;
+const WILL: u8 = 251;
+const WONT: u8 = 252;
+const DO: u8 = 253;
+const DONT: u8 = 254;
+const IAC: u8 = 255;
+
+/// The console output lately, which every session reads at its own pace.
+struct Output {
+    data: [u8; OUTPUT_SIZE],
+    /// Bytes written since boot; the last `OUTPUT_SIZE` of them are in `data`.
+    written: u64,
+}
+
+impl Output {
+    /// Copies the bytes from `cursor` on into `buf` and moves `cursor` past
+    /// them. Skips those that were overwritten already.
+    fn read(&self, cursor: &mut u64, buf: &mut [u8]) -> usize {
+        *cursor = (*cursor).max(self.written.saturating_sub(OUTPUT_SIZE as u64));
+        let len = ((self.written - *cursor) as usize).min(buf.len());
+        for (i, byte) in buf[..len].iter_mut().enumerate() {
+            *byte = self.data[((*cursor + i as u64) % OUTPUT_SIZE as u64) as usize];
+        }
+        *cursor += len as u64;
+        len
+    }
+}
+
+static OUTPUT: Mutex<Output> = Mutex::new(Output { data: [0; OUTPUT_SIZE], written: 0 });
+static SESSIONS: AtomicUsize = AtomicUsize::new(0);
+/// Notified when there is output, serial input or a session ending.
+static WAKEUP: WaitQueue = WaitQueue::new();
+
+/// Copies text written to the console for the sessions. Called by the
+/// console with interrupts disabled.
+pub(crate) fn mirror(bytes: &[u8]) {
+    if SESSIONS.load(Ordering::Relaxed) == 0 {
+        return;
+    }
+    // only a panic can leave it locked here
+    if let Some(mut output) = OUTPUT.try_lock() {
+        for &byte in bytes {
+            let at = (output.written % OUTPUT_SIZE as u64) as usize;
+            output.data[at] = byte;
+            output.written += 1;
+        }
+        drop(output);
+        WAKEUP.notify_all();
+    }
+}
+
+/// A session's place in the console output.
+struct Session {
+    cursor: u64,
+}
+
+impl Session {
+    fn start() -> Session {
+        SESSIONS.fetch_add(1, Ordering::Relaxed);
+        Session { cursor: without_interrupts(|| OUTPUT.lock().written) }
+    }
+
+    fn pending(&self) -> bool {
+        without_interrupts(|| OUTPUT.lock().written != self.cursor)
+    }
+
+    /// Appends the output not read yet to `out`, translated for a terminal.
+    fn output(&mut self, out: &mut Vec<u8>) {
+        let mut buf = [0; 256];
+        loop {
+            let len = without_interrupts(|| OUTPUT.lock().read(&mut self.cursor, &mut buf));
+            if len == 0 {
+                return;
+            }
+            translate(&buf[..len], out);
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        SESSIONS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Appends console text to `out` for a terminal: with CR LF line ends and
+/// without the bytes that are commands for the console, like colors.
+fn translate(bytes: &[u8], out: &mut Vec<u8>) {
+    for &byte in bytes {
+        match byte {
+            b'\n' => out.extend_from_slice(b"\r\n"),
+            0x08 => out.extend_from_slice(b"\x08 \x08"),
+            b'\t' | 0x20..=0x7e => out.push(byte),
+            // code page 437
+            0x80..=0xff => out.push(b'?'),
+            _ => {}
+        }
+    }
+}
+
+/// What a session's input amounts to.
+#[derive(Debug, PartialEq, Eq)]
+enum Event {
+    Line(String),
+    Interrupt,
+}
+
+/// Collects lines from the bytes a terminal sends.
+struct LineEditor {
+    line: String,
+    /// Whether to echo what is typed, for terminals that don't.
+    echo: bool,
+    after_cr: bool,
+}
+
+impl LineEditor {
+    fn new(echo: bool) -> LineEditor {
+        LineEditor { line: String::new(), echo, after_cr: false }
+    }
+
+    /// Takes a received byte, appending what to echo to `echo`.
+    fn feed(&mut self, byte: u8, echo: &mut Vec<u8>) -> Option<Event> {
+        let after_cr = core::mem::replace(&mut self.after_cr, byte == b'\r');
+        match byte {
+            // the rest of CR LF or CR NUL
+            b'\n' | 0 if after_cr => None,
+            b'\r' | b'\n' => {
+                if self.echo {
+                    echo.extend_from_slice(b"\r\n");
+                }
+                Some(Event::Line(core::mem::take(&mut self.line)))
+            }
+            0x03 => {
+                self.line.clear();
+                Some(Event::Interrupt)
+            }
+            0x08 | 0x7f => {
+                if self.line.pop().is_some() && self.echo {
+                    echo.extend_from_slice(b"\x08 \x08");
+                }
+                None
+            }
+            0x20..=0x7e if self.line.len() < MAX_LINE => {
+                self.line.push(char::from(byte));
+                if self.echo {
+                    echo.push(byte);
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Passes an event on to the shell.
+fn handle(event: Event) {
+    match event {
+        Event::Line(line) => shell::submit(line),
+        Event::Interrupt => {
+            if signal::interrupt_foreground() {
+                println!("^C");
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TelnetState {
+    Data,
+    Command,
+    Option(u8),
+    Subnegotiation,
+    SubnegotiationCommand,
+}
+
+/// Separates the data a telnet client sends from its commands. Every option
+/// the client offers or asks for is refused.
+struct Telnet {
+    state: TelnetState,
+}
+
+impl Telnet {
+    fn new() -> Telnet {
+        Telnet { state: TelnetState::Data }
+    }
+
+    /// Takes a received byte, and returns it if it is data. Interrupting is
+    /// returned as Ctrl-C. Appends replies to `reply`.
+    fn feed(&mut self, byte: u8, reply: &mut Vec<u8>) -> Option<u8> {
+        let (state, data) = match (self.state, byte) {
+            (TelnetState::Data, IAC) => (TelnetState::Command, None),
+            (TelnetState::Data, _) => (TelnetState::Data, Some(byte)),
+            (TelnetState::Command, IAC) => (TelnetState::Data, Some(IAC)),
+            (TelnetState::Command, IP) => (TelnetState::Data, Some(0x03)),
+            (TelnetState::Command, SB) => (TelnetState::Subnegotiation, None),
+            (TelnetState::Command, WILL..=DONT) => (TelnetState::Option(byte), None),
+            (TelnetState::Command, _) => (TelnetState::Data, None),
+            (TelnetState::Option(command), option) => {
+                match command {
+                    WILL => reply.extend_from_slice(&[IAC, DONT, option]),
+                    DO => reply.extend_from_slice(&[IAC, WONT, option]),
+                    _ => {}
+                }
+                (TelnetState::Data, None)
+            }
+            (TelnetState::Subnegotiation, IAC) => (TelnetState::SubnegotiationCommand, None),
+            (TelnetState::Subnegotiation, _) => (TelnetState::Subnegotiation, None),
+            (TelnetState::SubnegotiationCommand, SE) => (TelnetState::Data, None),
+            (TelnetState::SubnegotiationCommand, _) => (TelnetState::Subnegotiation, None),
+        };
+        self.state = state;
+        data
+    }
+}
+
+/// Starts the telnet server and the serial session the command line asks for.
+pub fn init() -> Result<(), &'static str> {
+    let cmdline = boot::cmdline();
+    match cmdline.get("telnet") {
+        Some("off") => {}
+        port => {
+            let port = match port {
+                Some(port) => port.parse().map_err(|_| "invalid telnet port")?,
+                None => TELNET_PORT,
+            };
+            sched::spawn("telnetd", move || serve_telnet(port))?;
+        }
+    }
+    match cmdline.get("serial_shell") {
+        Some(virtio::console::NAME) => start_virtio_console()?,
+        Some(name) => {
+            let com = *Com::ALL.iter().find(|com| com.name() == name).ok_or("unknown serial_shell port")?;
+            start_serial(com)?;
+        }
+        None => {}
+    }
+    Ok(())
+}
+initcall!(Late, "remote shell", init, needs: &["shell", "net"]);
+
+/// Accepts telnet connections on `port`, serving one session at a time.
+fn serve_telnet(port: u16) {
+    let listener = match TcpListener::bind(port) {
+        Ok(listener) => listener,
+        Err(error) => return log_warn!("telnet: port {}: {}", port, error.name()),
+    };
+    log_info!("telnet: listening on port {}", port);
+    loop {
+        let stream = task::block_on(listener.accept());
+        telnet_session(Arc::new(stream));
+    }
+}
+
+/// Runs a session on `stream` until it is closed. The output is sent by a
+/// thread of its own, so it flows while this one waits for input.
+fn telnet_session(stream: Arc<TcpStream>) {
+    let (addr, port) = stream.peer();
+    log_info!("telnet: session from {}:{}", addr, port);
+    let done = Arc::new(AtomicBool::new(false));
+    let sender = {
+        let (stream, done) = (stream.clone(), done.clone());
+        sched::spawn("telnet-out", move || send_output(&stream, &done))
+    };
+    let mut telnet = Telnet::new();
+    let mut editor = LineEditor::new(false);
+    let (mut buf, mut reply) = ([0; 256], Vec::new());
+    while sender.is_ok() {
+        let len = match task::block_on(stream.read(&mut buf)) {
+            Ok(0) | Err(_) => break,
+            Ok(len) => len,
+        };
+        for &byte in &buf[..len] {
+            if let Some(event) = telnet.feed(byte, &mut reply).and_then(|byte| editor.feed(byte, &mut Vec::new())) {
+                handle(event);
+            }
+        }
+        if !reply.is_empty() && task::block_on(stream.write_all(&reply)).is_err() {
+            break;
+        }
+        reply.clear();
+    }
+    done.store(true, Ordering::Release);
+    WAKEUP.notify_all();
+    if let Ok(id) = sender {
+        sched::join(id);
+    }
+    log_info!("telnet: session from {}:{} ended", addr, port);
+}
+
+/// Sends the console output to `stream` until `done` is set.
+fn send_output(stream: &TcpStream, done: &AtomicBool) {
+    let mut session = Session::start();
+    let mut out = Vec::new();
+    translate(GREETING, &mut out);
+    translate(PROMPT.as_bytes(), &mut out);
+    loop {
+        if !out.is_empty() && task::block_on(stream.write_all(&out)).is_err() {
+            return;
+        }
+        out.clear();
+        WAKEUP.wait_until(|| done.load(Ordering::Acquire) || session.pending());
+        if done.load(Ordering::Acquire) {
+            return;
+        }
+        session.output(&mut out);
+    }
+}
+
+/// Bytes received on the port of the serial session, not handled yet.
+struct SerialInput {
+    data: [u8; SERIAL_INPUT_SIZE],
+    len: usize,
+}
+
+static SERIAL_INPUT: Mutex<SerialInput> = Mutex::new(SerialInput { data: [0; SERIAL_INPUT_SIZE], len: 0 });
+
+/// Queues a received byte. Called by the serial interrupt handler; bytes
+/// arriving while the queue is full are dropped.
+fn serial_received(byte: u8) {
+    let mut input = SERIAL_INPUT.lock();
+    if input.len < SERIAL_INPUT_SIZE {
+        let len = input.len;
+        input.data[len] = byte;
+        input.len += 1;
+    }
+    drop(input);
+    WAKEUP.notify_all();
+}
+
+/// Sets up `com` for a session, and starts it.
+fn start_serial(com: Com) -> Result<(), &'static str> {
+    #[cfg(feature = "gdbstub")]
+    {
+        if com == Com::Com2 {
+            return Err("COM2 is used by the gdbstub");
+        }
+    }
+    let port = serial::port(com);
+    if !without_interrupts(|| port.lock().is_initialized()) {
+        serial::open(com, Config::default())?;
+    }
+    without_interrupts(|| port.lock().enable_interrupts());
+    serial::set_receiver(com, Some(serial_received));
+    let send = move |bytes: &[u8]| {
+        without_interrupts(|| {
+            let mut port = port.lock();
+            bytes.iter().for_each(|&byte| port.send(byte));
+        })
+    };
+    sched::spawn("serial-shell", move || serve_serial(send))?;
+    log_info!("shell: session on {}", com.name());
+    Ok(())
+}
+
+/// Starts a session on the virtio console.
+fn start_virtio_console() -> Result<(), &'static str> {
+    if !virtio::console::is_present() {
+        return Err("no virtio console");
+    }
+    virtio::console::set_receiver(Some(serial_received));
+    let send = |bytes: &[u8]| {
+        let _ = virtio::console::write(bytes);
+    };
+    sched::spawn("serial-shell", move || serve_serial(send))?;
+    log_info!("shell: session on {}", virtio::console::NAME);
+    Ok(())
+}
+
+/// Runs a session forever on the port `send` writes to.
+fn serve_serial(send: impl Fn(&[u8])) {
+    let mut session = Session::start();
+    let mut editor = LineEditor::new(true);
+    let mut out = Vec::new();
+    translate(GREETING, &mut out);
+    translate(PROMPT.as_bytes(), &mut out);
+    loop {
+        // a few bytes at a time, as sending may wait for the port
+        for chunk in out.chunks(64) {
+            send(chunk);
+        }
+        out.clear();
+        WAKEUP.wait_until(|| SERIAL_INPUT.lock().len > 0 || session.pending());
+        let (data, len) = without_interrupts(|| {
+            let mut input = SERIAL_INPUT.lock();
+            (input.data, core::mem::replace(&mut input.len, 0))
+        });
+        for &byte in &data[..len] {
+            if let Some(event) = editor.feed(byte, &mut out) {
+                handle(event);
+            }
+        }
+        session.output(&mut out);
+    }
+}
+
+#[test_case]
+fn test_line_editor() {
+    let mut editor = LineEditor::new(true);
+    let mut echo = Vec::new();
+    let events: Vec<Event> = b"lx\x7fs\r\nps\r\0\x03".iter().filter_map(|&byte| editor.feed(byte, &mut echo)).collect();
+    assert_eq!(events, [Event::Line(String::from("ls")), Event::Line(String::from("ps")), Event::Interrupt]);
+    assert_eq!(echo, b"lx\x08 \x08s\r\nps\r\n");
+
+    let mut out = Vec::new();
+    translate(b"\x0eerror\x0f\n\xdb", &mut out);
+    assert_eq!(out, b"error\r\n?");
+}
+
+#[test_case]
+fn test_telnet() {
+    let mut telnet = Telnet::new();
+    let mut reply = Vec::new();
+    let received = [b'a', IAC, DO, 1, IAC, WILL, 31, IAC, SB, 24, 0, IAC, SE, IAC, IAC, IAC, WONT, 1, IAC, IP, b'b'];
+    let data: Vec<u8> = received.iter().filter_map(|&byte| telnet.feed(byte, &mut reply)).collect();
+    assert_eq!(data, [b'a', IAC, 0x03, b'b']);
+    assert_eq!(reply, [IAC, WONT, 1, IAC, DONT, 31]);
+}
+
+#[test_case]
+fn test_output() {
+    let mut output = Output { data: [0; OUTPUT_SIZE], written: 0 };
+    for i in 0..OUTPUT_SIZE + 10 {
+        output.data[i % OUTPUT_SIZE] = i as u8;
+        output.written += 1;
+    }
+    // a reader that fell behind loses the oldest bytes
+    let (mut cursor, mut buf) = (0, [0; 4]);
+    assert_eq!(output.read(&mut cursor, &mut buf), 4);
+    assert_eq!((cursor, buf), (14, [10, 11, 12, 13]));
+    cursor = output.written - 2;
+    assert_eq!(output.read(&mut cursor, &mut buf), 2);
+    assert_eq!(buf[..2], [(OUTPUT_SIZE + 8) as u8, (OUTPUT_SIZE + 9) as u8]);
+}
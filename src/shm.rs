@@ -0,0 +1,153 @@
+//! Named shared memory objects.
+//!
+//! An object is a set of frames that can be mapped any number of times, each
+//! mapping with its own permissions. The frames are freed when the object was
+//! unlinked and its last mapping is gone. The kernel's own mappings are placed
+//! in `aslr::SHARED_MEMORY_WINDOW`; processes map objects into their address
+//! space with the `SHM_MAP` system call (see `process::map_shm`), each mapping
+//! holding references to the frames. Threads and processes sharing an object
+//! synchronize with `futex`.
+
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::Range;
+use spin::Mutex;
+use x86_64::structures::paging::{PageTableFlags, PhysFrame};
+use x86_64::VirtAddr;
+use crate::{aslr, memory};
+
+/// Largest object that can be created.
+const MAX_SIZE: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShmError {
+    NotFound,
+    AlreadyExists,
+    /// Zero or more than `MAX_SIZE` bytes.
+    InvalidSize,
+    OutOfMemory,
+    /// No free room in the address space for the mapping.
+    NoAddressSpace,
+}
+
+/// What a mapping allows besides reading.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protection {
+    ReadOnly,
+    ReadWrite,
+}
+
+struct Object {
+    frames: Vec<PhysFrame>,
+}
+
+impl Drop for Object {
+    fn drop(&mut self) {
+        // only dropped with the last kernel mapping; the mappings of
+        // processes hold references of their own
+        for frame in self.frames.drain(..) {
+            if memory::unshare_frame(frame) {
+                unsafe { memory::free_frames(Some(frame)) };
+            }
+        }
+    }
+}
+
+static OBJECTS: Mutex<BTreeMap<String, Arc<Object>>> = Mutex::new(BTreeMap::new());
+
+/// Creates the object `name` of `size` bytes, rounded up to whole pages and zeroed.
+pub fn create(name: &str, size: usize) -> Result<(), ShmError> {
+    if size == 0 || size > MAX_SIZE {
+        return Err(ShmError::InvalidSize);
+    }
+    let mut objects = OBJECTS.lock();
+    if objects.contains_key(name) {
+        return Err(ShmError::AlreadyExists);
+    }
+    let frames = memory::allocate_frames((size + 0xfff) / 0x1000).map_err(|_| ShmError::OutOfMemory)?;
+    objects.insert(name.to_string(), Arc::new(Object { frames }));
+    Ok(())
+}
+
+/// Removes the name `name`. Existing mappings keep the object alive.
+pub fn unlink(name: &str) -> Result<(), ShmError> {
+    OBJECTS.lock().remove(name).map(drop).ok_or(ShmError::NotFound)
+}
+
+/// Runs `f` with the frames of the object `name`, which stay allocated
+/// meanwhile.
+pub fn with_frames<R>(name: &str, f: impl FnOnce(&[PhysFrame]) -> R) -> Result<R, ShmError> {
+    let object = OBJECTS.lock().get(name).cloned().ok_or(ShmError::NotFound)?;
+    Ok(f(&object.frames))
+}
+
+/// A mapping of a shared memory object, unmapped when dropped.
+pub struct Mapping {
+    object: Arc<Object>,
+    start: VirtAddr,
+}
+
+/// Maps the object `name` with `protection`.
+pub fn map(name: &str, protection: Protection) -> Result<Mapping, ShmError> {
+    let object = OBJECTS.lock().get(name).cloned().ok_or(ShmError::NotFound)?;
+    let size = object.frames.len() as u64 * 0x1000;
+    let base = aslr::random_free_base(aslr::SHARED_MEMORY_WINDOW, size, 0x1000)
+        .ok_or(ShmError::NoAddressSpace)?;
+    let mut flags = PageTableFlags::NO_EXECUTE;
+    if protection == Protection::ReadWrite {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    let start = VirtAddr::new(base);
+    if memory::map_frames(start, &object.frames, flags).is_err() {
+        memory::unmap_range(start..start + size);
+        return Err(ShmError::OutOfMemory);
+    }
+    Ok(Mapping { object, start })
+}
+
+impl Mapping {
+    pub fn range(&self) -> Range<VirtAddr> {
+        self.start..self.start + self.size() as u64
+    }
+
+    pub fn size(&self) -> usize {
+        self.object.frames.len() * 0x1000
+    }
+
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.start.as_mut_ptr()
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        memory::unmap_range(self.range());
+    }
+}
+
+#[test_case]
+fn test_mappings_share_frames() {
+    create("test", 100).unwrap();
+    assert_eq!(create("test", 100), Err(ShmError::AlreadyExists));
+    assert_eq!(create("empty", 0), Err(ShmError::InvalidSize));
+
+    let writer = map("test", Protection::ReadWrite).unwrap();
+    let reader = map("test", Protection::ReadOnly).unwrap();
+    assert_eq!(reader.size(), 0x1000);
+    assert_ne!(writer.as_ptr(), reader.as_ptr());
+    unlink("test").unwrap();
+    assert!(map("test", Protection::ReadOnly).is_err());
+
+    unsafe {
+        (writer.as_ptr().add(8) as *mut u64).write_volatile(0x1234);
+        assert_eq!((reader.as_ptr().add(8) as *const u64).read_volatile(), 0x1234);
+    }
+    let flags = memory::mapping_of(reader.range().start).unwrap().flags;
+    assert!(!flags.contains(PageTableFlags::WRITABLE));
+
+    let range = writer.range();
+    drop(writer);
+    assert!(memory::mapping_of(range.start).is_none());
+}
@@ -0,0 +1,270 @@
+//! Unix-like signals between threads.
+//!
+//! `kill` marks a signal pending on a thread and wakes it if it is blocked. The
+//! thread handles its pending signals the next time it sleeps, yields or waits
+//! on a `WaitQueue`, never in the middle of other work, so a thread spinning
+//! without doing any of these can't be interrupted. Terminating a thread does
+//! not unwind its stack, so values it owns are leaked.
+//!
+//! Processes send signals with the `KILL` system call and handle them in user
+//! mode with `Action::User`. Those signals stay pending until the process
+//! returns from a system call, where `next_user_signal` hands them to the
+//! `syscall` gate, which runs the handler on the user stack.
+//!
+//! Ctrl-C on the console sends `Signal::Interrupt` to the foreground thread,
+//! the command the shell is running.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use crate::arch::interrupts;
+use crate::sched::{self, ThreadId};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signal {
+    Interrupt = 2,
+    /// Terminates the thread. Can't be blocked, ignored or handled.
+    Kill = 9,
+    User1 = 10,
+    User2 = 12,
+    Terminate = 15,
+    Child = 17,
+}
+
+impl Signal {
+    const ALL: [Signal; 6] = [Signal::Interrupt, Signal::Kill, Signal::User1, Signal::User2,
+                              Signal::Terminate, Signal::Child];
+
+    pub fn from_number(number: u32) -> Option<Signal> {
+        Signal::ALL.iter().copied().find(|&signal| signal as u32 == number)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Signal::Interrupt => "SIGINT",
+            Signal::Kill => "SIGKILL",
+            Signal::User1 => "SIGUSR1",
+            Signal::User2 => "SIGUSR2",
+            Signal::Terminate => "SIGTERM",
+            Signal::Child => "SIGCHLD",
+        }
+    }
+
+    fn bit(self) -> u32 {
+        1 << self as u32
+    }
+
+    /// Whether the default action ignores the signal rather than terminating.
+    fn ignored_by_default(self) -> bool {
+        self == Signal::Child
+    }
+}
+
+pub type Handler = fn(Signal);
+
+/// What a thread does when it receives a signal.
+#[derive(Debug, Clone, Copy)]
+pub enum Action {
+    /// Terminate, or ignore `Signal::Child`.
+    Default,
+    Ignore,
+    Handle(Handler),
+    /// Call `handler` in user mode with the signal's number, returning to
+    /// `trampoline`, which makes the `SIGRETURN` system call.
+    User { handler: u64, trampoline: u64 },
+}
+
+/// A signal taken by `next_user_signal` for a user-mode handler.
+#[derive(Debug, Clone, Copy)]
+pub struct UserSignal {
+    pub signal: Signal,
+    pub handler: u64,
+    pub trampoline: u64,
+    /// The blocked signals before the handler, to restore once it returns.
+    pub blocked: u32,
+}
+
+/// The signal state of one thread, kept by the scheduler.
+pub(crate) struct Signals {
+    pending: u32,
+    blocked: u32,
+    actions: [Action; 32],
+}
+
+impl Signals {
+    pub(crate) const fn new() -> Signals {
+        Signals { pending: 0, blocked: 0, actions: [Action::Default; 32] }
+    }
+
+    /// Whether a pending signal is not blocked and is handled in the kernel.
+    /// Those for user-mode handlers wait for the return to user mode.
+    pub(crate) fn deliverable(&self) -> bool {
+        self.deliverable_in_kernel() != 0
+    }
+
+    fn deliverable_in_kernel(&self) -> u32 {
+        let user = Signal::ALL.iter().copied()
+            .filter(|&signal| matches!(self.actions[signal as usize], Action::User { .. }))
+            .fold(0, |mask, signal| mask | signal.bit());
+        self.pending & !self.blocked & !user
+    }
+}
+
+/// The thread Ctrl-C interrupts, as `ThreadId` plus one, or zero for none.
+static FOREGROUND: AtomicU64 = AtomicU64::new(0);
+
+/// Sends `signal` to the thread `id`.
+pub fn kill(id: ThreadId, signal: Signal) -> Result<(), &'static str> {
+    sched::with_signals(id, |signals| signals.pending |= signal.bit())
+        .ok_or("no such thread")?;
+    sched::wake(id);
+    Ok(())
+}
+
+/// Sets what the calling thread does on `signal`.
+pub fn set_action(signal: Signal, action: Action) -> Result<(), &'static str> {
+    if signal == Signal::Kill {
+        return Err("SIGKILL can't be handled");
+    }
+    current(|signals| signals.actions[signal as usize] = action)
+}
+
+/// The signal state the child of a fork by the calling thread starts with:
+/// its actions, which are never kernel handlers in a process, and its blocked
+/// signals, with none pending.
+pub(crate) fn inherited() -> Signals {
+    current(|signals| Signals { pending: 0, blocked: signals.blocked, actions: signals.actions })
+        .unwrap_or_else(|_| Signals::new())
+}
+
+/// Sets the signals user-mode handlers handle back to their default action,
+/// as their handlers are gone with the program that `exec` replaces.
+pub fn reset_user_handlers() {
+    let _ = current(|signals| {
+        for action in signals.actions.iter_mut() {
+            if let Action::User { .. } = action {
+                *action = Action::Default;
+            }
+        }
+    });
+}
+
+/// Takes the next signal pending on the calling thread that is not blocked and
+/// has a user-mode handler, and blocks it while the handler runs.
+pub fn next_user_signal() -> Option<UserSignal> {
+    current(|signals| {
+        let deliverable = signals.pending & !signals.blocked;
+        Signal::ALL.iter().copied().filter(|signal| deliverable & signal.bit() != 0).find_map(|signal| {
+            match signals.actions[signal as usize] {
+                Action::User { handler, trampoline } => {
+                    let blocked = signals.blocked;
+                    signals.pending &= !signal.bit();
+                    signals.blocked |= signal.bit();
+                    Some(UserSignal { signal, handler, trampoline, blocked })
+                }
+                _ => None,
+            }
+        })
+    }).ok().flatten()
+}
+
+/// Replaces the blocked signals of the calling thread with `mask`, as a
+/// user-mode handler returns. `Signal::Kill` stays unblocked.
+pub fn set_blocked(mask: u32) {
+    let _ = current(|signals| signals.blocked = mask & !Signal::Kill.bit());
+}
+
+/// Keeps `signal` pending until `unblock` is called, instead of handling it.
+pub fn block(signal: Signal) -> Result<(), &'static str> {
+    if signal == Signal::Kill {
+        return Err("SIGKILL can't be blocked");
+    }
+    current(|signals| signals.blocked |= signal.bit())
+}
+
+/// Lets the calling thread handle `signal` again, including when it is already pending.
+pub fn unblock(signal: Signal) -> Result<(), &'static str> {
+    current(|signals| signals.blocked &= !signal.bit())?;
+    deliver();
+    Ok(())
+}
+
+/// Signals pending on the calling thread, blocked ones included.
+pub fn pending() -> impl Iterator<Item = Signal> {
+    let pending = current(|signals| signals.pending).unwrap_or(0);
+    Signal::ALL.iter().copied().filter(move |signal| pending & signal.bit() != 0)
+}
+
+fn current<R>(f: impl FnOnce(&mut Signals) -> R) -> Result<R, &'static str> {
+    let id = sched::current().ok_or("threads are not running yet")?;
+    sched::with_signals(id, f).ok_or("no such thread")
+}
+
+/// Handles the signals pending on the calling thread that are not blocked.
+/// Does nothing with interrupts disabled, as the handlers may block.
+pub fn deliver() {
+    if !interrupts::are_enabled() {
+        return;
+    }
+    loop {
+        let next = current(|signals| {
+            let deliverable = signals.deliverable_in_kernel();
+            let signal = Signal::ALL.iter().copied().find(|signal| deliverable & signal.bit() != 0)?;
+            signals.pending &= !signal.bit();
+            Some((signal, signals.actions[signal as usize]))
+        });
+        let (signal, action) = match next {
+            Ok(Some(next)) => next,
+            _ => return,
+        };
+        match action {
+            Action::Default if signal.ignored_by_default() => {}
+            Action::Default => crate::process::exit(128 + signal as i64),
+            Action::Ignore => {}
+            Action::Handle(handler) => handler(signal),
+            Action::User { .. } => unreachable!("user-mode handlers are left pending"),
+        }
+    }
+}
+
+/// Makes `id` the thread interrupted by Ctrl-C, or nobody.
+pub fn set_foreground(id: Option<ThreadId>) {
+    FOREGROUND.store(id.map_or(0, |id| id.0 + 1), Ordering::Relaxed);
+}
+
+/// Sends `Signal::Interrupt` to the foreground thread. Returns whether there was one.
+pub fn interrupt_foreground() -> bool {
+    match FOREGROUND.load(Ordering::Relaxed) {
+        0 => false,
+        id => kill(ThreadId(id - 1), Signal::Interrupt).is_ok(),
+    }
+}
+
+#[test_case]
+fn test_signals() {
+    use core::sync::atomic::AtomicUsize;
+    use crate::sched::WaitQueue;
+
+    static HANDLED: AtomicUsize = AtomicUsize::new(0);
+    static NEVER: WaitQueue = WaitQueue::new();
+
+    fn count(signal: Signal) {
+        assert_eq!(signal, Signal::User1);
+        HANDLED.fetch_add(1, Ordering::SeqCst);
+    }
+
+    set_action(Signal::User1, Action::Handle(count)).unwrap();
+    block(Signal::User1).unwrap();
+    kill(sched::current().unwrap(), Signal::User1).unwrap();
+    sched::yield_now();
+    assert_eq!(HANDLED.load(Ordering::SeqCst), 0);
+    assert!(pending().eq([Signal::User1].iter().copied()));
+    unblock(Signal::User1).unwrap();
+    assert_eq!(HANDLED.load(Ordering::SeqCst), 1);
+    set_action(Signal::User1, Action::Default).unwrap();
+    assert!(set_action(Signal::Kill, Action::Ignore).is_err());
+
+    // terminated whether it already waits or only starts to
+    let victim = sched::spawn("test-victim", || NEVER.wait_until(|| false)).unwrap();
+    kill(victim, Signal::Terminate).unwrap();
+    sched::join(victim);
+    assert!(kill(ThreadId(u64::MAX), Signal::Kill).is_err());
+}
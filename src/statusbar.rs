@@ -0,0 +1,149 @@
+//! The status bar on the bottom row of the VGA text screen: uptime, heap usage,
+//! current tty, keyboard layout, whether a keyboard macro is being recorded
+//! and the state of CapsLock and NumLock. While booting, the stage that runs
+//! takes the place of the tty and the layout.
+//!
+//! Refreshed once a second from the timer interrupt, and right away when a lock
+//! key changes.
+
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+use crate::arch::interrupts::without_interrupts;
+use crate::{allocator, keyboard, time, vga_buffer};
+
+static CAPS_LOCK: AtomicBool = AtomicBool::new(false);
+static NUM_LOCK: AtomicBool = AtomicBool::new(false);
+static RECORDING: AtomicBool = AtomicBool::new(false);
+static TTY: AtomicUsize = AtomicUsize::new(0);
+static BOOT_STAGE: Mutex<Option<&'static str>> = Mutex::new(None);
+/// Uptime in seconds at the last refresh.
+static LAST_REFRESH: AtomicU64 = AtomicU64::new(u64::MAX);
+
+/// What the status bar shows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Status {
+    pub uptime: Duration,
+    pub heap_used: usize,
+    pub heap_size: usize,
+    pub tty: usize,
+    pub layout: &'static str,
+    pub recording: bool,
+    pub caps_lock: bool,
+    pub num_lock: bool,
+    pub boot_stage: Option<&'static str>,
+}
+
+impl Status {
+    pub fn current() -> Status {
+        Status {
+            uptime: time::uptime(),
+            heap_used: allocator::heap_used(),
+            heap_size: allocator::HEAP_SIZE,
+            tty: TTY.load(Ordering::Relaxed),
+            layout: keyboard::LAYOUT,
+            recording: RECORDING.load(Ordering::Relaxed),
+            caps_lock: CAPS_LOCK.load(Ordering::Relaxed),
+            num_lock: NUM_LOCK.load(Ordering::Relaxed),
+            boot_stage: without_interrupts(|| *BOOT_STAGE.lock()),
+        }
+    }
+}
+
+/// A screen row of text, formatted without the heap.
+struct Row {
+    buf: [u8; 80],
+    len: usize,
+}
+
+impl Write for Row {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let len = s.len().min(self.buf.len() - self.len);
+        self.buf[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+fn format(status: &Status) -> Row {
+    let mut row = Row { buf: [b' '; 80], len: 0 };
+    let seconds = status.uptime.as_secs();
+    let _ = write!(row, " up {:02}:{:02}:{:02} | heap {}K/{}K | ",
+        seconds / 3600, seconds / 60 % 60, seconds % 60,
+        (status.heap_used + 1023) / 1024, status.heap_size / 1024);
+    let _ = match status.boot_stage {
+        Some(stage) => write!(row, "booting: {}...", stage),
+        None => write!(row, "tty{} | {}", status.tty, status.layout),
+    };
+    // macro recording and lock keys on the right
+    row.len = row.len.max(row.buf.len() - 12);
+    let _ = write!(row, "{} {} {}",
+        if status.recording { "REC" } else { "   " },
+        if status.caps_lock { "CAPS" } else { "    " },
+        if status.num_lock { "NUM" } else { "   " });
+    row
+}
+
+/// Draws the status bar.
+pub fn refresh() {
+    let row = format(&Status::current());
+    vga_buffer::draw_status_line(&row.buf);
+}
+
+/// Called from the timer interrupt: refreshes the status bar when the uptime
+/// reaches a new second.
+pub fn tick() {
+    let seconds = time::uptime().as_secs();
+    if LAST_REFRESH.swap(seconds, Ordering::Relaxed) != seconds {
+        refresh();
+    }
+}
+
+/// Records the state of the lock keys, redrawing the status bar if it changed.
+pub fn set_lock_keys(caps_lock: bool, num_lock: bool) {
+    let caps_changed = CAPS_LOCK.swap(caps_lock, Ordering::Relaxed) != caps_lock;
+    let num_changed = NUM_LOCK.swap(num_lock, Ordering::Relaxed) != num_lock;
+    if caps_changed || num_changed {
+        refresh();
+    }
+}
+
+/// Shows or hides the keyboard macro recording indicator.
+pub fn set_recording(recording: bool) {
+    RECORDING.store(recording, Ordering::Relaxed);
+    refresh();
+}
+
+/// Shows the boot stage that runs, or stops showing one.
+pub fn set_boot_stage(stage: Option<&'static str>) {
+    without_interrupts(|| *BOOT_STAGE.lock() = stage);
+    refresh();
+}
+
+/// Sets the number of the tty shown on the console.
+pub fn set_tty(tty: usize) {
+    TTY.store(tty, Ordering::Relaxed);
+    refresh();
+}
+
+#[test_case]
+fn test_status_format() {
+    let status = Status {
+        uptime: Duration::from_secs(3723),
+        heap_used: 12 * 1024 + 1,
+        heap_size: 100 * 1024,
+        tty: 1,
+        layout: "us104",
+        recording: true,
+        caps_lock: true,
+        num_lock: false,
+        boot_stage: None,
+    };
+    let row = format(&status);
+    let text = core::str::from_utf8(&row.buf).unwrap();
+    assert!(text.starts_with(" up 01:02:03 | heap 13K/100K | tty1 | us104 "));
+    assert!(text.ends_with("REC CAPS    "));
+    let row = format(&Status { boot_stage: Some("net"), ..status });
+    assert!(core::str::from_utf8(&row.buf).unwrap().starts_with(" up 01:02:03 | heap 13K/100K | booting: net... "));
+}
@@ -0,0 +1,121 @@
+//! The kernel's symbol table, to name code addresses in backtraces and reports.
+//!
+//! The kernel binary carries no symbol table of its own. Instead, the output
+//! of `nm -n -C` on it is passed in through fw_cfg:
+//!
+//! ```text
+//! nm -n -C target/x86_64-MarOS/debug/MarOS > symbols.txt
+//! -fw_cfg name=opt/maros/symbols,file=symbols.txt
+//! ```
+//!
+//! The text is kept as it is, sorted by address, and searched by bisection,
+//! so it takes no heap memory.
+
+use core::fmt;
+use spin::Once;
+use crate::boot::fw_cfg;
+use crate::memory;
+use crate::initcall;
+
+const FW_CFG_FILE: &str = "opt/maros/symbols";
+/// Largest table loaded.
+const MAX_SIZE: usize = 16 * 1024 * 1024;
+/// Farthest an address is from the symbol it is attributed to. Addresses past
+/// the end of the last function would otherwise all be attributed to it.
+const MAX_OFFSET: u64 = 0x10000;
+
+static TABLE: Once<&'static [u8]> = Once::new();
+
+/// A code address as a symbol and an offset into it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: &'static str,
+    pub offset: u64,
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}+{:#x}", self.name, self.offset)
+    }
+}
+
+/// Loads the symbol table, if one was passed in. Must be called after
+/// `memory::install`.
+pub fn init() -> Result<(), &'static str> {
+    let size = match fw_cfg::file_size(FW_CFG_FILE) {
+        Some(size) => size,
+        None => return Ok(()),
+    };
+    if size > MAX_SIZE {
+        return Err("symbol table too large");
+    }
+    // physically contiguous, so it can be read in one piece through the
+    // physical memory mapping
+    let region = memory::allocate_dma(size as u64)?;
+    let text: &'static mut [u8] = unsafe { core::slice::from_raw_parts_mut(region.virt.as_mut_ptr(), size) };
+    let len = fw_cfg::read_file(FW_CFG_FILE, text).ok_or("symbol table vanished")?;
+    let text: &'static [u8] = text;
+    TABLE.call_once(move || &text[..len]);
+    Ok(())
+}
+initcall!(Core, "symbols", init);
+
+/// Whether a symbol table is loaded.
+pub fn is_loaded() -> bool {
+    TABLE.get().is_some()
+}
+
+/// The symbol `addr` belongs to.
+pub fn resolve(addr: u64) -> Option<Symbol> {
+    lookup(TABLE.get()?, addr)
+}
+
+/// Finds the last symbol at or before `addr` in the `nm -n` output `text`.
+fn lookup(text: &'static [u8], addr: u64) -> Option<Symbol> {
+    let mut best = None;
+    // `lo` is always the start of a line
+    let (mut lo, mut hi) = (0, text.len());
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        let start = text[lo..mid].iter().rposition(|&byte| byte == b'\n').map_or(lo, |at| lo + at + 1);
+        let end = text[start..].iter().position(|&byte| byte == b'\n').map_or(text.len(), |at| start + at);
+        match parse(&text[start..end]) {
+            Some((address, name)) if address <= addr => {
+                best = Some((address, name));
+                lo = end + 1;
+            }
+            Some(_) => hi = start,
+            // undefined symbols have no address and come first
+            None => lo = end + 1,
+        }
+    }
+    let (address, name) = best?;
+    let offset = addr - address;
+    if offset >= MAX_OFFSET {
+        return None;
+    }
+    Some(Symbol { name, offset })
+}
+
+/// The address and name of a line like `ffffffff00201000 T MarOS::init`.
+fn parse(line: &'static [u8]) -> Option<(u64, &'static str)> {
+    let line = core::str::from_utf8(line).ok()?;
+    let mut fields = line.splitn(3, ' ');
+    let address = u64::from_str_radix(fields.next()?, 16).ok()?;
+    let _kind = fields.next()?;
+    Some((address, fields.next()?.trim_end()))
+}
+
+#[test_case]
+fn test_lookup() {
+    let text = b"                 U memcpy\n\
+        0000000000201000 T _start\n\
+        0000000000201010 t MarOS::init\n\
+        0000000000201080 T MarOS::shell::run\n";
+    assert_eq!(lookup(text, 0x201000), Some(Symbol { name: "_start", offset: 0 }));
+    assert_eq!(lookup(text, 0x20107f), Some(Symbol { name: "MarOS::init", offset: 0x6f }));
+    assert_eq!(lookup(text, 0x201100), Some(Symbol { name: "MarOS::shell::run", offset: 0x80 }));
+    assert_eq!(lookup(text, 0x200fff), None);
+    assert_eq!(lookup(text, 0x301080), None);
+    assert_eq!(lookup(b"", 0x1000), None);
+}
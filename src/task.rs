@@ -0,0 +1,131 @@
+//! Cooperative kernel tasks: futures polled by `run_ready`, woken by interrupt
+//! handlers and other tasks through their wakers.
+//!
+//! There is no separate executor loop: `block_on` runs the ready tasks while the
+//! future it waits for is pending, so the shell drives everything spawned.
+
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use spin::Mutex;
+use crate::arch::interrupts;
+use crate::sched::WaitQueue;
+
+pub mod channel;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TaskId(u64);
+
+impl TaskId {
+    fn new() -> TaskId {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+type TaskFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Spawned tasks that are not being polled right now.
+static TASKS: Mutex<BTreeMap<TaskId, TaskFuture>> = Mutex::new(BTreeMap::new());
+/// Tasks woken since they were last polled. Pushed to from interrupt handlers.
+static READY: Mutex<VecDeque<TaskId>> = Mutex::new(VecDeque::new());
+/// Notified whenever a task or a `block_on` future is woken.
+static WAKEUPS: WaitQueue = WaitQueue::new();
+
+struct TaskWaker(TaskId);
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        interrupts::without_interrupts(|| READY.lock().push_back(self.0));
+        WAKEUPS.notify_all();
+    }
+}
+
+/// Starts running `future` as a task.
+pub fn spawn(future: impl Future<Output = ()> + Send + 'static) -> TaskId {
+    let id = TaskId::new();
+    TASKS.lock().insert(id, Box::pin(future));
+    Waker::from(Arc::new(TaskWaker(id))).wake();
+    id
+}
+
+/// Polls every task that was woken, until none is left. Returns whether any
+/// task was polled.
+pub fn run_ready() -> bool {
+    let mut ran = false;
+    loop {
+        let id = match interrupts::without_interrupts(|| READY.lock().pop_front()) {
+            Some(id) => id,
+            None => return ran,
+        };
+        // missing if it finished, or is the task calling us through `block_on`
+        let mut future = match TASKS.lock().remove(&id) {
+            Some(future) => future,
+            None => continue,
+        };
+        ran = true;
+        let waker = Waker::from(Arc::new(TaskWaker(id)));
+        if future.as_mut().poll(&mut Context::from_waker(&waker)).is_pending() {
+            TASKS.lock().insert(id, future);
+        }
+    }
+}
+
+/// Wakes `block_on` by setting a flag.
+struct Flag(AtomicBool);
+
+impl Wake for Flag {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.0.store(true, Ordering::Release);
+        WAKEUPS.notify_all();
+    }
+}
+
+/// Runs `future` to completion, running the ready tasks and blocking the
+/// calling thread while it is pending.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = Box::pin(future);
+    let woken = Arc::new(Flag(AtomicBool::new(true)));
+    let waker = Waker::from(woken.clone());
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        if woken.0.swap(false, Ordering::Acquire) {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+        }
+        if run_ready() {
+            continue;
+        }
+        WAKEUPS.wait_until(|| woken.0.load(Ordering::Acquire) || !READY.lock().is_empty());
+    }
+}
+
+#[test_case]
+fn test_spawned_task_runs() {
+    use core::sync::atomic::AtomicUsize;
+
+    static RUNS: AtomicUsize = AtomicUsize::new(0);
+    let (sender, mut receiver) = channel::channel(1);
+    spawn(async move {
+        RUNS.fetch_add(1, Ordering::SeqCst);
+        let _ = sender.send(7u32).await;
+    });
+    assert_eq!(block_on(receiver.recv()), Some(7));
+    // the sender was dropped when the task finished
+    assert_eq!(block_on(receiver.recv()), None);
+    assert_eq!(RUNS.load(Ordering::SeqCst), 1);
+}
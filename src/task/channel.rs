@@ -0,0 +1,214 @@
+//! A bounded multi-producer, single-consumer channel.
+//!
+//! `try_send` never blocks or allocates, so interrupt handlers can use it; tasks
+//! can also wait for room with `send`. The receiving task waits with `recv`.
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::future::{poll_fn, Future};
+use core::task::{Poll, Waker};
+use spin::Mutex;
+use crate::arch::interrupts;
+
+/// Why `try_send` failed. Holds the value that wasn't sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    Full(T),
+    /// The receiver was dropped.
+    Closed(T),
+}
+
+/// Why `try_recv` returned nothing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    /// The channel is empty and every sender was dropped.
+    Closed,
+}
+
+struct State<T> {
+    queue: VecDeque<T>,
+    senders: usize,
+    receiver_alive: bool,
+    receiver: Option<Waker>,
+    /// Senders waiting for room in `send`.
+    blocked: VecDeque<Waker>,
+}
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    capacity: usize,
+}
+
+impl<T> Shared<T> {
+    /// Locks the state with interrupts disabled, so the lock can also be taken
+    /// by interrupt handlers.
+    fn with<R>(&self, f: impl FnOnce(&mut State<T>) -> R) -> R {
+        interrupts::without_interrupts(|| f(&mut self.state.lock()))
+    }
+}
+
+/// The sending half of a channel. Clone it for more producers.
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+/// The receiving half of a channel.
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+/// Creates a channel holding at most `capacity` values.
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "channel capacity must not be zero");
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            queue: VecDeque::with_capacity(capacity),
+            senders: 1,
+            receiver_alive: true,
+            receiver: None,
+            blocked: VecDeque::new(),
+        }),
+        capacity,
+    });
+    (Sender { shared: shared.clone() }, Receiver { shared })
+}
+
+impl<T> Sender<T> {
+    /// Queues `value` if there is room. Usable from interrupt handlers.
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        self.shared.with(|state| {
+            if !state.receiver_alive {
+                return Err(TrySendError::Closed(value));
+            }
+            if state.queue.len() >= self.shared.capacity {
+                return Err(TrySendError::Full(value));
+            }
+            state.queue.push_back(value);
+            if let Some(waker) = &state.receiver {
+                waker.wake_by_ref();
+            }
+            Ok(())
+        })
+    }
+
+    /// Waits for room and queues `value`. Fails with the value if the receiver
+    /// was dropped.
+    pub fn send(&self, value: T) -> impl Future<Output = Result<(), T>> + '_ {
+        let mut value = Some(value);
+        poll_fn(move |cx| {
+            let result = self.try_send(value.take().expect("send polled after completion"));
+            match result {
+                Ok(()) => Poll::Ready(Ok(())),
+                Err(TrySendError::Closed(v)) => Poll::Ready(Err(v)),
+                Err(TrySendError::Full(v)) => {
+                    // register, unless the receiver made room in the meantime
+                    let full = self.shared.with(|state| {
+                        let full = state.queue.len() >= self.shared.capacity;
+                        if full {
+                            state.blocked.push_back(cx.waker().clone());
+                        }
+                        full
+                    });
+                    if !full {
+                        cx.waker().wake_by_ref();
+                    }
+                    value = Some(v);
+                    Poll::Pending
+                }
+            }
+        })
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Sender<T> {
+        self.shared.with(|state| state.senders += 1);
+        Sender { shared: self.shared.clone() }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.shared.with(|state| {
+            state.senders -= 1;
+            if state.senders == 0 {
+                if let Some(waker) = &state.receiver {
+                    waker.wake_by_ref();
+                }
+            }
+        });
+    }
+}
+
+impl<T> Receiver<T> {
+    /// Takes the oldest value, if any.
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let result = self.shared.with(|state| match state.queue.pop_front() {
+            Some(value) => Ok((value, state.blocked.pop_front())),
+            None if state.senders == 0 => Err(TryRecvError::Closed),
+            None => Err(TryRecvError::Empty),
+        });
+        // wake outside the lock, the waker may be dropped here
+        result.map(|(value, blocked)| {
+            if let Some(waker) = blocked {
+                waker.wake();
+            }
+            value
+        })
+    }
+
+    /// Waits for the next value. Returns `None` once the channel is empty and
+    /// every sender was dropped.
+    pub fn recv(&mut self) -> impl Future<Output = Option<T>> + '_ {
+        poll_fn(move |cx| match self.try_recv() {
+            Ok(value) => Poll::Ready(Some(value)),
+            Err(TryRecvError::Closed) => Poll::Ready(None),
+            Err(TryRecvError::Empty) => {
+                let old = self.shared.with(|state| {
+                    if state.queue.is_empty() && state.senders > 0 {
+                        state.receiver.replace(cx.waker().clone())
+                    } else {
+                        // something changed since try_recv, poll again
+                        cx.waker().wake_by_ref();
+                        None
+                    }
+                });
+                drop(old);
+                Poll::Pending
+            }
+        })
+    }
+
+    /// Drops every queued value.
+    pub fn clear(&mut self) {
+        while self.try_recv().is_ok() {}
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let blocked = self.shared.with(|state| {
+            state.receiver_alive = false;
+            core::mem::take(&mut state.blocked)
+        });
+        blocked.into_iter().for_each(Waker::wake);
+    }
+}
+
+#[test_case]
+fn test_channel_capacity_and_close() {
+    let (sender, mut receiver) = channel(2);
+    assert_eq!(sender.try_send(1), Ok(()));
+    assert_eq!(sender.clone().try_send(2), Ok(()));
+    assert_eq!(sender.try_send(3), Err(TrySendError::Full(3)));
+    assert_eq!(receiver.try_recv(), Ok(1));
+    assert_eq!(receiver.try_recv(), Ok(2));
+    assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+    drop(sender);
+    assert_eq!(receiver.try_recv(), Err(TryRecvError::Closed));
+
+    let (sender, receiver) = channel(1);
+    drop(receiver);
+    assert_eq!(sender.try_send(1), Err(TrySendError::Closed(1)));
+}
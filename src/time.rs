@@ -0,0 +1,398 @@
+use core::arch::x86_64::_rdtsc;
+use core::ops::{Add, Sub};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+use spin::{Mutex, Once};
+use self::wheel::Wheel;
+use crate::arch::interrupts;
+use crate::arch::{self, x86_64::ioport::{self, PortRange}};
+use crate::drivers::pit;
+use crate::initcall;
+
+pub mod vdso;
+mod wheel;
+
+pub use self::wheel::{Callback, TimerId};
+pub use crate::drivers::rtc::DateTime;
+
+/// Input frequency of the programmable interval timer.
+pub const PIT_FREQUENCY: u64 = 1_193_182;
+
+/// Rate of the timer interrupt `init` sets, unless the `hz` option sets
+/// another. The scheduler's time slices last one tick, and timers without
+/// the HPET one-shot expire on a tick, so it matches `TIMER_RESOLUTION`.
+pub const DEFAULT_TICK_RATE: u64 = 1000;
+
+/// PIT input cycles between two timer interrupts. The power-on default
+/// is the slowest, about 18.2 Hz.
+static PIT_DIVISOR: AtomicU64 = AtomicU64::new(pit::MAX_DIVISOR as u64);
+/// The data port of PIT channel 0, claimed by `set_tick_rate`.
+static CHANNEL_0: Once<Option<PortRange>> = Once::new();
+
+/// Length of the PIT one-shot used to calibrate the TSC.
+const CALIBRATION_MS: u64 = 10;
+
+/// TSC frequency in Hz, measured by `init`.
+static TSC_HZ: AtomicU64 = AtomicU64::new(0);
+
+/// Granularity of timers. They expire on the first timer interrupt after their
+/// deadline, so without the HPET one-shot at the resolution of the timer
+/// interrupt.
+pub const TIMER_RESOLUTION: Duration = Duration::from_millis(1);
+
+/// Pending timers, counted in `TIMER_RESOLUTION` ticks.
+static TIMERS: Mutex<Wheel> = Mutex::new(Wheel::new());
+
+/// Calibrates the TSC against PIT channel 2, and sets the timer interrupt to
+/// `DEFAULT_TICK_RATE` or the rate of the `hz` option. `drivers::hpet::init`
+/// measures the TSC again more precisely if there is an HPET.
+///
+/// Must be called once during early boot, before any `Instant` is converted to
+/// a `Duration`.
+pub fn init() {
+    let hz = calibrate_tsc();
+    TSC_HZ.store(hz, Ordering::Relaxed);
+    let rate = crate::boot::cmdline().get("hz").and_then(|hz| hz.parse().ok()).unwrap_or(DEFAULT_TICK_RATE);
+    if let Err(error) = set_tick_rate(rate) {
+        crate::log_warn!("time: timer interrupt left at {} Hz: {}", tick_rate(), error);
+    }
+    let now = timer_tick(Instant::now());
+    interrupts::without_interrupts(|| TIMERS.lock().start_at(now));
+}
+initcall!(Early, "time", || {
+    init();
+    Ok(())
+});
+
+/// Reprograms PIT channel 0 to interrupt `hz` times a second, as close as
+/// its divisor gets. Fails while the HPET drives the timer interrupt, which
+/// takes the rate set before it was set up.
+pub fn set_tick_rate(hz: u64) -> Result<(), &'static str> {
+    if crate::drivers::hpet::drives_tick() {
+        return Err("the HPET drives the timer interrupt");
+    }
+    let divisor = match hz {
+        0 => return Err("tick rate out of range"),
+        hz => (PIT_FREQUENCY + hz / 2) / hz,
+    };
+    if !(1..=u64::from(pit::MAX_DIVISOR)).contains(&divisor) {
+        return Err("tick rate out of range");
+    }
+    let channel = CHANNEL_0.call_once(|| ioport::claim(0x40, 1, "pit channel 0").ok()).as_ref()
+        .ok_or("PIT channel 0 in use")?;
+    pit::start(0, channel, pit::Mode::RateGenerator, divisor as u32)?;
+    PIT_DIVISOR.store(divisor, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Timer interrupts per second, rounded.
+pub fn tick_rate() -> u64 {
+    let divisor = PIT_DIVISOR.load(Ordering::Relaxed);
+    (PIT_FREQUENCY + divisor / 2) / divisor
+}
+
+/// Time between two timer interrupts.
+pub fn tick_period() -> Duration {
+    Duration::from_nanos(PIT_DIVISOR.load(Ordering::Relaxed) * 1_000_000_000 / PIT_FREQUENCY)
+}
+
+/// Replaces the measured TSC frequency with `hz`, from a better clock than the PIT.
+pub fn set_tsc_frequency(hz: u64) {
+    TSC_HZ.store(hz, Ordering::Relaxed);
+    vdso::publish();
+}
+
+/// Returns the calibrated TSC frequency in Hz (0 before `init`).
+pub fn tsc_frequency() -> u64 {
+    TSC_HZ.load(Ordering::Relaxed)
+}
+
+/// Reads the time stamp counter.
+pub fn rdtsc() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Measures how many TSC cycles elapse during a `CALIBRATION_MS` PIT one-shot.
+/// Returns 0 if the PIT is in use.
+fn calibrate_tsc() -> u64 {
+    // channel 2, and the system control port with its gate
+    let (channel, control) = match (ioport::claim(0x42, 1, "pit channel 2"), ioport::claim(0x61, 1, "system control")) {
+        (Ok(channel), Ok(control)) => (channel, control),
+        _ => return 0,
+    };
+    let divisor = PIT_FREQUENCY * CALIBRATION_MS / 1000;
+
+    // enable the channel 2 gate, keep the speaker disconnected
+    let value: u8 = control.read(0);
+    control.write(0, (value & !0x02) | 0x01);
+
+    if pit::start(2, &channel, pit::Mode::InterruptOnTerminalCount, divisor as u32).is_err() {
+        return 0;
+    }
+
+    // restart the count by toggling the gate
+    let value = control.read::<u8>(0) & !0x01;
+    control.write(0, value);
+    control.write(0, value | 0x01);
+
+    let start = rdtsc();
+    // bit 5 mirrors the channel 2 output, which goes high on terminal count
+    while control.read::<u8>(0) & 0x20 == 0 {}
+    let end = rdtsc();
+
+    (end - start) * 1000 / CALIBRATION_MS
+}
+
+/// Time since the CPU was reset, when the TSC started counting.
+pub fn uptime() -> Duration {
+    Instant::now().duration_since(Instant(0))
+}
+
+/// Converts a number of TSC ticks into nanoseconds at the given frequency.
+fn ticks_to_nanos(ticks: u64, hz: u64) -> u64 {
+    if hz == 0 {
+        return 0;
+    }
+    (u128::from(ticks) * 1_000_000_000 / u128::from(hz)) as u64
+}
+
+/// Converts nanoseconds into TSC ticks at the given frequency.
+fn nanos_to_ticks(nanos: u64, hz: u64) -> u64 {
+    (u128::from(nanos) * u128::from(hz) / 1_000_000_000) as u64
+}
+
+/// The timer tick `instant` falls into.
+fn timer_tick(instant: Instant) -> u64 {
+    ticks_to_nanos(instant.0, tsc_frequency()) / TIMER_RESOLUTION.as_nanos() as u64
+}
+
+/// Calls `callback(arg)` from the timer interrupt once `deadline` has passed.
+///
+/// The callback runs with interrupts disabled and must not allocate or block,
+/// like any other interrupt handler code.
+pub fn add_timer(deadline: Instant, callback: Callback, arg: u64) -> TimerId {
+    // rounded up, so it never expires early
+    let resolution = TIMER_RESOLUTION.as_nanos() as u64;
+    let hz = tsc_frequency();
+    let expires = (ticks_to_nanos(deadline.0, hz) + resolution - 1) / resolution;
+    interrupts::without_interrupts(|| {
+        let id = TIMERS.lock().insert(expires, 0, callback, arg);
+        // the start of the tick, which is when the wheel lets the timer expire
+        crate::drivers::hpet::arm(Instant(nanos_to_ticks(expires * resolution, hz) + 1));
+        id
+    })
+}
+
+/// Calls `callback(arg)` from the timer interrupt every `period`, starting one
+/// `period` from now. Expiries that were missed are skipped, not made up for.
+pub fn add_periodic_timer(period: Duration, callback: Callback, arg: u64) -> TimerId {
+    let period = (period.as_nanos() / TIMER_RESOLUTION.as_nanos()).max(1) as u64;
+    let now = timer_tick(Instant::now());
+    interrupts::without_interrupts(|| TIMERS.lock().insert(now + period, period, callback, arg))
+}
+
+/// Stops the timer `id`. Returns false if it already expired.
+pub fn cancel_timer(id: TimerId) -> bool {
+    interrupts::without_interrupts(|| TIMERS.lock().cancel(id))
+}
+
+/// Calls `callback(arg)` from an interrupt handler once the realtime clock
+/// reaches `at`, like the callback of a timer. See `drivers::rtc`.
+pub fn alarm_at(at: DateTime, callback: Callback, arg: u64) -> Result<(), &'static str> {
+    crate::drivers::rtc::alarm_at(at, callback, arg)
+}
+
+/// When the next timer is due, or `None` without timers.
+pub fn next_timer() -> Option<Instant> {
+    let tick = interrupts::without_interrupts(|| TIMERS.lock().next_event())?;
+    Some(Instant(nanos_to_ticks(tick * TIMER_RESOLUTION.as_nanos() as u64, tsc_frequency()) + 1))
+}
+
+/// Set while the periodic tick is stopped.
+static TICK_STOPPED: AtomicBool = AtomicBool::new(false);
+
+/// Stops the periodic timer interrupt for an idle CPU and arms the local APIC
+/// to interrupt when the next timer is due instead, or at the next second of
+/// `uptime` for the status bar. Returns false if the tick has to keep going,
+/// because there is no TSC-deadline timer or a sound is playing.
+///
+/// Must be called with interrupts disabled, and `restart_tick` before anything
+/// but the idle thread runs.
+pub fn stop_tick() -> bool {
+    if !arch::timer::has_deadline() || crate::drivers::speaker::is_playing() {
+        return false;
+    }
+    let second = Duration::from_secs(uptime().as_secs() + 1);
+    let mut deadline = Instant(0) + second;
+    if let Some(timer) = next_timer() {
+        deadline = deadline.min(timer);
+    }
+    arch::timer::set_deadline(deadline);
+    if !TICK_STOPPED.swap(true, Ordering::Relaxed) {
+        interrupts::set_irq_masked(0, true);
+    }
+    true
+}
+
+/// Starts the periodic timer interrupt again after `stop_tick`.
+pub fn restart_tick() {
+    if TICK_STOPPED.swap(false, Ordering::Relaxed) {
+        arch::timer::clear_deadline();
+        interrupts::set_irq_masked(0, false);
+    }
+}
+
+/// Called from the timer interrupt: runs the callbacks of the timers that
+/// expired since the last call.
+pub fn tick() {
+    if tsc_frequency() == 0 {
+        return;
+    }
+    let now = timer_tick(Instant::now());
+    vdso::update_ticks(now);
+    loop {
+        // interrupted while adding or cancelling a timer, the next tick catches up
+        let expired = match TIMERS.try_lock() {
+            Some(mut timers) => {
+                timers.advance(now);
+                timers.pop_expired()
+            }
+            None => return,
+        };
+        match expired {
+            // without the lock, so the callback can add timers
+            Some((callback, arg)) => callback(arg),
+            None => return,
+        }
+    }
+}
+
+/// A measurement of the TSC, usable like `std::time::Instant`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Instant {
+        Instant(rdtsc())
+    }
+
+    /// Returns the raw TSC value of this instant.
+    pub fn ticks(&self) -> u64 {
+        self.0
+    }
+
+    /// The instant at which the TSC read `ticks`.
+    pub fn from_ticks(ticks: u64) -> Instant {
+        Instant(ticks)
+    }
+
+    /// Time elapsed from `earlier` to `self`, or zero if `earlier` is later.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        let ticks = self.0.saturating_sub(earlier.0);
+        Duration::from_nanos(ticks_to_nanos(ticks, tsc_frequency()))
+    }
+
+    /// Time elapsed since this instant was created.
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        let ticks = nanos_to_ticks(duration.as_nanos() as u64, tsc_frequency());
+        self.0.checked_add(ticks).map(Instant)
+    }
+
+    pub fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+        let ticks = nanos_to_ticks(duration.as_nanos() as u64, tsc_frequency());
+        self.0.checked_sub(ticks).map(Instant)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, rhs: Duration) -> Instant {
+        self.checked_add(rhs).expect("overflow when adding duration to instant")
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, rhs: Duration) -> Instant {
+        self.checked_sub(rhs).expect("overflow when subtracting duration from instant")
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, rhs: Instant) -> Duration {
+        self.duration_since(rhs)
+    }
+}
+
+#[test_case]
+fn test_tick_rate() {
+    assert!(set_tick_rate(0).is_err());
+    // below what the 16-bit divisor reaches
+    assert!(set_tick_rate(10).is_err());
+    if crate::drivers::hpet::drives_tick() {
+        return;
+    }
+    let rate = tick_rate();
+    set_tick_rate(100).unwrap();
+    assert_eq!(tick_rate(), 100);
+    assert!((tick_period().as_micros() as i64 - 10_000).abs() < 10);
+    set_tick_rate(rate).unwrap();
+}
+
+#[test_case]
+fn test_tick_conversion() {
+    assert_eq!(ticks_to_nanos(3_000_000_000, 3_000_000_000), 1_000_000_000);
+    assert_eq!(ticks_to_nanos(3, 3_000_000_000), 1);
+    assert_eq!(nanos_to_ticks(1_000, 2_000_000_000), 2_000);
+    assert_eq!(ticks_to_nanos(u64::MAX, 1_000_000_000), u64::MAX);
+}
+
+#[test_case]
+fn test_timer() {
+    static FIRED: AtomicU64 = AtomicU64::new(0);
+    fn fire(arg: u64) {
+        FIRED.fetch_add(arg, Ordering::Relaxed);
+    }
+    let start = Instant::now();
+    add_timer(start + Duration::from_millis(20), fire, 1);
+    let cancelled = add_timer(start + Duration::from_millis(30), fire, 10);
+    assert!(cancel_timer(cancelled));
+    let periodic = add_periodic_timer(Duration::from_millis(50), fire, 100);
+    while FIRED.load(Ordering::Relaxed) < 201 {
+        assert!(start.elapsed() < Duration::from_secs(2));
+        crate::arch::halt();
+    }
+    assert!(start.elapsed() >= Duration::from_millis(100));
+    assert!(cancel_timer(periodic));
+    assert!(!cancel_timer(cancelled));
+}
+
+#[test_case]
+fn test_next_timer() {
+    fn ignore(_: u64) {}
+    let deadline = Instant::now() + Duration::from_secs(60);
+    let timer = add_timer(deadline, ignore, 0);
+    let next = next_timer().unwrap();
+    assert!(next <= deadline + TIMER_RESOLUTION);
+    cancel_timer(timer);
+}
+
+#[test_case]
+fn test_instant_is_monotonic() {
+    let start = Instant::now();
+    for _ in 0..1000 {
+        core::hint::spin_loop();
+    }
+    let end = Instant::now();
+    assert!(end > start);
+    assert!(end - start > Duration::from_nanos(0));
+    assert_eq!(start - end, Duration::from_nanos(0));
+}
@@ -0,0 +1,95 @@
+//! The time page: a read-only page mapped into every process at `ADDRESS`,
+//! from which user code reads the time without a system call.
+//!
+//! The timer interrupt keeps the tick count on it current. The TSC frequency
+//! and the realtime clock's offset change rarely and are published under a
+//! sequence counter: it is odd while they are written, so a reader retries
+//! when it finds it odd or changed after reading them. `maros-ulib`'s `time`
+//! module is such a reader.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Once;
+use x86_64::structures::paging::PhysFrame;
+use crate::{initcall, memory};
+
+/// Where the page is mapped in processes, right after the image window. Not
+/// randomized: it holds no code.
+pub const ADDRESS: u64 = crate::aslr::USER_IMAGE_WINDOW.end;
+
+/// The layout of the page. User code depends on it, so fields are only ever
+/// added at the end.
+#[repr(C)]
+pub struct Data {
+    /// Odd while `tsc_hz` and `realtime_offset` are being changed.
+    pub sequence: AtomicU64,
+    /// `TIMER_RESOLUTION` ticks since the TSC started counting, as of the
+    /// last timer interrupt.
+    pub ticks: AtomicU64,
+    /// Length of a tick in nanoseconds.
+    pub tick_nanos: AtomicU64,
+    /// The TSC frequency in Hz.
+    pub tsc_hz: AtomicU64,
+    /// Nanoseconds since 1970-01-01 00:00:00, as the realtime clock counts
+    /// them, when the TSC started counting.
+    pub realtime_offset: AtomicU64,
+}
+
+static PAGE: Once<PhysFrame> = Once::new();
+
+/// Allocates the page and publishes the time on it. The realtime clock is read
+/// once, so its offset is 0 without one.
+pub fn init() -> Result<(), &'static str> {
+    let frame = memory::allocate_frames(1)?[0];
+    PAGE.call_once(|| frame);
+    publish();
+    Ok(())
+}
+initcall!(Driver, "vdso", init, after: &["rtc"]);
+
+/// The page, to be mapped read-only into processes; `None` before `init`.
+pub fn frame() -> Option<PhysFrame> {
+    PAGE.get().copied()
+}
+
+fn data() -> Option<&'static Data> {
+    let frame = PAGE.get()?;
+    let virt = memory::physical_memory_offset() + frame.start_address().as_u64();
+    Some(unsafe { &*virt.as_ptr::<Data>() })
+}
+
+/// Writes the TSC frequency and the realtime clock's offset to the page.
+pub fn publish() {
+    let data = match data() {
+        Some(data) => data,
+        None => return,
+    };
+    let uptime = super::uptime().as_nanos() as u64;
+    let realtime_offset = match crate::drivers::rtc::now() {
+        Ok(now) => (now.timestamp() * 1_000_000_000).saturating_sub(uptime),
+        Err(_) => 0,
+    };
+    crate::arch::interrupts::without_interrupts(|| {
+        data.sequence.fetch_add(1, Ordering::Release);
+        data.tick_nanos.store(super::TIMER_RESOLUTION.as_nanos() as u64, Ordering::Relaxed);
+        data.tsc_hz.store(super::tsc_frequency(), Ordering::Relaxed);
+        data.realtime_offset.store(realtime_offset, Ordering::Relaxed);
+        data.sequence.fetch_add(1, Ordering::Release);
+    });
+}
+
+/// Called from the timer interrupt with the current tick.
+pub(super) fn update_ticks(ticks: u64) {
+    if let Some(data) = data() {
+        data.ticks.store(ticks, Ordering::Relaxed);
+    }
+}
+
+#[test_case]
+fn test_page() {
+    let data = data().expect("time page not set up");
+    assert_eq!(data.sequence.load(Ordering::Relaxed) % 2, 0);
+    assert_eq!(data.tsc_hz.load(Ordering::Relaxed), super::tsc_frequency());
+    let ticks = data.ticks.load(Ordering::Relaxed);
+    crate::sched::sleep_ms(5);
+    assert!(data.ticks.load(Ordering::Relaxed) > ticks);
+}
@@ -0,0 +1,260 @@
+//! A hierarchical timer wheel: `LEVELS` rings of `SLOTS` lists each. A timer
+//! goes into the level whose slots are just fine enough for how far away it
+//! is, so inserting and cancelling take constant time. Whenever the lower
+//! level wraps around, the timers of the next slot of the level above are
+//! spread over the lower levels again, and the timers of the current slot of
+//! level 0 expire together.
+//!
+//! Timers live in a slab and are linked into their lists by index, so expiring
+//! and cancelling never allocate and can happen in interrupt handlers.
+
+use alloc::vec::Vec;
+
+/// Bits of the tick number each level covers.
+const SLOT_BITS: u32 = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+const LEVELS: usize = 4;
+/// Timers further away than this many ticks wait in the last level and are
+/// placed again when their slot comes up.
+const RANGE: u64 = 1 << (SLOT_BITS * LEVELS as u32);
+/// Index of the list of expired timers, after the lists of the slots.
+const EXPIRED: usize = LEVELS * SLOTS;
+const NIL: usize = usize::MAX;
+
+/// Function called when a timer expires, with the argument it was added with.
+pub type Callback = fn(u64);
+
+/// Names a timer for `Wheel::cancel`. Stays valid until the timer expired or
+/// was cancelled; periodic timers stay valid until they are cancelled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerId {
+    index: usize,
+    generation: u64,
+}
+
+struct Entry {
+    expires: u64,
+    /// Ticks between expiries, zero for a one-shot timer.
+    period: u64,
+    callback: Callback,
+    arg: u64,
+    /// The list the timer is in, `NIL` while it is free.
+    list: usize,
+    prev: usize,
+    next: usize,
+    /// Counts the uses of the entry, so stale `TimerId`s don't match.
+    generation: u64,
+}
+
+pub struct Wheel {
+    entries: Vec<Entry>,
+    /// Unused entries, reused before the slab grows.
+    free: Vec<usize>,
+    /// First timer of every slot list and of the expired list.
+    heads: [usize; EXPIRED + 1],
+    /// The last tick processed by `advance`.
+    now: u64,
+}
+
+impl Wheel {
+    pub const fn new() -> Wheel {
+        Wheel { entries: Vec::new(), free: Vec::new(), heads: [NIL; EXPIRED + 1], now: 0 }
+    }
+
+    /// Starts counting at `tick`, for a wheel without timers.
+    pub fn start_at(&mut self, tick: u64) {
+        self.now = tick;
+    }
+
+    /// Adds a timer calling `callback(arg)` at `expires`, and every `period`
+    /// ticks after that unless `period` is zero.
+    pub fn insert(&mut self, expires: u64, period: u64, callback: Callback, arg: u64) -> TimerId {
+        let index = match self.free.pop() {
+            Some(index) => index,
+            None => {
+                self.entries.push(Entry { expires: 0, period: 0, callback, arg, list: NIL, prev: NIL, next: NIL, generation: 0 });
+                self.entries.len() - 1
+            }
+        };
+        let entry = &mut self.entries[index];
+        entry.expires = expires;
+        entry.period = period;
+        entry.callback = callback;
+        entry.arg = arg;
+        let generation = entry.generation;
+        self.place(index);
+        TimerId { index, generation }
+    }
+
+    /// Removes the timer `id`. Returns false if it already expired or was
+    /// cancelled before.
+    pub fn cancel(&mut self, id: TimerId) -> bool {
+        match self.entries.get(id.index) {
+            Some(entry) if entry.generation == id.generation && entry.list != NIL => {
+                self.unlink(id.index);
+                self.release(id.index);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Processes every tick up to `tick`, moving the timers that expired to
+    /// the list `pop_expired` takes them from.
+    pub fn advance(&mut self, tick: u64) {
+        while self.now < tick {
+            self.now += 1;
+            for level in (1..LEVELS).rev() {
+                if self.now & ((1 << (SLOT_BITS * level as u32)) - 1) == 0 {
+                    let list = self.slot(level, self.now);
+                    self.cascade(list);
+                }
+            }
+            let list = self.slot(0, self.now);
+            self.cascade(list);
+        }
+    }
+
+    /// The next tick `advance` has work to do at: when the first timer expires,
+    /// or earlier when a slot of an upper level has to be spread out. `None`
+    /// without timers.
+    pub fn next_event(&self) -> Option<u64> {
+        if self.heads[EXPIRED] != NIL {
+            return Some(self.now);
+        }
+        (0..LEVELS).filter_map(|level| {
+            let shift = SLOT_BITS * level as u32;
+            (1..=SLOTS as u64)
+                .map(|step| ((self.now >> shift) + step) << shift)
+                .find(|&tick| self.heads[self.slot(level, tick)] != NIL)
+        }).min()
+    }
+
+    /// Takes one expired timer, returning what to call. A periodic timer is
+    /// added again for its next expiry.
+    pub fn pop_expired(&mut self) -> Option<(Callback, u64)> {
+        let index = self.heads[EXPIRED];
+        if index == NIL {
+            return None;
+        }
+        self.unlink(index);
+        let entry = &mut self.entries[index];
+        let expired = (entry.callback, entry.arg);
+        if entry.period == 0 {
+            self.release(index);
+        } else {
+            // skip the expiries that were missed
+            entry.expires = (entry.expires + entry.period).max(self.now + 1);
+            self.place(index);
+        }
+        Some(expired)
+    }
+
+    /// The list of the slot of `level` that `tick` falls into.
+    fn slot(&self, level: usize, tick: u64) -> usize {
+        level * SLOTS + (tick >> (SLOT_BITS * level as u32)) as usize % SLOTS
+    }
+
+    /// Links the entry `index` into the list its expiry time belongs to.
+    fn place(&mut self, index: usize) {
+        let expires = self.entries[index].expires;
+        let list = if expires <= self.now {
+            EXPIRED
+        } else {
+            let delta = expires - self.now;
+            if delta >= RANGE {
+                self.slot(LEVELS - 1, self.now + RANGE - 1)
+            } else {
+                let level = (0..LEVELS).find(|&level| delta < 1 << (SLOT_BITS * (level as u32 + 1))).unwrap();
+                self.slot(level, expires)
+            }
+        };
+        let next = self.heads[list];
+        let entry = &mut self.entries[index];
+        entry.list = list;
+        entry.prev = NIL;
+        entry.next = next;
+        if next != NIL {
+            self.entries[next].prev = index;
+        }
+        self.heads[list] = index;
+    }
+
+    /// Places every timer of `list` again, relative to the current tick.
+    fn cascade(&mut self, list: usize) {
+        let mut index = core::mem::replace(&mut self.heads[list], NIL);
+        while index != NIL {
+            let next = self.entries[index].next;
+            self.place(index);
+            index = next;
+        }
+    }
+
+    fn unlink(&mut self, index: usize) {
+        let (list, prev, next) = {
+            let entry = &self.entries[index];
+            (entry.list, entry.prev, entry.next)
+        };
+        if prev == NIL {
+            self.heads[list] = next;
+        } else {
+            self.entries[prev].next = next;
+        }
+        if next != NIL {
+            self.entries[next].prev = prev;
+        }
+        self.entries[index].list = NIL;
+    }
+
+    fn release(&mut self, index: usize) {
+        self.entries[index].generation += 1;
+        self.free.push(index);
+    }
+}
+
+#[test_case]
+fn test_wheel() {
+    use core::sync::atomic::{AtomicU64, Ordering};
+
+    static FIRED: AtomicU64 = AtomicU64::new(0);
+    fn fire(bit: u64) {
+        FIRED.fetch_or(1 << bit, Ordering::Relaxed);
+    }
+    let run = |wheel: &mut Wheel, tick: u64| {
+        FIRED.store(0, Ordering::Relaxed);
+        wheel.advance(tick);
+        while let Some((callback, arg)) = wheel.pop_expired() {
+            callback(arg);
+        }
+        FIRED.load(Ordering::Relaxed)
+    };
+
+    let mut wheel = Wheel::new();
+    wheel.start_at(1000);
+    wheel.insert(1001, 0, fire, 0);
+    wheel.insert(1064, 0, fire, 1);
+    let cancelled = wheel.insert(1500, 0, fire, 2);
+    wheel.insert(1000 + 5000, 0, fire, 3);
+    let periodic = wheel.insert(1100, 100, fire, 5);
+    wheel.insert(1000 + RANGE + 10, 0, fire, 4);
+    wheel.insert(900, 0, fire, 6);
+
+    assert_eq!(wheel.next_event(), Some(1000));
+    assert_eq!(run(&mut wheel, 1000), 1 << 6);
+    assert_eq!(wheel.next_event(), Some(1001));
+    assert_eq!(run(&mut wheel, 1001), 1 << 0);
+    assert_eq!(run(&mut wheel, 1063), 0);
+    assert_eq!(wheel.next_event(), Some(1064));
+    assert_eq!(run(&mut wheel, 1064), 1 << 1);
+    assert!(wheel.cancel(cancelled));
+    assert!(!wheel.cancel(cancelled));
+    assert_eq!(run(&mut wheel, 1099), 0);
+    assert_eq!(run(&mut wheel, 1100), 1 << 5);
+    assert_eq!(run(&mut wheel, 1250), 1 << 5);
+    assert_eq!(run(&mut wheel, 1300), 1 << 5);
+    assert!(wheel.cancel(periodic));
+    assert_eq!(run(&mut wheel, 5999), 0);
+    assert_eq!(run(&mut wheel, 6000), 1 << 3);
+    // beyond the range, it waits in the last level
+    assert!(wheel.heads[LEVELS * SLOTS - SLOTS..LEVELS * SLOTS].iter().any(|&head| head != NIL));
+}
@@ -0,0 +1,153 @@
+//! `top`: a full-screen system monitor, refreshed every second.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::time::Duration;
+use pc_keyboard::DecodedKey;
+use crate::arch::interrupts::{self, without_interrupts};
+use crate::fs::page_cache;
+use crate::keyboard;
+use crate::memory::{self, FrameStats};
+use crate::sched::{self, CpuUsage, ThreadInfo};
+use crate::time::Instant;
+use crate::tui::{Canvas, Frame, ProgressBar, Renderer, Style, Table, Widget};
+use crate::vga_buffer::WRITER;
+use crate::allocator;
+
+const REFRESH: Duration = Duration::from_secs(1);
+/// How often keys are checked for between refreshes.
+const KEY_POLL: Duration = Duration::from_millis(100);
+
+/// The statistics at one point in time. Rates are computed between two of them.
+struct Sample {
+    time: Instant,
+    usage: CpuUsage,
+    threads: Vec<ThreadInfo>,
+    irqs: [u64; 16],
+    nmis: u64,
+    spurious: u64,
+    heap_used: usize,
+    frames: Option<FrameStats>,
+    cached_pages: usize,
+}
+
+impl Sample {
+    fn take() -> Sample {
+        Sample {
+            time: Instant::now(),
+            usage: sched::cpu_usage(),
+            threads: sched::threads(),
+            irqs: interrupts::irq_counts(),
+            nmis: interrupts::nmi_count(),
+            spurious: interrupts::spurious_count(),
+            heap_used: allocator::heap_used(),
+            frames: memory::frame_stats(),
+            cached_pages: page_cache::cached_pages(),
+        }
+    }
+}
+
+/// Shows the monitor until `q`, Esc or Ctrl-C is pressed.
+pub fn run() {
+    let _grab = keyboard::grab();
+    without_interrupts(|| WRITER.lock().suspend());
+    let mut canvas = Canvas::screen();
+    let mut renderer = Renderer::new();
+    let mut previous = Sample::take();
+    let mut current = Sample::take();
+    'refresh: loop {
+        canvas.clear();
+        draw(&mut canvas, &previous, &current);
+        renderer.render(&canvas);
+        let deadline = current.time + REFRESH;
+        while Instant::now() < deadline {
+            while let Some(press) = keyboard::try_read_key() {
+                if let DecodedKey::Unicode('q') | DecodedKey::Unicode('\u{1b}') | DecodedKey::Unicode('\u{3}') = press.key {
+                    break 'refresh;
+                }
+            }
+            sched::sleep(KEY_POLL);
+        }
+        previous = core::mem::replace(&mut current, Sample::take());
+    }
+    without_interrupts(|| WRITER.lock().resume());
+}
+
+/// Draws the statistics of `current`, with the rates since `previous`.
+fn draw(canvas: &mut Canvas, previous: &Sample, current: &Sample) {
+    let area = canvas.area();
+    Frame { title: "top - q to quit", style: Style::NORMAL, double: false }.draw(canvas, area);
+    let inner = area.inner();
+    let elapsed = current.time.duration_since(previous.time).as_micros().max(1);
+
+    let busy = current.usage.busy.saturating_sub(previous.usage.busy);
+    let idle = current.usage.idle.saturating_sub(previous.usage.idle);
+    let (cpu, rest) = inner.split_rows(1);
+    ProgressBar { label: "cpu   ", value: busy.as_micros() as u64, max: (busy + idle).as_micros() as u64, style: Style::NORMAL }
+        .draw(canvas, cpu);
+
+    let (heap, rest) = rest.split_rows(1);
+    let label = format!("heap   {:>5}K/{}K", (current.heap_used + 1023) / 1024, allocator::HEAP_SIZE / 1024);
+    ProgressBar { label: &label, value: current.heap_used as u64, max: allocator::HEAP_SIZE as u64, style: Style::NORMAL }
+        .draw(canvas, heap);
+
+    let (frames, rest) = rest.split_rows(1);
+    let stats = current.frames.unwrap_or(FrameStats { total: 0, allocated: 0 });
+    let label = format!("frames {:>5}/{} ({} cached)", stats.allocated, stats.total, current.cached_pages);
+    ProgressBar { label: &label, value: stats.allocated as u64, max: stats.total as u64, style: Style::NORMAL }
+        .draw(canvas, frames);
+
+    let (irqs, rest) = rest.split_rows(2);
+    let rates: Vec<String> = current.irqs.iter().zip(previous.irqs.iter()).enumerate()
+        .filter(|(_, (now, before))| now > before)
+        .map(|(irq, (now, before))| format!("{}:{}", irq, (now - before) as u128 * 1_000_000 / elapsed))
+        .collect();
+    let mut totals = String::new();
+    if current.nmis > 0 {
+        totals += &format!("  nmi total {}", current.nmis);
+    }
+    if current.spurious > 0 {
+        totals += &format!("  spurious total {}", current.spurious);
+    }
+    canvas.print(irqs, 0, &format!("irq/s  {}{}", rates.join(" "), totals), Style::NORMAL);
+
+    let rows: Vec<Vec<String>> = current.threads.iter().map(|thread| {
+        let before = previous.threads.iter().find(|before| before.id == thread.id)
+            .map_or(Duration::ZERO, |before| before.cpu_time);
+        let cpu = thread.cpu_time.saturating_sub(before).as_micros() * 100 / elapsed;
+        let stack = if thread.stack_size == 0 {
+            String::from("-")
+        } else {
+            format!("{}/{}/{}", thread.stack_used, thread.stack_peak, thread.stack_size)
+        };
+        Vec::from([
+            format!("{:>4}", thread.id.0),
+            String::from(thread.name),
+            String::from(thread.state.name()),
+            format!("{}{}", thread.priority.name(), if thread.boosted { "+" } else { "" }),
+            format!("{:>4}", cpu.min(100)),
+            stack,
+        ])
+    }).collect();
+    Table {
+        headers: &["  ID", "NAME", "STATE", "PRIORITY", "CPU%", "STACK/PEAK/SIZE"],
+        widths: &[4, 12, 8, 9, 4, 17],
+        rows: &rows,
+        header_style: Style::INVERSE,
+        style: Style::NORMAL,
+    }.draw(canvas, rest);
+}
+
+#[test_case]
+fn test_draw() {
+    let previous = Sample::take();
+    let current = Sample::take();
+    let mut canvas = Canvas::screen();
+    draw(&mut canvas, &previous, &current);
+    let row = |row: usize| -> Vec<u8> { (0..canvas.area().width).map(|col| canvas.get(row, col).unwrap().byte).collect() };
+    assert!(row(0).starts_with(b"\xda top - q to quit "));
+    assert!(row(1).starts_with(b"\xb3cpu "));
+    assert!(row(6).starts_with(b"\xb3  ID NAME"));
+    assert!((7..23).any(|line| row(line).windows(4).any(|name| name == b"idle")));
+}
@@ -0,0 +1,361 @@
+//! Widgets for full-screen programs on the text console: bordered boxes,
+//! progress bars, tables and menus.
+//!
+//! Widgets draw into a `Canvas`, an off-screen copy of the text rows. A
+//! `Renderer` then puts the canvas on the screen, drawing only the cells that
+//! changed since its previous frame. As with the editor, the program must
+//! suspend the `Writer` while it owns the screen.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use crate::vga_buffer::{self, cp437, Color, BUFFER_WIDTH, TEXT_HEIGHT};
+
+/// Colors of a cell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Style {
+    pub foreground: Color,
+    pub background: Color,
+}
+
+impl Style {
+    pub const NORMAL: Style = Style::new(Color::White, Color::Black);
+    pub const INVERSE: Style = Style::new(Color::Black, Color::LightGray);
+
+    pub const fn new(foreground: Color, background: Color) -> Style {
+        Style { foreground, background }
+    }
+}
+
+/// A character of the code page 437 font with its colors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cell {
+    pub byte: u8,
+    pub style: Style,
+}
+
+/// A rectangle of cells.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub row: usize,
+    pub col: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Rect {
+    pub fn new(row: usize, col: usize, width: usize, height: usize) -> Rect {
+        Rect { row, col, width, height }
+    }
+
+    /// The rectangle inside a one cell border.
+    pub fn inner(&self) -> Rect {
+        Rect::new(self.row + 1, self.col + 1, self.width.saturating_sub(2), self.height.saturating_sub(2))
+    }
+
+    /// Splits off the first `rows` rows, returning them and the rest.
+    pub fn split_rows(&self, rows: usize) -> (Rect, Rect) {
+        let rows = rows.min(self.height);
+        (Rect::new(self.row, self.col, self.width, rows),
+         Rect::new(self.row + rows, self.col, self.width, self.height - rows))
+    }
+
+    /// Splits off the first `cols` columns, returning them and the rest.
+    pub fn split_cols(&self, cols: usize) -> (Rect, Rect) {
+        let cols = cols.min(self.width);
+        (Rect::new(self.row, self.col, cols, self.height),
+         Rect::new(self.row, self.col + cols, self.width - cols, self.height))
+    }
+}
+
+/// Cells drawn off screen.
+pub struct Canvas {
+    width: usize,
+    height: usize,
+    cells: Vec<Cell>,
+}
+
+impl Canvas {
+    pub fn new(width: usize, height: usize) -> Canvas {
+        Canvas { width, height, cells: vec![Cell { byte: b' ', style: Style::NORMAL }; width * height] }
+    }
+
+    /// A canvas the size of the text rows of the screen.
+    pub fn screen() -> Canvas {
+        Canvas::new(BUFFER_WIDTH, TEXT_HEIGHT)
+    }
+
+    pub fn area(&self) -> Rect {
+        Rect::new(0, 0, self.width, self.height)
+    }
+
+    pub fn get(&self, row: usize, col: usize) -> Option<Cell> {
+        if row < self.height && col < self.width {
+            Some(self.cells[row * self.width + col])
+        } else {
+            None
+        }
+    }
+
+    /// Sets a cell. Cells outside the canvas are ignored.
+    pub fn put(&mut self, row: usize, col: usize, byte: u8, style: Style) {
+        if row < self.height && col < self.width {
+            self.cells[row * self.width + col] = Cell { byte, style };
+        }
+    }
+
+    pub fn fill(&mut self, area: Rect, byte: u8, style: Style) {
+        for row in area.row..area.row + area.height {
+            for col in area.col..area.col + area.width {
+                self.put(row, col, byte, style);
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.fill(self.area(), b' ', Style::NORMAL);
+    }
+
+    /// Writes `text` on row `line` of `area`, cut off at its right edge.
+    /// Returns the number of cells written.
+    pub fn print(&mut self, area: Rect, line: usize, text: &str, style: Style) -> usize {
+        if line >= area.height {
+            return 0;
+        }
+        let mut written = 0;
+        for (col, c) in (area.col..area.col + area.width).zip(text.chars()) {
+            self.put(area.row + line, col, cp437::encode(c), style);
+            written += 1;
+        }
+        written
+    }
+}
+
+/// Something that draws itself into an area of a canvas.
+pub trait Widget {
+    fn draw(&self, canvas: &mut Canvas, area: Rect);
+}
+
+/// A border around an area, with a title in its top edge.
+pub struct Frame<'a> {
+    pub title: &'a str,
+    pub style: Style,
+    /// Double rather than single lines.
+    pub double: bool,
+}
+
+impl Widget for Frame<'_> {
+    fn draw(&self, canvas: &mut Canvas, area: Rect) {
+        if area.width < 2 || area.height < 2 {
+            return;
+        }
+        // horizontal, vertical and the corners clockwise from the top left
+        let [horizontal, vertical, top_left, top_right, bottom_right, bottom_left] = if self.double {
+            [0xcd, 0xba, 0xc9, 0xbb, 0xbc, 0xc8]
+        } else {
+            [0xc4, 0xb3, 0xda, 0xbf, 0xd9, 0xc0]
+        };
+        let (top, bottom) = (area.row, area.row + area.height - 1);
+        let (left, right) = (area.col, area.col + area.width - 1);
+        for col in left + 1..right {
+            canvas.put(top, col, horizontal, self.style);
+            canvas.put(bottom, col, horizontal, self.style);
+        }
+        for row in top + 1..bottom {
+            canvas.put(row, left, vertical, self.style);
+            canvas.put(row, right, vertical, self.style);
+        }
+        canvas.put(top, left, top_left, self.style);
+        canvas.put(top, right, top_right, self.style);
+        canvas.put(bottom, right, bottom_right, self.style);
+        canvas.put(bottom, left, bottom_left, self.style);
+        if !self.title.is_empty() && area.width > 4 {
+            let title = Rect::new(top, left + 1, area.width - 2, 1);
+            canvas.print(title, 0, &format!(" {} ", self.title), self.style);
+        }
+    }
+}
+
+/// A bar filled to `value` out of `max`, after a label and followed by the
+/// percentage.
+pub struct ProgressBar<'a> {
+    pub label: &'a str,
+    pub value: u64,
+    pub max: u64,
+    pub style: Style,
+}
+
+impl ProgressBar<'_> {
+    pub fn percent(&self) -> u64 {
+        if self.max == 0 {
+            return 0;
+        }
+        (u128::from(self.value.min(self.max)) * 100 / u128::from(self.max)) as u64
+    }
+}
+
+impl Widget for ProgressBar<'_> {
+    fn draw(&self, canvas: &mut Canvas, area: Rect) {
+        let label = if self.label.is_empty() { String::new() } else { format!("{} ", self.label) };
+        let label_len = canvas.print(area, 0, &label, self.style);
+        let percent = format!("{:>4}%", self.percent());
+        let bar = area.width.saturating_sub(label_len + percent.len());
+        let filled = if self.max == 0 {
+            0
+        } else {
+            (u128::from(self.value.min(self.max)) * bar as u128 / u128::from(self.max)) as usize
+        };
+        for i in 0..bar {
+            // a full block and light shade
+            canvas.put(area.row, area.col + label_len + i, if i < filled { 0xdb } else { 0xb0 }, self.style);
+        }
+        let (_, rest) = area.split_cols(label_len + bar);
+        canvas.print(rest, 0, &percent, self.style);
+    }
+}
+
+/// Rows of text in columns, under a header row.
+pub struct Table<'a> {
+    pub headers: &'a [&'a str],
+    /// Width of each column; cells are cut off to it.
+    pub widths: &'a [usize],
+    pub rows: &'a [Vec<String>],
+    pub header_style: Style,
+    pub style: Style,
+}
+
+impl Widget for Table<'_> {
+    fn draw(&self, canvas: &mut Canvas, area: Rect) {
+        let lines = core::iter::once((self.header_style, self.headers.to_vec()))
+            .chain(self.rows.iter().map(|row| (self.style, row.iter().map(String::as_str).collect())));
+        for (line, (style, cells)) in lines.take(area.height).enumerate() {
+            canvas.fill(Rect::new(area.row + line, area.col, area.width, 1), b' ', style);
+            let mut rest = area;
+            for (cell, &width) in cells.iter().zip(self.widths) {
+                let (column, after) = rest.split_cols(width);
+                canvas.print(column, line, cell, style);
+                rest = after.split_cols(1).1;
+            }
+        }
+    }
+}
+
+/// A list of items with one selected, scrolled to keep it visible.
+pub struct Menu<'a> {
+    pub items: &'a [&'a str],
+    pub selected: usize,
+    pub style: Style,
+    pub selected_style: Style,
+}
+
+impl Widget for Menu<'_> {
+    fn draw(&self, canvas: &mut Canvas, area: Rect) {
+        let first = (self.selected + 1).saturating_sub(area.height);
+        for (line, (index, item)) in self.items.iter().enumerate().skip(first).take(area.height).enumerate() {
+            let style = if index == self.selected { self.selected_style } else { self.style };
+            canvas.fill(Rect::new(area.row + line, area.col, area.width, 1), b' ', style);
+            let marker = if index == self.selected { "\u{25ba} " } else { "  " };
+            canvas.print(area, line, &format!("{}{}", marker, item), style);
+        }
+    }
+}
+
+/// Puts canvases on the screen, drawing only what changed since the last one.
+#[derive(Default)]
+pub struct Renderer {
+    /// The cells on the screen, `None` if they are unknown.
+    previous: Option<Vec<Cell>>,
+}
+
+impl Renderer {
+    pub fn new() -> Renderer {
+        Renderer::default()
+    }
+
+    /// Makes the next `render` draw every cell, e.g. after something else drew
+    /// on the screen.
+    pub fn invalidate(&mut self) {
+        self.previous = None;
+    }
+
+    /// Draws `canvas` at the top left of the screen. Returns the number of
+    /// cells drawn.
+    pub fn render(&mut self, canvas: &Canvas) -> usize {
+        self.damage(canvas, |row, col, cells| {
+            let bytes: Vec<u8> = cells.iter().map(|cell| cell.byte).collect();
+            vga_buffer::draw_text(row, col, &bytes, cells[0].style.foreground, cells[0].style.background);
+        })
+    }
+
+    /// Calls `draw` with the runs of changed cells of the same style, and
+    /// remembers `canvas` as what is on the screen.
+    fn damage(&mut self, canvas: &Canvas, mut draw: impl FnMut(usize, usize, &[Cell])) -> usize {
+        let previous = self.previous.take().filter(|previous| previous.len() == canvas.cells.len());
+        let mut drawn = 0;
+        for (row, cells) in canvas.cells.chunks(canvas.width).enumerate() {
+            let mut col = 0;
+            while col < cells.len() {
+                let changed = |col: usize| previous.as_ref().map_or(true, |previous| previous[row * canvas.width + col] != cells[col]);
+                if !changed(col) {
+                    col += 1;
+                    continue;
+                }
+                let start = col;
+                while col < cells.len() && changed(col) && cells[col].style == cells[start].style {
+                    col += 1;
+                }
+                draw(row, start, &cells[start..col]);
+                drawn += col - start;
+            }
+        }
+        self.previous = Some(canvas.cells.clone());
+        drawn
+    }
+}
+
+#[test_case]
+fn test_widgets() {
+    let mut canvas = Canvas::new(20, 6);
+    let area = canvas.area();
+    Frame { title: "cpu", style: Style::NORMAL, double: false }.draw(&mut canvas, area);
+    assert_eq!(canvas.get(0, 0).map(|cell| cell.byte), Some(0xda));
+    assert_eq!(canvas.get(5, 19).map(|cell| cell.byte), Some(0xd9));
+    assert_eq!(canvas.get(0, 2).map(|cell| cell.byte), Some(b'c'));
+
+    let inner = area.inner();
+    ProgressBar { label: "a", value: 1, max: 2, style: Style::NORMAL }.draw(&mut canvas, inner);
+    // "a " then a bar of 11 cells, 5 of them filled, then "  50%"
+    let row: Vec<u8> = (1..19).map(|col| canvas.get(1, col).unwrap().byte).collect();
+    assert_eq!(&row[..7], b"a \xdb\xdb\xdb\xdb\xdb");
+    assert_eq!(&row[7..13], &[0xb0; 6]);
+    assert_eq!(&row[13..], b"  50%");
+
+    let rows = [vec![String::from("1"), String::from("idle thread")]];
+    let (_, below) = inner.split_rows(1);
+    Table { headers: &["ID", "NAME"], widths: &[2, 4], rows: &rows, header_style: Style::INVERSE, style: Style::NORMAL }
+        .draw(&mut canvas, below);
+    assert_eq!(canvas.get(2, 4).map(|cell| (cell.byte, cell.style)), Some((b'N', Style::INVERSE)));
+    assert_eq!(canvas.get(3, 7).map(|cell| cell.byte), Some(b'e'));
+    assert_eq!(canvas.get(3, 8).map(|cell| cell.byte), Some(b' '));
+
+    Menu { items: &["one", "two", "three"], selected: 2, style: Style::NORMAL, selected_style: Style::INVERSE }
+        .draw(&mut canvas, Rect::new(0, 0, 10, 2));
+    assert_eq!(canvas.get(1, 0).map(|cell| (cell.byte, cell.style)), Some((0x10, Style::INVERSE)));
+    assert_eq!(canvas.get(0, 2).map(|cell| cell.byte), Some(b't'));
+}
+
+#[test_case]
+fn test_renderer_damage() {
+    let mut canvas = Canvas::new(8, 2);
+    let mut renderer = Renderer::new();
+    assert_eq!(renderer.damage(&canvas, |_, _, _| {}), 16);
+    assert_eq!(renderer.damage(&canvas, |_, _, _| {}), 0);
+    canvas.print(canvas.area(), 1, "ab", Style::NORMAL);
+    canvas.put(1, 5, b'x', Style::INVERSE);
+    let mut runs = Vec::new();
+    assert_eq!(renderer.damage(&canvas, |row, col, cells| runs.push((row, col, cells.len()))), 3);
+    assert_eq!(runs, [(1, 0, 2), (1, 5, 1)]);
+    renderer.invalidate();
+    assert_eq!(renderer.damage(&canvas, |_, _, _| {}), 16);
+}
@@ -1,29 +1,28 @@
+use alloc::collections::VecDeque;
 use alloc::string::String;
 use core::fmt;
 use core::ops::{Deref, DerefMut};
 use lazy_static::lazy_static;
 use spin::Mutex;
 use volatile::Volatile;
-use x86_64::instructions::interrupts::without_interrupts;
+#[cfg(test)]
+use crate::arch::interrupts::without_interrupts;
 use crate::vga_buffer::Color::{Black, Blue, Brown, Cyan, DarkGray, Green, LightBlue, LightCyan, LightGray, LightGreen, LightRed, Magenta, Pink, Red, White, Yellow};
+use crate::vga_buffer::theme::Theme;
+use crate::vga_buffer::writer::{Damage, Line, Selection};
+pub mod cp437;
+pub mod theme;
 pub mod writer;
 
 lazy_static! {
     /// A global `Writer` instance that can be used for printing to the VGA text buffer.
     ///
-    /// Used by the `print!` and `println!` macros.
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
-        column_position: 0,
-        row_position: 0,
-        color_code: ColorCode::new(Color::White, Color::Black),
-        buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
-        clipboard: String::new()
-    });
+    /// The `print!` and `println!` macros don't lock it themselves, see `console`.
+    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer::new(
+        unsafe { &mut *(0xb8000 as *mut Buffer) },
+    ));
 }
 
-const CURSOR: ScreenChar = ScreenChar { ascii_character: 0, color_code: ColorCode::new(Black, LightCyan) };
-const EMPTY: ScreenChar = ScreenChar { ascii_character: 0, color_code: ColorCode::new(White, Black) };
-
 /// The standard color palette in VGA text mode.
 #[allow(dead_code)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -81,8 +80,10 @@ impl ColorCode {
     pub const fn new(foreground: Color, background: Color) -> ColorCode {
         ColorCode((background as u8) << 4 | (foreground as u8))
     }
-    pub fn new_from(code: u8) -> ColorCode {
-        ColorCode(code)
+
+    /// The same colors, with the foreground and the background swapped.
+    const fn inverted(self) -> ColorCode {
+        ColorCode(self.0 << 4 | self.0 >> 4)
     }
 }
 
@@ -109,9 +110,11 @@ impl DerefMut for ScreenChar {
 }
 
 /// The height of the text buffer (normally 25 lines).
-const BUFFER_HEIGHT: usize = 25;
+pub const BUFFER_HEIGHT: usize = 25;
 /// The width of the text buffer (normally 80 columns).
-const BUFFER_WIDTH: usize = 80;
+pub const BUFFER_WIDTH: usize = 80;
+/// Rows the `Writer` uses. The last one belongs to the status bar.
+pub const TEXT_HEIGHT: usize = BUFFER_HEIGHT - 1;
 
 /// A structure representing the VGA text buffer.
 #[repr(transparent)]
@@ -121,17 +124,43 @@ struct Buffer {
 
 /// A writer type that allows writing ASCII bytes and strings to an underlying `Buffer`.
 ///
-/// Wraps lines at `BUFFER_WIDTH`. Supports newline characters and implements the
+/// The text is kept as logical lines on the heap, wrapped at `BUFFER_WIDTH` (at
+/// word boundaries in word-wrap mode) when they are rendered. Lines that scroll
+/// off the screen are dropped. Until the heap is initialized, output goes
+/// straight to the screen. Supports newline characters and implements the
 /// `core::fmt::Write` trait.
 pub struct Writer {
-    column_position: usize,
-    row_position: usize,
-    color_code: ColorCode,
+    lines: VecDeque<Line>,
+    /// Logical line of the cursor.
+    line: usize,
+    /// Index of the cursor in its line.
+    column: usize,
+    /// Rows of the first line that are above the screen.
+    top: usize,
+    word_wrap: bool,
+    /// What has to be rendered again at the next flush.
+    damage: Damage,
+    /// Screen position and original content of the cell the cursor is drawn on.
+    cursor: Option<(usize, usize, ScreenChar)>,
+    /// The text being selected with Shift and the arrow keys, or the mouse.
+    selection: Option<Selection>,
+    /// Screen position of the mouse pointer, if it is shown.
+    pointer: Option<(usize, usize)>,
+    /// Screen position and original content of the cell the pointer is drawn on.
+    under_pointer: Option<(usize, usize, ScreenChar)>,
+    /// Cursor position of the early console used before the heap exists.
+    early: (usize, usize),
+    /// Set while a full-screen program owns the text rows.
+    suspended: bool,
+    theme: &'static Theme,
+    /// Whether text is written in the error color, see `theme::ERROR_START`.
+    error: bool,
     buffer: &'static mut Buffer,
     clipboard: String
 }
 
-/// Like the `print!` macro in the standard library, but prints to the VGA text buffer.
+/// Like the `print!` macro in the standard library, but prints to the VGA text
+/// buffer. Characters that are not ASCII are shown as in `cp437::encode`.
 #[macro_export]
 macro_rules! print {
     ($($arg:tt)*) => ($crate::vga_buffer::_print(format_args!($($arg)*)));
@@ -144,13 +173,51 @@ macro_rules! println {
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
 
-/// Prints the given formatted string to the VGA text buffer through the global `WRITER` instance.
+/// Like `println!`, but in the error color of the theme.
+#[macro_export]
+macro_rules! eprintln {
+    ($($arg:tt)*) => ($crate::print!("\x0e{}\x0f\n", format_args!($($arg)*)));
+}
+
+/// Prints the given formatted string to the VGA text buffer through the console.
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
-    use core::fmt::Write;
-    without_interrupts(|| {
-        WRITER.lock().write_fmt(args).unwrap();
-    });
+    crate::console::print(args);
+}
+
+/// Draws `text` on the bottom row, reserved for the status bar, padding it
+/// with blanks. Doesn't take the `WRITER` lock, so it is usable from interrupts.
+pub(crate) fn draw_status_line(text: &[u8]) {
+    let color_code = ColorCode::new(Black, LightGray);
+    for col in 0..BUFFER_WIDTH {
+        let ascii_character = text.get(col).copied().unwrap_or(b' ');
+        let cell = 0xb8000 + (TEXT_HEIGHT * BUFFER_WIDTH + col) * core::mem::size_of::<ScreenChar>();
+        unsafe { core::ptr::write_volatile(cell as *mut ScreenChar, ScreenChar { ascii_character, color_code }) };
+    }
+}
+
+/// Draws `text` at `row` and `col` of the text rows, clipped at the right edge.
+///
+/// For full-screen programs, which suspend the `Writer` while they draw.
+pub fn draw_text(row: usize, col: usize, text: &[u8], foreground: Color, background: Color) {
+    if row >= TEXT_HEIGHT {
+        return;
+    }
+    let color_code = ColorCode::new(foreground, background);
+    for (col, &ascii_character) in (col..BUFFER_WIDTH).zip(text) {
+        let cell = 0xb8000 + (row * BUFFER_WIDTH + col) * core::mem::size_of::<ScreenChar>();
+        unsafe { core::ptr::write_volatile(cell as *mut ScreenChar, ScreenChar { ascii_character, color_code }) };
+    }
+}
+
+/// The character and the foreground and background colors shown at `row` and
+/// `col` of the screen, the status bar being the last row. Doesn't take the
+/// `WRITER` lock.
+pub fn read_cell(row: usize, col: usize) -> (u8, Color, Color) {
+    assert!(row < BUFFER_HEIGHT && col < BUFFER_WIDTH, "cell off the screen");
+    let cell = 0xb8000 + (row * BUFFER_WIDTH + col) * core::mem::size_of::<ScreenChar>();
+    let sc = unsafe { core::ptr::read_volatile(cell as *const ScreenChar) };
+    (sc.ascii_character, Color::from(sc.color_code.0 & 0xf), Color::from(sc.color_code.0 >> 4))
 }
 
 #[test_case]
@@ -174,8 +241,116 @@ fn test_println_output() {
         let mut writer = WRITER.lock();
         writeln!(writer, "\n{}", s).expect("writing failed");
         for (i, c) in s.chars().enumerate() {
-            let screen_char = writer.buffer.chars[BUFFER_HEIGHT - 2][i].read();
+            let screen_char = writer.buffer.chars[TEXT_HEIGHT - 2][i].read();
             assert_eq!(char::from(screen_char.ascii_character), c);
         }
     });
 }
+
+#[test_case]
+fn test_edit_wrapped_line() {
+    use alloc::string::ToString;
+    use core::fmt::Write;
+    let s = "0123456789".repeat(12);
+    without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        write!(writer, "\n{}", s).expect("writing failed");
+        for _ in 0..100 {
+            writer.move_left();
+        }
+        write!(writer, "ab").expect("writing failed");
+        let mut expected = s.clone();
+        expected.insert_str(20, "ab");
+        assert_eq!(writer.current_line(), expected);
+        // the line continues on the last row of the screen
+        let screen_char = writer.buffer.chars[TEXT_HEIGHT - 1][120 + 2 - BUFFER_WIDTH - 1].read();
+        assert_eq!(char::from(screen_char.ascii_character), '9');
+        writeln!(writer).expect("writing failed");
+        assert_eq!(writer.current_line(), s[20..].to_string());
+    });
+}
+
+#[test_case]
+fn test_selection_copy_paste() {
+    use alloc::string::ToString;
+    use core::fmt::Write;
+    use writer::SelectionMode;
+    without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        write!(writer, "\nfirst line\nsecond").expect("writing failed");
+        writer.start_selection(SelectionMode::Stream);
+        for _ in 0..9 {
+            writer.move_left();
+        }
+        assert_eq!(writer.selected_text(), Some("ne\nsecond".to_string()));
+        writer.start_selection(SelectionMode::Rectangle);
+        assert_eq!(writer.selected_text(), Some("li\n".to_string()));
+        writer.start_selection(SelectionMode::Stream);
+        writer.copy();
+        writer.clear_selection();
+        assert_eq!(writer.selected_text(), None);
+
+        writer.move_down();
+        writeln!(writer).expect("writing failed");
+        writer.paste();
+        assert_eq!(writer.current_line(), "second");
+        writer.move_up();
+        assert_eq!(writer.current_line(), "ne");
+    });
+}
+
+#[test_case]
+fn test_mouse_selection() {
+    use alloc::string::ToString;
+    use core::fmt::Write;
+    without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        write!(writer, "\nfirst line\nsecond").expect("writing failed");
+        writer.click(TEXT_HEIGHT - 2, 6);
+        writer.drag_to(TEXT_HEIGHT - 1, 3);
+        assert_eq!(writer.selected_text(), Some("line\nsec".to_string()));
+        // past the end of the line
+        writer.click(TEXT_HEIGHT - 2, 6);
+        writer.drag_to(TEXT_HEIGHT - 2, 40);
+        writer.copy_selection();
+        writer.click(TEXT_HEIGHT - 1, 40);
+        assert_eq!(writer.selected_text(), None);
+        writer.paste();
+        assert_eq!(writer.current_line(), "secondline");
+
+        writer.set_pointer(Some((0, 0)));
+        let cell = writer.buffer.chars[0][0].read();
+        writer.set_pointer(None);
+        assert_eq!(cell.color_code, writer.buffer.chars[0][0].read().color_code.inverted());
+        writeln!(writer).expect("writing failed");
+    });
+}
+
+#[test_case]
+fn test_switch_theme() {
+    use core::fmt::Write;
+    without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        write!(writer, "\nplain \x0eerror\x0f").expect("writing failed");
+        let light = theme::Theme::find("light").unwrap();
+        writer.set_theme(light);
+        let row = &writer.buffer.chars[TEXT_HEIGHT - 1];
+        assert_eq!(row[0].read().color_code, light.text());
+        assert_eq!(row[6].read().color_code, light.error_text());
+        assert_eq!(row[BUFFER_WIDTH - 1].read(), light.blank());
+        writer.set_theme(&theme::THEMES[0]);
+        assert_eq!(writer.buffer.chars[TEXT_HEIGHT - 1][0].read().color_code, theme::THEMES[0].text());
+    });
+}
+
+#[test_case]
+fn test_println_unicode() {
+    use core::fmt::Write;
+    without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writeln!(writer, "\ncafé 20°").expect("writing failed");
+        let row = &writer.buffer.chars[TEXT_HEIGHT - 2];
+        assert_eq!(row[3].read().ascii_character, 0x82);
+        assert_eq!(row[7].read().ascii_character, 0xf8);
+    });
+}
@@ -0,0 +1,76 @@
+//! Conversion between Unicode and code page 437, the character set of the VGA
+//! text mode font.
+
+/// Byte written for characters the font has no glyph for, a small square.
+pub const REPLACEMENT: u8 = 0xfe;
+
+/// The glyphs of bytes 0x80 to 0xff.
+const HIGH_HALF: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å',
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ',
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»',
+    '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐',
+    '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧',
+    '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐', '▀',
+    'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩',
+    '≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{a0}',
+];
+
+/// Glyphs below 0x20 whose bytes the `Writer` doesn't treat as control characters.
+const LOW_GLYPHS: [(char, u8); 6] = [('►', 0x10), ('◄', 0x11), ('↑', 0x18), ('↓', 0x19), ('→', 0x1a), ('←', 0x11)];
+
+/// Letters without their accents for U+00C0 to U+00FF, for the accented
+/// letters that are not in the code page.
+const LATIN1_LETTERS: &[u8; 64] = b"AAAAAAACEEEEIIIIDNOOOOOxOUUUUYPsaaaaaaaceeeeiiiidnooooo/ouuuuypy";
+
+/// The byte showing `c`: the glyph itself, a look-alike or `REPLACEMENT`.
+pub fn encode(c: char) -> u8 {
+    if c.is_ascii() {
+        return c as u8;
+    }
+    if let Some(index) = HIGH_HALF.iter().position(|&glyph| glyph == c) {
+        return 0x80 + index as u8;
+    }
+    if let Some(&(_, byte)) = LOW_GLYPHS.iter().find(|&&(glyph, _)| glyph == c) {
+        return byte;
+    }
+    match c {
+        'À'..='ÿ' => LATIN1_LETTERS[c as usize - 0xc0],
+        'β' => 0xe1,
+        'μ' => 0xe6,
+        '•' => 0xf9,
+        '€' => b'E',
+        '‘' | '’' => b'\'',
+        '“' | '”' => b'"',
+        '–' | '—' => b'-',
+        _ => REPLACEMENT,
+    }
+}
+
+/// The character shown for `byte`.
+pub fn decode(byte: u8) -> char {
+    match byte {
+        0x80..=0xff => HIGH_HALF[usize::from(byte - 0x80)],
+        _ => LOW_GLYPHS.iter().find(|&&(_, glyph)| glyph == byte).map_or(byte as char, |&(c, _)| c),
+    }
+}
+
+/// Calls `f` with the bytes showing `s`.
+pub fn encode_str(s: &str, f: impl FnMut(u8)) {
+    s.chars().map(encode).for_each(f);
+}
+
+#[test_case]
+fn test_encode() {
+    assert_eq!(encode('a'), b'a');
+    assert_eq!(encode('é'), 0x82);
+    assert_eq!(encode('°'), 0xf8);
+    assert_eq!(encode('┌'), 0xda);
+    assert_eq!(encode('→'), 0x1a);
+    assert_eq!(encode('ã'), b'a');
+    assert_eq!(encode('€'), b'E');
+    assert_eq!(encode('日'), REPLACEMENT);
+    for byte in 0x80..=0xff {
+        assert_eq!(encode(decode(byte)), byte);
+    }
+}
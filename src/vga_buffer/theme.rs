@@ -0,0 +1,123 @@
+//! Color themes of the console, switched with `Writer::set_theme`, the `theme`
+//! shell command or Ctrl+Alt+T.
+
+use crate::vga_buffer::{Color, ColorCode, ScreenChar};
+use crate::vga_buffer::Color::*;
+
+/// The colors the `Writer` draws with.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Theme {
+    pub name: &'static str,
+    pub foreground: Color,
+    pub background: Color,
+    /// Text written between `ERROR_START` and `ERROR_END`, e.g. by `eprintln!`.
+    pub error: Color,
+    pub cursor_foreground: Color,
+    pub cursor_background: Color,
+    pub selection_foreground: Color,
+    pub selection_background: Color,
+}
+
+/// The built-in themes. The first one is the default.
+pub static THEMES: [Theme; 5] = [
+    Theme {
+        name: "classic",
+        foreground: White,
+        background: Black,
+        error: LightRed,
+        cursor_foreground: Black,
+        cursor_background: LightCyan,
+        selection_foreground: White,
+        selection_background: Blue,
+    },
+    Theme {
+        name: "blue",
+        foreground: White,
+        background: Blue,
+        error: Yellow,
+        cursor_foreground: Blue,
+        cursor_background: LightGray,
+        selection_foreground: Black,
+        selection_background: LightCyan,
+    },
+    Theme {
+        name: "light",
+        foreground: Black,
+        background: LightGray,
+        error: Red,
+        cursor_foreground: White,
+        cursor_background: Black,
+        selection_foreground: White,
+        selection_background: Blue,
+    },
+    Theme {
+        name: "green",
+        foreground: LightGreen,
+        background: Black,
+        error: Yellow,
+        cursor_foreground: Black,
+        cursor_background: LightGreen,
+        selection_foreground: Black,
+        selection_background: Green,
+    },
+    Theme {
+        name: "amber",
+        foreground: Yellow,
+        background: Black,
+        error: LightRed,
+        cursor_foreground: Black,
+        cursor_background: Yellow,
+        selection_foreground: Black,
+        selection_background: Brown,
+    },
+];
+
+/// Starts text in the error color when written to the `Writer`.
+pub const ERROR_START: u8 = 0x0e;
+/// Goes back to the normal text color.
+pub const ERROR_END: u8 = 0x0f;
+
+impl Theme {
+    pub fn find(name: &str) -> Option<&'static Theme> {
+        THEMES.iter().find(|theme| theme.name == name)
+    }
+
+    /// The built-in theme after this one, wrapping around.
+    pub fn next(&self) -> &'static Theme {
+        let index = THEMES.iter().position(|theme| theme == self).unwrap_or(0);
+        &THEMES[(index + 1) % THEMES.len()]
+    }
+
+    pub(super) fn text(&self) -> ColorCode {
+        ColorCode::new(self.foreground, self.background)
+    }
+
+    pub(super) fn error_text(&self) -> ColorCode {
+        ColorCode::new(self.error, self.background)
+    }
+
+    pub(super) fn cursor(&self) -> ColorCode {
+        ColorCode::new(self.cursor_foreground, self.cursor_background)
+    }
+
+    pub(super) fn selection(&self) -> ColorCode {
+        ColorCode::new(self.selection_foreground, self.selection_background)
+    }
+
+    /// An empty cell.
+    pub(super) fn blank(&self) -> ScreenChar {
+        ScreenChar { ascii_character: 0, color_code: self.text() }
+    }
+
+    /// The color `color_code` of this theme has in `theme`. Colors that are
+    /// not part of this theme stay the same.
+    pub(super) fn translate(&self, color_code: ColorCode, theme: &Theme) -> ColorCode {
+        if color_code == self.text() {
+            theme.text()
+        } else if color_code == self.error_text() {
+            theme.error_text()
+        } else {
+            color_code
+        }
+    }
+}
@@ -1,42 +1,149 @@
+use alloc::collections::VecDeque;
 use alloc::string::String;
+use alloc::vec::Vec;
 use core::fmt;
-use crate::vga_buffer::{BUFFER_HEIGHT, BUFFER_WIDTH, ColorCode, CURSOR, EMPTY, ScreenChar, Writer};
-use crate::vga_buffer::*;
+use core::ops::Range;
+use crate::vga_buffer::{Buffer, BUFFER_WIDTH, ColorCode, ScreenChar, TEXT_HEIGHT, Writer};
+use crate::vga_buffer::cp437;
+use crate::vga_buffer::theme::{self, Theme, THEMES};
+
+/// A logical line of text, wrapped over as many screen rows as it needs.
+pub(super) struct Line {
+    chars: Vec<ScreenChar>,
+    /// Number of screen rows the line takes, updated whenever it changes.
+    rows: usize,
+}
+
+impl Line {
+    fn new(chars: Vec<ScreenChar>, word_wrap: bool) -> Line {
+        let mut line = Line { chars, rows: 0 };
+        line.update_rows(word_wrap);
+        line
+    }
+
+    fn rows(&self, word_wrap: bool) -> Rows {
+        Rows { chars: &self.chars, start: Some(0), word_wrap }
+    }
+
+    fn update_rows(&mut self, word_wrap: bool) {
+        self.rows = self.rows(word_wrap).count();
+    }
+}
+
+/// The parts of the screen that changed since the last flush.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Damage {
+    None,
+    /// Only this line changed, and it still takes the same number of rows.
+    Line(usize),
+    All,
+}
+
+/// How the text between the selection anchor and the cursor is selected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SelectionMode {
+    /// Everything from the anchor to the cursor, in reading order.
+    Stream,
+    /// The screen rectangle with the anchor and the cursor at opposite corners.
+    Rectangle,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Selection {
+    /// Logical line and index in it where the selection started.
+    anchor: (usize, usize),
+    mode: SelectionMode,
+}
+
+/// Iterator over the ranges of a line that go on each screen row.
+///
+/// There is always at least one row, and the last one is never full, so the
+/// end of the line has a cell for the cursor.
+struct Rows<'a> {
+    chars: &'a [ScreenChar],
+    start: Option<usize>,
+    word_wrap: bool,
+}
+
+impl Iterator for Rows<'_> {
+    type Item = Range<usize>;
+
+    fn next(&mut self) -> Option<Range<usize>> {
+        let start = self.start?;
+        let len = self.chars.len();
+        if len - start < BUFFER_WIDTH {
+            self.start = None;
+            return Some(start..len);
+        }
+        let mut end = start + BUFFER_WIDTH;
+        if self.word_wrap && end < len && !is_space(self.chars[end]) {
+            // break after the last space of the row instead of inside a word
+            if let Some(space) = self.chars[start..end].iter().rposition(|&c| is_space(c)) {
+                end = start + space + 1;
+            }
+        }
+        self.start = Some(end);
+        Some(start..end)
+    }
+}
+
+fn is_space(c: ScreenChar) -> bool {
+    c.ascii_character == b' '
+}
+
+/// Writes `cells` to `row` of the screen and fills the rest of the row with `blank`.
+fn draw_row(buffer: &mut Buffer, row: usize, cells: &[ScreenChar], blank: ScreenChar) {
+    for col in 0..BUFFER_WIDTH {
+        buffer.chars[row][col].write(cells.get(col).copied().unwrap_or(blank));
+    }
+}
 
 impl Writer {
+    pub(super) fn new(buffer: &'static mut Buffer) -> Writer {
+        Writer {
+            lines: VecDeque::new(),
+            line: 0,
+            column: 0,
+            top: 0,
+            word_wrap: true,
+            damage: Damage::None,
+            cursor: None,
+            selection: None,
+            pointer: None,
+            under_pointer: None,
+            early: (0, 0),
+            suspended: false,
+            theme: &THEMES[0],
+            error: false,
+            buffer,
+            clipboard: String::new(),
+        }
+    }
+
     /// Writes an ASCII byte to the buffer.
     ///
     /// Wraps lines at `BUFFER_WIDTH`. Supports the `\n` newline character.
     pub fn write_byte(&mut self, byte: u8) {
-        let color_code = self.color_code;
-        self.clean_cursor_current_position();
-        match byte {
-            b'\n' => {
-                self.new_line()
-            }
-            byte => {
-                if self.column_position >= BUFFER_WIDTH { self.new_line(); }
-                self.shift_char_right();
-                self.write_relative_sc(0, ScreenChar {
-                    ascii_character: byte,
-                    color_code,
-                });
-                self.move_right();
-            }
+        if !self.ready() {
+            return self.early_write_byte(byte);
         }
-        self.update_cursor();
+        self.put_byte(byte);
+        self.flush();
     }
 
-    /// Writes the given ASCII string to the buffer.
+    /// Writes the given ASCII bytes to the buffer.
     ///
     /// Wraps lines at `BUFFER_WIDTH`. Supports the `\n` newline character. Does **not**
-    /// support strings with non-ASCII characters, since they can't be printed in the VGA text
+    /// support non-ASCII characters, since they can't be printed in the VGA text
     /// mode.
-    fn write_string(&mut self, s: &str) {
-        for byte in s.bytes() {
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        if !self.ready() {
+            return bytes.iter().for_each(|&byte| self.early_write_byte(byte));
+        }
+        for &byte in bytes {
             match byte {
                 // printable ASCII byte or newline
-                0x20..=0x7e | b'\n' => self.write_byte(byte),
+                0x20..=0x7e | b'\n' => self.put_byte(byte),
                 b'\t' => {
                     self.tab();
                 }
@@ -44,289 +151,659 @@ impl Writer {
                     self.backspace();
                 }
                 0x1b => { // Esc
-                    self.clear_all();
-                    self.column_position = 0;
-                    self.row_position = 0;
-                    self.update_cursor();
-                }
-                0x0c => { //Control-L
-                    self.clear_all();
-                    self.column_position = 0;
-                    self.row_position = 0;
-                    self.update_cursor();
-                    self.write_string("MarOS:\n");
-                }
-                0x03 => {//Control-C
-                    self.copy_line(self.row_position);
-                }
-                0x16 => {//Control-v
-                    self.paste_line(self.row_position);
-                }
-                0x7f => {//canc
-                    self.canc();
+                    self.clear();
                 }
+                theme::ERROR_START => self.error = true,
+                theme::ERROR_END => self.error = false,
                 // not part of printable ASCII range
-                _ => self.write_byte(byte),
+                _ => self.put_byte(byte),
             }
         }
+        self.flush();
     }
 
-    /// Shifts all lines one line up and clears the last row.
-    fn shift_lines_up(&mut self) {
-        for row in 1..BUFFER_HEIGHT {
-            for col in 0..BUFFER_WIDTH {
-                let character = self.buffer.chars[row][col].read();
-                self.buffer.chars[row - 1][col].write(character);
+    /// Switches between wrapping long lines at word boundaries and at any character.
+    pub fn set_word_wrap(&mut self, enabled: bool) {
+        self.word_wrap = enabled;
+        if self.ready() {
+            for line in self.lines.iter_mut() {
+                line.update_rows(enabled);
             }
+            self.damage = Damage::All;
+            self.flush();
         }
-        self.clear_row(BUFFER_HEIGHT - 1);
-        self.column_position = 0;
     }
 
-    fn shift_char_right(&mut self) {
-        let mut current = self.read_relative_sc(0);
-        let mut i = self.column_position;
-        while i < BUFFER_WIDTH - 1 && current != EMPTY {
-            let next = self.buffer.chars[self.row_position][i + 1].read();
-            self.buffer.chars[self.row_position][i + 1].write(current);
-            current = next;
-            i += 1;
+    pub fn theme(&self) -> &'static Theme {
+        self.theme
+    }
+
+    /// Switches to `theme`, recoloring the text already written.
+    pub fn set_theme(&mut self, theme: &'static Theme) {
+        let old = core::mem::replace(&mut self.theme, theme);
+        if self.lines.is_empty() {
+            // the early console, whose text lives on the screen only
+            for row in 0..TEXT_HEIGHT {
+                for col in 0..BUFFER_WIDTH {
+                    let mut sc = self.buffer.chars[row][col].read();
+                    sc.color_code = old.translate(sc.color_code, theme);
+                    self.buffer.chars[row][col].write(sc);
+                }
+            }
+            return;
         }
-        if i == BUFFER_WIDTH - 1 {
-            self._shift_char_right_rec(self.row_position + 1, current);
+        for sc in self.lines.iter_mut().flat_map(|line| line.chars.iter_mut()) {
+            sc.color_code = old.translate(sc.color_code, theme);
         }
+        self.cursor = None;
+        self.damage = Damage::All;
+        self.flush();
     }
-    fn _shift_char_right_rec(&mut self, row: usize, current: ScreenChar) {
-        let mut current = current;
-        let mut i = 0;
-        while i < BUFFER_WIDTH && current != EMPTY {
-            let next = self.buffer.chars[row][i].read();
-            self.buffer.chars[row][i].write(current);
-            current = next;
-            i += 1;
-        }
-        if i == BUFFER_WIDTH {
-            self._shift_char_right_rec(row + 1, current);
+
+    /// The color of the text being written.
+    fn color_code(&self) -> ColorCode {
+        if self.error {
+            self.theme.error_text()
+        } else {
+            self.theme.text()
         }
     }
 
-    /// Clears a row by overwriting it with blank characters.
-    fn clear_row(&mut self, row: usize) {
-        for col in 0..BUFFER_WIDTH {
-            self.buffer.chars[row][col].write(EMPTY);
+    /// Returns the text of the logical line the cursor is on.
+    pub fn current_line(&self) -> String {
+        match self.lines.get(self.line) {
+            Some(line) => line.chars.iter().map(|sc| cp437::decode(sc.ascii_character)).collect(),
+            None => String::new(),
         }
     }
 
     pub fn clear_all(&mut self) {
-        for row in 0..BUFFER_HEIGHT {
-            self.clear_row(row);
+        if self.ready() {
+            self.clear();
+            self.flush();
+        } else {
+            for row in 0..TEXT_HEIGHT {
+                draw_row(self.buffer, row, &[], self.theme.blank());
+            }
+            self.early = (0, 0);
         }
     }
 
-    ///moves down the cursor if possible, otherwise shifts lines up
-    fn new_line(&mut self) {
-        let mut tmp = String::new();
-        for i in self.column_position..BUFFER_WIDTH - 1 {
-            let sc = self.buffer.chars[self.row_position][i].read();
-            if sc == EMPTY { break; }
-            self.buffer.chars[self.row_position][i].write(EMPTY);
-            tmp.push(sc.ascii_character as char);
+    pub(crate) fn move_left(&mut self) {
+        if !self.ready() {
+            return;
         }
-        if self.row_position == BUFFER_HEIGHT - 1 {
-            self.shift_lines_up();
-        } else {
-            self.row_position += 1;
-            self.column_position = 0;
+        if self.column > 0 {
+            self.column -= 1;
+        } else if self.line > 0 {
+            self.line -= 1;
+            self.column = self.lines[self.line].chars.len();
+        }
+        self.flush();
+    }
+
+    pub(crate) fn move_right(&mut self) {
+        if !self.ready() {
+            return;
         }
-        self.write_string(tmp.as_str());
-        self.clean_cursor_current_position();
-        self.column_position = 0;
+        self.step_right();
+        self.flush();
     }
 
-    fn read_relative_sc(&mut self, shift: i32) -> ScreenChar {
-        let (row, col) = self.get_relative_position(shift);
-        self.buffer.chars[row][col].read()
+    pub(crate) fn move_down(&mut self) {
+        if self.ready() {
+            self.move_vertically(1);
+        }
     }
 
-    fn write_relative_sc(&mut self, shift: i32, sc: ScreenChar) {
-        let (row, col) = self.get_relative_position(shift);
-        self.buffer.chars[row][col].write(ScreenChar {
-            ascii_character: sc.ascii_character,
-            color_code: sc.color_code,
-        });
+    pub(crate) fn move_up(&mut self) {
+        if self.ready() {
+            self.move_vertically(-1);
+        }
     }
-    /// Takes away cursor color scheme if present on the current screenchar
-    fn clean_cursor_current_position(&mut self) {
-        let current_sc = self.read_relative_sc(0);
-        if current_sc.color_code == CURSOR.color_code { // Cursor visibility has to adapt
-            self.update_color_code(self.color_code.0)
+
+    /// Starts selecting at the cursor, unless a selection is already being made.
+    /// Moving the cursor then extends the selection.
+    pub(crate) fn start_selection(&mut self, mode: SelectionMode) {
+        if !self.ready() {
+            return;
+        }
+        match self.selection.as_mut() {
+            Some(selection) => selection.mode = mode,
+            None => self.selection = Some(Selection { anchor: (self.line, self.column), mode }),
         }
+        self.damage = Damage::All;
+        self.flush();
     }
 
-    fn get_relative_position(&mut self, shift: i32) -> (usize, usize) {
-        if shift < 0 {
-            if self.column_position != 0 { self.column_position -= 1 } else {
-                self.row_position = if self.row_position != 0 { self.row_position - 1 } else { 0 };
-                self.column_position = BUFFER_WIDTH - 1;
-                while self.read_relative_sc(0).ascii_character == EMPTY.ascii_character && self.column_position != 0 {
-                    self.column_position -= 1;
+    pub(crate) fn clear_selection(&mut self) {
+        if self.selection.take().is_some() {
+            self.damage = Damage::All;
+            self.flush();
+        }
+    }
+
+    /// Returns the selected text, with a newline between lines (or rows of a
+    /// rectangular selection).
+    pub fn selected_text(&self) -> Option<String> {
+        let selection = self.selection?;
+        let mut text = String::new();
+        match selection.mode {
+            SelectionMode::Stream => {
+                let (start, end) = self.stream_bounds(selection);
+                for line in start.0..=end.0 {
+                    let chars = &self.lines[line].chars;
+                    let from = if line == start.0 { start.1 } else { 0 };
+                    let to = if line == end.0 { end.1 } else { chars.len() };
+                    if line != start.0 {
+                        text.push('\n');
+                    }
+                    text.extend(chars[from..to].iter().map(|sc| cp437::decode(sc.ascii_character)));
+                }
+            }
+            SelectionMode::Rectangle => {
+                let (rows, cols) = self.rectangle_bounds(selection);
+                let screen_rows = self.screen_rows();
+                for row in rows.clone() {
+                    let (line, range) = &screen_rows[row];
+                    let chars = &self.lines[*line].chars[range.clone()];
+                    let cells = chars.get(cols.start.min(chars.len())..cols.end.min(chars.len())).unwrap_or(&[]);
+                    text.extend(cells.iter().map(|sc| cp437::decode(sc.ascii_character)));
+                    if row + 1 != rows.end {
+                        text.push('\n');
+                    }
                 }
-                if !(self.read_relative_sc(0) == EMPTY) && self.column_position != BUFFER_WIDTH - 1 { self.column_position += 1 };
             }
-            return self.get_relative_position(shift + 1);
         }
-        if shift > 0 {
-            if self.column_position == BUFFER_WIDTH - 1 || self.read_relative_sc(0) == EMPTY {
-                self.column_position = 0;
-                self.row_position = if self.row_position != BUFFER_HEIGHT - 1
-                { self.row_position + 1 } else { self.row_position }
+        Some(text)
+    }
+
+    /// The start and end (exclusive) of a stream selection as (line, index).
+    fn stream_bounds(&self, selection: Selection) -> ((usize, usize), (usize, usize)) {
+        let cursor = (self.line, self.column);
+        (selection.anchor.min(cursor), selection.anchor.max(cursor))
+    }
+
+    /// The rows (counted like `first_row`) and columns a rectangular selection covers.
+    fn rectangle_bounds(&self, selection: Selection) -> (Range<usize>, Range<usize>) {
+        let anchor = self.position_of(selection.anchor.0, selection.anchor.1);
+        let cursor = self.position_of(self.line, self.column);
+        (anchor.0.min(cursor.0)..anchor.0.max(cursor.0) + 1, anchor.1.min(cursor.1)..anchor.1.max(cursor.1))
+    }
+
+    /// Switches from the early console to the line model once the heap is
+    /// initialized, taking over what was written so far. Returns false before.
+    fn ready(&mut self) -> bool {
+        if !self.lines.is_empty() {
+            return true;
+        }
+        if !crate::allocator::is_initialized() {
+            return false;
+        }
+        let (last_row, last_col) = self.early;
+        for row in 0..=last_row {
+            let mut chars: Vec<ScreenChar> = (0..BUFFER_WIDTH).map(|col| self.buffer.chars[row][col].read()).collect();
+            let len = if row == last_row {
+                last_col
             } else {
-                if self.read_relative_sc(0) != EMPTY { self.column_position += 1; }
+                chars.iter().rposition(|&sc| sc != self.theme.blank()).map_or(0, |last| last + 1)
+            };
+            chars.truncate(len);
+            self.lines.push_back(Line::new(chars, self.word_wrap));
+        }
+        self.line = last_row;
+        self.column = last_col;
+        self.damage = Damage::All;
+        true
+    }
+
+    /// Writes a byte at the early console's position, scrolling the whole screen.
+    fn early_write_byte(&mut self, byte: u8) {
+        if byte == theme::ERROR_START || byte == theme::ERROR_END {
+            self.error = byte == theme::ERROR_START;
+            return;
+        }
+        let (row, col) = self.early;
+        if byte == b'\n' || col >= BUFFER_WIDTH {
+            if row + 1 < TEXT_HEIGHT {
+                self.early = (row + 1, 0);
+            } else {
+                for row in 1..TEXT_HEIGHT {
+                    for col in 0..BUFFER_WIDTH {
+                        let character = self.buffer.chars[row][col].read();
+                        self.buffer.chars[row - 1][col].write(character);
+                    }
+                }
+                draw_row(self.buffer, TEXT_HEIGHT - 1, &[], self.theme.blank());
+                self.early = (row, 0);
+            }
+            if byte == b'\n' {
+                return;
+            }
+        }
+        let (row, col) = self.early;
+        self.buffer.chars[row][col].write(ScreenChar { ascii_character: byte, color_code: self.color_code() });
+        self.early = (row, col + 1);
+    }
+
+    /// Inserts a byte at the cursor without rendering it.
+    fn put_byte(&mut self, byte: u8) {
+        self.forget_selection();
+        if byte == b'\n' {
+            return self.split_line();
+        }
+        let sc = ScreenChar { ascii_character: byte, color_code: self.color_code() };
+        let rows = self.lines[self.line].rows;
+        self.lines[self.line].chars.insert(self.column, sc);
+        self.column += 1;
+        self.line_changed(rows);
+    }
+
+    /// Moves the text after the cursor to a new line.
+    fn split_line(&mut self) {
+        let line = &mut self.lines[self.line];
+        let rest = line.chars.split_off(self.column);
+        line.update_rows(self.word_wrap);
+        self.lines.insert(self.line + 1, Line::new(rest, self.word_wrap));
+        self.line += 1;
+        self.column = 0;
+        self.damage = Damage::All;
+    }
+
+    /// Appends the next line to the cursor's line.
+    fn join_next_line(&mut self) {
+        if let Some(next) = self.lines.remove(self.line + 1) {
+            let line = &mut self.lines[self.line];
+            line.chars.extend(next.chars);
+            line.update_rows(self.word_wrap);
+            self.damage = Damage::All;
+        }
+    }
+
+    /// Updates the wrapping of the cursor's line, which took `rows` rows before
+    /// it was edited, and records what needs rendering.
+    fn line_changed(&mut self, rows: usize) {
+        let line = &mut self.lines[self.line];
+        line.update_rows(self.word_wrap);
+        self.damage = match self.damage {
+            _ if line.rows != rows => Damage::All,
+            Damage::None => Damage::Line(self.line),
+            Damage::Line(damaged) if damaged == self.line => Damage::Line(damaged),
+            _ => Damage::All,
+        };
+    }
+
+    /// Drops the selection once the text is edited.
+    fn forget_selection(&mut self) {
+        if self.selection.take().is_some() {
+            self.damage = Damage::All;
+        }
+    }
+
+    fn clear(&mut self) {
+        self.selection = None;
+        self.lines.clear();
+        self.lines.push_back(Line::new(Vec::new(), self.word_wrap));
+        self.line = 0;
+        self.column = 0;
+        self.top = 0;
+        self.damage = Damage::All;
+    }
+
+    fn step_right(&mut self) {
+        if self.column < self.lines[self.line].chars.len() {
+            self.column += 1;
+        } else if self.line + 1 < self.lines.len() {
+            self.line += 1;
+            self.column = 0;
+        }
+    }
+
+    /// Index of the first row of `line`, counted from the first row of the first line.
+    fn first_row(&self, line: usize) -> usize {
+        self.lines.iter().take(line).map(|line| line.rows).sum()
+    }
+
+    /// The row of index `column` of `line`, counted like `first_row`, and its
+    /// column on that row.
+    fn position_of(&self, line: usize, column: usize) -> (usize, usize) {
+        let mut position = (0, 0);
+        for (row, range) in self.lines[line].rows(self.word_wrap).enumerate() {
+            position = (row, column - range.start);
+            if column < range.end {
+                break;
             }
-            return self.get_relative_position(shift - 1);
         }
+        (self.first_row(line) + position.0, position.1)
+    }
 
-        (self.row_position, self.column_position)
+    /// Every row of text, as its line and the range of the line on it.
+    fn screen_rows(&self) -> Vec<(usize, Range<usize>)> {
+        self.lines.iter().enumerate()
+            .flat_map(|(index, line)| line.rows(self.word_wrap).map(move |range| (index, range)))
+            .collect()
     }
-    fn set_relative_position(&mut self, shift: i32) {
-        (self.row_position, self.column_position) = self.get_relative_position(shift);
+
+    /// The cells of row `row` (counted like `first_row`), showing `range` of `line`
+    /// with the selection highlighted.
+    fn render_row(&self, row: usize, line: usize, range: Range<usize>) -> [ScreenChar; BUFFER_WIDTH] {
+        let mut cells = [self.theme.blank(); BUFFER_WIDTH];
+        let chars = &self.lines[line].chars[range.clone()];
+        cells[..chars.len()].copy_from_slice(chars);
+        let selected = match self.selection {
+            None => 0..0,
+            Some(selection) if selection.mode == SelectionMode::Stream => {
+                let (start, end) = self.stream_bounds(selection);
+                let from = if line == start.0 { start.1 } else if line > start.0 { 0 } else { usize::MAX };
+                let to = if line == end.0 { end.1 } else if line < end.0 { usize::MAX } else { 0 };
+                from.max(range.start) - range.start..to.min(range.end).max(range.start) - range.start
+            }
+            Some(selection) => {
+                let (rows, cols) = self.rectangle_bounds(selection);
+                if rows.contains(&row) { cols } else { 0..0 }
+            }
+        };
+        for cell in cells.iter_mut().take(selected.end).skip(selected.start) {
+            cell.color_code = self.theme.selection();
+        }
+        cells
     }
 
-    pub(crate) fn move_left(&mut self) {
-        self.clean_cursor_current_position();
-        self.set_relative_position(-1);
-        self.update_cursor()
+    /// Moves the cursor `delta` screen rows, keeping its column where the
+    /// target row is long enough.
+    fn move_vertically(&mut self, delta: isize) {
+        let (row, col) = self.position_of(self.line, self.column);
+        let total: usize = self.lines.iter().map(|line| line.rows).sum();
+        let target = match row.checked_add_signed(delta) {
+            Some(target) if target >= self.top && target < total => target,
+            _ => return,
+        };
+        let mut first = 0;
+        for (index, line) in self.lines.iter().enumerate() {
+            if target < first + line.rows {
+                let mut rows = line.rows(self.word_wrap).skip(target - first).peekable();
+                let range = rows.next().unwrap();
+                // the end of a row that isn't the last one is the start of the next
+                let last = if rows.peek().is_some() { range.end - 1 } else { range.end };
+                self.line = index;
+                self.column = (range.start + col).min(last);
+                break;
+            }
+            first += line.rows;
+        }
+        self.flush();
     }
-    pub(crate) fn move_right(&mut self) {
-        self.clean_cursor_current_position();
-        self.set_relative_position(1);
-        self.update_cursor();
+
+    /// Stops drawing to the screen, so a full-screen program can use it. Text
+    /// written meanwhile is kept and shown by `resume`.
+    pub fn suspend(&mut self) {
+        self.suspended = true;
     }
-    pub(crate) fn move_down(&mut self) {
-        self.clean_cursor_current_position();
-        if self.row_position == BUFFER_HEIGHT - 1 {
-            self.update_cursor();
+
+    /// Draws the whole screen again after `suspend`.
+    pub fn resume(&mut self) {
+        self.suspended = false;
+        self.cursor = None;
+        self.under_pointer = None;
+        self.damage = Damage::All;
+        if self.ready() {
+            self.flush();
+        }
+    }
+
+    /// Renders what changed and draws the cursor.
+    fn flush(&mut self) {
+        if self.suspended {
+            self.damage = Damage::All;
             return;
         }
-        self.row_position += 1;
-        while self.read_relative_sc(0) == EMPTY && self.column_position > 0 {
-            self.column_position -= 1;
+        // the pointer is drawn over the cursor
+        if let Some((row, col, sc)) = self.under_pointer.take() {
+            self.buffer.chars[row][col].write(sc);
+        }
+        if let Some((row, col, sc)) = self.cursor.take() {
+            self.buffer.chars[row][col].write(sc);
+        }
+        let total: usize = self.lines.iter().map(|line| line.rows).sum();
+        let (cursor_row, cursor_col) = self.position_of(self.line, self.column);
+        let top = total.saturating_sub(TEXT_HEIGHT).min(cursor_row);
+        if top != self.top {
+            self.top = top;
+            self.damage = Damage::All;
+        }
+        // forget the lines that scrolled off the screen
+        while self.line > 0 && self.lines[0].rows <= self.top {
+            let line = self.lines.pop_front().unwrap();
+            self.top -= line.rows;
+            self.line -= 1;
+            if let Some(selection) = self.selection.as_mut() {
+                selection.anchor = match selection.anchor.0 {
+                    0 => (0, 0),
+                    anchor => (anchor - 1, selection.anchor.1),
+                };
+            }
+            self.damage = Damage::All;
+        }
+        if self.selection.is_some() {
+            // the highlight follows the cursor
+            self.damage = Damage::All;
+        }
+
+        match self.damage {
+            Damage::None => {}
+            Damage::Line(line) => {
+                let first = self.first_row(line);
+                let rows: Vec<Range<usize>> = self.lines[line].rows(self.word_wrap).collect();
+                for (i, range) in rows.into_iter().enumerate() {
+                    if let Some(row) = (first + i).checked_sub(self.top).filter(|&row| row < TEXT_HEIGHT) {
+                        let cells = self.render_row(first + i, line, range);
+                        draw_row(self.buffer, row, &cells, self.theme.blank());
+                    }
+                }
+            }
+            Damage::All => {
+                let rows = self.screen_rows();
+                for row in 0..TEXT_HEIGHT {
+                    match rows.get(self.top + row) {
+                        Some((line, range)) => {
+                            let cells = self.render_row(self.top + row, *line, range.clone());
+                            draw_row(self.buffer, row, &cells, self.theme.blank());
+                        }
+                        None => draw_row(self.buffer, row, &[], self.theme.blank()),
+                    }
+                }
+            }
+        }
+        self.damage = Damage::None;
+
+        let (row, col) = (cursor_row - top, cursor_col);
+        if row < TEXT_HEIGHT && col < BUFFER_WIDTH {
+            let sc = self.buffer.chars[row][col].read();
+            self.cursor = Some((row, col, sc));
+            self.buffer.chars[row][col].write(ScreenChar { ascii_character: sc.ascii_character, color_code: self.theme.cursor() });
+        }
+        if let Some((row, col)) = self.pointer.filter(|&(row, col)| row < TEXT_HEIGHT && col < BUFFER_WIDTH) {
+            let sc = self.buffer.chars[row][col].read();
+            self.under_pointer = Some((row, col, sc));
+            self.buffer.chars[row][col].write(ScreenChar { ascii_character: sc.ascii_character, color_code: sc.color_code.inverted() });
         }
-        self.update_cursor()
     }
-    pub(crate) fn move_up(&mut self) {
-        self.clean_cursor_current_position();
-        if self.row_position == 0 {
-            self.update_cursor();
+
+    /// Shows the mouse pointer on the cell at `row`, `col` of the screen, or hides it.
+    pub(crate) fn set_pointer(&mut self, pointer: Option<(usize, usize)>) {
+        self.pointer = pointer;
+        if self.ready() {
+            self.flush();
+        }
+    }
+
+    /// Moves the cursor to the text shown at `row`, `col` of the screen,
+    /// dropping the selection.
+    pub(crate) fn click(&mut self, row: usize, col: usize) {
+        if !self.ready() || self.suspended {
             return;
         }
-        self.row_position -= 1;
-        while self.read_relative_sc(0) == EMPTY && self.column_position > 0 {
-            self.column_position -= 1;
+        self.forget_selection();
+        if let Some((line, column)) = self.text_at(row, col) {
+            self.line = line;
+            self.column = column;
         }
-        self.update_cursor()
+        self.flush();
     }
-    fn update_char(&mut self, ascii_character: u8) {
-        let sc = self.buffer.chars[self.row_position][self.column_position].read();
-        self.buffer.chars[self.row_position][self.column_position].write(ScreenChar {
-            ascii_character,
-            color_code: sc.color_code,
-        })
+
+    /// Selects from the cursor to the text shown at `row`, `col` of the
+    /// screen, moving the cursor there like Shift and the arrows do.
+    pub(crate) fn drag_to(&mut self, row: usize, col: usize) {
+        if !self.ready() || self.suspended {
+            return;
+        }
+        if let Some((line, column)) = self.text_at(row, col) {
+            if self.selection.is_none() {
+                self.selection = Some(Selection { anchor: (self.line, self.column), mode: SelectionMode::Stream });
+            }
+            self.line = line;
+            self.column = column;
+        }
+        self.flush();
     }
-    fn update_color_code(&mut self, color_code: u8) {
-        let sc = self.buffer.chars[self.row_position][self.column_position].read();
-        self.buffer.chars[self.row_position][self.column_position].write(ScreenChar {
-            ascii_character: sc.ascii_character,
-            color_code: ColorCode::new_from(color_code),
-        })
+
+    /// The line and index in it of the text at `row`, `col` of the screen.
+    /// Past the end of a row, that is its end; below the text, the end of
+    /// the text.
+    fn text_at(&self, row: usize, col: usize) -> Option<(usize, usize)> {
+        let rows = self.screen_rows();
+        let (line, range) = match rows.get(self.top + row) {
+            Some(row) => row.clone(),
+            None => return rows.last().map(|(line, range)| (*line, range.end)),
+        };
+        // the end of a row that isn't the last of its line is the start of the next
+        let last_row = rows.get(self.top + row + 1).map_or(true, |(next, _)| *next != line);
+        let end = if last_row { range.end } else { range.end - 1 };
+        Some((line, (range.start + col).min(end)))
     }
-    fn update_cursor(&mut self) {
-        self.update_color_code(CURSOR.color_code.0);
+
+    /// Copies the selection to the clipboard, or the cursor's line if nothing is selected.
+    pub(crate) fn copy(&mut self) {
+        if self.ready() {
+            self.clipboard = self.selected_text().unwrap_or_else(|| self.current_line());
+        }
     }
 
-    fn copy_line(&mut self, row: usize) {
-        let mut tmp = String::new();
-        for i in 0..BUFFER_WIDTH {
-            let sc = self.buffer.chars[row][i].read();
-            if sc == EMPTY { break; }
-            let ch = sc.ascii_character as char;
-            tmp.push(ch)
+    /// Copies the selection into the clipboard, if there is one.
+    pub(crate) fn copy_selection(&mut self) {
+        if let Some(text) = self.selected_text() {
+            self.clipboard = text;
         }
-        self.clipboard = tmp;
     }
-    fn paste_line(&mut self, row: usize) {
-        self.clean_cursor_current_position();
-        self.clear_row(row);
-        self.column_position = 0;
-        let sentence = self.clipboard.clone();
-        self.write_string(sentence.chars().as_str());
-        self.update_cursor();
+
+    /// Inserts the clipboard at the cursor, splitting lines at its newlines.
+    pub(crate) fn paste(&mut self) {
+        if !self.ready() {
+            return;
+        }
+        let clipboard = core::mem::take(&mut self.clipboard);
+        cp437::encode_str(&clipboard, |byte| self.put_byte(byte));
+        self.clipboard = clipboard;
+        self.flush();
+    }
+
+    /// Clears the screen, leaving the banner on it.
+    pub(crate) fn clear_screen(&mut self) {
+        if !self.ready() {
+            return;
+        }
+        self.clear();
+        "MarOS:\n".bytes().for_each(|byte| self.put_byte(byte));
+        self.flush();
     }
+
     fn tab(&mut self) {
-        if self.column_position == 0 && self.read_relative_sc(0).ascii_character == 0x0 {
+        self.forget_selection();
+        let line = &self.lines[self.line];
+        if line.chars.is_empty() {
             for _ in 0..4 {
-                self.write_byte(b' ');
+                self.put_byte(b' ');
             }
             return;
         }
-        let mut current = self.read_relative_sc(0).ascii_character;
-        while current != b' ' && current != 0x0 {
-            self.move_right();
-            current = self.read_relative_sc(0).ascii_character;
+        // jump past the end of the current word
+        while self.column < line.chars.len() && !is_space(line.chars[self.column]) {
+            self.column += 1;
         }
-        self.move_right()
+        self.step_right();
     }
+
     fn backspace(&mut self) {
-        self.clean_cursor_current_position();
-        let mut tmp_str = String::new();
-        let mut going_up: bool = false;
-        if self.column_position == 0 && self.row_position != 0 {
-            going_up = true;
-            for i in 0..BUFFER_WIDTH {
-                let sc = self.buffer.chars[self.row_position][i].read();
-                if sc == EMPTY { break; }
-                tmp_str.push(sc.ascii_character as char);
-            }
+        self.forget_selection();
+        if self.column > 0 {
+            let rows = self.lines[self.line].rows;
+            self.lines[self.line].chars.remove(self.column - 1);
+            self.column -= 1;
+            self.line_changed(rows);
+        } else if self.line > 0 {
+            self.line -= 1;
+            self.column = self.lines[self.line].chars.len();
+            self.join_next_line();
         }
-        self.move_left();
-        if !going_up {
-            for i in self.column_position..BUFFER_WIDTH - 1 {
-                let nc = self.buffer.chars[self.row_position][i + 1].read().ascii_character;
-                self.buffer.chars[self.row_position][i].write(ScreenChar {
-                    ascii_character: nc,
-                    color_code: self.color_code,
-                })
-            }
-        } else {
-            self.clear_row(self.row_position + 1);
-            let (prev_col, prev_row) = (self.column_position, self.row_position);
-            for ch in tmp_str.chars() {
-                self.write_byte(ch as u8)
-            }
-            self.clean_cursor_current_position();
-            (self.column_position, self.row_position) = (prev_col, prev_row );
-        }
-        self.buffer.chars[self.row_position][BUFFER_WIDTH - 1].write(EMPTY);
-        self.update_cursor();
     }
-    fn canc(&mut self) {
-        self.clean_cursor_current_position();
-        self.write_relative_sc(0, EMPTY);
-        for i in self.column_position..BUFFER_WIDTH - 1 {
-            let nc = self.buffer.chars[self.row_position][i + 1].read().ascii_character;
-            self.buffer.chars[self.row_position][i].write(ScreenChar {
-                ascii_character: nc,
-                color_code: self.color_code,
-            })
+
+    /// Deletes the character under the cursor, or joins the next line at the
+    /// end of one.
+    pub(crate) fn delete(&mut self) {
+        if !self.ready() {
+            return;
+        }
+        self.forget_selection();
+        if self.column < self.lines[self.line].chars.len() {
+            let rows = self.lines[self.line].rows;
+            self.lines[self.line].chars.remove(self.column);
+            self.line_changed(rows);
+        } else {
+            self.join_next_line();
         }
-        self.buffer.chars[self.row_position][BUFFER_WIDTH - 1].write(EMPTY);
-        self.update_cursor();
+        self.flush();
     }
 }
 
 impl fmt::Write for Writer {
+    /// Writes `s`, with the characters that are not ASCII translated to code
+    /// page 437 by `cp437::encode`.
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        self.write_string(s);
+        if s.is_ascii() {
+            self.write_bytes(s.as_bytes());
+            return Ok(());
+        }
+        let mut bytes = [0; 64];
+        let mut len = 0;
+        for c in s.chars() {
+            bytes[len] = cp437::encode(c);
+            len += 1;
+            if len == bytes.len() {
+                self.write_bytes(&bytes);
+                len = 0;
+            }
+        }
+        self.write_bytes(&bytes[..len]);
         Ok(())
     }
 }
+
+#[test_case]
+fn test_word_wrap_rows() {
+    let color_code = THEMES[0].text();
+    let text: Vec<ScreenChar> = "word ".repeat(17).bytes()
+        .map(|ascii_character| ScreenChar { ascii_character, color_code })
+        .collect();
+    // 85 characters: the 17th word does not fit on the first row
+    let rows: Vec<Range<usize>> = Rows { chars: &text, start: Some(0), word_wrap: true }.collect();
+    assert_eq!(rows, [0..80, 80..85]);
+    let rows: Vec<Range<usize>> = Rows { chars: &text[..83], start: Some(0), word_wrap: true }.collect();
+    assert_eq!(rows, [0..80, 80..83]);
+    let rows: Vec<Range<usize>> = Rows { chars: &text[1..84], start: Some(0), word_wrap: true }.collect();
+    assert_eq!(rows, [0..79, 79..83]);
+    let rows: Vec<Range<usize>> = Rows { chars: &text[1..84], start: Some(0), word_wrap: false }.collect();
+    assert_eq!(rows, [0..80, 80..83]);
+    // a full row leaves an empty one for the cursor
+    let rows: Vec<Range<usize>> = Rows { chars: &text[..80], start: Some(0), word_wrap: true }.collect();
+    assert_eq!(rows, [0..80, 80..80]);
+}
@@ -0,0 +1,166 @@
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::structures::idt::InterruptStackFrame;
+use crate::serial::SERIAL1;
+use crate::{klog, sched, serial_print, serial_println, symbols};
+use crate::time::{self, Instant, TimerId};
+
+static WATCHDOGS: Mutex<Vec<Watchdog>> = Mutex::new(Vec::new());
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+static REBOOT_ON_EXPIRY: AtomicBool = AtomicBool::new(false);
+/// Set by the timer of a watchdog that expired, until `check` reported it.
+static EXPIRED: AtomicBool = AtomicBool::new(false);
+
+struct Watchdog {
+    id: usize,
+    name: &'static str,
+    timeout: Duration,
+    last_pet: Instant,
+    fired: bool,
+    /// Expires one timeout after the last pet.
+    timer: TimerId,
+}
+
+/// A registered watchdog. It must be petted at least once per timeout, otherwise
+/// the timer interrupt reports it as hung. Dropping the handle unregisters it.
+pub struct WatchdogHandle {
+    id: usize,
+}
+
+/// Registers a watchdog called `name` that expires after `timeout` without a pet.
+pub fn register(name: &'static str, timeout: Duration) -> WatchdogHandle {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let now = Instant::now();
+    WATCHDOGS.lock().push(Watchdog {
+        id,
+        name,
+        timeout,
+        last_pet: now,
+        fired: false,
+        timer: time::add_timer(now + timeout, expire, id as u64),
+    });
+    WatchdogHandle { id }
+}
+
+/// Whether an expired watchdog reboots the machine after the diagnostic dump.
+pub fn set_reboot_on_expiry(reboot: bool) {
+    REBOOT_ON_EXPIRY.store(reboot, Ordering::Relaxed);
+}
+
+impl WatchdogHandle {
+    /// Signals that the owner is still making progress.
+    pub fn pet(&self) {
+        self.with(|watchdog| {
+            watchdog.last_pet = Instant::now();
+            watchdog.fired = false;
+            time::cancel_timer(watchdog.timer);
+            watchdog.timer = time::add_timer(watchdog.last_pet + watchdog.timeout, expire, watchdog.id as u64);
+        });
+    }
+
+    /// Whether the watchdog expired since it was last petted.
+    pub fn has_fired(&self) -> bool {
+        self.with(|watchdog| watchdog.fired)
+    }
+
+    fn with<R>(&self, f: impl FnOnce(&mut Watchdog) -> R) -> R {
+        let mut watchdogs = WATCHDOGS.lock();
+        let watchdog = watchdogs.iter_mut()
+            .find(|w| w.id == self.id)
+            .expect("watchdog handle without registration");
+        f(watchdog)
+    }
+}
+
+impl Drop for WatchdogHandle {
+    fn drop(&mut self) {
+        self.with(|watchdog| time::cancel_timer(watchdog.timer));
+        WATCHDOGS.lock().retain(|w| w.id != self.id);
+    }
+}
+
+/// Timer callback of a watchdog that was not petted within its timeout.
+fn expire(_id: u64) {
+    EXPIRED.store(true, Ordering::Relaxed);
+}
+
+/// Called from the timer interrupt after the expired timers ran: reports every
+/// watchdog that has not been petted within its timeout.
+pub fn check(stack_frame: &InterruptStackFrame) {
+    if !EXPIRED.load(Ordering::Relaxed) {
+        return;
+    }
+    // a handle is being petted or registered right now, try again next tick
+    let mut watchdogs = match WATCHDOGS.try_lock() {
+        Some(watchdogs) => watchdogs,
+        None => return,
+    };
+    EXPIRED.store(false, Ordering::Relaxed);
+
+    // the timer may have raced with a pet
+    let now = Instant::now();
+    let mut expired = false;
+    for watchdog in watchdogs.iter_mut() {
+        if !watchdog.fired && now.duration_since(watchdog.last_pet) > watchdog.timeout {
+            watchdog.fired = true;
+            expired = true;
+        }
+    }
+    if !expired {
+        return;
+    }
+
+    // the hang may be inside a serial print, so don't wait for the lock
+    if SERIAL1.is_locked() {
+        unsafe { SERIAL1.force_unlock() };
+    }
+    serial_println!("WATCHDOG: timeout expired");
+    for watchdog in watchdogs.iter() {
+        serial_println!("  {:<16} timeout {:?}, last pet {:?} ago{}",
+            watchdog.name,
+            watchdog.timeout,
+            now.duration_since(watchdog.last_pet),
+            if watchdog.fired { " [EXPIRED]" } else { "" });
+    }
+    serial_println!("interrupted context: {:#?}", stack_frame);
+    serial_println!("threads:");
+    let listed = sched::try_for_each_thread(|id, name, state, rip| {
+        serial_print!("  {:>4} {:<8} {:<16}", id.0, state.name(), name);
+        match rip.map(|rip| (rip, symbols::resolve(rip))) {
+            Some((rip, Some(symbol))) => {
+                serial_println!(" rip {:#x} ({})", rip, symbol);
+            }
+            Some((rip, None)) => {
+                serial_println!(" rip {:#x}", rip);
+            }
+            // the one the frame above interrupted
+            None => {
+                serial_println!(" running");
+            }
+        }
+    });
+    if !listed {
+        serial_println!("  scheduler is locked");
+    }
+    serial_println!("recent log:");
+    klog::recent_lines(|line| {
+        serial_println!("  {}", core::str::from_utf8(line).unwrap_or("<garbled>"));
+    });
+
+    if REBOOT_ON_EXPIRY.load(Ordering::Relaxed) {
+        crate::reboot();
+    }
+}
+
+#[test_case]
+fn test_petted_watchdog_does_not_fire() {
+    let handle = register("test", Duration::from_millis(50));
+    for _ in 0..3 {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_millis(30) {}
+        handle.pet();
+    }
+    assert!(!handle.has_fired());
+}
@@ -0,0 +1,100 @@
+//! Deferred work: interrupt handlers queue closures here, and a kernel thread
+//! runs them later with interrupts enabled.
+//!
+//! Handlers should only do what can't wait (acknowledge the device, read its
+//! data) and queue the rest, so interrupts stay disabled for as short as possible.
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+use crate::arch::interrupts;
+use crate::sched::{self, Priority, WaitQueue};
+use crate::initcall;
+
+/// Work items that may wait at once. Queueing more fails.
+const MAX_PENDING: usize = 64;
+
+type Work = Box<dyn FnOnce() + Send>;
+
+static PENDING: Mutex<VecDeque<Work>> = Mutex::new(VecDeque::new());
+/// Notified when work is queued, and when the worker finished an item.
+static WAKEUP: WaitQueue = WaitQueue::new();
+static STARTED: AtomicBool = AtomicBool::new(false);
+/// Set while the worker runs an item.
+static RUNNING: AtomicBool = AtomicBool::new(false);
+static QUEUED: AtomicU64 = AtomicU64::new(0);
+static DONE: AtomicU64 = AtomicU64::new(0);
+
+/// Starts the worker thread. Must be called after `sched::init`.
+pub fn init() -> Result<(), &'static str> {
+    interrupts::without_interrupts(|| PENDING.lock().reserve(MAX_PENDING));
+    sched::spawn_with_priority("workqueue", Priority::Realtime, worker)?;
+    STARTED.store(true, Ordering::Release);
+    Ok(())
+}
+initcall!(Core, "workqueue", init, needs: &["sched"], required: true);
+
+/// Queues `work` to run on the worker thread. Usable from interrupt handlers.
+///
+/// Fails before `init` and when `MAX_PENDING` items are waiting already, in
+/// which case the caller has to do the work itself or drop it.
+pub fn queue(work: impl FnOnce() + Send + 'static) -> Result<(), &'static str> {
+    if !STARTED.load(Ordering::Acquire) {
+        return Err("work queue is not running");
+    }
+    let work: Work = Box::new(work);
+    let queued = interrupts::without_interrupts(|| {
+        let mut pending = PENDING.lock();
+        if pending.len() >= MAX_PENDING {
+            return false;
+        }
+        pending.push_back(work);
+        true
+    });
+    if !queued {
+        return Err("work queue is full");
+    }
+    QUEUED.fetch_add(1, Ordering::Relaxed);
+    WAKEUP.notify_all();
+    Ok(())
+}
+
+/// Whether the caller is deferred work, done on behalf of an interrupt handler.
+pub fn in_worker() -> bool {
+    RUNNING.load(Ordering::Relaxed)
+}
+
+/// Waits until everything queued before the call has run.
+pub fn flush() {
+    let target = QUEUED.load(Ordering::Relaxed);
+    WAKEUP.wait_until(|| DONE.load(Ordering::Relaxed) >= target);
+}
+
+fn worker() {
+    loop {
+        WAKEUP.wait_until(|| !PENDING.lock().is_empty());
+        while let Some(work) = interrupts::without_interrupts(|| PENDING.lock().pop_front()) {
+            RUNNING.store(true, Ordering::Relaxed);
+            work();
+            RUNNING.store(false, Ordering::Relaxed);
+            DONE.fetch_add(1, Ordering::Relaxed);
+        }
+        WAKEUP.notify_all();
+    }
+}
+
+#[test_case]
+fn test_queued_work_runs() {
+    static RAN: AtomicBool = AtomicBool::new(false);
+    if !STARTED.load(Ordering::Acquire) {
+        init().unwrap();
+    }
+    queue(|| {
+        assert!(in_worker());
+        RAN.store(true, Ordering::SeqCst);
+    }).unwrap();
+    flush();
+    assert!(RAN.load(Ordering::SeqCst));
+    assert!(!in_worker());
+}
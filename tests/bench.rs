@@ -0,0 +1,67 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(MarOS::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use MarOS::boot::info::BootInformation;
+use core::panic::PanicInfo;
+use spin::Mutex;
+use x86_64::structures::paging::FrameAllocator;
+use MarOS::bench_test;
+use MarOS::memory::BootInfoFrameAllocator;
+
+entry_point!(main);
+
+/// Handed over from `main` so the frame allocator benchmark can use it.
+static FRAME_ALLOCATOR: Mutex<Option<BootInfoFrameAllocator>> = Mutex::new(None);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use MarOS::allocator;
+    use MarOS::memory;
+    use x86_64::VirtAddr;
+
+    let boot_info = MarOS::boot::set_info(BootInformation::from(boot_info));
+    MarOS::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(boot_info.memory_regions())
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    MarOS::test_panic_handler(info)
+}
+
+bench_test!(bench_box_alloc_free, 20_000, |b| b.iter(|| Box::new(42u64)));
+
+bench_test!(bench_vec_push_100, 200_000, |b| b.iter(|| {
+    let mut vec = Vec::new();
+    for i in 0..100u64 {
+        vec.push(i);
+    }
+    vec
+}));
+
+bench_test!(bench_vga_println, 2_000_000, |b| b.iter(|| {
+    MarOS::println!("bench_vga_println output");
+}));
+
+bench_test!(bench_frame_allocation, 1_000_000, |b| {
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    let frame_allocator = frame_allocator.as_mut().unwrap();
+    b.iter_n(1000, || frame_allocator.allocate_frame().expect("out of frames"));
+});
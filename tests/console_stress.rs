@@ -0,0 +1,163 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(MarOS::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+use MarOS::arch::interrupts::{self, without_interrupts};
+use MarOS::boot::info::BootInformation;
+use MarOS::console::{self, ScreenshotFormat};
+use MarOS::time::{self, Instant};
+use MarOS::vga_buffer::{TEXT_HEIGHT, WRITER};
+use MarOS::{println, sched};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use MarOS::allocator;
+    use MarOS::initcall::{self, Level};
+    use MarOS::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    let boot_info = MarOS::boot::set_info(BootInformation::from(boot_info));
+    MarOS::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(boot_info.memory_regions())
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+    // the scheduler, the timers and the console thread
+    initcall::run(Level::Core);
+
+    test_main();
+    MarOS::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    MarOS::test_panic_handler(info)
+}
+
+/// A PIC line nothing else uses here, raised with `int` instead of a device.
+const SYNTHETIC_IRQ: u8 = 5;
+const THREADS: usize = 4;
+const LINES_PER_THREAD: u64 = 500;
+/// Printing never waits for a lock, so a `println!` of an interrupt handler
+/// taking longer than this means it did.
+const MAX_LATENCY: Duration = Duration::from_millis(5);
+/// The same for the threads, which the others preempt for a few time slices.
+const MAX_THREAD_LATENCY: Duration = Duration::from_millis(100);
+/// How long the whole test may take before it counts as a deadlock.
+const DEADLINE: Duration = Duration::from_secs(10);
+
+/// The tags of the printers, padded to `TAG_WIDTH`: threads, the timer and the IRQ.
+const TAGS: [&str; THREADS + 2] = ["thread0", "thread1", "thread2", "thread3", "timer", "irq"];
+const TAG_WIDTH: usize = 8;
+/// Filler after the sequence number, long enough for a torn line to show.
+const FILLER: usize = 40;
+
+static STOP: AtomicBool = AtomicBool::new(false);
+static SEQUENCES: [AtomicU64; TAGS.len()] = {
+    const ZERO: AtomicU64 = AtomicU64::new(0);
+    [ZERO; TAGS.len()]
+};
+/// The slowest `println!` of each printer, in microseconds.
+static LATENCIES: [AtomicU64; TAGS.len()] = {
+    const ZERO: AtomicU64 = AtomicU64::new(0);
+    [ZERO; TAGS.len()]
+};
+
+/// Prints a line of printer `index`: its tag, a sequence number and the
+/// first letter of its tag repeated.
+fn print_line(index: usize) {
+    let sequence = SEQUENCES[index].fetch_add(1, Ordering::Relaxed);
+    let tag = TAGS[index];
+    let filler = [tag.as_bytes()[0]; FILLER];
+    let filler = core::str::from_utf8(&filler).unwrap();
+    let start = Instant::now();
+    println!("{:<width$}{:06} {}", tag, sequence, filler, width = TAG_WIDTH);
+    let micros = start.elapsed().as_micros() as u64;
+    LATENCIES[index].fetch_max(micros, Ordering::Relaxed);
+}
+
+/// Whether `row` is a line `print_line` printed, whole.
+fn is_whole_line(row: &str) -> bool {
+    if row.len() != TAG_WIDTH + 7 + FILLER {
+        return false;
+    }
+    let (tag, rest) = row.split_at(TAG_WIDTH);
+    let tag = tag.trim_end();
+    let (sequence, filler) = rest.split_at(7);
+    let first = match tag.chars().next() {
+        Some(first) => first,
+        None => return false,
+    };
+    TAGS.contains(&tag)
+        && sequence[..6].bytes().all(|byte| byte.is_ascii_digit())
+        && sequence.ends_with(' ')
+        && filler.chars().all(|character| character == first)
+}
+
+fn timer_printer(_arg: u64) {
+    if !STOP.load(Ordering::Relaxed) {
+        print_line(THREADS);
+    }
+}
+
+fn irq_printer() {
+    print_line(THREADS + 1);
+}
+
+#[test_case]
+fn test_println_under_interrupts() {
+    without_interrupts(|| WRITER.lock().clear_all());
+    interrupts::register_irq(SYNTHETIC_IRQ, irq_printer).unwrap();
+    let timer = time::add_periodic_timer(Duration::from_millis(1), timer_printer, 0);
+    let start = Instant::now();
+
+    let mut threads: Vec<_> = (0..THREADS).map(|index| sched::spawn("printer", move || {
+        for _ in 0..LINES_PER_THREAD {
+            print_line(index);
+        }
+    }).unwrap()).collect();
+    // raises the synthetic IRQ while the printers run
+    threads.push(sched::spawn("irq", || while !STOP.load(Ordering::SeqCst) {
+        // the vector of PIC_1_OFFSET + SYNTHETIC_IRQ
+        unsafe { core::arch::asm!("int 37") };
+        sched::sleep_ms(1);
+    }).unwrap());
+
+    let printers = &threads[..THREADS];
+    while printers.iter().any(|&id| !matches!(sched::state(id), None | Some(sched::State::Exited))) {
+        assert!(start.elapsed() < DEADLINE, "printing deadlocked");
+        sched::sleep_ms(10);
+    }
+    STOP.store(true, Ordering::SeqCst);
+    time::cancel_timer(timer);
+    for &id in &threads {
+        sched::join(id);
+    }
+    console::flush();
+
+    for (index, &tag) in TAGS.iter().enumerate() {
+        assert!(SEQUENCES[index].load(Ordering::Relaxed) > 0, "{} never printed", tag);
+        let latency = Duration::from_micros(LATENCIES[index].load(Ordering::Relaxed));
+        let bound = if index < THREADS { MAX_THREAD_LATENCY } else { MAX_LATENCY };
+        assert!(latency <= bound, "a println of {} took {:?}", tag, latency);
+    }
+    let screen = console::screenshot(ScreenshotFormat::Text);
+    for row in screen.lines().take(TEXT_HEIGHT).filter(|row| !row.is_empty()) {
+        assert!(is_whole_line(row), "torn line: {:?}", row);
+    }
+    println!();
+}
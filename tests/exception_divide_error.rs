@@ -0,0 +1,25 @@
+#![no_std]
+#![no_main]
+
+use core::arch::asm;
+use core::panic::PanicInfo;
+use MarOS::{exit_qemu, hlt_loop, serial_print, serial_println, QemuExitCode};
+
+const NAME: &str = "exception_divide_error::divide_by_zero";
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    serial_print!("{}...\t", NAME);
+    MarOS::arch::x86_64::gdt::init();
+    MarOS::arch::x86_64::interrupts::init_idt();
+    // with asm, so the compiler doesn't check the divisor itself
+    unsafe { asm!("xor edx, edx", "mov eax, 1", "xor ecx, ecx", "div ecx", out("eax") _, out("ecx") _, out("edx") _) };
+    serial_println!("[no exception]");
+    exit_qemu(QemuExitCode::Failed);
+    hlt_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    MarOS::expect_panic_handler(NAME, info, "EXCEPTION: DIVIDE ERROR (vector 0)")
+}
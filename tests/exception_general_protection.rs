@@ -0,0 +1,26 @@
+#![no_std]
+#![no_main]
+
+use core::arch::asm;
+use core::panic::PanicInfo;
+use MarOS::{exit_qemu, hlt_loop, serial_print, serial_println, QemuExitCode};
+
+const NAME: &str = "exception_general_protection::bad_segment_load";
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    serial_print!("{}...\t", NAME);
+    MarOS::arch::x86_64::gdt::init();
+    MarOS::arch::x86_64::interrupts::init_idt();
+    // GDT index 0x246 is far beyond the end of the table, which the error
+    // code reports as the selector
+    unsafe { asm!("mov ds, {0:x}", in(reg) 0x1230u16) };
+    serial_println!("[no exception]");
+    exit_qemu(QemuExitCode::Failed);
+    hlt_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    MarOS::expect_panic_handler(NAME, info, "EXCEPTION: GENERAL PROTECTION FAULT (vector 13, error code 0x1230)")
+}
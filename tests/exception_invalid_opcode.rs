@@ -0,0 +1,24 @@
+#![no_std]
+#![no_main]
+
+use core::arch::asm;
+use core::panic::PanicInfo;
+use MarOS::{exit_qemu, hlt_loop, serial_print, serial_println, QemuExitCode};
+
+const NAME: &str = "exception_invalid_opcode::ud2";
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    serial_print!("{}...\t", NAME);
+    MarOS::arch::x86_64::gdt::init();
+    MarOS::arch::x86_64::interrupts::init_idt();
+    unsafe { asm!("ud2") };
+    serial_println!("[no exception]");
+    exit_qemu(QemuExitCode::Failed);
+    hlt_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    MarOS::expect_panic_handler(NAME, info, "EXCEPTION: INVALID OPCODE (vector 6)")
+}
@@ -0,0 +1,33 @@
+#![no_std]
+#![no_main]
+
+use core::arch::asm;
+use core::panic::PanicInfo;
+use MarOS::{exit_qemu, hlt_loop, serial_print, serial_println, QemuExitCode};
+
+const NAME: &str = "exception_unaligned_sse::movaps";
+
+#[repr(align(16))]
+struct Aligned([u8; 32]);
+
+static DATA: Aligned = Aligned([0; 32]);
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    serial_print!("{}...\t", NAME);
+    MarOS::arch::x86_64::gdt::init();
+    MarOS::arch::x86_64::interrupts::init_idt();
+    MarOS::arch::x86_64::fpu::init();
+    // movaps requires 16 byte alignment and raises #GP(0) without it
+    let unaligned = DATA.0.as_ptr().wrapping_add(1);
+    // the kernel is built without SSE, so xmm0 is nobody's register
+    unsafe { asm!("movaps xmm0, [{0}]", in(reg) unaligned, options(nostack, readonly)) };
+    serial_println!("[no exception]");
+    exit_qemu(QemuExitCode::Failed);
+    hlt_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    MarOS::expect_panic_handler(NAME, info, "EXCEPTION: GENERAL PROTECTION FAULT (vector 13, error code 0x0)")
+}
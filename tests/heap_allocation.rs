@@ -8,6 +8,7 @@
 extern crate alloc;
 
 use bootloader::{entry_point, BootInfo};
+use MarOS::boot::info::BootInformation;
 use core::panic::PanicInfo;
 
 entry_point!(main);
@@ -23,11 +24,12 @@ fn main(boot_info: &'static BootInfo) -> ! {
     use MarOS::memory::{self, BootInfoFrameAllocator};
     use x86_64::VirtAddr;
 
+    let boot_info = MarOS::boot::set_info(BootInformation::from(boot_info));
     MarOS::init();
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
     let mut frame_allocator = unsafe {
-        BootInfoFrameAllocator::init(&boot_info.memory_map)
+        BootInfoFrameAllocator::init(boot_info.memory_regions())
     };
     allocator::init_heap(&mut mapper, &mut frame_allocator)
         .expect("heap initialization failed");
@@ -36,7 +38,30 @@ fn main(boot_info: &'static BootInfo) -> ! {
     loop {}
 }
 
+use alloc::alloc::{alloc, dealloc, Layout};
 use alloc::boxed::Box;
+use MarOS::allocator::heap_start;
+
+// Must stay the first test: the linked list allocator never merges freed
+// regions, so the heap is fragmented once the other tests have run.
+// The heap-debug allocator needs room for its canaries, so the whole heap
+// can't be allocated there.
+#[cfg(not(feature = "heap-debug"))]
+#[test_case]
+fn exact_heap_boundary() {
+    let whole_heap = Layout::from_size_align(HEAP_SIZE, 8).unwrap();
+    for _ in 0..2 {
+        let ptr = unsafe { alloc(whole_heap) };
+        assert_eq!(ptr as usize, heap_start());
+        unsafe {
+            ptr.write_bytes(0xab, HEAP_SIZE);
+            dealloc(ptr, whole_heap);
+        }
+    }
+
+    let too_large = Layout::from_size_align(HEAP_SIZE + 1, 8).unwrap();
+    assert!(unsafe { alloc(too_large) }.is_null());
+}
 
 #[test_case]
 fn simple_allocation() {
@@ -48,6 +73,18 @@ fn simple_allocation() {
 
 use alloc::vec::Vec;
 
+#[test_case]
+fn large_vec_reallocation() {
+    let mut vec = Vec::with_capacity(1);
+    for i in 0..2000u64 {
+        vec.push(i);
+    }
+    assert!(vec.capacity() >= 2000);
+    assert!(vec.iter().enumerate().all(|(i, &v)| v == i as u64));
+    vec.shrink_to_fit();
+    assert_eq!(vec.iter().sum::<u64>(), 1999 * 2000 / 2);
+}
+
 #[test_case]
 fn large_vec() {
     let n = 1000;
@@ -67,3 +104,50 @@ fn many_boxes() {
         assert_eq!(*x, i);
     }
 }
+
+#[test_case]
+fn many_boxes_long_lived() {
+    let long_lived = Box::new(1);
+    for i in 0..HEAP_SIZE {
+        let x = Box::new(i);
+        assert_eq!(*x, i);
+    }
+    assert_eq!(*long_lived, 1);
+}
+
+#[test_case]
+fn interleaved_lifetimes() {
+    let mut long_lived = Vec::new();
+    for i in 0..100usize {
+        let short_lived = Box::new([i; 16]);
+        if i % 10 == 0 {
+            long_lived.push(Box::new(i));
+        }
+        assert!(short_lived.iter().all(|&v| v == i));
+    }
+    assert!(long_lived.iter().enumerate().all(|(j, v)| **v == j * 10));
+}
+
+#[test_case]
+fn memory_is_reused() {
+    let first = Box::new(7u64);
+    let addr = &*first as *const u64;
+    drop(first);
+    let second = Box::new(8u64);
+    assert_eq!(&*second as *const u64, addr);
+}
+
+#[test_case]
+fn aligned_allocations() {
+    for &align in &[8, 16, 64, 256, 1024, 4096] {
+        let layout = Layout::from_size_align(100, align).unwrap();
+        let ptr = unsafe { alloc(layout) };
+        assert!(!ptr.is_null());
+        assert_eq!(ptr as usize % align, 0);
+        assert!(ptr as usize >= heap_start() && ptr as usize + 100 <= heap_start() + HEAP_SIZE);
+        unsafe {
+            ptr.write_bytes(0xcd, 100);
+            dealloc(ptr, layout);
+        }
+    }
+}
@@ -0,0 +1,181 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(MarOS::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+use MarOS::boot::info::BootInformation;
+use MarOS::sched::{self, stack};
+use MarOS::time::Instant;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use MarOS::allocator;
+    use MarOS::initcall::{self, Level};
+    use MarOS::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    let boot_info = MarOS::boot::set_info(BootInformation::from(boot_info));
+    MarOS::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(boot_info.memory_regions())
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+    // the scheduler and the timers
+    initcall::run(Level::Core);
+
+    test_main();
+    MarOS::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    MarOS::test_panic_handler(info)
+}
+
+/// Threads spinning at once in `test_fair_progress`.
+const SPINNERS: usize = 3;
+/// How much of its share a spinning thread may fall behind, in percent.
+const FAIRNESS_PERCENT: u64 = 50;
+/// How late a sleeping thread may wake.
+const WAKE_TOLERANCE: Duration = Duration::from_millis(20);
+
+#[test_case]
+fn test_fair_progress() {
+    static COUNTERS: [AtomicU64; SPINNERS] = [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)];
+    static STOP: AtomicBool = AtomicBool::new(false);
+
+    // they never yield, so only the timer interrupt switches between them
+    let threads: Vec<_> = (0..SPINNERS).map(|index| sched::spawn("spinner", move || {
+        while !STOP.load(Ordering::SeqCst) {
+            COUNTERS[index].fetch_add(1, Ordering::Relaxed);
+        }
+    }).unwrap()).collect();
+    sched::sleep_ms(300);
+    STOP.store(true, Ordering::SeqCst);
+    for &id in &threads {
+        sched::join(id);
+    }
+
+    let counts: Vec<u64> = COUNTERS.iter().map(|counter| counter.load(Ordering::Relaxed)).collect();
+    let total: u64 = counts.iter().sum();
+    for &count in &counts {
+        assert!(count * SPINNERS as u64 * 100 >= total * FAIRNESS_PERCENT, "unfair progress: {:?}", counts);
+    }
+}
+
+#[test_case]
+fn test_sleepers_wake_in_time() {
+    static LATENESS: [AtomicU64; 4] = [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)];
+    static STOP: AtomicBool = AtomicBool::new(false);
+
+    // keeps the CPU busy, so the sleepers are woken under preemption
+    let spinner = sched::spawn("spinner", || while !STOP.load(Ordering::SeqCst) {
+        core::hint::spin_loop();
+    }).unwrap();
+    let sleepers: Vec<_> = (0..LATENESS.len()).map(|index| sched::spawn("sleeper", move || {
+        let duration = Duration::from_millis(10 << index);
+        let start = Instant::now();
+        sched::sleep(duration);
+        let elapsed = start.elapsed();
+        assert!(elapsed >= duration, "woke after {:?} of {:?}", elapsed, duration);
+        LATENESS[index].store((elapsed - duration).as_micros() as u64, Ordering::SeqCst);
+    }).unwrap()).collect();
+    for &id in &sleepers {
+        sched::join(id);
+    }
+    STOP.store(true, Ordering::SeqCst);
+    sched::join(spinner);
+
+    for lateness in &LATENESS {
+        let lateness = Duration::from_micros(lateness.load(Ordering::SeqCst));
+        assert!(lateness <= WAKE_TOLERANCE, "woke {:?} late", lateness);
+    }
+}
+
+#[test_case]
+fn test_exited_threads_free_stacks() {
+    const THREADS: usize = 4;
+
+    let threads: Vec<_> = (0..THREADS).map(|_| sched::spawn("exiting", || {}).unwrap()).collect();
+    for &id in &threads {
+        sched::join(id);
+    }
+    let free = stack::free_count();
+    // spawning reaps the exited threads, then takes a stack for the new one
+    let last = sched::spawn("exiting", || {}).unwrap();
+    for &id in &threads {
+        assert_eq!(sched::state(id), None, "thread {} was not reaped", id.0);
+    }
+    assert_eq!(stack::free_count(), (free + THREADS).min(stack::MAX_FREE) - 1);
+    sched::join(last);
+}
+
+#[test_case]
+fn test_simd_state_survives_preemption() {
+    /// Additions per `asm!` block, between which nothing touches the registers
+    /// either, as the kernel is built without SSE.
+    const BATCH: u64 = 100_000;
+    const RUN_FOR: Duration = Duration::from_millis(200);
+    static FAILURES: AtomicU64 = AtomicU64::new(0);
+
+    /// Counts up both lanes of xmm0 from `seed` for `RUN_FOR`, while xmm7
+    /// holds `!seed` throughout, and checks both at the end.
+    fn add_up(seed: u64) {
+        unsafe {
+            core::arch::asm!(
+                "movq xmm0, {seed}", "punpcklqdq xmm0, xmm0",
+                "movq xmm1, {one}", "punpcklqdq xmm1, xmm1",
+                "movq xmm7, {inverse}", "punpcklqdq xmm7, xmm7",
+                seed = in(reg) seed, one = in(reg) 1u64, inverse = in(reg) !seed,
+                options(nomem, nostack),
+            );
+        }
+        let start = Instant::now();
+        let mut added = 0;
+        while start.elapsed() < RUN_FOR {
+            unsafe {
+                core::arch::asm!("2:", "paddq xmm0, xmm1", "dec {n}", "jnz 2b", n = inout(reg) BATCH => _,
+                    options(nomem, nostack));
+            }
+            added += BATCH;
+        }
+        let lanes = |register: u8| {
+            let (low, high): (u64, u64);
+            unsafe {
+                match register {
+                    0 => core::arch::asm!("movq {}, xmm0", "movhlps xmm2, xmm0", "movq {}, xmm2",
+                        out(reg) low, out(reg) high, options(nomem, nostack)),
+                    _ => core::arch::asm!("movq {}, xmm7", "movhlps xmm2, xmm7", "movq {}, xmm2",
+                        out(reg) low, out(reg) high, options(nomem, nostack)),
+                }
+            }
+            [low, high]
+        };
+        if lanes(0) != [seed + added; 2] || lanes(7) != [!seed; 2] {
+            FAILURES.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    // both spin for the whole time, so each is preempted every time slice
+    let threads = [
+        sched::spawn("simd", || add_up(0x1111_0000_0000)).unwrap(),
+        sched::spawn("simd", || add_up(0x2222_0000_0000)).unwrap(),
+    ];
+    for &id in &threads {
+        sched::join(id);
+    }
+    assert_eq!(FAILURES.load(Ordering::SeqCst), 0, "SIMD registers corrupted by a task switch");
+}
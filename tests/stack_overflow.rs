@@ -7,13 +7,13 @@ use lazy_static::lazy_static;
 use volatile::Volatile;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
 use MarOS::{exit_qemu, init, serial_println};
-use MarOS::interrupts::init_idt;
+use MarOS::arch::x86_64::interrupts::init_idt;
 use MarOS::QemuExitCode::Success;
 
 #[no_mangle]
 pub extern "C" fn _start() -> ! {
     serial_println!("stack_overflow::stack_overflow...\t");
-    MarOS::gdt::init();
+    MarOS::arch::x86_64::gdt::init();
     init_test_idt();
     stack_overflow();
     panic!("Execution continued after stack_overflow!")
@@ -36,7 +36,7 @@ lazy_static!{
         unsafe {
             idt.double_fault
             .set_handler_fn(test_double_fault_handler)
-            .set_stack_index(MarOS::gdt::DOUBLE_FAULT_IST_INDEX);
+            .set_stack_index(MarOS::arch::x86_64::gdt::DOUBLE_FAULT_IST_INDEX);
         }
         idt
     };
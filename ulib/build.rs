@@ -0,0 +1,7 @@
+// links the examples to load where the kernel expects programs; programs of
+// other crates pass `-C link-arg=-T<path to link.ld>` themselves
+fn main() {
+    let dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+    println!("cargo:rustc-link-arg-examples=-T{}/link.ld", dir);
+    println!("cargo:rerun-if-changed=link.ld");
+}
@@ -0,0 +1,42 @@
+//! Greets, then shows the heap and files at work. Build it with
+//! `cargo build -p maros-ulib --example hello` and copy
+//! `target/x86_64-MarOS/debug/examples/hello` onto a disk the kernel mounts.
+
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use maros_ulib::fs::{File, O_CREAT, O_TRUNC};
+use maros_ulib::{entry, println, process, time};
+
+entry!(main);
+
+fn main() -> i32 {
+    println!("hello from PID {}", process::getpid());
+    let start = time::Instant::now();
+    println!("up for {:?}, {} ticks", time::uptime(), time::ticks());
+
+    let squares: Vec<u64> = (1..=10).map(|n| n * n).collect();
+    println!("squares: {:?}", squares);
+
+    let file = match File::open("/tmp/hello.txt", O_CREAT | O_TRUNC) {
+        Ok(file) => file,
+        Err(errno) => {
+            println!("cannot open /tmp/hello.txt: {}", errno);
+            return 1;
+        }
+    };
+    let mut text = String::new();
+    for square in &squares {
+        text.push_str(&alloc::format!("{}\n", square));
+    }
+    if let Err(errno) = file.write_all(text.as_bytes()) {
+        println!("cannot write /tmp/hello.txt: {}", errno);
+        return 1;
+    }
+    println!("wrote {} bytes to /tmp/hello.txt in {:?}", text.len(), start.elapsed());
+    0
+}
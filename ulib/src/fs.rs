@@ -0,0 +1,121 @@
+//! Files: opening, reading and writing them, pipes and read-only mappings.
+
+use core::slice;
+use crate::syscall::{self, syscall, Errno};
+
+pub const O_CREAT: u64 = 0o100;
+pub const O_TRUNC: u64 = 0o1000;
+
+pub const SEEK_SET: u64 = 0;
+pub const SEEK_CUR: u64 = 1;
+pub const SEEK_END: u64 = 2;
+
+/// An open file descriptor, closed when dropped.
+#[derive(Debug)]
+pub struct File {
+    fd: u64,
+}
+
+impl File {
+    /// Opens the file at `path`, with `O_CREAT` and `O_TRUNC` in `flags`.
+    pub fn open(path: &str, flags: u64) -> Result<File, Errno> {
+        let fd = unsafe { syscall(syscall::OPEN, [path.as_ptr() as u64, path.len() as u64, flags, 0]) }?;
+        Ok(File { fd })
+    }
+
+    /// Takes over the descriptor `fd`, which it closes when dropped.
+    pub fn from_raw_fd(fd: u64) -> File {
+        File { fd }
+    }
+
+    /// Gives up the descriptor without closing it.
+    pub fn into_raw_fd(self) -> u64 {
+        let fd = self.fd;
+        core::mem::forget(self);
+        fd
+    }
+
+    pub fn as_raw_fd(&self) -> u64 {
+        self.fd
+    }
+
+    /// Reads into `buf`, returning the number of bytes read, 0 at the end.
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize, Errno> {
+        read(self.fd, buf)
+    }
+
+    /// Writes `buf`, returning the number of bytes written.
+    pub fn write(&self, buf: &[u8]) -> Result<usize, Errno> {
+        write(self.fd, buf)
+    }
+
+    /// Writes all of `buf`.
+    pub fn write_all(&self, mut buf: &[u8]) -> Result<(), Errno> {
+        while !buf.is_empty() {
+            match self.write(buf)? {
+                0 => return Err(Errno::EIO),
+                n => buf = &buf[n..],
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves the offset by `offset` from `SEEK_SET`, `SEEK_CUR` or `SEEK_END`
+    /// and returns it.
+    pub fn seek(&self, offset: i64, whence: u64) -> Result<u64, Errno> {
+        unsafe { syscall(syscall::LSEEK, [self.fd, offset as u64, whence, 0]) }
+    }
+
+    /// Another descriptor for the same open file, sharing its offset.
+    pub fn try_clone(&self) -> Result<File, Errno> {
+        let fd = unsafe { syscall(syscall::DUP, [self.fd, 0, 0, 0]) }?;
+        Ok(File { fd })
+    }
+
+    /// Maps `len` bytes of the file from `offset`, which must be page aligned,
+    /// read-only. The mapping stays until `unmap`.
+    pub fn map(&self, offset: u64, len: usize) -> Result<&'static [u8], Errno> {
+        let addr = unsafe { syscall(syscall::MMAP, [self.fd, offset, len as u64, 0]) }?;
+        Ok(unsafe { slice::from_raw_parts(addr as *const u8, len) })
+    }
+}
+
+impl Drop for File {
+    fn drop(&mut self) {
+        let _ = unsafe { syscall(syscall::CLOSE, [self.fd, 0, 0, 0]) };
+    }
+}
+
+/// Reads from the descriptor `fd` into `buf`.
+pub fn read(fd: u64, buf: &mut [u8]) -> Result<usize, Errno> {
+    let len = unsafe { syscall(syscall::READ, [fd, buf.as_mut_ptr() as u64, buf.len() as u64, 0]) }?;
+    Ok(len as usize)
+}
+
+/// Writes `buf` to the descriptor `fd`.
+pub fn write(fd: u64, buf: &[u8]) -> Result<usize, Errno> {
+    let len = unsafe { syscall(syscall::WRITE, [fd, buf.as_ptr() as u64, buf.len() as u64, 0]) }?;
+    Ok(len as usize)
+}
+
+/// Creates a pipe, returning its reading and writing ends.
+pub fn pipe() -> Result<(File, File), Errno> {
+    let mut fds = [0u32; 2];
+    unsafe { syscall(syscall::PIPE, [fds.as_mut_ptr() as u64, 0, 0, 0]) }?;
+    Ok((File { fd: u64::from(fds[0]) }, File { fd: u64::from(fds[1]) }))
+}
+
+/// Unmaps a mapping made by `File::map`.
+///
+/// ## Safety
+///
+/// Nothing may use the mapping anymore.
+pub unsafe fn unmap(mapping: &'static [u8]) -> Result<(), Errno> {
+    syscall(syscall::MUNMAP, [mapping.as_ptr() as u64, mapping.len() as u64, 0, 0]).map(drop)
+}
+
+/// Mounts the filesystem on the block device `device` at `path`.
+pub fn mount(device: &str, path: &str) -> Result<(), Errno> {
+    let args = [device.as_ptr() as u64, device.len() as u64, path.as_ptr() as u64, path.len() as u64];
+    unsafe { syscall(syscall::MOUNT, args) }.map(drop)
+}
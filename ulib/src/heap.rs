@@ -0,0 +1,82 @@
+//! The global allocator: a bump allocator on the heap `brk` grows.
+//!
+//! A process has a single thread, so nothing is locked. Freed memory is only
+//! reused when it is the last allocation; programs that allocate and free a
+//! lot in a loop should reuse their buffers.
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::cell::Cell;
+use core::ptr;
+use crate::process;
+
+/// How much the heap grows at least at once.
+const GROWTH: u64 = 64 * 1024;
+
+struct Heap {
+    /// Where the next allocation starts, 0 before the first one.
+    next: Cell<u64>,
+    /// The end of the heap, as `brk` last set it.
+    end: Cell<u64>,
+}
+
+// processes have a single thread
+unsafe impl Sync for Heap {}
+
+#[global_allocator]
+static HEAP: Heap = Heap { next: Cell::new(0), end: Cell::new(0) };
+
+unsafe impl GlobalAlloc for Heap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if self.next.get() == 0 {
+            match process::brk(0) {
+                Ok(end) => {
+                    self.next.set(end);
+                    self.end.set(end);
+                }
+                Err(_) => return ptr::null_mut(),
+            }
+        }
+        let align = layout.align() as u64;
+        let start = match self.next.get().checked_add(align - 1) {
+            Some(start) => start & !(align - 1),
+            None => return ptr::null_mut(),
+        };
+        let end = match start.checked_add(layout.size() as u64) {
+            Some(end) => end,
+            None => return ptr::null_mut(),
+        };
+        if end > self.end.get() {
+            let new_end = end.max(self.end.get() + GROWTH);
+            match process::brk(new_end).or_else(|_| process::brk(end)) {
+                Ok(new_end) => self.end.set(new_end),
+                Err(_) => return ptr::null_mut(),
+            }
+        }
+        self.next.set(end);
+        start as *mut u8
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if ptr as u64 + layout.size() as u64 == self.next.get() {
+            self.next.set(ptr as u64);
+        }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        // the last allocation grows or shrinks in place
+        if ptr as u64 + layout.size() as u64 == self.next.get() {
+            let end = ptr as u64 + new_size as u64;
+            if end <= self.end.get() || process::brk(end).map(|end| self.end.set(end)).is_ok() {
+                self.next.set(end);
+                return ptr;
+            }
+            return ptr::null_mut();
+        }
+        let new = self.alloc(Layout::from_size_align_unchecked(new_size, layout.align()));
+        if !new.is_null() {
+            ptr::copy_nonoverlapping(ptr, new, layout.size().min(new_size));
+            self.dealloc(ptr, layout);
+        }
+        new
+    }
+}
@@ -0,0 +1,72 @@
+//! Standard input and output, and the `print!` family writing to them.
+
+use core::fmt::{self, Write};
+use crate::fs;
+
+pub const STDIN: u64 = 0;
+pub const STDOUT: u64 = 1;
+pub const STDERR: u64 = 2;
+
+/// A descriptor written to with `fmt::Write`.
+struct Output(u64);
+
+impl Write for Output {
+    fn write_str(&mut self, mut s: &str) -> fmt::Result {
+        while !s.is_empty() {
+            match fs::write(self.0, s.as_bytes()) {
+                Ok(n) if n > 0 => s = &s[n..],
+                _ => return Err(fmt::Error),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(fd: u64, args: fmt::Arguments) {
+    let _ = Output(fd).write_fmt(args);
+}
+
+/// Reads a line from standard input into `buf`, newline included unless it
+/// didn't fit or the input ended. Returns its length, 0 at the end of input.
+pub fn read_line(buf: &mut [u8]) -> Result<usize, crate::Errno> {
+    let mut len = 0;
+    while len < buf.len() {
+        match fs::read(STDIN, &mut buf[len..])? {
+            0 => break,
+            n => {
+                len += n;
+                if buf[len - 1] == b'\n' {
+                    break;
+                }
+            }
+        }
+    }
+    Ok(len)
+}
+
+/// Prints to standard output.
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ($crate::io::_print($crate::io::STDOUT, format_args!($($arg)*)));
+}
+
+/// Prints to standard output, with a newline.
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
+}
+
+/// Prints to standard error.
+#[macro_export]
+macro_rules! eprint {
+    ($($arg:tt)*) => ($crate::io::_print($crate::io::STDERR, format_args!($($arg)*)));
+}
+
+/// Prints to standard error, with a newline.
+#[macro_export]
+macro_rules! eprintln {
+    () => ($crate::eprint!("\n"));
+    ($($arg:tt)*) => ($crate::eprint!("{}\n", format_args!($($arg)*)));
+}
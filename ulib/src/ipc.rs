@@ -0,0 +1,32 @@
+//! Message passing through named ports: a port belongs to the process that
+//! created it, the only one that can receive from it.
+
+use crate::syscall::{self, syscall, Errno};
+
+/// Largest message in bytes.
+pub const MESSAGE_SIZE: usize = 64;
+
+/// Creates the port `name`. Only this process may send to it if `private`,
+/// anyone may otherwise.
+pub fn create(name: &str, private: bool) -> Result<(), Errno> {
+    unsafe { syscall(syscall::PORT_CREATE, [name.as_ptr() as u64, name.len() as u64, private as u64, 0]) }.map(drop)
+}
+
+/// Removes the port `name` of this process, dropping its messages.
+pub fn destroy(name: &str) -> Result<(), Errno> {
+    unsafe { syscall(syscall::PORT_DESTROY, [name.as_ptr() as u64, name.len() as u64, 0, 0]) }.map(drop)
+}
+
+/// Queues `message` on the port `name`. Fails with `EAGAIN` if the port is
+/// full and with `EMSGSIZE` if the message is longer than `MESSAGE_SIZE`.
+pub fn send(name: &str, message: &[u8]) -> Result<(), Errno> {
+    let args = [name.as_ptr() as u64, name.len() as u64, message.as_ptr() as u64, message.len() as u64];
+    unsafe { syscall(syscall::SEND, args) }.map(drop)
+}
+
+/// Waits for the next message on the port `name` of this process and returns
+/// its length.
+pub fn receive(name: &str, buf: &mut [u8; MESSAGE_SIZE]) -> Result<usize, Errno> {
+    let args = [name.as_ptr() as u64, name.len() as u64, buf.as_mut_ptr() as u64, MESSAGE_SIZE as u64];
+    unsafe { syscall(syscall::RECEIVE, args) }.map(|len| len as usize)
+}
@@ -0,0 +1,55 @@
+//! The runtime of MarOS user programs: the entry point, `print!` and friends,
+//! a heap on `brk` for `alloc`, the process, file, IPC, shared memory and
+//! signal system calls, the time without system calls, and a panic handler
+//! that reports the panic and exits with 101.
+//!
+//! A program is a `no_std`, `no_main` binary naming its main function with
+//! `entry!`:
+//!
+//! ```ignore
+//! #![no_std]
+//! #![no_main]
+//!
+//! use maros_ulib::{entry, println};
+//!
+//! entry!(main);
+//!
+//! fn main() -> i32 {
+//!     println!("hello from PID {}", maros_ulib::process::getpid());
+//!     0
+//! }
+//! ```
+//!
+//! It is linked with `link.ld` to load where the kernel expects programs, see
+//! `examples/hello.rs` for how to build one.
+
+#![no_std]
+#![feature(alloc_error_handler)]
+
+extern crate alloc;
+
+pub mod fs;
+mod heap;
+pub mod io;
+pub mod ipc;
+pub mod process;
+mod rt;
+pub mod shm;
+pub mod signal;
+pub mod syscall;
+pub mod time;
+
+pub use syscall::Errno;
+
+/// Makes `$main`, a `fn() -> i32`, the main function of the program. The
+/// program exits with what it returns.
+#[macro_export]
+macro_rules! entry {
+    ($main:path) => {
+        #[export_name = "maros_ulib_main"]
+        pub extern "C" fn __maros_ulib_main() -> ! {
+            let main: fn() -> i32 = $main;
+            $crate::process::exit(main())
+        }
+    };
+}
@@ -0,0 +1,87 @@
+//! Processes: exiting, forking, running programs and waiting for them, and
+//! the arguments of this one.
+
+use alloc::vec::Vec;
+use core::{slice, str};
+use crate::syscall::{self, syscall, Errno};
+
+/// Ends the process with `code` as its exit status.
+pub fn exit(code: i32) -> ! {
+    unsafe {
+        let _ = syscall(syscall::EXIT, [code as i64 as u64, 0, 0, 0]);
+    }
+    unreachable!("the process exited")
+}
+
+pub fn getpid() -> u64 {
+    unsafe { syscall(syscall::GETPID, [0; 4]) }.unwrap_or(0)
+}
+
+/// Duplicates the process. Returns the child's id in the parent and 0 in the
+/// child.
+pub fn fork() -> Result<u64, Errno> {
+    unsafe { syscall(syscall::FORK, [0; 4]) }
+}
+
+/// The arguments the program was started with, not counting its path.
+pub fn args() -> impl Iterator<Item = &'static str> {
+    let args = unsafe { slice::from_raw_parts(crate::rt::ARGS as *const u8, crate::rt::ARGS_LEN) };
+    // the kernel ends each with a NUL
+    args.split(|&byte| byte == 0)
+        .take(args.iter().filter(|&&byte| byte == 0).count())
+        .map(|arg| str::from_utf8(arg).unwrap_or(""))
+}
+
+/// Replaces the program of the process with the one at `path`, started with
+/// `args`. Only returns if that failed.
+pub fn exec(path: &str, args: &[&str]) -> Errno {
+    let mut buf = Vec::new();
+    for arg in args {
+        buf.extend_from_slice(arg.as_bytes());
+        buf.push(0);
+    }
+    let call = [path.as_ptr() as u64, path.len() as u64, buf.as_ptr() as u64, buf.len() as u64];
+    match unsafe { syscall(syscall::EXEC, call) } {
+        Ok(_) => unreachable!("exec returned"),
+        Err(errno) => errno,
+    }
+}
+
+/// Waits for the child `pid` to exit and returns its exit status.
+pub fn wait(pid: u64) -> Result<i64, Errno> {
+    unsafe { syscall(syscall::WAIT, [pid, 0, 0, 0]) }.map(|status| status as i64)
+}
+
+/// Like `wait`, but for any child if `pid` is `None`, returning its id with
+/// its status; with `nohang`, returns `None` at once if no child exited yet.
+pub fn waitpid(pid: Option<u64>, nohang: bool) -> Result<Option<(u64, i64)>, Errno> {
+    let mut status = 0i64;
+    let pid = pid.unwrap_or(u64::MAX);
+    let args = [pid, &mut status as *mut i64 as u64, nohang as u64, 0];
+    match unsafe { syscall(syscall::WAITPID, args) }? {
+        0 => Ok(None),
+        child => Ok(Some((child, status))),
+    }
+}
+
+/// Runs the program at `path` with `args` in a child process and waits for
+/// it to exit. Returns its exit status.
+pub fn run(path: &str, args: &[&str]) -> Result<i64, Errno> {
+    match fork()? {
+        0 => {
+            let errno = exec(path, args);
+            crate::eprintln!("cannot run {}: {}", path, errno);
+            exit(127)
+        }
+        child => wait(child),
+    }
+}
+
+pub fn sleep_ms(ms: u64) {
+    let _ = unsafe { syscall(syscall::SLEEP, [ms, 0, 0, 0]) };
+}
+
+/// Moves the end of the heap to `end` and returns it; 0 just returns it.
+pub fn brk(end: u64) -> Result<u64, Errno> {
+    unsafe { syscall(syscall::BRK, [end, 0, 0, 0]) }
+}
@@ -0,0 +1,36 @@
+//! The entry point, `_start`, and what ends the program when it goes wrong.
+
+use core::alloc::Layout;
+use core::arch::global_asm;
+use core::panic::PanicInfo;
+use crate::process;
+
+global_asm!(r#"
+// entered with the arguments on top of the stack, their address in rdi and
+// their length in rsi, and every other register zero
+.global _start
+_start:
+    mov [rip + maros_ulib_args], rdi
+    mov [rip + maros_ulib_args_len], rsi
+    xor ebp, ebp
+    and rsp, -16
+    call maros_ulib_main
+    ud2
+"#);
+
+/// The arguments `_start` found, for `process::args`.
+#[export_name = "maros_ulib_args"]
+pub(crate) static mut ARGS: usize = 0;
+#[export_name = "maros_ulib_args_len"]
+pub(crate) static mut ARGS_LEN: usize = 0;
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    crate::eprintln!("panic: {}", info);
+    process::exit(101)
+}
+
+#[alloc_error_handler]
+fn alloc_error_handler(layout: Layout) -> ! {
+    panic!("allocation error: {:?}", layout)
+}
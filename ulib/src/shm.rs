@@ -0,0 +1,44 @@
+//! Named shared memory objects, mapped into any number of processes, and
+//! futexes to wait on words in them.
+
+use core::sync::atomic::AtomicU32;
+use crate::syscall::{self, syscall, Errno};
+
+/// Creates the object `name` of `size` bytes, rounded up to whole pages and
+/// zeroed.
+pub fn create(name: &str, size: usize) -> Result<(), Errno> {
+    unsafe { syscall(syscall::SHM_CREATE, [name.as_ptr() as u64, name.len() as u64, size as u64, 0]) }.map(drop)
+}
+
+/// Maps the object `name`, writable if `writable`, and returns its address.
+/// The mapping stays until `unmap`, and is shared with the children forked
+/// afterwards.
+pub fn map(name: &str, writable: bool) -> Result<*mut u8, Errno> {
+    let addr = unsafe { syscall(syscall::SHM_MAP, [name.as_ptr() as u64, name.len() as u64, writable as u64, 0]) }?;
+    Ok(addr as *mut u8)
+}
+
+/// Unmaps the `size` bytes mapped at `addr` by `map`.
+///
+/// ## Safety
+///
+/// Nothing may use the mapping anymore.
+pub unsafe fn unmap(addr: *mut u8, size: usize) -> Result<(), Errno> {
+    syscall(syscall::MUNMAP, [addr as u64, size as u64, 0, 0]).map(drop)
+}
+
+/// Removes the name `name`. The mappings keep the object alive.
+pub fn unlink(name: &str) -> Result<(), Errno> {
+    unsafe { syscall(syscall::SHM_UNLINK, [name.as_ptr() as u64, name.len() as u64, 0, 0]) }.map(drop)
+}
+
+/// Blocks while `word` holds `expected`. Returns right away if it doesn't.
+pub fn futex_wait(word: &AtomicU32, expected: u32) -> Result<(), Errno> {
+    unsafe { syscall(syscall::FUTEX_WAIT, [word as *const AtomicU32 as u64, u64::from(expected), 0, 0]) }.map(drop)
+}
+
+/// Wakes up to `count` processes waiting on `word`, returning how many there
+/// were.
+pub fn futex_wake(word: &AtomicU32, count: usize) -> Result<usize, Errno> {
+    unsafe { syscall(syscall::FUTEX_WAKE, [word as *const AtomicU32 as u64, count as u64, 0, 0]) }.map(|n| n as usize)
+}
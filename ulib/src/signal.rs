@@ -0,0 +1,53 @@
+//! Signals: sending them to processes and handling them.
+//!
+//! A handler runs when the process next returns from a system call, on its
+//! stack, with the signal blocked until it returns.
+
+use core::arch::global_asm;
+use crate::syscall::{self, syscall, Errno};
+
+pub const SIGINT: u32 = 2;
+pub const SIGKILL: u32 = 9;
+pub const SIGUSR1: u32 = 10;
+pub const SIGUSR2: u32 = 12;
+pub const SIGTERM: u32 = 15;
+pub const SIGCHLD: u32 = 17;
+
+const SIG_DFL: u64 = 0;
+const SIG_IGN: u64 = 1;
+
+global_asm!(r#"
+// where signal handlers return, to continue where the signal interrupted
+.global maros_ulib_sigreturn
+maros_ulib_sigreturn:
+    mov eax, 29 // SIGRETURN
+    int 0x80
+    ud2
+"#);
+
+extern "C" {
+    fn maros_ulib_sigreturn();
+}
+
+/// Sends `signal` to the process `pid`.
+pub fn kill(pid: u64, signal: u32) -> Result<(), Errno> {
+    unsafe { syscall(syscall::KILL, [pid, u64::from(signal), 0, 0]) }.map(drop)
+}
+
+/// Calls `handler` with the signal's number when `signal` arrives.
+pub fn handle(signal: u32, handler: extern "C" fn(u32)) -> Result<(), Errno> {
+    let trampoline = maros_ulib_sigreturn as unsafe extern "C" fn() as usize as u64;
+    let args = [u64::from(signal), handler as usize as u64, trampoline, 0];
+    unsafe { syscall(syscall::SIGACTION, args) }.map(drop)
+}
+
+/// Ignores `signal`.
+pub fn ignore(signal: u32) -> Result<(), Errno> {
+    unsafe { syscall(syscall::SIGACTION, [u64::from(signal), SIG_IGN, 0, 0]) }.map(drop)
+}
+
+/// Goes back to the default action for `signal`: terminating, or ignoring
+/// `SIGCHLD`.
+pub fn reset(signal: u32) -> Result<(), Errno> {
+    unsafe { syscall(syscall::SIGACTION, [u64::from(signal), SIG_DFL, 0, 0]) }.map(drop)
+}
@@ -0,0 +1,113 @@
+//! The raw system calls: `int 0x80` with the call number in `rax` and the
+//! arguments in `rdi`, `rsi`, `rdx` and `r10`, returning `-errno` on errors.
+//! The numbers are those of the kernel's `process::syscall`.
+
+use core::arch::asm;
+use core::fmt;
+
+pub const EXIT: u64 = 0;
+pub const GETPID: u64 = 1;
+pub const FORK: u64 = 2;
+pub const EXEC: u64 = 3;
+pub const WAIT: u64 = 4;
+pub const OPEN: u64 = 5;
+pub const CLOSE: u64 = 6;
+pub const READ: u64 = 7;
+pub const WRITE: u64 = 8;
+pub const LSEEK: u64 = 9;
+pub const DUP: u64 = 10;
+pub const PIPE: u64 = 11;
+pub const MMAP: u64 = 12;
+pub const MUNMAP: u64 = 13;
+pub const WAITPID: u64 = 14;
+pub const SLEEP: u64 = 15;
+pub const MOUNT: u64 = 16;
+pub const BRK: u64 = 17;
+pub const PORT_CREATE: u64 = 18;
+pub const PORT_DESTROY: u64 = 19;
+pub const SEND: u64 = 20;
+pub const RECEIVE: u64 = 21;
+pub const SHM_CREATE: u64 = 22;
+pub const SHM_MAP: u64 = 23;
+pub const SHM_UNLINK: u64 = 24;
+pub const FUTEX_WAIT: u64 = 25;
+pub const FUTEX_WAKE: u64 = 26;
+pub const KILL: u64 = 27;
+pub const SIGACTION: u64 = 28;
+pub const SIGRETURN: u64 = 29;
+
+/// An error number returned by a system call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Errno(pub i64);
+
+impl Errno {
+    pub const EPERM: Errno = Errno(1);
+    pub const ENOENT: Errno = Errno(2);
+    pub const ESRCH: Errno = Errno(3);
+    pub const EIO: Errno = Errno(5);
+    pub const ENOEXEC: Errno = Errno(8);
+    pub const EBADF: Errno = Errno(9);
+    pub const ECHILD: Errno = Errno(10);
+    pub const EAGAIN: Errno = Errno(11);
+    pub const ENOMEM: Errno = Errno(12);
+    pub const EFAULT: Errno = Errno(14);
+    pub const EEXIST: Errno = Errno(17);
+    pub const ENOTDIR: Errno = Errno(20);
+    pub const EISDIR: Errno = Errno(21);
+    pub const EINVAL: Errno = Errno(22);
+    pub const EMFILE: Errno = Errno(24);
+    pub const EPIPE: Errno = Errno(32);
+    pub const ENOSYS: Errno = Errno(38);
+    pub const ENOTEMPTY: Errno = Errno(39);
+    pub const EMSGSIZE: Errno = Errno(90);
+
+    pub fn name(self) -> &'static str {
+        match self.0 {
+            1 => "operation not permitted",
+            2 => "no such file or directory",
+            3 => "no such process",
+            5 => "input/output error",
+            8 => "not an executable",
+            9 => "bad file descriptor",
+            10 => "no such child",
+            11 => "try again",
+            12 => "out of memory",
+            14 => "bad address",
+            17 => "file exists",
+            20 => "not a directory",
+            21 => "is a directory",
+            22 => "invalid argument",
+            24 => "too many open files",
+            32 => "broken pipe",
+            38 => "no such system call",
+            39 => "directory not empty",
+            90 => "message too long",
+            _ => "unknown error",
+        }
+    }
+}
+
+impl fmt::Display for Errno {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (error {})", self.name(), self.0)
+    }
+}
+
+/// Makes the system call `number` with `args`, the unused ones 0.
+///
+/// ## Safety
+///
+/// The arguments must be what the call expects, pointers included.
+pub unsafe fn syscall(number: u64, args: [u64; 4]) -> Result<u64, Errno> {
+    let result: i64;
+    asm!(
+        "int 0x80",
+        inlateout("rax") number as i64 => result,
+        in("rdi") args[0],
+        in("rsi") args[1],
+        in("rdx") args[2],
+        in("r10") args[3],
+        options(nostack),
+    );
+    if result < 0 { Err(Errno(-result)) } else { Ok(result as u64) }
+}
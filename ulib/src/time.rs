@@ -0,0 +1,102 @@
+//! The time, read from the page the kernel maps into every process (its
+//! `time::vdso`) instead of asking it with a system call.
+
+use core::arch::x86_64::_rdtsc;
+use core::sync::atomic::{fence, AtomicU64, Ordering};
+use core::time::Duration;
+
+/// Where the kernel maps the time page.
+pub const PAGE: u64 = 0x0808_0000_0000;
+
+/// The layout of the page, as the kernel writes it.
+#[repr(C)]
+struct Data {
+    sequence: AtomicU64,
+    ticks: AtomicU64,
+    tick_nanos: AtomicU64,
+    tsc_hz: AtomicU64,
+    realtime_offset: AtomicU64,
+}
+
+fn data() -> &'static Data {
+    unsafe { &*(PAGE as *const Data) }
+}
+
+/// Reads the TSC frequency and the realtime offset consistently, retrying
+/// while the kernel changes them.
+fn calibration() -> (u64, u64) {
+    let data = data();
+    loop {
+        let sequence = data.sequence.load(Ordering::Acquire);
+        if sequence % 2 == 1 {
+            core::hint::spin_loop();
+            continue;
+        }
+        let tsc_hz = data.tsc_hz.load(Ordering::Relaxed);
+        let realtime_offset = data.realtime_offset.load(Ordering::Relaxed);
+        fence(Ordering::Acquire);
+        if data.sequence.load(Ordering::Relaxed) == sequence {
+            return (tsc_hz, realtime_offset);
+        }
+    }
+}
+
+/// Nanoseconds since the TSC started counting.
+fn nanos(tsc_hz: u64) -> u64 {
+    if tsc_hz == 0 {
+        return 0;
+    }
+    let tsc = unsafe { _rdtsc() };
+    (u128::from(tsc) * 1_000_000_000 / u128::from(tsc_hz)) as u64
+}
+
+/// The kernel's timer ticks since the machine started, as of the last timer
+/// interrupt.
+pub fn ticks() -> u64 {
+    data().ticks.load(Ordering::Relaxed)
+}
+
+/// The length of a timer tick.
+pub fn tick_period() -> Duration {
+    Duration::from_nanos(data().tick_nanos.load(Ordering::Relaxed))
+}
+
+/// The TSC frequency the kernel measured, in Hz.
+pub fn tsc_frequency() -> u64 {
+    calibration().0
+}
+
+/// Time since the machine started, to the TSC's precision.
+pub fn uptime() -> Duration {
+    Duration::from_nanos(nanos(calibration().0))
+}
+
+/// Time since 1970-01-01 00:00:00 in the realtime clock's time zone, or since
+/// the machine started if it has no realtime clock.
+pub fn now() -> Duration {
+    let (tsc_hz, realtime_offset) = calibration();
+    Duration::from_nanos(realtime_offset + nanos(tsc_hz))
+}
+
+/// A measurement of the TSC, like `std::time::Instant`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Instant {
+        Instant(unsafe { _rdtsc() })
+    }
+
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        let tsc_hz = tsc_frequency();
+        if tsc_hz == 0 {
+            return Duration::ZERO;
+        }
+        let cycles = self.0.saturating_sub(earlier.0);
+        Duration::from_nanos((u128::from(cycles) * 1_000_000_000 / u128::from(tsc_hz)) as u64)
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+}